@@ -0,0 +1,62 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! `cargo bench --features bench`. Criterion reports handshakes and segments per second; the
+//! allocation counts are printed once at the end.
+
+use catnip::{
+    bench,
+    bench::CountingAllocator,
+};
+use criterion::{
+    Criterion,
+    Throughput,
+};
+use std::time::Duration;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const SEGMENT_SIZE: usize = 1024;
+
+fn bench_handshakes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tcp");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("handshake", |b| {
+        b.iter_custom(|iters| bench::handshakes(iters).elapsed)
+    });
+    group.finish();
+}
+
+fn bench_segments(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tcp");
+    group.throughput(Throughput::Bytes(SEGMENT_SIZE as u64));
+    group.bench_function("segment", |b| {
+        b.iter_custom(|iters| {
+            let report = bench::segments(iters, SEGMENT_SIZE);
+            // Odd counts round up to a whole round trip.
+            report.elapsed * iters as u32 / report.iterations as u32
+        })
+    });
+    group.finish();
+}
+
+fn main() {
+    let mut c = Criterion::default()
+        .measurement_time(Duration::from_secs(5))
+        .configure_from_args();
+    bench_handshakes(&mut c);
+    bench_segments(&mut c);
+    c.final_summary();
+
+    let report = bench::handshakes(1000);
+    println!(
+        "allocations per handshake: {:.1}",
+        report.allocations_per_iteration()
+    );
+    let report = bench::segments(10000, SEGMENT_SIZE);
+    println!(
+        "allocations per segment:   {:.1}",
+        report.allocations_per_iteration()
+    );
+}
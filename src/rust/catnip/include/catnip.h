@@ -0,0 +1,86 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+#ifndef CATNIP_H_IS_INCLUDED
+#define CATNIP_H_IS_INCLUDED
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* All functions returning `int` return zero on success or an errno value on failure. `EAGAIN`
+ * means the requested frame or result isn't available yet. */
+
+typedef struct catnip_engine catnip_engine_t;
+typedef uint32_t catnip_qd_t;
+typedef uint64_t catnip_qtoken_t;
+
+typedef struct catnip_endpoint {
+    uint8_t addr[4];
+    uint16_t port; /* host byte order */
+} catnip_endpoint_t;
+
+typedef struct catnip_config {
+    uint8_t link_addr[6];
+    uint8_t ipv4_addr[4];
+    uint8_t rng_seed[16];
+    uint32_t tcp_advertised_mss; /* zero keeps the default */
+    bool disable_arp;
+} catnip_config_t;
+
+typedef enum catnip_opcode {
+    CATNIP_OPC_FAILED = 0,
+    CATNIP_OPC_CONNECT,
+    CATNIP_OPC_ACCEPT,
+    CATNIP_OPC_PUSH,
+    CATNIP_OPC_POP,
+} catnip_opcode_t;
+
+typedef struct catnip_result {
+    catnip_opcode_t opcode;
+    catnip_qd_t qd;
+    int error;               /* CATNIP_OPC_FAILED */
+    uint32_t code;           /* CATNIP_OPC_FAILED; see catnip_strerror */
+    catnip_qd_t new_qd;      /* CATNIP_OPC_ACCEPT */
+    bool has_remote;         /* UDP CATNIP_OPC_POP */
+    catnip_endpoint_t remote;
+    uint8_t *data;           /* CATNIP_OPC_POP; release with catnip_result_free */
+    size_t data_len;
+} catnip_result_t;
+
+catnip_engine_t *catnip_engine_create(const catnip_config_t *config);
+void catnip_engine_destroy(catnip_engine_t *engine);
+
+int catnip_engine_receive(catnip_engine_t *engine, const uint8_t *frame, size_t len);
+int catnip_engine_advance_clock(catnip_engine_t *engine, uint64_t now_ns);
+int catnip_engine_poll(catnip_engine_t *engine);
+int catnip_engine_pop_frame(catnip_engine_t *engine, uint8_t *buf, size_t cap, size_t *len_out);
+
+int catnip_engine_take_result(catnip_engine_t *engine, catnip_qtoken_t qt, catnip_result_t *result_out);
+int catnip_engine_drop_qtoken(catnip_engine_t *engine, catnip_qtoken_t qt);
+void catnip_result_free(catnip_result_t *result);
+const char *catnip_strerror(uint32_t code);
+
+int catnip_tcp_socket(catnip_engine_t *engine, catnip_qd_t *qd_out);
+int catnip_udp_socket(catnip_engine_t *engine, catnip_qd_t *qd_out);
+int catnip_bind(catnip_engine_t *engine, catnip_qd_t qd, catnip_endpoint_t local);
+int catnip_close(catnip_engine_t *engine, catnip_qd_t qd);
+
+int catnip_tcp_listen(catnip_engine_t *engine, catnip_qd_t qd, int backlog);
+int catnip_tcp_accept(catnip_engine_t *engine, catnip_qd_t qd, catnip_qtoken_t *qt_out);
+int catnip_tcp_connect(catnip_engine_t *engine, catnip_qd_t qd, catnip_endpoint_t remote, catnip_qtoken_t *qt_out);
+int catnip_tcp_write(catnip_engine_t *engine, catnip_qd_t qd, const uint8_t *data, size_t len, catnip_qtoken_t *qt_out);
+int catnip_tcp_read(catnip_engine_t *engine, catnip_qd_t qd, catnip_qtoken_t *qt_out);
+
+int catnip_udp_sendto(catnip_engine_t *engine, catnip_qd_t qd, const uint8_t *data, size_t len, catnip_endpoint_t remote);
+int catnip_udp_recvfrom(catnip_engine_t *engine, catnip_qd_t qd, catnip_qtoken_t *qt_out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CATNIP_H_IS_INCLUDED */
@@ -0,0 +1,65 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Userspace network backends for running the engine without DPDK.
+//!
+//! A backend is just a [`NetworkDevice`] that can hand over received frames and accept frames to
+//! transmit. [`DeviceRuntime`] wraps one in a `Runtime`, so it plugs into `LibOS` (whose
+//! background polling loop pumps frames between the device and the engine) like any other.
+//! The runtime also consults the device's [`Capabilities`] and [`LinkState`]: checksums the
+//! device offloads aren't computed or verified in software, nothing is transmitted while the
+//! link is down, and the engine holds frames back while the device reports its TX ring full.
+
+#[cfg(all(feature = "raw", unix))]
+pub mod raw;
+mod runtime;
+#[cfg(all(feature = "tap", target_os = "linux"))]
+pub mod tap;
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+pub mod xdp;
+
+pub use self::runtime::DeviceRuntime;
+use crate::{
+    runtime::RECEIVE_BATCH_SIZE,
+    sync::Bytes,
+};
+use arrayvec::ArrayVec;
+
+/// What a device does in hardware (or the kernel) on the engine's behalf.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Received frames' TCP and UDP checksums have already been verified.
+    pub rx_checksum_offload: bool,
+    /// The device fills in TCP and UDP checksums on transmit.
+    pub tx_checksum_offload: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkState {
+    Up,
+    Down,
+}
+
+pub trait NetworkDevice: 'static {
+    /// Transmit a single Ethernet frame. Frames the device can't accept right now are dropped,
+    /// like they would be on the wire.
+    fn transmit(&mut self, frame: &[u8]);
+
+    /// Append up to a batch of received frames to `batch` without blocking.
+    fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>);
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Checked before every transmit, so it should be cheap.
+    fn link_state(&self) -> LinkState {
+        LinkState::Up
+    }
+
+    /// Whether `transmit` would accept a frame right now, e.g. because the TX ring has a free
+    /// slot. Also checked before every transmit.
+    fn tx_ready(&self) -> bool {
+        true
+    }
+}
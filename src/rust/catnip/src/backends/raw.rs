@@ -0,0 +1,361 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Slow but portable backend that sends and receives whole Ethernet frames on an existing
+//! interface, for CI and development machines without DPDK or TAP support. On Linux this is an
+//! `AF_PACKET` socket; elsewhere it's a libpcap handle.
+//!
+//! Either way it needs raw socket privileges, and the engine shares the interface with the host
+//! stack, so give it an address (and MAC, in promiscuous mode) the host isn't using.
+
+use super::{
+    DeviceRuntime,
+    NetworkDevice,
+};
+use crate::{
+    fail::Fail,
+    options::Options,
+    runtime::{
+        RuntimeBuf,
+        RECEIVE_BATCH_SIZE,
+    },
+    sync::Bytes,
+};
+use arrayvec::ArrayVec;
+
+// Ethernet header plus a VLAN tag.
+const MAX_HEADER_SIZE: usize = 18;
+
+#[derive(Clone, Debug)]
+pub struct RawOptions {
+    pub ifname: String,
+    /// Receive frames addressed to any MAC, not just the interface's own. Needed unless the
+    /// engine uses the interface's MAC address.
+    pub promiscuous: bool,
+    pub mtu: usize,
+}
+
+impl RawOptions {
+    pub fn new(ifname: &str) -> Self {
+        Self {
+            ifname: ifname.to_string(),
+            promiscuous: true,
+            mtu: 1500,
+        }
+    }
+
+    pub fn promiscuous(mut self, value: bool) -> Self {
+        self.promiscuous = value;
+        self
+    }
+
+    pub fn mtu(mut self, value: usize) -> Self {
+        assert!(value >= 68);
+        self.mtu = value;
+        self
+    }
+}
+
+pub use self::imp::RawDevice;
+
+pub type RawRuntime = DeviceRuntime<RawDevice>;
+
+/// Open `ifname` for raw frame access and wrap it in a runtime for the engine configured by
+/// `options`.
+pub fn runtime(raw: &RawOptions, options: Options) -> Result<RawRuntime, Fail> {
+    let device = RawDevice::open(raw)?;
+    Ok(DeviceRuntime::new(device, options))
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::*;
+    use libc::{
+        c_int,
+        c_ushort,
+        c_void,
+        socklen_t,
+    };
+    use std::{
+        ffi::CString,
+        io,
+        mem,
+        os::unix::io::{
+            AsRawFd,
+            RawFd,
+        },
+    };
+
+    const SOL_PACKET: c_int = 263;
+    const PACKET_ADD_MEMBERSHIP: c_int = 1;
+    const PACKET_MR_PROMISC: c_ushort = 1;
+    const PACKET_OUTGOING: u8 = 4;
+
+    #[repr(C)]
+    struct PacketMreq {
+        mr_ifindex: c_int,
+        mr_type: c_ushort,
+        mr_alen: c_ushort,
+        mr_address: [u8; 8],
+    }
+
+    fn os_error(what: &str) -> Fail {
+        warn!("AF_PACKET: {} failed: {}", what, io::Error::last_os_error());
+        Fail::IoError {}
+    }
+
+    pub struct RawDevice {
+        fd: RawFd,
+        rx_buf: Vec<u8>,
+    }
+
+    impl RawDevice {
+        pub fn open(options: &RawOptions) -> Result<Self, Fail> {
+            let ifname = CString::new(options.ifname.clone()).map_err(|_| Fail::Invalid {
+                details: "Invalid interface name",
+            })?;
+            let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
+            if ifindex == 0 {
+                return Err(os_error("if_nametoindex"));
+            }
+            let protocol = (libc::ETH_P_ALL as u16).to_be();
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_PACKET,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK,
+                    protocol as c_int,
+                )
+            };
+            if fd < 0 {
+                return Err(os_error("socket"));
+            }
+            let device = Self {
+                fd,
+                rx_buf: vec![0u8; options.mtu + MAX_HEADER_SIZE],
+            };
+
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            addr.sll_family = libc::AF_PACKET as u16;
+            addr.sll_protocol = protocol;
+            addr.sll_ifindex = ifindex as c_int;
+            let r = unsafe {
+                libc::bind(
+                    fd,
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_ll>() as socklen_t,
+                )
+            };
+            if r < 0 {
+                return Err(os_error("bind"));
+            }
+
+            if options.promiscuous {
+                let mreq = PacketMreq {
+                    mr_ifindex: ifindex as c_int,
+                    mr_type: PACKET_MR_PROMISC,
+                    mr_alen: 0,
+                    mr_address: [0; 8],
+                };
+                let r = unsafe {
+                    libc::setsockopt(
+                        fd,
+                        SOL_PACKET,
+                        PACKET_ADD_MEMBERSHIP,
+                        &mreq as *const _ as *const c_void,
+                        mem::size_of::<PacketMreq>() as socklen_t,
+                    )
+                };
+                if r < 0 {
+                    return Err(os_error("PACKET_ADD_MEMBERSHIP"));
+                }
+            }
+            info!("Opened AF_PACKET socket on {}", options.ifname);
+            Ok(device)
+        }
+    }
+
+    impl NetworkDevice for RawDevice {
+        fn transmit(&mut self, frame: &[u8]) {
+            let n = unsafe { libc::send(self.fd, frame.as_ptr() as *const c_void, frame.len(), 0) };
+            if n < 0 {
+                warn!("AF_PACKET send failed: {}", io::Error::last_os_error());
+            }
+        }
+
+        fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+            while !batch.is_full() {
+                let mut from: libc::sockaddr_ll = unsafe { mem::zeroed() };
+                let mut from_len = mem::size_of::<libc::sockaddr_ll>() as socklen_t;
+                let n = unsafe {
+                    libc::recvfrom(
+                        self.fd,
+                        self.rx_buf.as_mut_ptr() as *mut c_void,
+                        self.rx_buf.len(),
+                        0,
+                        &mut from as *mut _ as *mut libc::sockaddr,
+                        &mut from_len,
+                    )
+                };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        warn!("AF_PACKET recv failed: {}", e);
+                    }
+                    break;
+                }
+                // Packet sockets also see everything sent on the interface, including our own
+                // frames.
+                if from.sll_pkttype == PACKET_OUTGOING {
+                    continue;
+                }
+                batch.push(Bytes::from_slice(&self.rx_buf[..n as usize]));
+            }
+        }
+    }
+
+    impl AsRawFd for RawDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd
+        }
+    }
+
+    impl Drop for RawDevice {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::*;
+    use libc::{
+        c_char,
+        c_int,
+        c_void,
+        size_t,
+    };
+    use std::{
+        ffi::{
+            CStr,
+            CString,
+        },
+        ptr,
+        slice,
+    };
+
+    const PCAP_ERRBUF_SIZE: usize = 256;
+    const PCAP_D_IN: c_int = 1;
+
+    #[allow(non_camel_case_types)]
+    enum pcap_t {}
+
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    struct pcap_pkthdr {
+        ts: libc::timeval,
+        caplen: u32,
+        len: u32,
+    }
+
+    #[link(name = "pcap")]
+    extern "C" {
+        fn pcap_open_live(
+            device: *const c_char,
+            snaplen: c_int,
+            promisc: c_int,
+            to_ms: c_int,
+            errbuf: *mut c_char,
+        ) -> *mut pcap_t;
+        fn pcap_setnonblock(p: *mut pcap_t, nonblock: c_int, errbuf: *mut c_char) -> c_int;
+        fn pcap_setdirection(p: *mut pcap_t, d: c_int) -> c_int;
+        fn pcap_next_ex(
+            p: *mut pcap_t,
+            header: *mut *mut pcap_pkthdr,
+            data: *mut *const u8,
+        ) -> c_int;
+        fn pcap_inject(p: *mut pcap_t, buf: *const c_void, size: size_t) -> c_int;
+        fn pcap_geterr(p: *mut pcap_t) -> *const c_char;
+        fn pcap_close(p: *mut pcap_t);
+    }
+
+    fn pcap_error(what: &str, msg: *const c_char) -> Fail {
+        let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy();
+        warn!("libpcap: {} failed: {}", what, msg);
+        Fail::IoError {}
+    }
+
+    pub struct RawDevice {
+        handle: *mut pcap_t,
+    }
+
+    impl RawDevice {
+        pub fn open(options: &RawOptions) -> Result<Self, Fail> {
+            let ifname = CString::new(options.ifname.clone()).map_err(|_| Fail::Invalid {
+                details: "Invalid interface name",
+            })?;
+            let mut errbuf = [0 as c_char; PCAP_ERRBUF_SIZE];
+            let snaplen = (options.mtu + MAX_HEADER_SIZE) as c_int;
+            let handle = unsafe {
+                pcap_open_live(
+                    ifname.as_ptr(),
+                    snaplen,
+                    options.promiscuous as c_int,
+                    1,
+                    errbuf.as_mut_ptr(),
+                )
+            };
+            if handle.is_null() {
+                return Err(pcap_error("pcap_open_live", errbuf.as_ptr()));
+            }
+            let device = Self { handle };
+            if unsafe { pcap_setnonblock(handle, 1, errbuf.as_mut_ptr()) } < 0 {
+                return Err(pcap_error("pcap_setnonblock", errbuf.as_ptr()));
+            }
+            // Don't loop our own transmissions back to the engine.
+            if unsafe { pcap_setdirection(handle, PCAP_D_IN) } < 0 {
+                return Err(pcap_error("pcap_setdirection", unsafe {
+                    pcap_geterr(handle)
+                }));
+            }
+            info!("Opened libpcap handle on {}", options.ifname);
+            Ok(device)
+        }
+    }
+
+    impl NetworkDevice for RawDevice {
+        fn transmit(&mut self, frame: &[u8]) {
+            let r =
+                unsafe { pcap_inject(self.handle, frame.as_ptr() as *const c_void, frame.len()) };
+            if r < 0 {
+                pcap_error("pcap_inject", unsafe { pcap_geterr(self.handle) });
+            }
+        }
+
+        fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+            while !batch.is_full() {
+                let mut header: *mut pcap_pkthdr = ptr::null_mut();
+                let mut data: *const u8 = ptr::null();
+                match unsafe { pcap_next_ex(self.handle, &mut header, &mut data) } {
+                    1 => {
+                        let len = unsafe { (*header).caplen } as usize;
+                        let frame = unsafe { slice::from_raw_parts(data, len) };
+                        batch.push(Bytes::from_slice(frame));
+                    },
+                    // Nothing ready in nonblocking mode.
+                    0 => break,
+                    _ => {
+                        pcap_error("pcap_next_ex", unsafe { pcap_geterr(self.handle) });
+                        break;
+                    },
+                }
+            }
+        }
+    }
+
+    impl Drop for RawDevice {
+        fn drop(&mut self) {
+            unsafe { pcap_close(self.handle) };
+        }
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    LinkState,
+    NetworkDevice,
+};
+use crate::{
+    interop::{
+        dmtr_sgarray_t,
+        dmtr_sgaseg_t,
+    },
+    options::Options,
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        icmpv4,
+        ipv4,
+        tcp,
+        udp,
+    },
+    runtime::{
+        self,
+        PacketBuf,
+        Runtime,
+        RECEIVE_BATCH_SIZE,
+    },
+    scheduler::{
+        Operation,
+        Scheduler,
+        SchedulerHandle,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    timer::{
+        Timer,
+        TimerRc,
+    },
+};
+use arrayvec::ArrayVec;
+use futures::FutureExt;
+use rand::{
+    distributions::{
+        Distribution,
+        Standard,
+    },
+    rngs::SmallRng,
+    seq::SliceRandom,
+    Rng,
+    SeedableRng,
+};
+use std::{
+    cell::RefCell,
+    future::Future,
+    mem,
+    net::Ipv4Addr,
+    ptr,
+    rc::Rc,
+    slice,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// A runtime that sends and receives through a userspace [`NetworkDevice`], using heap-allocated
+/// buffers and the system clock.
+pub struct DeviceRuntime<D: NetworkDevice> {
+    inner: Rc<RefCell<Inner<D>>>,
+    scheduler: Scheduler<Operation<DeviceRuntime<D>>>,
+}
+
+// `#[derive(Clone)]` would needlessly require `D: Clone`.
+impl<D: NetworkDevice> Clone for DeviceRuntime<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            scheduler: self.scheduler.clone(),
+        }
+    }
+}
+
+struct Inner<D: NetworkDevice> {
+    device: D,
+    timer: TimerRc,
+    rng: SmallRng,
+    // Scratch space for serializing outgoing frames.
+    tx_buf: Vec<u8>,
+    num_link_down: u64,
+
+    link_addr: MacAddress,
+    ipv4_addr: Ipv4Addr,
+    arp_options: arp::Options,
+    tcp_options: tcp::Options,
+    udp_options: udp::Options,
+    icmpv4_options: icmpv4::Options,
+    ipv4_options: ipv4::Options,
+}
+
+impl<D: NetworkDevice> DeviceRuntime<D> {
+    pub fn new(device: D, options: Options) -> Self {
+        let mut seed = [0u8; 16];
+        seed.copy_from_slice(&options.rng_seed[..16]);
+        let inner = Inner {
+            device,
+            timer: TimerRc(Rc::new(Timer::new(Instant::now()))),
+            rng: SmallRng::from_seed(seed),
+            tx_buf: Vec::new(),
+            num_link_down: 0,
+            link_addr: options.my_link_addr,
+            ipv4_addr: options.my_ipv4_addr,
+            arp_options: options.arp,
+            tcp_options: options.tcp,
+            udp_options: options.udp,
+            icmpv4_options: options.icmpv4,
+            ipv4_options: ipv4::Options::default().mtu(options.mtu),
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+            scheduler: Scheduler::new(),
+        }
+    }
+
+    /// Run `f` with exclusive access to the underlying device.
+    pub fn with_device<R>(&self, f: impl FnOnce(&mut D) -> R) -> R {
+        f(&mut self.inner.borrow_mut().device)
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        self.inner.borrow().device.link_state()
+    }
+
+    /// Frames the engine sent while the device's link was down, which were dropped.
+    pub fn num_link_down(&self) -> u64 {
+        self.inner.borrow().num_link_down
+    }
+}
+
+impl<D: NetworkDevice> Runtime for DeviceRuntime<D> {
+    type Buf = Bytes;
+    type WaitFuture = crate::timer::WaitFuture<TimerRc>;
+
+    fn into_sgarray(&self, buf: Bytes) -> dmtr_sgarray_t {
+        let buf_copy: Box<[u8]> = (&buf[..]).into();
+        let ptr = Box::into_raw(buf_copy);
+        let sgaseg = dmtr_sgaseg_t {
+            sgaseg_buf: ptr as *mut _,
+            sgaseg_len: buf.len() as u32,
+        };
+        dmtr_sgarray_t {
+            sga_buf: ptr::null_mut(),
+            sga_numsegs: 1,
+            sga_segs: [sgaseg],
+            sga_addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    fn alloc_sgarray(&self, size: usize) -> dmtr_sgarray_t {
+        let allocation: Box<[u8]> = vec![0u8; size].into_boxed_slice();
+        let ptr = Box::into_raw(allocation);
+        let sgaseg = dmtr_sgaseg_t {
+            sgaseg_buf: ptr as *mut _,
+            sgaseg_len: size as u32,
+        };
+        dmtr_sgarray_t {
+            sga_buf: ptr::null_mut(),
+            sga_numsegs: 1,
+            sga_segs: [sgaseg],
+            sga_addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    fn free_sgarray(&self, sga: dmtr_sgarray_t) {
+        assert_eq!(sga.sga_numsegs, 1);
+        let sgaseg = sga.sga_segs[0];
+        let allocation: Box<[u8]> = unsafe {
+            Box::from_raw(slice::from_raw_parts_mut(
+                sgaseg.sgaseg_buf as *mut _,
+                sgaseg.sgaseg_len as usize,
+            ))
+        };
+        drop(allocation);
+    }
+
+    fn clone_sgarray(&self, sga: &dmtr_sgarray_t) -> Bytes {
+        let mut len = 0;
+        for i in 0..sga.sga_numsegs as usize {
+            len += sga.sga_segs[i].sgaseg_len;
+        }
+        let mut buf = BytesMut::zeroed(len as usize);
+        let mut pos = 0;
+        for i in 0..sga.sga_numsegs as usize {
+            let seg = &sga.sga_segs[i];
+            let seg_slice = unsafe {
+                slice::from_raw_parts(seg.sgaseg_buf as *mut u8, seg.sgaseg_len as usize)
+            };
+            buf[pos..(pos + seg_slice.len())].copy_from_slice(seg_slice);
+            pos += seg_slice.len();
+        }
+        buf.freeze()
+    }
+
+    fn advance_clock(&self, now: Instant) {
+        self.inner.borrow_mut().timer.0.advance_clock(now);
+    }
+
+    fn transmit(&self, pkt: impl PacketBuf<Bytes>) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.device.link_state() == LinkState::Down {
+            inner.num_link_down += 1;
+            return;
+        }
+        let Inner {
+            ref mut device,
+            ref mut tx_buf,
+            ..
+        } = *inner;
+        tx_buf.resize(runtime::frame_size(&pkt), 0);
+        runtime::serialize_frame(pkt, &mut tx_buf[..]);
+        device.transmit(&tx_buf[..]);
+    }
+
+    fn tx_ready(&self) -> bool {
+        self.inner.borrow().device.tx_ready()
+    }
+
+    fn receive(&self) -> ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]> {
+        let mut out = ArrayVec::new();
+        self.inner.borrow_mut().device.poll_receive(&mut out);
+        out
+    }
+
+    fn local_link_addr(&self) -> MacAddress {
+        self.inner.borrow().link_addr
+    }
+
+    fn local_ipv4_addr(&self) -> Ipv4Addr {
+        self.inner.borrow().ipv4_addr
+    }
+
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
+    fn arp_options(&self) -> arp::Options {
+        self.inner.borrow().arp_options.clone()
+    }
+
+    fn tcp_options(&self) -> tcp::Options {
+        let inner = self.inner.borrow();
+        let capabilities = inner.device.capabilities();
+        let mut options = inner.tcp_options.clone();
+        options.rx_checksum_offload |= capabilities.rx_checksum_offload;
+        options.tx_checksum_offload |= capabilities.tx_checksum_offload;
+        options
+    }
+
+    fn udp_options(&self) -> udp::Options {
+        let inner = self.inner.borrow();
+        let capabilities = inner.device.capabilities();
+        let mut options = inner.udp_options.clone();
+        options.rx_checksum_offload |= capabilities.rx_checksum_offload;
+        options.tx_checksum_offload |= capabilities.tx_checksum_offload;
+        options
+    }
+
+    fn icmpv4_options(&self) -> icmpv4::Options {
+        self.inner.borrow().icmpv4_options.clone()
+    }
+
+    fn ipv4_options(&self) -> ipv4::Options {
+        self.inner.borrow().ipv4_options.clone()
+    }
+
+    fn wait(&self, duration: Duration) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        let now = inner.timer.0.now();
+        inner
+            .timer
+            .0
+            .wait_until(inner.timer.clone(), now + duration)
+    }
+
+    fn wait_until(&self, when: Instant) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        inner.timer.0.wait_until(inner.timer.clone(), when)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.borrow().timer.0.now()
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
+    fn rng_gen<T>(&self) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.rng.gen()
+    }
+
+    fn rng_shuffle<T>(&self, slice: &mut [T]) {
+        let mut inner = self.inner.borrow_mut();
+        slice.shuffle(&mut inner.rng);
+    }
+
+    fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
+        self.scheduler
+            .insert(Operation::Background(future.boxed_local()))
+    }
+
+    fn scheduler(&self) -> &Scheduler<Operation<Self>> {
+        &self.scheduler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceRuntime;
+    use crate::{
+        backends::{
+            Capabilities,
+            LinkState,
+            NetworkDevice,
+        },
+        engine::Engine,
+        options::Options,
+        runtime::{
+            Runtime,
+            RuntimeBuf,
+            RECEIVE_BATCH_SIZE,
+        },
+        sync::Bytes,
+        test_helpers,
+    };
+    use arrayvec::ArrayVec;
+    use futures::{
+        task::{
+            noop_waker_ref,
+            Context,
+        },
+        FutureExt,
+    };
+    use must_let::must_let;
+    use std::{
+        collections::VecDeque,
+        future::Future,
+        task::Poll,
+    };
+
+    #[derive(Default)]
+    struct QueueDevice {
+        rx: VecDeque<Bytes>,
+        tx: VecDeque<Bytes>,
+        capabilities: Capabilities,
+        link_down: bool,
+    }
+
+    impl NetworkDevice for QueueDevice {
+        fn transmit(&mut self, frame: &[u8]) {
+            self.tx.push_back(Bytes::from_slice(frame));
+        }
+
+        fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+            while !batch.is_full() {
+                match self.rx.pop_front() {
+                    Some(frame) => batch.push(frame),
+                    None => break,
+                }
+            }
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            self.capabilities
+        }
+
+        fn link_state(&self) -> LinkState {
+            if self.link_down {
+                LinkState::Down
+            } else {
+                LinkState::Up
+            }
+        }
+    }
+
+    #[test]
+    fn arp_through_device() {
+        let options = Options::default()
+            .my_ipv4_addr(test_helpers::ALICE_IPV4)
+            .my_link_addr(test_helpers::ALICE_MAC);
+        let rt = DeviceRuntime::new(QueueDevice::default(), options);
+        let mut alice = Engine::new(rt.clone()).unwrap();
+        let mut carrie = test_helpers::new_carrie(rt.now());
+
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+        // The request leaves through the device...
+        let request = rt.with_device(|d| d.tx.pop_front()).unwrap();
+        carrie.receive(request).unwrap();
+        carrie.rt().advance_clock(rt.now());
+        let reply = carrie.rt().pop_frame();
+
+        // ...and the reply comes back in through it.
+        rt.with_device(|d| d.rx.push_back(reply));
+        for frame in rt.receive() {
+            alice.receive(frame).unwrap();
+        }
+        must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+        assert_eq!(link_addr, test_helpers::CARRIE_MAC);
+    }
+
+    #[test]
+    fn capabilities_and_link_state() {
+        let options = Options::default()
+            .my_ipv4_addr(test_helpers::ALICE_IPV4)
+            .my_link_addr(test_helpers::ALICE_MAC);
+        let device = QueueDevice {
+            capabilities: Capabilities {
+                rx_checksum_offload: true,
+                tx_checksum_offload: false,
+            },
+            ..QueueDevice::default()
+        };
+        let rt = DeviceRuntime::new(device, options);
+        assert!(rt.tcp_options().rx_checksum_offload);
+        assert!(!rt.tcp_options().tx_checksum_offload);
+        assert!(rt.udp_options().rx_checksum_offload);
+
+        let alice = Engine::new(rt.clone()).unwrap();
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        rt.with_device(|d| d.link_down = true);
+        assert_eq!(rt.link_state(), LinkState::Down);
+        let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        assert!(rt.with_device(|d| d.tx.is_empty()));
+        assert_eq!(rt.num_link_down(), 1);
+    }
+}
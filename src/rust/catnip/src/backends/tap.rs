@@ -0,0 +1,368 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Linux TAP backend, for testing against the host's own network stack (or anything bridged to
+//! it) without DPDK hardware.
+//!
+//! Opening the device needs `CAP_NET_ADMIN`. The engine owns the "far" end of the TAP interface
+//! and should get its own address on the host subnet, e.g.
+//!
+//! ```ignore
+//! let tap = TapOptions::new("catnip0").host_addr(Ipv4Addr::new(10, 0, 0, 1), 24);
+//! let options = Options::default()
+//!     .my_ipv4_addr(Ipv4Addr::new(10, 0, 0, 2))
+//!     .my_link_addr(MacAddress::new([0x02, 0, 0, 0, 0, 0x02]));
+//! let libos = LibOS::new(tap::runtime(&tap, options)?)?;
+//! ```
+
+use super::{
+    DeviceRuntime,
+    NetworkDevice,
+};
+use crate::{
+    fail::Fail,
+    options::Options,
+    runtime::{
+        RuntimeBuf,
+        RECEIVE_BATCH_SIZE,
+    },
+    sync::Bytes,
+};
+use arrayvec::ArrayVec;
+use libc::{
+    c_char,
+    c_int,
+    c_short,
+    c_ulong,
+    c_ushort,
+    c_void,
+};
+use std::{
+    ffi::CString,
+    io,
+    mem,
+    net::Ipv4Addr,
+    os::unix::io::{
+        AsRawFd,
+        RawFd,
+    },
+};
+
+const IFNAMSIZ: usize = 16;
+
+const TUNSETIFF: c_ulong = 0x400454ca;
+const IFF_TAP: c_short = 0x0002;
+const IFF_NO_PI: c_short = 0x1000;
+
+const SIOCADDRT: c_ulong = 0x890b;
+const SIOCGIFFLAGS: c_ulong = 0x8913;
+const SIOCSIFFLAGS: c_ulong = 0x8914;
+const SIOCSIFADDR: c_ulong = 0x8916;
+const SIOCSIFNETMASK: c_ulong = 0x891c;
+const SIOCSIFMTU: c_ulong = 0x8922;
+
+const RTF_UP: c_ushort = 0x0001;
+const RTF_GATEWAY: c_ushort = 0x0002;
+
+// Ethernet header plus a VLAN tag.
+const MAX_HEADER_SIZE: usize = 18;
+
+// Only the fields used by the ioctls below; the kernel's `ifreq` has more.
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+union IfReqData {
+    addr: libc::sockaddr_in,
+    flags: c_short,
+    mtu: c_int,
+    _pad: [u8; 24],
+}
+
+#[repr(C)]
+struct IfReq {
+    name: [c_char; IFNAMSIZ],
+    data: IfReqData,
+}
+
+impl IfReq {
+    fn new(name: &str) -> Result<Self, Fail> {
+        if name.is_empty() || name.len() >= IFNAMSIZ || name.contains('\0') {
+            return Err(Fail::Invalid {
+                details: "Invalid TAP interface name",
+            });
+        }
+        let mut ifr: IfReq = unsafe { mem::zeroed() };
+        for (dst, &src) in ifr.name.iter_mut().zip(name.as_bytes()) {
+            *dst = src as c_char;
+        }
+        Ok(ifr)
+    }
+
+    fn name(&self) -> String {
+        let bytes = self
+            .name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct RtEntry {
+    rt_pad1: c_ulong,
+    rt_dst: libc::sockaddr_in,
+    rt_gateway: libc::sockaddr_in,
+    rt_genmask: libc::sockaddr_in,
+    rt_flags: c_ushort,
+    rt_pad2: c_short,
+    rt_pad3: c_ulong,
+    rt_pad4: *mut c_void,
+    rt_metric: c_short,
+    rt_dev: *mut c_char,
+    rt_mtu: c_ulong,
+    rt_window: c_ulong,
+    rt_irtt: c_ushort,
+}
+
+fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr_in {
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_addr.s_addr = u32::from(addr).to_be();
+    sin
+}
+
+fn netmask(prefix_len: u8) -> Ipv4Addr {
+    match prefix_len {
+        0 => Ipv4Addr::new(0, 0, 0, 0),
+        n => Ipv4Addr::from(!0u32 << (32 - n as u32)),
+    }
+}
+
+fn os_error(what: &str) -> Fail {
+    warn!("TAP: {} failed: {}", what, io::Error::last_os_error());
+    Fail::IoError {}
+}
+
+#[derive(Clone, Debug)]
+pub struct TapOptions {
+    /// Interface name; the kernel picks one (`tapN`) if it contains a `%d`.
+    pub name: String,
+    /// Address and prefix length to configure on the host side of the interface. The kernel then
+    /// routes the whole subnet through the TAP device.
+    pub host_addr: Option<(Ipv4Addr, u8)>,
+    /// Extra host routes (destination, prefix length) to send through the engine, which acts as
+    /// their gateway.
+    pub routes: Vec<(Ipv4Addr, u8)>,
+    pub mtu: usize,
+}
+
+impl TapOptions {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            host_addr: None,
+            routes: vec![],
+            mtu: 1500,
+        }
+    }
+
+    pub fn host_addr(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32);
+        self.host_addr = Some((addr, prefix_len));
+        self
+    }
+
+    pub fn route(mut self, dst: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32);
+        self.routes.push((dst, prefix_len));
+        self
+    }
+
+    pub fn mtu(mut self, value: usize) -> Self {
+        assert!(value >= 68);
+        self.mtu = value;
+        self
+    }
+}
+
+pub struct TapDevice {
+    fd: RawFd,
+    name: String,
+    rx_buf: Vec<u8>,
+}
+
+impl TapDevice {
+    /// Create (or attach to) the TAP interface and bring it up. `gateway` is the engine's own
+    /// address, which extra routes in `options` are installed through.
+    pub fn open(options: &TapOptions, gateway: Ipv4Addr) -> Result<Self, Fail> {
+        let path = CString::new("/dev/net/tun").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(os_error("open /dev/net/tun"));
+        }
+        let mut ifr = IfReq::new(&options.name)?;
+        ifr.data.flags = IFF_TAP | IFF_NO_PI;
+        if unsafe { libc::ioctl(fd, TUNSETIFF, &mut ifr) } < 0 {
+            let e = os_error("TUNSETIFF");
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let device = Self {
+            fd,
+            name: ifr.name(),
+            rx_buf: vec![0u8; options.mtu + MAX_HEADER_SIZE],
+        };
+        device.configure_host(options, gateway)?;
+        info!("Opened TAP interface {}", device.name);
+        Ok(device)
+    }
+
+    fn ioctl(
+        &self,
+        sock: RawFd,
+        request: c_ulong,
+        what: &str,
+        f: impl FnOnce(&mut IfReq),
+    ) -> Result<IfReq, Fail> {
+        let mut ifr = IfReq::new(&self.name)?;
+        f(&mut ifr);
+        if unsafe { libc::ioctl(sock, request, &mut ifr) } < 0 {
+            return Err(os_error(what));
+        }
+        Ok(ifr)
+    }
+
+    /// Name of the interface, as chosen by the kernel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn configure_host(&self, options: &TapOptions, gateway: Ipv4Addr) -> Result<(), Fail> {
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if sock < 0 {
+            return Err(os_error("socket"));
+        }
+        let result = self.configure_with(sock, options, gateway);
+        unsafe { libc::close(sock) };
+        result
+    }
+
+    fn configure_with(
+        &self,
+        sock: RawFd,
+        options: &TapOptions,
+        gateway: Ipv4Addr,
+    ) -> Result<(), Fail> {
+        let mtu = options.mtu as c_int;
+        self.ioctl(sock, SIOCSIFMTU, "SIOCSIFMTU", |ifr| ifr.data.mtu = mtu)?;
+        if let Some((addr, prefix_len)) = options.host_addr {
+            self.ioctl(sock, SIOCSIFADDR, "SIOCSIFADDR", |ifr| {
+                ifr.data.addr = sockaddr_in(addr)
+            })?;
+            self.ioctl(sock, SIOCSIFNETMASK, "SIOCSIFNETMASK", |ifr| {
+                ifr.data.addr = sockaddr_in(netmask(prefix_len))
+            })?;
+        }
+        let ifr = self.ioctl(sock, SIOCGIFFLAGS, "SIOCGIFFLAGS", |_| ())?;
+        let up = (unsafe { ifr.data.flags } as c_int | libc::IFF_UP | libc::IFF_RUNNING) as c_short;
+        self.ioctl(sock, SIOCSIFFLAGS, "SIOCSIFFLAGS", |ifr| {
+            ifr.data.flags = up
+        })?;
+
+        let dev = CString::new(self.name.clone()).unwrap();
+        for &(dst, prefix_len) in &options.routes {
+            let mask = netmask(prefix_len);
+            let dst = Ipv4Addr::from(u32::from(dst) & u32::from(mask));
+            let mut route: RtEntry = unsafe { mem::zeroed() };
+            route.rt_dst = sockaddr_in(dst);
+            route.rt_genmask = sockaddr_in(mask);
+            route.rt_gateway = sockaddr_in(gateway);
+            route.rt_flags = RTF_UP | RTF_GATEWAY;
+            route.rt_dev = dev.as_ptr() as *mut c_char;
+            if unsafe { libc::ioctl(sock, SIOCADDRT, &mut route) } < 0 {
+                return Err(os_error("SIOCADDRT"));
+            }
+            info!(
+                "Routing {}/{} via {} on {}",
+                dst, prefix_len, gateway, self.name
+            );
+        }
+        Ok(())
+    }
+}
+
+impl NetworkDevice for TapDevice {
+    fn transmit(&mut self, frame: &[u8]) {
+        let n = unsafe { libc::write(self.fd, frame.as_ptr() as *const c_void, frame.len()) };
+        if n < 0 {
+            // The kernel queue is full (or the interface went down); drop the frame.
+            warn!("TAP write failed: {}", io::Error::last_os_error());
+        }
+    }
+
+    fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+        while !batch.is_full() {
+            let n = unsafe {
+                libc::read(
+                    self.fd,
+                    self.rx_buf.as_mut_ptr() as *mut c_void,
+                    self.rx_buf.len(),
+                )
+            };
+            if n <= 0 {
+                let e = io::Error::last_os_error();
+                if n < 0 && e.kind() != io::ErrorKind::WouldBlock {
+                    warn!("TAP read failed: {}", e);
+                }
+                break;
+            }
+            batch.push(Bytes::from_slice(&self.rx_buf[..n as usize]));
+        }
+    }
+}
+
+impl AsRawFd for TapDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for TapDevice {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+pub type TapRuntime = DeviceRuntime<TapDevice>;
+
+/// Open a TAP interface and wrap it in a runtime for the engine configured by `options`.
+pub fn runtime(tap: &TapOptions, options: Options) -> Result<TapRuntime, Fail> {
+    let device = TapDevice::open(tap, options.my_ipv4_addr)?;
+    Ok(DeviceRuntime::new(device, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        netmask,
+        IfReq,
+    };
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn netmasks() {
+        assert_eq!(netmask(0), Ipv4Addr::new(0, 0, 0, 0));
+        assert_eq!(netmask(24), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(netmask(32), Ipv4Addr::new(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn interface_names() {
+        assert_eq!(IfReq::new("tap%d").unwrap().name(), "tap%d");
+        assert!(IfReq::new("").is_err());
+        assert!(IfReq::new("a-much-too-long-name").is_err());
+    }
+}
@@ -0,0 +1,703 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! AF_XDP backend: a kernel-bypass datapath on stock Linux NICs, without hugepages or VFIO.
+//!
+//! The socket binds to a single queue of an interface. Some XDP program must redirect that
+//! queue's packets into an `XSKMAP`; pass the map's fd as [`XdpOptions::xsks_map`] and the socket
+//! registers itself there (this module doesn't load BPF programs itself).
+//!
+//! Frames live in a UMEM region shared with the kernel. It's split in two: the first half is
+//! handed to the kernel through the fill ring for receiving, and the second half is a free list
+//! of transmit buffers that come back through the completion ring.
+
+use super::{
+    DeviceRuntime,
+    NetworkDevice,
+};
+use crate::{
+    fail::Fail,
+    options::Options,
+    runtime::{
+        RuntimeBuf,
+        RECEIVE_BATCH_SIZE,
+    },
+    sync::Bytes,
+};
+use arrayvec::ArrayVec;
+use libc::{
+    c_int,
+    c_void,
+    socklen_t,
+};
+use std::{
+    ffi::CString,
+    io,
+    mem,
+    os::unix::io::{
+        AsRawFd,
+        RawFd,
+    },
+    ptr,
+    slice,
+    sync::atomic::{
+        AtomicU32,
+        Ordering,
+    },
+    time::Duration,
+};
+
+const AF_XDP: c_int = 44;
+const SOL_XDP: c_int = 283;
+
+const XDP_MMAP_OFFSETS: c_int = 1;
+const XDP_RX_RING: c_int = 2;
+const XDP_TX_RING: c_int = 3;
+const XDP_UMEM_REG: c_int = 4;
+const XDP_UMEM_FILL_RING: c_int = 5;
+const XDP_UMEM_COMPLETION_RING: c_int = 6;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1 << 0;
+
+const SO_BUSY_POLL: c_int = 46;
+const SO_PREFER_BUSY_POLL: c_int = 69;
+const SO_BUSY_POLL_BUDGET: c_int = 70;
+
+const BPF_MAP_UPDATE_ELEM: c_int = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct BpfMapUpdate {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+fn os_error(what: &str) -> Fail {
+    warn!("AF_XDP: {} failed: {}", what, io::Error::last_os_error());
+    Fail::IoError {}
+}
+
+/// One of the four single-producer, single-consumer rings shared with the kernel. We're the
+/// producer for the fill and TX rings and the consumer for the completion and RX rings.
+struct Ring<T: Copy> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const u32,
+    descs: *mut T,
+    mask: u32,
+    size: u32,
+
+    // Our view of the other side's index, refreshed only when we run out.
+    cached_prod: u32,
+    cached_cons: u32,
+
+    map: *mut c_void,
+    map_len: usize,
+}
+
+impl<T: Copy> Ring<T> {
+    unsafe fn new(base: *mut u8, offsets: &XdpRingOffset, size: u32) -> Self {
+        assert!(size.is_power_of_two());
+        Self {
+            producer: base.add(offsets.producer as usize) as *const AtomicU32,
+            consumer: base.add(offsets.consumer as usize) as *const AtomicU32,
+            flags: base.add(offsets.flags as usize) as *const u32,
+            descs: base.add(offsets.desc as usize) as *mut T,
+            mask: size - 1,
+            size,
+            cached_prod: 0,
+            cached_cons: 0,
+            map: ptr::null_mut(),
+            map_len: 0,
+        }
+    }
+
+    fn mmap(
+        fd: RawFd,
+        offsets: &XdpRingOffset,
+        size: u32,
+        pgoff: libc::off_t,
+    ) -> Result<Self, Fail> {
+        let map_len = offsets.desc as usize + size as usize * mem::size_of::<T>();
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(os_error("mmap ring"));
+        }
+        let mut ring = unsafe { Self::new(map as *mut u8, offsets, size) };
+        ring.map = map;
+        ring.map_len = map_len;
+        ring.cached_prod = ring.producer().load(Ordering::Relaxed);
+        ring.cached_cons = ring.consumer().load(Ordering::Relaxed);
+        Ok(ring)
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { ptr::read_volatile(self.flags) & XDP_RING_NEED_WAKEUP != 0 }
+    }
+
+    /// Free slots for a producer, compared against the consumer index the kernel last published.
+    fn free(&mut self) -> u32 {
+        let free = self.size - self.cached_prod.wrapping_sub(self.cached_cons);
+        if free > 0 {
+            return free;
+        }
+        self.cached_cons = self.consumer().load(Ordering::Acquire);
+        self.size - self.cached_prod.wrapping_sub(self.cached_cons)
+    }
+
+    /// Queue up `desc` as a producer. It isn't visible to the kernel until `submit`.
+    fn push(&mut self, desc: T) -> bool {
+        if self.free() == 0 {
+            return false;
+        }
+        unsafe { *self.descs.add((self.cached_prod & self.mask) as usize) = desc };
+        self.cached_prod = self.cached_prod.wrapping_add(1);
+        true
+    }
+
+    fn submit(&self) {
+        self.producer().store(self.cached_prod, Ordering::Release);
+    }
+
+    /// Take the next entry as a consumer. It isn't returned to the kernel until `release`.
+    fn pop(&mut self) -> Option<T> {
+        if self.cached_cons == self.cached_prod {
+            self.cached_prod = self.producer().load(Ordering::Acquire);
+            if self.cached_cons == self.cached_prod {
+                return None;
+            }
+        }
+        let desc = unsafe { *self.descs.add((self.cached_cons & self.mask) as usize) };
+        self.cached_cons = self.cached_cons.wrapping_add(1);
+        Some(desc)
+    }
+
+    fn release(&self) {
+        self.consumer().store(self.cached_cons, Ordering::Release);
+    }
+}
+
+impl<T: Copy> Drop for Ring<T> {
+    fn drop(&mut self) {
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map, self.map_len) };
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct XdpOptions {
+    pub ifname: String,
+    pub queue_id: u32,
+    /// Number of UMEM frames, split evenly between receive and transmit.
+    pub num_frames: u32,
+    pub frame_size: u32,
+    /// Entries in each of the four rings; must be a power of two.
+    pub ring_size: u32,
+    /// Require zero-copy mode instead of letting the kernel fall back to copying.
+    pub zero_copy: bool,
+    /// Busy-poll the device queue from `receive` for up to this long (and this many packets)
+    /// instead of waiting on interrupts. Needs Linux 5.11.
+    pub busy_poll: Option<(Duration, u16)>,
+    /// An `XSKMAP` to register the socket in, keyed by `queue_id`.
+    pub xsks_map: Option<RawFd>,
+}
+
+impl XdpOptions {
+    pub fn new(ifname: &str, queue_id: u32) -> Self {
+        Self {
+            ifname: ifname.to_string(),
+            queue_id,
+            num_frames: 4096,
+            frame_size: 2048,
+            ring_size: 2048,
+            zero_copy: false,
+            busy_poll: None,
+            xsks_map: None,
+        }
+    }
+
+    pub fn num_frames(mut self, value: u32) -> Self {
+        assert!(value >= 2);
+        self.num_frames = value;
+        self
+    }
+
+    pub fn frame_size(mut self, value: u32) -> Self {
+        assert!(value.is_power_of_two() && value >= 2048);
+        self.frame_size = value;
+        self
+    }
+
+    pub fn ring_size(mut self, value: u32) -> Self {
+        assert!(value.is_power_of_two());
+        self.ring_size = value;
+        self
+    }
+
+    pub fn zero_copy(mut self, value: bool) -> Self {
+        self.zero_copy = value;
+        self
+    }
+
+    pub fn busy_poll(mut self, timeout: Duration, budget: u16) -> Self {
+        self.busy_poll = Some((timeout, budget));
+        self
+    }
+
+    pub fn xsks_map(mut self, fd: RawFd) -> Self {
+        self.xsks_map = Some(fd);
+        self
+    }
+}
+
+pub struct XdpDevice {
+    fd: RawFd,
+    umem: *mut u8,
+    umem_len: usize,
+    frame_size: u32,
+    busy_poll: bool,
+
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    rx: Ring<XdpDesc>,
+    tx: Ring<XdpDesc>,
+    // UMEM addresses of transmit frames we own.
+    tx_free: Vec<u64>,
+}
+
+impl XdpDevice {
+    pub fn open(options: &XdpOptions) -> Result<Self, Fail> {
+        let ifname = CString::new(options.ifname.clone()).map_err(|_| Fail::Invalid {
+            details: "Invalid interface name",
+        })?;
+        let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(os_error("if_nametoindex"));
+        }
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(os_error("socket"));
+        }
+        let umem_len = options.num_frames as usize * options.frame_size as usize;
+        let umem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            let e = os_error("mmap UMEM");
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        // From here on, `Drop` for the partially set up `XdpDevice` cleans up after us.
+        let mut device = Self {
+            fd,
+            umem: umem as *mut u8,
+            umem_len,
+            frame_size: options.frame_size,
+            busy_poll: options.busy_poll.is_some(),
+            fill: unsafe { Ring::new(ptr::null_mut(), &XdpRingOffset::default(), 1) },
+            completion: unsafe { Ring::new(ptr::null_mut(), &XdpRingOffset::default(), 1) },
+            rx: unsafe { Ring::new(ptr::null_mut(), &XdpRingOffset::default(), 1) },
+            tx: unsafe { Ring::new(ptr::null_mut(), &XdpRingOffset::default(), 1) },
+            tx_free: vec![],
+        };
+        device.setup(options, ifindex)?;
+        info!(
+            "Opened AF_XDP socket on {} queue {}",
+            options.ifname, options.queue_id
+        );
+        Ok(device)
+    }
+
+    fn setsockopt<T>(&self, level: c_int, name: c_int, value: &T, what: &str) -> Result<(), Fail> {
+        let r = unsafe {
+            libc::setsockopt(
+                self.fd,
+                level,
+                name,
+                value as *const T as *const c_void,
+                mem::size_of::<T>() as socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(os_error(what));
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self, options: &XdpOptions, ifindex: u32) -> Result<(), Fail> {
+        let reg = XdpUmemReg {
+            addr: self.umem as u64,
+            len: self.umem_len as u64,
+            chunk_size: options.frame_size,
+            headroom: 0,
+            flags: 0,
+        };
+        self.setsockopt(SOL_XDP, XDP_UMEM_REG, &reg, "XDP_UMEM_REG")?;
+        let ring_size = options.ring_size;
+        for &(name, what) in &[
+            (XDP_UMEM_FILL_RING, "XDP_UMEM_FILL_RING"),
+            (XDP_UMEM_COMPLETION_RING, "XDP_UMEM_COMPLETION_RING"),
+            (XDP_RX_RING, "XDP_RX_RING"),
+            (XDP_TX_RING, "XDP_TX_RING"),
+        ] {
+            self.setsockopt(SOL_XDP, name, &ring_size, what)?;
+        }
+
+        let mut offsets = XdpMmapOffsets::default();
+        let mut len = mem::size_of::<XdpMmapOffsets>() as socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                self.fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut _ as *mut c_void,
+                &mut len,
+            )
+        };
+        if r < 0 {
+            return Err(os_error("XDP_MMAP_OFFSETS"));
+        }
+        self.fill = Ring::mmap(self.fd, &offsets.fr, ring_size, XDP_UMEM_PGOFF_FILL_RING)?;
+        self.completion = Ring::mmap(
+            self.fd,
+            &offsets.cr,
+            ring_size,
+            XDP_UMEM_PGOFF_COMPLETION_RING,
+        )?;
+        self.rx = Ring::mmap(self.fd, &offsets.rx, ring_size, XDP_PGOFF_RX_RING)?;
+        self.tx = Ring::mmap(self.fd, &offsets.tx, ring_size, XDP_PGOFF_TX_RING)?;
+
+        let rx_frames = options.num_frames / 2;
+        for i in 0..rx_frames {
+            if !self.fill.push(i as u64 * self.frame_size as u64) {
+                // The fill ring is smaller than the receive half of the UMEM; keep the rest for
+                // transmit.
+                self.tx_free.push(i as u64 * self.frame_size as u64);
+            }
+        }
+        self.fill.submit();
+        for i in rx_frames..options.num_frames {
+            self.tx_free.push(i as u64 * self.frame_size as u64);
+        }
+
+        if let Some((timeout, budget)) = options.busy_poll {
+            let one: c_int = 1;
+            let usecs = timeout.as_micros() as c_int;
+            let budget = budget as c_int;
+            self.setsockopt(
+                libc::SOL_SOCKET,
+                SO_PREFER_BUSY_POLL,
+                &one,
+                "SO_PREFER_BUSY_POLL",
+            )?;
+            self.setsockopt(libc::SOL_SOCKET, SO_BUSY_POLL, &usecs, "SO_BUSY_POLL")?;
+            self.setsockopt(
+                libc::SOL_SOCKET,
+                SO_BUSY_POLL_BUDGET,
+                &budget,
+                "SO_BUSY_POLL_BUDGET",
+            )?;
+        }
+
+        // Without either flag the kernel uses zero-copy if the driver supports it.
+        let mode = if options.zero_copy { XDP_ZEROCOPY } else { 0 };
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: mode | XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: options.queue_id,
+            sxdp_shared_umem_fd: 0,
+        };
+        let r = unsafe {
+            libc::bind(
+                self.fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(os_error("bind"));
+        }
+
+        if let Some(map_fd) = options.xsks_map {
+            let key = options.queue_id;
+            let value = self.fd as u32;
+            let attr = BpfMapUpdate {
+                map_fd: map_fd as u32,
+                _pad: 0,
+                key: &key as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            };
+            let r = unsafe {
+                libc::syscall(
+                    libc::SYS_bpf,
+                    BPF_MAP_UPDATE_ELEM,
+                    &attr as *const BpfMapUpdate,
+                    mem::size_of::<BpfMapUpdate>(),
+                )
+            };
+            if r < 0 {
+                return Err(os_error("XSKMAP update"));
+            }
+        }
+        Ok(())
+    }
+
+    fn kick(&self) {
+        let r =
+            unsafe { libc::sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => (),
+                _ => warn!("AF_XDP TX wakeup failed: {}", e),
+            }
+        }
+    }
+
+    fn reclaim_tx(&mut self) {
+        let mut reclaimed = false;
+        while let Some(addr) = self.completion.pop() {
+            self.tx_free.push(addr);
+            reclaimed = true;
+        }
+        if reclaimed {
+            self.completion.release();
+        }
+    }
+
+    fn frame(&mut self, addr: u64, len: usize) -> &mut [u8] {
+        assert!(addr as usize + len <= self.umem_len);
+        unsafe { slice::from_raw_parts_mut(self.umem.add(addr as usize), len) }
+    }
+}
+
+impl NetworkDevice for XdpDevice {
+    fn transmit(&mut self, frame: &[u8]) {
+        if frame.len() > self.frame_size as usize {
+            warn!(
+                "Dropping {} byte frame larger than UMEM frames",
+                frame.len()
+            );
+            return;
+        }
+        self.reclaim_tx();
+        let addr = match self.tx_free.pop() {
+            Some(addr) => addr,
+            None => {
+                warn!("AF_XDP TX frames exhausted, dropping frame");
+                return;
+            },
+        };
+        self.frame(addr, frame.len()).copy_from_slice(frame);
+        let desc = XdpDesc {
+            addr,
+            len: frame.len() as u32,
+            options: 0,
+        };
+        if !self.tx.push(desc) {
+            self.tx_free.push(addr);
+            warn!("AF_XDP TX ring full, dropping frame");
+            return;
+        }
+        self.tx.submit();
+        if self.tx.needs_wakeup() {
+            self.kick();
+        }
+    }
+
+    fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+        if self.busy_poll || self.fill.needs_wakeup() {
+            unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+        }
+        let mut received = false;
+        while !batch.is_full() {
+            let desc = match self.rx.pop() {
+                Some(d) => d,
+                None => break,
+            };
+            received = true;
+            let buf = Bytes::from_slice(self.frame(desc.addr, desc.len as usize));
+            batch.push(buf);
+            // We always consume at most as many RX descriptors as we put in the fill ring, so
+            // there's room to hand the frame straight back.
+            assert!(self
+                .fill
+                .push(desc.addr - desc.addr % self.frame_size as u64));
+        }
+        if received {
+            self.rx.release();
+            self.fill.submit();
+        }
+        self.reclaim_tx();
+    }
+}
+
+impl AsRawFd for XdpDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for XdpDevice {
+    fn drop(&mut self) {
+        // The rings unmap themselves.
+        unsafe {
+            libc::close(self.fd);
+            libc::munmap(self.umem as *mut c_void, self.umem_len);
+        }
+    }
+}
+
+pub type XdpRuntime = DeviceRuntime<XdpDevice>;
+
+/// Open an AF_XDP socket and wrap it in a runtime for the engine configured by `options`.
+pub fn runtime(xdp: &XdpOptions, options: Options) -> Result<XdpRuntime, Fail> {
+    let device = XdpDevice::open(xdp)?;
+    Ok(DeviceRuntime::new(device, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Ring,
+        XdpDesc,
+        XdpRingOffset,
+    };
+    use std::mem;
+
+    // Lay out a ring like the kernel does: producer, consumer and flags words, then descriptors.
+    fn ring_pair(buf: &mut Vec<u64>, size: u32) -> (Ring<XdpDesc>, Ring<XdpDesc>) {
+        let offsets = XdpRingOffset {
+            producer: 0,
+            consumer: 8,
+            flags: 16,
+            desc: 24,
+        };
+        let words = 3 + size as usize * mem::size_of::<XdpDesc>() / 8;
+        buf.resize(words, 0);
+        let base = buf.as_mut_ptr() as *mut u8;
+        unsafe {
+            (
+                Ring::new(base, &offsets, size),
+                Ring::new(base, &offsets, size),
+            )
+        }
+    }
+
+    #[test]
+    fn ring_wraparound() {
+        let mut buf = vec![];
+        let (mut producer, mut consumer) = ring_pair(&mut buf, 4);
+        for round in 0..10u64 {
+            for i in 0..4 {
+                let desc = XdpDesc {
+                    addr: round * 4 + i,
+                    len: 60,
+                    options: 0,
+                };
+                assert!(producer.push(desc));
+            }
+            // A full ring refuses more entries, and a consumer sees nothing until `submit`.
+            assert!(!producer.push(XdpDesc::default()));
+            assert_eq!(consumer.pop(), None);
+            producer.submit();
+
+            for i in 0..4 {
+                assert_eq!(consumer.pop().unwrap().addr, round * 4 + i);
+            }
+            assert_eq!(consumer.pop(), None);
+            // The space only frees up once the consumer releases it.
+            assert!(!producer.push(XdpDesc::default()));
+            consumer.release();
+        }
+    }
+}
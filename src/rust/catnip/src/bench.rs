@@ -0,0 +1,256 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Throughput benchmarks over two simulated engines, built with the `bench` feature.
+//!
+//! Alice and Bob from `test_helpers` are wired back to back, so these measure the engine itself
+//! (demultiplexing, the scheduler and its coroutines, buffer handling) rather than any device.
+//! `benches/engine.rs` runs them under criterion and prints allocation counts, which only mean
+//! anything when the binary's global allocator is a `CountingAllocator`.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    test_helpers::{
+        self,
+        TestEngine,
+    },
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    alloc::{
+        GlobalAlloc,
+        Layout,
+        System,
+    },
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+static NUM_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting every allocation it makes. Install it in a benchmark binary
+/// with `#[global_allocator]`.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        NUM_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        NUM_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Allocations made through `CountingAllocator` so far, in every thread.
+pub fn num_allocations() -> u64 {
+    NUM_ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// The outcome of one measurement. `elapsed` covers only the work being measured, not setup.
+#[derive(Clone, Copy, Debug)]
+pub struct Report {
+    pub iterations: u64,
+    pub elapsed: Duration,
+    pub allocations: u64,
+}
+
+impl Report {
+    pub fn per_second(&self) -> f64 {
+        self.iterations as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn allocations_per_iteration(&self) -> f64 {
+        self.allocations as f64 / self.iterations as f64
+    }
+}
+
+const LISTEN_PORT: u16 = 80;
+
+/// Alice and Bob, with Bob listening.
+pub struct Pair {
+    pub alice: TestEngine,
+    pub bob: TestEngine,
+    listen_fd: FileDescriptor,
+}
+
+impl Pair {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        let alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        let listen_fd = bob.tcp_socket();
+        bob.tcp_bind(listen_fd, Self::listen_addr()).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+        Self {
+            alice,
+            bob,
+            listen_fd,
+        }
+    }
+
+    fn listen_addr() -> ipv4::Endpoint {
+        ipv4::Endpoint::new(
+            test_helpers::BOB_IPV4,
+            ip::Port::try_from(LISTEN_PORT).unwrap(),
+        )
+    }
+
+    /// Run both engines and trade frames until neither has anything left to send.
+    pub fn pump(&mut self) {
+        loop {
+            self.alice.rt().poll_scheduler();
+            self.bob.rt().poll_scheduler();
+            if self.alice.rt().num_outgoing() == 0 && self.bob.rt().num_outgoing() == 0 {
+                break;
+            }
+            while self.alice.rt().num_outgoing() > 0 {
+                self.bob.receive(self.alice.rt().pop_frame()).unwrap();
+            }
+            while self.bob.rt().num_outgoing() > 0 {
+                self.alice.receive(self.bob.rt().pop_frame()).unwrap();
+            }
+        }
+    }
+
+    /// Open a connection from Alice to Bob, returning Alice's and Bob's ends.
+    pub fn connect(&mut self) -> (FileDescriptor, FileDescriptor) {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut accept_future = self.bob.tcp_accept(self.listen_fd);
+        let alice_fd = self.alice.tcp_socket();
+        let mut connect_future = self.alice.tcp_connect(alice_fd, Self::listen_addr());
+        self.pump();
+        must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+        (alice_fd, bob_fd)
+    }
+
+    /// Close both ends and let the FINs play out.
+    pub fn close(&mut self, alice_fd: FileDescriptor, bob_fd: FileDescriptor) {
+        self.alice.tcp_close(alice_fd).unwrap();
+        self.bob.tcp_close(bob_fd).unwrap();
+        self.pump();
+    }
+
+    /// One segment each way: Alice sends `buf` to Bob, who sends it back.
+    pub fn round_trip(&mut self, alice_fd: FileDescriptor, bob_fd: FileDescriptor, buf: &Bytes) {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut push_future = self.alice.tcp_push(alice_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        self.alice.rt().poll_scheduler();
+        self.bob.receive(self.alice.rt().pop_frame()).unwrap();
+        let mut pop_future = self.bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+        let mut push_future = self.bob.tcp_push(bob_fd, received);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        self.bob.rt().poll_scheduler();
+        self.alice.receive(self.bob.rt().pop_frame()).unwrap();
+        let mut pop_future = self.alice.tcp_pop(alice_fd);
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    }
+}
+
+impl Default for Pair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Connections left in TIME_WAIT hold on to their local ports, so `handshakes` starts over with a
+// fresh pair of engines this often.
+const HANDSHAKES_PER_PAIR: u64 = 1000;
+
+/// Time `count` three-way handshakes. Closing the connections isn't included.
+pub fn handshakes(count: u64) -> Report {
+    let mut pair = Pair::new();
+    let mut elapsed = Duration::new(0, 0);
+    let mut allocations = 0;
+    for i in 0..count {
+        if i > 0 && i % HANDSHAKES_PER_PAIR == 0 {
+            pair = Pair::new();
+        }
+        let allocations_before = num_allocations();
+        let start = Instant::now();
+        let (alice_fd, bob_fd) = pair.connect();
+        elapsed += start.elapsed();
+        allocations += num_allocations() - allocations_before;
+        pair.close(alice_fd, bob_fd);
+    }
+    Report {
+        iterations: count,
+        elapsed,
+        allocations,
+    }
+}
+
+/// Time `count` data segments of `size` bytes over one connection, half of them each way.
+pub fn segments(count: u64, size: usize) -> Report {
+    let mut pair = Pair::new();
+    let (alice_fd, bob_fd) = pair.connect();
+    let mut buf = BytesMut::zeroed(size);
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let buf = buf.freeze();
+
+    let rounds = (count + 1) / 2;
+    let allocations_before = num_allocations();
+    let start = Instant::now();
+    for _ in 0..rounds {
+        pair.round_trip(alice_fd, bob_fd, &buf);
+    }
+    let elapsed = start.elapsed();
+    Report {
+        iterations: rounds * 2,
+        elapsed,
+        allocations: num_allocations() - allocations_before,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        handshakes,
+        segments,
+    };
+
+    #[test]
+    fn harness() {
+        // Enough handshakes to need a second pair of engines.
+        let report = handshakes(super::HANDSHAKES_PER_PAIR + 5);
+        assert_eq!(report.iterations, super::HANDSHAKES_PER_PAIR + 5);
+
+        let report = segments(11, 1024);
+        assert_eq!(report.iterations, 12);
+        assert!(report.per_second() > 0.0);
+    }
+}
@@ -165,16 +165,13 @@ where
         self.insert_with_ttl(key, value, self.default_ttl)
     }
 
-    pub fn remove(&mut self, _key: &K) -> Option<V> {
-        // if let Some(ref record) = self.map.remove(key) {
-        //     if let Some(ref expiry) = record.expiry {
-        //         if !expiry.has_expired(self.clock) {
-        //             return Some(record.value.clone());
-        //         }
-        //     }
-        // }
-
-        None
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        // The key's tombstone stays in the graveyard until it comes due.
+        let record = self.map.remove(key)?;
+        match record.expiry {
+            Some(ref expiry) if expiry.has_expired(self.clock) => None,
+            _ => Some(record.value),
+        }
     }
 
     pub fn get(&self, key: &K) -> Option<&V>
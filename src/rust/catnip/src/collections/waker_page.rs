@@ -63,6 +63,11 @@ impl WakerPage {
         notified
     }
 
+    /// Whether any future on the page has been woken and not yet polled.
+    pub fn has_notified(&self) -> bool {
+        self.notified.load() & !self.completed.load() & !self.dropped.load() != 0
+    }
+
     pub fn has_completed(&self, ix: usize) -> bool {
         debug_assert!(ix < 64);
         self.completed.load() & (1 << ix) != 0
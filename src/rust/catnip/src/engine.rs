@@ -2,70 +2,183 @@
 // Licensed under the MIT license.
 
 use crate::{
+    event::{
+        Event,
+        EventQueue,
+        LabeledEvent,
+    },
     fail::Fail,
     file_table::{
         File,
         FileDescriptor,
         FileTable,
     },
+    fmt,
+    logging,
+    metrics::{
+        MetricsExporter,
+        MetricsSink,
+    },
     operations::ResultFuture,
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
+        dhcp,
+        dns,
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+            },
+            MacAddress,
         },
+        gre,
+        icmpv4,
+        ip,
         ipv4,
+        ipv4::Ipv4Protocol2,
+        ipv6,
+        nat,
+        sntp,
+        tcp,
         tcp::operations::{
             AcceptFuture,
             ConnectFuture,
             PopFuture,
             PushFuture,
         },
+        udp,
         udp::peer::{
             PopFuture as UdpPopFuture,
+            RecvMsgFuture,
             UdpOperation,
         },
+        vxlan,
+    },
+    replay::{
+        Input,
+        Log,
+        Recorder,
     },
     runtime::Runtime,
     scheduler::Operation,
+    snapshot::EngineSnapshot,
+    stats::{
+        Stats,
+        TcpGroupStats,
+        TcpLatencyStats,
+        TcpListenerStats,
+        TcpThroughputStats,
+    },
 };
 use std::{
+    collections::HashMap,
+    convert::TryFrom,
     future::Future,
-    net::Ipv4Addr,
-    time::Duration,
+    net::{
+        Ipv4Addr,
+        Ipv6Addr,
+        Shutdown,
+    },
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
 };
 use tracy_client::static_span;
 
-#[cfg(test)]
-use crate::protocols::ethernet2::MacAddress;
-#[cfg(test)]
-use std::collections::HashMap;
 
 pub struct Engine<RT: Runtime> {
     rt: RT,
     arp: arp::Peer<RT>,
     ipv4: ipv4::Peer<RT>,
+    ipv6: ipv6::Peer<RT>,
 
     file_table: FileTable,
+
+    recorder: Option<Recorder>,
+    sntp: Option<sntp::Client<RT>>,
+    dhcp: Option<dhcp::Client<RT>>,
+    dns: Option<dns::Resolver<RT>>,
+    udp_services: Vec<udp::Service<RT>>,
+    vxlan: Option<vxlan::Vtep<RT>>,
+    nat: Option<nat::Nat<RT>>,
+    path_monitor: Option<icmpv4::PathMonitor>,
+    events: EventQueue,
+    rx_dropped: u64,
+    metrics: Option<MetricsExporter>,
+    label: Option<Rc<str>>,
+    // When a frame last arrived or the scheduler last had something to run.
+    last_active: Instant,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Protocol {
     Tcp,
     Udp,
 }
 
+/// Whether an engine has anything to do, for poll-mode drivers choosing between busy-polling and
+/// sleeping until a frame arrives or `next_deadline`. See `Engine::idle_hint`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IdleHint {
+    /// How long since a frame last arrived or a coroutine last ran.
+    pub idle_for: Duration,
+    /// Whether a coroutine is ready to run, so the engine should be polled right away.
+    pub runnable: bool,
+    /// TCP connections with data queued or in flight, whose ACKs should be picked up promptly.
+    pub active_connections: usize,
+    /// When a timer next needs the engine, which a sleeping driver should wake by.
+    pub next_deadline: Option<Instant>,
+}
+
+impl IdleHint {
+    /// Whether the driver can stop busy-polling: nothing's runnable, no connection has data
+    /// moving, and the engine has been idle for at least `threshold`.
+    pub fn can_sleep(&self, threshold: Duration) -> bool {
+        !self.runnable && self.active_connections == 0 && self.idle_for >= threshold
+    }
+}
+
 impl<RT: Runtime> Engine<RT> {
     pub fn new(rt: RT) -> Result<Self, Fail> {
         let now = rt.now();
         let file_table = FileTable::new();
         let arp = arp::Peer::new(now, rt.clone())?;
-        let ipv4 = ipv4::Peer::new(rt.clone(), arp.clone(), file_table.clone());
+        let events = EventQueue::new();
+        let ipv4 = ipv4::Peer::new(rt.clone(), arp.clone(), file_table.clone(), events.clone());
+        let ipv6 = ipv6::Peer::new(rt.clone());
+        let udp_options = rt.udp_options();
+        let mut udp_services = vec![];
+        for &(kind, port) in &[
+            (udp::ServiceKind::Echo, udp_options.echo_port),
+            (udp::ServiceKind::Discard, udp_options.discard_port),
+        ] {
+            if let Some(port) = port {
+                let service = udp::Service::new(rt.clone(), ipv4.udp.clone(), kind, port)?;
+                udp_services.push(service);
+            }
+        }
         Ok(Engine {
             rt,
             arp,
             ipv4,
+            ipv6,
             file_table,
+            recorder: None,
+            sntp: None,
+            dhcp: None,
+            dns: None,
+            udp_services,
+            vxlan: None,
+            nat: None,
+            path_monitor: None,
+            events,
+            rx_dropped: 0,
+            metrics: None,
+            label: None,
+            last_active: now,
         })
     }
 
@@ -73,18 +186,158 @@ impl<RT: Runtime> Engine<RT> {
         &self.rt
     }
 
+    /// Name this engine, for processes running several, so what it reports can be told apart:
+    /// events taken with `take_labeled_events` carry the label, and lines it logs while receiving,
+    /// polling or advancing its clock lead with it when logging was set up through
+    /// `logging::initialize`.
+    pub fn set_label(&mut self, label: &str) {
+        let label: Rc<str> = label.into();
+        self.events.set_label(Some(label.clone()));
+        self.label = Some(label);
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Start recording every input to the engine into a replay log. See `crate::replay`.
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(Recorder::new(self.rt.now()));
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Log> {
+        self.recorder.take().map(|r| r.finish())
+    }
+
+    fn record(&mut self, input: impl FnOnce() -> Input) {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record(input());
+        }
+    }
+
+    pub fn advance_clock(&mut self, now: Instant) {
+        let _label = logging::enter_engine(self.label.clone());
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_clock(now);
+        }
+        self.rt.advance_clock(now);
+        let due = match self.metrics {
+            Some(ref metrics) => metrics.is_due(now),
+            None => false,
+        };
+        if due {
+            let stats = self.stats();
+            if let Some(ref mut metrics) = self.metrics {
+                metrics.export(now, &stats);
+            }
+        }
+        let summaries_due = match self.metrics {
+            Some(ref metrics) => metrics.summaries_due(now),
+            None => false,
+        };
+        if summaries_due {
+            let summaries = self.ipv4.tcp.connection_summaries();
+            if let Some(ref mut metrics) = self.metrics {
+                metrics.export_summaries(now, &summaries);
+            }
+        }
+    }
+
+    pub fn poll_scheduler(&mut self) {
+        let _label = logging::enter_engine(self.label.clone());
+        self.record(|| Input::PollScheduler);
+        if self.rt.scheduler().poll() > 0 {
+            self.last_active = self.rt.now();
+        }
+    }
+
+    /// One turn of a driver loop: advance the clock to `now`, run whatever that made ready, and
+    /// return the events raised along with when the engine next needs to be polled. The deadline
+    /// is `None` when nothing is pending but new frames or calls from the application.
+    pub fn poll(&mut self, now: Instant) -> (Vec<Event>, Option<Instant>) {
+        self.advance_clock(now);
+        self.poll_scheduler();
+        (self.events.take(), self.next_deadline())
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let deadline = self.rt.next_expiry();
+        match self.metrics {
+            Some(ref metrics) => {
+                let due = metrics.next_due();
+                Some(deadline.map_or(due, |d| d.min(due)))
+            },
+            None => deadline,
+        }
+    }
+
+    /// Whether the engine has had anything to do lately. See `IdleHint`.
+    pub fn idle_hint(&self) -> IdleHint {
+        IdleHint {
+            idle_for: self.rt.now().saturating_duration_since(self.last_active),
+            runnable: self.rt.scheduler().has_runnable(),
+            active_connections: self.ipv4.tcp.num_active(),
+            next_deadline: self.next_deadline(),
+        }
+    }
+
     pub fn receive(&mut self, bytes: RT::Buf) -> Result<(), Fail> {
+        self.record(|| Input::Receive {
+            frame: bytes[..].to_vec(),
+        });
+        let now = self.rt.now();
+        self.receive_frame(bytes, now)
+    }
+
+    /// Receive a frame the device stamped on arrival, in hardware or as soon as the driver saw
+    /// it, on the runtime's clock. TCP measures RTTs (and when data arrived) from `timestamp`
+    /// rather than from when the engine got around to processing the frame.
+    pub fn receive_with_timestamp(
+        &mut self,
+        bytes: RT::Buf,
+        timestamp: Instant,
+    ) -> Result<(), Fail> {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_timestamped_receive(&bytes[..], timestamp);
+        }
+        self.receive_frame(bytes, timestamp)
+    }
+
+    fn receive_frame(&mut self, bytes: RT::Buf, rx_time: Instant) -> Result<(), Fail> {
         let _s = static_span!();
+        let _label = logging::enter_engine(self.label.clone());
+        debug!("Engine received {}", fmt::Summary(&bytes[..]));
+        self.last_active = self.rt.now();
+        let result = self.dispatch(bytes, rx_time);
+        if result.is_err() {
+            self.rx_dropped += 1;
+        }
+        result
+    }
+
+    fn dispatch(&mut self, bytes: RT::Buf, rx_time: Instant) -> Result<(), Fail> {
         let (header, payload) = Ethernet2Header::parse(bytes)?;
-        debug!("Engine received {:?}", header);
-        if self.rt.local_link_addr() != header.dst_addr && !header.dst_addr.is_broadcast() {
+        if self.rt.local_link_addr() != header.dst_addr
+            && !header.dst_addr.is_broadcast()
+            && !self.ipv6.accepts(header.dst_addr)
+        {
             return Err(Fail::Ignored {
                 details: "Physical dst_addr mismatch",
             });
         }
         match header.ether_type {
             EtherType2::Arp => self.arp.receive(payload),
-            EtherType2::Ipv4 => self.ipv4.receive(payload),
+            EtherType2::Ipv4 => {
+                let payload = match self.nat {
+                    Some(ref mut nat) => match nat.receive(payload)? {
+                        Some(payload) => payload,
+                        None => return Ok(()),
+                    },
+                    None => payload,
+                };
+                self.ipv4.receive(payload, rx_time)
+            },
+            EtherType2::Ipv6 => self.ipv6.receive(payload),
         }
     }
 
@@ -96,11 +349,34 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.ping(dest_ipv4_addr, timeout)
     }
 
+    pub fn ping_with_payload(
+        &self,
+        dest_ipv4_addr: Ipv4Addr,
+        payload: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<Duration, Fail>> {
+        self.ipv4
+            .ping_with_payload(dest_ipv4_addr, payload, timeout)
+    }
+
+    /// Ping a set of destinations, `concurrency` at a time, e.g. to check a testbed's topology.
+    /// See `icmpv4::Peer::ping_many`.
+    pub fn ping_many(
+        &self,
+        destinations: &[Ipv4Addr],
+        concurrency: usize,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Vec<(Ipv4Addr, Result<Duration, Fail>)>> {
+        self.ipv4.ping_many(destinations, concurrency, timeout)
+    }
+
     pub fn socket(&mut self, protocol: Protocol) -> FileDescriptor {
-        match protocol {
+        let fd = match protocol {
             Protocol::Tcp => self.ipv4.tcp.socket(),
             Protocol::Udp => self.ipv4.udp.socket(),
-        }
+        };
+        self.record(|| Input::Socket { protocol, fd });
+        fd
     }
 
     pub fn connect(
@@ -108,6 +384,10 @@ impl<RT: Runtime> Engine<RT> {
         fd: FileDescriptor,
         remote_endpoint: ipv4::Endpoint,
     ) -> Operation<RT> {
+        self.record(|| Input::Connect {
+            fd,
+            remote: remote_endpoint,
+        });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.connect(fd, remote_endpoint)),
             Some(File::UdpSocket) => {
@@ -119,6 +399,7 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn bind(&mut self, fd: FileDescriptor, endpoint: ipv4::Endpoint) -> Result<(), Fail> {
+        self.record(|| Input::Bind { fd, endpoint });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => self.ipv4.tcp.bind(fd, endpoint),
             Some(File::UdpSocket) => self.ipv4.udp.bind(fd, endpoint),
@@ -127,6 +408,7 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn accept(&mut self, fd: FileDescriptor) -> Operation<RT> {
+        self.record(|| Input::Accept { fd });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.accept(fd)),
             Some(File::UdpSocket) => {
@@ -138,6 +420,7 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn listen(&mut self, fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
+        self.record(|| Input::Listen { fd, backlog });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => self.ipv4.tcp.listen(fd, backlog),
             Some(File::UdpSocket) => Err(Fail::Malformed {
@@ -148,6 +431,10 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn push(&mut self, fd: FileDescriptor, buf: RT::Buf) -> Operation<RT> {
+        self.record(|| Input::Push {
+            fd,
+            data: buf[..].to_vec(),
+        });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.push(fd, buf)),
             Some(File::UdpSocket) => {
@@ -159,6 +446,11 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn pushto(&mut self, fd: FileDescriptor, buf: RT::Buf, to: ipv4::Endpoint) -> Operation<RT> {
+        self.record(|| Input::Pushto {
+            fd,
+            data: buf[..].to_vec(),
+            to,
+        });
         match self.file_table.get(fd) {
             Some(File::UdpSocket) => {
                 let udp_op = UdpOperation::Push(fd, self.ipv4.udp.pushto(fd, buf, to));
@@ -169,14 +461,70 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn udp_push(&mut self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
+        self.record(|| Input::UdpPush {
+            fd,
+            data: buf[..].to_vec(),
+        });
         self.ipv4.udp.push(fd, buf)
     }
 
+    pub fn udp_pushto(
+        &mut self,
+        fd: FileDescriptor,
+        buf: RT::Buf,
+        to: ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        self.record(|| Input::UdpPushto {
+            fd,
+            data: buf[..].to_vec(),
+            to,
+        });
+        self.ipv4.udp.pushto(fd, buf, to)
+    }
+
     pub fn udp_pop(&mut self, fd: FileDescriptor) -> UdpPopFuture<RT> {
+        self.record(|| Input::UdpPop { fd });
         self.ipv4.udp.pop(fd)
     }
 
+    /// `udp_pushto` with GSO, ECN marking, and pacing.
+    pub fn udp_sendmsg(
+        &mut self,
+        fd: FileDescriptor,
+        buf: RT::Buf,
+        to: ipv4::Endpoint,
+        meta: udp::TxMeta,
+    ) -> Result<(), Fail> {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record_udp_sendmsg(fd, &buf[..], to, &meta);
+        }
+        self.ipv4.udp.sendmsg(fd, buf, to, meta)
+    }
+
+    pub fn udp_recvmsg(&mut self, fd: FileDescriptor) -> RecvMsgFuture<RT> {
+        self.record(|| Input::UdpRecvmsg { fd });
+        self.ipv4.udp.recvmsg(fd)
+    }
+
+    pub fn udp_set_gso(
+        &self,
+        fd: FileDescriptor,
+        segment_size: Option<usize>,
+    ) -> Result<(), Fail> {
+        self.ipv4.udp.set_gso(fd, segment_size)
+    }
+
+    pub fn udp_set_gro(&self, fd: FileDescriptor, enabled: bool) -> Result<(), Fail> {
+        self.ipv4.udp.set_gro(fd, enabled)
+    }
+
+    /// The built-in services `udp::Options` asked for.
+    pub fn udp_services(&self) -> Vec<udp::ServiceStats> {
+        self.udp_services.iter().map(|s| s.stats()).collect()
+    }
+
     pub fn pop(&mut self, fd: FileDescriptor) -> Operation<RT> {
+        self.record(|| Input::Pop { fd });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => Operation::from(self.ipv4.tcp.pop(fd)),
             Some(File::UdpSocket) => {
@@ -192,6 +540,7 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn close(&mut self, fd: FileDescriptor) -> Result<(), Fail> {
+        self.record(|| Input::Close { fd });
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => self.ipv4.tcp.close(fd),
             Some(File::UdpSocket) => self.ipv4.udp.close(fd),
@@ -200,7 +549,12 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn tcp_socket(&mut self) -> FileDescriptor {
-        self.ipv4.tcp.socket()
+        let fd = self.ipv4.tcp.socket();
+        self.record(|| Input::Socket {
+            protocol: Protocol::Tcp,
+            fd,
+        });
+        fd
     }
 
     pub fn tcp_connect(
@@ -208,50 +562,827 @@ impl<RT: Runtime> Engine<RT> {
         socket_fd: FileDescriptor,
         remote_endpoint: ipv4::Endpoint,
     ) -> ConnectFuture<RT> {
+        self.record(|| Input::Connect {
+            fd: socket_fd,
+            remote: remote_endpoint,
+        });
         self.ipv4.tcp.connect(socket_fd, remote_endpoint)
     }
 
+    /// Connect from a chosen local endpoint instead of an ephemeral port; see
+    /// `tcp::Peer::connect_from`.
+    pub fn tcp_connect_from(
+        &mut self,
+        socket_fd: FileDescriptor,
+        local_endpoint: ipv4::Endpoint,
+        remote_endpoint: ipv4::Endpoint,
+    ) -> ConnectFuture<RT> {
+        self.record(|| Input::ConnectFrom {
+            fd: socket_fd,
+            local: local_endpoint,
+            remote: remote_endpoint,
+        });
+        self.ipv4
+            .tcp
+            .connect_from(socket_fd, local_endpoint, remote_endpoint)
+    }
+
+    /// Connect to the first of `candidates` to answer; see `tcp::Peer::connect_any`.
+    pub fn tcp_connect_any(
+        &mut self,
+        candidates: &[ipv4::Endpoint],
+    ) -> impl Future<Output = Result<(FileDescriptor, ipv4::Endpoint), Fail>> {
+        self.record(|| Input::ConnectAny {
+            candidates: candidates.to_vec(),
+        });
+        self.ipv4.tcp.connect_any(candidates)
+    }
+
     pub fn tcp_bind(
         &mut self,
         socket_fd: FileDescriptor,
         endpoint: ipv4::Endpoint,
     ) -> Result<(), Fail> {
+        self.record(|| Input::Bind {
+            fd: socket_fd,
+            endpoint,
+        });
         self.ipv4.tcp.bind(socket_fd, endpoint)
     }
 
     pub fn tcp_accept(&mut self, handle: FileDescriptor) -> AcceptFuture<RT> {
+        self.record(|| Input::Accept { fd: handle });
         self.ipv4.tcp.accept(handle)
     }
 
     pub fn tcp_push(&mut self, socket_fd: FileDescriptor, buf: RT::Buf) -> PushFuture<RT> {
+        self.record(|| Input::Push {
+            fd: socket_fd,
+            data: buf[..].to_vec(),
+        });
         self.ipv4.tcp.push(socket_fd, buf)
     }
 
+    /// Push `bufs` back to back without joining them; see `tcp::Peer::writev`. It's recorded as a
+    /// plain push of everything together.
+    pub fn tcp_writev(&mut self, socket_fd: FileDescriptor, bufs: Vec<RT::Buf>) -> PushFuture<RT> {
+        self.record(|| Input::Push {
+            fd: socket_fd,
+            data: bufs.iter().flat_map(|b| b[..].iter().cloned()).collect(),
+        });
+        self.ipv4.tcp.writev(socket_fd, bufs)
+    }
+
+    /// Push `buf`, waiting for room if the connection's send buffer is full; see
+    /// `tcp::Peer::write`. It's recorded as a plain push.
+    pub fn tcp_write(
+        &mut self,
+        socket_fd: FileDescriptor,
+        buf: RT::Buf,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        self.record(|| Input::Push {
+            fd: socket_fd,
+            data: buf[..].to_vec(),
+        });
+        self.ipv4.tcp.write(socket_fd, buf)
+    }
+
+    /// Push `buf`, failing if it isn't all acknowledged by `deadline`; see
+    /// `tcp::Peer::write_with_deadline`. It's recorded as a plain push, so a reset for a missed
+    /// deadline isn't replayed.
+    pub fn tcp_write_with_deadline(
+        &mut self,
+        socket_fd: FileDescriptor,
+        buf: RT::Buf,
+        deadline: Instant,
+        abort_on_miss: bool,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        self.record(|| Input::Push {
+            fd: socket_fd,
+            data: buf[..].to_vec(),
+        });
+        self.ipv4
+            .tcp
+            .write_with_deadline(socket_fd, buf, deadline, abort_on_miss)
+    }
+
     pub fn tcp_pop(&mut self, socket_fd: FileDescriptor) -> PopFuture<RT> {
+        self.record(|| Input::Pop { fd: socket_fd });
         self.ipv4.tcp.pop(socket_fd)
     }
 
+    /// See `tcp::Peer::read_exact`.
+    pub fn tcp_read_exact(
+        &mut self,
+        socket_fd: FileDescriptor,
+        len: usize,
+    ) -> impl Future<Output = Result<RT::Buf, Fail>> {
+        self.record(|| Input::ReadExact { fd: socket_fd, len });
+        self.ipv4.tcp.read_exact(socket_fd, len)
+    }
+
+    /// See `tcp::Peer::read_up_to`.
+    pub fn tcp_read_up_to(
+        &mut self,
+        socket_fd: FileDescriptor,
+        max: usize,
+    ) -> impl Future<Output = Result<RT::Buf, Fail>> {
+        self.record(|| Input::ReadUpTo { fd: socket_fd, max });
+        self.ipv4.tcp.read_up_to(socket_fd, max)
+    }
+
+    /// See `tcp::Peer::readable`.
+    pub fn tcp_readable(
+        &self,
+        socket_fd: FileDescriptor,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        self.ipv4.tcp.readable(socket_fd)
+    }
+
+    /// See `tcp::Peer::peek_at`.
+    pub fn tcp_peek_at(
+        &self,
+        socket_fd: FileDescriptor,
+        offset: usize,
+        len: usize,
+    ) -> Result<RT::Buf, Fail> {
+        self.ipv4.tcp.peek_at(socket_fd, offset, len)
+    }
+
+    /// See `tcp::Peer::consume`.
+    pub fn tcp_consume(&mut self, socket_fd: FileDescriptor, num_bytes: usize) -> Result<(), Fail> {
+        self.record(|| Input::Consume {
+            fd: socket_fd,
+            num_bytes,
+        });
+        self.ipv4.tcp.consume(socket_fd, num_bytes)
+    }
+
+    /// See `tcp::Peer::set_transform`.
+    pub fn tcp_set_transform(
+        &mut self,
+        socket_fd: FileDescriptor,
+        transform: Option<Box<dyn tcp::StreamTransform>>,
+    ) -> Result<(), Fail> {
+        self.record(|| Input::SetTransform {
+            fd: socket_fd,
+            enabled: transform.is_some(),
+        });
+        self.ipv4.tcp.set_transform(socket_fd, transform)
+    }
+
+    /// See `tcp::Peer::set_framing`.
+    pub fn tcp_set_framing(
+        &mut self,
+        fd: FileDescriptor,
+        framing: tcp::Framing,
+    ) -> Result<(), Fail> {
+        self.record(|| Input::SetFraming { fd, framing });
+        self.ipv4.tcp.set_framing(fd, framing)
+    }
+
+    /// See `tcp::Peer::add_connection_hooks`.
+    pub fn tcp_add_connection_hooks(
+        &self,
+        hooks: Rc<dyn tcp::hooks::ConnectionHooks>,
+    ) -> tcp::hooks::HooksId {
+        self.ipv4.tcp.add_connection_hooks(hooks)
+    }
+
+    /// See `tcp::Peer::remove_connection_hooks`.
+    pub fn tcp_remove_connection_hooks(&self, id: tcp::hooks::HooksId) -> Result<(), Fail> {
+        self.ipv4.tcp.remove_connection_hooks(id)
+    }
+
+    /// See `tcp::Peer::set_listener_transform`.
+    pub fn tcp_set_listener_transform(
+        &self,
+        socket_fd: FileDescriptor,
+        factory: Option<tcp::TransformFactory>,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_listener_transform(socket_fd, factory)
+    }
+
     pub fn tcp_close(&mut self, socket_fd: FileDescriptor) -> Result<(), Fail> {
+        self.record(|| Input::Close { fd: socket_fd });
         self.ipv4.tcp.close(socket_fd)
     }
 
+    /// Shut down one or both directions of a TCP connection; see `tcp::Peer::shutdown_socket`.
+    pub fn tcp_shutdown(&mut self, socket_fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        self.record(|| Input::Shutdown { fd: socket_fd, how });
+        self.ipv4.tcp.shutdown_socket(socket_fd, how)
+    }
+
+    /// Shut down gracefully: TCP stops accepting connections and closes every established one,
+    /// resetting those still open after `timeout`, the SNTP client, DNS resolver and UDP services
+    /// stop, and frames held back by rate limits are sent. The returned future resolves once
+    /// nothing is left in flight; like
+    /// any other engine future, it only makes progress while the scheduler is polled and the
+    /// clock advanced.
+    pub fn shutdown(&mut self, timeout: Duration) -> impl Future<Output = ()> {
+        self.sntp.take();
+        self.dns.take();
+        self.udp_services.clear();
+        self.path_monitor.take();
+        let tcp = self.ipv4.tcp.shutdown(timeout);
+        let egress = self.ipv4.egress().clone();
+        async move {
+            tcp.await;
+            egress.flush();
+        }
+    }
+
+    /// Snapshot of the engine-wide statistics.
+    pub fn stats(&self) -> Stats {
+        let (arp_hits, arp_misses) = self.arp.counters();
+        Stats {
+            tcp_latency: self.ipv4.tcp.engine_latency_stats(),
+            rx_dropped: self.rx_dropped,
+            rx_protocol_disabled: self.ipv4.num_disabled(),
+            arp_hits,
+            arp_misses,
+            arp_unsupported: self.arp.num_unsupported(),
+            tcp_established: self.ipv4.tcp.num_established(),
+            tcp_rsts_suppressed: self.ipv4.tcp.num_rsts_suppressed(),
+            icmpv4_errors_suppressed: self.ipv4.icmpv4_errors().num_suppressed(),
+            ipv4_reassembly_timeouts: self.ipv4.reassembler().num_timeouts(),
+            ipv4_fragments_dropped: self.ipv4.reassembler().num_dropped(),
+            rx_checksum_errors: self.ipv4.checksum_errors(),
+            tx_busy: self.ipv4.egress().num_tx_busy(),
+            paths: self
+                .path_monitor
+                .as_ref()
+                .map(|m| m.statuses())
+                .unwrap_or_default(),
+            tcp_listeners: self.ipv4.tcp.all_listener_stats(),
+            tcp_options: self.ipv4.tcp.option_stats(),
+        }
+    }
+
+    /// Push the engine's statistics into `sink` every `interval` of runtime clock time, checked
+    /// in `advance_clock`. Replaces any installed sink.
+    pub fn set_metrics_sink(&mut self, sink: Box<dyn MetricsSink>, interval: Duration) {
+        self.metrics = Some(MetricsExporter::new(sink, interval, self.rt.now()));
+    }
+
+    /// Also push a `TcpConnectionSummary` of every established connection into the installed
+    /// sink every `interval`, or stop with `None`. Returns whether there's a sink to push to;
+    /// replacing or removing the sink turns summaries off again.
+    pub fn set_connection_summary_interval(&mut self, interval: Option<Duration>) -> bool {
+        let now = self.rt.now();
+        match self.metrics {
+            Some(ref mut metrics) => {
+                metrics.set_summary_interval(interval, now);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Remove the installed sink, returning whether there was one.
+    pub fn clear_metrics_sink(&mut self) -> bool {
+        self.metrics.take().is_some()
+    }
+
+    /// Export to the installed sink now, without moving its schedule.
+    pub fn export_metrics(&mut self) {
+        let stats = self.stats();
+        let now = self.rt.now();
+        if let Some(ref mut metrics) = self.metrics {
+            metrics.export_now(now, &stats);
+        }
+    }
+
+    /// Latency histograms for a single established TCP connection.
+    pub fn tcp_latency_stats(&self, fd: FileDescriptor) -> Result<TcpLatencyStats, Fail> {
+        self.ipv4.tcp.latency_stats(fd)
+    }
+
+    /// MSS, window scaling and the other options a TCP connection's handshake settled on.
+    pub fn tcp_state(&self, fd: FileDescriptor) -> Result<tcp::State, Fail> {
+        self.ipv4.tcp.get_state(fd)
+    }
+
+    pub fn tcp_negotiated(&self, fd: FileDescriptor) -> Result<tcp::Negotiated, Fail> {
+        self.ipv4.tcp.get_negotiated(fd)
+    }
+
+    /// The congestion window and slow start threshold of an established TCP connection.
+    pub fn tcp_congestion(&self, fd: FileDescriptor) -> Result<tcp::Congestion, Fail> {
+        self.ipv4.tcp.get_congestion(fd)
+    }
+
+    /// Whether an established TCP connection is stalled on a closed remote window, and how the
+    /// persist timer's probing is going if so.
+    pub fn tcp_send_status(&self, fd: FileDescriptor) -> Result<tcp::SendStatus, Fail> {
+        self.ipv4.tcp.get_send_status(fd)
+    }
+
+    /// Whether an established TCP connection sends small segments right away (`true`), or holds
+    /// them while data is unacknowledged (Nagle's algorithm). See `tcp::Options::nagle`.
+    pub fn tcp_set_nodelay(&mut self, fd: FileDescriptor, nodelay: bool) -> Result<(), Fail> {
+        self.record(|| Input::SetNodelay { fd, nodelay });
+        self.ipv4.tcp.set_nodelay(fd, nodelay)
+    }
+
+    /// Whether a push to an established TCP connection would be taken right now; see
+    /// `tcp::Peer::writable`.
+    pub fn tcp_writable(&self, fd: FileDescriptor) -> Result<bool, Fail> {
+        self.ipv4.tcp.writable(fd)
+    }
+
+    /// Limit how much an established TCP connection holds for sending. See
+    /// `tcp::Options::send_buffer_size`.
+    pub fn tcp_set_send_buffer_size(&self, fd: FileDescriptor, size: usize) -> Result<(), Fail> {
+        self.ipv4.tcp.set_send_buffer_size(fd, size)
+    }
+
+    /// How much an established TCP connection has in flight, and how much more the remote's
+    /// window and the congestion window have room for. This only reads the connection's counters,
+    /// so it's cheap enough to call before every write.
+    pub fn tcp_send_window(&self, fd: FileDescriptor) -> Result<tcp::SendWindow, Fail> {
+        self.ipv4.tcp.get_send_window(fd)
+    }
+
+    /// When data last arrived on an established TCP connection; see `receive_with_timestamp`.
+    pub fn tcp_last_received(&self, fd: FileDescriptor) -> Result<Option<Instant>, Fail> {
+        self.ipv4.tcp.last_received(fd)
+    }
+
+    /// Delivered and transmitted byte counts and rates for a single established TCP connection.
+    pub fn tcp_throughput_stats(&self, fd: FileDescriptor) -> Result<TcpThroughputStats, Fail> {
+        self.ipv4.tcp.throughput_stats(fd)
+    }
+
+    /// Generate `pattern` on an established TCP connection from inside the engine, as fast as
+    /// it'll go or at `rate` bytes per second; see `tcp::Peer::start_source`.
+    pub fn tcp_start_source(
+        &mut self,
+        fd: FileDescriptor,
+        rate: Option<u64>,
+        pattern: tcp::TrafficPattern,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.start_source(fd, rate, pattern)
+    }
+
+    /// Discard everything arriving on an established TCP connection from inside the engine.
+    pub fn tcp_start_sink(&mut self, fd: FileDescriptor) -> Result<(), Fail> {
+        self.ipv4.tcp.start_sink(fd)
+    }
+
+    /// Bytes moved and rates achieved by a TCP connection's traffic generators.
+    pub fn tcp_traffic_stats(&self, fd: FileDescriptor) -> tcp::Traffic {
+        self.ipv4.tcp.traffic_stats(fd)
+    }
+
+    /// Stop a TCP connection's traffic generators, returning their final stats.
+    pub fn tcp_stop_traffic(&mut self, fd: FileDescriptor) -> tcp::Traffic {
+        self.ipv4.tcp.stop_traffic(fd)
+    }
+
+    /// Stop the TCP listener on `port` taking new connections while its existing ones finish,
+    /// raising `Event::TcpDrained` once they're all gone.
+    pub fn tcp_drain(&mut self, port: ip::Port) -> Result<(), Fail> {
+        self.ipv4.tcp.drain(port)
+    }
+
+    /// Close the TCP listener on `port`, resetting the connections it hasn't handed out yet.
+    pub fn tcp_close_listener(&mut self, port: ip::Port) -> Result<(), Fail> {
+        self.ipv4.tcp.close_listener(port)
+    }
+
+    /// Handshake counters for a listening TCP socket. `Engine::stats` has every listener's.
+    pub fn tcp_listener_stats(&self, fd: FileDescriptor) -> Result<TcpListenerStats, Fail> {
+        self.ipv4.tcp.listener_stats(fd)
+    }
+
+    /// Start periodically querying an NTP server for wall-clock time, replacing any previously
+    /// configured server. The client's socket and traffic aren't captured by record-and-replay.
+    pub fn sntp_start(&mut self, options: sntp::Options) -> Result<(), Fail> {
+        // Drop the old client first so its socket releases the local port.
+        self.sntp.take();
+        let client = sntp::Client::new(self.rt.clone(), self.ipv4.udp.clone(), options)?;
+        self.sntp = Some(client);
+        Ok(())
+    }
+
+    /// Offset-corrected wall-clock time, or `None` until SNTP has completed an exchange.
+    pub fn wall_clock(&self) -> Option<SystemTime> {
+        self.sntp.as_ref()?.wall_clock()
+    }
+
+    pub fn sntp_sample(&self) -> Option<sntp::Sample> {
+        self.sntp.as_ref()?.sample()
+    }
+
+    /// Start configuring the engine's IPv4 address with DHCP, replacing any running client. The
+    /// address is unspecified until a lease is bound, and each change is raised as
+    /// `Event::Ipv4ConfigChanged`. The client's traffic isn't captured by record-and-replay.
+    pub fn dhcp_start(&mut self, options: dhcp::Options) -> Result<(), Fail> {
+        // Drop the old client first so its socket releases the port.
+        self.dhcp.take();
+        let client = dhcp::Client::new(
+            self.rt.clone(),
+            self.ipv4.udp.clone(),
+            self.events.clone(),
+            options,
+        )?;
+        self.dhcp = Some(client);
+        Ok(())
+    }
+
+    /// Stop the DHCP client. The address it last configured stays in place.
+    pub fn dhcp_stop(&mut self) -> bool {
+        self.dhcp.take().is_some()
+    }
+
+    pub fn dhcp_lease(&self) -> Option<dhcp::Lease> {
+        self.dhcp.as_ref()?.lease()
+    }
+
+    pub fn dhcp_state(&self) -> Option<dhcp::State> {
+        Some(self.dhcp.as_ref()?.state())
+    }
+
+    /// Start resolving names with `options.servers` (say, a DHCP lease's `dns_servers`),
+    /// replacing any running resolver along with its cache. Queries aren't captured by
+    /// record-and-replay.
+    pub fn dns_start(&mut self, options: dns::Options) -> Result<(), Fail> {
+        // Drop the old resolver first so its socket releases the local port.
+        self.dns.take();
+        let resolver = dns::Resolver::new(
+            self.rt.clone(),
+            self.ipv4.udp.clone(),
+            self.ipv4.tcp.clone(),
+            options,
+        )?;
+        self.dns = Some(resolver);
+        Ok(())
+    }
+
+    /// Look up an IPv4 address for `hostname`; see `dns::Resolver::resolve`. Fails with
+    /// `Invalid` unless `dns_start` has been called, except for dotted-quad addresses.
+    pub fn resolve_host(&self, hostname: &str) -> impl Future<Output = Result<Ipv4Addr, Fail>> {
+        let literal = hostname.parse::<Ipv4Addr>().ok();
+        let resolve = self.dns.as_ref().map(|r| r.resolve(hostname));
+        async move {
+            match (resolve, literal) {
+                (Some(resolve), _) => resolve.await,
+                (None, Some(addr)) => Ok(addr),
+                (None, None) => Err(Fail::Invalid {
+                    details: "No DNS resolver",
+                }),
+            }
+        }
+    }
+
+    /// Start pinging `options.destinations` in the background and reporting changes in their
+    /// reachability as `Event::PathUp` and `Event::PathDown`. Replaces any running monitor, whose
+    /// state is lost. Probes aren't captured by record-and-replay.
+    pub fn path_monitor_start(&mut self, options: icmpv4::MonitorOptions) -> Result<(), Fail> {
+        if options.destinations.is_empty() {
+            return Err(Fail::Invalid {
+                details: "Path monitor has no destinations",
+            });
+        }
+        let monitor = icmpv4::PathMonitor::new(
+            self.rt.clone(),
+            self.ipv4.icmpv4().clone(),
+            self.events.clone(),
+            options,
+        );
+        self.path_monitor = Some(monitor);
+        Ok(())
+    }
+
+    pub fn path_monitor_stop(&mut self) -> bool {
+        self.path_monitor.take().is_some()
+    }
+
+    pub fn path_status(&self, destination: Ipv4Addr) -> Option<icmpv4::PathStatus> {
+        self.path_monitor.as_ref()?.status(destination)
+    }
+
+    /// Events raised since the last call, oldest first. See `crate::event`.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.events.take()
+    }
+
+    /// Like `take_events`, with each event's engine label.
+    pub fn take_labeled_events(&mut self) -> Vec<LabeledEvent> {
+        self.events.take_labeled()
+    }
+
+    /// The packet filter's rule table, which can be changed at any time.
+    pub fn filter(&self) -> &ipv4::Filter {
+        self.ipv4.filter()
+    }
+
+    /// Cap the rate at which the engine transmits IPv4 packets, or remove the cap with `None`.
+    /// Packets over the budget are delayed rather than dropped, up to a bounded queue.
+    pub fn set_rate_limit(&self, limit: Option<ipv4::RateLimit>) {
+        self.ipv4.egress().set_rate_limit(limit)
+    }
+
+    /// Cap the rate at which a single established TCP connection transmits, under any
+    /// engine-wide cap. The limit is keyed on the connection's endpoints, so it outlives the
+    /// connection until it's cleared.
+    pub fn tcp_set_rate_limit(
+        &self,
+        fd: FileDescriptor,
+        limit: Option<ipv4::RateLimit>,
+    ) -> Result<(), Fail> {
+        let (local, remote) = self.ipv4.tcp.endpoints(fd)?;
+        self.ipv4.egress().set_flow_rate_limit(local, remote, limit);
+        Ok(())
+    }
+
+    /// Carry all IPv4 traffic to `options.remote` over a GRE tunnel, replacing any open tunnel.
+    /// The far end needs a matching tunnel back to us.
+    pub fn gre_open(&self, options: gre::Options) {
+        self.ipv4.egress().set_tunnel(Some(gre::Tunnel::new(options)))
+    }
+
+    pub fn gre_close(&self) {
+        self.ipv4.egress().set_tunnel(None)
+    }
+
+    /// Choose how frames queued behind the engine-wide rate limit are picked across traffic
+    /// classes.
+    pub fn set_egress_scheduling(&self, scheduling: ipv4::Scheduling) {
+        self.ipv4.egress().set_scheduling(scheduling)
+    }
+
+    /// Assign an established TCP connection's data a traffic class, or return it to the default
+    /// (bulk) with `None`. Its handshake, teardown and pure ACK segments are always control.
+    pub fn tcp_set_traffic_class(
+        &self,
+        fd: FileDescriptor,
+        class: Option<ipv4::TrafficClass>,
+    ) -> Result<(), Fail> {
+        let (local, remote) = self.ipv4.tcp.endpoints(fd)?;
+        self.ipv4.egress().set_flow_class(local, remote, class);
+        Ok(())
+    }
+
+    /// Tag a TCP socket with a connection group, or untag it with `None`. A listener's group is
+    /// passed on to the connections it accepts.
+    pub fn tcp_set_group(
+        &self,
+        fd: FileDescriptor,
+        group: Option<tcp::GroupId>,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_group(fd, group)
+    }
+
+    pub fn tcp_group(&self, fd: FileDescriptor) -> Option<tcp::GroupId> {
+        self.ipv4.tcp.group(fd)
+    }
+
+    /// Start closing every established connection in `group`, returning how many were closed.
+    pub fn tcp_group_close(&self, group: tcp::GroupId) -> usize {
+        self.ipv4.tcp.close_group(group)
+    }
+
+    /// Reset every established connection in `group`, returning how many were reset.
+    pub fn tcp_group_abort(&self, group: tcp::GroupId) -> usize {
+        self.ipv4.tcp.abort_group(group)
+    }
+
+    /// `tcp_set_rate_limit` for each of `group`'s established connections. Connections that
+    /// join the group later don't pick the limit up.
+    pub fn tcp_group_set_rate_limit(&self, group: tcp::GroupId, limit: Option<ipv4::RateLimit>) {
+        for fd in self.ipv4.tcp.group_members(group) {
+            // Listeners and sockets still connecting have nothing to limit yet.
+            let _ = self.tcp_set_rate_limit(fd, limit);
+        }
+    }
+
+    /// `tcp_set_traffic_class` for each of `group`'s established connections.
+    pub fn tcp_group_set_traffic_class(
+        &self,
+        group: tcp::GroupId,
+        class: Option<ipv4::TrafficClass>,
+    ) {
+        for fd in self.ipv4.tcp.group_members(group) {
+            let _ = self.tcp_set_traffic_class(fd, class);
+        }
+    }
+
+    /// Throughput and queue totals over `group`'s established connections.
+    pub fn tcp_group_stats(&self, group: tcp::GroupId) -> TcpGroupStats {
+        self.ipv4.tcp.group_stats(group)
+    }
+
+    /// Start a VXLAN tunnel endpoint on `options.local_port`, replacing any running one. Devices
+    /// attached to the old endpoint stop carrying traffic.
+    pub fn vxlan_start(&mut self, options: vxlan::Options) -> Result<(), Fail> {
+        self.vxlan.take();
+        let vtep = vxlan::Vtep::new(self.rt.clone(), self.ipv4.udp.clone(), options)?;
+        self.vxlan = Some(vtep);
+        Ok(())
+    }
+
+    /// Join virtual network `vni`, whose other members are the VTEPs at `remotes`. Run another
+    /// engine on a `DeviceRuntime` over the returned device to use the overlay.
+    pub fn vxlan_attach(
+        &self,
+        vni: u32,
+        remotes: Vec<Ipv4Addr>,
+    ) -> Result<vxlan::VxlanDevice<RT>, Fail> {
+        match self.vxlan {
+            Some(ref vtep) => vtep.attach(vni, remotes),
+            None => Err(Fail::ResourceNotFound {
+                details: "VXLAN not started",
+            }),
+        }
+    }
+
+    /// Start translating between the inside network in `options` and everything else, with the
+    /// engine's address as the public one. Replaces any running NAT, dropping its mappings.
+    pub fn nat_start(&mut self, options: nat::Options) -> Result<(), Fail> {
+        if options.last_port >= ip::Port::first_private_port() {
+            return Err(Fail::Invalid {
+                details: "NAT port range overlaps the ephemeral ports",
+            });
+        }
+        let nat = nat::Nat::new(
+            self.rt.clone(),
+            self.arp.clone(),
+            self.ipv4.egress().clone(),
+            options,
+        );
+        self.nat = Some(nat);
+        Ok(())
+    }
+
+    pub fn nat_stop(&mut self) {
+        self.nat = None;
+    }
+
+    /// Forward `external_port` on the engine's address to `inside`, for any remote.
+    pub fn nat_add_static_mapping(
+        &mut self,
+        protocol: Ipv4Protocol2,
+        external_port: ip::Port,
+        inside: ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        match self.nat {
+            Some(ref mut nat) => nat.add_static(protocol, external_port, inside),
+            None => Err(Fail::ResourceNotFound {
+                details: "NAT not started",
+            }),
+        }
+    }
+
+    pub fn nat_remove_static_mapping(
+        &mut self,
+        protocol: Ipv4Protocol2,
+        external_port: ip::Port,
+    ) -> bool {
+        match self.nat {
+            Some(ref mut nat) => nat.remove_static(protocol, external_port),
+            None => false,
+        }
+    }
+
+    pub fn nat_mappings(&self) -> Vec<nat::Mapping> {
+        match self.nat {
+            Some(ref nat) => nat.mappings(),
+            None => vec![],
+        }
+    }
+
+    pub fn nat_stats(&self) -> Option<nat::Stats> {
+        self.nat.as_ref().map(|n| n.stats())
+    }
+
+    /// Capture the engine's sockets and ARP cache so another engine can take them over with
+    /// `restore`. See `crate::snapshot` for what's included.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let mut arp_cache = self
+            .arp
+            .export_cache()
+            .into_iter()
+            .map(|(addr, link_addr)| (addr, link_addr.octets()))
+            .collect::<Vec<_>>();
+        arp_cache.sort();
+
+        // The services' sockets belong to this engine, not to the application.
+        let service_fds = self
+            .sntp
+            .iter()
+            .map(|c| c.fd())
+            .chain(self.dhcp.iter().map(|c| c.fd()))
+            .chain(self.dns.iter().map(|r| r.fd()))
+            .chain(self.udp_services.iter().map(|s| s.fd()))
+            .chain(self.vxlan.iter().map(|v| v.fd()))
+            .collect::<Vec<_>>();
+        let mut udp_sockets = self.ipv4.udp.snapshot();
+        udp_sockets.retain(|s| !service_fds.contains(&s.fd));
+
+        let (tcp_listeners, tcp_connections) = self.ipv4.tcp.snapshot();
+        EngineSnapshot {
+            arp_cache,
+            udp_sockets,
+            tcp_listeners,
+            tcp_connections,
+        }
+    }
+
+    /// Recreate the sockets in a snapshot taken on another engine, returning a map from each
+    /// socket's file descriptor there to its descriptor here. Sockets restored before a failure
+    /// are left open. Restoring isn't captured by record-and-replay.
+    pub fn restore(
+        &mut self,
+        snapshot: &EngineSnapshot,
+    ) -> Result<HashMap<FileDescriptor, FileDescriptor>, Fail> {
+        let arp_cache = snapshot
+            .arp_cache
+            .iter()
+            .map(|&(addr, link_addr)| (addr, MacAddress::new(link_addr)))
+            .collect();
+        self.arp.import_cache(arp_cache);
+
+        let mut fds = HashMap::new();
+        for s in &snapshot.udp_sockets {
+            let fd = self.ipv4.udp.socket();
+            fds.insert(s.fd, fd);
+            self.ipv4.udp.bind(fd, ipv4::Endpoint::try_from(s.local)?)?;
+            if let Some(remote) = s.remote {
+                self.ipv4.udp.connect(fd, ipv4::Endpoint::try_from(remote)?)?;
+            }
+        }
+        for s in &snapshot.tcp_listeners {
+            let fd = self.ipv4.tcp.socket();
+            fds.insert(s.fd, fd);
+            self.ipv4.tcp.bind(fd, ipv4::Endpoint::try_from(s.local)?)?;
+            self.ipv4.tcp.listen(fd, s.backlog)?;
+        }
+        for s in &snapshot.tcp_connections {
+            let fd = self.ipv4.tcp.restore(s)?;
+            fds.insert(s.fd, fd);
+        }
+        Ok(fds)
+    }
+
     pub fn tcp_listen(&mut self, socket_fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
+        self.record(|| Input::Listen {
+            fd: socket_fd,
+            backlog,
+        });
         self.ipv4.tcp.listen(socket_fd, backlog)
     }
 
+    /// Find the interface and next hop link address packets for `dst` would be sent with, resolving
+    /// it with ARP if need be. See `ipv4::Peer::resolve`.
+    pub fn resolve(&self, dst: Ipv4Addr) -> impl Future<Output = Result<ipv4::Route, Fail>> {
+        self.ipv4.resolve(dst)
+    }
+
+    /// Resolve a neighbor's IPv6 address to its link address with NDP.
+    pub fn ndp_query(&self, ipv6_addr: Ipv6Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
+        self.ipv6.ndp().query(ipv6_addr)
+    }
+
     #[cfg(test)]
     pub fn arp_query(&self, ipv4_addr: Ipv4Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
         self.arp.query(ipv4_addr)
     }
 
+    /// Local and remote endpoints of an established connection.
+    pub fn tcp_endpoints(
+        &self,
+        fd: FileDescriptor,
+    ) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
+        self.ipv4.tcp.endpoints(fd)
+    }
+
     #[cfg(test)]
     pub fn tcp_mss(&self, handle: FileDescriptor) -> Result<usize, Fail> {
         self.ipv4.tcp_mss(handle)
     }
 
-    #[cfg(test)]
+    /// The current retransmission timeout of an established TCP connection, backoff included.
     pub fn tcp_rto(&self, handle: FileDescriptor) -> Result<Duration, Fail> {
-        self.ipv4.tcp_rto(handle)
+        self.ipv4.tcp.current_rto(handle)
+    }
+
+    /// The smoothed RTT of an established TCP connection, or `None` before it has taken a sample.
+    pub fn tcp_srtt(&self, handle: FileDescriptor) -> Result<Option<Duration>, Fail> {
+        self.ipv4.tcp.get_srtt(handle)
+    }
+
+    /// The ARP cache's entries and outstanding queries, sorted by address.
+    pub fn neighbors(&self) -> Vec<arp::Neighbor> {
+        self.arp.neighbors()
+    }
+
+    /// Which socket holds each local TCP and UDP port, and whether it was bound or handed out
+    /// to an active open.
+    pub fn ports(&self) -> Vec<ip::PortClaim> {
+        self.ipv4.ports().claims()
     }
 
     #[cfg(test)]
@@ -259,6 +1390,11 @@ impl<RT: Runtime> Engine<RT> {
         self.arp.export_cache()
     }
 
+    #[cfg(test)]
+    pub fn export_ndp_cache(&self) -> HashMap<Ipv6Addr, MacAddress> {
+        self.ipv6.ndp().export_cache()
+    }
+
     #[cfg(test)]
     pub fn import_arp_cache(&self, cache: HashMap<Ipv4Addr, MacAddress>) {
         self.arp.import_cache(cache)
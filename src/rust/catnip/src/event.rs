@@ -0,0 +1,125 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Things the engine reports as they happen, rather than through a future or a stats snapshot.
+//!
+//! Events queue up inside the engine until the application takes them with
+//! `Engine::take_events`. The queue is bounded: if nobody drains it, the oldest events are
+//! dropped and counted. Events carry the label of the engine that raised them, when it has one
+//! (see `Engine::set_label`), for processes that gather events from several engines.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::{
+        ipv4,
+        tcp,
+    },
+};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    net::Ipv4Addr,
+    rc::Rc,
+    time::Duration,
+};
+
+/// Events held before the oldest ones are dropped.
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A destination the path monitor probes stopped answering.
+    PathDown { destination: Ipv4Addr },
+    /// A destination the path monitor probes started answering, with the RTT of the probe that
+    /// brought it up.
+    PathUp {
+        destination: Ipv4Addr,
+        rtt: Duration,
+    },
+    /// An established TCP connection was torn down, whether it finished closing, was reset by
+    /// either end, or gave up retransmitting. `state` is where it was when that happened:
+    /// `TimeWait` or `Closed` after an orderly close, `Closed` after a RST from the remote, or
+    /// the state it was reset from otherwise. Its descriptor is no longer valid.
+    TcpClosed {
+        fd: FileDescriptor,
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+        state: tcp::State,
+    },
+    /// A listener being drained (see `tcp::Peer::drain`) has no connections left: every one it
+    /// accepted is gone, and no handshakes are in progress or waiting for accept.
+    TcpDrained {
+        fd: FileDescriptor,
+        local: ipv4::Endpoint,
+    },
+    /// The DHCP client changed the engine's address, or learned a different netmask or gateway
+    /// with it. `addr` is unspecified when a lease was lost and hasn't been replaced yet.
+    Ipv4ConfigChanged {
+        addr: Ipv4Addr,
+        netmask: Option<Ipv4Addr>,
+        gateway: Option<Ipv4Addr>,
+    },
+}
+
+/// An event along with the label the engine that raised it had at the time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LabeledEvent {
+    pub engine: Option<Rc<str>>,
+    pub event: Event,
+}
+
+#[derive(Default)]
+struct Inner {
+    events: VecDeque<LabeledEvent>,
+    num_dropped: u64,
+    label: Option<Rc<str>>,
+}
+
+/// The engine's event queue, shared with whatever produces events.
+#[derive(Clone, Default)]
+pub struct EventQueue {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, event: Event) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.events.len() >= MAX_QUEUED_EVENTS {
+            inner.events.pop_front();
+            inner.num_dropped += 1;
+        }
+        let engine = inner.label.clone();
+        inner.events.push_back(LabeledEvent { engine, event });
+    }
+
+    /// Label the events pushed from now on.
+    pub fn set_label(&self, label: Option<Rc<str>>) {
+        self.inner.borrow_mut().label = label;
+    }
+
+    /// Every queued event, oldest first.
+    pub fn take(&self) -> Vec<Event> {
+        self.take_labeled().into_iter().map(|e| e.event).collect()
+    }
+
+    pub fn take_labeled(&self) -> Vec<LabeledEvent> {
+        self.inner.borrow_mut().events.drain(..).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.borrow().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Events dropped because the queue was full.
+    pub fn num_dropped(&self) -> u64 {
+        self.inner.borrow().num_dropped
+    }
+}
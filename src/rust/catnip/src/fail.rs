@@ -1,12 +1,24 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+//! Errors.
+//!
+//! [`Fail`] says what went wrong, and is what the engine returns internally. [`Error`] wraps one
+//! with where it went wrong (the protocol, the endpoints involved, and the underlying OS error, if
+//! any) for callers that want to log or branch on more than the kind. Both map to a stable
+//! numeric code for the FFI; codes are never reused or renumbered.
+
+use crate::protocols::ipv4;
 use custom_error::custom_error;
 use float_duration;
 use std::{
     cell::BorrowMutError,
+    error::Error as StdError,
+    fmt,
     io::Error as IoError,
+    net::SocketAddrV4,
     num::TryFromIntError,
+    sync::Arc,
 };
 
 // the following type alias is needed because the `custom_error!` macro doesn't
@@ -29,6 +41,7 @@ custom_error! {#[derive(Clone)] pub Fail
     TypeMismatch{details: Str} = "type mismatch ({details})",
     Unsupported{details: Str} = "unsupported ({details})",
     Invalid {details: Str} = "invalid ({details})",
+    WouldBlock{} = "operation would block",
 }
 
 impl From<IoError> for Fail {
@@ -84,6 +97,242 @@ impl Fail {
             Fail::IoError {} => libc::EIO,
             Fail::BorrowMutError {} => libc::EINVAL,
             Fail::Invalid { .. } => libc::EINVAL,
+            Fail::WouldBlock {} => libc::EAGAIN,
+        }
+    }
+
+    /// The stable numeric code for this kind of failure. Zero means success.
+    pub fn code(&self) -> u32 {
+        match self {
+            Fail::ConnectionAborted {} => 1,
+            Fail::ConnectionRefused {} => 2,
+            Fail::IoError {} => 3,
+            Fail::BorrowMutError {} => 4,
+            Fail::Ignored { .. } => 5,
+            Fail::Malformed { .. } => 6,
+            Fail::Misdelivered {} => 7,
+            Fail::OutOfRange { .. } => 8,
+            Fail::ResourceBusy { .. } => 9,
+            Fail::ResourceExhausted { .. } => 10,
+            Fail::ResourceNotFound { .. } => 11,
+            Fail::Timeout {} => 12,
+            Fail::TypeMismatch { .. } => 13,
+            Fail::Unsupported { .. } => 14,
+            Fail::Invalid { .. } => 15,
+            Fail::WouldBlock {} => 16,
+        }
+    }
+}
+
+/// A short, NUL-terminated description of a code from [`Fail::code`].
+pub fn describe_code(code: u32) -> &'static str {
+    match code {
+        0 => "success\0",
+        1 => "connection aborted\0",
+        2 => "connection refused\0",
+        3 => "I/O error\0",
+        4 => "resource already borrowed\0",
+        5 => "operation had no effect\0",
+        6 => "malformed datagram\0",
+        7 => "misdelivered datagram\0",
+        8 => "value out of range\0",
+        9 => "resource busy\0",
+        10 => "resource exhausted\0",
+        11 => "resource not found\0",
+        12 => "operation timed out\0",
+        13 => "type mismatch\0",
+        14 => "unsupported\0",
+        15 => "invalid argument\0",
+        16 => "operation would block\0",
+        _ => "unknown error\0",
+    }
+}
+
+/// The protocol an [`Error`] came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Protocol {
+    Ethernet2,
+    Arp,
+    Ipv4,
+    Icmpv4,
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Protocol::Ethernet2 => "Ethernet",
+            Protocol::Arp => "ARP",
+            Protocol::Ipv4 => "IPv4",
+            Protocol::Icmpv4 => "ICMPv4",
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A [`Fail`] with context.
+#[derive(Clone, Debug)]
+pub struct Error {
+    kind: Fail,
+    protocol: Option<Protocol>,
+    local: Option<ipv4::Endpoint>,
+    remote: Option<ipv4::Endpoint>,
+    source: Option<Arc<dyn StdError + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new(kind: Fail) -> Self {
+        Self {
+            kind,
+            protocol: None,
+            local: None,
+            remote: None,
+            source: None,
+        }
+    }
+
+    pub fn with_protocol(mut self, value: Protocol) -> Self {
+        self.protocol = Some(value);
+        self
+    }
+
+    pub fn with_local(mut self, value: ipv4::Endpoint) -> Self {
+        self.local = Some(value);
+        self
+    }
+
+    pub fn with_remote(mut self, value: ipv4::Endpoint) -> Self {
+        self.remote = Some(value);
+        self
+    }
+
+    pub fn with_source(mut self, value: impl StdError + Send + Sync + 'static) -> Self {
+        self.source = Some(Arc::new(value));
+        self
+    }
+
+    pub fn kind(&self) -> &Fail {
+        &self.kind
+    }
+
+    pub fn into_kind(self) -> Fail {
+        self.kind
+    }
+
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.protocol
+    }
+
+    pub fn local(&self) -> Option<ipv4::Endpoint> {
+        self.local
+    }
+
+    pub fn remote(&self) -> Option<ipv4::Endpoint> {
+        self.remote
+    }
+
+    pub fn code(&self) -> u32 {
+        self.kind.code()
+    }
+
+    pub fn errno(&self) -> libc::c_int {
+        self.kind.errno()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(protocol) = self.protocol {
+            write!(f, "{}: ", protocol)?;
         }
+        write!(f, "{}", self.kind)?;
+        match (self.local, self.remote) {
+            (Some(local), Some(remote)) => write!(
+                f,
+                " ({} -> {})",
+                SocketAddrV4::from(local),
+                SocketAddrV4::from(remote)
+            )?,
+            (Some(local), None) => write!(f, " (at {})", SocketAddrV4::from(local))?,
+            (None, Some(remote)) => write!(f, " (to {})", SocketAddrV4::from(remote))?,
+            (None, None) => (),
+        }
+        if let Some(ref source) = self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.source
+            .as_ref()
+            .map(|s| &**s as &(dyn StdError + 'static))
+    }
+}
+
+impl From<Fail> for Error {
+    fn from(kind: Fail) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(e: IoError) -> Self {
+        Error::new(Fail::IoError {}).with_source(e)
+    }
+}
+
+impl From<Error> for Fail {
+    fn from(e: Error) -> Self {
+        e.kind
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        describe_code,
+        Error,
+        Fail,
+        Protocol,
+    };
+    use crate::{
+        protocols::{
+            ip,
+            ipv4,
+        },
+        test_helpers,
+    };
+    use std::{
+        convert::TryFrom,
+        error::Error as StdError,
+        io,
+    };
+
+    #[test]
+    fn codes() {
+        let exhausted = Fail::ResourceExhausted { details: "ports" };
+        let would_block = Fail::WouldBlock {};
+        assert_eq!(exhausted.code(), 10);
+        assert_eq!(would_block.code(), 16);
+        assert_eq!(describe_code(would_block.code()), "operation would block\0");
+        assert_eq!(describe_code(1000), "unknown error\0");
+
+        let port = ip::Port::try_from(80).unwrap();
+        let e = Error::new(Fail::ConnectionRefused {})
+            .with_protocol(Protocol::Tcp)
+            .with_remote(ipv4::Endpoint::new(test_helpers::BOB_IPV4, port));
+        assert_eq!(e.code(), 2);
+        assert_eq!(e.to_string(), "TCP: connection refused (to 192.168.1.2:80)");
+        assert!(e.source().is_none());
+
+        let e = Error::from(io::Error::new(io::ErrorKind::Other, "device gone"));
+        must_let::must_let!(let Fail::IoError {} = e.kind());
+        assert_eq!(e.source().unwrap().to_string(), "device gone");
     }
 }
@@ -0,0 +1,667 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! C ABI for embedding the engine directly, without a libOS or DPDK.
+//!
+//! The embedder owns the wire and the clock: it feeds received frames in with
+//! `catnip_engine_receive`, drains frames to transmit with `catnip_engine_pop_frame`, and moves
+//! time forward with `catnip_engine_advance_clock`. Socket operations that can't complete
+//! immediately return a queue token, which `catnip_engine_take_result` turns into a
+//! `catnip_result_t` once the operation is done.
+//!
+//! Every function returns zero on success or an `errno` value on failure, with `EAGAIN` meaning
+//! "not yet". See `include/catnip.h` for the matching declarations.
+
+#![allow(non_camel_case_types)]
+
+mod runtime;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use self::runtime::FfiRuntime;
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::{
+        self,
+        Fail,
+    },
+    file_table::FileDescriptor,
+    operations::OperationResult,
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        ip,
+        ipv4,
+        tcp,
+        udp,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::Operation,
+    sync::Bytes,
+};
+use libc::{
+    c_char,
+    c_int,
+    size_t,
+};
+use std::{
+    convert::TryFrom,
+    net::Ipv4Addr,
+    ptr,
+    slice,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+pub type catnip_qd_t = u32;
+pub type catnip_qtoken_t = u64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct catnip_endpoint_t {
+    pub addr: [u8; 4],
+    /// Host byte order.
+    pub port: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct catnip_config_t {
+    pub link_addr: [u8; 6],
+    pub ipv4_addr: [u8; 4],
+    pub rng_seed: [u8; 16],
+    /// Zero keeps the default.
+    pub tcp_advertised_mss: u32,
+    pub disable_arp: bool,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum catnip_opcode_t {
+    CATNIP_OPC_FAILED = 0,
+    CATNIP_OPC_CONNECT,
+    CATNIP_OPC_ACCEPT,
+    CATNIP_OPC_PUSH,
+    CATNIP_OPC_POP,
+}
+
+/// A completed operation. For `CATNIP_OPC_POP`, `data` is owned by the caller and must be
+/// released with `catnip_result_free`.
+#[repr(C)]
+pub struct catnip_result_t {
+    pub opcode: catnip_opcode_t,
+    pub qd: catnip_qd_t,
+    /// `errno` value for `CATNIP_OPC_FAILED`.
+    pub error: c_int,
+    /// Stable catnip error code for `CATNIP_OPC_FAILED`, finer-grained than `error`.
+    pub code: u32,
+    /// New connection for `CATNIP_OPC_ACCEPT`.
+    pub new_qd: catnip_qd_t,
+    /// Sender for a UDP `CATNIP_OPC_POP`.
+    pub has_remote: bool,
+    pub remote: catnip_endpoint_t,
+    pub data: *mut u8,
+    pub data_len: size_t,
+}
+
+pub struct catnip_engine_t {
+    engine: Engine<FfiRuntime>,
+    // The embedder's clock is in nanoseconds since engine creation.
+    origin: Instant,
+}
+
+impl catnip_endpoint_t {
+    fn to_endpoint(self) -> Result<ipv4::Endpoint, Fail> {
+        Ok(ipv4::Endpoint::new(
+            Ipv4Addr::from(self.addr),
+            ip::Port::try_from(self.port)?,
+        ))
+    }
+
+    fn from_endpoint(endpoint: ipv4::Endpoint) -> Self {
+        Self {
+            addr: endpoint.addr.octets(),
+            port: endpoint.port.into(),
+        }
+    }
+}
+
+impl catnip_result_t {
+    fn new(qd: FileDescriptor, result: OperationResult<FfiRuntime>) -> Self {
+        let mut r = Self {
+            opcode: catnip_opcode_t::CATNIP_OPC_FAILED,
+            qd,
+            error: 0,
+            code: 0,
+            new_qd: 0,
+            has_remote: false,
+            remote: catnip_endpoint_t {
+                addr: [0; 4],
+                port: 0,
+            },
+            data: ptr::null_mut(),
+            data_len: 0,
+        };
+        match result {
+            OperationResult::Connect => r.opcode = catnip_opcode_t::CATNIP_OPC_CONNECT,
+            OperationResult::Accept(new_qd) => {
+                r.opcode = catnip_opcode_t::CATNIP_OPC_ACCEPT;
+                r.new_qd = new_qd;
+            },
+            OperationResult::Push => r.opcode = catnip_opcode_t::CATNIP_OPC_PUSH,
+            OperationResult::Pop(remote, buf) => {
+                r.opcode = catnip_opcode_t::CATNIP_OPC_POP;
+                if let Some(remote) = remote {
+                    r.has_remote = true;
+                    r.remote = catnip_endpoint_t::from_endpoint(remote);
+                }
+                let data: Box<[u8]> = buf[..].into();
+                r.data_len = data.len();
+                r.data = Box::into_raw(data) as *mut u8;
+            },
+            OperationResult::Failed(e) => {
+                r.error = e.errno();
+                r.code = e.code();
+            },
+        }
+        r
+    }
+}
+
+impl catnip_engine_t {
+    fn insert(&self, op: Operation<FfiRuntime>) -> catnip_qtoken_t {
+        self.engine.rt().scheduler().insert(op).into_raw()
+    }
+}
+
+// Collapse a `Result` into a status code, writing the success value to `out`.
+fn status<T>(r: Result<T, Fail>, out: *mut T) -> c_int {
+    match r {
+        Ok(v) => {
+            if !out.is_null() {
+                unsafe { *out = v };
+            }
+            0
+        },
+        Err(e) => e.errno(),
+    }
+}
+
+fn engine_mut<'a>(engine: *mut catnip_engine_t) -> Result<&'a mut catnip_engine_t, Fail> {
+    unsafe { engine.as_mut() }.ok_or(Fail::Invalid {
+        details: "Null engine",
+    })
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: size_t) -> Result<&'a [u8], Fail> {
+    if data.is_null() && len > 0 {
+        return Err(Fail::Invalid {
+            details: "Null buffer",
+        });
+    }
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(slice::from_raw_parts(data, len))
+}
+
+/// Create an engine, returning null if the configuration is invalid.
+#[no_mangle]
+pub extern "C" fn catnip_engine_create(config: *const catnip_config_t) -> *mut catnip_engine_t {
+    let config = match unsafe { config.as_ref() } {
+        Some(c) => *c,
+        None => return ptr::null_mut(),
+    };
+    let link_addr = MacAddress::new(config.link_addr);
+    let ipv4_addr = Ipv4Addr::from(config.ipv4_addr);
+    if link_addr.is_nil() || link_addr.is_broadcast() {
+        return ptr::null_mut();
+    }
+    if ipv4_addr.is_unspecified() || ipv4_addr.is_broadcast() {
+        return ptr::null_mut();
+    }
+    let mut arp_options = arp::Options::default();
+    arp_options.disable_arp = config.disable_arp;
+    let mut tcp_options = tcp::Options::default();
+    if config.tcp_advertised_mss != 0 {
+        tcp_options.advertised_mss = config.tcp_advertised_mss as usize;
+    }
+
+    let origin = Instant::now();
+    let rt = FfiRuntime::new(
+        origin,
+        link_addr,
+        ipv4_addr,
+        config.rng_seed,
+        arp_options,
+        tcp_options,
+        udp::Options::default(),
+    );
+    match Engine::new(rt) {
+        Ok(engine) => Box::into_raw(Box::new(catnip_engine_t { engine, origin })),
+        Err(e) => {
+            warn!("Failed to create engine: {:?}", e);
+            ptr::null_mut()
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_engine_destroy(engine: *mut catnip_engine_t) {
+    if !engine.is_null() {
+        drop(unsafe { Box::from_raw(engine) });
+    }
+}
+
+/// Feed a received Ethernet frame into the engine. Frames the engine drops (for another host, or
+/// malformed) are reported as errors but leave the engine unaffected.
+#[no_mangle]
+pub extern "C" fn catnip_engine_receive(
+    engine: *mut catnip_engine_t,
+    frame: *const u8,
+    len: size_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        let frame = unsafe { bytes(frame, len)? };
+        e.engine.receive(Bytes::from_slice(frame))?;
+    };
+    status(r, ptr::null_mut())
+}
+
+/// Advance the engine's clock to `now_ns` nanoseconds after its creation and run any work that
+/// became ready. The clock may not move backwards.
+#[no_mangle]
+pub extern "C" fn catnip_engine_advance_clock(engine: *mut catnip_engine_t, now_ns: u64) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        let now = e.origin + Duration::from_nanos(now_ns);
+        if now < e.engine.rt().now() {
+            Err(Fail::Invalid {
+                details: "Clock moved backwards",
+            })?;
+        }
+        e.engine.advance_clock(now);
+        e.engine.poll_scheduler();
+    };
+    status(r, ptr::null_mut())
+}
+
+/// Run any work that's ready without advancing the clock.
+#[no_mangle]
+pub extern "C" fn catnip_engine_poll(engine: *mut catnip_engine_t) -> c_int {
+    status(
+        engine_mut(engine).map(|e| e.engine.poll_scheduler()),
+        ptr::null_mut(),
+    )
+}
+
+/// Copy the next frame to transmit into `buf`. Returns `EAGAIN` if there's nothing to send, and
+/// `ENOBUFS` (with `*len_out` set to the frame's size) if `buf` is too small, in which case the
+/// frame stays queued.
+#[no_mangle]
+pub extern "C" fn catnip_engine_pop_frame(
+    engine: *mut catnip_engine_t,
+    buf: *mut u8,
+    cap: size_t,
+    len_out: *mut size_t,
+) -> c_int {
+    let e = match engine_mut(engine) {
+        Ok(e) => e,
+        Err(e) => return e.errno(),
+    };
+    let len = match e.engine.rt().front_frame_len() {
+        Some(len) => len,
+        None => return libc::EAGAIN,
+    };
+    if !len_out.is_null() {
+        unsafe { *len_out = len };
+    }
+    if len > cap {
+        return libc::ENOBUFS;
+    }
+    if buf.is_null() {
+        return libc::EINVAL;
+    }
+    let frame = e.engine.rt().pop_frame().unwrap();
+    unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), buf, len) };
+    0
+}
+
+/// Returns `EAGAIN` if the operation is still in flight. Once this succeeds, `qt` is no longer
+/// valid.
+#[no_mangle]
+pub extern "C" fn catnip_engine_take_result(
+    engine: *mut catnip_engine_t,
+    qt: catnip_qtoken_t,
+    result_out: *mut catnip_result_t,
+) -> c_int {
+    let e = match engine_mut(engine) {
+        Ok(e) => e,
+        Err(e) => return e.errno(),
+    };
+    if result_out.is_null() {
+        return libc::EINVAL;
+    }
+    let scheduler = e.engine.rt().scheduler();
+    let handle = match scheduler.from_raw_handle(qt) {
+        Some(h) => h,
+        None => return libc::EBADF,
+    };
+    if !handle.has_completed() {
+        handle.into_raw();
+        return libc::EAGAIN;
+    }
+    let (qd, result) = match scheduler.take(handle) {
+        Operation::Tcp(f) => f.expect_result(),
+        Operation::Udp(f) => f.expect_result(),
+        Operation::Background(..) => return libc::EBADF,
+    };
+    unsafe { *result_out = catnip_result_t::new(qd, result) };
+    0
+}
+
+/// Cancel an in-flight operation.
+#[no_mangle]
+pub extern "C" fn catnip_engine_drop_qtoken(
+    engine: *mut catnip_engine_t,
+    qt: catnip_qtoken_t,
+) -> c_int {
+    let e = match engine_mut(engine) {
+        Ok(e) => e,
+        Err(e) => return e.errno(),
+    };
+    match e.engine.rt().scheduler().from_raw_handle(qt) {
+        Some(handle) => {
+            drop(handle);
+            0
+        },
+        None => libc::EBADF,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_result_free(result: *mut catnip_result_t) {
+    let result = match unsafe { result.as_mut() } {
+        Some(r) => r,
+        None => return,
+    };
+    if !result.data.is_null() {
+        let data = ptr::slice_from_raw_parts_mut(result.data, result.data_len);
+        drop(unsafe { Box::from_raw(data) });
+        result.data = ptr::null_mut();
+        result.data_len = 0;
+    }
+}
+
+/// A static description of an error code from `catnip_result_t::code`. Never null.
+#[no_mangle]
+pub extern "C" fn catnip_strerror(code: u32) -> *const c_char {
+    fail::describe_code(code).as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_tcp_socket(
+    engine: *mut catnip_engine_t,
+    qd_out: *mut catnip_qd_t,
+) -> c_int {
+    status(engine_mut(engine).map(|e| e.engine.tcp_socket()), qd_out)
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_udp_socket(
+    engine: *mut catnip_engine_t,
+    qd_out: *mut catnip_qd_t,
+) -> c_int {
+    status(
+        engine_mut(engine).map(|e| e.engine.socket(Protocol::Udp)),
+        qd_out,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_bind(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    local: catnip_endpoint_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        e.engine.bind(qd, local.to_endpoint()?)?
+    };
+    status(r, ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_tcp_listen(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    backlog: c_int,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        if backlog <= 0 {
+            Err(Fail::Invalid {
+                details: "Backlog must be positive",
+            })?;
+        }
+        e.engine.listen(qd, backlog as usize)?
+    };
+    status(r, ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_tcp_accept(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    qt_out: *mut catnip_qtoken_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        let op = e.engine.accept(qd);
+        e.insert(op)
+    };
+    status(r, qt_out)
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_tcp_connect(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    remote: catnip_endpoint_t,
+    qt_out: *mut catnip_qtoken_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        let op = e.engine.connect(qd, remote.to_endpoint()?);
+        e.insert(op)
+    };
+    status(r, qt_out)
+}
+
+/// Queue `len` bytes on a TCP connection (or a connected UDP socket). The data is copied, so
+/// `data` may be reused as soon as this returns.
+#[no_mangle]
+pub extern "C" fn catnip_tcp_write(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    data: *const u8,
+    len: size_t,
+    qt_out: *mut catnip_qtoken_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        let buf = Bytes::from_slice(unsafe { bytes(data, len)? });
+        let op = e.engine.push(qd, buf);
+        e.insert(op)
+    };
+    status(r, qt_out)
+}
+
+/// Wait for the next chunk of data on a TCP connection (or a UDP socket).
+#[no_mangle]
+pub extern "C" fn catnip_tcp_read(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    qt_out: *mut catnip_qtoken_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        let op = e.engine.pop(qd);
+        e.insert(op)
+    };
+    status(r, qt_out)
+}
+
+/// Send a datagram. UDP sends complete immediately, so there's no queue token.
+#[no_mangle]
+pub extern "C" fn catnip_udp_sendto(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    data: *const u8,
+    len: size_t,
+    remote: catnip_endpoint_t,
+) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        let buf = Bytes::from_slice(unsafe { bytes(data, len)? });
+        e.engine.udp_pushto(qd, buf, remote.to_endpoint()?)?
+    };
+    status(r, ptr::null_mut())
+}
+
+/// Wait for the next datagram on a UDP socket. The sender is reported in the result.
+#[no_mangle]
+pub extern "C" fn catnip_udp_recvfrom(
+    engine: *mut catnip_engine_t,
+    qd: catnip_qd_t,
+    qt_out: *mut catnip_qtoken_t,
+) -> c_int {
+    catnip_tcp_read(engine, qd, qt_out)
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_close(engine: *mut catnip_engine_t, qd: catnip_qd_t) -> c_int {
+    let r: Result<_, Fail> = try {
+        let e = engine_mut(engine)?;
+        check_qd(e, qd)?;
+        e.engine.close(qd)?
+    };
+    status(r, ptr::null_mut())
+}
+
+// The engine panics on unknown descriptors, which we can't let unwind across the FFI boundary.
+fn check_qd(e: &catnip_engine_t, qd: catnip_qd_t) -> Result<(), Fail> {
+    if !e.engine.is_qd_valid(qd) {
+        return Err(Fail::ResourceNotFound {
+            details: "Bad queue descriptor",
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_engine(last_octet: u8) -> *mut catnip_engine_t {
+        let config = catnip_config_t {
+            link_addr: [0x02, 0, 0, 0, 0, last_octet],
+            ipv4_addr: [10, 0, 0, last_octet],
+            rng_seed: [last_octet; 16],
+            tcp_advertised_mss: 0,
+            disable_arp: false,
+        };
+        let engine = catnip_engine_create(&config);
+        assert!(!engine.is_null());
+        engine
+    }
+
+    // Move every queued frame from `from` to `to`, then let both sides react.
+    fn pump(from: *mut catnip_engine_t, to: *mut catnip_engine_t) {
+        let mut buf = [0u8; 2048];
+        let mut len = 0;
+        while catnip_engine_pop_frame(from, buf.as_mut_ptr(), buf.len(), &mut len) == 0 {
+            let _ = catnip_engine_receive(to, buf.as_ptr(), len);
+        }
+        assert_eq!(catnip_engine_poll(from), 0);
+        assert_eq!(catnip_engine_poll(to), 0);
+    }
+
+    #[test]
+    fn udp_echo() {
+        let alice = new_engine(1);
+        let bob = new_engine(2);
+        let alice_addr = catnip_endpoint_t {
+            addr: [10, 0, 0, 1],
+            port: 5000,
+        };
+        let bob_addr = catnip_endpoint_t {
+            addr: [10, 0, 0, 2],
+            port: 7,
+        };
+
+        let mut bob_qd = 0;
+        assert_eq!(catnip_udp_socket(bob, &mut bob_qd), 0);
+        assert_eq!(catnip_bind(bob, bob_qd, bob_addr), 0);
+        let mut qt = 0;
+        assert_eq!(catnip_udp_recvfrom(bob, bob_qd, &mut qt), 0);
+
+        let mut alice_qd = 0;
+        assert_eq!(catnip_udp_socket(alice, &mut alice_qd), 0);
+        assert_eq!(catnip_bind(alice, alice_qd, alice_addr), 0);
+        let msg = b"ping";
+        assert_eq!(
+            catnip_udp_sendto(alice, alice_qd, msg.as_ptr(), msg.len(), bob_addr),
+            0
+        );
+        assert_eq!(catnip_engine_poll(alice), 0);
+
+        let mut result: catnip_result_t = unsafe { std::mem::zeroed() };
+        assert_eq!(
+            catnip_engine_take_result(bob, qt, &mut result),
+            libc::EAGAIN
+        );
+
+        // ARP request, ARP reply, then the datagram itself.
+        pump(alice, bob);
+        pump(bob, alice);
+        pump(alice, bob);
+
+        assert_eq!(catnip_engine_take_result(bob, qt, &mut result), 0);
+        assert_eq!(result.opcode, catnip_opcode_t::CATNIP_OPC_POP);
+        assert_eq!(result.qd, bob_qd);
+        assert!(result.has_remote);
+        assert_eq!(result.remote.addr, alice_addr.addr);
+        assert_eq!(result.remote.port, alice_addr.port);
+        let data = unsafe { slice::from_raw_parts(result.data, result.data_len) };
+        assert_eq!(data, &msg[..]);
+        catnip_result_free(&mut result);
+
+        assert_eq!(catnip_close(bob, 1234), libc::ENOENT);
+        assert_eq!(catnip_engine_advance_clock(bob, 1_000_000), 0);
+        assert_eq!(
+            catnip_engine_advance_clock(bob, 0),
+            libc::EINVAL,
+            "clock moved backwards"
+        );
+
+        catnip_engine_destroy(alice);
+        catnip_engine_destroy(bob);
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    interop::{
+        dmtr_sgarray_t,
+        dmtr_sgaseg_t,
+    },
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        tcp,
+        udp,
+    },
+    runtime::{
+        self,
+        PacketBuf,
+        Runtime,
+        RECEIVE_BATCH_SIZE,
+    },
+    scheduler::{
+        Operation,
+        Scheduler,
+        SchedulerHandle,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
+    },
+    timer::{
+        Timer,
+        TimerRc,
+    },
+};
+use arrayvec::ArrayVec;
+use futures::FutureExt;
+use rand::{
+    distributions::{
+        Distribution,
+        Standard,
+    },
+    rngs::SmallRng,
+    seq::SliceRandom,
+    Rng,
+    SeedableRng,
+};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::Future,
+    mem,
+    net::Ipv4Addr,
+    ptr,
+    rc::Rc,
+    slice,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// A runtime driven entirely by its embedder: frames come in through `Engine::receive`, go out
+/// to a queue the embedder drains, and time only moves when the embedder advances the clock.
+#[derive(Clone)]
+pub struct FfiRuntime {
+    inner: Rc<RefCell<Inner>>,
+    scheduler: Scheduler<Operation<FfiRuntime>>,
+}
+
+struct Inner {
+    timer: TimerRc,
+    rng: SmallRng,
+    outgoing: VecDeque<Bytes>,
+
+    link_addr: MacAddress,
+    ipv4_addr: Ipv4Addr,
+    arp_options: arp::Options,
+    tcp_options: tcp::Options,
+    udp_options: udp::Options,
+}
+
+impl FfiRuntime {
+    pub fn new(
+        now: Instant,
+        link_addr: MacAddress,
+        ipv4_addr: Ipv4Addr,
+        rng_seed: [u8; 16],
+        arp_options: arp::Options,
+        tcp_options: tcp::Options,
+        udp_options: udp::Options,
+    ) -> Self {
+        let inner = Inner {
+            timer: TimerRc(Rc::new(Timer::new(now))),
+            rng: SmallRng::from_seed(rng_seed),
+            outgoing: VecDeque::new(),
+            link_addr,
+            ipv4_addr,
+            arp_options,
+            tcp_options,
+            udp_options,
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+            scheduler: Scheduler::new(),
+        }
+    }
+
+    pub fn front_frame_len(&self) -> Option<usize> {
+        self.inner.borrow().outgoing.front().map(|b| b.len())
+    }
+
+    pub fn pop_frame(&self) -> Option<Bytes> {
+        self.inner.borrow_mut().outgoing.pop_front()
+    }
+}
+
+impl Runtime for FfiRuntime {
+    type Buf = Bytes;
+    type WaitFuture = crate::timer::WaitFuture<TimerRc>;
+
+    fn into_sgarray(&self, buf: Bytes) -> dmtr_sgarray_t {
+        let buf_copy: Box<[u8]> = (&buf[..]).into();
+        let ptr = Box::into_raw(buf_copy);
+        let sgaseg = dmtr_sgaseg_t {
+            sgaseg_buf: ptr as *mut _,
+            sgaseg_len: buf.len() as u32,
+        };
+        dmtr_sgarray_t {
+            sga_buf: ptr::null_mut(),
+            sga_numsegs: 1,
+            sga_segs: [sgaseg],
+            sga_addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    fn alloc_sgarray(&self, size: usize) -> dmtr_sgarray_t {
+        let allocation: Box<[u8]> = vec![0u8; size].into_boxed_slice();
+        let ptr = Box::into_raw(allocation);
+        let sgaseg = dmtr_sgaseg_t {
+            sgaseg_buf: ptr as *mut _,
+            sgaseg_len: size as u32,
+        };
+        dmtr_sgarray_t {
+            sga_buf: ptr::null_mut(),
+            sga_numsegs: 1,
+            sga_segs: [sgaseg],
+            sga_addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    fn free_sgarray(&self, sga: dmtr_sgarray_t) {
+        assert_eq!(sga.sga_numsegs, 1);
+        let sgaseg = sga.sga_segs[0];
+        let allocation: Box<[u8]> = unsafe {
+            Box::from_raw(slice::from_raw_parts_mut(
+                sgaseg.sgaseg_buf as *mut _,
+                sgaseg.sgaseg_len as usize,
+            ))
+        };
+        drop(allocation);
+    }
+
+    fn clone_sgarray(&self, sga: &dmtr_sgarray_t) -> Bytes {
+        let mut len = 0;
+        for i in 0..sga.sga_numsegs as usize {
+            len += sga.sga_segs[i].sgaseg_len;
+        }
+        let mut buf = BytesMut::zeroed(len as usize);
+        let mut pos = 0;
+        for i in 0..sga.sga_numsegs as usize {
+            let seg = &sga.sga_segs[i];
+            let seg_slice = unsafe {
+                slice::from_raw_parts(seg.sgaseg_buf as *mut u8, seg.sgaseg_len as usize)
+            };
+            buf[pos..(pos + seg_slice.len())].copy_from_slice(seg_slice);
+            pos += seg_slice.len();
+        }
+        buf.freeze()
+    }
+
+    fn advance_clock(&self, now: Instant) {
+        self.inner.borrow_mut().timer.0.advance_clock(now);
+    }
+
+    fn transmit(&self, pkt: impl PacketBuf<Bytes>) {
+        let mut buf = BytesMut::zeroed(runtime::frame_size(&pkt));
+        runtime::serialize_frame(pkt, &mut buf[..]);
+        self.inner.borrow_mut().outgoing.push_back(buf.freeze());
+    }
+
+    // Received frames are pushed directly into the engine by the embedder.
+    fn receive(&self) -> ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]> {
+        ArrayVec::new()
+    }
+
+    fn local_link_addr(&self) -> MacAddress {
+        self.inner.borrow().link_addr
+    }
+
+    fn local_ipv4_addr(&self) -> Ipv4Addr {
+        self.inner.borrow().ipv4_addr
+    }
+
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
+    fn arp_options(&self) -> arp::Options {
+        self.inner.borrow().arp_options.clone()
+    }
+
+    fn tcp_options(&self) -> tcp::Options {
+        self.inner.borrow().tcp_options.clone()
+    }
+
+    fn udp_options(&self) -> udp::Options {
+        self.inner.borrow().udp_options.clone()
+    }
+
+    fn wait(&self, duration: Duration) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        let now = inner.timer.0.now();
+        inner
+            .timer
+            .0
+            .wait_until(inner.timer.clone(), now + duration)
+    }
+
+    fn wait_until(&self, when: Instant) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        inner.timer.0.wait_until(inner.timer.clone(), when)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.borrow().timer.0.now()
+    }
+
+    fn next_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
+    fn rng_gen<T>(&self) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.rng.gen()
+    }
+
+    fn rng_shuffle<T>(&self, slice: &mut [T]) {
+        let mut inner = self.inner.borrow_mut();
+        slice.shuffle(&mut inner.rng);
+    }
+
+    fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
+        self.scheduler
+            .insert(Operation::Background(future.boxed_local()))
+    }
+
+    fn scheduler(&self) -> &Scheduler<Operation<Self>> {
+        &self.scheduler
+    }
+}
@@ -0,0 +1,593 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! JavaScript-friendly exports for running engines in a browser, as the hosts of a simulated
+//! network for teaching and visualization tools.
+//!
+//! Emscripten hands 64-bit integers and by-value structs to JavaScript awkwardly, so these take
+//! only 32-bit integers, doubles, and pointers into the module's heap. Addresses are host-order
+//! `u32`s and time is in milliseconds since the engine was created, as JavaScript keeps it. The
+//! clock only moves when the caller advances it and the RNG is seeded by the caller, so a
+//! simulation replays identically.
+//!
+//! Everything the engine does comes back as a single stream of `catnip_event_t`s: frames it
+//! transmitted, and operations that completed, tagged with a token the caller picked when
+//! starting them. `wasm/catnip.js` wraps all of this in a small class.
+//!
+//! There are no threads anywhere in the engine, so the default single-threaded Emscripten build
+//! works: `cargo build --lib --features wasm --target wasm32-unknown-emscripten`, then link with
+//! `wasm/build.sh`.
+
+use super::{
+    catnip_opcode_t,
+    catnip_result_t,
+    FfiRuntime,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::Fail,
+    file_table::FileDescriptor,
+    fmt,
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        ip,
+        ipv4,
+        tcp,
+        udp,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::{
+        Operation,
+        SchedulerHandle,
+    },
+    sync::Bytes,
+};
+use libc::{
+    c_char,
+    c_int,
+};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    ffi::CString,
+    net::Ipv4Addr,
+    ptr,
+    slice,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum catnip_event_kind_t {
+    /// The engine transmitted `data`, which the caller should deliver (or lose) as it sees fit.
+    CATNIP_EVENT_FRAME = 0,
+    CATNIP_EVENT_FAILED,
+    CATNIP_EVENT_CONNECT,
+    CATNIP_EVENT_ACCEPT,
+    CATNIP_EVENT_PUSH,
+    CATNIP_EVENT_POP,
+}
+
+/// Every field is four bytes wide, so JavaScript can read the event as a `Uint32Array`. Release
+/// it with `catnip_sim_event_free`.
+#[repr(C)]
+pub struct catnip_event_t {
+    pub kind: catnip_event_kind_t,
+    /// The token passed when starting the operation; zero for frames.
+    pub token: u32,
+    pub qd: u32,
+    /// New connection for `CATNIP_EVENT_ACCEPT`.
+    pub new_qd: u32,
+    /// Stable error code for `CATNIP_EVENT_FAILED`; see `catnip_strerror`.
+    pub code: u32,
+    /// Sender of a UDP `CATNIP_EVENT_POP`, or zero.
+    pub remote_addr: u32,
+    pub remote_port: u32,
+    /// The frame for `CATNIP_EVENT_FRAME`, or the data for `CATNIP_EVENT_POP`.
+    pub data: *mut u8,
+    pub data_len: u32,
+    /// One-line description of a `CATNIP_EVENT_FRAME`'s frame, NUL-terminated.
+    pub summary: *mut c_char,
+}
+
+pub struct catnip_sim_t {
+    // Operations in flight and the caller's token for each, in the order they were started.
+    // Declared first so they're cancelled before the engine goes away.
+    pending: Vec<(u32, SchedulerHandle)>,
+    events: VecDeque<catnip_event_t>,
+    engine: Engine<FfiRuntime>,
+    origin: Instant,
+}
+
+impl catnip_event_t {
+    fn frame(frame: Bytes) -> Self {
+        let summary = CString::new(fmt::summary(&frame[..])).unwrap_or_default();
+        let data: Box<[u8]> = frame[..].into();
+        Self {
+            kind: catnip_event_kind_t::CATNIP_EVENT_FRAME,
+            token: 0,
+            qd: 0,
+            new_qd: 0,
+            code: 0,
+            remote_addr: 0,
+            remote_port: 0,
+            data_len: data.len() as u32,
+            data: Box::into_raw(data) as *mut u8,
+            summary: summary.into_raw(),
+        }
+    }
+
+    fn completion(token: u32, r: catnip_result_t) -> Self {
+        let kind = match r.opcode {
+            catnip_opcode_t::CATNIP_OPC_FAILED => catnip_event_kind_t::CATNIP_EVENT_FAILED,
+            catnip_opcode_t::CATNIP_OPC_CONNECT => catnip_event_kind_t::CATNIP_EVENT_CONNECT,
+            catnip_opcode_t::CATNIP_OPC_ACCEPT => catnip_event_kind_t::CATNIP_EVENT_ACCEPT,
+            catnip_opcode_t::CATNIP_OPC_PUSH => catnip_event_kind_t::CATNIP_EVENT_PUSH,
+            catnip_opcode_t::CATNIP_OPC_POP => catnip_event_kind_t::CATNIP_EVENT_POP,
+        };
+        let (remote_addr, remote_port) = if r.has_remote {
+            (
+                u32::from(Ipv4Addr::from(r.remote.addr)),
+                r.remote.port as u32,
+            )
+        } else {
+            (0, 0)
+        };
+        // The POP data's allocation moves over to the event.
+        Self {
+            kind,
+            token,
+            qd: r.qd,
+            new_qd: r.new_qd,
+            code: r.code,
+            remote_addr,
+            remote_port,
+            data: r.data,
+            data_len: r.data_len as u32,
+            summary: ptr::null_mut(),
+        }
+    }
+}
+
+impl catnip_sim_t {
+    fn start(&mut self, token: u32, op: Operation<FfiRuntime>) {
+        let handle = self.engine.rt().scheduler().insert(op);
+        self.pending.push((token, handle));
+    }
+
+    // Run whatever is ready and turn its results into events.
+    fn collect(&mut self) {
+        self.engine.poll_scheduler();
+        let scheduler = self.engine.rt().scheduler();
+        let mut i = 0;
+        while i < self.pending.len() {
+            if !self.pending[i].1.has_completed() {
+                i += 1;
+                continue;
+            }
+            let (token, handle) = self.pending.remove(i);
+            let (qd, result) = match scheduler.take(handle) {
+                Operation::Tcp(f) => f.expect_result(),
+                Operation::Udp(f) => f.expect_result(),
+                Operation::Background(..) => continue,
+            };
+            let event = catnip_event_t::completion(token, catnip_result_t::new(qd, result));
+            self.events.push_back(event);
+        }
+        while let Some(frame) = self.engine.rt().pop_frame() {
+            self.events.push_back(catnip_event_t::frame(frame));
+        }
+    }
+
+    fn check_qd(&self, qd: FileDescriptor) -> Result<(), Fail> {
+        if !self.engine.is_qd_valid(qd) {
+            return Err(Fail::ResourceNotFound {
+                details: "Bad queue descriptor",
+            });
+        }
+        Ok(())
+    }
+}
+
+fn sim_mut<'a>(sim: *mut catnip_sim_t) -> Result<&'a mut catnip_sim_t, Fail> {
+    unsafe { sim.as_mut() }.ok_or(Fail::Invalid {
+        details: "Null simulator",
+    })
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_nanos() as f64 / 1e6
+}
+
+fn endpoint(addr: u32, port: u32) -> Result<ipv4::Endpoint, Fail> {
+    let port = u16::try_from(port).map_err(|_| Fail::OutOfRange {
+        details: "Port must fit in 16 bits",
+    })?;
+    Ok(ipv4::Endpoint::new(
+        Ipv4Addr::from(addr),
+        ip::Port::try_from(port)?,
+    ))
+}
+
+// Run `f` against the simulator and collapse its result into a status code, writing any value
+// to `out`. Whatever `f` set off is collected as events before returning.
+fn with_sim<T>(
+    sim: *mut catnip_sim_t,
+    out: *mut T,
+    f: impl FnOnce(&mut catnip_sim_t) -> Result<T, Fail>,
+) -> c_int {
+    let r = sim_mut(sim).and_then(|s| {
+        let r = f(s);
+        s.collect();
+        r
+    });
+    super::status(r, out)
+}
+
+/// Create an engine with `link_addr` (6 bytes) and `ipv4_addr`, seeding its RNG from the 16
+/// bytes at `rng_seed`. Returns null if the addresses aren't usable.
+#[no_mangle]
+pub extern "C" fn catnip_sim_create(
+    ipv4_addr: u32,
+    link_addr: *const u8,
+    rng_seed: *const u8,
+) -> *mut catnip_sim_t {
+    if link_addr.is_null() || rng_seed.is_null() {
+        return ptr::null_mut();
+    }
+    let link_addr = MacAddress::from_bytes(unsafe { slice::from_raw_parts(link_addr, 6) });
+    let ipv4_addr = Ipv4Addr::from(ipv4_addr);
+    if link_addr.is_nil() || link_addr.is_broadcast() {
+        return ptr::null_mut();
+    }
+    if ipv4_addr.is_unspecified() || ipv4_addr.is_broadcast() {
+        return ptr::null_mut();
+    }
+    let mut seed = [0u8; 16];
+    seed.copy_from_slice(unsafe { slice::from_raw_parts(rng_seed, 16) });
+
+    let origin = Instant::now();
+    let rt = FfiRuntime::new(
+        origin,
+        link_addr,
+        ipv4_addr,
+        seed,
+        arp::Options::default(),
+        tcp::Options::default(),
+        udp::Options::default(),
+    );
+    match Engine::new(rt) {
+        Ok(engine) => {
+            let sim = catnip_sim_t {
+                pending: Vec::new(),
+                events: VecDeque::new(),
+                engine,
+                origin,
+            };
+            Box::into_raw(Box::new(sim))
+        },
+        Err(e) => {
+            warn!("Failed to create engine: {:?}", e);
+            ptr::null_mut()
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_destroy(sim: *mut catnip_sim_t) {
+    if sim.is_null() {
+        return;
+    }
+    let mut sim = unsafe { Box::from_raw(sim) };
+    for mut event in sim.events.drain(..) {
+        catnip_sim_event_free(&mut event);
+    }
+}
+
+/// Deliver a frame to the engine. Frames it drops are reported as errors but leave it unaffected.
+#[no_mangle]
+pub extern "C" fn catnip_sim_receive(sim: *mut catnip_sim_t, frame: *const u8, len: u32) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        let frame = unsafe { super::bytes(frame, len as usize)? };
+        s.engine.receive(Bytes::from_slice(frame))
+    })
+}
+
+/// Move the clock to `now_ms` milliseconds after the engine's creation, firing any timers that
+/// came due. The clock may not move backwards.
+#[no_mangle]
+pub extern "C" fn catnip_sim_advance_clock(sim: *mut catnip_sim_t, now_ms: f64) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        if !now_ms.is_finite() || now_ms < 0.0 {
+            return Err(Fail::Invalid {
+                details: "Invalid time",
+            });
+        }
+        // Round to the nanosecond so a time read back from `catnip_sim_next_timer` lands exactly
+        // on the timer.
+        let now = s.origin + Duration::from_nanos((now_ms * 1e6).round() as u64);
+        if now < s.engine.rt().now() {
+            return Err(Fail::Invalid {
+                details: "Clock moved backwards",
+            });
+        }
+        s.engine.advance_clock(now);
+        Ok(())
+    })
+}
+
+/// The engine's current time in milliseconds.
+#[no_mangle]
+pub extern "C" fn catnip_sim_now(sim: *mut catnip_sim_t) -> f64 {
+    match sim_mut(sim) {
+        Ok(s) => millis(s.engine.rt().now() - s.origin),
+        Err(..) => -1.0,
+    }
+}
+
+/// When the engine's next timer fires in milliseconds, or -1 if nothing is waiting. Advancing
+/// the clock straight there skips the idle time in between.
+#[no_mangle]
+pub extern "C" fn catnip_sim_next_timer(sim: *mut catnip_sim_t) -> f64 {
+    let s = match sim_mut(sim) {
+        Ok(s) => s,
+        Err(..) => return -1.0,
+    };
+    match s.engine.rt().next_expiry() {
+        Some(when) => millis(when - s.origin),
+        None => -1.0,
+    }
+}
+
+/// Move the oldest event into `event_out`. Returns `EAGAIN` if there isn't one.
+#[no_mangle]
+pub extern "C" fn catnip_sim_next_event(
+    sim: *mut catnip_sim_t,
+    event_out: *mut catnip_event_t,
+) -> c_int {
+    let s = match sim_mut(sim) {
+        Ok(s) => s,
+        Err(e) => return e.errno(),
+    };
+    if event_out.is_null() {
+        return libc::EINVAL;
+    }
+    match s.events.pop_front() {
+        Some(event) => {
+            unsafe { ptr::write(event_out, event) };
+            0
+        },
+        None => libc::EAGAIN,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_event_free(event: *mut catnip_event_t) {
+    let event = match unsafe { event.as_mut() } {
+        Some(e) => e,
+        None => return,
+    };
+    if !event.data.is_null() {
+        let data = ptr::slice_from_raw_parts_mut(event.data, event.data_len as usize);
+        drop(unsafe { Box::from_raw(data) });
+        event.data = ptr::null_mut();
+        event.data_len = 0;
+    }
+    if !event.summary.is_null() {
+        drop(unsafe { CString::from_raw(event.summary) });
+        event.summary = ptr::null_mut();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_tcp_socket(sim: *mut catnip_sim_t, qd_out: *mut u32) -> c_int {
+    with_sim(sim, qd_out, |s| Ok(s.engine.tcp_socket()))
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_udp_socket(sim: *mut catnip_sim_t, qd_out: *mut u32) -> c_int {
+    with_sim(sim, qd_out, |s| Ok(s.engine.socket(Protocol::Udp)))
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_bind(sim: *mut catnip_sim_t, qd: u32, addr: u32, port: u32) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        s.engine.bind(qd, endpoint(addr, port)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_tcp_listen(sim: *mut catnip_sim_t, qd: u32, backlog: u32) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        if backlog == 0 {
+            return Err(Fail::Invalid {
+                details: "Backlog must be positive",
+            });
+        }
+        s.engine.listen(qd, backlog as usize)
+    })
+}
+
+/// Accept one connection, reported as a `CATNIP_EVENT_ACCEPT` carrying `token`.
+#[no_mangle]
+pub extern "C" fn catnip_sim_tcp_accept(sim: *mut catnip_sim_t, qd: u32, token: u32) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        let op = s.engine.accept(qd);
+        s.start(token, op);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_tcp_connect(
+    sim: *mut catnip_sim_t,
+    qd: u32,
+    addr: u32,
+    port: u32,
+    token: u32,
+) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        let op = s.engine.connect(qd, endpoint(addr, port)?);
+        s.start(token, op);
+        Ok(())
+    })
+}
+
+/// Queue `len` bytes on a connection. The data is copied.
+#[no_mangle]
+pub extern "C" fn catnip_sim_tcp_write(
+    sim: *mut catnip_sim_t,
+    qd: u32,
+    data: *const u8,
+    len: u32,
+    token: u32,
+) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        let buf = Bytes::from_slice(unsafe { super::bytes(data, len as usize)? });
+        let op = s.engine.push(qd, buf);
+        s.start(token, op);
+        Ok(())
+    })
+}
+
+/// Wait for the next chunk of data on a connection, or the next datagram on a UDP socket.
+#[no_mangle]
+pub extern "C" fn catnip_sim_read(sim: *mut catnip_sim_t, qd: u32, token: u32) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        let op = s.engine.pop(qd);
+        s.start(token, op);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_udp_sendto(
+    sim: *mut catnip_sim_t,
+    qd: u32,
+    data: *const u8,
+    len: u32,
+    addr: u32,
+    port: u32,
+) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        let buf = Bytes::from_slice(unsafe { super::bytes(data, len as usize)? });
+        s.engine.udp_pushto(qd, buf, endpoint(addr, port)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn catnip_sim_close(sim: *mut catnip_sim_t, qd: u32) -> c_int {
+    with_sim(sim, ptr::null_mut(), |s| {
+        s.check_qd(qd)?;
+        s.engine.close(qd)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn new_sim(last_octet: u8) -> *mut catnip_sim_t {
+        let link_addr = [0x02, 0, 0, 0, 0, last_octet];
+        let seed = [last_octet; 16];
+        let ipv4_addr = u32::from(Ipv4Addr::new(10, 0, 0, last_octet));
+        let sim = catnip_sim_create(ipv4_addr, link_addr.as_ptr(), seed.as_ptr());
+        assert!(!sim.is_null());
+        sim
+    }
+
+    // Deliver `from`'s frames to `to`, returning the completions `from` reported along the way.
+    fn pump(from: *mut catnip_sim_t, to: *mut catnip_sim_t) -> Vec<catnip_event_t> {
+        let mut completions = vec![];
+        let mut event: catnip_event_t = unsafe { std::mem::zeroed() };
+        while catnip_sim_next_event(from, &mut event) == 0 {
+            if event.kind == catnip_event_kind_t::CATNIP_EVENT_FRAME {
+                let summary = unsafe { CStr::from_ptr(event.summary) };
+                assert!(!summary.to_bytes().is_empty());
+                let _ = catnip_sim_receive(to, event.data, event.data_len);
+                catnip_sim_event_free(&mut event);
+            } else {
+                completions.push(event);
+                event = unsafe { std::mem::zeroed() };
+            }
+        }
+        completions
+    }
+
+    #[test]
+    fn tcp_events() {
+        let alice = new_sim(1);
+        let bob = new_sim(2);
+        let bob_addr = u32::from(Ipv4Addr::new(10, 0, 0, 2));
+
+        let mut listen_qd = 0;
+        assert_eq!(catnip_sim_tcp_socket(bob, &mut listen_qd), 0);
+        assert_eq!(catnip_sim_bind(bob, listen_qd, bob_addr, 80), 0);
+        assert_eq!(catnip_sim_tcp_listen(bob, listen_qd, 4), 0);
+        assert_eq!(catnip_sim_tcp_accept(bob, listen_qd, 7), 0);
+
+        let mut qd = 0;
+        assert_eq!(catnip_sim_tcp_socket(alice, &mut qd), 0);
+        assert_eq!(catnip_sim_tcp_connect(alice, qd, bob_addr, 80, 8), 0);
+
+        // ARP, then the handshake.
+        let mut accepted = vec![];
+        let mut connected = vec![];
+        for _ in 0..4 {
+            connected.extend(pump(alice, bob));
+            accepted.extend(pump(bob, alice));
+        }
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].kind, catnip_event_kind_t::CATNIP_EVENT_ACCEPT);
+        assert_eq!(accepted[0].token, 7);
+        let bob_qd = accepted[0].new_qd;
+        assert_eq!(connected.len(), 1);
+        assert_eq!(connected[0].kind, catnip_event_kind_t::CATNIP_EVENT_CONNECT);
+        assert_eq!(connected[0].token, 8);
+
+        assert_eq!(catnip_sim_read(bob, bob_qd, 9), 0);
+        let msg = b"hello";
+        assert_eq!(
+            catnip_sim_tcp_write(alice, qd, msg.as_ptr(), msg.len() as u32, 10),
+            0
+        );
+        let pushed = pump(alice, bob);
+        assert_eq!(pushed[0].kind, catnip_event_kind_t::CATNIP_EVENT_PUSH);
+        let mut popped = pump(bob, alice);
+        assert_eq!(popped[0].kind, catnip_event_kind_t::CATNIP_EVENT_POP);
+        assert_eq!(popped[0].token, 9);
+        let data = unsafe { slice::from_raw_parts(popped[0].data, popped[0].data_len as usize) };
+        assert_eq!(data, &msg[..]);
+        catnip_sim_event_free(&mut popped[0]);
+
+        // Skip straight to Bob's next timer.
+        let deadline = catnip_sim_next_timer(bob);
+        assert!(deadline > catnip_sim_now(bob));
+        assert_eq!(catnip_sim_advance_clock(bob, deadline), 0);
+        assert_eq!(catnip_sim_now(bob), deadline);
+        assert!(catnip_sim_next_timer(bob) != deadline);
+        assert_eq!(catnip_sim_advance_clock(bob, 0.0), libc::EINVAL);
+        assert_eq!(catnip_sim_advance_clock(bob, f64::NAN), libc::EINVAL);
+
+        catnip_sim_destroy(alice);
+        catnip_sim_destroy(bob);
+    }
+}
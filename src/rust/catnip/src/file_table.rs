@@ -26,8 +26,11 @@ impl FileTable {
     }
 
     pub fn is_valid(&self, fd: FileDescriptor) -> bool {
+        if fd == 0 {
+            return false;
+        }
         let inner = self.inner.borrow();
-        inner.table.contains(fd as usize)
+        inner.table.contains(fd as usize - 1)
     }
 
     pub fn alloc(&self, file: File) -> FileDescriptor {
@@ -0,0 +1,668 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Human-readable rendering of frames for diagnostics.
+//!
+//! [`Summary`] renders a frame as a single tshark-style line, and [`Detail`] renders it as an
+//! indented, per-layer breakdown that includes every decoded TCP option. Both are `Display`
+//! wrappers, so they only do work when a log line is actually emitted:
+//!
+//! ```ignore
+//! debug!("Engine received {}", fmt::Summary(&frame[..]));
+//! ```
+//!
+//! Decoding here is deliberately more lenient than the protocol parsers: frames with bad
+//! checksums or unsupported fields are still rendered (with the problem noted) rather than
+//! rejected, since those are usually the frames you want to look at.
+
+use crate::{
+    protocols::{
+        ethernet2::MacAddress,
+        ipv4::{
+            Ipv4Header,
+            Ipv4Protocol2,
+        },
+        tcp::segment::{
+            TcpHeader,
+            TcpOptions2,
+        },
+    },
+    runtime::RuntimeBuf,
+    sync::Bytes,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    fmt::{
+        self,
+        Display,
+        Formatter,
+    },
+    net::Ipv4Addr,
+};
+
+const ETHERNET2_HEADER_SIZE: usize = 14;
+const ARP_MESSAGE_SIZE: usize = 28;
+const IPV4_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+const ICMPV4_HEADER_SIZE: usize = 8;
+
+const ETHERTYPE_ARP: u16 = 0x806;
+const ETHERTYPE_IPV4: u16 = 0x800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+const IPPROTO_ICMP: u8 = 0x01;
+const IPPROTO_TCP: u8 = 0x06;
+const IPPROTO_UDP: u8 = 0x11;
+const IPPROTO_GRE: u8 = 0x2f;
+
+/// Renders an Ethernet frame as a one-line summary.
+pub struct Summary<'a>(pub &'a [u8]);
+
+/// Renders an Ethernet frame as a multi-line, per-layer breakdown.
+pub struct Detail<'a>(pub &'a [u8]);
+
+pub fn summary(frame: &[u8]) -> String {
+    Summary(frame).to_string()
+}
+
+pub fn detail(frame: &[u8]) -> String {
+    Detail(frame).to_string()
+}
+
+impl<'a> Display for Summary<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let frame = self.0;
+        let eth = match Ethernet::decode(frame) {
+            Some(eth) => eth,
+            None => return write!(f, "[Malformed Ethernet frame, {} bytes]", frame.len()),
+        };
+        match eth.ether_type {
+            ETHERTYPE_ARP => summarize_arp(f, &eth, frame.len()),
+            ETHERTYPE_IPV4 => summarize_ipv4(f, &eth, frame.len()),
+            other => write!(
+                f,
+                "{} → {} 0x{:04x} {}",
+                eth.src_addr,
+                eth.dst_addr,
+                other,
+                frame.len()
+            ),
+        }
+    }
+}
+
+impl<'a> Display for Detail<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let frame = self.0;
+        writeln!(f, "Frame: {} bytes", frame.len())?;
+        let eth = match Ethernet::decode(frame) {
+            Some(eth) => eth,
+            None => return writeln!(f, "[Malformed Ethernet frame]"),
+        };
+        writeln!(
+            f,
+            "Ethernet II, Src: {}, Dst: {}, Type: {} (0x{:04x})",
+            eth.src_addr,
+            eth.dst_addr,
+            ether_type_name(eth.ether_type),
+            eth.ether_type
+        )?;
+        match eth.ether_type {
+            ETHERTYPE_ARP => detail_arp(f, eth.payload),
+            ETHERTYPE_IPV4 => detail_ipv4(f, eth.payload),
+            _ => writeln!(f, "Data ({} bytes)", eth.payload.len()),
+        }
+    }
+}
+
+/// Renders the header as a one-liner, e.g. `49152 → 80 [SYN] Seq=1 Ack=0 Win=1024 MSS=1450`.
+impl Display for TcpHeader {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} → {} ", self.src_port, self.dst_port)?;
+        write_tcp_flags(f, self)?;
+        write!(
+            f,
+            " Seq={} Ack={} Win={}",
+            self.seq_num, self.ack_num, self.window_size
+        )?;
+        for option in self.iter_options() {
+            write_tcp_option_short(f, option)?;
+        }
+        Ok(())
+    }
+}
+
+struct Ethernet<'a> {
+    dst_addr: MacAddress,
+    src_addr: MacAddress,
+    ether_type: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> Ethernet<'a> {
+    fn decode(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < ETHERNET2_HEADER_SIZE {
+            return None;
+        }
+        Some(Self {
+            dst_addr: MacAddress::from_bytes(&frame[0..6]),
+            src_addr: MacAddress::from_bytes(&frame[6..12]),
+            ether_type: NetworkEndian::read_u16(&frame[12..14]),
+            payload: &frame[ETHERNET2_HEADER_SIZE..],
+        })
+    }
+}
+
+struct Arp {
+    operation: u16,
+    sender_hardware_addr: MacAddress,
+    sender_protocol_addr: Ipv4Addr,
+    target_hardware_addr: MacAddress,
+    target_protocol_addr: Ipv4Addr,
+}
+
+impl Arp {
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < ARP_MESSAGE_SIZE {
+            return None;
+        }
+        Some(Self {
+            operation: NetworkEndian::read_u16(&buf[6..8]),
+            sender_hardware_addr: MacAddress::from_bytes(&buf[8..14]),
+            sender_protocol_addr: Ipv4Addr::from(NetworkEndian::read_u32(&buf[14..18])),
+            target_hardware_addr: MacAddress::from_bytes(&buf[18..24]),
+            target_protocol_addr: Ipv4Addr::from(NetworkEndian::read_u32(&buf[24..28])),
+        })
+    }
+}
+
+struct Ipv4<'a> {
+    header_len: usize,
+    dscp: u8,
+    ecn: u8,
+    total_length: usize,
+    identification: u16,
+    flags: u8,
+    fragment_offset: u16,
+    time_to_live: u8,
+    protocol: u8,
+    checksum: u16,
+    checksum_valid: bool,
+    src_addr: Ipv4Addr,
+    dst_addr: Ipv4Addr,
+    payload: &'a [u8],
+}
+
+impl<'a> Ipv4<'a> {
+    fn decode(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < IPV4_HEADER_SIZE || buf[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (buf[0] & 0xf) as usize * 4;
+        if header_len < IPV4_HEADER_SIZE || header_len > buf.len() {
+            return None;
+        }
+        let total_length = NetworkEndian::read_u16(&buf[2..4]) as usize;
+        // Tolerate a TOTALLEN that disagrees with the buffer, but never read past either.
+        let end = if total_length >= header_len && total_length <= buf.len() {
+            total_length
+        } else {
+            buf.len()
+        };
+        let flags_and_offset = NetworkEndian::read_u16(&buf[6..8]);
+        Some(Self {
+            header_len,
+            dscp: buf[1] >> 2,
+            ecn: buf[1] & 3,
+            total_length,
+            identification: NetworkEndian::read_u16(&buf[4..6]),
+            flags: (flags_and_offset >> 13) as u8,
+            fragment_offset: flags_and_offset & 0x1fff,
+            time_to_live: buf[8],
+            protocol: buf[9],
+            checksum: NetworkEndian::read_u16(&buf[10..12]),
+            checksum_valid: ones_complement_sum(&buf[..header_len]) == 0xffff,
+            src_addr: Ipv4Addr::from(NetworkEndian::read_u32(&buf[12..16])),
+            dst_addr: Ipv4Addr::from(NetworkEndian::read_u32(&buf[16..20])),
+            payload: &buf[header_len..end],
+        })
+    }
+
+    fn header(&self) -> Option<Ipv4Header> {
+        let protocol = match self.protocol {
+            IPPROTO_ICMP => Ipv4Protocol2::Icmpv4,
+            IPPROTO_TCP => Ipv4Protocol2::Tcp,
+            IPPROTO_UDP => Ipv4Protocol2::Udp,
+            IPPROTO_GRE => Ipv4Protocol2::Gre,
+            _ => return None,
+        };
+        Some(Ipv4Header::new(self.src_addr, self.dst_addr, protocol))
+    }
+}
+
+fn ones_complement_sum(buf: &[u8]) -> u16 {
+    let mut state = 0u32;
+    for chunk in buf.chunks(2) {
+        let word = if chunk.len() == 2 {
+            NetworkEndian::read_u16(chunk)
+        } else {
+            (chunk[0] as u16) << 8
+        };
+        state += word as u32;
+    }
+    while state > 0xffff {
+        state = (state & 0xffff) + (state >> 16);
+    }
+    state as u16
+}
+
+fn ether_type_name(ether_type: u16) -> &'static str {
+    match ether_type {
+        ETHERTYPE_ARP => "ARP",
+        ETHERTYPE_IPV4 => "IPv4",
+        ETHERTYPE_IPV6 => "IPv6",
+        _ => "Unknown",
+    }
+}
+
+fn ip_protocol_name(protocol: u8) -> &'static str {
+    match protocol {
+        IPPROTO_ICMP => "ICMP",
+        IPPROTO_TCP => "TCP",
+        IPPROTO_UDP => "UDP",
+        IPPROTO_GRE => "GRE",
+        _ => "Unknown",
+    }
+}
+
+fn icmpv4_type_name(type_byte: u8) -> &'static str {
+    match type_byte {
+        0 => "Echo (ping) reply",
+        3 => "Destination unreachable",
+        4 => "Source quench",
+        5 => "Redirect",
+        8 => "Echo (ping) request",
+        9 => "Router advertisement",
+        10 => "Router solicitation",
+        11 => "Time-to-live exceeded",
+        12 => "Parameter problem",
+        13 => "Timestamp request",
+        14 => "Timestamp reply",
+        _ => "Unknown",
+    }
+}
+
+fn decode_tcp(ipv4: &Ipv4) -> Result<(TcpHeader, usize), String> {
+    let ipv4_hdr = ipv4.header().unwrap();
+    // Checksums are verified by the receive path; here we only want the decoded fields.
+    match TcpHeader::parse(&ipv4_hdr, Bytes::from_slice(ipv4.payload), true) {
+        Ok((hdr, data)) => Ok((hdr, data.len())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn summarize_arp(f: &mut Formatter, eth: &Ethernet, frame_len: usize) -> fmt::Result {
+    write!(f, "{} → {} ARP {} ", eth.src_addr, eth.dst_addr, frame_len)?;
+    let arp = match Arp::decode(eth.payload) {
+        Some(arp) => arp,
+        None => return write!(f, "[Malformed ARP]"),
+    };
+    match arp.operation {
+        1 => write!(
+            f,
+            "Who has {}? Tell {}",
+            arp.target_protocol_addr, arp.sender_protocol_addr
+        ),
+        2 => write!(
+            f,
+            "{} is at {}",
+            arp.sender_protocol_addr, arp.sender_hardware_addr
+        ),
+        op => write!(f, "Unknown operation {}", op),
+    }
+}
+
+fn summarize_ipv4(f: &mut Formatter, eth: &Ethernet, frame_len: usize) -> fmt::Result {
+    let ipv4 = match Ipv4::decode(eth.payload) {
+        Some(ipv4) => ipv4,
+        None => {
+            return write!(
+                f,
+                "{} → {} IPv4 {} [Malformed IPv4]",
+                eth.src_addr, eth.dst_addr, frame_len
+            )
+        },
+    };
+    write!(
+        f,
+        "{} → {} {} {} ",
+        ipv4.src_addr,
+        ipv4.dst_addr,
+        ip_protocol_name(ipv4.protocol),
+        frame_len
+    )?;
+    match ipv4.protocol {
+        IPPROTO_TCP => match decode_tcp(&ipv4) {
+            Ok((hdr, data_len)) => {
+                write!(f, "{} → {} ", hdr.src_port, hdr.dst_port)?;
+                write_tcp_flags(f, &hdr)?;
+                write!(
+                    f,
+                    " Seq={} Ack={} Win={} Len={}",
+                    hdr.seq_num, hdr.ack_num, hdr.window_size, data_len
+                )?;
+                for option in hdr.iter_options() {
+                    write_tcp_option_short(f, option)?;
+                }
+            },
+            Err(e) => write!(f, "[Malformed TCP: {}]", e)?,
+        },
+        IPPROTO_UDP => {
+            if ipv4.payload.len() < UDP_HEADER_SIZE {
+                write!(f, "[Malformed UDP]")?;
+            } else {
+                let buf = ipv4.payload;
+                write!(
+                    f,
+                    "{} → {} Len={}",
+                    NetworkEndian::read_u16(&buf[0..2]),
+                    NetworkEndian::read_u16(&buf[2..4]),
+                    buf.len() - UDP_HEADER_SIZE
+                )?;
+            }
+        },
+        IPPROTO_ICMP => {
+            if ipv4.payload.len() < ICMPV4_HEADER_SIZE {
+                write!(f, "[Malformed ICMP]")?;
+            } else {
+                let buf = ipv4.payload;
+                write!(f, "{}", icmpv4_type_name(buf[0]))?;
+                if buf[0] == 0 || buf[0] == 8 {
+                    write!(
+                        f,
+                        " id=0x{:04x}, seq={}",
+                        NetworkEndian::read_u16(&buf[4..6]),
+                        NetworkEndian::read_u16(&buf[6..8])
+                    )?;
+                }
+                write!(f, ", ttl={}", ipv4.time_to_live)?;
+            }
+        },
+        other => write!(f, "IP protocol {}", other)?,
+    }
+    if ipv4.fragment_offset != 0 || ipv4.flags & 0x1 != 0 {
+        write!(f, " [Fragment offset={}]", ipv4.fragment_offset as usize * 8)?;
+    }
+    if !ipv4.checksum_valid {
+        write!(f, " [Bad IPv4 checksum]")?;
+    }
+    Ok(())
+}
+
+fn detail_arp(f: &mut Formatter, buf: &[u8]) -> fmt::Result {
+    let arp = match Arp::decode(buf) {
+        Some(arp) => arp,
+        None => return writeln!(f, "[Malformed ARP]"),
+    };
+    let operation = match arp.operation {
+        1 => "request",
+        2 => "reply",
+        _ => "unknown",
+    };
+    writeln!(f, "Address Resolution Protocol ({})", operation)?;
+    writeln!(
+        f,
+        "    Sender: {} ({})",
+        arp.sender_protocol_addr, arp.sender_hardware_addr
+    )?;
+    writeln!(
+        f,
+        "    Target: {} ({})",
+        arp.target_protocol_addr, arp.target_hardware_addr
+    )
+}
+
+fn detail_ipv4(f: &mut Formatter, buf: &[u8]) -> fmt::Result {
+    let ipv4 = match Ipv4::decode(buf) {
+        Some(ipv4) => ipv4,
+        None => return writeln!(f, "[Malformed IPv4]"),
+    };
+    writeln!(
+        f,
+        "Internet Protocol Version 4, Src: {}, Dst: {}",
+        ipv4.src_addr, ipv4.dst_addr
+    )?;
+    writeln!(
+        f,
+        "    Header Length: {}, DSCP: {}, ECN: {}, Total Length: {}",
+        ipv4.header_len, ipv4.dscp, ipv4.ecn, ipv4.total_length
+    )?;
+    writeln!(
+        f,
+        "    Identification: 0x{:04x}, Flags: 0x{:x}, Fragment Offset: {}",
+        ipv4.identification,
+        ipv4.flags,
+        ipv4.fragment_offset as usize * 8
+    )?;
+    writeln!(
+        f,
+        "    TTL: {}, Protocol: {} ({}), Checksum: 0x{:04x} [{}]",
+        ipv4.time_to_live,
+        ip_protocol_name(ipv4.protocol),
+        ipv4.protocol,
+        ipv4.checksum,
+        if ipv4.checksum_valid { "valid" } else { "invalid" }
+    )?;
+    match ipv4.protocol {
+        IPPROTO_TCP => detail_tcp(f, &ipv4),
+        IPPROTO_UDP => detail_udp(f, ipv4.payload),
+        IPPROTO_ICMP => detail_icmpv4(f, ipv4.payload),
+        _ => writeln!(f, "Data ({} bytes)", ipv4.payload.len()),
+    }
+}
+
+fn detail_tcp(f: &mut Formatter, ipv4: &Ipv4) -> fmt::Result {
+    let (hdr, data_len) = match decode_tcp(ipv4) {
+        Ok(r) => r,
+        Err(e) => return writeln!(f, "[Malformed TCP: {}]", e),
+    };
+    writeln!(
+        f,
+        "Transmission Control Protocol, Src Port: {}, Dst Port: {}, Seq: {}, Ack: {}, Len: {}",
+        hdr.src_port, hdr.dst_port, hdr.seq_num, hdr.ack_num, data_len
+    )?;
+    write!(f, "    Flags: ")?;
+    write_tcp_flags(f, &hdr)?;
+    writeln!(
+        f,
+        ", Window: {}, Urgent Pointer: {}",
+        hdr.window_size, hdr.urgent_pointer
+    )?;
+    let mut options = hdr.iter_options().peekable();
+    if options.peek().is_some() {
+        writeln!(f, "    Options:")?;
+    }
+    for option in options {
+        write!(f, "        ")?;
+        match option {
+            TcpOptions2::NoOperation => writeln!(f, "No-Operation (NOP)")?,
+            TcpOptions2::MaximumSegmentSize(mss) => {
+                writeln!(f, "Maximum segment size: {} bytes", mss)?
+            },
+            TcpOptions2::WindowScale(scale) => writeln!(
+                f,
+                "Window scale: {} (multiply by {})",
+                scale,
+                1u32.checked_shl(*scale as u32).unwrap_or(0)
+            )?,
+            TcpOptions2::SelectiveAcknowlegementPermitted => writeln!(f, "SACK permitted")?,
+            TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } => {
+                write!(f, "SACK:")?;
+                for sack in &sacks[..*num_sacks] {
+                    write!(f, " {}-{}", sack.begin, sack.end)?;
+                }
+                writeln!(f)?;
+            },
+            TcpOptions2::Timestamp {
+                sender_timestamp,
+                echo_timestamp,
+            } => writeln!(
+                f,
+                "Timestamps: TSval {}, TSecr {}",
+                sender_timestamp, echo_timestamp
+            )?,
+        }
+    }
+    Ok(())
+}
+
+fn detail_udp(f: &mut Formatter, buf: &[u8]) -> fmt::Result {
+    if buf.len() < UDP_HEADER_SIZE {
+        return writeln!(f, "[Malformed UDP]");
+    }
+    writeln!(
+        f,
+        "User Datagram Protocol, Src Port: {}, Dst Port: {}",
+        NetworkEndian::read_u16(&buf[0..2]),
+        NetworkEndian::read_u16(&buf[2..4])
+    )?;
+    writeln!(
+        f,
+        "    Length: {}, Checksum: 0x{:04x}, Data: {} bytes",
+        NetworkEndian::read_u16(&buf[4..6]),
+        NetworkEndian::read_u16(&buf[6..8]),
+        buf.len() - UDP_HEADER_SIZE
+    )
+}
+
+fn detail_icmpv4(f: &mut Formatter, buf: &[u8]) -> fmt::Result {
+    if buf.len() < ICMPV4_HEADER_SIZE {
+        return writeln!(f, "[Malformed ICMP]");
+    }
+    writeln!(f, "Internet Control Message Protocol")?;
+    writeln!(
+        f,
+        "    Type: {} ({}), Code: {}, Checksum: 0x{:04x}",
+        buf[0],
+        icmpv4_type_name(buf[0]),
+        buf[1],
+        NetworkEndian::read_u16(&buf[2..4])
+    )?;
+    if buf[0] == 0 || buf[0] == 8 {
+        writeln!(
+            f,
+            "    Identifier: 0x{:04x}, Sequence Number: {}",
+            NetworkEndian::read_u16(&buf[4..6]),
+            NetworkEndian::read_u16(&buf[6..8])
+        )?;
+    }
+    writeln!(f, "    Data: {} bytes", buf.len() - ICMPV4_HEADER_SIZE)
+}
+
+fn write_tcp_flags(f: &mut Formatter, hdr: &TcpHeader) -> fmt::Result {
+    let flags = [
+        (hdr.fin, "FIN"),
+        (hdr.syn, "SYN"),
+        (hdr.rst, "RST"),
+        (hdr.psh, "PSH"),
+        (hdr.ack, "ACK"),
+        (hdr.urg, "URG"),
+        (hdr.ece, "ECE"),
+        (hdr.cwr, "CWR"),
+        (hdr.ns, "NS"),
+    ];
+    write!(f, "[")?;
+    let mut first = true;
+    for &(set, name) in &flags {
+        if !set {
+            continue;
+        }
+        if !first {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", name)?;
+        first = false;
+    }
+    write!(f, "]")
+}
+
+fn write_tcp_option_short(f: &mut Formatter, option: &TcpOptions2) -> fmt::Result {
+    match option {
+        TcpOptions2::NoOperation => Ok(()),
+        TcpOptions2::MaximumSegmentSize(mss) => write!(f, " MSS={}", mss),
+        TcpOptions2::WindowScale(scale) => write!(f, " WS={}", scale),
+        TcpOptions2::SelectiveAcknowlegementPermitted => write!(f, " SACK_PERM"),
+        TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } => {
+            for sack in &sacks[..*num_sacks] {
+                write!(f, " SLE={} SRE={}", sack.begin, sack.end)?;
+            }
+            Ok(())
+        },
+        TcpOptions2::Timestamp {
+            sender_timestamp,
+            echo_timestamp,
+        } => write!(f, " TSval={} TSecr={}", sender_timestamp, echo_timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocols::{
+            ip,
+            ipv4,
+        },
+        test_helpers,
+    };
+    use std::{
+        convert::TryFrom,
+        time::Instant,
+    };
+
+    #[test]
+    fn tcp_syn() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let fd = alice.tcp_socket();
+        let remote = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+        let _connect = alice.tcp_connect(fd, remote);
+        alice.rt().poll_scheduler();
+        let syn = alice.rt().pop_frame();
+
+        let line = summary(&syn[..]);
+        assert!(line.starts_with("192.168.1.1 → 192.168.1.2 TCP "), "{}", line);
+        assert!(line.contains(" → 80 [SYN] Seq="), "{}", line);
+        assert!(line.contains("Len=0 MSS=2048 WS=2"), "{}", line);
+        assert!(!line.contains('\n'));
+
+        let lines = detail(&syn[..]);
+        assert!(lines.contains("Maximum segment size: 2048 bytes"), "{}", lines);
+        assert!(lines.contains("Window scale: 2 (multiply by 4)"), "{}", lines);
+        assert!(lines.contains("Checksum: 0x"), "{}", lines);
+        assert!(!lines.contains("invalid"), "{}", lines);
+    }
+
+    #[test]
+    fn arp_request() {
+        let mut frame = vec![0u8; 42];
+        frame[0..6].copy_from_slice(&MacAddress::broadcast().octets());
+        frame[6..12].copy_from_slice(&test_helpers::ALICE_MAC.octets());
+        NetworkEndian::write_u16(&mut frame[12..14], ETHERTYPE_ARP);
+        let arp = &mut frame[ETHERNET2_HEADER_SIZE..];
+        NetworkEndian::write_u16(&mut arp[0..2], 1);
+        NetworkEndian::write_u16(&mut arp[2..4], ETHERTYPE_IPV4);
+        arp[4] = 6;
+        arp[5] = 4;
+        NetworkEndian::write_u16(&mut arp[6..8], 1);
+        arp[8..14].copy_from_slice(&test_helpers::ALICE_MAC.octets());
+        arp[14..18].copy_from_slice(&test_helpers::ALICE_IPV4.octets());
+        arp[24..28].copy_from_slice(&test_helpers::BOB_IPV4.octets());
+
+        assert!(summary(&frame[..]).ends_with("ARP 42 Who has 192.168.1.2? Tell 192.168.1.1"));
+        assert_eq!(summary(&frame[..10]), "[Malformed Ethernet frame, 10 bytes]");
+    }
+}
@@ -66,6 +66,14 @@ pub struct dmtr_qresult_t {
 }
 
 impl dmtr_qresult_t {
+    /// Status code for a completed operation: zero on success, otherwise its `errno`.
+    pub fn errno<RT: Runtime>(result: &OperationResult<RT>) -> c_int {
+        match result {
+            OperationResult::Failed(e) => e.errno(),
+            _ => 0,
+        }
+    }
+
     pub fn pack<RT: Runtime>(rt: &RT, result: OperationResult<RT>, qd: FileDescriptor, qt: u64) -> Self {
         match result {
             OperationResult::Connect => Self {
@@ -109,8 +117,12 @@ impl dmtr_qresult_t {
                     qr_value,
                 }
             },
-            OperationResult::Failed(e) => {
-                panic!("Unhandled error: {:?}", e);
+            // The caller reports the error itself (see `dmtr_qresult_t::errno`).
+            OperationResult::Failed(..) => Self {
+                qr_opcode: dmtr_opcode_t::DMTR_OPC_INVALID,
+                qr_qd: qd as c_int,
+                qr_qt: qt,
+                qr_value: unsafe { mem::zeroed() },
             },
         }
     }
@@ -27,18 +27,32 @@ extern crate log;
 #[macro_use]
 extern crate derive_more;
 
+pub mod backends;
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod collections;
 pub mod engine;
+pub mod event;
 pub mod fail;
+pub mod ffi;
 pub mod file_table;
+pub mod fmt;
 pub mod interop;
 pub mod libos;
 pub mod logging;
+pub mod metrics;
 pub mod operations;
 pub mod options;
+pub mod pcap;
 pub mod protocols;
+pub mod replay;
 pub mod runtime;
 pub mod scheduler;
+pub mod snapshot;
+pub mod socket;
+pub mod stats;
 pub mod sync;
 pub mod test_helpers;
 pub mod timer;
+#[cfg(all(feature = "tokio", unix))]
+pub mod tokio_adapter;
@@ -9,18 +9,37 @@ use crate::{
         dmtr_qresult_t,
         dmtr_sgarray_t,
     },
-    protocols::ipv4::Endpoint,
+    protocols::{
+        gre,
+        ipv4::{
+            Endpoint,
+            Filter,
+            RateLimit,
+            Scheduling,
+            TrafficClass,
+        },
+        sntp,
+        vxlan,
+    },
     runtime::Runtime,
     scheduler::{
         Operation,
         SchedulerHandle,
     },
+    snapshot::EngineSnapshot,
     operations::OperationResult,
+    replay::Log,
 };
 use must_let::must_let;
 use libc::c_int;
 use std::{
-    time::Instant,
+    collections::HashMap,
+    net::Ipv4Addr,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
 };
 use tracy_client::static_span;
 
@@ -50,6 +69,81 @@ impl<RT: Runtime> LibOS<RT> {
         &self.rt
     }
 
+    pub fn start_recording(&mut self) {
+        self.engine.start_recording();
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Log> {
+        self.engine.stop_recording()
+    }
+
+    pub fn sntp_start(&mut self, options: sntp::Options) -> Result<(), Fail> {
+        self.engine.sntp_start(options)
+    }
+
+    pub fn wall_clock(&self) -> Option<SystemTime> {
+        self.engine.wall_clock()
+    }
+
+    pub fn filter(&self) -> &Filter {
+        self.engine.filter()
+    }
+
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.engine.set_rate_limit(limit)
+    }
+
+    pub fn tcp_set_rate_limit(
+        &mut self,
+        fd: FileDescriptor,
+        limit: Option<RateLimit>,
+    ) -> Result<(), Fail> {
+        self.engine.tcp_set_rate_limit(fd, limit)
+    }
+
+    pub fn gre_open(&mut self, options: gre::Options) {
+        self.engine.gre_open(options)
+    }
+
+    pub fn gre_close(&mut self) {
+        self.engine.gre_close()
+    }
+
+    pub fn set_egress_scheduling(&mut self, scheduling: Scheduling) {
+        self.engine.set_egress_scheduling(scheduling)
+    }
+
+    pub fn tcp_set_traffic_class(
+        &mut self,
+        fd: FileDescriptor,
+        class: Option<TrafficClass>,
+    ) -> Result<(), Fail> {
+        self.engine.tcp_set_traffic_class(fd, class)
+    }
+
+    pub fn snapshot(&self) -> EngineSnapshot {
+        self.engine.snapshot()
+    }
+
+    pub fn restore(
+        &mut self,
+        snapshot: &EngineSnapshot,
+    ) -> Result<HashMap<FileDescriptor, FileDescriptor>, Fail> {
+        self.engine.restore(snapshot)
+    }
+
+    pub fn vxlan_start(&mut self, options: vxlan::Options) -> Result<(), Fail> {
+        self.engine.vxlan_start(options)
+    }
+
+    pub fn vxlan_attach(
+        &mut self,
+        vni: u32,
+        remotes: Vec<Ipv4Addr>,
+    ) -> Result<vxlan::VxlanDevice<RT>, Fail> {
+        self.engine.vxlan_attach(vni, remotes)
+    }
+
     pub fn socket(
         &mut self,
         domain: c_int,
@@ -173,31 +267,76 @@ impl<RT: Runtime> LibOS<RT> {
 
     pub fn wait_any(&mut self, qts: &[QToken]) -> (usize, dmtr_qresult_t) {
         let _s = static_span!();
+        let (i, qd, r) = self.wait_any2(qts);
+        (i, dmtr_qresult_t::pack(&self.rt, r, qd, qts[i]))
+    }
+
+    pub fn wait_any2(&mut self, qts: &[QToken]) -> (usize, FileDescriptor, OperationResult<RT>) {
         loop {
-            self.poll_bg_work();
-            for (i, &qt) in qts.iter().enumerate() {
-                let handle = self.rt.scheduler().from_raw_handle(qt).unwrap();
-                if handle.has_completed() {
-                    let (qd, r) = self.take_operation(handle);
-                    return (i, dmtr_qresult_t::pack(&self.rt, r, qd, qt));
-                }
-                handle.into_raw();
+            if let Some(r) = self.poll_any2(qts) {
+                return r;
             }
         }
     }
 
-    pub fn wait_any2(&mut self, qts: &[QToken]) -> (usize, FileDescriptor, OperationResult<RT>) {
+    /// Like `wait_any2`, but gives up after `timeout`. Tokens that haven't completed remain valid.
+    pub fn timedwait_any2(
+        &mut self,
+        qts: &[QToken],
+        timeout: Duration,
+    ) -> Option<(usize, FileDescriptor, OperationResult<RT>)> {
+        let deadline = Instant::now() + timeout;
         loop {
+            if let Some(r) = self.poll_any2(qts) {
+                return Some(r);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+        }
+    }
+
+    /// Make one pass over background work, returning the first completed operation in `qts` (by
+    /// position) if there is one. Only the returned token is invalidated.
+    pub fn poll_any2(
+        &mut self,
+        qts: &[QToken],
+    ) -> Option<(usize, FileDescriptor, OperationResult<RT>)> {
+        self.poll_bg_work();
+        for (i, &qt) in qts.iter().enumerate() {
+            let handle = match self.rt.scheduler().from_raw_handle(qt) {
+                Some(h) => h,
+                None => panic!("Invalid handle {}", qt),
+            };
+            if handle.has_completed() {
+                let (qd, r) = self.take_operation(handle);
+                return Some((i, qd, r));
+            }
+            handle.into_raw();
+        }
+        None
+    }
+
+    /// Wait for every operation in `qts` to complete, returning their results in order.
+    pub fn wait_all2(&mut self, qts: &[QToken]) -> Vec<(FileDescriptor, OperationResult<RT>)> {
+        let mut results: Vec<Option<_>> = qts.iter().map(|_| None).collect();
+        let mut remaining = qts.len();
+        while remaining > 0 {
             self.poll_bg_work();
             for (i, &qt) in qts.iter().enumerate() {
+                if results[i].is_some() {
+                    continue;
+                }
                 let handle = self.rt.scheduler().from_raw_handle(qt).unwrap();
                 if handle.has_completed() {
-                    let (qd, r) = self.take_operation(handle);
-                    return (i, qd, r);
+                    results[i] = Some(self.take_operation(handle));
+                    remaining -= 1;
+                } else {
+                    handle.into_raw();
                 }
-                handle.into_raw();
             }
         }
+        results.into_iter().map(|r| r.unwrap()).collect()
     }
 
     pub fn is_qd_valid(&self, fd: FileDescriptor) -> bool {
@@ -214,7 +353,7 @@ impl<RT: Runtime> LibOS<RT> {
 
     fn poll_bg_work(&mut self) {
         let _s = static_span!();
-        self.rt.scheduler().poll();
+        self.engine.poll_scheduler();
         for _ in 0..MAX_RECV_ITERS {
             let batch = self.rt.receive();
             if batch.is_empty() {
@@ -228,8 +367,83 @@ impl<RT: Runtime> LibOS<RT> {
         }
         if self.ts_iters == 0 {
             let _t = static_span!("advance_clock");
-            self.rt.advance_clock(Instant::now());
+            self.engine.advance_clock(Instant::now());
         }
         self.ts_iters = (self.ts_iters + 1) % TIMER_RESOLUTION;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::LibOS;
+    use crate::{
+        operations::OperationResult,
+        protocols::{
+            ip,
+            ipv4,
+        },
+        runtime::RuntimeBuf,
+        sync::Bytes,
+        test_helpers::{
+            self,
+            TestRuntime,
+        },
+    };
+    use must_let::must_let;
+    use std::{
+        convert::TryFrom,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    #[test]
+    fn wait_any_udp() {
+        let now = Instant::now();
+        let alice_rt = TestRuntime::new(
+            "alice",
+            now,
+            test_helpers::ALICE_MAC,
+            test_helpers::ALICE_IPV4,
+        );
+        let bob_rt = TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+        let mut alice = LibOS::new(alice_rt.clone()).unwrap();
+        let mut bob = LibOS::new(bob_rt.clone()).unwrap();
+
+        let port = ip::Port::try_from(7).unwrap();
+        let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+        let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+        let alice_fd = alice.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+        alice.bind(alice_fd, alice_addr).unwrap();
+        let bob_fds: Vec<_> = (0..2)
+            .map(|i| {
+                let fd = bob.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+                let port = ip::Port::try_from(7 + i).unwrap();
+                bob.bind(fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port))
+                    .unwrap();
+                fd
+            })
+            .collect();
+        let qts: Vec<_> = bob_fds.iter().map(|&fd| bob.pop(fd)).collect();
+
+        // Nothing has arrived yet, so polling leaves both tokens outstanding.
+        assert!(bob.poll_any2(&qts[..]).is_none());
+        assert!(bob
+            .timedwait_any2(&qts[..], Duration::from_millis(1))
+            .is_none());
+
+        let qt = alice.pushto2(alice_fd, Bytes::from_slice(b"first"), bob_addr);
+        let push_results = alice.wait_all2(&[qt]);
+        must_let!(let [(_, OperationResult::Push)] = &push_results[..]);
+        bob_rt.push_frame(alice_rt.pop_frame());
+
+        must_let!(let (0, fd, OperationResult::Pop(Some(remote), buf)) = bob.wait_any2(&qts[..]));
+        assert_eq!(fd, bob_fds[0]);
+        assert_eq!(remote, alice_addr);
+        assert_eq!(&buf[..], b"first");
+
+        // The other token is still valid.
+        bob.drop_qtoken(qts[1]);
+    }
+}
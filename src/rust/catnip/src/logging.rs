@@ -1,13 +1,446 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use flexi_logger::Logger;
-use std::sync::Once;
+use flexi_logger::{
+    DeferredNow,
+    Logger,
+};
+use log::{
+    Level,
+    Record,
+};
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
+    fmt::Write as _,
+    io,
+    net::SocketAddrV4,
+    rc::Rc,
+    sync::Once,
+    time::{
+        Duration,
+        Instant,
+    },
+};
 
 static INIT_LOG: Once = Once::new();
 
+thread_local! {
+    // The label of the engine running on this thread right now, if it has one.
+    static ENGINE_LABEL: RefCell<Option<Rc<str>>> = RefCell::new(None);
+    // Where structured datapath records go on this thread, if anywhere.
+    static RECORD_SINK: RefCell<Option<Rc<dyn RecordSink>>> = RefCell::new(None);
+}
+
 pub fn initialize() {
+    initialize_with("");
+}
+
+/// Like `initialize`, but falls back to `spec` when `RUST_LOG` isn't set.
+pub fn initialize_with(spec: &str) {
     INIT_LOG.call_once(|| {
-        Logger::with_env_or_str("").start().unwrap();
+        Logger::with_env_or_str(spec)
+            .format(labeled_format)
+            .start()
+            .unwrap();
     });
 }
+
+/// flexi_logger's default format, led by the label of the engine that logged the line when it
+/// has one.
+pub fn labeled_format(
+    w: &mut dyn io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), io::Error> {
+    if let Some(label) = engine_label() {
+        write!(w, "[{}] ", label)?;
+    }
+    flexi_logger::default_format(w, now, record)
+}
+
+/// The label of the engine running on this thread, if it has one. See `Engine::set_label`.
+pub fn engine_label() -> Option<Rc<str>> {
+    ENGINE_LABEL.with(|l| l.borrow().clone())
+}
+
+/// Attribute what's logged on this thread to the engine labelled `label` until the guard is
+/// dropped, when whatever was running before is restored.
+pub fn enter_engine(label: Option<Rc<str>>) -> EngineGuard {
+    let previous = ENGINE_LABEL.with(|l| l.replace(label));
+    EngineGuard { previous }
+}
+
+pub struct EngineGuard {
+    previous: Option<Rc<str>>,
+}
+
+impl Drop for EngineGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        ENGINE_LABEL.with(|l| *l.borrow_mut() = previous);
+    }
+}
+
+/// What a structured datapath record reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecordKind {
+    /// A segment arrived on a connection.
+    Receive,
+    /// A segment went out, retransmissions included.
+    Send,
+    /// A segment is about to be resent because its retransmission timer expired.
+    Retransmit,
+    /// Resending after duplicate ACKs.
+    FastRetransmit,
+    /// Resending what SACK blocks showed missing.
+    SackRetransmit,
+}
+
+impl RecordKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RecordKind::Receive => "receive",
+            RecordKind::Send => "send",
+            RecordKind::Retransmit => "retransmit",
+            RecordKind::FastRetransmit => "fast_retransmit",
+            RecordKind::SackRetransmit => "sack_retransmit",
+        }
+    }
+}
+
+/// A datapath log line as typed fields, for a `RecordSink`. The endpoints identify the
+/// connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogRecord {
+    pub timestamp: Instant,
+    /// The label of the engine it came from, if it has one. See `Engine::set_label`.
+    pub engine: Option<Rc<str>>,
+    pub kind: RecordKind,
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub seq: u32,
+    pub ack: Option<u32>,
+    pub window: u16,
+    /// Payload bytes.
+    pub len: usize,
+}
+
+impl LogRecord {
+    /// The record as a single-line JSON object, its timestamp in microseconds since `origin`.
+    pub fn to_json(&self, origin: Instant) -> String {
+        let mut out = String::new();
+        let timestamp = self.timestamp.saturating_duration_since(origin).as_micros();
+        write!(out, "{{\"ts_us\":{}", timestamp).unwrap();
+        if let Some(ref engine) = self.engine {
+            out.push_str(",\"engine\":");
+            json_string(engine, &mut out);
+        }
+        write!(
+            out,
+            ",\"kind\":\"{}\",\"local\":\"{}\",\"remote\":\"{}\",\"seq\":{}",
+            self.kind.as_str(),
+            self.local,
+            self.remote,
+            self.seq
+        )
+        .unwrap();
+        if let Some(ack) = self.ack {
+            write!(out, ",\"ack\":{}", ack).unwrap();
+        }
+        write!(out, ",\"window\":{},\"len\":{}}}", self.window, self.len).unwrap();
+        out
+    }
+}
+
+// Append `s` as a JSON string literal.
+fn json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Receives structured datapath records, unsampled, alongside the text log. Install one with
+/// `set_record_sink`.
+pub trait RecordSink {
+    fn record(&self, record: &LogRecord);
+}
+
+/// Writes records as JSON lines, one object per record.
+pub struct JsonSink<W: io::Write> {
+    out: RefCell<W>,
+    origin: Instant,
+}
+
+impl<W: io::Write> JsonSink<W> {
+    /// Timestamps are written in microseconds since `origin`, such as the engine's start.
+    pub fn new(out: W, origin: Instant) -> Self {
+        Self {
+            out: RefCell::new(out),
+            origin,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner()
+    }
+}
+
+impl<W: io::Write> RecordSink for JsonSink<W> {
+    fn record(&self, record: &LogRecord) {
+        let line = record.to_json(self.origin);
+        // Like the text log, records are dropped rather than failing the datapath.
+        let _ = writeln!(self.out.borrow_mut(), "{}", line);
+    }
+}
+
+/// Send this thread's structured datapath records to `sink`, or stop with `None`. With no sink,
+/// records aren't even built.
+pub fn set_record_sink(sink: Option<Rc<dyn RecordSink>>) {
+    RECORD_SINK.with(|s| *s.borrow_mut() = sink);
+}
+
+/// Hand the record `f` builds to this thread's sink, if there is one. Its engine label is filled
+/// in from the engine running.
+pub fn emit_record(f: impl FnOnce() -> LogRecord) {
+    let sink = match RECORD_SINK.with(|s| s.borrow().clone()) {
+        Some(sink) => sink,
+        None => return,
+    };
+    let mut record = f();
+    if record.engine.is_none() {
+        record.engine = engine_label();
+    }
+    sink.record(&record);
+}
+
+/// How `Sampler` thins out per-segment log lines.
+#[derive(Clone, Copy, Debug)]
+pub struct LogSampling {
+    /// Log one in this many events. 1 logs everything (subject to `burst`).
+    pub every: u32,
+    /// Most lines logged per `interval`; the rest are counted and summarized.
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+impl Default for LogSampling {
+    fn default() -> Self {
+        Self {
+            every: 100,
+            burst: 20,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Decides which of a stream of datapath events get logged. Each connection keeps its own, so a
+/// busy connection can't crowd out the others' lines. At the end of every interval in which lines
+/// were dropped, the sampler logs how many.
+///
+/// Use it through `sampled!`, which skips all of this unless the level is enabled.
+pub struct Sampler {
+    name: String,
+    sampling: LogSampling,
+    state: Cell<SamplerState>,
+}
+
+#[derive(Clone, Copy)]
+struct SamplerState {
+    interval_start: Option<Instant>,
+    num_seen: u64,
+    num_logged: u32,
+    num_suppressed: u64,
+}
+
+impl Sampler {
+    pub fn new(name: String, sampling: LogSampling) -> Self {
+        let state = SamplerState {
+            interval_start: None,
+            num_seen: 0,
+            num_logged: 0,
+            num_suppressed: 0,
+        };
+        Self {
+            name,
+            sampling,
+            state: Cell::new(state),
+        }
+    }
+
+    /// Count an event at `level`, returning whether to log it.
+    pub fn sample(&self, level: Level, now: Instant) -> bool {
+        let mut state = self.state.get();
+        match state.interval_start {
+            Some(start) if now < start + self.sampling.interval => (),
+            start => {
+                if state.num_suppressed > 0 {
+                    log!(
+                        level,
+                        "{}: suppressed {} of {} log lines in the last {:?}",
+                        self.name,
+                        state.num_suppressed,
+                        state.num_seen,
+                        now - start.unwrap_or(now),
+                    );
+                }
+                state = SamplerState {
+                    interval_start: Some(now),
+                    num_seen: 0,
+                    num_logged: 0,
+                    num_suppressed: 0,
+                };
+            },
+        }
+        let every = cmp::max(self.sampling.every, 1) as u64;
+        let log = state.num_seen % every == 0 && state.num_logged < self.sampling.burst;
+        state.num_seen += 1;
+        if log {
+            state.num_logged += 1;
+        } else {
+            state.num_suppressed += 1;
+        }
+        self.state.set(state);
+        log
+    }
+}
+
+/// `sampled!(sampler, now, Level::Debug, "format", args...)` logs through `sampler`.
+#[macro_export]
+macro_rules! sampled {
+    ($sampler:expr, $now:expr, $level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if log_enabled!(level) && $sampler.sample(level, $now) {
+            log!(level, $($arg)+);
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        emit_record,
+        engine_label,
+        enter_engine,
+        set_record_sink,
+        JsonSink,
+        LogRecord,
+        LogSampling,
+        RecordKind,
+        RecordSink,
+        Sampler,
+    };
+    use log::Level;
+    use std::{
+        cell::RefCell,
+        net::{
+            Ipv4Addr,
+            SocketAddrV4,
+        },
+        rc::Rc,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    #[test]
+    fn one_in_n_with_burst_limit() {
+        let sampling = LogSampling {
+            every: 10,
+            burst: 3,
+            interval: Duration::from_secs(1),
+        };
+        let sampler = Sampler::new("test".to_string(), sampling);
+        let mut now = Instant::now();
+
+        let logged = (0..100)
+            .filter(|_| sampler.sample(Level::Debug, now))
+            .count();
+        assert_eq!(logged, 3);
+
+        // The burst budget resets with the interval.
+        now += Duration::from_secs(1);
+        let logged = (0..25)
+            .filter(|_| sampler.sample(Level::Debug, now))
+            .count();
+        assert_eq!(logged, 3);
+        assert!(!sampler.sample(Level::Debug, now));
+    }
+
+    #[test]
+    fn json_records() {
+        #[derive(Default)]
+        struct Collect(RefCell<Vec<LogRecord>>);
+        impl RecordSink for Collect {
+            fn record(&self, record: &LogRecord) {
+                self.0.borrow_mut().push(record.clone());
+            }
+        }
+
+        let origin = Instant::now();
+        let record = || LogRecord {
+            timestamp: origin + Duration::from_micros(1500),
+            engine: None,
+            kind: RecordKind::Send,
+            local: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80),
+            remote: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 50000),
+            seq: 1001,
+            ack: Some(7),
+            window: 65535,
+            len: 100,
+        };
+
+        // Without a sink, nothing's built.
+        emit_record(|| panic!("record built without a sink"));
+
+        let sink = Rc::new(Collect::default());
+        set_record_sink(Some(sink.clone()));
+        {
+            let _label = enter_engine(Some("a\"b".into()));
+            emit_record(record);
+        }
+        set_record_sink(None);
+        emit_record(record);
+        let records = sink.0.borrow();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].to_json(origin),
+            "{\"ts_us\":1500,\"engine\":\"a\\\"b\",\"kind\":\"send\",\"local\":\"10.0.0.1:80\",\
+             \"remote\":\"10.0.0.2:50000\",\"seq\":1001,\"ack\":7,\"window\":65535,\"len\":100}"
+        );
+
+        let json = JsonSink::new(vec![], origin);
+        let mut record = record();
+        record.ack = None;
+        json.record(&record);
+        let out = String::from_utf8(json.into_inner()).unwrap();
+        assert!(out.ends_with("\"seq\":1001,\"window\":65535,\"len\":100}\n"));
+    }
+
+    #[test]
+    fn engine_labels_nest() {
+        assert_eq!(engine_label(), None);
+        let outer = enter_engine(Some("outer".into()));
+        {
+            let _inner = enter_engine(Some("inner".into()));
+            assert_eq!(engine_label().as_deref(), Some("inner"));
+        }
+        assert_eq!(engine_label().as_deref(), Some("outer"));
+        drop(outer);
+        assert_eq!(engine_label(), None);
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Exporting engine statistics to monitoring systems.
+//!
+//! `Engine::set_metrics_sink` installs a `MetricsSink` that the engine pushes its `Stats` into
+//! from `advance_clock`, once per configured interval. `Engine::export_metrics` does the same on
+//! demand. Metric names are stable and prefixed with `catnip_`; latencies are in seconds.
+//!
+//! Sinks can also ask for a `TcpConnectionSummary` of every established connection on a separate
+//! interval, with `Engine::set_connection_summary_interval`.
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+use crate::stats::{
+    LatencyHistogram,
+    Stats,
+    TcpConnectionSummary,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+/// Receives one export's worth of metrics. Each export is bracketed by `begin` and `finish`, so a
+/// sink can swap in a complete set of values at once.
+pub trait MetricsSink {
+    fn begin(&mut self, _now: Instant) {}
+
+    /// A monotonically increasing count.
+    fn counter(&mut self, name: &'static str, help: &'static str, value: u64);
+
+    /// A value that can go up and down.
+    fn gauge(&mut self, name: &'static str, help: &'static str, value: f64);
+
+    /// A latency distribution, from the start of the engine.
+    fn histogram(&mut self, name: &'static str, help: &'static str, value: &LatencyHistogram);
+
+    fn finish(&mut self) {}
+
+    /// One connection's summary, pushed outside of `begin` and `finish`. Ignored by default.
+    fn connection_summary(&mut self, _now: Instant, _summary: &TcpConnectionSummary) {}
+}
+
+impl Stats {
+    /// Push every statistic into `sink`.
+    pub fn export(&self, sink: &mut dyn MetricsSink) {
+        sink.counter(
+            "catnip_rx_dropped_total",
+            "Received frames the engine rejected.",
+            self.rx_dropped,
+        );
+        sink.counter(
+            "catnip_rx_protocol_disabled_total",
+            "Received packets for a disabled protocol.",
+            self.rx_protocol_disabled,
+        );
+        sink.counter(
+            "catnip_arp_hits_total",
+            "ARP cache lookups that found an entry.",
+            self.arp_hits,
+        );
+        sink.counter(
+            "catnip_arp_misses_total",
+            "ARP cache lookups that found no entry.",
+            self.arp_misses,
+        );
+        sink.counter(
+            "catnip_arp_unsupported_total",
+            "Received ARP packets in a format other than Ethernet/IPv4.",
+            self.arp_unsupported,
+        );
+        sink.counter(
+            "catnip_rx_checksum_errors_icmpv4_total",
+            "Received ICMPv4 messages with a bad checksum.",
+            self.rx_checksum_errors.icmpv4,
+        );
+        sink.counter(
+            "catnip_rx_checksum_errors_tcp_total",
+            "Received TCP segments with a bad checksum.",
+            self.rx_checksum_errors.tcp,
+        );
+        sink.counter(
+            "catnip_rx_checksum_errors_udp_total",
+            "Received UDP datagrams with a bad checksum.",
+            self.rx_checksum_errors.udp,
+        );
+        sink.counter(
+            "catnip_tx_busy_total",
+            "Times outgoing frames waited for the device to have room.",
+            self.tx_busy,
+        );
+        sink.counter(
+            "catnip_tcp_rsts_suppressed_total",
+            "TCP segments for closed ports the RST policy left unanswered.",
+            self.tcp_rsts_suppressed,
+        );
+        sink.counter(
+            "catnip_icmpv4_errors_suppressed_total",
+            "ICMPv4 errors the rate limit held back.",
+            self.icmpv4_errors_suppressed,
+        );
+        sink.counter(
+            "catnip_ipv4_reassembly_timeouts_total",
+            "Fragmented IPv4 datagrams whose fragments didn't all arrive in time.",
+            self.ipv4_reassembly_timeouts,
+        );
+        sink.counter(
+            "catnip_ipv4_fragments_dropped_total",
+            "IPv4 fragments dropped for exceeding their source's reassembly limit.",
+            self.ipv4_fragments_dropped,
+        );
+        sink.counter(
+            "catnip_tcp_options_unknown_total",
+            "TCP options of unknown kinds skipped by strict option parsing.",
+            self.tcp_options.unknown.values().sum(),
+        );
+        sink.counter(
+            "catnip_tcp_options_malformed_total",
+            "Malformed TCP options skipped by strict option parsing.",
+            self.tcp_options.malformed.values().sum(),
+        );
+        sink.counter(
+            "catnip_tcp_missing_mss_total",
+            "TCP connections established without an MSS option from the remote.",
+            self.tcp_options.missing_mss,
+        );
+        sink.counter(
+            "catnip_tcp_missing_window_scale_total",
+            "TCP connections established without a window scale option from the remote.",
+            self.tcp_options.missing_window_scale,
+        );
+        sink.counter(
+            "catnip_tcp_missing_sack_total",
+            "TCP connections established without the remote permitting the SACK we offered.",
+            self.tcp_options.missing_sack,
+        );
+        sink.counter(
+            "catnip_tcp_missing_timestamps_total",
+            "TCP connections established without the remote echoing the timestamps we offered.",
+            self.tcp_options.missing_timestamps,
+        );
+        sink.gauge(
+            "catnip_tcp_established",
+            "TCP connections currently established.",
+            self.tcp_established as f64,
+        );
+        sink.histogram(
+            "catnip_tcp_srtt_seconds",
+            "Smoothed TCP RTT after each accepted sample.",
+            &self.tcp_latency.srtt,
+        );
+        sink.histogram(
+            "catnip_tcp_ack_latency_seconds",
+            "Time between receiving TCP data and acknowledging it.",
+            &self.tcp_latency.ack_latency,
+        );
+        sink.histogram(
+            "catnip_tcp_send_queue_seconds",
+            "Time TCP data waits before its first transmission.",
+            &self.tcp_latency.send_queue_time,
+        );
+    }
+}
+
+/// A sink and when it's next due, kept by the engine.
+pub struct MetricsExporter {
+    sink: Box<dyn MetricsSink>,
+    interval: Duration,
+    next_export: Instant,
+    // The connection summary interval and when they're next due, if they're wanted.
+    summaries: Option<(Duration, Instant)>,
+}
+
+impl MetricsExporter {
+    /// The first export happens `interval` after `now`.
+    pub fn new(sink: Box<dyn MetricsSink>, interval: Duration, now: Instant) -> Self {
+        Self {
+            sink,
+            interval,
+            next_export: now + interval,
+            summaries: None,
+        }
+    }
+
+    /// Push connection summaries every `interval` from `now` on, or stop if it's `None`.
+    pub fn set_summary_interval(&mut self, interval: Option<Duration>, now: Instant) {
+        self.summaries = interval.map(|i| (i, now + i));
+    }
+
+    pub fn summaries_due(&self, now: Instant) -> bool {
+        match self.summaries {
+            Some((_, next)) => now >= next,
+            None => false,
+        }
+    }
+
+    /// Push `summaries` and schedule the next ones, skipping missed intervals like `export`.
+    pub fn export_summaries(&mut self, now: Instant, summaries: &[TcpConnectionSummary]) {
+        for summary in summaries {
+            self.sink.connection_summary(now, summary);
+        }
+        if let Some((interval, ref mut next)) = self.summaries {
+            while *next <= now {
+                *next += interval;
+            }
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_export
+    }
+
+    /// The earlier of the next export and the next connection summaries.
+    pub fn next_due(&self) -> Instant {
+        match self.summaries {
+            Some((_, next)) if next < self.next_export => next,
+            _ => self.next_export,
+        }
+    }
+
+    /// Export `stats` and schedule the next export. If the clock jumped several intervals, the
+    /// missed exports are skipped rather than replayed.
+    pub fn export(&mut self, now: Instant, stats: &Stats) {
+        self.export_now(now, stats);
+        while self.next_export <= now {
+            self.next_export += self.interval;
+        }
+    }
+
+    pub fn export_now(&mut self, now: Instant, stats: &Stats) {
+        self.sink.begin(now);
+        stats.export(&mut *self.sink);
+        self.sink.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetricsSink;
+    use crate::{
+        protocols::{
+            ip,
+            ipv4,
+        },
+        stats::{
+            LatencyHistogram,
+            TcpConnectionSummary,
+        },
+        sync::Bytes,
+        test_helpers,
+    };
+    use futures::task::noop_waker_ref;
+    use must_let::must_let;
+    use std::{
+        cell::RefCell,
+        convert::TryFrom,
+        future::Future,
+        pin::Pin,
+        rc::Rc,
+        task::{
+            Context,
+            Poll,
+        },
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    #[derive(Clone, Default)]
+    struct Recording {
+        exports: Rc<RefCell<Vec<(Instant, Vec<(&'static str, u64)>)>>>,
+        summaries: Rc<RefCell<Vec<(Instant, TcpConnectionSummary)>>>,
+    }
+
+    impl MetricsSink for Recording {
+        fn begin(&mut self, now: Instant) {
+            self.exports.borrow_mut().push((now, vec![]));
+        }
+
+        fn counter(&mut self, name: &'static str, _help: &'static str, value: u64) {
+            self.exports
+                .borrow_mut()
+                .last_mut()
+                .unwrap()
+                .1
+                .push((name, value));
+        }
+
+        fn gauge(&mut self, _name: &'static str, _help: &'static str, _value: f64) {}
+
+        fn histogram(&mut self, name: &'static str, _help: &'static str, h: &LatencyHistogram) {
+            self.exports
+                .borrow_mut()
+                .last_mut()
+                .unwrap()
+                .1
+                .push((name, h.len()));
+        }
+
+        fn connection_summary(&mut self, now: Instant, summary: &TcpConnectionSummary) {
+            self.summaries.borrow_mut().push((now, summary.clone()));
+        }
+    }
+
+    #[test]
+    fn periodic_export() {
+        let mut now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let sink = Recording::default();
+        alice.set_metrics_sink(Box::new(sink.clone()), Duration::from_secs(10));
+
+        // Nothing is exported until the first interval elapses.
+        now += Duration::from_secs(5);
+        alice.advance_clock(now);
+        assert!(sink.exports.borrow().is_empty());
+
+        assert!(alice.receive(Bytes::from_slice(&[0; 4])).is_err());
+        now += Duration::from_secs(5);
+        alice.advance_clock(now);
+        {
+            let exports = sink.exports.borrow();
+            assert_eq!(exports.len(), 1);
+            assert_eq!(exports[0].0, now);
+            assert!(exports[0].1.contains(&("catnip_rx_dropped_total", 1)));
+        }
+
+        // A long stall produces one export, not one per missed interval.
+        now += Duration::from_secs(35);
+        alice.advance_clock(now);
+        assert_eq!(sink.exports.borrow().len(), 2);
+        now += Duration::from_secs(4);
+        alice.advance_clock(now);
+        assert_eq!(sink.exports.borrow().len(), 2);
+        now += Duration::from_secs(1);
+        alice.advance_clock(now);
+        assert_eq!(sink.exports.borrow().len(), 3);
+
+        assert!(alice.clear_metrics_sink());
+        now += Duration::from_secs(60);
+        alice.advance_clock(now);
+        assert_eq!(sink.exports.borrow().len(), 3);
+    }
+
+    #[test]
+    fn connection_summaries() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut bob = test_helpers::new_bob(now);
+        let sink = Recording::default();
+        assert!(!alice.set_connection_summary_interval(Some(Duration::from_secs(1))));
+        alice.set_metrics_sink(Box::new(sink.clone()), Duration::from_secs(60));
+        assert!(alice.set_connection_summary_interval(Some(Duration::from_secs(1))));
+
+        let listen_addr =
+            ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+        let listen_fd = bob.tcp_socket();
+        bob.tcp_bind(listen_fd, listen_addr).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+        let mut accept_future = bob.tcp_accept(listen_fd);
+        let alice_fd = alice.tcp_socket();
+        let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+        // Data that's been sent but not acknowledged shows up as in flight.
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        now += Duration::from_secs(1);
+        alice.advance_clock(now);
+        {
+            let summaries = sink.summaries.borrow();
+            must_let!(let [(when, summary)] = &summaries[..]);
+            assert_eq!(*when, now);
+            assert_eq!(summary.fd, alice_fd);
+            assert_eq!(summary.remote, listen_addr);
+            assert_eq!(summary.unacked_bytes, 100);
+            assert_eq!(summary.unsent_bytes, 0);
+        }
+        // Summaries don't wait for the metrics interval.
+        assert!(sink.exports.borrow().is_empty());
+
+        assert!(alice.set_connection_summary_interval(None));
+        now += Duration::from_secs(5);
+        alice.advance_clock(now);
+        assert_eq!(sink.summaries.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! A `MetricsSink` that renders the Prometheus text exposition format. Keep a clone of the
+//! exporter after installing it and serve `text()` from whatever HTTP endpoint the deployment
+//! scrapes; the engine doesn't run a server itself.
+
+use super::MetricsSink;
+use crate::stats::LatencyHistogram;
+use std::{
+    cell::RefCell,
+    fmt::Write,
+    rc::Rc,
+    time::Instant,
+};
+
+// Latency histograms are exposed as summaries with these quantiles.
+const QUANTILES: &[(f64, &str)] = &[
+    (50.0, "0.5"),
+    (90.0, "0.9"),
+    (99.0, "0.99"),
+    (99.9, "0.999"),
+];
+
+#[derive(Clone, Default)]
+pub struct PrometheusExporter {
+    pending: String,
+    latest: Rc<RefCell<String>>,
+}
+
+impl PrometheusExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The most recent complete export, empty until the first one.
+    pub fn text(&self) -> String {
+        self.latest.borrow().clone()
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        // Writing to a `String` can't fail.
+        let _ = writeln!(self.pending, "# HELP {} {}", name, help);
+        let _ = writeln!(self.pending, "# TYPE {} {}", name, kind);
+    }
+}
+
+impl MetricsSink for PrometheusExporter {
+    fn begin(&mut self, _now: Instant) {
+        self.pending.clear();
+    }
+
+    fn counter(&mut self, name: &'static str, help: &'static str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.pending, "{} {}", name, value);
+    }
+
+    fn gauge(&mut self, name: &'static str, help: &'static str, value: f64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.pending, "{} {}", name, value);
+    }
+
+    fn histogram(&mut self, name: &'static str, help: &'static str, value: &LatencyHistogram) {
+        self.header(name, help, "summary");
+        for &(percentile, label) in QUANTILES {
+            let secs = match value.percentile(percentile) {
+                Some(d) => d.as_secs_f64(),
+                None => std::f64::NAN,
+            };
+            let _ = writeln!(self.pending, "{}{{quantile=\"{}\"}} {}", name, label, secs);
+        }
+        let sum = value
+            .mean()
+            .map(|d| d.as_secs_f64() * value.len() as f64)
+            .unwrap_or(0.0);
+        let _ = writeln!(self.pending, "{}_sum {}", name, sum);
+        let _ = writeln!(self.pending, "{}_count {}", name, value.len());
+    }
+
+    fn finish(&mut self) {
+        let mut latest = self.latest.borrow_mut();
+        std::mem::swap(&mut *latest, &mut self.pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrometheusExporter;
+    use crate::{
+        metrics::MetricsSink,
+        stats::Stats,
+    };
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    #[test]
+    fn text_format() {
+        let mut exporter = PrometheusExporter::new();
+        let mut stats = Stats::default();
+        stats.rx_dropped = 7;
+        stats.tcp_established = 2;
+        for _ in 0..4 {
+            stats.tcp_latency.srtt.record(Duration::from_millis(10));
+        }
+
+        exporter.begin(Instant::now());
+        stats.export(&mut exporter);
+        // Nothing is visible until the export completes.
+        assert_eq!(exporter.text(), "");
+        exporter.finish();
+
+        let text = exporter.text();
+        assert!(
+            text.contains("# TYPE catnip_rx_dropped_total counter\ncatnip_rx_dropped_total 7\n")
+        );
+        assert!(text.contains("catnip_tcp_established 2\n"));
+        assert!(text.contains("# TYPE catnip_tcp_srtt_seconds summary\n"));
+        assert!(text.contains("catnip_tcp_srtt_seconds_count 4\n"));
+        assert!(text.contains("catnip_tcp_ack_latency_seconds{quantile=\"0.5\"} NaN\n"));
+        let p50 = text
+            .lines()
+            .find(|l| l.starts_with("catnip_tcp_srtt_seconds{quantile=\"0.5\"}"))
+            .unwrap();
+        let secs: f64 = p50.rsplit(' ').next().unwrap().parse().unwrap();
+        assert!((secs - 0.010).abs() < 0.0001, "{}", secs);
+    }
+}
@@ -1,26 +1,60 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::protocols::{
-    arp,
-    ethernet2::MacAddress,
-    tcp,
-    udp,
+use crate::{
+    fail::Fail,
+    protocols::{
+        arp,
+        ethernet2::MacAddress,
+        icmpv4,
+        ip,
+        ip::port::FIRST_PRIVATE_PORT,
+        ipv4::RateLimit,
+        tcp,
+        tcp::constants::{
+            MAX_MSS,
+            MAX_WINDOW_SIZE,
+            MIN_MSS,
+        },
+        udp,
+    },
 };
 use rand::{
     thread_rng,
     Rng,
 };
-use std::net::Ipv4Addr;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    fs,
+    net::Ipv4Addr,
+    path::Path,
+    time::Duration,
+};
+
+const DEFAULT_MTU: usize = 1500;
+const MIN_MTU: usize = 68;
+// IPv4 and TCP headers without options.
+const MTU_OVERHEAD: usize = 40;
 
 #[derive(Clone, Debug)]
 pub struct Options {
     pub arp: arp::Options,
     pub my_ipv4_addr: Ipv4Addr,
     pub my_link_addr: MacAddress,
+    /// Largest IP packet the link carries.
+    pub mtu: usize,
     pub rng_seed: [u8; 32],
     pub tcp: tcp::Options,
     pub udp: udp::Options,
+    pub icmpv4: icmpv4::Options,
+    /// Engine-wide egress limit for the embedder to apply with `Engine::set_rate_limit`.
+    pub rate_limit: Option<RateLimit>,
+    /// `flexi_logger` spec (e.g. `"info,catnip::protocols::tcp=debug"`) for
+    /// `logging::initialize_with`.
+    pub logging: Option<String>,
 }
 
 impl Default for Options {
@@ -31,9 +65,13 @@ impl Default for Options {
             arp: arp::Options::default(),
             my_ipv4_addr: Ipv4Addr::new(0, 0, 0, 0),
             my_link_addr: MacAddress::nil(),
+            mtu: DEFAULT_MTU,
             rng_seed,
             tcp: tcp::Options::default(),
             udp: Default::default(),
+            icmpv4: icmpv4::Options::default(),
+            rate_limit: None,
+            logging: None,
         }
     }
 }
@@ -58,6 +96,12 @@ impl Options {
         self
     }
 
+    pub fn mtu(mut self, value: usize) -> Self {
+        assert!(value >= MIN_MTU);
+        self.mtu = value;
+        self
+    }
+
     pub fn rng_seed(mut self, value: [u8; 32]) -> Self {
         self.rng_seed = value;
         self
@@ -67,4 +111,789 @@ impl Options {
         self.tcp = value;
         self
     }
+
+    pub fn udp(mut self, value: udp::Options) -> Self {
+        self.udp = value;
+        self
+    }
+
+    pub fn icmpv4(mut self, value: icmpv4::Options) -> Self {
+        self.icmpv4 = value;
+        self
+    }
+
+    pub fn rate_limit(mut self, value: Option<RateLimit>) -> Self {
+        self.rate_limit = value;
+        self
+    }
+
+    pub fn logging(mut self, spec: &str) -> Self {
+        self.logging = Some(spec.to_string());
+        self
+    }
+
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::default(),
+        }
+    }
+
+    /// Check that the settings are consistent with each other. The per-field setters only check
+    /// each value on its own.
+    pub fn validate(&self) -> Result<(), Fail> {
+        check(self.mtu >= MIN_MTU, "MTU too small")?;
+
+        let tcp = &self.tcp;
+        check(
+            tcp.advertised_mss >= MIN_MSS && tcp.advertised_mss <= MAX_MSS,
+            "TCP MSS out of range",
+        )?;
+        check(
+            tcp.advertised_mss + MTU_OVERHEAD <= self.mtu,
+            "TCP MSS doesn't fit in the MTU",
+        )?;
+        check(tcp.window_scale <= 14, "TCP window scale may be at most 14")?;
+        check(
+            tcp.receive_window_size <= MAX_WINDOW_SIZE,
+            "TCP receive window too large to advertise",
+        )?;
+        check(
+            tcp.receive_window_size as usize >= tcp.advertised_mss,
+            "TCP receive window smaller than the MSS",
+        )?;
+        check(
+            tcp.handshake_retries > 0,
+            "TCP handshake retries must be positive",
+        )?;
+        check(tcp.retries > 0, "TCP retries must be positive")?;
+        check(
+            tcp.log_sampling.every > 0,
+            "TCP log sampling rate must be positive",
+        )?;
+        // Delaying ACKs past the peer's initial RTO makes it retransmit needlessly.
+        check(
+            tcp.trailing_ack_delay < tcp.handshake_timeout,
+            "TCP ACK delay must be shorter than the handshake timeout",
+        )?;
+        check(
+            *tcp.ephemeral_ports.start() >= FIRST_PRIVATE_PORT,
+            "TCP ephemeral ports must be in the dynamic port range",
+        )?;
+        check(
+            tcp.ephemeral_ports.start() <= tcp.ephemeral_ports.end(),
+            "TCP ephemeral port range is empty",
+        )?;
+
+        let zero = Duration::new(0, 0);
+        check(self.arp.cache_ttl > zero, "ARP cache TTL must be positive")?;
+        check(
+            self.arp.request_timeout > zero,
+            "ARP request timeout must be positive",
+        )?;
+        check(self.arp.retry_count > 0, "ARP retry count must be positive")?;
+        check(
+            self.icmpv4.echo_timeout > zero,
+            "ICMP echo timeout must be positive",
+        )?;
+        let udp = &self.udp;
+        check(
+            udp.echo_port.is_none() || udp.echo_port != udp.discard_port,
+            "UDP echo and discard services need different ports",
+        )?;
+
+        if let Some(limit) = self.rate_limit {
+            check(
+                limit.bytes_per_sec > 0 && limit.burst > 0,
+                "Rate limit must be positive",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Load options from a YAML (`.yaml`, `.yml`) or TOML (`.toml`) file. Settings live under a
+    /// `catnip` key, so the file can be shared with other components' configuration, and anything
+    /// left out keeps its default. For example:
+    ///
+    /// ```yaml
+    /// catnip:
+    ///   profile: bulk-throughput
+    ///   my_ipv4_addr: 10.0.0.1
+    ///   my_link_addr: "02:00:00:00:00:01"
+    ///   mtu: 9000
+    ///   arp_table:
+    ///     "02:00:00:00:00:02": 10.0.0.2
+    ///   tcp:
+    ///     handshake_timeout_ms: 500
+    ///     window_scale: 4
+    ///   rate_limit:
+    ///     bytes_per_sec: 125000000
+    ///     burst: 65536
+    ///   logging: info
+    /// ```
+    ///
+    /// A `profile` is applied before the other settings. Unless the file sets
+    /// `tcp.advertised_mss`, it's derived from the MTU. The result is checked with `validate`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Fail> {
+        let path = path.as_ref();
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => {
+                return Err(Fail::Unsupported {
+                    details: "Config file must be .yaml, .yml or .toml",
+                })
+            },
+        };
+        let contents = fs::read_to_string(path).map_err(|e| {
+            warn!("Failed to read {}: {}", path.display(), e);
+            Fail::IoError {}
+        })?;
+        Self::from_str(&contents, format)
+    }
+
+    fn from_str(contents: &str, format: ConfigFormat) -> Result<Self, Fail> {
+        let file: ConfigFile = match format {
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(malformed)?,
+            ConfigFormat::Toml => toml::from_str(contents).map_err(malformed)?,
+        };
+        let config = file.catnip.ok_or(Fail::Malformed {
+            details: "Config file has no catnip section",
+        })?;
+        let options = config.apply(Options::default())?;
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+/// Presets for common kinds of experiment. A profile only changes the knobs it's about, so it
+/// should be applied before any individual settings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Fail fast and never hold back ACKs, for latency benchmarks on a quiet local network.
+    LowLatency,
+    /// Large scaled windows, SACK and ACKs batched behind a short delay, for long transfers.
+    BulkThroughput,
+    /// Patient timers, many retries and SACK, for links that drop packets and take a while to
+    /// answer.
+    LossyWan,
+}
+
+impl Profile {
+    fn apply(self, options: &mut Options) {
+        let tcp = &mut options.tcp;
+        let arp = &mut options.arp;
+        let icmpv4 = &mut options.icmpv4;
+        match self {
+            Profile::LowLatency => {
+                tcp.handshake_timeout = Duration::from_millis(250);
+                tcp.handshake_retries = 3;
+                tcp.retries = 3;
+                tcp.trailing_ack_delay = Duration::from_micros(1);
+                arp.request_timeout = Duration::from_millis(100);
+                arp.retry_count = 3;
+                icmpv4.echo_timeout = Duration::from_secs(1);
+            },
+            Profile::BulkThroughput => {
+                tcp.receive_window_size = 0xffff << 5;
+                tcp.window_scale = 5;
+                tcp.sack = true;
+                tcp.timestamps = true;
+                tcp.trailing_ack_delay = Duration::from_micros(200);
+            },
+            Profile::LossyWan => {
+                tcp.handshake_timeout = Duration::from_secs(5);
+                tcp.handshake_retries = 8;
+                tcp.retries = 12;
+                tcp.receive_window_size = 0xffff << 2;
+                tcp.window_scale = 2;
+                tcp.sack = true;
+                tcp.timestamps = true;
+                arp.request_timeout = Duration::from_secs(3);
+                arp.retry_count = 8;
+                arp.cache_ttl = Duration::from_secs(60);
+                icmpv4.echo_timeout = Duration::from_secs(10);
+            },
+        }
+    }
+}
+
+/// Builds `Options`, deferring all checks to `build` so invalid combinations come back as an
+/// error instead of a panic.
+#[derive(Clone, Debug)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn profile(mut self, profile: Profile) -> Self {
+        profile.apply(&mut self.options);
+        self
+    }
+
+    pub fn my_ipv4_addr(mut self, value: Ipv4Addr) -> Self {
+        self.options.my_ipv4_addr = value;
+        self
+    }
+
+    pub fn my_link_addr(mut self, value: MacAddress) -> Self {
+        self.options.my_link_addr = value;
+        self
+    }
+
+    pub fn mtu(mut self, value: usize) -> Self {
+        self.options.mtu = value;
+        self
+    }
+
+    pub fn rng_seed(mut self, value: [u8; 32]) -> Self {
+        self.options.rng_seed = value;
+        self
+    }
+
+    pub fn arp(mut self, f: impl FnOnce(&mut arp::Options)) -> Self {
+        f(&mut self.options.arp);
+        self
+    }
+
+    pub fn tcp(mut self, f: impl FnOnce(&mut tcp::Options)) -> Self {
+        f(&mut self.options.tcp);
+        self
+    }
+
+    pub fn udp(mut self, f: impl FnOnce(&mut udp::Options)) -> Self {
+        f(&mut self.options.udp);
+        self
+    }
+
+    pub fn icmpv4(mut self, f: impl FnOnce(&mut icmpv4::Options)) -> Self {
+        f(&mut self.options.icmpv4);
+        self
+    }
+
+    pub fn rate_limit(mut self, value: RateLimit) -> Self {
+        self.options.rate_limit = Some(value);
+        self
+    }
+
+    pub fn logging(mut self, spec: &str) -> Self {
+        self.options.logging = Some(spec.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Options, Fail> {
+        let options = self.options;
+        let addr = options.my_ipv4_addr;
+        check(!addr.is_unspecified(), "IPv4 address not set")?;
+        check(!addr.is_broadcast(), "IPv4 address may not be broadcast")?;
+        let link_addr = options.my_link_addr;
+        check(!link_addr.is_nil(), "Link address not set")?;
+        check(
+            !link_addr.is_broadcast(),
+            "Link address may not be broadcast",
+        )?;
+        options.validate()?;
+        Ok(options)
+    }
+}
+
+fn malformed<E: fmt::Display>(e: E) -> Fail {
+    warn!("Invalid config file: {}", e);
+    Fail::Malformed {
+        details: "Invalid config file",
+    }
+}
+
+fn check(condition: bool, details: &'static str) -> Result<(), Fail> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Fail::Invalid { details })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    catnip: Option<Config>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    profile: Option<Profile>,
+    my_ipv4_addr: Option<Ipv4Addr>,
+    my_link_addr: Option<String>,
+    mtu: Option<usize>,
+    /// Static ARP entries, from link address to IPv4 address.
+    arp_table: HashMap<String, Ipv4Addr>,
+    disable_arp: Option<bool>,
+    arp: ArpConfig,
+    tcp: TcpConfig,
+    udp: UdpConfig,
+    icmpv4: Icmpv4Config,
+    rate_limit: Option<RateLimitConfig>,
+    logging: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ArpConfig {
+    cache_ttl_ms: Option<u64>,
+    request_timeout_ms: Option<u64>,
+    retry_count: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TcpConfig {
+    advertised_mss: Option<usize>,
+    handshake_retries: Option<usize>,
+    handshake_timeout_ms: Option<u64>,
+    receive_window_size: Option<u32>,
+    reassembly_limit: Option<usize>,
+    retries: Option<usize>,
+    min_rto_ms: Option<u64>,
+    max_rto_ms: Option<u64>,
+    soft_error_limit: Option<usize>,
+    blackhole_timeouts: Option<usize>,
+    msl_ms: Option<u64>,
+    trailing_ack_delay_us: Option<u64>,
+    delayed_ack_timeout_ms: Option<u64>,
+    timer_granularity_us: Option<u64>,
+    window_scale: Option<u8>,
+    sack: Option<bool>,
+    timestamps: Option<bool>,
+    nagle: Option<bool>,
+    send_buffer_size: Option<usize>,
+    rx_checksum_offload: Option<bool>,
+    tx_checksum_offload: Option<bool>,
+    log_every: Option<u32>,
+    log_burst: Option<u32>,
+    listen_only: Option<bool>,
+    reset_on_backlog_overflow: Option<bool>,
+    closed_port_rst: Option<RstPolicyConfig>,
+    closed_port_rsts_per_sec: Option<u32>,
+    syn_cookies: Option<SynCookiePolicyConfig>,
+    connect_attempt_delay_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RstPolicyConfig {
+    Always,
+    RateLimited,
+    Never,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SynCookiePolicyConfig {
+    Never,
+    OnOverflow,
+    Always,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UdpConfig {
+    rx_checksum_offload: Option<bool>,
+    tx_checksum_offload: Option<bool>,
+    enabled: Option<bool>,
+    echo_port: Option<u16>,
+    discard_port: Option<u16>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Icmpv4Config {
+    echo_timeout_ms: Option<u64>,
+    echo_reply: Option<bool>,
+    rx_checksum_offload: Option<bool>,
+    errors_per_sec: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    bytes_per_sec: u64,
+    burst: u64,
+}
+
+impl Config {
+    fn apply(self, mut options: Options) -> Result<Options, Fail> {
+        if let Some(profile) = self.profile {
+            profile.apply(&mut options);
+        }
+        if let Some(addr) = self.my_ipv4_addr {
+            check(
+                !addr.is_unspecified(),
+                "my_ipv4_addr may not be unspecified",
+            )?;
+            check(!addr.is_broadcast(), "my_ipv4_addr may not be broadcast")?;
+            options.my_ipv4_addr = addr;
+        }
+        if let Some(ref s) = self.my_link_addr {
+            let link_addr = MacAddress::parse_str(s)?;
+            check(!link_addr.is_nil(), "my_link_addr may not be nil")?;
+            check(
+                !link_addr.is_broadcast(),
+                "my_link_addr may not be broadcast",
+            )?;
+            options.my_link_addr = link_addr;
+        }
+        if let Some(mtu) = self.mtu {
+            check(mtu >= MIN_MTU, "mtu too small")?;
+            options.mtu = mtu;
+        }
+
+        for (link_addr, ipv4_addr) in self.arp_table {
+            let link_addr = MacAddress::parse_str(&link_addr)?;
+            options.arp.initial_values.insert(link_addr, ipv4_addr);
+        }
+        if let Some(disable_arp) = self.disable_arp {
+            options.arp.disable_arp = disable_arp;
+        }
+        if let Some(ms) = self.arp.cache_ttl_ms {
+            check(ms > 0, "arp.cache_ttl_ms must be positive")?;
+            options.arp.cache_ttl = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.arp.request_timeout_ms {
+            check(ms > 0, "arp.request_timeout_ms must be positive")?;
+            options.arp.request_timeout = Duration::from_millis(ms);
+        }
+        if let Some(n) = self.arp.retry_count {
+            check(n > 0, "arp.retry_count must be positive")?;
+            options.arp.retry_count = n;
+        }
+
+        let tcp = &mut options.tcp;
+        match self.tcp.advertised_mss {
+            Some(mss) => {
+                check(
+                    mss >= MIN_MSS && mss <= MAX_MSS,
+                    "tcp.advertised_mss out of range",
+                )?;
+                tcp.advertised_mss = mss;
+            },
+            None if self.mtu.is_some() => {
+                let mss = options.mtu.saturating_sub(MTU_OVERHEAD);
+                tcp.advertised_mss = mss.max(MIN_MSS).min(MAX_MSS);
+            },
+            None => (),
+        }
+        if let Some(n) = self.tcp.handshake_retries {
+            check(n > 0, "tcp.handshake_retries must be positive")?;
+            tcp.handshake_retries = n;
+        }
+        if let Some(ms) = self.tcp.handshake_timeout_ms {
+            check(ms > 0, "tcp.handshake_timeout_ms must be positive")?;
+            tcp.handshake_timeout = Duration::from_millis(ms);
+        }
+        if let Some(size) = self.tcp.receive_window_size {
+            check(size > 0, "tcp.receive_window_size must be positive")?;
+            check(
+                size <= MAX_WINDOW_SIZE,
+                "tcp.receive_window_size may be at most 0xffff << 14",
+            )?;
+            tcp.receive_window_size = size;
+        }
+        if let Some(limit) = self.tcp.reassembly_limit {
+            tcp.reassembly_limit = limit;
+        }
+        if let Some(n) = self.tcp.retries {
+            check(n > 0, "tcp.retries must be positive")?;
+            tcp.retries = n;
+        }
+        if let Some(ms) = self.tcp.min_rto_ms {
+            check(ms > 0, "tcp.min_rto_ms must be positive")?;
+            tcp.min_rto = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.tcp.max_rto_ms {
+            tcp.max_rto = Duration::from_millis(ms);
+        }
+        check(
+            tcp.min_rto <= tcp.max_rto,
+            "tcp.min_rto_ms may not exceed tcp.max_rto_ms",
+        )?;
+        if let Some(n) = self.tcp.soft_error_limit {
+            tcp.soft_error_limit = n;
+        }
+        if let Some(n) = self.tcp.blackhole_timeouts {
+            tcp.blackhole_timeouts = n;
+        }
+        if let Some(ms) = self.tcp.msl_ms {
+            tcp.msl = Duration::from_millis(ms);
+        }
+        if let Some(us) = self.tcp.trailing_ack_delay_us {
+            tcp.trailing_ack_delay = Duration::from_micros(us);
+        }
+        if let Some(ms) = self.tcp.delayed_ack_timeout_ms {
+            check(ms <= 500, "tcp.delayed_ack_timeout_ms may be at most 500")?;
+            tcp.delayed_ack_timeout = Duration::from_millis(ms);
+        }
+        if let Some(us) = self.tcp.timer_granularity_us {
+            tcp.timer_granularity = Duration::from_micros(us);
+        }
+        if let Some(scale) = self.tcp.window_scale {
+            // RFC 1323 caps the shift at 14.
+            check(scale <= 14, "tcp.window_scale may be at most 14")?;
+            tcp.window_scale = scale;
+        }
+        if let Some(enabled) = self.tcp.sack {
+            tcp.sack = enabled;
+        }
+        if let Some(enabled) = self.tcp.timestamps {
+            tcp.timestamps = enabled;
+        }
+        if let Some(enabled) = self.tcp.nagle {
+            tcp.nagle = enabled;
+        }
+        if let Some(size) = self.tcp.send_buffer_size {
+            tcp.send_buffer_size = size;
+        }
+        if let Some(enabled) = self.tcp.rx_checksum_offload {
+            tcp.rx_checksum_offload = enabled;
+        }
+        if let Some(enabled) = self.tcp.tx_checksum_offload {
+            tcp.tx_checksum_offload = enabled;
+        }
+        if let Some(n) = self.tcp.log_every {
+            check(n > 0, "tcp.log_every must be positive")?;
+            tcp.log_sampling.every = n;
+        }
+        if let Some(n) = self.tcp.log_burst {
+            tcp.log_sampling.burst = n;
+        }
+        if let Some(listen_only) = self.tcp.listen_only {
+            tcp.listen_only = listen_only;
+        }
+        if let Some(enabled) = self.tcp.reset_on_backlog_overflow {
+            tcp.reset_on_backlog_overflow = enabled;
+        }
+        if let Some(policy) = self.tcp.closed_port_rst {
+            tcp.closed_port_rst = match policy {
+                RstPolicyConfig::Always => tcp::RstPolicy::Always,
+                RstPolicyConfig::Never => tcp::RstPolicy::Never,
+                RstPolicyConfig::RateLimited => {
+                    let per_second = self.tcp.closed_port_rsts_per_sec.unwrap_or(0);
+                    check(
+                        per_second > 0,
+                        "tcp.closed_port_rsts_per_sec must be positive",
+                    )?;
+                    tcp::RstPolicy::RateLimited { per_second }
+                },
+            };
+        }
+        if let Some(policy) = self.tcp.syn_cookies {
+            tcp.syn_cookies = match policy {
+                SynCookiePolicyConfig::Never => tcp::SynCookiePolicy::Never,
+                SynCookiePolicyConfig::OnOverflow => tcp::SynCookiePolicy::OnOverflow,
+                SynCookiePolicyConfig::Always => tcp::SynCookiePolicy::Always,
+            };
+        }
+        if let Some(ms) = self.tcp.connect_attempt_delay_ms {
+            tcp.connect_attempt_delay = Duration::from_millis(ms);
+        }
+
+        if let Some(enabled) = self.udp.rx_checksum_offload {
+            options.udp.rx_checksum_offload = enabled;
+        }
+        if let Some(enabled) = self.udp.tx_checksum_offload {
+            options.udp.tx_checksum_offload = enabled;
+        }
+        if let Some(enabled) = self.udp.enabled {
+            options.udp.enabled = enabled;
+        }
+        if let Some(port) = self.udp.echo_port {
+            check(port != 0, "udp.echo_port must be nonzero")?;
+            options.udp.echo_port = Some(ip::Port::try_from(port).unwrap());
+        }
+        if let Some(port) = self.udp.discard_port {
+            check(port != 0, "udp.discard_port must be nonzero")?;
+            options.udp.discard_port = Some(ip::Port::try_from(port).unwrap());
+        }
+
+        if let Some(ms) = self.icmpv4.echo_timeout_ms {
+            check(ms > 0, "icmpv4.echo_timeout_ms must be positive")?;
+            options.icmpv4.echo_timeout = Duration::from_millis(ms);
+        }
+        if let Some(enabled) = self.icmpv4.echo_reply {
+            options.icmpv4.echo_reply = enabled;
+        }
+        if let Some(enabled) = self.icmpv4.rx_checksum_offload {
+            options.icmpv4.rx_checksum_offload = enabled;
+        }
+        if let Some(per_second) = self.icmpv4.errors_per_sec {
+            options.icmpv4.error_rate_limit = Some(per_second);
+        }
+
+        if let Some(limit) = self.rate_limit {
+            check(
+                limit.bytes_per_sec > 0,
+                "rate_limit.bytes_per_sec must be positive",
+            )?;
+            check(limit.burst > 0, "rate_limit.burst must be positive")?;
+            options.rate_limit = Some(RateLimit::new(limit.bytes_per_sec, limit.burst));
+        }
+        options.logging = self.logging;
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConfigFormat,
+        Options,
+        Profile,
+    };
+    use crate::{
+        fail::Fail,
+        protocols::{
+            ethernet2::MacAddress,
+            ip,
+        },
+    };
+    use must_let::must_let;
+    use std::{
+        convert::TryFrom,
+        net::Ipv4Addr,
+        time::Duration,
+    };
+
+    const YAML: &str = r#"
+catnip:
+  my_ipv4_addr: 10.0.0.1
+  my_link_addr: "02:00:00:00:00:01"
+  mtu: 9000
+  arp_table:
+    "02:00:00:00:00:02": 10.0.0.2
+  tcp:
+    handshake_timeout_ms: 500
+    window_scale: 4
+  rate_limit:
+    bytes_per_sec: 1000000
+    burst: 65536
+  logging: info
+dpdk:
+  eal_init: ["-c", "0xff"]
+"#;
+
+    const TOML: &str = r#"
+[catnip]
+my_ipv4_addr = "10.0.0.1"
+my_link_addr = "02:00:00:00:00:01"
+mtu = 9000
+logging = "info"
+
+[catnip.arp_table]
+"02:00:00:00:00:02" = "10.0.0.2"
+
+[catnip.tcp]
+handshake_timeout_ms = 500
+window_scale = 4
+
+[catnip.rate_limit]
+bytes_per_sec = 1000000
+burst = 65536
+"#;
+
+    #[test]
+    fn parse_config() {
+        for &(contents, format) in &[(YAML, ConfigFormat::Yaml), (TOML, ConfigFormat::Toml)] {
+            let options = Options::from_str(contents, format).unwrap();
+            assert_eq!(options.my_ipv4_addr, Ipv4Addr::new(10, 0, 0, 1));
+            assert_eq!(options.my_link_addr.octets(), [2, 0, 0, 0, 0, 1]);
+            let peer = MacAddress::new([2, 0, 0, 0, 0, 2]);
+            assert_eq!(
+                options.arp.initial_values[&peer],
+                Ipv4Addr::new(10, 0, 0, 2)
+            );
+            assert_eq!(options.mtu, 9000);
+            assert_eq!(options.tcp.advertised_mss, 8960);
+            assert_eq!(options.tcp.handshake_timeout, Duration::from_millis(500));
+            assert_eq!(options.tcp.window_scale, 4);
+            assert_eq!(options.rate_limit.unwrap().burst, 65536);
+            assert_eq!(options.logging.as_ref().unwrap(), "info");
+        }
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let config = "catnip:\n  tcp:\n    advertised_mss: 100\n";
+        must_let!(let Err(Fail::Invalid { .. }) = Options::from_str(config, ConfigFormat::Yaml));
+        let config = "catnip:\n  tcp:\n    advertize_mss: 1000\n";
+        must_let!(let Err(Fail::Malformed { .. }) = Options::from_str(config, ConfigFormat::Yaml));
+        must_let!(let Err(Fail::Malformed { .. }) = Options::from_str("", ConfigFormat::Toml));
+        must_let!(let Err(Fail::Unsupported { .. }) = Options::from_file("catnip.json"));
+    }
+
+    #[test]
+    fn build_options() {
+        let builder = Options::builder()
+            .my_ipv4_addr(Ipv4Addr::new(10, 0, 0, 1))
+            .my_link_addr(MacAddress::new([2, 0, 0, 0, 0, 1]));
+        must_let!(let Err(Fail::Invalid { .. }) = Options::builder().build());
+
+        for &profile in &[
+            Profile::LowLatency,
+            Profile::BulkThroughput,
+            Profile::LossyWan,
+        ] {
+            builder.clone().profile(profile).build().unwrap();
+        }
+        let options = builder.clone().profile(Profile::LossyWan).build().unwrap();
+        assert_eq!(options.tcp.retries, 12);
+
+        // Jumbo segments don't fit in a standard MTU...
+        let r = builder.clone().tcp(|tcp| tcp.advertised_mss = 8960).build();
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+        builder
+            .clone()
+            .mtu(9000)
+            .tcp(|tcp| tcp.advertised_mss = 8960)
+            .build()
+            .unwrap();
+
+        // ...or in a receive window smaller than a segment.
+        let r = builder
+            .clone()
+            .tcp(|tcp| tcp.receive_window_size = 1000)
+            .build();
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+
+        // Windows past what the largest scale can express can't be advertised.
+        let r = builder
+            .clone()
+            .tcp(|tcp| tcp.receive_window_size = 1 << 30)
+            .build();
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+
+        let r = builder
+            .clone()
+            .udp(|udp| {
+                udp.echo_port = Some(ip::Port::try_from(7).unwrap());
+                udp.discard_port = udp.echo_port;
+            })
+            .build();
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+
+        let r = builder
+            .clone()
+            .tcp(|tcp| tcp.trailing_ack_delay = tcp.handshake_timeout)
+            .build();
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+
+        let r = builder
+            .clone()
+            .tcp(|tcp| tcp.ephemeral_ports = 1024..=2048)
+            .build();
+        must_let!(let Err(Fail::Invalid { .. }) = r);
+    }
 }
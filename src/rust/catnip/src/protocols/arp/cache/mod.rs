@@ -18,8 +18,9 @@ use futures::{
     },
     FutureExt,
 };
-use std::collections::HashMap;
 use std::{
+    cell::Cell,
+    collections::HashMap,
     future::Future,
     net::Ipv4Addr,
     time::{
@@ -30,20 +31,74 @@ use std::{
 
 const DUMMY_MAC_ADDRESS: MacAddress = MacAddress::new([0; 6]);
 
+/// Where a cache entry stands, like the states `ip neigh` reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NeighborState {
+    /// Configured rather than learned, and never goes stale.
+    Static,
+    /// Learned from ARP traffic within the cache TTL.
+    Reachable,
+    /// Learned longer ago than the cache TTL. The address is still used.
+    Stale,
+    /// Unknown, with a query outstanding.
+    Probing,
+}
+
+/// One entry in `Engine::neighbors`.
+#[derive(Clone, Debug)]
+pub struct Neighbor {
+    pub ipv4_addr: Ipv4Addr,
+    /// `None` while probing.
+    pub link_addr: Option<MacAddress>,
+    pub state: NeighborState,
+    /// Lookups that found this entry.
+    pub hits: u64,
+    pub last_used: Option<Instant>,
+    /// When ARP traffic last confirmed the entry; `None` for static and probing entries.
+    pub last_confirmed: Option<Instant>,
+    /// Requests sent by the outstanding query, for probing entries.
+    pub num_probes: usize,
+}
+
 #[derive(Debug, Clone)]
 struct Record {
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
+    is_static: bool,
+    confirmed: Instant,
+    hits: Cell<u64>,
+    last_used: Cell<Option<Instant>>,
+}
+
+impl Record {
+    fn new(ipv4_addr: Ipv4Addr, link_addr: MacAddress, is_static: bool, now: Instant) -> Self {
+        Self {
+            link_addr,
+            ipv4_addr,
+            is_static,
+            confirmed: now,
+            hits: Cell::new(0),
+            last_used: Cell::new(None),
+        }
+    }
+}
+
+struct Probe {
+    num_sent: usize,
 }
 
 pub struct ArpCache {
     cache: HashTtlCache<Ipv4Addr, Record>,
     rmap: HashMap<MacAddress, Ipv4Addr>,
+    probes: HashMap<Ipv4Addr, Probe>,
+    reachable_time: Option<Duration>,
 
-    // TODO: Allow multiple waiters for the same address
-    // TODO: Deregister waiters here when the receiver goes away.
-    waiters: HashMap<Ipv4Addr, Sender<MacAddress>>,
+    // Waiters whose receiver went away are pruned when the next one registers.
+    waiters: HashMap<Ipv4Addr, Vec<Sender<MacAddress>>>,
     arp_disabled: bool,
+
+    num_hits: Cell<u64>,
+    num_misses: Cell<u64>,
 }
 
 impl ArpCache {
@@ -51,56 +106,69 @@ impl ArpCache {
         ArpCache {
             cache: HashTtlCache::new(now, default_ttl),
             rmap: HashMap::default(),
+            probes: HashMap::default(),
+            reachable_time: default_ttl,
             waiters: HashMap::default(),
             arp_disabled,
+            num_hits: Cell::new(0),
+            num_misses: Cell::new(0),
         }
     }
 
-    pub fn insert_with_ttl(
+    /// Learn (or refresh) a mapping from ARP traffic. A static entry for `ipv4_addr` is left
+    /// alone.
+    pub fn insert(
         &mut self,
         ipv4_addr: Ipv4Addr,
         link_addr: MacAddress,
-        _ttl: Option<Duration>,
+        now: Instant,
     ) -> Option<MacAddress> {
-        let record = Record {
-            ipv4_addr,
-            link_addr,
-        };
-
-        let result = self
-            .cache
-            .insert(ipv4_addr, record)
-            .map(|r| r.link_addr);
-        self.rmap.insert(link_addr, ipv4_addr);
-        if let Some(sender) = self.waiters.remove(&ipv4_addr) {
-            let _ = sender.send(link_addr);
+        if let Some(r) = self.cache.get(&ipv4_addr) {
+            if r.is_static {
+                return Some(r.link_addr);
+            }
         }
-        result
+        self.insert_record(Record::new(ipv4_addr, link_addr, false, now))
     }
 
-    pub fn insert(&mut self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) -> Option<MacAddress> {
-        let record = Record {
-            ipv4_addr,
-            link_addr,
-        };
-        if let Some(sender) = self.waiters.remove(&ipv4_addr) {
-            let _ = sender.send(link_addr);
+    /// Configure a mapping that ARP traffic can't change.
+    pub fn insert_static(
+        &mut self,
+        ipv4_addr: Ipv4Addr,
+        link_addr: MacAddress,
+        now: Instant,
+    ) -> Option<MacAddress> {
+        self.insert_record(Record::new(ipv4_addr, link_addr, true, now))
+    }
+
+    fn insert_record(&mut self, record: Record) -> Option<MacAddress> {
+        let (ipv4_addr, link_addr) = (record.ipv4_addr, record.link_addr);
+        self.probes.remove(&ipv4_addr);
+        self.wake_waiters(ipv4_addr, link_addr);
+        // Keep the usage history of an entry that's only being refreshed.
+        if let Some(old) = self.cache.get(&ipv4_addr) {
+            record.hits.set(old.hits.get());
+            record.last_used.set(old.last_used.get());
         }
         let result = self.cache.insert(ipv4_addr, record).map(|r| r.link_addr);
         self.rmap.insert(link_addr, ipv4_addr);
         result
     }
 
-    pub fn remove(&mut self, _ipv4_addr: Ipv4Addr) {
-        return;
-        // if let Some(record) = self.cache.remove(&ipv4_addr) {
-        //     assert!(self.rmap.remove(&record.link_addr).is_some());
-        // } else {
-        //     panic!(
-        //         "attempt to remove unrecognized engine (`{}`) from ARP cache",
-        //         ipv4_addr
-        //     );
-        // }
+    fn wake_waiters(&mut self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) {
+        for sender in self.waiters.remove(&ipv4_addr).into_iter().flatten() {
+            let _ = sender.send(link_addr);
+        }
+    }
+
+    /// Forget `ipv4_addr`, if we know it.
+    pub fn remove(&mut self, ipv4_addr: Ipv4Addr) {
+        if let Some(record) = self.cache.remove(&ipv4_addr) {
+            // The link address may have moved on to another IPv4 address since.
+            if self.rmap.get(&record.link_addr) == Some(&ipv4_addr) {
+                self.rmap.remove(&record.link_addr);
+            }
+        }
     }
 
     pub fn get_link_addr(&self, ipv4_addr: Ipv4Addr) -> Option<&MacAddress> {
@@ -112,6 +180,82 @@ impl ArpCache {
         result
     }
 
+    /// Like `get_link_addr`, but counted as a use of the entry.
+    pub fn lookup(&self, ipv4_addr: Ipv4Addr, now: Instant) -> Option<MacAddress> {
+        if self.arp_disabled {
+            return Some(DUMMY_MAC_ADDRESS);
+        }
+        match self.cache.get(&ipv4_addr) {
+            Some(r) => {
+                r.hits.set(r.hits.get() + 1);
+                r.last_used.set(Some(now));
+                self.num_hits.set(self.num_hits.get() + 1);
+                Some(r.link_addr)
+            },
+            None => {
+                self.num_misses.set(self.num_misses.get() + 1);
+                None
+            },
+        }
+    }
+
+    /// Note that a query for `ipv4_addr` sent another request.
+    pub fn record_probe(&mut self, ipv4_addr: Ipv4Addr) {
+        self.probes
+            .entry(ipv4_addr)
+            .or_insert(Probe { num_sent: 0 })
+            .num_sent += 1;
+    }
+
+    pub fn end_probe(&mut self, ipv4_addr: Ipv4Addr) {
+        self.probes.remove(&ipv4_addr);
+    }
+
+    pub fn num_hits(&self) -> u64 {
+        self.num_hits.get()
+    }
+
+    pub fn num_misses(&self) -> u64 {
+        self.num_misses.get()
+    }
+
+    /// Every entry and outstanding query, sorted by address.
+    pub fn neighbors(&self, now: Instant) -> Vec<Neighbor> {
+        let mut neighbors = vec![];
+        for (&ipv4_addr, r) in self.cache.iter() {
+            let state = if r.is_static {
+                NeighborState::Static
+            } else {
+                match self.reachable_time {
+                    Some(ttl) if now >= r.confirmed + ttl => NeighborState::Stale,
+                    _ => NeighborState::Reachable,
+                }
+            };
+            neighbors.push(Neighbor {
+                ipv4_addr,
+                link_addr: Some(r.link_addr),
+                state,
+                hits: r.hits.get(),
+                last_used: r.last_used.get(),
+                last_confirmed: if r.is_static { None } else { Some(r.confirmed) },
+                num_probes: 0,
+            });
+        }
+        for (&ipv4_addr, probe) in &self.probes {
+            neighbors.push(Neighbor {
+                ipv4_addr,
+                link_addr: None,
+                state: NeighborState::Probing,
+                hits: 0,
+                last_used: None,
+                last_confirmed: None,
+                num_probes: probe.num_sent,
+            });
+        }
+        neighbors.sort_by_key(|n| n.ipv4_addr);
+        neighbors
+    }
+
     pub fn wait_link_addr(&mut self, ipv4_addr: Ipv4Addr) -> impl Future<Output = MacAddress> {
         let (tx, rx) = channel();
         if self.arp_disabled {
@@ -119,13 +263,17 @@ impl ArpCache {
         } else if let Some(r) = self.cache.get(&ipv4_addr) {
             let _ = tx.send(r.link_addr);
         } else {
-            assert!(self.waiters.insert(ipv4_addr, tx).is_none(), "Duplicate waiter for {:?}", ipv4_addr);
+            let waiters = self.waiters.entry(ipv4_addr).or_insert_with(Vec::new);
+            waiters.retain(|w| !w.is_canceled());
+            waiters.push(tx);
         }
         rx.map(|r| r.expect("Dropped waiter?"))
     }
 
     pub fn get_ipv4_addr(&self, link_addr: MacAddress) -> Option<&Ipv4Addr> {
-        assert_ne!(link_addr, DUMMY_MAC_ADDRESS);
+        if link_addr == DUMMY_MAC_ADDRESS {
+            return None;
+        }
         self.rmap.get(&link_addr)
     }
 
@@ -153,10 +301,11 @@ impl ArpCache {
         map
     }
 
-    pub fn import(&mut self, cache: HashMap<Ipv4Addr, MacAddress>) {
+    /// Replace the cache's contents with learned entries, confirmed as of `now`.
+    pub fn import(&mut self, cache: HashMap<Ipv4Addr, MacAddress>, now: Instant) {
         self.clear();
         for (k, v) in &cache {
-            self.insert(k.clone(), v.clone());
+            self.insert(k.clone(), v.clone(), now);
         }
     }
 }
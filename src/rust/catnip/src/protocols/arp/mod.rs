@@ -3,11 +3,15 @@
 
 mod cache;
 mod options;
-mod pdu;
+pub(crate) mod pdu;
 mod peer;
 
 #[cfg(test)]
 mod tests;
 
+pub use cache::{
+    Neighbor,
+    NeighborState,
+};
 pub use options::ArpOptions as Options;
 pub use peer::ArpPeer as Peer;
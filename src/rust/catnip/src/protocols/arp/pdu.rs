@@ -28,6 +28,12 @@ const HLEN_ETHER2: u8 = 6;
 const PTYPE_IPV4: u16 = 0x800;
 const PLEN_IPV4: u8 = 4;
 const ARP_MESSAGE_SIZE: usize = 28;
+// The fixed part before the addresses, which says how long they are.
+const ARP_FORMAT_SIZE: usize = 8;
+
+/// `Fail::Ignored` details for an ARP packet in a hardware or protocol format other than
+/// Ethernet/IPv4, which the peer counts.
+pub const UNSUPPORTED_FORMAT: &str = "Unsupported ARP hardware/protocol format";
 
 #[repr(u16)]
 #[derive(FromPrimitive, Clone, Copy, Debug, PartialEq, Eq)]
@@ -36,7 +42,7 @@ pub enum ArpOperation {
     Reply = 2,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ArpPdu {
     // We only support Ethernet/Ipv4, so omit these fields.
     // hardware_type: u16,
@@ -91,36 +97,32 @@ impl ArpPdu {
     }
 
     pub fn parse<T: RuntimeBuf>(buf: T) -> Result<Self, Fail> {
-        if buf.len() < ARP_MESSAGE_SIZE {
+        // The address lengths are in the packet, so check the format before assuming the
+        // addresses are Ethernet/IPv4 sized: other formats may legitimately be shorter or longer.
+        if buf.len() < ARP_FORMAT_SIZE {
             return Err(Fail::Malformed {
                 details: "ARP message too short",
             });
         }
-        let buf: &[u8; ARP_MESSAGE_SIZE] = &buf[..ARP_MESSAGE_SIZE].try_into().unwrap();
         let hardware_type = NetworkEndian::read_u16(&buf[0..2]);
-        if hardware_type != HTYPE_ETHER2 {
-            return Err(Fail::Unsupported {
-                details: "Unsupported HTYPE",
-            });
-        }
         let protocol_type = NetworkEndian::read_u16(&buf[2..4]);
-        if protocol_type != PTYPE_IPV4 {
-            return Err(Fail::Unsupported {
-                details: "Unsupported PTYPE",
-            });
-        }
         let hardware_address_len = buf[4];
-        if hardware_address_len != HLEN_ETHER2 {
-            return Err(Fail::Unsupported {
-                details: "Unsupported HLEN",
+        let protocol_address_len = buf[5];
+        if hardware_type != HTYPE_ETHER2
+            || protocol_type != PTYPE_IPV4
+            || hardware_address_len != HLEN_ETHER2
+            || protocol_address_len != PLEN_IPV4
+        {
+            return Err(Fail::Ignored {
+                details: UNSUPPORTED_FORMAT,
             });
         }
-        let protocol_address_len = buf[5];
-        if protocol_address_len != PLEN_IPV4 {
-            return Err(Fail::Unsupported {
-                details: "Unsupported PLEN",
+        if buf.len() < ARP_MESSAGE_SIZE {
+            return Err(Fail::Malformed {
+                details: "ARP message too short",
             });
         }
+        let buf: &[u8; ARP_MESSAGE_SIZE] = &buf[..ARP_MESSAGE_SIZE].try_into().unwrap();
         let operation =
             FromPrimitive::from_u16(NetworkEndian::read_u16(&buf[6..8])).ok_or_else(|| {
                 Fail::Unsupported {
@@ -1,23 +1,22 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use std::marker::PhantomData;
 use super::{
-    cache::ArpCache,
+    cache::{
+        ArpCache,
+        Neighbor,
+    },
     pdu::{
-        ArpMessage,
         ArpOperation,
         ArpPdu,
+        UNSUPPORTED_FORMAT,
     },
 };
 use crate::{
     fail::Fail,
-    protocols::ethernet2::{
-        frame::{
-            EtherType2,
-            Ethernet2Header,
-        },
-        MacAddress,
+    protocols::{
+        ethernet2::MacAddress,
+        packet::PacketBuilder,
     },
     runtime::Runtime,
     scheduler::SchedulerHandle,
@@ -25,7 +24,10 @@ use crate::{
 use futures::FutureExt;
 use std::collections::HashMap;
 use std::{
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
     future::Future,
     net::Ipv4Addr,
     rc::Rc,
@@ -41,6 +43,7 @@ pub struct ArpPeer<RT: Runtime> {
     // TODO: Move this to a strong owner that gets polled once.
     cache: Rc<RefCell<ArpCache>>,
     background: Rc<SchedulerHandle>,
+    num_unsupported: Rc<Cell<u64>>,
 }
 
 impl<RT: Runtime> ArpPeer<RT> {
@@ -56,6 +59,7 @@ impl<RT: Runtime> ArpPeer<RT> {
             rt,
             cache,
             background: Rc::new(handle),
+            num_unsupported: Rc::new(Cell::new(0)),
         };
         for (&link_addr, &ipv4_addr) in &options.initial_values {
             peer.insert(ipv4_addr, link_addr);
@@ -83,26 +87,65 @@ impl<RT: Runtime> ArpPeer<RT> {
         // > [optionally check the hardware length ar$hln]
         // > ?Do I speak the protocol in ar$pro?
         // > [optionally check the protocol length ar$pln]
-        let pdu = ArpPdu::parse(buf)?;
+        let pdu = match ArpPdu::parse(buf) {
+            Err(Fail::Ignored { details }) if details == UNSUPPORTED_FORMAT => {
+                self.num_unsupported.set(self.num_unsupported.get() + 1);
+                return Err(Fail::Ignored { details });
+            },
+            r => r?,
+        };
         debug!("Received {:?}", pdu);
 
+        // Nothing in the packet may poison the cache: the sender must be a unicast station other
+        // than us, and never claim our own address.
+        let local_link_addr = self.rt.local_link_addr();
+        let local_ipv4_addr = self.rt.local_ipv4_addr();
+        if !pdu.sender_hardware_addr.is_unicast() || pdu.sender_hardware_addr.is_nil() {
+            return Err(Fail::Ignored {
+                details: "ARP sender hardware address isn't unicast",
+            });
+        }
+        if pdu.sender_hardware_addr == local_link_addr {
+            return Err(Fail::Ignored {
+                details: "Looped back ARP packet",
+            });
+        }
+        if pdu.sender_protocol_addr == local_ipv4_addr {
+            warn!(
+                "{} claims our address {}",
+                pdu.sender_hardware_addr, local_ipv4_addr
+            );
+            return Err(Fail::Ignored {
+                details: "ARP sender claims our address",
+            });
+        }
+        // An address probe (RFC 5227) has no sender address yet. It still gets a reply if it's
+        // for us, but there's nothing to cache.
+        let is_probe = pdu.sender_protocol_addr.is_unspecified();
+        if is_probe && pdu.operation == ArpOperation::Reply {
+            return Err(Fail::Ignored {
+                details: "ARP reply without a sender address",
+            });
+        }
+
         // from RFC 826:
         // > Merge_flag := false
         // > If the pair <protocol type, sender protocol address> is
         // > already in my translation table, update the sender
         // > hardware address field of the entry with the new
         // > information in the packet and set Merge_flag to true.
-        let merge_flag = {
+        let now = self.rt.now();
+        let merge_flag = !is_probe && {
             let mut cache = self.cache.borrow_mut();
             if cache.get_link_addr(pdu.sender_protocol_addr).is_some() {
-                cache.insert(pdu.sender_protocol_addr, pdu.sender_hardware_addr);
+                cache.insert(pdu.sender_protocol_addr, pdu.sender_hardware_addr, now);
                 true
             } else {
                 false
             }
         };
         // from RFC 826: ?Am I the target protocol address?
-        if pdu.target_protocol_addr != self.rt.local_ipv4_addr() {
+        if pdu.target_protocol_addr != local_ipv4_addr {
             if merge_flag {
                 // we did do something.
                 return Ok(());
@@ -117,10 +160,10 @@ impl<RT: Runtime> ArpPeer<RT> {
         // > If Merge_flag is false, add the triplet <protocol type,
         // > sender protocol address, sender hardware address> to
         // > the translation table.
-        if !merge_flag {
+        if !merge_flag && !is_probe {
             self.cache
                 .borrow_mut()
-                .insert(pdu.sender_protocol_addr, pdu.sender_hardware_addr);
+                .insert(pdu.sender_protocol_addr, pdu.sender_hardware_addr, now);
         }
 
         match pdu.operation {
@@ -128,21 +171,21 @@ impl<RT: Runtime> ArpPeer<RT> {
                 // from RFC 826:
                 // > Swap hardware and protocol fields, putting the local
                 // > hardware and protocol addresses in the sender fields.
-                let reply = ArpMessage {
-                    ethernet2_hdr: Ethernet2Header {
-                        dst_addr: pdu.sender_hardware_addr,
-                        src_addr: self.rt.local_link_addr(),
-                        ether_type: EtherType2::Arp,
-                    },
-                    arp_pdu: ArpPdu {
-                        operation: ArpOperation::Reply,
-                        sender_hardware_addr: self.rt.local_link_addr(),
-                        sender_protocol_addr: self.rt.local_ipv4_addr(),
-                        target_hardware_addr: pdu.sender_hardware_addr,
-                        target_protocol_addr: pdu.sender_protocol_addr,
-                    },
-                    _body_marker: PhantomData,
-                };
+                // The reply is unicast to the requester, whether the request was broadcast or
+                // not, and whatever it put in its target hardware address field. There's nothing
+                // to wait for, so it's queued right here rather than from a background task.
+                let reply = PacketBuilder::new(
+                    &self.rt,
+                    pdu.sender_hardware_addr,
+                    pdu.sender_protocol_addr,
+                )
+                .arp(ArpPdu {
+                    operation: ArpOperation::Reply,
+                    sender_hardware_addr: local_link_addr,
+                    sender_protocol_addr: local_ipv4_addr,
+                    target_hardware_addr: pdu.sender_hardware_addr,
+                    target_protocol_addr: pdu.sender_protocol_addr,
+                });
                 debug!("Responding {:?}", reply);
                 self.rt.transmit(reply);
                 Ok(())
@@ -152,40 +195,34 @@ impl<RT: Runtime> ArpPeer<RT> {
                     "reply from `{}/{}`",
                     pdu.sender_protocol_addr, pdu.sender_hardware_addr
                 );
-                self.cache
-                    .borrow_mut()
-                    .insert(pdu.sender_protocol_addr, pdu.sender_hardware_addr);
+                self.cache.borrow_mut().insert(
+                    pdu.sender_protocol_addr,
+                    pdu.sender_hardware_addr,
+                    now,
+                );
                 Ok(())
             },
         }
     }
 
     pub fn try_query(&self, ipv4_addr: Ipv4Addr) -> Option<MacAddress> {
-        self.cache.borrow().get_link_addr(ipv4_addr).cloned()
+        self.cache.borrow().lookup(ipv4_addr, self.rt.now())
     }
 
     pub fn query(&self, ipv4_addr: Ipv4Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
         let rt = self.rt.clone();
         let cache = self.cache.clone();
         async move {
-            if let Some(&link_addr) = cache.borrow().get_link_addr(ipv4_addr) {
+            if let Some(link_addr) = cache.borrow().lookup(ipv4_addr, rt.now()) {
                 return Ok(link_addr);
             }
-            let msg = ArpMessage {
-                ethernet2_hdr: Ethernet2Header {
-                    dst_addr: MacAddress::broadcast(),
-                    src_addr: rt.local_link_addr(),
-                    ether_type: EtherType2::Arp,
-                },
-                arp_pdu: ArpPdu {
-                    operation: ArpOperation::Request,
-                    sender_hardware_addr: rt.local_link_addr(),
-                    sender_protocol_addr: rt.local_ipv4_addr(),
-                    target_hardware_addr: MacAddress::broadcast(),
-                    target_protocol_addr: ipv4_addr,
-                },
-                _body_marker: PhantomData,
-            };
+            let msg = PacketBuilder::new(&rt, MacAddress::broadcast(), ipv4_addr).arp(ArpPdu {
+                operation: ArpOperation::Request,
+                sender_hardware_addr: rt.local_link_addr(),
+                sender_protocol_addr: rt.local_ipv4_addr(),
+                target_hardware_addr: MacAddress::broadcast(),
+                target_protocol_addr: ipv4_addr,
+            });
             let arp_response = cache.borrow_mut().wait_link_addr(ipv4_addr).fuse();
             futures::pin_mut!(arp_response);
 
@@ -193,8 +230,13 @@ impl<RT: Runtime> ArpPeer<RT> {
             // > The frequency of the ARP request is very close to one per
             // > second, the maximum suggested by [RFC1122].
             let arp_options = rt.arp_options();
+            let _probe = ProbeGuard {
+                cache: cache.clone(),
+                ipv4_addr,
+            };
 
             for i in 0..arp_options.retry_count + 1 {
+                cache.borrow_mut().record_probe(ipv4_addr);
                 rt.transmit(msg.clone());
                 futures::select! {
                     link_addr = arp_response => {
@@ -215,10 +257,40 @@ impl<RT: Runtime> ArpPeer<RT> {
     }
 
     pub fn import_cache(&self, cache: HashMap<Ipv4Addr, MacAddress>) {
-        self.cache.borrow_mut().import(cache);
+        self.cache.borrow_mut().import(cache, self.rt.now());
     }
 
+    /// Add a static entry.
     pub fn insert(&self, ipv4_addr: Ipv4Addr, link_addr: MacAddress) {
-        self.cache.borrow_mut().insert(ipv4_addr, link_addr);
+        self.cache
+            .borrow_mut()
+            .insert_static(ipv4_addr, link_addr, self.rt.now());
+    }
+
+    pub fn neighbors(&self) -> Vec<Neighbor> {
+        self.cache.borrow().neighbors(self.rt.now())
+    }
+
+    /// Lookups that found (hits) or didn't find (misses) an entry.
+    pub fn counters(&self) -> (u64, u64) {
+        let cache = self.cache.borrow();
+        (cache.num_hits(), cache.num_misses())
+    }
+
+    /// Packets ignored for not being Ethernet/IPv4 ARP.
+    pub fn num_unsupported(&self) -> u64 {
+        self.num_unsupported.get()
+    }
+}
+
+// Ends a query's probing state however the query ends, including by being dropped.
+struct ProbeGuard {
+    cache: Rc<RefCell<ArpCache>>,
+    ipv4_addr: Ipv4Addr,
+}
+
+impl Drop for ProbeGuard {
+    fn drop(&mut self) {
+        self.cache.borrow_mut().end_probe(self.ipv4_addr);
     }
 }
@@ -1,16 +1,30 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::pdu::{
-    ArpOperation,
-    ArpPdu,
+use super::{
+    pdu::{
+        ArpMessage,
+        ArpOperation,
+        ArpPdu,
+    },
+    NeighborState,
 };
 use crate::{
     fail::Fail,
-    protocols::ethernet2::frame::{
-        Ethernet2Header,
+    protocols::{
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+                ETHERNET2_HEADER_SIZE,
+            },
+            MacAddress,
+        },
+        gre,
+        ipv4,
     },
     runtime::Runtime,
+    sync::Bytes,
     test_helpers,
 };
 use futures::{
@@ -22,8 +36,15 @@ use futures::{
 };
 use std::collections::HashMap;
 use must_let::must_let;
+use rand::{
+    rngs::SmallRng,
+    Rng,
+    SeedableRng,
+};
 use std::{
     future::Future,
+    marker::PhantomData,
+    net::Ipv4Addr,
     task::Poll,
     time::{
         Duration,
@@ -61,6 +82,8 @@ fn immediate_reply() {
 
     carrie.receive(request).unwrap();
     info!("passing ARP request to carrie...");
+    // The reply goes out without the scheduler having to run.
+    assert_eq!(carrie.rt().num_outgoing(), 1);
     let cache = carrie.export_arp_cache();
     assert_eq!(
         cache.get(&test_helpers::ALICE_IPV4),
@@ -113,6 +136,8 @@ fn slow_reply() {
 
     carrie.receive(request).unwrap();
     info!("passing ARP request to carrie...");
+    // The reply goes out without the scheduler having to run.
+    assert_eq!(carrie.rt().num_outgoing(), 1);
     let cache = carrie.export_arp_cache();
     assert_eq!(
         cache.get(&test_helpers::ALICE_IPV4),
@@ -166,3 +191,262 @@ fn no_reply() {
 
     must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
 }
+
+#[test]
+fn resolve() {
+    // Routes to cached neighbors resolve right away, and a neighbor that never answers fails
+    // the same way an ARP query does.
+    let mut now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut ctx = Context::from_waker(noop_waker_ref());
+
+    let mut fut = alice.resolve(test_helpers::BOB_IPV4).boxed_local();
+    must_let!(let Poll::Ready(Ok(route)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(route.interface, ipv4::Interface::Ethernet);
+    assert_eq!(route.next_hop, test_helpers::BOB_IPV4);
+    assert_eq!(route.link_addr, test_helpers::BOB_MAC);
+
+    alice.gre_open(gre::Options::new(test_helpers::BOB_IPV4));
+    let mut fut = alice.resolve(test_helpers::BOB_IPV4).boxed_local();
+    must_let!(let Poll::Ready(Ok(route)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(route.interface, ipv4::Interface::Gre);
+    alice.gre_close();
+
+    let mut fut = alice.resolve(Ipv4Addr::BROADCAST).boxed_local();
+    must_let!(let Poll::Ready(Ok(route)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(route.link_addr, MacAddress::broadcast());
+    let mut fut = alice.resolve(test_helpers::ALICE_IPV4).boxed_local();
+    must_let!(let Poll::Ready(Err(Fail::Invalid { .. })) = Future::poll(fut.as_mut(), &mut ctx));
+
+    alice.import_arp_cache(HashMap::new());
+    let mut fut = alice.resolve(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    let options = alice.rt().arp_options();
+    for _ in 0..options.retry_count {
+        now += options.request_timeout;
+        alice.rt().advance_clock(now);
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    }
+    now += options.request_timeout;
+    alice.rt().advance_clock(now);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
+}
+
+#[test]
+fn concurrent_queries() {
+    // Several lookups of the same unresolved address all complete off one reply, and a lookup
+    // that timed out doesn't get in the way of the next one.
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    alice.import_arp_cache(HashMap::new());
+    let mut carrie = test_helpers::new_carrie(now);
+    carrie.import_arp_cache(HashMap::new());
+    let options = alice.rt().arp_options();
+
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut abandoned = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(abandoned.as_mut(), &mut ctx).is_pending());
+    for _ in 0..options.retry_count {
+        now += options.request_timeout;
+        alice.rt().advance_clock(now);
+        assert!(Future::poll(abandoned.as_mut(), &mut ctx).is_pending());
+    }
+    now += options.request_timeout;
+    alice.rt().advance_clock(now);
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(abandoned.as_mut(), &mut ctx));
+    drop(abandoned);
+    while alice.rt().num_outgoing() > 0 {
+        alice.rt().pop_frame();
+    }
+
+    let mut first = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    let mut second = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(first.as_mut(), &mut ctx).is_pending());
+    assert!(Future::poll(second.as_mut(), &mut ctx).is_pending());
+
+    carrie.receive(alice.rt().pop_frame()).unwrap();
+    alice.receive(carrie.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(first_addr)) = Future::poll(first.as_mut(), &mut ctx));
+    must_let!(let Poll::Ready(Ok(second_addr)) = Future::poll(second.as_mut(), &mut ctx));
+    assert_eq!(first_addr, test_helpers::CARRIE_MAC);
+    assert_eq!(second_addr, test_helpers::CARRIE_MAC);
+}
+
+#[test]
+fn neighbors() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let ttl = alice.rt().arp_options().cache_ttl;
+
+    // The test runtime preconfigures everyone.
+    let neighbors = alice.neighbors();
+    assert_eq!(neighbors.len(), 3);
+    assert!(neighbors.iter().all(|n| n.state == NeighborState::Static));
+
+    alice.import_arp_cache(HashMap::new());
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+    must_let!(let [probing] = &alice.neighbors()[..]);
+    assert_eq!(probing.state, NeighborState::Probing);
+    assert_eq!(probing.link_addr, None);
+    assert_eq!(probing.num_probes, 1);
+
+    carrie.receive(alice.rt().pop_frame()).unwrap();
+    alice.receive(carrie.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(fut.as_mut(), &mut ctx));
+    drop(fut);
+
+    now += Duration::from_secs(1);
+    alice.rt().advance_clock(now);
+    let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(fut.as_mut(), &mut ctx));
+    must_let!(let [reachable] = &alice.neighbors()[..]);
+    assert_eq!(reachable.state, NeighborState::Reachable);
+    assert_eq!(reachable.link_addr, Some(test_helpers::CARRIE_MAC));
+    assert_eq!(reachable.hits, 1);
+    assert_eq!(reachable.last_used, Some(now));
+
+    now += ttl;
+    alice.rt().advance_clock(now);
+    assert_eq!(alice.neighbors()[0].state, NeighborState::Stale);
+
+    let stats = alice.stats();
+    assert_eq!((stats.arp_hits, stats.arp_misses), (1, 1));
+}
+
+/// A request from alice for `target`, with `sender_protocol_addr` as alice's address.
+fn forged_request(
+    alice: &test_helpers::TestEngine,
+    sender_protocol_addr: Ipv4Addr,
+    target: Ipv4Addr,
+) -> Bytes {
+    alice.rt().transmit(ArpMessage {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: MacAddress::broadcast(),
+            src_addr: test_helpers::ALICE_MAC,
+            ether_type: EtherType2::Arp,
+        },
+        arp_pdu: ArpPdu {
+            operation: ArpOperation::Request,
+            sender_hardware_addr: test_helpers::ALICE_MAC,
+            sender_protocol_addr,
+            target_hardware_addr: MacAddress::nil(),
+            target_protocol_addr: target,
+        },
+        _body_marker: PhantomData,
+    });
+    alice.rt().pop_frame()
+}
+
+#[test]
+fn unsupported_format() {
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let request = forged_request(&alice, test_helpers::ALICE_IPV4, test_helpers::BOB_IPV4);
+
+    // Each of the format fields in turn, then a format whose addresses are shorter than
+    // Ethernet/IPv4's, so the packet is too short to be parsed as one.
+    let arp = ETHERNET2_HEADER_SIZE;
+    for &(offset, value) in &[(arp, 0x06), (arp + 2, 0x86), (arp + 4, 8), (arp + 5, 16)] {
+        let mut frame = request[..].to_vec();
+        frame[offset] = value;
+        must_let!(let Err(Fail::Ignored { .. }) = bob.receive(Bytes::from_slice(&frame[..])));
+    }
+    let mut frame = request[..(arp + 20)].to_vec();
+    frame[arp + 4] = 2;
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(Bytes::from_slice(&frame[..])));
+    assert_eq!(bob.stats().arp_unsupported, 5);
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    // Truncated Ethernet/IPv4 packets are malformed rather than unsupported.
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(Bytes::from_slice(&request[..(arp + 20)])));
+    assert_eq!(bob.stats().arp_unsupported, 5);
+
+    bob.receive(request).unwrap();
+    assert_eq!(bob.rt().num_outgoing(), 1);
+}
+
+#[test]
+fn reply_edge_cases() {
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.import_arp_cache(HashMap::new());
+
+    // An address probe is answered, but its sender isn't cached.
+    let probe = forged_request(&alice, Ipv4Addr::UNSPECIFIED, test_helpers::BOB_IPV4);
+    bob.receive(probe).unwrap();
+    assert!(bob.export_arp_cache().is_empty());
+    let (eth_hdr, buf) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    assert_eq!(eth_hdr.dst_addr, test_helpers::ALICE_MAC);
+    assert_eq!(eth_hdr.src_addr, test_helpers::BOB_MAC);
+    let reply = ArpPdu::parse(buf).unwrap();
+    assert_eq!(
+        reply,
+        ArpPdu {
+            operation: ArpOperation::Reply,
+            sender_hardware_addr: test_helpers::BOB_MAC,
+            sender_protocol_addr: test_helpers::BOB_IPV4,
+            target_hardware_addr: test_helpers::ALICE_MAC,
+            target_protocol_addr: Ipv4Addr::UNSPECIFIED,
+        }
+    );
+
+    // Nobody gets to claim bob's own address.
+    let conflict = forged_request(&alice, test_helpers::BOB_IPV4, test_helpers::BOB_IPV4);
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(conflict));
+    assert!(bob.export_arp_cache().is_empty());
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    // Frames from bob's own hardware address are looped back, and ignored too.
+    let mut looped =
+        forged_request(&alice, test_helpers::ALICE_IPV4, test_helpers::BOB_IPV4)[..].to_vec();
+    looped[(ETHERNET2_HEADER_SIZE + 8)..(ETHERNET2_HEADER_SIZE + 14)]
+        .copy_from_slice(&test_helpers::BOB_MAC.octets());
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(Bytes::from_slice(&looped[..])));
+    assert_eq!(bob.rt().num_outgoing(), 0);
+}
+
+#[test]
+fn pdu_fuzz() {
+    let mut rng = SmallRng::seed_from_u64(0xa2a2);
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    for _ in 0..1000 {
+        let mut buf = [0u8; 28];
+        let operation = if rng.gen() {
+            ArpOperation::Request
+        } else {
+            ArpOperation::Reply
+        };
+        let pdu = ArpPdu {
+            operation,
+            sender_hardware_addr: MacAddress::new(rng.gen()),
+            sender_protocol_addr: Ipv4Addr::from(rng.gen::<u32>()),
+            target_hardware_addr: MacAddress::new(rng.gen()),
+            target_protocol_addr: Ipv4Addr::from(rng.gen::<u32>()),
+        };
+        pdu.serialize(&mut buf);
+        assert_eq!(ArpPdu::parse(Bytes::from_slice(&buf)).unwrap(), pdu);
+    }
+
+    // Random bodies, some starting with a valid format, behind a valid Ethernet header. They may
+    // be rejected but mustn't panic.
+    let request = forged_request(&alice, test_helpers::ALICE_IPV4, test_helpers::BOB_IPV4);
+    let header = &request[..(ETHERNET2_HEADER_SIZE + 8)];
+    for i in 0..1000 {
+        let mut frame = header.to_vec();
+        if i % 2 == 0 {
+            frame.truncate(ETHERNET2_HEADER_SIZE);
+        }
+        let len = rng.gen_range(0, 40);
+        frame.extend((0..len).map(|_| rng.gen::<u8>()));
+        let _ = bob.receive(Bytes::from_slice(&frame[..]));
+        bob.rt().poll_scheduler();
+    }
+}
@@ -0,0 +1,438 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    options::DhcpOptions,
+    packet::{
+        DhcpMessage,
+        DhcpMessageType,
+        BOOTREPLY,
+        DHCP_CLIENT_PORT,
+        DHCP_SERVER_PORT,
+        PARAMETER_REQUESTS,
+    },
+};
+use crate::{
+    event::{
+        Event,
+        EventQueue,
+    },
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+        udp,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use futures::FutureExt;
+use std::{
+    cell::RefCell,
+    cmp,
+    convert::TryFrom,
+    net::Ipv4Addr,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// RFC 2131 4.4.5: While renewing or rebinding, retransmit halfway to the deadline, but no
+// more often than this.
+const MIN_RENEW_RETRANSMIT: Duration = Duration::from_secs(60);
+
+/// Where the client is in the RFC 2131 4.4 state machine. The reboot states aren't used, since
+/// the client doesn't remember leases across restarts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhcpState {
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+/// An address lease the client holds, as the server's ACK granted it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+    pub addr: Ipv4Addr,
+    pub server: Ipv4Addr,
+    pub netmask: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// When the REQUEST that got the lease was sent, on the runtime's clock, which its times
+    /// count from.
+    pub acquired: Instant,
+    pub lease_time: Duration,
+    /// T1: when the client starts asking its server to extend the lease.
+    pub renewal_time: Duration,
+    /// T2: when the client starts asking any server to extend the lease.
+    pub rebinding_time: Duration,
+}
+
+impl Lease {
+    fn new(ack: &DhcpMessage, acquired: Instant) -> Result<Self, Fail> {
+        let server = ack.server_id.ok_or(Fail::Malformed {
+            details: "DHCP ACK without a server identifier",
+        })?;
+        let lease_time = ack.lease_time.ok_or(Fail::Malformed {
+            details: "DHCP ACK without a lease time",
+        })?;
+        if ack.yiaddr.is_unspecified() || ack.yiaddr.is_broadcast() || ack.yiaddr.is_multicast() {
+            return Err(Fail::Malformed {
+                details: "DHCP ACK for an invalid address",
+            });
+        }
+        // RFC 2131 4.4.5 defaults, which also stand in for times out of order.
+        let mut renewal_time = ack.renewal_time.unwrap_or(lease_time / 2);
+        let mut rebinding_time = ack.rebinding_time.unwrap_or(lease_time * 7 / 8);
+        if renewal_time > rebinding_time || rebinding_time > lease_time {
+            renewal_time = lease_time / 2;
+            rebinding_time = lease_time * 7 / 8;
+        }
+        Ok(Self {
+            addr: ack.yiaddr,
+            server,
+            netmask: ack.subnet_mask,
+            gateway: ack.routers.get(0).copied(),
+            dns_servers: ack.dns_servers.clone(),
+            acquired,
+            lease_time,
+            renewal_time,
+            rebinding_time,
+        })
+    }
+
+    pub fn renews(&self) -> Instant {
+        self.acquired + self.renewal_time
+    }
+
+    pub fn rebinds(&self) -> Instant {
+        self.acquired + self.rebinding_time
+    }
+
+    pub fn expires(&self) -> Instant {
+        self.acquired + self.lease_time
+    }
+}
+
+// How an attempt to extend a lease ended.
+enum Renewal {
+    Acked(Lease),
+    Nacked,
+    TimedOut,
+}
+
+struct State {
+    state: DhcpState,
+    lease: Option<Lease>,
+    // What the runtime and the last event were told.
+    config: (Ipv4Addr, Option<Ipv4Addr>, Option<Ipv4Addr>),
+    num_requests: u64,
+}
+
+/// A DHCP client (RFC 2131) that configures the runtime's IPv4 address. While the client has
+/// no lease, the address is unspecified; each change of address, netmask or gateway is raised as
+/// `Event::Ipv4ConfigChanged`. The stack routes everything on-link, so the netmask and gateway
+/// are only reported, for the application to act on. Offered addresses aren't probed with ARP
+/// before they're used.
+pub struct DhcpClient<RT: Runtime> {
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+    state: Rc<RefCell<State>>,
+
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> DhcpClient<RT> {
+    pub fn new(
+        rt: RT,
+        udp: udp::Peer<RT>,
+        events: EventQueue,
+        options: DhcpOptions,
+    ) -> Result<Self, Fail> {
+        let fd = udp.socket();
+        // Replies are broadcast until we have an address, so listen on all of them.
+        let port = ip::Port::try_from(DHCP_CLIENT_PORT).unwrap();
+        if let Err(e) = udp.bind(fd, ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, port)) {
+            udp.close(fd)?;
+            return Err(e);
+        }
+        let state = Rc::new(RefCell::new(State {
+            state: DhcpState::Selecting,
+            lease: None,
+            config: (rt.local_ipv4_addr(), None, None),
+            num_requests: 0,
+        }));
+        let background = Background {
+            rt: rt.clone(),
+            udp: udp.clone(),
+            fd,
+            events,
+            options,
+            state: state.clone(),
+        };
+        background.configure(None);
+        let handle = rt.spawn(background.run());
+        Ok(Self {
+            udp,
+            fd,
+            state,
+            handle,
+        })
+    }
+
+    pub fn state(&self) -> DhcpState {
+        self.state.borrow().state
+    }
+
+    /// The lease currently held, if any.
+    pub fn lease(&self) -> Option<Lease> {
+        self.state.borrow().lease.clone()
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    /// DISCOVERs and REQUESTs sent, counting retransmissions.
+    pub fn num_requests(&self) -> u64 {
+        self.state.borrow().num_requests
+    }
+}
+
+impl<RT: Runtime> Drop for DhcpClient<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close DHCP socket: {:?}", e);
+        }
+    }
+}
+
+struct Background<RT: Runtime> {
+    rt: RT,
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+    events: EventQueue,
+    options: DhcpOptions,
+    state: Rc<RefCell<State>>,
+}
+
+impl<RT: Runtime> Background<RT> {
+    async fn run(self) {
+        loop {
+            let mut lease = match self.acquire().await {
+                Some(lease) => lease,
+                None => continue,
+            };
+            loop {
+                self.configure(Some(&lease));
+                self.set_state(DhcpState::Bound);
+                self.rt.wait_until(lease.renews()).await;
+
+                self.set_state(DhcpState::Renewing);
+                let mut renewal = self.extend(&lease, lease.server, lease.rebinds()).await;
+                if let Renewal::TimedOut = renewal {
+                    self.set_state(DhcpState::Rebinding);
+                    renewal = self
+                        .extend(&lease, Ipv4Addr::BROADCAST, lease.expires())
+                        .await;
+                }
+                match renewal {
+                    Renewal::Acked(extended) => lease = extended,
+                    Renewal::Nacked | Renewal::TimedOut => {
+                        warn!("DHCP lease on {} lost", lease.addr);
+                        self.configure(None);
+                        break;
+                    },
+                }
+            }
+        }
+    }
+
+    // SELECTING and REQUESTING: broadcast for offers and request the first one.
+    async fn acquire(&self) -> Option<Lease> {
+        self.set_state(DhcpState::Selecting);
+        let xid = self.rt.rng_gen();
+        let mut discover = self.message(DhcpMessageType::Discover, xid);
+        discover.broadcast = true;
+        let mut timeout = self.options.request_timeout;
+        let offer = loop {
+            let deadline = self.rt.now() + timeout;
+            let offer = self
+                .exchange(&discover, Ipv4Addr::BROADCAST, deadline, &[DhcpMessageType::Offer])
+                .await;
+            match offer {
+                Some(offer) if offer.server_id.is_some() => break offer,
+                Some(..) => debug!("Ignoring DHCP offer without a server identifier"),
+                None => (),
+            }
+            self.rt.wait_until(deadline).await;
+            timeout = cmp::min(timeout * 2, self.options.max_request_timeout);
+        };
+        debug!("DHCP offer of {} from {:?}", offer.yiaddr, offer.server_id);
+
+        self.set_state(DhcpState::Requesting);
+        let mut request = self.message(DhcpMessageType::Request, xid);
+        request.broadcast = true;
+        request.requested_addr = Some(offer.yiaddr);
+        request.server_id = offer.server_id;
+        let mut timeout = self.options.request_timeout;
+        let acquired = self.rt.now();
+        loop {
+            let deadline = self.rt.now() + timeout;
+            let reply = self
+                .exchange(&request, Ipv4Addr::BROADCAST, deadline, &[
+                    DhcpMessageType::Ack,
+                    DhcpMessageType::Nak,
+                ])
+                .await;
+            match reply {
+                Some(ack) if ack.message_type == DhcpMessageType::Ack => {
+                    match Lease::new(&ack, acquired) {
+                        Ok(lease) => return Some(lease),
+                        Err(e) => warn!("Unusable DHCP ACK: {:?}", e),
+                    }
+                },
+                Some(..) => {
+                    warn!("DHCP server refused {}", offer.yiaddr);
+                    break;
+                },
+                None => (),
+            }
+            if timeout >= self.options.max_request_timeout {
+                break;
+            }
+            self.rt.wait_until(deadline).await;
+            timeout = cmp::min(timeout * 2, self.options.max_request_timeout);
+        }
+        // Don't come straight back with another DISCOVER.
+        self.rt.wait(self.options.request_timeout).await;
+        None
+    }
+
+    // RENEWING and REBINDING: ask `server` to extend `lease` until `until`.
+    async fn extend(&self, lease: &Lease, server: Ipv4Addr, until: Instant) -> Renewal {
+        let xid = self.rt.rng_gen();
+        let mut request = self.message(DhcpMessageType::Request, xid);
+        request.ciaddr = lease.addr;
+        loop {
+            let now = self.rt.now();
+            if now >= until {
+                return Renewal::TimedOut;
+            }
+            let retransmit = cmp::max((until - now) / 2, MIN_RENEW_RETRANSMIT);
+            let deadline = cmp::min(now + retransmit, until);
+            let reply = self
+                .exchange(&request, server, deadline, &[
+                    DhcpMessageType::Ack,
+                    DhcpMessageType::Nak,
+                ])
+                .await;
+            match reply {
+                Some(ack) if ack.message_type == DhcpMessageType::Ack => {
+                    match Lease::new(&ack, now) {
+                        Ok(lease) => return Renewal::Acked(lease),
+                        Err(e) => warn!("Unusable DHCP ACK: {:?}", e),
+                    }
+                },
+                Some(..) => return Renewal::Nacked,
+                None => (),
+            }
+            self.rt.wait_until(deadline).await;
+        }
+    }
+
+    // Send `msg` to `server`, and wait until `deadline` for a reply of one of `types`.
+    async fn exchange(
+        &self,
+        msg: &DhcpMessage,
+        server: Ipv4Addr,
+        deadline: Instant,
+        types: &[DhcpMessageType],
+    ) -> Option<DhcpMessage> {
+        let port = ip::Port::try_from(DHCP_SERVER_PORT).unwrap();
+        let buf = RT::Buf::from_slice(&msg.serialize()[..]);
+        if let Err(e) = self.udp.pushto(self.fd, buf, ipv4::Endpoint::new(server, port)) {
+            warn!("Failed to send DHCP {:?}: {:?}", msg.message_type, e);
+        }
+        self.state.borrow_mut().num_requests += 1;
+        loop {
+            let pop_future = self.udp.pop(self.fd).fuse();
+            let timeout = self.rt.wait_until(deadline).fuse();
+            futures::pin_mut!(pop_future);
+            futures::pin_mut!(timeout);
+            futures::select_biased! {
+                r = pop_future => {
+                    let buf = match r {
+                        Ok((Some(remote), buf)) if remote.port == port => buf,
+                        Ok((remote, _)) => {
+                            debug!("Dropping DHCP datagram from {:?}", remote);
+                            continue;
+                        },
+                        Err(e) => {
+                            warn!("DHCP socket failed: {:?}", e);
+                            return None;
+                        },
+                    };
+                    let reply = match DhcpMessage::parse(&buf[..]) {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            debug!("Ignoring DHCP message: {:?}", e);
+                            continue;
+                        },
+                    };
+                    if reply.op == BOOTREPLY
+                        && reply.xid == msg.xid
+                        && reply.chaddr == msg.chaddr
+                        && types.contains(&reply.message_type)
+                    {
+                        return Some(reply);
+                    }
+                    debug!("Ignoring DHCP {:?} for another request", reply.message_type);
+                },
+                _ = timeout => return None,
+            }
+        }
+    }
+
+    fn message(&self, message_type: DhcpMessageType, xid: u32) -> DhcpMessage {
+        let mut msg = DhcpMessage::request(message_type, xid, self.rt.local_link_addr());
+        msg.parameter_requests = PARAMETER_REQUESTS.to_vec();
+        msg
+    }
+
+    fn set_state(&self, state: DhcpState) {
+        debug!("DHCP client {:?}", state);
+        self.state.borrow_mut().state = state;
+    }
+
+    // Hold `lease`, or none, and apply its address, raising an event if anything changed.
+    fn configure(&self, lease: Option<&Lease>) {
+        let config = match lease {
+            Some(lease) => (lease.addr, lease.netmask, lease.gateway),
+            None => (Ipv4Addr::UNSPECIFIED, None, None),
+        };
+        let mut state = self.state.borrow_mut();
+        state.lease = lease.cloned();
+        if state.config == config {
+            return;
+        }
+        state.config = config;
+        let (addr, netmask, gateway) = config;
+        info!("IPv4 address now {} (netmask {:?}, gateway {:?})", addr, netmask, gateway);
+        self.rt.set_local_ipv4_addr(addr);
+        self.events.push(Event::Ipv4ConfigChanged {
+            addr,
+            netmask,
+            gateway,
+        });
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! A DHCP client (RFC 2131), for when the engine's IPv4 address isn't configured statically.
+//!
+//! The client runs over a UDP socket bound to port 68 on every address, broadcasting until it
+//! has a lease and then renewing it with its server, or failing that any server, as the lease's
+//! timers come due. Each change to the address, netmask or gateway it hands out is raised as an
+//! `Event`.
+
+mod client;
+mod options;
+pub mod packet;
+
+#[cfg(test)]
+mod tests;
+
+pub use client::{
+    DhcpClient as Client,
+    DhcpState as State,
+    Lease,
+};
+pub use options::DhcpOptions as Options;
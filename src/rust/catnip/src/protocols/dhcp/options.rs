@@ -0,0 +1,37 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct DhcpOptions {
+    /// How long to wait for the first reply to a DISCOVER or REQUEST before sending it again.
+    /// The wait doubles with each retransmission (RFC 2131 4.1).
+    pub request_timeout: Duration,
+    /// The longest the doubling wait gets. Once a REQUEST has waited this long unanswered, the
+    /// client starts over with a DISCOVER.
+    pub max_request_timeout: Duration,
+}
+
+impl Default for DhcpOptions {
+    fn default() -> Self {
+        DhcpOptions {
+            request_timeout: Duration::from_secs(4),
+            max_request_timeout: Duration::from_secs(64),
+        }
+    }
+}
+
+impl DhcpOptions {
+    pub fn request_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.request_timeout = value;
+        self
+    }
+
+    pub fn max_request_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.max_request_timeout = value;
+        self
+    }
+}
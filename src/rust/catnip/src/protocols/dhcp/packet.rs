@@ -0,0 +1,299 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    protocols::ethernet2::MacAddress,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use num_traits::FromPrimitive;
+use std::{
+    convert::TryFrom,
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+pub const BOOTREQUEST: u8 = 1;
+pub const BOOTREPLY: u8 = 2;
+
+// The BOOTP header (RFC 2131 2), then the magic cookie that starts the options.
+const DHCP_HEADER_SIZE: usize = 236;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = DHCP_HEADER_SIZE + 4;
+// Some BOOTP relays drop anything shorter (RFC 1542 2.1).
+const DHCP_MIN_MESSAGE_SIZE: usize = 300;
+
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+// Option codes (RFC 2132).
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDR: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUESTS: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+/// What the client asks servers to include in their replies.
+pub const PARAMETER_REQUESTS: [u8; 6] = [
+    OPTION_SUBNET_MASK,
+    OPTION_ROUTER,
+    OPTION_DNS_SERVER,
+    OPTION_LEASE_TIME,
+    OPTION_RENEWAL_TIME,
+    OPTION_REBINDING_TIME,
+];
+
+#[repr(u8)]
+#[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DhcpMessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl TryFrom<u8> for DhcpMessageType {
+    type Error = Fail;
+
+    fn try_from(n: u8) -> Result<Self, Fail> {
+        match FromPrimitive::from_u8(n) {
+            Some(n) => Ok(n),
+            None => Err(Fail::Unsupported {
+                details: "Unsupported DHCP message type",
+            }),
+        }
+    }
+}
+
+/// A DHCP message (RFC 2131) with the options the client uses. Other options are skipped when
+/// parsing, and the server name and boot file fields are neither read nor written.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhcpMessage {
+    pub op: u8,
+    pub xid: u32,
+    pub secs: u16,
+    /// Asks the server to broadcast its reply, since we can't receive unicast without an address.
+    pub broadcast: bool,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: MacAddress,
+
+    pub message_type: DhcpMessageType,
+    pub server_id: Option<Ipv4Addr>,
+    pub requested_addr: Option<Ipv4Addr>,
+    pub lease_time: Option<Duration>,
+    pub renewal_time: Option<Duration>,
+    pub rebinding_time: Option<Duration>,
+    pub subnet_mask: Option<Ipv4Addr>,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub parameter_requests: Vec<u8>,
+}
+
+impl DhcpMessage {
+    /// A client message of type `message_type`, with every optional field empty.
+    pub fn request(message_type: DhcpMessageType, xid: u32, chaddr: MacAddress) -> Self {
+        Self {
+            op: BOOTREQUEST,
+            xid,
+            secs: 0,
+            broadcast: false,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            message_type,
+            server_id: None,
+            requested_addr: None,
+            lease_time: None,
+            renewal_time: None,
+            rebinding_time: None,
+            subnet_mask: None,
+            routers: vec![],
+            dns_servers: vec![],
+            parameter_requests: vec![],
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < DHCP_OPTIONS_OFFSET {
+            return Err(Fail::Malformed {
+                details: "DHCP message too small",
+            });
+        }
+        if buf[1] != HTYPE_ETHERNET || buf[2] != 6 {
+            return Err(Fail::Unsupported {
+                details: "DHCP hardware address isn't Ethernet",
+            });
+        }
+        if buf[DHCP_HEADER_SIZE..DHCP_OPTIONS_OFFSET] != DHCP_MAGIC_COOKIE {
+            return Err(Fail::Malformed {
+                details: "DHCP message without the magic cookie",
+            });
+        }
+        let mut message_type = None;
+        let mut msg = Self::request(DhcpMessageType::Discover, 0, MacAddress::nil());
+        msg.op = buf[0];
+        msg.xid = NetworkEndian::read_u32(&buf[4..8]);
+        msg.secs = NetworkEndian::read_u16(&buf[8..10]);
+        msg.broadcast = NetworkEndian::read_u16(&buf[10..12]) & FLAG_BROADCAST != 0;
+        msg.ciaddr = read_addr(&buf[12..16]);
+        msg.yiaddr = read_addr(&buf[16..20]);
+        msg.siaddr = read_addr(&buf[20..24]);
+        msg.giaddr = read_addr(&buf[24..28]);
+        msg.chaddr = MacAddress::from_bytes(&buf[28..34]);
+
+        let mut options = &buf[DHCP_OPTIONS_OFFSET..];
+        while let Some(&code) = options.get(0) {
+            match code {
+                OPTION_PAD => {
+                    options = &options[1..];
+                    continue;
+                },
+                OPTION_END => break,
+                _ => (),
+            }
+            let len = match options.get(1) {
+                Some(&len) if 2 + len as usize <= options.len() => len as usize,
+                _ => {
+                    return Err(Fail::Malformed {
+                        details: "DHCP option overruns the message",
+                    })
+                },
+            };
+            let value = &options[2..(2 + len)];
+            match code {
+                OPTION_MESSAGE_TYPE if len == 1 => {
+                    message_type = Some(DhcpMessageType::try_from(value[0])?);
+                },
+                OPTION_SUBNET_MASK if len == 4 => msg.subnet_mask = Some(read_addr(value)),
+                OPTION_SERVER_ID if len == 4 => msg.server_id = Some(read_addr(value)),
+                OPTION_REQUESTED_ADDR if len == 4 => msg.requested_addr = Some(read_addr(value)),
+                OPTION_LEASE_TIME if len == 4 => msg.lease_time = Some(read_secs(value)),
+                OPTION_RENEWAL_TIME if len == 4 => msg.renewal_time = Some(read_secs(value)),
+                OPTION_REBINDING_TIME if len == 4 => msg.rebinding_time = Some(read_secs(value)),
+                OPTION_ROUTER if len % 4 == 0 => {
+                    msg.routers = value.chunks(4).map(read_addr).collect();
+                },
+                OPTION_DNS_SERVER if len % 4 == 0 => {
+                    msg.dns_servers = value.chunks(4).map(read_addr).collect();
+                },
+                OPTION_PARAMETER_REQUESTS => msg.parameter_requests = value.to_vec(),
+                OPTION_MESSAGE_TYPE
+                | OPTION_SUBNET_MASK
+                | OPTION_SERVER_ID
+                | OPTION_REQUESTED_ADDR
+                | OPTION_LEASE_TIME
+                | OPTION_RENEWAL_TIME
+                | OPTION_REBINDING_TIME
+                | OPTION_ROUTER
+                | OPTION_DNS_SERVER => {
+                    return Err(Fail::Malformed {
+                        details: "Bad DHCP option length",
+                    })
+                },
+                _ => (),
+            }
+            options = &options[(2 + len)..];
+        }
+        msg.message_type = message_type.ok_or(Fail::Malformed {
+            details: "DHCP message without a type",
+        })?;
+        Ok(msg)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; DHCP_OPTIONS_OFFSET];
+        buf[0] = self.op;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = 6;
+        NetworkEndian::write_u32(&mut buf[4..8], self.xid);
+        NetworkEndian::write_u16(&mut buf[8..10], self.secs);
+        if self.broadcast {
+            NetworkEndian::write_u16(&mut buf[10..12], FLAG_BROADCAST);
+        }
+        buf[12..16].copy_from_slice(&self.ciaddr.octets());
+        buf[16..20].copy_from_slice(&self.yiaddr.octets());
+        buf[20..24].copy_from_slice(&self.siaddr.octets());
+        buf[24..28].copy_from_slice(&self.giaddr.octets());
+        buf[28..34].copy_from_slice(&self.chaddr.octets());
+        buf[DHCP_HEADER_SIZE..DHCP_OPTIONS_OFFSET].copy_from_slice(&DHCP_MAGIC_COOKIE);
+
+        write_option(&mut buf, OPTION_MESSAGE_TYPE, &[self.message_type as u8]);
+        let addrs = [
+            (OPTION_SUBNET_MASK, self.subnet_mask),
+            (OPTION_SERVER_ID, self.server_id),
+            (OPTION_REQUESTED_ADDR, self.requested_addr),
+        ];
+        for &(code, addr) in &addrs {
+            if let Some(addr) = addr {
+                write_option(&mut buf, code, &addr.octets());
+            }
+        }
+        let times = [
+            (OPTION_LEASE_TIME, self.lease_time),
+            (OPTION_RENEWAL_TIME, self.renewal_time),
+            (OPTION_REBINDING_TIME, self.rebinding_time),
+        ];
+        for &(code, time) in &times {
+            if let Some(time) = time {
+                let secs = std::cmp::min(time.as_secs(), u32::max_value() as u64) as u32;
+                let mut value = [0u8; 4];
+                NetworkEndian::write_u32(&mut value, secs);
+                write_option(&mut buf, code, &value);
+            }
+        }
+        write_addrs(&mut buf, OPTION_ROUTER, &self.routers);
+        write_addrs(&mut buf, OPTION_DNS_SERVER, &self.dns_servers);
+        if !self.parameter_requests.is_empty() {
+            write_option(&mut buf, OPTION_PARAMETER_REQUESTS, &self.parameter_requests);
+        }
+        buf.push(OPTION_END);
+        if buf.len() < DHCP_MIN_MESSAGE_SIZE {
+            buf.resize(DHCP_MIN_MESSAGE_SIZE, OPTION_PAD);
+        }
+        buf
+    }
+}
+
+fn write_option(buf: &mut Vec<u8>, code: u8, value: &[u8]) {
+    assert!(value.len() <= 255);
+    buf.push(code);
+    buf.push(value.len() as u8);
+    buf.extend_from_slice(value);
+}
+
+fn write_addrs(buf: &mut Vec<u8>, code: u8, addrs: &[Ipv4Addr]) {
+    if !addrs.is_empty() {
+        let value = addrs.iter().flat_map(|a| a.octets().to_vec()).collect::<Vec<_>>();
+        write_option(buf, code, &value);
+    }
+}
+
+fn read_addr(buf: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])
+}
+
+fn read_secs(buf: &[u8]) -> Duration {
+    Duration::from_secs(NetworkEndian::read_u32(buf) as u64)
+}
@@ -0,0 +1,233 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    packet::{
+        DhcpMessage,
+        DhcpMessageType,
+        BOOTREPLY,
+        DHCP_CLIENT_PORT,
+        DHCP_SERVER_PORT,
+    },
+    Options,
+    State,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    event::Event,
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ethernet2::frame::ETHERNET2_HEADER_SIZE,
+        ip,
+        ipv4,
+        ipv4::datagram::Ipv4Header,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    sync::Bytes,
+    test_helpers::{
+        self,
+        TestRuntime,
+    },
+};
+use futures::task::{
+    noop_waker_ref,
+    Context,
+};
+use must_let::must_let;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    pin::Pin,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+const LEASED_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+// The DHCP message alice sent, after bob's server socket has received it, along with the IPv4
+// header it came in.
+fn serve(
+    alice: &mut Engine<TestRuntime>,
+    bob: &mut Engine<TestRuntime>,
+    fd: FileDescriptor,
+) -> (Ipv4Header, DhcpMessage) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    let mut packet = frame.clone();
+    packet.adjust(ETHERNET2_HEADER_SIZE);
+    let (ipv4_hdr, _) = Ipv4Header::parse(packet).unwrap();
+    bob.receive(frame).unwrap();
+    let mut pop_future = bob.udp_pop(fd);
+    must_let!(let Poll::Ready(Ok((Some(client), buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(client.port, ip::Port::try_from(DHCP_CLIENT_PORT).unwrap());
+    (ipv4_hdr, DhcpMessage::parse(&buf[..]).unwrap())
+}
+
+// Bob's reply to `request`, sent to `dst`.
+fn reply(
+    alice: &mut Engine<TestRuntime>,
+    bob: &mut Engine<TestRuntime>,
+    fd: FileDescriptor,
+    request: &DhcpMessage,
+    message_type: DhcpMessageType,
+    dst: Ipv4Addr,
+) {
+    let mut reply = DhcpMessage::request(message_type, request.xid, request.chaddr);
+    reply.op = BOOTREPLY;
+    reply.server_id = Some(test_helpers::BOB_IPV4);
+    if message_type != DhcpMessageType::Nak {
+        reply.yiaddr = LEASED_ADDR;
+        reply.lease_time = Some(Duration::from_secs(3600));
+        reply.renewal_time = Some(Duration::from_secs(100));
+        reply.rebinding_time = Some(Duration::from_secs(150));
+        reply.subnet_mask = Some(NETMASK);
+        reply.routers = vec![test_helpers::BOB_IPV4];
+        reply.dns_servers = vec![test_helpers::CARRIE_IPV4];
+    }
+    let to = ipv4::Endpoint::new(dst, ip::Port::try_from(DHCP_CLIENT_PORT).unwrap());
+    let buf = Bytes::from_slice(&reply.serialize()[..]);
+    bob.udp_pushto(fd, buf, to).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+}
+
+#[test]
+fn message_round_trip() {
+    let mut msg =
+        DhcpMessage::request(DhcpMessageType::Request, 0x1234_5678, test_helpers::ALICE_MAC);
+    msg.broadcast = true;
+    msg.requested_addr = Some(LEASED_ADDR);
+    msg.server_id = Some(test_helpers::BOB_IPV4);
+    msg.routers = vec![test_helpers::BOB_IPV4, test_helpers::CARRIE_IPV4];
+    msg.lease_time = Some(Duration::from_secs(86400));
+    msg.parameter_requests = vec![1, 3, 6];
+    let buf = msg.serialize();
+    assert_eq!(buf.len(), 300);
+    assert_eq!(DhcpMessage::parse(&buf[..]).unwrap(), msg);
+
+    // The options start after the 236 byte header and the magic cookie, with the message type.
+    let mut truncated = buf[..243].to_vec();
+    assert_eq!(&truncated[240..], &[53, 1, DhcpMessageType::Request as u8]);
+    must_let!(let Ok(..) = DhcpMessage::parse(&truncated[..]));
+    truncated[241] = 2;
+    must_let!(let Err(Fail::Malformed { .. }) = DhcpMessage::parse(&truncated[..]));
+    must_let!(let Err(Fail::Malformed { .. }) = DhcpMessage::parse(&buf[..242]));
+
+    let mut no_cookie = buf.clone();
+    no_cookie[236] = 0;
+    must_let!(let Err(Fail::Malformed { .. }) = DhcpMessage::parse(&no_cookie[..]));
+}
+
+#[test]
+fn acquire_renew_and_lose_lease() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    // Bob plays the DHCP server.
+    let server = ipv4::Endpoint::new(
+        Ipv4Addr::UNSPECIFIED,
+        ip::Port::try_from(DHCP_SERVER_PORT).unwrap(),
+    );
+    let fd = bob.socket(Protocol::Udp);
+    bob.bind(fd, server).unwrap();
+
+    // Alice gives up her static address straight away.
+    alice.dhcp_start(Options::default()).unwrap();
+    assert_eq!(alice.rt().local_ipv4_addr(), Ipv4Addr::UNSPECIFIED);
+    let events = alice.take_events();
+    must_let!(let [Event::Ipv4ConfigChanged { addr, netmask: None, gateway: None }] = &events[..]);
+    assert_eq!(*addr, Ipv4Addr::UNSPECIFIED);
+
+    let (ipv4_hdr, discover) = serve(&mut alice, &mut bob, fd);
+    assert_eq!(ipv4_hdr.src_addr, Ipv4Addr::UNSPECIFIED);
+    assert_eq!(ipv4_hdr.dst_addr, Ipv4Addr::BROADCAST);
+    assert_eq!(discover.message_type, DhcpMessageType::Discover);
+    assert!(discover.broadcast);
+    assert_eq!(alice.dhcp_state(), Some(State::Selecting));
+    reply(&mut alice, &mut bob, fd, &discover, DhcpMessageType::Offer, Ipv4Addr::BROADCAST);
+
+    let (_, request) = serve(&mut alice, &mut bob, fd);
+    assert_eq!(request.message_type, DhcpMessageType::Request);
+    assert_eq!(request.xid, discover.xid);
+    assert_eq!(request.requested_addr, Some(LEASED_ADDR));
+    assert_eq!(request.server_id, Some(test_helpers::BOB_IPV4));
+    reply(&mut alice, &mut bob, fd, &request, DhcpMessageType::Ack, Ipv4Addr::BROADCAST);
+
+    assert_eq!(alice.dhcp_state(), Some(State::Bound));
+    assert_eq!(alice.rt().local_ipv4_addr(), LEASED_ADDR);
+    must_let!(let [Event::Ipv4ConfigChanged { addr, netmask, gateway }] = &alice.take_events()[..]);
+    assert_eq!(*addr, LEASED_ADDR);
+    assert_eq!(*netmask, Some(NETMASK));
+    assert_eq!(*gateway, Some(test_helpers::BOB_IPV4));
+    let lease = alice.dhcp_lease().unwrap();
+    assert_eq!(lease.renews(), now + Duration::from_secs(100));
+    assert_eq!(lease.dns_servers, vec![test_helpers::CARRIE_IPV4]);
+
+    // At T1, alice asks bob directly to extend the lease, and he does.
+    let mut cache = HashMap::new();
+    cache.insert(LEASED_ADDR, test_helpers::ALICE_MAC);
+    bob.import_arp_cache(cache);
+    now += Duration::from_secs(100);
+    alice.rt().advance_clock(now);
+    let (ipv4_hdr, renewal) = serve(&mut alice, &mut bob, fd);
+    assert_eq!(alice.dhcp_state(), Some(State::Renewing));
+    assert_eq!(ipv4_hdr.src_addr, LEASED_ADDR);
+    assert_eq!(ipv4_hdr.dst_addr, test_helpers::BOB_IPV4);
+    assert_eq!(renewal.ciaddr, LEASED_ADDR);
+    assert_eq!(renewal.requested_addr, None);
+    reply(&mut alice, &mut bob, fd, &renewal, DhcpMessageType::Ack, LEASED_ADDR);
+    assert_eq!(alice.dhcp_state(), Some(State::Bound));
+    assert_eq!(alice.dhcp_lease().unwrap().renews(), now + Duration::from_secs(100));
+    assert!(alice.take_events().is_empty());
+
+    // The next time, bob refuses, so alice drops the address and starts over.
+    now += Duration::from_secs(100);
+    alice.rt().advance_clock(now);
+    let (_, renewal) = serve(&mut alice, &mut bob, fd);
+    reply(&mut alice, &mut bob, fd, &renewal, DhcpMessageType::Nak, LEASED_ADDR);
+    assert_eq!(alice.rt().local_ipv4_addr(), Ipv4Addr::UNSPECIFIED);
+    assert_eq!(alice.dhcp_lease(), None);
+    must_let!(let [Event::Ipv4ConfigChanged { addr, .. }] = &alice.take_events()[..]);
+    assert_eq!(*addr, Ipv4Addr::UNSPECIFIED);
+    let (_, discover) = serve(&mut alice, &mut bob, fd);
+    assert_eq!(discover.message_type, DhcpMessageType::Discover);
+}
+
+#[test]
+fn retransmit_with_backoff() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let options = Options::default()
+        .request_timeout(Duration::from_secs(2))
+        .max_request_timeout(Duration::from_secs(4));
+    alice.dhcp_start(options).unwrap();
+
+    // Unanswered DISCOVERs go out after 2s, then every 4s.
+    for &wait in &[2, 4, 4] {
+        alice.rt().poll_scheduler();
+        alice.rt().pop_frame();
+        now += Duration::from_secs(wait) - Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert_eq!(alice.rt().num_outgoing(), 0);
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+    }
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.rt().num_outgoing(), 1);
+}
@@ -0,0 +1,18 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! A stub DNS resolver (RFC 1035), so applications can look up IPv4 addresses by name.
+//!
+//! Queries go over UDP to a list of recursive resolvers, trying each in turn with a doubling
+//! timeout, and are retried over TCP when a response comes back truncated. Answers are cached
+//! for their TTL.
+
+mod options;
+pub mod packet;
+mod resolver;
+
+#[cfg(test)]
+mod tests;
+
+pub use options::DnsOptions as Options;
+pub use resolver::DnsResolver as Resolver;
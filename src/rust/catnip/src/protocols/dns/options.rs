@@ -0,0 +1,48 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ip;
+use std::{
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+#[derive(Clone, Debug)]
+pub struct DnsOptions {
+    /// Recursive resolvers to ask, in order of preference, e.g. those from a DHCP lease.
+    pub servers: Vec<Ipv4Addr>,
+    pub local_port: ip::Port,
+    /// How long to wait for the first round of queries to be answered. The wait doubles with
+    /// each round.
+    pub request_timeout: Duration,
+    /// Rounds of queries to every server before giving up.
+    pub attempts: u32,
+}
+
+impl DnsOptions {
+    pub fn new(servers: Vec<Ipv4Addr>) -> Self {
+        DnsOptions {
+            servers,
+            local_port: ip::Port::first_private_port(),
+            request_timeout: Duration::from_secs(1),
+            attempts: 3,
+        }
+    }
+
+    pub fn local_port(mut self, value: ip::Port) -> Self {
+        self.local_port = value;
+        self
+    }
+
+    pub fn request_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.request_timeout = value;
+        self
+    }
+
+    pub fn attempts(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.attempts = value;
+        self
+    }
+}
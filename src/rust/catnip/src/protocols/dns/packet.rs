@@ -0,0 +1,330 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::fail::Fail;
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+pub const DNS_PORT: u16 = 53;
+
+const DNS_HEADER_SIZE: usize = 12;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_MASK: u16 = 0x000f;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_SERVER_FAILURE: u8 = 2;
+pub const RCODE_NAME_ERROR: u8 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+
+// Limits on names (RFC 1035 2.3.4), counting the length octets of the encoded form.
+const MAX_LABEL_SIZE: usize = 63;
+const MAX_NAME_SIZE: usize = 255;
+// Compression pointers a name may follow before we decide it loops.
+const MAX_NAME_POINTERS: usize = 16;
+
+/// The lower-cased form of `hostname` without its trailing dot, or an error if it isn't a name
+/// we can put in a query.
+pub fn normalize_name(hostname: &str) -> Result<String, Fail> {
+    let name = hostname.strip_suffix('.').unwrap_or(hostname);
+    if name.is_empty() || name.len() + 2 > MAX_NAME_SIZE {
+        return Err(Fail::Invalid {
+            details: "DNS name is empty or too long",
+        });
+    }
+    if name
+        .split('.')
+        .any(|label| label.is_empty() || label.len() > MAX_LABEL_SIZE)
+    {
+        return Err(Fail::Invalid {
+            details: "DNS name has an empty or overlong label",
+        });
+    }
+    Ok(name.to_ascii_lowercase())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DnsRecordData {
+    A(Ipv4Addr),
+    Cname(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsRecord {
+    pub name: String,
+    pub ttl: Duration,
+    pub data: DnsRecordData,
+}
+
+/// A DNS message (RFC 1035 4.1) with a single question for IPv4 addresses. Only A and CNAME
+/// answers are kept when parsing, and the authority and additional sections are neither read nor
+/// written. Names are lower-cased.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub response: bool,
+    /// Set by a server that had to cut its response short to fit in a datagram.
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub rcode: u8,
+    pub question: String,
+    pub answers: Vec<DnsRecord>,
+}
+
+impl DnsMessage {
+    /// A recursive query for the A records of `name`, which should already be normalized.
+    pub fn query(id: u16, name: String) -> Self {
+        Self {
+            id,
+            response: false,
+            truncated: false,
+            recursion_desired: true,
+            rcode: RCODE_NO_ERROR,
+            question: name,
+            answers: vec![],
+        }
+    }
+
+    /// The addresses the question's name resolves to, following any CNAMEs, along with the
+    /// shortest TTL on the way.
+    pub fn addresses(&self) -> Option<(Vec<Ipv4Addr>, Duration)> {
+        let mut name = &self.question;
+        let mut ttl = None;
+        // Each hop uses up an answer, which stops a CNAME loop.
+        for _ in 0..=self.answers.len() {
+            let mut addrs = vec![];
+            let mut next = None;
+            for record in self.answers.iter().filter(|r| &r.name == name) {
+                match record.data {
+                    DnsRecordData::A(addr) => {
+                        addrs.push(addr);
+                        ttl = Some(ttl.map_or(record.ttl, |t| std::cmp::min(t, record.ttl)));
+                    },
+                    DnsRecordData::Cname(ref target) => next = Some((target, record.ttl)),
+                }
+            }
+            if !addrs.is_empty() {
+                return Some((addrs, ttl.unwrap()));
+            }
+            let (target, cname_ttl) = next?;
+            name = target;
+            ttl = Some(ttl.map_or(cname_ttl, |t| std::cmp::min(t, cname_ttl)));
+        }
+        None
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < DNS_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "DNS message too small",
+            });
+        }
+        let flags = NetworkEndian::read_u16(&buf[2..4]);
+        if flags & OPCODE_MASK != 0 {
+            return Err(Fail::Unsupported {
+                details: "DNS message isn't a standard query",
+            });
+        }
+        if NetworkEndian::read_u16(&buf[4..6]) != 1 {
+            return Err(Fail::Unsupported {
+                details: "DNS message doesn't have exactly one question",
+            });
+        }
+        let num_answers = NetworkEndian::read_u16(&buf[6..8]);
+
+        let mut offset = DNS_HEADER_SIZE;
+        let question = read_name(buf, &mut offset)?;
+        let (qtype, qclass) = match buf.get(offset..(offset + 4)) {
+            Some(b) => (NetworkEndian::read_u16(&b[0..2]), NetworkEndian::read_u16(&b[2..4])),
+            None => {
+                return Err(Fail::Malformed {
+                    details: "DNS question overruns the message",
+                })
+            },
+        };
+        offset += 4;
+        if qtype != TYPE_A || qclass != CLASS_IN {
+            return Err(Fail::Unsupported {
+                details: "DNS question isn't for IPv4 addresses",
+            });
+        }
+
+        let mut msg = Self::query(NetworkEndian::read_u16(&buf[0..2]), question);
+        msg.response = flags & FLAG_RESPONSE != 0;
+        msg.truncated = flags & FLAG_TRUNCATED != 0;
+        msg.recursion_desired = flags & FLAG_RECURSION_DESIRED != 0;
+        msg.rcode = (flags & RCODE_MASK) as u8;
+        // A truncated message may stop partway through a record, and gets asked again over TCP
+        // anyway.
+        if msg.truncated {
+            return Ok(msg);
+        }
+        for _ in 0..num_answers {
+            let name = read_name(buf, &mut offset)?;
+            let fixed = match buf.get(offset..(offset + 10)) {
+                Some(b) => b,
+                None => {
+                    return Err(Fail::Malformed {
+                        details: "DNS record overruns the message",
+                    })
+                },
+            };
+            let rtype = NetworkEndian::read_u16(&fixed[0..2]);
+            let class = NetworkEndian::read_u16(&fixed[2..4]);
+            let ttl = Duration::from_secs(NetworkEndian::read_u32(&fixed[4..8]) as u64);
+            let len = NetworkEndian::read_u16(&fixed[8..10]) as usize;
+            let data_offset = offset + 10;
+            offset = data_offset + len;
+            if offset > buf.len() {
+                return Err(Fail::Malformed {
+                    details: "DNS record data overruns the message",
+                });
+            }
+            let data = match (rtype, class) {
+                (TYPE_A, CLASS_IN) if len == 4 => {
+                    let b = &buf[data_offset..offset];
+                    DnsRecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+                },
+                (TYPE_A, CLASS_IN) => {
+                    return Err(Fail::Malformed {
+                        details: "Bad DNS A record length",
+                    })
+                },
+                (TYPE_CNAME, CLASS_IN) => {
+                    // The target may point back into the rest of the message.
+                    let mut target_offset = data_offset;
+                    let target = read_name(&buf[..offset], &mut target_offset)?;
+                    DnsRecordData::Cname(target)
+                },
+                _ => continue,
+            };
+            msg.answers.push(DnsRecord { name, ttl, data });
+        }
+        Ok(msg)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; DNS_HEADER_SIZE];
+        let mut flags = self.rcode as u16 & RCODE_MASK;
+        if self.response {
+            flags |= FLAG_RESPONSE;
+        }
+        if self.truncated {
+            flags |= FLAG_TRUNCATED;
+        }
+        if self.recursion_desired {
+            flags |= FLAG_RECURSION_DESIRED;
+        }
+        NetworkEndian::write_u16(&mut buf[0..2], self.id);
+        NetworkEndian::write_u16(&mut buf[2..4], flags);
+        NetworkEndian::write_u16(&mut buf[4..6], 1);
+        NetworkEndian::write_u16(&mut buf[6..8], self.answers.len() as u16);
+
+        write_name(&mut buf, &self.question);
+        write_u16(&mut buf, TYPE_A);
+        write_u16(&mut buf, CLASS_IN);
+        for record in &self.answers {
+            write_name(&mut buf, &record.name);
+            let mut data = vec![];
+            let rtype = match record.data {
+                DnsRecordData::A(addr) => {
+                    data.extend_from_slice(&addr.octets());
+                    TYPE_A
+                },
+                DnsRecordData::Cname(ref target) => {
+                    write_name(&mut data, target);
+                    TYPE_CNAME
+                },
+            };
+            write_u16(&mut buf, rtype);
+            write_u16(&mut buf, CLASS_IN);
+            let ttl = std::cmp::min(record.ttl.as_secs(), u32::max_value() as u64) as u32;
+            let mut value = [0u8; 4];
+            NetworkEndian::write_u32(&mut value, ttl);
+            buf.extend_from_slice(&value);
+            write_u16(&mut buf, data.len() as u16);
+            buf.extend_from_slice(&data);
+        }
+        buf
+    }
+}
+
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    let mut b = [0u8; 2];
+    NetworkEndian::write_u16(&mut b, value);
+    buf.extend_from_slice(&b);
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        assert!(!label.is_empty() && label.len() <= MAX_LABEL_SIZE);
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+// Read the name at `offset`, following compression pointers (RFC 1035 4.1.4), and move `offset`
+// past it.
+fn read_name(buf: &[u8], offset: &mut usize) -> Result<String, Fail> {
+    let overrun = Fail::Malformed {
+        details: "DNS name overruns the message",
+    };
+    let mut labels = vec![];
+    let mut size = 1;
+    let mut pos = *offset;
+    let mut num_pointers = 0;
+    loop {
+        let len = *buf.get(pos).ok_or(overrun.clone())? as usize;
+        match len & 0xc0 {
+            0xc0 => {
+                let low = *buf.get(pos + 1).ok_or(overrun.clone())? as usize;
+                if num_pointers == 0 {
+                    *offset = pos + 2;
+                }
+                num_pointers += 1;
+                if num_pointers > MAX_NAME_POINTERS {
+                    return Err(Fail::Malformed {
+                        details: "DNS name has too many pointers",
+                    });
+                }
+                pos = ((len & 0x3f) << 8) | low;
+            },
+            0 if len == 0 => {
+                if num_pointers == 0 {
+                    *offset = pos + 1;
+                }
+                break;
+            },
+            0 => {
+                let label = buf.get((pos + 1)..(pos + 1 + len)).ok_or(overrun.clone())?;
+                size += 1 + len;
+                if size > MAX_NAME_SIZE {
+                    return Err(Fail::Malformed {
+                        details: "DNS name too long",
+                    });
+                }
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + len;
+            },
+            _ => {
+                return Err(Fail::Unsupported {
+                    details: "DNS name uses an unknown label type",
+                })
+            },
+        }
+    }
+    Ok(labels.join("."))
+}
@@ -0,0 +1,345 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    options::DnsOptions,
+    packet::{
+        self,
+        DnsMessage,
+        DNS_PORT,
+        RCODE_NAME_ERROR,
+        RCODE_NO_ERROR,
+    },
+};
+use crate::{
+    collections::HashTtlCache,
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+        tcp,
+        udp,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::{
+    channel::oneshot::{
+        channel,
+        Sender,
+    },
+    FutureExt,
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Clone)]
+struct CachedAnswer {
+    addr: Ipv4Addr,
+    expires: Instant,
+}
+
+struct PendingQuery {
+    server: Ipv4Addr,
+    name: String,
+    tx: Sender<DnsMessage>,
+}
+
+struct Inner {
+    // `HashTtlCache` doesn't check expiry on lookup, so each answer carries its own.
+    cache: HashTtlCache<String, CachedAnswer>,
+    // Outstanding UDP queries by ID.
+    pending: HashMap<u16, PendingQuery>,
+    num_queries: u64,
+    num_responses: u64,
+}
+
+pub struct DnsResolver<RT: Runtime> {
+    rt: RT,
+    udp: udp::Peer<RT>,
+    tcp: tcp::Peer<RT>,
+    fd: FileDescriptor,
+    options: Rc<DnsOptions>,
+    inner: Rc<RefCell<Inner>>,
+
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> DnsResolver<RT> {
+    pub fn new(
+        rt: RT,
+        udp: udp::Peer<RT>,
+        tcp: tcp::Peer<RT>,
+        options: DnsOptions,
+    ) -> Result<Self, Fail> {
+        if options.servers.is_empty() {
+            return Err(Fail::Invalid {
+                details: "No DNS servers",
+            });
+        }
+        let fd = udp.socket();
+        let local = ipv4::Endpoint::new(rt.local_ipv4_addr(), options.local_port);
+        if let Err(e) = udp.bind(fd, local) {
+            udp.close(fd)?;
+            return Err(e);
+        }
+        let inner = Rc::new(RefCell::new(Inner {
+            cache: HashTtlCache::new(rt.now(), None),
+            pending: HashMap::new(),
+            num_queries: 0,
+            num_responses: 0,
+        }));
+        let handle = rt.spawn(Self::background(udp.clone(), fd, inner.clone()));
+        Ok(Self {
+            rt,
+            udp,
+            tcp,
+            fd,
+            options: Rc::new(options),
+            inner,
+            handle,
+        })
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    pub fn num_queries(&self) -> u64 {
+        self.inner.borrow().num_queries
+    }
+
+    pub fn num_responses(&self) -> u64 {
+        self.inner.borrow().num_responses
+    }
+
+    /// Look up an IPv4 address for `hostname`, which may also be a dotted-quad address. A name
+    /// the servers say doesn't exist, or has no IPv4 addresses, fails with `ResourceNotFound`;
+    /// running out of attempts fails with `Timeout`.
+    pub fn resolve(&self, hostname: &str) -> impl Future<Output = Result<Ipv4Addr, Fail>> {
+        let literal = hostname.parse::<Ipv4Addr>().ok();
+        let name = packet::normalize_name(hostname);
+        let rt = self.rt.clone();
+        let udp = self.udp.clone();
+        let tcp = self.tcp.clone();
+        let fd = self.fd;
+        let options = self.options.clone();
+        let inner = self.inner.clone();
+        async move {
+            if let Some(addr) = literal {
+                return Ok(addr);
+            }
+            let name = name?;
+            {
+                let mut inner = inner.borrow_mut();
+                inner.cache.advance_clock(rt.now());
+                match inner.cache.get(&name) {
+                    Some(answer) if answer.expires > rt.now() => return Ok(answer.addr),
+                    Some(..) => {
+                        inner.cache.remove(&name);
+                    },
+                    None => (),
+                }
+            }
+
+            let mut timeout = options.request_timeout;
+            for _ in 0..options.attempts {
+                for &server in &options.servers {
+                    let r = match query_udp(&rt, &udp, fd, &inner, server, &name, timeout).await {
+                        Ok(ref response) if response.truncated => {
+                            debug!("DNS response from {} truncated, retrying over TCP", server);
+                            query_tcp(&rt, &tcp, server, &name, timeout).await
+                        },
+                        r => r,
+                    };
+                    let response = match r {
+                        Ok(response) => response,
+                        Err(e) => {
+                            debug!("DNS query for {} to {} failed: {:?}", name, server, e);
+                            continue;
+                        },
+                    };
+                    match response.rcode {
+                        RCODE_NO_ERROR => (),
+                        RCODE_NAME_ERROR => {
+                            return Err(Fail::ResourceNotFound {
+                                details: "DNS name doesn't exist",
+                            })
+                        },
+                        rcode => {
+                            debug!("DNS server {} failed {} with rcode {}", server, name, rcode);
+                            continue;
+                        },
+                    }
+                    let (addrs, ttl) = response.addresses().ok_or(Fail::ResourceNotFound {
+                        details: "DNS name has no IPv4 addresses",
+                    })?;
+                    let answer = CachedAnswer {
+                        addr: addrs[0],
+                        expires: rt.now() + ttl,
+                    };
+                    if ttl > Duration::new(0, 0) {
+                        let mut inner = inner.borrow_mut();
+                        inner.cache.insert_with_ttl(name, answer.clone(), Some(ttl));
+                    }
+                    return Ok(answer.addr);
+                }
+                timeout *= 2;
+            }
+            Err(Fail::Timeout {})
+        }
+    }
+
+    // Hand each response on the socket to the query it answers.
+    async fn background(udp: udp::Peer<RT>, fd: FileDescriptor, inner: Rc<RefCell<Inner>>) {
+        loop {
+            let (remote, buf) = match udp.pop(fd).await {
+                Ok((Some(remote), buf)) => (remote, buf),
+                Ok((None, _)) => continue,
+                Err(e) => {
+                    warn!("DNS socket failed: {:?}", e);
+                    return;
+                },
+            };
+            let response = match DnsMessage::parse(&buf[..]) {
+                Ok(response) if response.response => response,
+                Ok(..) => {
+                    debug!("Dropping DNS query from {:?}", remote);
+                    continue;
+                },
+                Err(e) => {
+                    debug!("Dropping DNS datagram from {:?}: {:?}", remote, e);
+                    continue;
+                },
+            };
+            let mut inner = inner.borrow_mut();
+            // Only the server we asked, answering the question we asked, can complete a query.
+            let matches = match inner.pending.get(&response.id) {
+                Some(query) => {
+                    remote == server_endpoint(query.server) && response.question == query.name
+                },
+                None => false,
+            };
+            if !matches {
+                debug!("Dropping unexpected DNS response from {:?}", remote);
+                continue;
+            }
+            let query = inner.pending.remove(&response.id).unwrap();
+            inner.num_responses += 1;
+            let _ = query.tx.send(response);
+        }
+    }
+}
+
+impl<RT: Runtime> Drop for DnsResolver<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close DNS socket: {:?}", e);
+        }
+    }
+}
+
+fn server_endpoint(server: Ipv4Addr) -> ipv4::Endpoint {
+    ipv4::Endpoint::new(server, ip::Port::try_from(DNS_PORT).unwrap())
+}
+
+async fn query_udp<RT: Runtime>(
+    rt: &RT,
+    udp: &udp::Peer<RT>,
+    fd: FileDescriptor,
+    inner: &Rc<RefCell<Inner>>,
+    server: Ipv4Addr,
+    name: &str,
+    timeout: Duration,
+) -> Result<DnsMessage, Fail> {
+    let (id, rx) = {
+        let mut inner = inner.borrow_mut();
+        // Random IDs make it harder for an off-path attacker to forge a response.
+        let id = loop {
+            let id: u16 = rt.rng_gen();
+            if !inner.pending.contains_key(&id) {
+                break id;
+            }
+        };
+        let (tx, rx) = channel();
+        let query = PendingQuery {
+            server,
+            name: name.to_string(),
+            tx,
+        };
+        inner.pending.insert(id, query);
+        inner.num_queries += 1;
+        (id, rx)
+    };
+    let query = DnsMessage::query(id, name.to_string()).serialize();
+    let result = match udp.pushto(fd, RT::Buf::from_slice(&query[..]), server_endpoint(server)) {
+        Ok(()) => futures::select! {
+            r = rx.fuse() => r.map_err(|_| Fail::Timeout {}),
+            _ = rt.wait(timeout).fuse() => Err(Fail::Timeout {}),
+        },
+        Err(e) => Err(e),
+    };
+    inner.borrow_mut().pending.remove(&id);
+    result
+}
+
+// Ask again over a TCP connection, where messages are prefixed with their length (RFC 1035
+// 4.2.2).
+async fn query_tcp<RT: Runtime>(
+    rt: &RT,
+    tcp: &tcp::Peer<RT>,
+    server: Ipv4Addr,
+    name: &str,
+    timeout: Duration,
+) -> Result<DnsMessage, Fail> {
+    let fd = tcp.socket();
+    let id: u16 = rt.rng_gen();
+    let exchange = async {
+        tcp.connect(fd, server_endpoint(server)).await?;
+        let query = DnsMessage::query(id, name.to_string()).serialize();
+        let mut buf = vec![0u8; 2];
+        NetworkEndian::write_u16(&mut buf[..], query.len() as u16);
+        buf.extend_from_slice(&query[..]);
+        tcp.push(fd, RT::Buf::from_slice(&buf[..])).await?;
+        let len = tcp.read_exact(fd, 2).await?;
+        let len = NetworkEndian::read_u16(&len[..]) as usize;
+        let response = DnsMessage::parse(&tcp.read_exact(fd, len).await?[..])?;
+        if response.id != id || !response.response || response.question != name {
+            return Err(Fail::Malformed {
+                details: "DNS response doesn't match the query",
+            });
+        }
+        Ok(response)
+    }
+    .fuse();
+    let deadline = rt.wait(timeout).fuse();
+    futures::pin_mut!(exchange);
+    futures::pin_mut!(deadline);
+    let result = futures::select_biased! {
+        r = exchange => r,
+        _ = deadline => Err(Fail::Timeout {}),
+    };
+    if let Err(e) = tcp.close(fd) {
+        debug!("Failed to close DNS connection: {:?}", e);
+    }
+    result
+}
@@ -0,0 +1,298 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    packet::{
+        normalize_name,
+        DnsMessage,
+        DnsRecord,
+        DnsRecordData,
+        DNS_PORT,
+        RCODE_NAME_ERROR,
+    },
+    Options,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ethernet2::frame::ETHERNET2_HEADER_SIZE,
+        ip,
+        ipv4,
+        ipv4::datagram::Ipv4Header,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    sync::Bytes,
+    test_helpers::{
+        self,
+        TestRuntime,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::task::{
+    noop_waker_ref,
+    Context,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    net::Ipv4Addr,
+    pin::Pin,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+const NAME: &str = "www.example.com";
+const ADDR: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+fn answer(name: &str, ttl: u64, data: DnsRecordData) -> DnsRecord {
+    DnsRecord {
+        name: name.to_string(),
+        ttl: Duration::from_secs(ttl),
+        data,
+    }
+}
+
+// Bob playing a DNS server on port 53.
+fn new_server(bob: &mut Engine<TestRuntime>) -> FileDescriptor {
+    let fd = bob.socket(Protocol::Udp);
+    let port = ip::Port::try_from(DNS_PORT).unwrap();
+    bob.bind(fd, ipv4::Endpoint::new(test_helpers::BOB_IPV4, port))
+        .unwrap();
+    fd
+}
+
+// The query alice sent, once bob's server has it, along with where to send the response.
+fn serve(
+    alice: &mut Engine<TestRuntime>,
+    bob: &mut Engine<TestRuntime>,
+    fd: FileDescriptor,
+) -> (ipv4::Endpoint, DnsMessage) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut pop_future = bob.udp_pop(fd);
+    must_let!(let Poll::Ready(Ok((Some(client), buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    (client, DnsMessage::parse(&buf[..]).unwrap())
+}
+
+fn respond(
+    alice: &mut Engine<TestRuntime>,
+    bob: &mut Engine<TestRuntime>,
+    fd: FileDescriptor,
+    client: ipv4::Endpoint,
+    response: &DnsMessage,
+) {
+    let buf = Bytes::from_slice(&response.serialize()[..]);
+    bob.udp_pushto(fd, buf, client).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+}
+
+fn response_to(query: &DnsMessage, answers: Vec<DnsRecord>) -> DnsMessage {
+    let mut response = query.clone();
+    response.response = true;
+    response.answers = answers;
+    response
+}
+
+// Deliver frames back and forth until both sides go quiet.
+fn shuttle(alice: &mut Engine<TestRuntime>, bob: &mut Engine<TestRuntime>) {
+    loop {
+        alice.rt().poll_scheduler();
+        bob.rt().poll_scheduler();
+        if alice.rt().num_outgoing() == 0 && bob.rt().num_outgoing() == 0 {
+            return;
+        }
+        while alice.rt().num_outgoing() > 0 {
+            bob.receive(alice.rt().pop_frame()).unwrap();
+        }
+        while bob.rt().num_outgoing() > 0 {
+            alice.receive(bob.rt().pop_frame()).unwrap();
+        }
+    }
+}
+
+#[test]
+fn message_round_trip() {
+    assert_eq!(normalize_name("WWW.Example.com.").unwrap(), NAME);
+    must_let!(let Err(Fail::Invalid { .. }) = normalize_name(""));
+    must_let!(let Err(Fail::Invalid { .. }) = normalize_name("www..example.com"));
+    must_let!(let Err(Fail::Invalid { .. }) = normalize_name(&"a".repeat(64)));
+
+    let query = DnsMessage::query(0x1234, NAME.to_string());
+    assert_eq!(DnsMessage::parse(&query.serialize()[..]).unwrap(), query);
+
+    // The addresses are found through the CNAME, and live as long as the shortest TTL.
+    let response = response_to(
+        &query,
+        vec![
+            answer(NAME, 300, DnsRecordData::Cname("example.com".to_string())),
+            answer("example.com", 60, DnsRecordData::A(ADDR)),
+            answer("other.com", 600, DnsRecordData::A(Ipv4Addr::LOCALHOST)),
+        ],
+    );
+    let buf = response.serialize();
+    assert_eq!(DnsMessage::parse(&buf[..]).unwrap(), response);
+    assert_eq!(response.addresses(), Some((vec![ADDR], Duration::from_secs(60))));
+    must_let!(let Err(Fail::Malformed { .. }) = DnsMessage::parse(&buf[..(buf.len() - 1)]));
+
+    // An answer whose name points back at the question's (RFC 1035 4.1.4).
+    let mut compressed = query.serialize();
+    NetworkEndian::write_u16(&mut compressed[2..4], 0x8180);
+    NetworkEndian::write_u16(&mut compressed[6..8], 1);
+    compressed.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4]);
+    compressed.extend_from_slice(&ADDR.octets());
+    let response = DnsMessage::parse(&compressed[..]).unwrap();
+    assert_eq!(response.addresses(), Some((vec![ADDR], Duration::from_secs(30))));
+
+    // A pointer to itself loops.
+    let len = compressed.len();
+    compressed[(len - 16)..(len - 14)].copy_from_slice(&[0xc0, (len - 16) as u8]);
+    must_let!(let Err(Fail::Malformed { .. }) = DnsMessage::parse(&compressed[..]));
+}
+
+#[test]
+fn resolve_and_cache() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let fd = new_server(&mut bob);
+    alice
+        .dns_start(Options::new(vec![test_helpers::BOB_IPV4]))
+        .unwrap();
+
+    // Addresses don't need looking up.
+    let mut resolve = Box::pin(alice.resolve_host("192.168.1.3"));
+    must_let!(let Poll::Ready(Ok(addr)) = Future::poll(resolve.as_mut(), &mut ctx));
+    assert_eq!(addr, test_helpers::CARRIE_IPV4);
+
+    let mut resolve = Box::pin(alice.resolve_host("WWW.Example.COM."));
+    assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+    let (client, query) = serve(&mut alice, &mut bob, fd);
+    assert_eq!(query.question, NAME);
+    assert!(query.recursion_desired);
+
+    // A response with the wrong ID is dropped.
+    let localhost = answer(NAME, 60, DnsRecordData::A(Ipv4Addr::LOCALHOST));
+    let mut forged = response_to(&query, vec![localhost]);
+    forged.id = query.id.wrapping_add(1);
+    respond(&mut alice, &mut bob, fd, client, &forged);
+    assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+
+    let response = response_to(&query, vec![answer(NAME, 60, DnsRecordData::A(ADDR))]);
+    respond(&mut alice, &mut bob, fd, client, &response);
+    must_let!(let Poll::Ready(Ok(addr)) = Future::poll(resolve.as_mut(), &mut ctx));
+    assert_eq!(addr, ADDR);
+
+    // The answer is cached for its TTL.
+    let mut resolve = Box::pin(alice.resolve_host(NAME));
+    must_let!(let Poll::Ready(Ok(addr)) = Future::poll(resolve.as_mut(), &mut ctx));
+    assert_eq!(addr, ADDR);
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    now += Duration::from_secs(60);
+    alice.rt().advance_clock(now);
+    let mut resolve = Box::pin(alice.resolve_host(NAME));
+    assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+    let (client, query) = serve(&mut alice, &mut bob, fd);
+    let mut response = response_to(&query, vec![]);
+    response.rcode = RCODE_NAME_ERROR;
+    respond(&mut alice, &mut bob, fd, client, &response);
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(resolve.as_mut(), &mut ctx));
+}
+
+#[test]
+fn retry_then_give_up() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let options = Options::new(vec![test_helpers::BOB_IPV4, test_helpers::CARRIE_IPV4])
+        .request_timeout(Duration::from_secs(1))
+        .attempts(2);
+    alice.dns_start(options).unwrap();
+
+    // Each server is asked in turn, with the wait doubling in the second round.
+    let mut resolve = Box::pin(alice.resolve_host(NAME));
+    let rounds = [
+        (test_helpers::BOB_IPV4, 1),
+        (test_helpers::CARRIE_IPV4, 1),
+        (test_helpers::BOB_IPV4, 2),
+        (test_helpers::CARRIE_IPV4, 2),
+    ];
+    for &(server, wait) in &rounds {
+        assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+        alice.rt().poll_scheduler();
+        let mut packet = alice.rt().pop_frame();
+        packet.adjust(ETHERNET2_HEADER_SIZE);
+        let (ipv4_hdr, _) = Ipv4Header::parse(packet).unwrap();
+        assert_eq!(ipv4_hdr.dst_addr, server);
+        assert_eq!(alice.rt().num_outgoing(), 0);
+        now += Duration::from_secs(wait);
+        alice.rt().advance_clock(now);
+    }
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(resolve.as_mut(), &mut ctx));
+}
+
+#[test]
+fn truncated_response_falls_back_to_tcp() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let fd = new_server(&mut bob);
+    let listen_addr =
+        ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(DNS_PORT).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    alice
+        .dns_start(Options::new(vec![test_helpers::BOB_IPV4]))
+        .unwrap();
+
+    let mut resolve = Box::pin(alice.resolve_host(NAME));
+    assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+    let (client, query) = serve(&mut alice, &mut bob, fd);
+    let mut response = response_to(&query, vec![]);
+    response.truncated = true;
+    respond(&mut alice, &mut bob, fd, client, &response);
+
+    // Alice connects to the same server and asks again.
+    assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+    shuttle(&mut alice, &mut bob);
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    assert!(Future::poll(resolve.as_mut(), &mut ctx).is_pending());
+    shuttle(&mut alice, &mut bob);
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(NetworkEndian::read_u16(&buf[..2]) as usize, buf.len() - 2);
+    let query = DnsMessage::parse(&buf[2..]).unwrap();
+    assert_eq!(query.question, NAME);
+
+    let response = response_to(&query, vec![answer(NAME, 60, DnsRecordData::A(ADDR))]);
+    let mut framed = vec![0u8; 2];
+    let serialized = response.serialize();
+    NetworkEndian::write_u16(&mut framed[..], serialized.len() as u16);
+    framed.extend_from_slice(&serialized[..]);
+    let mut push_future = bob.tcp_push(bob_fd, Bytes::from_slice(&framed[..]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    shuttle(&mut alice, &mut bob);
+    must_let!(let Poll::Ready(Ok(addr)) = Future::poll(resolve.as_mut(), &mut ctx));
+    assert_eq!(addr, ADDR);
+}
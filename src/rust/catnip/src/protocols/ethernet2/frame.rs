@@ -15,12 +15,15 @@ use std::convert::{
 
 pub const MIN_PAYLOAD_SIZE: usize = 46;
 pub const ETHERNET2_HEADER_SIZE: usize = 14;
+/// The shortest frame Ethernet carries, not counting the FCS.
+pub const MIN_FRAME_SIZE: usize = ETHERNET2_HEADER_SIZE + MIN_PAYLOAD_SIZE;
 
 #[repr(u16)]
 #[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
 pub enum EtherType2 {
     Arp = 0x806,
     Ipv4 = 0x800,
+    Ipv6 = 0x86DD,
 }
 
 impl TryFrom<u16> for EtherType2 {
@@ -0,0 +1,205 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::Ethernet2Header,
+        ipv4::datagram::Ipv4Header,
+    },
+    runtime::{
+        PacketBuf,
+        RuntimeBuf,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+
+pub const GRE_HEADER_SIZE: usize = 4;
+
+const FLAG_CHECKSUM: u8 = 0x80;
+const FLAG_KEY: u8 = 0x20;
+const FLAG_SEQUENCE: u8 = 0x10;
+
+const PROTOCOL_IPV4: u16 = 0x0800;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GreHeader {
+    pub key: Option<u32>,
+    // We never send checksums or sequence numbers. When parsing, checksums are verified and
+    // sequence numbers ignored.
+}
+
+impl GreHeader {
+    pub fn compute_size(&self) -> usize {
+        GRE_HEADER_SIZE + if self.key.is_some() { 4 } else { 0 }
+    }
+
+    pub fn parse<T: RuntimeBuf>(mut buf: T) -> Result<(Self, T), Fail> {
+        if buf.len() < GRE_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "GRE header too small",
+            });
+        }
+        let flags = buf[0];
+        // Anything else in the first 13 bits is either reserved or RFC 1701 source routing.
+        if flags & !(FLAG_CHECKSUM | FLAG_KEY | FLAG_SEQUENCE) != 0 || buf[1] & 0xf8 != 0 {
+            return Err(Fail::Unsupported {
+                details: "Unsupported GRE flags",
+            });
+        }
+        if buf[1] & 0x7 != 0 {
+            return Err(Fail::Unsupported {
+                details: "Unsupported GRE version",
+            });
+        }
+        if NetworkEndian::read_u16(&buf[2..4]) != PROTOCOL_IPV4 {
+            return Err(Fail::Unsupported {
+                details: "Unsupported GRE protocol type",
+            });
+        }
+
+        let mut header_size = GRE_HEADER_SIZE;
+        if flags & FLAG_CHECKSUM != 0 {
+            header_size += 4;
+        }
+        let key_offset = header_size;
+        if flags & FLAG_KEY != 0 {
+            header_size += 4;
+        }
+        if flags & FLAG_SEQUENCE != 0 {
+            header_size += 4;
+        }
+        if buf.len() < header_size {
+            return Err(Fail::Malformed {
+                details: "GRE header too small",
+            });
+        }
+        // The checksum covers the GRE header and payload, including the checksum itself.
+        if flags & FLAG_CHECKSUM != 0 && ones_complement_sum(&buf[..]) != 0xffff {
+            return Err(Fail::Malformed {
+                details: "Invalid GRE checksum",
+            });
+        }
+        let key = if flags & FLAG_KEY != 0 {
+            Some(NetworkEndian::read_u32(&buf[key_offset..]))
+        } else {
+            None
+        };
+        buf.adjust(header_size);
+        Ok((Self { key }, buf))
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) {
+        assert_eq!(buf.len(), self.compute_size());
+        buf[0] = if self.key.is_some() { FLAG_KEY } else { 0 };
+        buf[1] = 0;
+        NetworkEndian::write_u16(&mut buf[2..4], PROTOCOL_IPV4);
+        if let Some(key) = self.key {
+            NetworkEndian::write_u32(&mut buf[4..8], key);
+        }
+    }
+}
+
+fn ones_complement_sum(buf: &[u8]) -> u16 {
+    let mut state = 0u32;
+    for chunk in buf.chunks(2) {
+        let word = if chunk.len() == 2 {
+            NetworkEndian::read_u16(chunk)
+        } else {
+            (chunk[0] as u16) << 8
+        };
+        state += word as u32;
+    }
+    while state > 0xffff {
+        state = (state & 0xffff) + (state >> 16);
+    }
+    state as u16
+}
+
+/// An IPv4 packet wrapped in GRE. The inner packet is carried unmodified.
+pub struct GreDatagram<T: RuntimeBuf> {
+    pub ethernet2_hdr: Ethernet2Header,
+    pub ipv4_hdr: Ipv4Header,
+    pub gre_hdr: GreHeader,
+    pub inner: T,
+}
+
+impl<T: RuntimeBuf> PacketBuf<T> for GreDatagram<T> {
+    fn header_size(&self) -> usize {
+        self.ethernet2_hdr.compute_size()
+            + self.ipv4_hdr.compute_size()
+            + self.gre_hdr.compute_size()
+    }
+
+    fn body_size(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn write_header(&self, buf: &mut [u8]) {
+        let eth_hdr_size = self.ethernet2_hdr.compute_size();
+        let ipv4_hdr_size = self.ipv4_hdr.compute_size();
+        let gre_hdr_size = self.gre_hdr.compute_size();
+        let mut cur_pos = 0;
+
+        self.ethernet2_hdr
+            .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
+        cur_pos += eth_hdr_size;
+
+        let ipv4_payload_len = gre_hdr_size + self.inner.len();
+        self.ipv4_hdr.serialize(
+            &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
+            ipv4_payload_len,
+        );
+        cur_pos += ipv4_hdr_size;
+
+        self.gre_hdr
+            .serialize(&mut buf[cur_pos..(cur_pos + gre_hdr_size)]);
+    }
+
+    fn take_body(self) -> Option<T> {
+        Some(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GreHeader;
+    use crate::{
+        fail::Fail,
+        runtime::RuntimeBuf,
+        sync::Bytes,
+    };
+    use must_let::must_let;
+
+    #[test]
+    fn parse_header() {
+        let header = GreHeader {
+            key: Some(0xdeadbeef),
+        };
+        let mut buf = vec![0u8; header.compute_size()];
+        header.serialize(&mut buf[..]);
+        buf.extend_from_slice(&[1, 2, 3]);
+        let (parsed, payload) = GreHeader::parse(Bytes::from_slice(&buf[..])).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(&payload[..], &[1, 2, 3]);
+
+        // Checksum and sequence number present: C, K and S set.
+        let mut buf = vec![0xb0, 0x00, 0x08, 0x00];
+        buf.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 1, 0xaa]);
+        let sum = super::ones_complement_sum(&buf[..]);
+        buf[4..6].copy_from_slice(&(!sum).to_be_bytes());
+        let (parsed, payload) = GreHeader::parse(Bytes::from_slice(&buf[..])).unwrap();
+        assert_eq!(parsed.key, Some(7));
+        assert_eq!(&payload[..], &[0xaa]);
+
+        buf[16] = 0xbb;
+        must_let!(let Err(Fail::Malformed { .. }) = GreHeader::parse(Bytes::from_slice(&buf[..])));
+
+        // Version 1 is PPTP's enhanced GRE.
+        let buf = [0x00, 0x01, 0x08, 0x00];
+        must_let!(let Err(Fail::Unsupported { .. }) = GreHeader::parse(Bytes::from_slice(&buf[..])));
+    }
+}
@@ -0,0 +1,24 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Point-to-point GRE (RFC 2784, with RFC 2890 keys) tunnel. While a tunnel is open, every IPv4
+//! packet the engine sends to the tunnel's remote endpoint is wrapped in an outer IPv4 + GRE
+//! header on its way out, and GRE packets from the remote are unwrapped and fed back into the
+//! IPv4 receive path. Encapsulation adds `tunnel_overhead()` bytes, which the MSS doesn't account
+//! for.
+
+mod datagram;
+mod tunnel;
+
+#[cfg(test)]
+mod tests;
+
+pub use datagram::{
+    GreDatagram,
+    GreHeader,
+};
+pub use tunnel::{
+    tunnel_overhead,
+    Options,
+    Tunnel,
+};
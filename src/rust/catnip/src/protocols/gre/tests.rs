@@ -0,0 +1,73 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::Options;
+use crate::{
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::ETHERNET2_HEADER_SIZE,
+        ip,
+        ipv4,
+        ipv4::datagram::Ipv4Protocol2,
+    },
+    test_helpers,
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::Instant,
+};
+
+const PROTOCOL_OFFSET: usize = ETHERNET2_HEADER_SIZE + 9;
+
+#[test]
+fn tcp_over_gre() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.gre_open(Options::new(test_helpers::BOB_IPV4).key(42));
+    bob.gre_open(Options::new(test_helpers::ALICE_IPV4).key(42));
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+
+    // Every segment of the handshake crosses the wire encapsulated.
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    assert_eq!(syn[PROTOCOL_OFFSET], Ipv4Protocol2::Gre as u8);
+    bob.receive(syn).unwrap();
+
+    bob.rt().poll_scheduler();
+    let syn_ack = bob.rt().pop_frame();
+    assert_eq!(syn_ack[PROTOCOL_OFFSET], Ipv4Protocol2::Gre as u8);
+    alice.receive(syn_ack).unwrap();
+
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Without the tunnel, Bob doesn't accept GRE.
+    bob.gre_close();
+    let alice_fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    must_let!(let Err(Fail::Unsupported { .. }) = bob.receive(alice.rt().pop_frame()));
+}
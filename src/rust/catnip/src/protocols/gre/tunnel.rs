@@ -0,0 +1,125 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::datagram::{
+    GreDatagram,
+    GreHeader,
+};
+use crate::{
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::{
+            EtherType2,
+            Ethernet2Header,
+            ETHERNET2_HEADER_SIZE,
+        },
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+            IPV4_HEADER_SIZE,
+        },
+    },
+    runtime::RuntimeBuf,
+};
+use std::net::Ipv4Addr;
+
+const DEFAULT_TIME_TO_LIVE: u8 = 64;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub remote: Ipv4Addr,
+    /// RFC 2890 key sent with every packet. Inbound packets must carry the same key.
+    pub key: Option<u32>,
+    /// TTL of the outer header, so encapsulated packets survive routers between the endpoints.
+    pub time_to_live: u8,
+}
+
+impl Options {
+    pub fn new(remote: Ipv4Addr) -> Self {
+        Self {
+            remote,
+            key: None,
+            time_to_live: DEFAULT_TIME_TO_LIVE,
+        }
+    }
+
+    pub fn key(mut self, value: u32) -> Self {
+        self.key = Some(value);
+        self
+    }
+
+    pub fn time_to_live(mut self, value: u8) -> Self {
+        assert!(value > 0);
+        self.time_to_live = value;
+        self
+    }
+}
+
+/// Bytes encapsulation adds to every tunneled packet.
+pub fn tunnel_overhead(options: &Options) -> usize {
+    let gre_hdr = GreHeader { key: options.key };
+    IPV4_HEADER_SIZE + gre_hdr.compute_size()
+}
+
+#[derive(Clone, Debug)]
+pub struct Tunnel {
+    options: Options,
+}
+
+impl Tunnel {
+    pub fn new(options: Options) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Whether a serialized Ethernet frame is an IPv4 packet the tunnel should carry.
+    pub fn carries(&self, frame: &[u8]) -> bool {
+        if frame.len() < ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE {
+            return false;
+        }
+        if frame[12..14] != (EtherType2::Ipv4 as u16).to_be_bytes() {
+            return false;
+        }
+        let ip = &frame[ETHERNET2_HEADER_SIZE..];
+        ip[16..20] == self.options.remote.octets() && ip[9] != Ipv4Protocol2::Gre as u8
+    }
+
+    /// Wrap the IPv4 packet in `frame`, which `carries` accepted. The frame's Ethernet header is
+    /// reused for the outer packet since it's already addressed to the remote's link address.
+    pub fn encapsulate<T: RuntimeBuf>(
+        &self,
+        local_addr: Ipv4Addr,
+        frame: &[u8],
+    ) -> Result<GreDatagram<T>, Fail> {
+        let (ethernet2_hdr, inner) = Ethernet2Header::parse(T::from_slice(frame))?;
+        let mut ipv4_hdr = Ipv4Header::new(local_addr, self.options.remote, Ipv4Protocol2::Gre);
+        ipv4_hdr.time_to_live = self.options.time_to_live;
+        Ok(GreDatagram {
+            ethernet2_hdr,
+            ipv4_hdr,
+            gre_hdr: GreHeader {
+                key: self.options.key,
+            },
+            inner,
+        })
+    }
+
+    /// Unwrap a GRE packet, returning the inner IPv4 packet.
+    pub fn decapsulate<T: RuntimeBuf>(&self, header: &Ipv4Header, payload: T) -> Result<T, Fail> {
+        if header.src_addr != self.options.remote {
+            return Err(Fail::Ignored {
+                details: "GRE packet from unknown endpoint",
+            });
+        }
+        let (gre_hdr, inner) = GreHeader::parse(payload)?;
+        if gre_hdr.key != self.options.key {
+            return Err(Fail::Ignored {
+                details: "GRE key mismatch",
+            });
+        }
+        Ok(inner)
+    }
+}
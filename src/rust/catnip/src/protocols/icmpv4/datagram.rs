@@ -1,6 +1,7 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 use std::marker::PhantomData;
+use arrayvec::ArrayVec;
 use crate::{
     runtime::RuntimeBuf,
     fail::Fail,
@@ -26,7 +27,8 @@ const MAX_ICMPV4_DATAGRAM_SIZE: usize = 576;
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Icmpv4Type2 {
     EchoReply { id: u16, seq_num: u16 },
-    DestinationUnreachable,
+    /// `next_hop_mtu` is only set for fragmentation needed (RFC 1191).
+    DestinationUnreachable { next_hop_mtu: u16 },
     SourceQuench,
     RedirectMessage,
     EchoRequest { id: u16, seq_num: u16 },
@@ -47,7 +49,10 @@ impl Icmpv4Type2 {
                 let seq_num = NetworkEndian::read_u16(&rest_of_header[2..4]);
                 Ok(EchoReply { id, seq_num })
             },
-            3 => Ok(DestinationUnreachable),
+            3 => {
+                let next_hop_mtu = NetworkEndian::read_u16(&rest_of_header[2..4]);
+                Ok(DestinationUnreachable { next_hop_mtu })
+            },
             4 => Ok(SourceQuench),
             5 => Ok(RedirectMessage),
             8 => {
@@ -70,11 +75,15 @@ impl Icmpv4Type2 {
     fn serialize(&self) -> (u8, [u8; 4]) {
         use Icmpv4Type2::*;
         match self {
-            EchoReply { .. } => (0, [0u8; 4]),
-            DestinationUnreachable => (3, [0u8; 4]),
+            EchoReply { id, seq_num } => (0, echo_rest_of_header(*id, *seq_num)),
+            DestinationUnreachable { next_hop_mtu } => {
+                let mut buf = [0u8; 4];
+                NetworkEndian::write_u16(&mut buf[2..4], *next_hop_mtu);
+                (3, buf)
+            },
             SourceQuench => (4, [0u8; 4]),
             RedirectMessage => (5, [0u8; 4]),
-            EchoRequest { .. } => (8, [0u8; 4]),
+            EchoRequest { id, seq_num } => (8, echo_rest_of_header(*id, *seq_num)),
             RouterAdvertisement => (9, [0u8; 4]),
             RouterSolicitation => (10, [0u8; 4]),
             TimeExceeded => (11, [0u8; 4]),
@@ -85,20 +94,79 @@ impl Icmpv4Type2 {
     }
 }
 
+fn echo_rest_of_header(id: u16, seq_num: u16) -> [u8; 4] {
+    let mut buf = [0u8; 4];
+    NetworkEndian::write_u16(&mut buf[0..2], id);
+    NetworkEndian::write_u16(&mut buf[2..4], seq_num);
+    buf
+}
+
 pub struct Icmpv4Message<T> {
     pub ethernet2_hdr: Ethernet2Header,
     pub ipv4_hdr: Ipv4Header,
     pub icmpv4_hdr: Icmpv4Header,
-    // TODO: Add a body enum when we need it.
+    /// Everything after the ICMP header, e.g. an echo's payload.
+    pub data: T,
+}
+
+impl<T: RuntimeBuf> PacketBuf<T> for Icmpv4Message<T> {
+    fn header_size(&self) -> usize {
+        self.ethernet2_hdr.compute_size()
+            + self.ipv4_hdr.compute_size()
+            + self.icmpv4_hdr.compute_size()
+    }
+
+    fn body_size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write_header(&self, buf: &mut [u8]) {
+        let eth_hdr_size = self.ethernet2_hdr.compute_size();
+        let ipv4_hdr_size = self.ipv4_hdr.compute_size();
+        let icmpv4_hdr_size = self.icmpv4_hdr.compute_size();
+        let mut cur_pos = 0;
+
+        self.ethernet2_hdr
+            .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
+        cur_pos += eth_hdr_size;
+
+        let ipv4_payload_len = icmpv4_hdr_size + self.data.len();
+        self.ipv4_hdr.serialize(
+            &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
+            ipv4_payload_len,
+        );
+        cur_pos += ipv4_hdr_size;
+
+        self.icmpv4_hdr.serialize(
+            &mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)],
+            &self.data[..],
+        );
+    }
+
+    fn take_body(self) -> Option<T> {
+        Some(self.data)
+    }
+}
+
+/// An ICMP error quotes the offending datagram's IPv4 header and the first 8 bytes of its payload.
+pub const ICMPV4_ERROR_CONTEXT_SIZE: usize = 28;
+
+/// An ICMP error message (e.g. destination unreachable) along with its context.
+pub struct Icmpv4Error<T> {
+    pub ethernet2_hdr: Ethernet2Header,
+    pub ipv4_hdr: Ipv4Header,
+    pub icmpv4_hdr: Icmpv4Header,
+    pub context: ArrayVec<[u8; ICMPV4_ERROR_CONTEXT_SIZE]>,
 
     pub _body_marker: PhantomData<T>,
 }
 
-impl<T> PacketBuf<T> for Icmpv4Message<T> {
+impl<T> PacketBuf<T> for Icmpv4Error<T> {
     fn header_size(&self) -> usize {
         self.ethernet2_hdr.compute_size()
             + self.ipv4_hdr.compute_size()
             + self.icmpv4_hdr.compute_size()
+            + self.context.len()
     }
 
     fn body_size(&self) -> usize {
@@ -115,15 +183,19 @@ impl<T> PacketBuf<T> for Icmpv4Message<T> {
             .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
         cur_pos += eth_hdr_size;
 
-        let ipv4_payload_len = icmpv4_hdr_size;
         self.ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
-            ipv4_payload_len,
+            icmpv4_hdr_size + self.context.len(),
         );
         cur_pos += ipv4_hdr_size;
 
-        self.icmpv4_hdr
-            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)]);
+        self.icmpv4_hdr.serialize(
+            &mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)],
+            &self.context[..],
+        );
+        cur_pos += icmpv4_hdr_size;
+
+        buf[cur_pos..(cur_pos + self.context.len())].copy_from_slice(&self.context[..]);
     }
 
     fn take_body(self) -> Option<T> {
@@ -133,6 +205,9 @@ impl<T> PacketBuf<T> for Icmpv4Message<T> {
 
 pub const ICMPV4_HEADER_SIZE: usize = 8;
 
+/// `Fail::Malformed` details for a message whose checksum is wrong.
+pub const CHECKSUM_MISMATCH: &str = "ICMPv4 checksum mismatch";
+
 #[derive(Copy, Clone, Debug)]
 pub struct Icmpv4Header {
     pub icmpv4_type: Icmpv4Type2,
@@ -145,7 +220,7 @@ impl Icmpv4Header {
         ICMPV4_HEADER_SIZE
     }
 
-    pub fn parse<T: RuntimeBuf>(mut buf: T) -> Result<(Self, T), Fail> {
+    pub fn parse<T: RuntimeBuf>(mut buf: T, rx_checksum_offload: bool) -> Result<(Self, T), Fail> {
         if buf.len() < ICMPV4_HEADER_SIZE {
             return Err(Fail::Malformed {
                 details: "ICMPv4 datagram too small for header",
@@ -155,11 +230,13 @@ impl Icmpv4Header {
 
         let type_byte = hdr_buf[0];
         let code = hdr_buf[1];
-        let checksum = NetworkEndian::read_u16(&hdr_buf[2..4]);
-        if checksum != icmpv4_checksum(hdr_buf, &buf[ICMPV4_HEADER_SIZE..]) {
-            return Err(Fail::Malformed {
-                details: "ICMPv4 checksum mismatch",
-            });
+        if !rx_checksum_offload {
+            let checksum = NetworkEndian::read_u16(&hdr_buf[2..4]);
+            if checksum != icmpv4_checksum(hdr_buf, &buf[ICMPV4_HEADER_SIZE..]) {
+                return Err(Fail::Malformed {
+                    details: CHECKSUM_MISMATCH,
+                });
+            }
         }
         let rest_of_header: &[u8; 4] = hdr_buf[4..8].try_into().unwrap();
         let icmpv4_type = Icmpv4Type2::parse(type_byte, rest_of_header)?;
@@ -168,7 +245,8 @@ impl Icmpv4Header {
         Ok((Self { icmpv4_type, code }, buf))
     }
 
-    pub fn serialize(&self, buf: &mut [u8]) {
+    /// `body` is everything that follows the header, which the checksum covers.
+    pub fn serialize(&self, buf: &mut [u8], body: &[u8]) {
         let buf: &mut [u8; ICMPV4_HEADER_SIZE] =
             (&mut buf[..ICMPV4_HEADER_SIZE]).try_into().unwrap();
         let (type_byte, rest_of_header) = self.icmpv4_type.serialize();
@@ -176,7 +254,7 @@ impl Icmpv4Header {
         buf[1] = self.code;
         // Skip the checksum for now.
         buf[4..8].copy_from_slice(&rest_of_header[..]);
-        let checksum = icmpv4_checksum(buf, &[]);
+        let checksum = icmpv4_checksum(buf, body);
         NetworkEndian::write_u16(&mut buf[2..4], checksum);
     }
 }
@@ -0,0 +1,210 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    datagram::{
+        Icmpv4Error,
+        Icmpv4Header,
+        Icmpv4Type2,
+        ICMPV4_ERROR_CONTEXT_SIZE,
+    },
+    ECHO_REQUEST,
+};
+use crate::{
+    fail::Fail,
+    protocols::{
+        arp,
+        ethernet2::frame::EtherType2,
+        ipv4::{
+            datagram::{
+                Ipv4Header,
+                Ipv4Protocol2,
+                IPV4_HEADER_SIZE,
+            },
+            Egress,
+        },
+        packet::PacketBuilder,
+    },
+    runtime::Runtime,
+};
+use arrayvec::ArrayVec;
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    cell::RefCell,
+    marker::PhantomData,
+    net::Ipv4Addr,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// A fragmentation needed error (RFC 1191): a router couldn't forward a datagram we sent, with
+/// DF set, over a link whose MTU is `next_hop_mtu`. Routers that predate RFC 1191 leave that zero.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FragmentationNeeded {
+    pub next_hop_mtu: u16,
+    /// From the quoted header of the datagram that didn't fit.
+    pub src_addr: Ipv4Addr,
+    pub dst_addr: Ipv4Addr,
+    pub protocol: u8,
+    pub total_len: u16,
+    /// The start of its payload: for TCP, the ports and sequence number.
+    pub payload: [u8; 8],
+}
+
+impl FragmentationNeeded {
+    /// Parse the datagram quoted after the ICMP header.
+    pub fn parse(next_hop_mtu: u16, quoted: &[u8]) -> Result<Self, Fail> {
+        if quoted.len() < IPV4_HEADER_SIZE || quoted[0] >> 4 != 4 {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error doesn't quote an IPv4 header",
+            });
+        }
+        let ihl = (quoted[0] & 0xf) as usize * 4;
+        if ihl < IPV4_HEADER_SIZE || quoted.len() < ihl + 8 {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 error quotes too little of the datagram",
+            });
+        }
+        let mut payload = [0u8; 8];
+        payload.copy_from_slice(&quoted[ihl..(ihl + 8)]);
+        Ok(Self {
+            next_hop_mtu,
+            src_addr: Ipv4Addr::new(quoted[12], quoted[13], quoted[14], quoted[15]),
+            dst_addr: Ipv4Addr::new(quoted[16], quoted[17], quoted[18], quoted[19]),
+            protocol: quoted[9],
+            total_len: NetworkEndian::read_u16(&quoted[2..4]),
+            payload,
+        })
+    }
+}
+
+struct Inner {
+    // The start of the current one-second rate limiting window, and errors sent in it.
+    window: (Instant, u32),
+    num_suppressed: u64,
+}
+
+/// Sends ICMPv4 errors about datagrams the stack couldn't deliver, on behalf of every protocol
+/// that rejects them. Errors are best effort: they're sent only if the offender's sender is
+/// already in the ARP cache, within `Icmpv4Options::error_rate_limit`, and never about another
+/// ICMP error or a datagram that wasn't addressed to a single host. Nothing is reported back to
+/// the caller, which is usually dropping the datagram for a reason of its own.
+pub struct ErrorSender<RT: Runtime> {
+    rt: RT,
+    arp: arp::Peer<RT>,
+    egress: Egress<RT>,
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl<RT: Runtime> Clone for ErrorSender<RT> {
+    fn clone(&self) -> Self {
+        Self {
+            rt: self.rt.clone(),
+            arp: self.arp.clone(),
+            egress: self.egress.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+fn is_unicast(addr: Ipv4Addr) -> bool {
+    !addr.is_unspecified() && !addr.is_broadcast() && !addr.is_multicast()
+}
+
+impl<RT: Runtime> ErrorSender<RT> {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, egress: Egress<RT>) -> Self {
+        let inner = Inner {
+            window: (rt.now(), 0),
+            num_suppressed: 0,
+        };
+        Self {
+            rt,
+            arp,
+            egress,
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Errors the rate limit held back.
+    pub fn num_suppressed(&self) -> u64 {
+        self.inner.borrow().num_suppressed
+    }
+
+    /// Tell the sender of `ipv4_header`/`payload` that it couldn't be delivered, quoting its
+    /// header and the start of `payload`.
+    pub fn destination_unreachable(&self, code: u8, ipv4_header: &Ipv4Header, payload: &[u8]) {
+        let icmpv4_type = Icmpv4Type2::DestinationUnreachable { next_hop_mtu: 0 };
+        self.send(icmpv4_type, code, ipv4_header, payload);
+    }
+
+    /// Tell the sender of `ipv4_header`/`payload` that it was dropped after taking too long, e.g.
+    /// waiting for the rest of its fragments, quoting it as `destination_unreachable` does.
+    pub fn time_exceeded(&self, code: u8, ipv4_header: &Ipv4Header, payload: &[u8]) {
+        self.send(Icmpv4Type2::TimeExceeded, code, ipv4_header, payload);
+    }
+
+    fn send(&self, icmpv4_type: Icmpv4Type2, code: u8, ipv4_header: &Ipv4Header, payload: &[u8]) {
+        if ipv4_header.protocol == Ipv4Protocol2::Icmpv4 && payload.get(0) != Some(&ECHO_REQUEST) {
+            return;
+        }
+        if !is_unicast(ipv4_header.src_addr) || !is_unicast(ipv4_header.dst_addr) {
+            return;
+        }
+        if !self.permitted() {
+            debug!("Rate limited ICMPv4 error to {}", ipv4_header.src_addr);
+            return;
+        }
+        let dst_link_addr = match self.arp.try_query(ipv4_header.src_addr) {
+            Some(addr) => addr,
+            None => {
+                warn!(
+                    "Not sending ICMPv4 error to {}: not in ARP cache",
+                    ipv4_header.src_addr
+                );
+                return;
+            },
+        };
+
+        let quoted = payload
+            .len()
+            .min(ICMPV4_ERROR_CONTEXT_SIZE - IPV4_HEADER_SIZE);
+        let mut context = ArrayVec::new();
+        let mut ipv4_hdr_buf = [0u8; IPV4_HEADER_SIZE];
+        ipv4_header.serialize(&mut ipv4_hdr_buf, payload.len());
+        context.extend(ipv4_hdr_buf.iter().cloned());
+        context.extend(payload[..quoted].iter().cloned());
+        let builder = PacketBuilder::new(&self.rt, dst_link_addr, ipv4_header.src_addr);
+        let msg = Icmpv4Error {
+            ethernet2_hdr: builder.ethernet2(EtherType2::Ipv4),
+            ipv4_hdr: builder.ipv4(Ipv4Protocol2::Icmpv4),
+            icmpv4_hdr: Icmpv4Header { icmpv4_type, code },
+            context,
+            _body_marker: PhantomData,
+        };
+        self.egress.transmit(msg);
+    }
+
+    fn permitted(&self) -> bool {
+        let per_second = match self.rt.icmpv4_options().error_rate_limit {
+            Some(n) => n,
+            None => return true,
+        };
+        let now = self.rt.now();
+        let mut inner = self.inner.borrow_mut();
+        if now >= inner.window.0 + Duration::from_secs(1) {
+            inner.window = (now, 0);
+        }
+        if inner.window.1 >= per_second {
+            inner.num_suppressed += 1;
+            return false;
+        }
+        inner.window.1 += 1;
+        true
+    }
+}
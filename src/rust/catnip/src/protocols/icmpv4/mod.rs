@@ -1,7 +1,36 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-mod datagram;
+pub(crate) mod datagram;
+mod error;
+mod monitor;
+mod options;
 mod peer;
 
+#[cfg(test)]
+mod tests;
+
+pub use datagram::CHECKSUM_MISMATCH;
+pub use error::{
+    ErrorSender,
+    FragmentationNeeded,
+};
+pub use monitor::{
+    MonitorOptions,
+    PathMonitor,
+    PathState,
+    PathStatus,
+};
+pub use options::Icmpv4Options as Options;
 pub use peer::Icmpv4Peer as Peer;
+
+/// ICMP type byte of an echo request.
+pub const ECHO_REQUEST: u8 = 8;
+
+/// Destination unreachable codes.
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+pub const CODE_ADMIN_PROHIBITED: u8 = 13;
+
+/// Time exceeded code for a datagram whose fragments didn't all arrive in time.
+pub const CODE_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
@@ -0,0 +1,236 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Reachability monitoring for a fixed set of destinations, e.g. the gateways of a multi-homed
+//! setup. Each destination is pinged once per interval; a run of lost probes marks it down and a
+//! run of answered ones marks it up again, and every such change is reported as an `Event`.
+//! Current state and RTTs are in `Stats::paths`.
+
+use super::peer::Icmpv4Peer;
+use crate::{
+    event::{
+        Event,
+        EventQueue,
+    },
+    runtime::Runtime,
+    scheduler::SchedulerHandle,
+};
+use std::{
+    cell::RefCell,
+    net::Ipv4Addr,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Clone, Debug)]
+pub struct MonitorOptions {
+    pub destinations: Vec<Ipv4Addr>,
+    /// Time between probes to each destination.
+    pub interval: Duration,
+    /// How long a probe waits for its reply before it counts as lost.
+    pub timeout: Duration,
+    /// Consecutive lost probes that take a destination down.
+    pub down_after: u32,
+    /// Consecutive answered probes that bring it back up.
+    pub up_after: u32,
+}
+
+impl MonitorOptions {
+    pub fn new(destinations: Vec<Ipv4Addr>) -> Self {
+        MonitorOptions {
+            destinations,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            down_after: 3,
+            up_after: 1,
+        }
+    }
+
+    pub fn interval(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.interval = value;
+        self
+    }
+
+    pub fn timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.timeout = value;
+        self
+    }
+
+    pub fn down_after(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.down_after = value;
+        self
+    }
+
+    pub fn up_after(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.up_after = value;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PathState {
+    /// Not enough probes have completed to tell yet.
+    Unknown,
+    Up,
+    Down,
+}
+
+/// What the monitor knows about one destination.
+#[derive(Clone, Debug)]
+pub struct PathStatus {
+    pub destination: Ipv4Addr,
+    pub state: PathState,
+    /// When `state` last changed, or when monitoring started.
+    pub since: Instant,
+    pub last_rtt: Option<Duration>,
+    /// RTT smoothed over answered probes, with the same gain as TCP's (1/8).
+    pub srtt: Option<Duration>,
+    pub probes_sent: u64,
+    pub probes_lost: u64,
+    pub consecutive_lost: u32,
+    pub consecutive_answered: u32,
+}
+
+impl PathStatus {
+    fn new(destination: Ipv4Addr, now: Instant) -> Self {
+        Self {
+            destination,
+            state: PathState::Unknown,
+            since: now,
+            last_rtt: None,
+            srtt: None,
+            probes_sent: 0,
+            probes_lost: 0,
+            consecutive_lost: 0,
+            consecutive_answered: 0,
+        }
+    }
+
+    /// Account for a completed probe, which was answered after `rtt` or lost.
+    fn record(
+        &mut self,
+        rtt: Option<Duration>,
+        now: Instant,
+        options: &MonitorOptions,
+    ) -> Option<Event> {
+        self.probes_sent += 1;
+        match rtt {
+            Some(rtt) => {
+                self.last_rtt = Some(rtt);
+                self.srtt = Some(match self.srtt {
+                    Some(srtt) => (srtt * 7 + rtt) / 8,
+                    None => rtt,
+                });
+                self.consecutive_lost = 0;
+                self.consecutive_answered += 1;
+                if self.state != PathState::Up && self.consecutive_answered >= options.up_after {
+                    self.state = PathState::Up;
+                    self.since = now;
+                    return Some(Event::PathUp {
+                        destination: self.destination,
+                        rtt,
+                    });
+                }
+            },
+            None => {
+                self.probes_lost += 1;
+                self.consecutive_answered = 0;
+                self.consecutive_lost += 1;
+                if self.state != PathState::Down && self.consecutive_lost >= options.down_after {
+                    self.state = PathState::Down;
+                    self.since = now;
+                    return Some(Event::PathDown {
+                        destination: self.destination,
+                    });
+                }
+            },
+        }
+        None
+    }
+}
+
+pub struct PathMonitor {
+    statuses: Rc<RefCell<Vec<PathStatus>>>,
+
+    #[allow(unused)]
+    handles: Vec<SchedulerHandle>,
+}
+
+impl PathMonitor {
+    pub fn new<RT: Runtime>(
+        rt: RT,
+        icmpv4: Icmpv4Peer<RT>,
+        events: EventQueue,
+        options: MonitorOptions,
+    ) -> Self {
+        let now = rt.now();
+        let statuses: Vec<_> = options
+            .destinations
+            .iter()
+            .map(|&d| PathStatus::new(d, now))
+            .collect();
+        let statuses = Rc::new(RefCell::new(statuses));
+        let options = Rc::new(options);
+        let handles = (0..options.destinations.len())
+            .map(|i| {
+                let future = Self::probe(
+                    rt.clone(),
+                    icmpv4.clone(),
+                    events.clone(),
+                    options.clone(),
+                    statuses.clone(),
+                    i,
+                );
+                rt.spawn(future)
+            })
+            .collect();
+        Self { statuses, handles }
+    }
+
+    /// Every destination's status, in the order they were configured.
+    pub fn statuses(&self) -> Vec<PathStatus> {
+        self.statuses.borrow().clone()
+    }
+
+    pub fn status(&self, destination: Ipv4Addr) -> Option<PathStatus> {
+        self.statuses
+            .borrow()
+            .iter()
+            .find(|s| s.destination == destination)
+            .cloned()
+    }
+
+    async fn probe<RT: Runtime>(
+        rt: RT,
+        icmpv4: Icmpv4Peer<RT>,
+        events: EventQueue,
+        options: Rc<MonitorOptions>,
+        statuses: Rc<RefCell<Vec<PathStatus>>>,
+        index: usize,
+    ) {
+        let destination = options.destinations[index];
+        loop {
+            let sent = rt.now();
+            let rtt = match icmpv4.ping(destination, Some(options.timeout)).await {
+                Ok(rtt) => Some(rtt),
+                Err(e) => {
+                    debug!("Path probe to {} failed: {:?}", destination, e);
+                    None
+                },
+            };
+            let event = statuses.borrow_mut()[index].record(rtt, rt.now(), &options);
+            if let Some(event) = event {
+                info!("{:?}", event);
+                events.push(event);
+            }
+            rt.wait_until(sent + options.interval).await;
+        }
+    }
+}
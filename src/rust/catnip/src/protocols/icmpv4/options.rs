@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Icmpv4Options {
+    /// How long `ping` waits for a reply when the caller doesn't give a timeout.
+    pub echo_timeout: Duration,
+    /// Answer echo requests. When off, they're dropped at the IPv4 demux; `ping` still works.
+    pub echo_reply: bool,
+    /// Trust the device to have verified received checksums.
+    pub rx_checksum_offload: bool,
+    /// The most errors `ErrorSender` sends in any one second, or `None` for no limit.
+    pub error_rate_limit: Option<u32>,
+}
+
+impl Default for Icmpv4Options {
+    fn default() -> Self {
+        Icmpv4Options {
+            echo_timeout: Duration::from_secs(5),
+            echo_reply: true,
+            rx_checksum_offload: false,
+            error_rate_limit: Some(100),
+        }
+    }
+}
+
+impl Icmpv4Options {
+    pub fn echo_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.echo_timeout = value;
+        self
+    }
+
+    pub fn echo_reply(mut self, value: bool) -> Self {
+        self.echo_reply = value;
+        self
+    }
+
+    pub fn error_rate_limit(mut self, value: Option<u32>) -> Self {
+        self.error_rate_limit = value;
+        self
+    }
+}
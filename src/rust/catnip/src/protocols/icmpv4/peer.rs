@@ -1,26 +1,32 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use std::marker::PhantomData;
-use super::datagram::{
-    Icmpv4Header,
-    Icmpv4Type2,
+use super::{
+    datagram::{
+        Icmpv4Header,
+        Icmpv4Type2,
+        ICMPV4_HEADER_SIZE,
+    },
+    error::FragmentationNeeded,
+    CODE_FRAGMENTATION_NEEDED,
 };
 use crate::{
     fail::Fail,
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
-        },
-        icmpv4::datagram::Icmpv4Message,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
+        ipv4::{
+            datagram::{
+                Ipv4Header,
+                IPV4_HEADER_SIZE,
+            },
+            Egress,
         },
+        packet::PacketBuilder,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
     },
-    runtime::Runtime,
     scheduler::SchedulerHandle,
 };
 use byteorder::{
@@ -39,7 +45,10 @@ use std::{
     num::Wrapping,
     process,
     rc::Rc,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 // TODO: Use unsync channel
 use futures::channel::{
@@ -53,21 +62,49 @@ use futures::channel::{
 pub struct Icmpv4Peer<RT: Runtime> {
     rt: RT,
     arp: arp::Peer<RT>,
+    egress: Egress<RT>,
 
     #[allow(unused)]
-    handle: SchedulerHandle,
-    tx: mpsc::UnboundedSender<(Ipv4Addr, u16, u16)>,
+    handle: Rc<SchedulerHandle>,
+    tx: mpsc::UnboundedSender<(Ipv4Addr, u16, u16, RT::Buf)>,
 
     inner: Rc<RefCell<Inner>>,
 }
 
+impl<RT: Runtime> Clone for Icmpv4Peer<RT> {
+    fn clone(&self) -> Self {
+        Self {
+            rt: self.rt.clone(),
+            arp: self.arp.clone(),
+            egress: self.egress.clone(),
+            handle: self.handle.clone(),
+            tx: self.tx.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// Size of the payload `ping` sends when the caller doesn't supply one, as with `ping(8)`: an
+/// 8-byte timestamp followed by a counting pattern.
+pub const DEFAULT_ECHO_PAYLOAD_SIZE: usize = 56;
+
+/// The largest payload that fits in an IPv4 datagram.
+pub const MAX_ECHO_PAYLOAD_SIZE: usize = 65535 - IPV4_HEADER_SIZE - ICMPV4_HEADER_SIZE;
+
+struct PendingEcho {
+    payload: Vec<u8>,
+    tx: Sender<Result<(), Fail>>,
+}
+
 struct Inner {
-    requests: HashMap<(u16, u16), Sender<()>>,
+    requests: HashMap<(u16, u16), PendingEcho>,
     ping_seq_num_counter: Wrapping<u16>,
+    // Timestamps in default payloads count from here.
+    epoch: Instant,
 }
 
 impl<RT: Runtime> Icmpv4Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>) -> Icmpv4Peer<RT> {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, egress: Egress<RT>) -> Icmpv4Peer<RT> {
         let (tx, rx) = mpsc::unbounded();
         let inner = Inner {
             requests: HashMap::new(),
@@ -76,13 +113,15 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
             // > Number field starts with the value 0 and is increased by 1 every
             // > time a new Echo Request message is sent.
             ping_seq_num_counter: Wrapping(0),
+            epoch: rt.now(),
         };
         let inner = Rc::new(RefCell::new(inner));
-        let future = Self::background(rt.clone(), arp.clone(), rx);
-        let handle = rt.spawn(future);
+        let future = Self::background(rt.clone(), arp.clone(), egress.clone(), rx);
+        let handle = Rc::new(rt.spawn(future));
         Icmpv4Peer {
             rt,
             arp,
+            egress,
             tx,
             handle,
             inner,
@@ -92,9 +131,10 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
     async fn background(
         rt: RT,
         arp: arp::Peer<RT>,
-        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, u16, u16)>,
+        egress: Egress<RT>,
+        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, u16, u16, RT::Buf)>,
     ) {
-        while let Some((dst_ipv4_addr, id, seq_num)) = rx.next().await {
+        while let Some((dst_ipv4_addr, id, seq_num, data)) = rx.next().await {
             let r: Result<_, Fail> = try {
                 debug!("initiating ARP query");
                 let dst_link_addr = arp.query(dst_ipv4_addr).await?;
@@ -102,24 +142,13 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     "ARP query complete ({} -> {})",
                     dst_ipv4_addr, dst_link_addr
                 );
-                let msg = Icmpv4Message {
-                    ethernet2_hdr: Ethernet2Header {
-                        dst_addr: dst_link_addr,
-                        src_addr: rt.local_link_addr(),
-                        ether_type: EtherType2::Ipv4,
-                    },
-                    ipv4_hdr: Ipv4Header::new(
-                        rt.local_ipv4_addr(),
-                        dst_ipv4_addr,
-                        Ipv4Protocol2::Icmpv4,
-                    ),
-                    icmpv4_hdr: Icmpv4Header {
-                        icmpv4_type: Icmpv4Type2::EchoReply { id, seq_num },
-                        code: 0,
-                    },
-                    _body_marker: PhantomData,
+                let icmpv4_hdr = Icmpv4Header {
+                    icmpv4_type: Icmpv4Type2::EchoReply { id, seq_num },
+                    code: 0,
                 };
-                rt.transmit(msg);
+                let msg = PacketBuilder::new(&rt, dst_link_addr, dst_ipv4_addr)
+                    .icmpv4(icmpv4_hdr, data);
+                egress.transmit(msg);
             };
             if let Err(e) = r {
                 warn!(
@@ -130,23 +159,35 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
         }
     }
 
-    pub fn receive(&mut self, ipv4_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
-        let (icmpv4_hdr, _) = Icmpv4Header::parse(buf)?;
+    /// Handle an ICMP message, returning fragmentation needed errors for the protocol that sent
+    /// the datagram to act on.
+    pub fn receive(
+        &mut self,
+        ipv4_header: &Ipv4Header,
+        buf: RT::Buf,
+    ) -> Result<Option<FragmentationNeeded>, Fail> {
+        let rx_checksum_offload = self.rt.icmpv4_options().rx_checksum_offload;
+        let (icmpv4_hdr, data) = Icmpv4Header::parse(buf, rx_checksum_offload)?;
         match icmpv4_hdr.icmpv4_type {
             Icmpv4Type2::EchoRequest { id, seq_num } => {
-                self.reply_to_ping(ipv4_header.src_addr, id, seq_num);
+                self.reply_to_ping(ipv4_header.src_addr, id, seq_num, data);
             },
             Icmpv4Type2::EchoReply { id, seq_num } => {
                 let mut inner = self.inner.borrow_mut();
-                if let Some(tx) = inner.requests.remove(&(id, seq_num)) {
-                    let _ = tx.send(());
+                if let Some(pending) = inner.requests.remove(&(id, seq_num)) {
+                    let _ = pending.tx.send(check_echo(&pending.payload, &data[..]));
                 }
             },
+            Icmpv4Type2::DestinationUnreachable { next_hop_mtu }
+                if icmpv4_hdr.code == CODE_FRAGMENTATION_NEEDED =>
+            {
+                return FragmentationNeeded::parse(next_hop_mtu, &data[..]).map(Some);
+            },
             _ => {
                 warn!("Unsupported ICMPv4 message: {:?}", icmpv4_hdr);
             },
         }
-        Ok(())
+        Ok(None)
     }
 
     pub fn ping(
@@ -154,12 +195,47 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
         dst_ipv4_addr: Ipv4Addr,
         timeout: Option<Duration>,
     ) -> impl Future<Output = Result<Duration, Fail>> {
-        let timeout = timeout.unwrap_or_else(|| Duration::from_millis(5000));
+        self.ping_with_payload(dst_ipv4_addr, None, timeout)
+    }
+
+    /// Ping each of `destinations`, with at most `concurrency` pings outstanding at once, and
+    /// return their results in the same order. Each ping waits `timeout` (or the default) on its
+    /// own, so a sweep of unreachable destinations takes about `destinations.len() /
+    /// concurrency` timeouts.
+    pub fn ping_many(
+        &self,
+        destinations: &[Ipv4Addr],
+        concurrency: usize,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Vec<(Ipv4Addr, Result<Duration, Fail>)>> {
+        assert!(concurrency > 0);
+        let peer = self.clone();
+        // Each ping is only started when the stream gets to it, so the sequence numbers are
+        // handed out in order as slots free up.
+        futures::stream::iter(destinations.to_vec())
+            .map(move |dst| {
+                let ping = peer.ping(dst, timeout);
+                async move { (dst, ping.await) }
+            })
+            .buffered(concurrency)
+            .collect()
+    }
+
+    /// Send an echo request carrying `payload` (or a timestamped default) and wait for a reply
+    /// that echoes it back intact. A reply that comes back short fails with `Malformed` as
+    /// truncated, one with different contents as corrupted; no reply at all is a `Timeout`.
+    pub fn ping_with_payload(
+        &self,
+        dst_ipv4_addr: Ipv4Addr,
+        payload: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<Duration, Fail>> {
+        let timeout = timeout.unwrap_or_else(|| self.rt.icmpv4_options().echo_timeout);
         let id = {
             let mut state = 0xFFFF as u32;
             let addr_octets = self.rt.local_ipv4_addr().octets();
             state += NetworkEndian::read_u16(&addr_octets[0..2]) as u32;
-            state += NetworkEndian::read_u16(&addr_octets[3..4]) as u32;
+            state += NetworkEndian::read_u16(&addr_octets[2..4]) as u32;
 
             let mut pid_buf = [0u8; 4];
             NetworkEndian::write_u32(&mut pid_buf[..], process::id());
@@ -180,10 +256,20 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
             inner.ping_seq_num_counter += Wrapping(1);
             seq_num
         };
+        let payload = match payload {
+            Some(payload) => payload.to_vec(),
+            None => default_payload(self.rt.now() - self.inner.borrow().epoch),
+        };
         let arp = self.arp.clone();
         let rt = self.rt.clone();
+        let egress = self.egress.clone();
         let inner = self.inner.clone();
         async move {
+            if payload.len() > MAX_ECHO_PAYLOAD_SIZE {
+                return Err(Fail::Invalid {
+                    details: "Echo payload too large",
+                });
+            }
             let t0 = rt.now();
             debug!("initiating ARP query");
             let dst_link_addr = arp.query(dst_ipv4_addr).await?;
@@ -192,41 +278,72 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                 dst_ipv4_addr, dst_link_addr
             );
 
-            let msg = Icmpv4Message {
-                ethernet2_hdr: Ethernet2Header {
-                    dst_addr: dst_link_addr,
-                    src_addr: rt.local_link_addr(),
-                    ether_type: EtherType2::Ipv4,
-                },
-                ipv4_hdr: Ipv4Header::new(
-                    rt.local_ipv4_addr(),
-                    dst_ipv4_addr,
-                    Ipv4Protocol2::Icmpv4,
-                ),
-                icmpv4_hdr: Icmpv4Header {
-                    icmpv4_type: Icmpv4Type2::EchoRequest { id, seq_num },
-                    code: 0,
-                },
-                _body_marker: PhantomData,
+            let icmpv4_hdr = Icmpv4Header {
+                icmpv4_type: Icmpv4Type2::EchoRequest { id, seq_num },
+                code: 0,
             };
-            rt.transmit(msg);
+            let msg = PacketBuilder::new(&rt, dst_link_addr, dst_ipv4_addr)
+                .icmpv4(icmpv4_hdr, RT::Buf::from_slice(&payload[..]));
             let rx = {
                 let (tx, rx) = channel();
                 let mut inner = inner.borrow_mut();
-                assert!(inner.requests.insert((id, seq_num), tx).is_none());
+                // The sequence number wraps after 65536 pings, so an abandoned one may still hold
+                // this slot.
+                inner
+                    .requests
+                    .insert((id, seq_num), PendingEcho { payload, tx });
                 rx
             };
+            egress.transmit(msg);
             // TODO: Handle cancellation here and unregister the completion in `requests`.
             futures::select! {
-                _ = rx.fuse() => Ok(rt.now() - t0),
+                r = rx.fuse() => match r {
+                    Ok(Ok(())) => Ok(rt.now() - t0),
+                    Ok(Err(e)) => Err(e),
+                    // Replaced by a newer ping with the same sequence number.
+                    Err(_) => Err(Fail::Timeout {}),
+                },
                 _ = rt.wait(timeout).fuse() => Err(Fail::Timeout {}),
             }
         }
     }
 
-    pub fn reply_to_ping(&mut self, dest_ipv4_addr: Ipv4Addr, id: u16, seq_num: u16) {
-        self.tx
-            .unbounded_send((dest_ipv4_addr, id, seq_num))
-            .unwrap();
+    pub fn reply_to_ping(
+        &mut self,
+        dest_ipv4_addr: Ipv4Addr,
+        id: u16,
+        seq_num: u16,
+        data: RT::Buf,
+    ) {
+        if self
+            .tx
+            .unbounded_send((dest_ipv4_addr, id, seq_num, data))
+            .is_err()
+        {
+            warn!("Dropping echo request from {}: reply task is gone", dest_ipv4_addr);
+        }
+    }
+}
+
+fn default_payload(timestamp: Duration) -> Vec<u8> {
+    let mut payload = vec![0u8; DEFAULT_ECHO_PAYLOAD_SIZE];
+    NetworkEndian::write_u64(&mut payload[0..8], timestamp.as_nanos() as u64);
+    for (i, b) in payload.iter_mut().enumerate().skip(8) {
+        *b = i as u8;
+    }
+    payload
+}
+
+fn check_echo(sent: &[u8], received: &[u8]) -> Result<(), Fail> {
+    if received.len() < sent.len() {
+        return Err(Fail::Malformed {
+            details: "Echo reply truncated",
+        });
+    }
+    if received != sent {
+        return Err(Fail::Malformed {
+            details: "Echo reply corrupted",
+        });
     }
+    Ok(())
 }
@@ -0,0 +1,358 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    datagram::{
+        Icmpv4Header,
+        Icmpv4Message,
+        Icmpv4Type2,
+    },
+    peer::DEFAULT_ECHO_PAYLOAD_SIZE,
+    MonitorOptions,
+    PathState,
+    CODE_FRAGMENTATION_NEEDED,
+};
+use crate::{
+    event::Event,
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::{
+            EtherType2,
+            Ethernet2Header,
+        },
+        ip,
+        ipv4,
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+        },
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    sync::Bytes,
+    test_helpers,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Parse an echo request frame into its IDs and payload.
+fn parse_echo_request(frame: Bytes) -> (Ethernet2Header, Ipv4Header, u16, u16, Bytes) {
+    let (eth_hdr, buf) = Ethernet2Header::parse(frame).unwrap();
+    let (ipv4_hdr, buf) = Ipv4Header::parse(buf).unwrap();
+    let (icmpv4_hdr, data) = Icmpv4Header::parse(buf, false).unwrap();
+    must_let!(let Icmpv4Type2::EchoRequest { id, seq_num } = icmpv4_hdr.icmpv4_type);
+    (eth_hdr, ipv4_hdr, id, seq_num, data)
+}
+
+/// A well-formed reply to `request` that carries `data` instead of the request's payload.
+fn forged_reply(request: Bytes, data: &[u8]) -> Icmpv4Message<Bytes> {
+    let (eth_hdr, ipv4_hdr, id, seq_num, _) = parse_echo_request(request);
+    Icmpv4Message {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: eth_hdr.src_addr,
+            src_addr: eth_hdr.dst_addr,
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: Ipv4Header::new(ipv4_hdr.dst_addr, ipv4_hdr.src_addr, Ipv4Protocol2::Icmpv4),
+        icmpv4_hdr: Icmpv4Header {
+            icmpv4_type: Icmpv4Type2::EchoReply { id, seq_num },
+            code: 0,
+        },
+        data: Bytes::from_slice(data),
+    }
+}
+
+#[test]
+fn echo_round_trip() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let mut ping = Box::pin(alice.ping(test_helpers::BOB_IPV4, None));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    let request = alice.rt().pop_frame();
+    let (_, _, _, _, payload) = parse_echo_request(request.clone());
+    assert_eq!(payload.len(), DEFAULT_ECHO_PAYLOAD_SIZE);
+
+    bob.receive(request).unwrap();
+    bob.rt().poll_scheduler();
+    let reply = bob.rt().pop_frame();
+    let (_, buf) = Ethernet2Header::parse(reply.clone()).unwrap();
+    let (_, buf) = Ipv4Header::parse(buf).unwrap();
+    let (_, echoed) = Icmpv4Header::parse(buf, false).unwrap();
+    assert_eq!(&echoed[..], &payload[..]);
+
+    alice.receive(reply).unwrap();
+    must_let!(let Poll::Ready(Ok(..)) = Future::poll(ping.as_mut(), &mut ctx));
+
+    // A caller-supplied payload goes out as is.
+    let mut ping =
+        Box::pin(alice.ping_with_payload(test_helpers::BOB_IPV4, Some(&b"hello"[..]), None));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    let (_, _, _, _, payload) = parse_echo_request(alice.rt().pop_frame());
+    assert_eq!(&payload[..], b"hello");
+}
+
+#[test]
+fn ping_many() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    // Carrie never answers, so her ping holds one of the two slots until it times out.
+    let destinations = [
+        test_helpers::BOB_IPV4,
+        test_helpers::CARRIE_IPV4,
+        test_helpers::BOB_IPV4,
+    ];
+    let timeout = Duration::from_secs(1);
+    let mut sweep = Box::pin(alice.ping_many(&destinations, 2, Some(timeout)));
+    assert!(Future::poll(sweep.as_mut(), &mut ctx).is_pending());
+    let request = alice.rt().pop_frame();
+    let (_, ipv4_hdr, ..) = parse_echo_request(alice.rt().pop_frame());
+    assert_eq!(ipv4_hdr.dst_addr, test_helpers::CARRIE_IPV4);
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    // Bob's first reply frees a slot for the last ping.
+    bob.receive(request).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(sweep.as_mut(), &mut ctx).is_pending());
+    let request = alice.rt().pop_frame();
+    bob.receive(request).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(Future::poll(sweep.as_mut(), &mut ctx).is_pending());
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    now += timeout;
+    alice.rt().advance_clock(now);
+    must_let!(let Poll::Ready(results) = Future::poll(sweep.as_mut(), &mut ctx));
+    must_let!(let [(bob1, Ok(..)), (carrie, Err(Fail::Timeout {})), (bob2, Ok(..))] = &results[..]);
+    assert_eq!((*bob1, *carrie, *bob2), (destinations[0], destinations[1], destinations[2]));
+}
+
+#[test]
+fn echo_validation() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+    let timeout = Some(Duration::from_secs(1));
+
+    let mut ping =
+        Box::pin(alice.ping_with_payload(test_helpers::BOB_IPV4, Some(&b"abcdefgh"[..]), timeout));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    bob.rt()
+        .transmit(forged_reply(alice.rt().pop_frame(), b"abcd"));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::Malformed { details })) = Future::poll(ping.as_mut(), &mut ctx));
+    assert_eq!(details, "Echo reply truncated");
+
+    let mut ping =
+        Box::pin(alice.ping_with_payload(test_helpers::BOB_IPV4, Some(&b"abcdefgh"[..]), timeout));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    bob.rt()
+        .transmit(forged_reply(alice.rt().pop_frame(), b"abcdefgX"));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::Malformed { details })) = Future::poll(ping.as_mut(), &mut ctx));
+    assert_eq!(details, "Echo reply corrupted");
+
+    // No reply at all is still a timeout.
+    let mut ping =
+        Box::pin(alice.ping_with_payload(test_helpers::BOB_IPV4, Some(&b"abcdefgh"[..]), timeout));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    alice.rt().advance_clock(now + Duration::from_secs(2));
+    alice.rt().poll_scheduler();
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(ping.as_mut(), &mut ctx));
+}
+
+#[test]
+fn checksum_validation() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let mut ping = Box::pin(alice.ping(test_helpers::BOB_IPV4, None));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    let mut corrupted = alice.rt().pop_frame()[..].to_vec();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    let corrupted = Bytes::from_slice(&corrupted[..]);
+
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(corrupted.clone()));
+    assert_eq!(bob.stats().rx_checksum_errors.icmpv4, 1);
+
+    // With validation offloaded, the request is taken at face value.
+    bob.rt()
+        .set_icmpv4_options(|o| o.rx_checksum_offload = true);
+    bob.receive(corrupted).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 1);
+    assert_eq!(bob.stats().rx_checksum_errors.icmpv4, 1);
+}
+
+#[test]
+fn path_monitor() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let options = MonitorOptions::new(vec![test_helpers::BOB_IPV4, test_helpers::CARRIE_IPV4])
+        .timeout(Duration::from_millis(500))
+        .down_after(2);
+    alice.path_monitor_start(options).unwrap();
+
+    // Bob answers every probe, while Carrie never sees hers.
+    for _ in 0..3 {
+        alice.rt().poll_scheduler();
+        while alice.rt().num_outgoing() > 0 {
+            let frame = alice.rt().pop_frame();
+            let (_, ipv4_hdr, ..) = parse_echo_request(frame.clone());
+            if ipv4_hdr.dst_addr == test_helpers::BOB_IPV4 {
+                bob.receive(frame).unwrap();
+                bob.rt().poll_scheduler();
+                alice.receive(bob.rt().pop_frame()).unwrap();
+            }
+        }
+        alice.rt().poll_scheduler();
+        now += Duration::from_millis(500);
+        alice.advance_clock(now);
+        alice.rt().poll_scheduler();
+        now += Duration::from_millis(500);
+        alice.advance_clock(now);
+    }
+
+    let events = alice.take_events();
+    must_let!(let [Event::PathUp { destination, .. }, Event::PathDown { destination: down }] = &events[..]);
+    assert_eq!(*destination, test_helpers::BOB_IPV4);
+    assert_eq!(*down, test_helpers::CARRIE_IPV4);
+    assert!(alice.take_events().is_empty());
+
+    let bob_status = alice.path_status(test_helpers::BOB_IPV4).unwrap();
+    assert_eq!(bob_status.state, PathState::Up);
+    assert_eq!(bob_status.probes_lost, 0);
+    must_let!(let Some(_) = bob_status.srtt);
+    let paths = alice.stats().paths;
+    assert_eq!(paths.len(), 2);
+    assert_eq!(paths[1].state, PathState::Down);
+    assert_eq!(paths[1].consecutive_lost, 2);
+
+    assert!(alice.path_monitor_stop());
+    assert!(alice.stats().paths.is_empty());
+}
+
+#[test]
+fn poll_deadline() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let options = MonitorOptions::new(vec![test_helpers::CARRIE_IPV4])
+        .timeout(Duration::from_millis(500))
+        .down_after(1);
+    alice.path_monitor_start(options).unwrap();
+
+    // The first probe goes out, and nothing needs doing until it times out.
+    let (events, deadline) = alice.poll(now);
+    assert!(events.is_empty());
+    assert_eq!(alice.rt().num_outgoing(), 1);
+    assert_eq!(deadline, Some(now + Duration::from_millis(500)));
+
+    // Polling at that deadline reports the loss, and the next probe is due a second after the
+    // first.
+    let (events, deadline) = alice.poll(deadline.unwrap());
+    must_let!(let [Event::PathDown { destination }] = &events[..]);
+    assert_eq!(*destination, test_helpers::CARRIE_IPV4);
+    assert_eq!(deadline, Some(now + Duration::from_secs(1)));
+}
+
+/// A router's fragmentation needed error to the sender of `frame`, quoting it.
+fn fragmentation_needed(frame: &[u8], next_hop_mtu: u16) -> Icmpv4Message<Bytes> {
+    let (eth_hdr, buf) = Ethernet2Header::parse(Bytes::from_slice(frame)).unwrap();
+    let (ipv4_hdr, _) = Ipv4Header::parse(buf).unwrap();
+    Icmpv4Message {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: eth_hdr.src_addr,
+            src_addr: test_helpers::CARRIE_MAC,
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: Ipv4Header::new(
+            test_helpers::CARRIE_IPV4,
+            ipv4_hdr.src_addr,
+            Ipv4Protocol2::Icmpv4,
+        ),
+        icmpv4_hdr: Icmpv4Header {
+            icmpv4_type: Icmpv4Type2::DestinationUnreachable { next_hop_mtu },
+            code: CODE_FRAGMENTATION_NEEDED,
+        },
+        data: Bytes::from_slice(&frame[14..(14 + 28)]),
+    }
+}
+
+#[test]
+fn fragmentation_needed_shrinks_mss() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert_eq!(alice.tcp_negotiated(alice_fd).unwrap().mss, 2048);
+
+    // A segment too big for a link on the way is dropped, and the router says so.
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 1500]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+
+    // An error quoting data Alice never sent is ignored.
+    let mut forged = frame[..].to_vec();
+    let seq_num = NetworkEndian::read_u32(&forged[38..42]);
+    NetworkEndian::write_u32(&mut forged[38..42], seq_num.wrapping_add(100_000));
+    bob.rt().transmit(fragmentation_needed(&forged, 576));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_negotiated(alice_fd).unwrap().mss, 2048);
+
+    bob.rt().transmit(fragmentation_needed(&frame, 1280));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_negotiated(alice_fd).unwrap().mss, 1240);
+
+    // The lost data is retransmitted in segments that fit.
+    alice.advance_clock(now + alice.tcp_rto(alice_fd).unwrap());
+    alice.rt().poll_scheduler();
+    let frame = alice.rt().pop_frame();
+    assert_eq!(frame.len(), 14 + 20 + 20 + 1240);
+}
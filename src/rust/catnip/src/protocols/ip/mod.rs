@@ -3,4 +3,10 @@
 
 pub mod port;
 
-pub use port::Port;
+pub use port::{
+    Port,
+    PortClaim,
+    PortProtocol,
+    PortTable,
+    PortUse,
+};
@@ -3,14 +3,19 @@
 
 use crate::{
     fail::Fail,
+    file_table::FileDescriptor,
     runtime::Runtime,
 };
 use std::{
+    cell::RefCell,
+    collections::BTreeMap,
     convert::TryFrom,
     num::NonZeroU16,
+    ops::RangeInclusive,
+    rc::Rc,
 };
 
-const FIRST_PRIVATE_PORT: u16 = 49152;
+pub const FIRST_PRIVATE_PORT: u16 = 49152;
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Display, Ord, PartialOrd)]
 pub struct Port(NonZeroU16);
@@ -42,17 +47,21 @@ impl Port {
 }
 
 pub struct EphemeralPorts {
+    range: RangeInclusive<u16>,
     ports: Vec<Port>,
 }
 
 impl EphemeralPorts {
     pub fn new<RT: Runtime>(rt: &RT) -> Self {
-        let mut ports = (FIRST_PRIVATE_PORT..=65535u16)
-            .map(|p| Port(NonZeroU16::new(p).unwrap()))
+        let range = rt.tcp_options().ephemeral_ports;
+        let mut ports = range
+            .clone()
+            .filter_map(NonZeroU16::new)
+            .map(Port)
             .collect::<Vec<_>>();
 
         rt.rng_shuffle(&mut ports[..]);
-        Self { ports }
+        Self { range, ports }
     }
 
     pub fn alloc(&mut self) -> Result<Port, Fail> {
@@ -61,7 +70,168 @@ impl EphemeralPorts {
         })
     }
 
+    /// Like `alloc`, but only hands out a port that satisfies `f`.
+    pub fn alloc_matching(&mut self, f: impl Fn(Port) -> bool) -> Result<Port, Fail> {
+        match self.ports.iter().rposition(|&p| f(p)) {
+            Some(i) => Ok(self.ports.swap_remove(i)),
+            None => Err(Fail::ResourceExhausted {
+                details: "Out of private ports",
+            }),
+        }
+    }
+
     pub fn free(&mut self, port: Port) {
-        self.ports.push(port);
+        if self.range.contains(&port.0.get()) {
+            self.ports.push(port);
+        }
+    }
+
+    /// Take a specific port out of the pool, for a connection that was set up elsewhere.
+    pub fn reserve(&mut self, port: Port) -> Result<(), Fail> {
+        if !self.range.contains(&port.0.get()) {
+            return Ok(());
+        }
+        match self.ports.iter().position(|&p| p == port) {
+            Some(i) => {
+                self.ports.swap_remove(i);
+                Ok(())
+            },
+            None => Err(Fail::ResourceBusy {
+                details: "Port already in use",
+            }),
+        }
+    }
+}
+
+/// The transport a port belongs to. TCP and UDP ports are separate namespaces, so the same number
+/// can be held once in each.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Why a port is held.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PortUse {
+    /// A socket was bound to it, to listen or receive on.
+    Bound,
+    /// It was handed out to an active open.
+    Ephemeral,
+}
+
+/// A local port and the socket holding it. See `Engine::ports`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortClaim {
+    pub protocol: PortProtocol,
+    pub port: Port,
+    pub fd: FileDescriptor,
+    pub usage: PortUse,
+}
+
+/// Which socket holds each local port, shared by the TCP and UDP peers so both turn away a port
+/// that's taken the same way, and so the whole engine's ports can be listed in one place.
+#[derive(Clone)]
+pub struct PortTable {
+    inner: Rc<RefCell<PortTableInner>>,
+}
+
+struct PortTableInner {
+    claims: BTreeMap<(PortProtocol, Port), PortClaim>,
+    tcp_ephemeral: EphemeralPorts,
+}
+
+impl PortTable {
+    pub fn new<RT: Runtime>(rt: &RT) -> Self {
+        let inner = PortTableInner {
+            claims: BTreeMap::new(),
+            tcp_ephemeral: EphemeralPorts::new(rt),
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Bind `port` to `fd`, unless another socket already holds it.
+    pub fn claim(&self, protocol: PortProtocol, port: Port, fd: FileDescriptor) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.claims.contains_key(&(protocol, port)) {
+            return Err(Fail::ResourceBusy {
+                details: "Port already in use",
+            });
+        }
+        let claim = PortClaim {
+            protocol,
+            port,
+            fd,
+            usage: PortUse::Bound,
+        };
+        inner.claims.insert((protocol, port), claim);
+        Ok(())
+    }
+
+    /// Hand `fd` a free TCP ephemeral port that satisfies `f`.
+    pub fn alloc_ephemeral(
+        &self,
+        fd: FileDescriptor,
+        f: impl Fn(Port) -> bool,
+    ) -> Result<Port, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let PortTableInner {
+            ref mut claims,
+            ref mut tcp_ephemeral,
+        } = *inner;
+        let port = tcp_ephemeral
+            .alloc_matching(|port| f(port) && !claims.contains_key(&(PortProtocol::Tcp, port)))?;
+        let claim = PortClaim {
+            protocol: PortProtocol::Tcp,
+            port,
+            fd,
+            usage: PortUse::Ephemeral,
+        };
+        claims.insert((PortProtocol::Tcp, port), claim);
+        Ok(port)
+    }
+
+    /// Give `fd` a specific TCP ephemeral port, for a connection that was set up elsewhere.
+    pub fn reserve_ephemeral(&self, port: Port, fd: FileDescriptor) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.claims.contains_key(&(PortProtocol::Tcp, port)) {
+            return Err(Fail::ResourceBusy {
+                details: "Port already in use",
+            });
+        }
+        inner.tcp_ephemeral.reserve(port)?;
+        let claim = PortClaim {
+            protocol: PortProtocol::Tcp,
+            port,
+            fd,
+            usage: PortUse::Ephemeral,
+        };
+        inner.claims.insert((PortProtocol::Tcp, port), claim);
+        Ok(())
+    }
+
+    /// Let go of `port` if `fd` holds it, returning it to the ephemeral pool if it came from
+    /// there. Connections accepted on a listener share its port, so they leave it alone.
+    pub fn release(&self, protocol: PortProtocol, port: Port, fd: FileDescriptor) {
+        let mut inner = self.inner.borrow_mut();
+        match inner.claims.get(&(protocol, port)) {
+            Some(claim) if claim.fd == fd => (),
+            _ => return,
+        }
+        let claim = inner.claims.remove(&(protocol, port)).unwrap();
+        if claim.usage == PortUse::Ephemeral {
+            inner.tcp_ephemeral.free(port);
+        }
+    }
+
+    pub fn owner(&self, protocol: PortProtocol, port: Port) -> Option<PortClaim> {
+        self.inner.borrow().claims.get(&(protocol, port)).cloned()
+    }
+
+    /// Every port held, TCP then UDP, in port order.
+    pub fn claims(&self) -> Vec<PortClaim> {
+        self.inner.borrow().claims.values().cloned().collect()
     }
 }
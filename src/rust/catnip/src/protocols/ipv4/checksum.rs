@@ -0,0 +1,25 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+
+/// Update an Internet checksum for `old` being replaced by `new`, without summing everything
+/// else it covers again (RFC 1624, eqn. 3). Both are a whole number of 16-bit words, aligned the
+/// same way they are in the checksummed data.
+pub fn adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    assert_eq!(old.len(), new.len());
+    assert_eq!(old.len() % 2, 0);
+    // HC' = ~(~HC + ~m + m')
+    let mut sum = (!checksum) as u32;
+    for (o, n) in old.chunks(2).zip(new.chunks(2)) {
+        sum += (!NetworkEndian::read_u16(o)) as u32;
+        sum += NetworkEndian::read_u16(n) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
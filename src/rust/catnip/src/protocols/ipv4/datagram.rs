@@ -22,12 +22,17 @@ pub const DEFAULT_IPV4_TTL: u8 = 64;
 pub const IPV4_IHL_NO_OPTIONS: u8 = 5;
 pub const IPV4_VERSION: u8 = 4;
 
+/// Bits of `Ipv4Header::flags`.
+pub const IPV4_FLAG_DONT_FRAGMENT: u8 = 0b010;
+pub const IPV4_FLAG_MORE_FRAGMENTS: u8 = 0b001;
+
 #[repr(u8)]
-#[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Ipv4Protocol2 {
     Icmpv4 = 0x01,
     Tcp = 0x06,
     Udp = 0x11,
+    Gre = 0x2f,
 }
 
 impl TryFrom<u8> for Ipv4Protocol2 {
@@ -43,7 +48,7 @@ impl TryFrom<u8> for Ipv4Protocol2 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Ipv4Header {
     // [ version 4 bits ] [ IHL 4 bits ]
     // The user shouldn't be able to mutate the version, so we parse it out but don't include it
@@ -97,7 +102,7 @@ impl Ipv4Header {
             identification: 0,
             flags: 0,
             fragment_offset: 0,
-            time_to_live: 0,
+            time_to_live: DEFAULT_IPV4_TTL,
             protocol,
             src_addr,
             dst_addr,
@@ -109,6 +114,11 @@ impl Ipv4Header {
         IPV4_HEADER_SIZE
     }
 
+    /// Whether this is only part of a datagram, to be reassembled before it's delivered.
+    pub fn is_fragment(&self) -> bool {
+        self.flags & IPV4_FLAG_MORE_FRAGMENTS != 0 || self.fragment_offset != 0
+    }
+
     pub fn parse<T: RuntimeBuf>(mut buf: T) -> Result<(Self, T), Fail> {
         if buf.len() < IPV4_HEADER_SIZE {
             return Err(Fail::Malformed {
@@ -157,11 +167,6 @@ impl Ipv4Header {
         let flags = (NetworkEndian::read_u16(&hdr_buf[6..8]) >> 13) as u8;
 
         let fragment_offset = NetworkEndian::read_u16(&hdr_buf[6..8]) & 0x1fff;
-        if fragment_offset != 0 {
-            return Err(Fail::Unsupported {
-                details: "IPv4 fragmentation is unsupported",
-            });
-        }
 
         let time_to_live = hdr_buf[8];
         let protocol = Ipv4Protocol2::try_from(hdr_buf[9])?;
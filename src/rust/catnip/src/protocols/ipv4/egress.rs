@@ -0,0 +1,711 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! The transmit path shared by everything that sends IPv4 packets. Packets pass the egress filter
+//! and then any token-bucket rate limits before they reach `Runtime::transmit`. With no limits
+//! configured they go straight out; otherwise they're serialized and queued, and a background
+//! coroutine sleeps until the buckets have refilled enough to release them.
+//!
+//! Frames waiting on the engine-wide limit are queued by traffic class, so handshakes and pure
+//! ACKs can overtake bulk data, and dequeued by strict priority or weighted round robin.
+//!
+//! Frames also queue while the device reports it can't take any more (`Runtime::tx_ready`), and
+//! are retried shortly after, in the same order.
+//!
+//! An open GRE tunnel also hooks in here, after the filter and before shaping.
+
+use super::{
+    datagram::Ipv4Protocol2,
+    filter::{
+        Filter,
+        PacketSummary,
+        TCP_FIN,
+        TCP_RST,
+        TCP_SYN,
+    },
+    Endpoint,
+};
+use crate::{
+    collections::watched::WatchedValue,
+    protocols::{
+        ethernet2::frame::ETHERNET2_HEADER_SIZE,
+        gre,
+        ip,
+    },
+    runtime::{
+        PacketBuf,
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::{
+    future::{
+        self,
+        Either,
+    },
+    FutureExt,
+};
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    convert::TryFrom,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Frames held back by rate limits beyond which new ones are dropped.
+const MAX_QUEUED_FRAMES: usize = 4096;
+
+/// How soon to try again after the device reported itself busy.
+const TX_BUSY_RETRY: Duration = Duration::from_micros(50);
+
+// Rounding slack when comparing token counts, well under a byte.
+const TOKEN_EPSILON: f64 = 1e-6;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    /// Bytes that may be sent back to back after the sender has been idle.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Self {
+        assert!(bytes_per_sec > 0);
+        assert!(burst > 0);
+        Self {
+            bytes_per_sec,
+            burst,
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn set_limit(&mut self, limit: RateLimit) {
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    // Frames larger than the burst size go out once the bucket is full rather than never.
+    fn cost(&self, len: usize) -> f64 {
+        cmp::min(len as u64, self.limit.burst) as f64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        let tokens = self.tokens + elapsed * self.limit.bytes_per_sec as f64;
+        self.tokens = tokens.min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// How long until a `len` byte frame may be sent, or `None` if it may be sent now.
+    fn delay(&mut self, now: Instant, len: usize) -> Option<Duration> {
+        self.refill(now);
+        let deficit = self.cost(len) - self.tokens;
+        if deficit <= TOKEN_EPSILON {
+            return None;
+        }
+        let nanos = (deficit * 1e9 / self.limit.bytes_per_sec as f64).ceil() as u64;
+        Some(Duration::from_nanos(cmp::max(nanos, 1)))
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.tokens -= self.cost(len);
+    }
+}
+
+const NUM_CLASSES: usize = 3;
+
+/// Egress queues, highest priority first.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TrafficClass {
+    /// Handshakes, teardowns, pure ACKs and ICMP. Always used for these, regardless of the
+    /// flow's assigned class.
+    Control = 0,
+    Interactive = 1,
+    /// The default for data.
+    Bulk = 2,
+}
+
+/// How to pick the next frame from the per-class queues.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scheduling {
+    /// Always drain higher classes first. Bulk traffic can starve.
+    StrictPriority,
+    /// Send up to `weights[class]` frames from each class in turn. Weights must be nonzero.
+    WeightedRoundRobin([u32; NUM_CLASSES]),
+}
+
+impl Default for Scheduling {
+    fn default() -> Self {
+        Scheduling::StrictPriority
+    }
+}
+
+type FlowKey = (Endpoint, Endpoint);
+
+struct Flow<T> {
+    bucket: TokenBucket,
+    queue: VecDeque<(TrafficClass, T)>,
+}
+
+struct Inner<T> {
+    limit: Option<TokenBucket>,
+    flows: HashMap<FlowKey, Flow<T>>,
+    classes: HashMap<FlowKey, TrafficClass>,
+    // Frames that are past their flow's limit (if any) and waiting on the engine-wide one.
+    queues: [VecDeque<T>; NUM_CLASSES],
+    scheduling: Scheduling,
+    // Round robin position and the frames it may still send this turn.
+    wrr_class: usize,
+    wrr_credit: u32,
+    num_queued: usize,
+    num_dropped: u64,
+    // Times frames were held back because the device was busy.
+    num_tx_busy: u64,
+}
+
+impl<T: RuntimeBuf> Inner<T> {
+    fn new() -> Self {
+        Self {
+            limit: None,
+            flows: HashMap::new(),
+            classes: HashMap::new(),
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            scheduling: Scheduling::default(),
+            wrr_class: 0,
+            wrr_credit: 0,
+            num_queued: 0,
+            num_dropped: 0,
+            num_tx_busy: 0,
+        }
+    }
+
+    fn is_shaping(&self) -> bool {
+        self.limit.is_some() || !self.flows.is_empty()
+    }
+
+    fn enqueue(&mut self, frame: T) {
+        if self.num_queued >= MAX_QUEUED_FRAMES {
+            debug!("Egress queue full, dropping {} byte frame", frame.len());
+            self.num_dropped += 1;
+            return;
+        }
+        self.num_queued += 1;
+        let key = flow_key(&frame[..]);
+        let class = if is_control(&frame[..]) {
+            TrafficClass::Control
+        } else {
+            key.and_then(|k| self.classes.get(&k).cloned())
+                .unwrap_or(TrafficClass::Bulk)
+        };
+        let flow = match key {
+            Some(ref k) => self.flows.get_mut(k),
+            None => None,
+        };
+        match flow {
+            Some(flow) => flow.queue.push_back((class, frame)),
+            None => self.queues[class as usize].push_back(frame),
+        }
+    }
+
+    fn set_scheduling(&mut self, scheduling: Scheduling) {
+        if let Scheduling::WeightedRoundRobin(ref weights) = scheduling {
+            assert!(weights.iter().all(|&w| w > 0));
+            self.wrr_class = 0;
+            self.wrr_credit = weights[0];
+        }
+        self.scheduling = scheduling;
+    }
+
+    /// The class whose head frame goes next, if any are queued.
+    fn next_class(&mut self) -> Option<usize> {
+        match self.scheduling {
+            Scheduling::StrictPriority => (0..NUM_CLASSES).find(|&i| !self.queues[i].is_empty()),
+            Scheduling::WeightedRoundRobin(weights) => {
+                // Visiting every class once with fresh credit is enough to find a frame.
+                for _ in 0..=NUM_CLASSES {
+                    if self.wrr_credit > 0 && !self.queues[self.wrr_class].is_empty() {
+                        return Some(self.wrr_class);
+                    }
+                    self.wrr_class = (self.wrr_class + 1) % NUM_CLASSES;
+                    self.wrr_credit = weights[self.wrr_class];
+                }
+                None
+            },
+        }
+    }
+
+    fn dequeue(&mut self, class: usize) -> T {
+        if let Scheduling::WeightedRoundRobin(..) = self.scheduling {
+            self.wrr_credit -= 1;
+        }
+        self.num_queued -= 1;
+        self.queues[class].pop_front().unwrap()
+    }
+
+    /// Send everything queued right away, regardless of the buckets, as far as the device will
+    /// take it. Returns whether any frames are left.
+    fn flush<RT: Runtime<Buf = T>>(&mut self, rt: &RT) -> bool {
+        for flow in self.flows.values_mut() {
+            for (class, frame) in flow.queue.drain(..) {
+                self.queues[class as usize].push_back(frame);
+            }
+        }
+        while let Some(class) = self.next_class() {
+            if !rt.tx_ready() {
+                return true;
+            }
+            let frame = self.dequeue(class);
+            rt.transmit(Frame(frame));
+        }
+        false
+    }
+
+    /// Send everything the buckets allow at `now`, returning when the next queued frame may go.
+    fn pump<RT: Runtime<Buf = T>>(&mut self, rt: &RT, now: Instant) -> Option<Instant> {
+        let mut deadline: Option<Instant> = None;
+        let mut defer = |delay: Duration| {
+            let when = now + delay;
+            deadline = Some(deadline.map_or(when, |d| cmp::min(d, when)));
+        };
+        for flow in self.flows.values_mut() {
+            while let Some(len) = flow.queue.front().map(|(_, f)| f.len()) {
+                if let Some(delay) = flow.bucket.delay(now, len) {
+                    defer(delay);
+                    break;
+                }
+                flow.bucket.consume(len);
+                let (class, frame) = flow.queue.pop_front().unwrap();
+                self.queues[class as usize].push_back(frame);
+            }
+        }
+        while let Some(class) = self.next_class() {
+            if !rt.tx_ready() {
+                self.num_tx_busy += 1;
+                defer(TX_BUSY_RETRY);
+                break;
+            }
+            let len = self.queues[class].front().unwrap().len();
+            if let Some(ref mut bucket) = self.limit {
+                if let Some(delay) = bucket.delay(now, len) {
+                    defer(delay);
+                    break;
+                }
+                bucket.consume(len);
+            }
+            let frame = self.dequeue(class);
+            rt.transmit(Frame(frame));
+        }
+        deadline
+    }
+}
+
+// The (local, remote) endpoints of a serialized TCP or UDP frame.
+fn flow_key(frame: &[u8]) -> Option<FlowKey> {
+    let packet = PacketSummary::from_frame(frame)?;
+    let src_port = ip::Port::try_from(packet.src_port?).ok()?;
+    let dst_port = ip::Port::try_from(packet.dst_port?).ok()?;
+    Some((
+        Endpoint::new(packet.src_addr, src_port),
+        Endpoint::new(packet.dst_addr, dst_port),
+    ))
+}
+
+// Whether a serialized frame is connection control traffic rather than data.
+fn is_control(frame: &[u8]) -> bool {
+    let packet = match PacketSummary::from_frame(frame) {
+        Some(p) => p,
+        None => return true,
+    };
+    match packet.protocol {
+        Ipv4Protocol2::Icmpv4 => true,
+        Ipv4Protocol2::Udp | Ipv4Protocol2::Gre => false,
+        Ipv4Protocol2::Tcp => {
+            if packet.tcp_flags.unwrap_or(0) & (TCP_SYN | TCP_FIN | TCP_RST) != 0 {
+                return true;
+            }
+            // `from_frame` has already checked the IPv4 header's there.
+            let ip = &frame[ETHERNET2_HEADER_SIZE..];
+            let ihl = (ip[0] & 0xf) as usize * 4;
+            let total_len = NetworkEndian::read_u16(&ip[2..4]) as usize;
+            match ip.get(ihl + 12) {
+                Some(offset) => total_len <= ihl + (offset >> 4) as usize * 4,
+                None => true,
+            }
+        },
+    }
+}
+
+fn serialize<T: RuntimeBuf>(pkt: impl PacketBuf<T>) -> Vec<u8> {
+    let header_size = pkt.header_size();
+    let mut buf = vec![0u8; header_size + pkt.body_size()];
+    pkt.write_header(&mut buf[..header_size]);
+    if let Some(body) = pkt.take_body() {
+        buf[header_size..].copy_from_slice(&body[..]);
+    }
+    buf
+}
+
+// A frame that was serialized to be queued or inspected.
+struct Frame<T>(T);
+
+impl<T: RuntimeBuf> PacketBuf<T> for Frame<T> {
+    fn header_size(&self) -> usize {
+        0
+    }
+
+    fn write_header(&self, _buf: &mut [u8]) {}
+
+    fn body_size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn take_body(self) -> Option<T> {
+        Some(self.0)
+    }
+}
+
+pub struct Egress<RT: Runtime> {
+    rt: RT,
+    filter: Filter,
+    tunnel: Rc<RefCell<Option<gre::Tunnel>>>,
+    inner: Rc<RefCell<Inner<RT::Buf>>>,
+    // When the background coroutine should next release queued frames.
+    deadline: Rc<WatchedValue<Option<Instant>>>,
+    #[allow(unused)]
+    handle: Rc<SchedulerHandle>,
+}
+
+impl<RT: Runtime> Clone for Egress<RT> {
+    fn clone(&self) -> Self {
+        Self {
+            rt: self.rt.clone(),
+            filter: self.filter.clone(),
+            tunnel: self.tunnel.clone(),
+            inner: self.inner.clone(),
+            deadline: self.deadline.clone(),
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+impl<RT: Runtime> Egress<RT> {
+    pub fn new(rt: RT, filter: Filter) -> Self {
+        let inner = Rc::new(RefCell::new(Inner::new()));
+        let deadline = Rc::new(WatchedValue::new(None));
+        let future = Self::background(rt.clone(), inner.clone(), deadline.clone());
+        let handle = rt.spawn(future);
+        Self {
+            rt,
+            filter,
+            tunnel: Rc::new(RefCell::new(None)),
+            inner,
+            deadline,
+            handle: Rc::new(handle),
+        }
+    }
+
+    async fn background(
+        rt: RT,
+        inner: Rc<RefCell<Inner<RT::Buf>>>,
+        deadline: Rc<WatchedValue<Option<Instant>>>,
+    ) {
+        loop {
+            let (when, when_changed) = deadline.watch();
+            futures::pin_mut!(when_changed);
+
+            let wait_future = match when {
+                Some(t) => Either::Left(rt.wait_until(t).fuse()),
+                None => Either::Right(future::pending()),
+            };
+            futures::pin_mut!(wait_future);
+
+            futures::select_biased! {
+                _ = when_changed => continue,
+                _ = wait_future => {
+                    let next = inner.borrow_mut().pump(&rt, rt.now());
+                    deadline.set(next);
+                },
+            }
+        }
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    pub fn tunnel(&self) -> Option<gre::Tunnel> {
+        self.tunnel.borrow().clone()
+    }
+
+    pub fn set_tunnel(&self, tunnel: Option<gre::Tunnel>) {
+        *self.tunnel.borrow_mut() = tunnel;
+    }
+
+    /// Send `pkt` now, or queue it if a rate limit applies, unless the egress filter drops it.
+    pub fn transmit(&self, pkt: impl PacketBuf<RT::Buf>) {
+        if !self.filter.permits_egress(&pkt) {
+            return;
+        }
+        let tunnel = self.tunnel();
+        match tunnel {
+            Some(tunnel) => {
+                let frame = serialize(pkt);
+                if !tunnel.carries(&frame[..]) {
+                    self.send(Frame(RT::Buf::from_slice(&frame[..])));
+                } else {
+                    match tunnel.encapsulate(self.rt.local_ipv4_addr(), &frame[..]) {
+                        Ok(datagram) => self.send(datagram),
+                        Err(e) => warn!("Failed to encapsulate packet: {:?}", e),
+                    }
+                }
+            },
+            None => self.send(pkt),
+        }
+    }
+
+    fn send(&self, pkt: impl PacketBuf<RT::Buf>) {
+        let mut inner = self.inner.borrow_mut();
+        // Anything already queued for a busy device goes first.
+        if !inner.is_shaping() && inner.num_queued == 0 && self.rt.tx_ready() {
+            self.rt.transmit(pkt);
+            return;
+        }
+        inner.enqueue(RT::Buf::from_slice(&serialize(pkt)[..]));
+        self.pump(&mut inner);
+    }
+
+    fn pump(&self, inner: &mut Inner<RT::Buf>) {
+        let next = inner.pump(&self.rt, self.rt.now());
+        if self.deadline.get() != next {
+            self.deadline.set(next);
+        }
+    }
+
+    /// Limit everything the engine sends, or remove the limit with `None`.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        let mut inner = self.inner.borrow_mut();
+        let now = self.rt.now();
+        inner.limit = match (inner.limit.take(), limit) {
+            (Some(mut bucket), Some(limit)) => {
+                bucket.set_limit(limit);
+                Some(bucket)
+            },
+            (None, Some(limit)) => Some(TokenBucket::new(limit, now)),
+            (_, None) => None,
+        };
+        self.pump(&mut inner);
+    }
+
+    /// Limit the TCP or UDP traffic sent from `local` to `remote`, or remove the limit with
+    /// `None`. Engine-wide limits still apply on top.
+    pub fn set_flow_rate_limit(&self, local: Endpoint, remote: Endpoint, limit: Option<RateLimit>) {
+        let mut inner = self.inner.borrow_mut();
+        let now = self.rt.now();
+        let key = (local, remote);
+        match limit {
+            Some(limit) => match inner.flows.get_mut(&key) {
+                Some(flow) => flow.bucket.set_limit(limit),
+                None => {
+                    let flow = Flow {
+                        bucket: TokenBucket::new(limit, now),
+                        queue: VecDeque::new(),
+                    };
+                    inner.flows.insert(key, flow);
+                },
+            },
+            None => {
+                if let Some(flow) = inner.flows.remove(&key) {
+                    for (class, frame) in flow.queue {
+                        inner.queues[class as usize].push_back(frame);
+                    }
+                }
+            },
+        }
+        self.pump(&mut inner);
+    }
+
+    /// Assign the TCP or UDP traffic sent from `local` to `remote` a class, or return it to the
+    /// default with `None`. Classes only take effect while frames are queued behind a rate limit.
+    pub fn set_flow_class(&self, local: Endpoint, remote: Endpoint, class: Option<TrafficClass>) {
+        let mut inner = self.inner.borrow_mut();
+        match class {
+            Some(class) => inner.classes.insert((local, remote), class),
+            None => inner.classes.remove(&(local, remote)),
+        };
+    }
+
+    pub fn set_scheduling(&self, scheduling: Scheduling) {
+        let mut inner = self.inner.borrow_mut();
+        inner.set_scheduling(scheduling);
+        self.pump(&mut inner);
+    }
+
+    /// Send every frame held back by rate limits now. Frames the device can't take yet are still
+    /// retried as it frees up.
+    pub fn flush(&self) {
+        let next = if self.inner.borrow_mut().flush(&self.rt) {
+            Some(self.rt.now() + TX_BUSY_RETRY)
+        } else {
+            None
+        };
+        if self.deadline.get() != next {
+            self.deadline.set(next);
+        }
+    }
+
+    /// Frames currently held back by rate limits.
+    pub fn num_queued(&self) -> usize {
+        self.inner.borrow().num_queued
+    }
+
+    /// Frames dropped because too many were already queued.
+    pub fn num_dropped(&self) -> u64 {
+        self.inner.borrow().num_dropped
+    }
+
+    /// Times the device was too busy to take a frame, so the queues waited for it.
+    pub fn num_tx_busy(&self) -> u64 {
+        self.inner.borrow().num_tx_busy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Inner,
+        RateLimit,
+        Scheduling,
+        TokenBucket,
+        TrafficClass,
+    };
+    use crate::{
+        runtime::RuntimeBuf,
+        sync::Bytes,
+        test_helpers,
+    };
+    use futures::task::noop_waker_ref;
+    use std::{
+        future::Future,
+        task::Context,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(1000, 1500), now);
+
+        // A full bucket lets the burst through back to back.
+        assert_eq!(bucket.delay(now, 1000), None);
+        bucket.consume(1000);
+        assert_eq!(bucket.delay(now, 500), None);
+        bucket.consume(500);
+
+        // Then it refills at the configured rate.
+        assert_eq!(bucket.delay(now, 100), Some(Duration::from_millis(100)));
+        let later = now + Duration::from_millis(100);
+        assert_eq!(bucket.delay(later, 100), None);
+        bucket.consume(100);
+
+        // Frames bigger than the burst wait for a full bucket.
+        let later = later + Duration::from_secs(10);
+        assert_eq!(bucket.delay(later, 9000), None);
+        bucket.consume(9000);
+        assert_eq!(bucket.delay(later, 9000), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let mut inner = Inner::<Bytes>::new();
+        for &class in &[
+            TrafficClass::Control,
+            TrafficClass::Interactive,
+            TrafficClass::Bulk,
+        ] {
+            for _ in 0..4 {
+                inner.queues[class as usize].push_back(Bytes::empty());
+            }
+        }
+        inner.num_queued = 12;
+        let drain = |inner: &mut Inner<Bytes>, n: usize| {
+            let mut order = vec![];
+            for _ in 0..n {
+                let class = inner.next_class().unwrap();
+                inner.dequeue(class);
+                order.push(class);
+            }
+            order
+        };
+
+        inner.set_scheduling(Scheduling::WeightedRoundRobin([2, 1, 1]));
+        assert_eq!(drain(&mut inner, 8), vec![0, 0, 1, 2, 0, 0, 1, 2]);
+
+        // Empty classes are skipped, and strict priority drains in class order.
+        inner.set_scheduling(Scheduling::StrictPriority);
+        assert_eq!(drain(&mut inner, 4), vec![1, 1, 2, 2]);
+        assert_eq!(inner.next_class(), None);
+    }
+
+    #[test]
+    fn device_backpressure() {
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut now = Instant::now();
+        let alice = test_helpers::new_alice(now);
+        alice.rt().set_tx_ready(false);
+
+        // Nothing goes out while the device is busy, however long it stays that way.
+        let mut first = Box::pin(alice.ping(test_helpers::BOB_IPV4, None));
+        let mut second = Box::pin(alice.ping(test_helpers::CARRIE_IPV4, None));
+        assert!(Future::poll(first.as_mut(), &mut ctx).is_pending());
+        assert!(Future::poll(second.as_mut(), &mut ctx).is_pending());
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert_eq!(alice.rt().num_outgoing(), 0);
+        assert!(alice.stats().tx_busy > 0);
+
+        // Once it frees up, the held back frames follow in order.
+        alice.rt().set_tx_ready(true);
+        now += Duration::from_millis(1);
+        alice.rt().advance_clock(now);
+        alice.rt().poll_scheduler();
+        assert_eq!(alice.rt().num_outgoing(), 2);
+        let frames = [alice.rt().pop_frame(), alice.rt().pop_frame()];
+        assert_eq!(&frames[0][..6], &test_helpers::BOB_MAC.octets()[..]);
+        assert_eq!(&frames[1][..6], &test_helpers::CARRIE_MAC.octets()[..]);
+    }
+}
@@ -1,8 +1,17 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::protocols::ip;
-use std::net::Ipv4Addr;
+use crate::{
+    fail::Fail,
+    protocols::ip,
+};
+use std::{
+    convert::TryFrom,
+    net::{
+        Ipv4Addr,
+        SocketAddrV4,
+    },
+};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Ipv4Endpoint {
@@ -23,3 +32,18 @@ impl Ipv4Endpoint {
         self.port
     }
 }
+
+impl From<Ipv4Endpoint> for SocketAddrV4 {
+    fn from(endpoint: Ipv4Endpoint) -> Self {
+        SocketAddrV4::new(endpoint.addr, endpoint.port.into())
+    }
+}
+
+impl TryFrom<SocketAddrV4> for Ipv4Endpoint {
+    type Error = Fail;
+
+    fn try_from(addr: SocketAddrV4) -> Result<Self, Fail> {
+        let port = ip::Port::try_from(addr.port())?;
+        Ok(Ipv4Endpoint::new(*addr.ip(), port))
+    }
+}
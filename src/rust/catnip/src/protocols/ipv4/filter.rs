@@ -0,0 +1,457 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Stateless packet filter. Rules are evaluated in order and the first match decides what happens
+//! to the packet; packets that match no rule are accepted. Ingress rules run after IPv4 parsing
+//! and before transport demux, egress rules run on every IPv4 packet the engine transmits.
+
+use super::datagram::{
+    Ipv4Header,
+    Ipv4Protocol2,
+    IPV4_HEADER_SIZE,
+};
+use crate::{
+    fail::Fail,
+    protocols::ethernet2::frame::ETHERNET2_HEADER_SIZE,
+    runtime::PacketBuf,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    net::Ipv4Addr,
+    rc::Rc,
+};
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Accept,
+    Drop,
+    /// Drop the packet and tell the sender: a RST for TCP, an ICMP destination unreachable
+    /// otherwise. Egress packets are just dropped.
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Prefix {
+    pub addr: Ipv4Addr,
+    pub len: u8,
+}
+
+impl Prefix {
+    pub fn new(addr: Ipv4Addr, len: u8) -> Self {
+        assert!(len <= 32);
+        Self { addr, len }
+    }
+
+    fn mask(&self) -> u32 {
+        match self.len {
+            0 => 0,
+            n => !0u32 << (32 - n as u32),
+        }
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        (u32::from(addr) ^ u32::from(self.addr)) & self.mask() == 0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// `None` matches both directions.
+    pub direction: Option<Direction>,
+    pub protocol: Option<Ipv4Protocol2>,
+    pub src: Option<Prefix>,
+    pub dst: Option<Prefix>,
+    /// Inclusive port ranges. Rules with ports only match TCP and UDP.
+    pub src_ports: Option<(u16, u16)>,
+    pub dst_ports: Option<(u16, u16)>,
+    /// `(mask, value)`: matches TCP segments whose flags byte satisfies `flags & mask == value`.
+    pub tcp_flags: Option<(u8, u8)>,
+    pub action: Action,
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Self {
+            direction: None,
+            protocol: None,
+            src: None,
+            dst: None,
+            src_ports: None,
+            dst_ports: None,
+            tcp_flags: None,
+            action,
+        }
+    }
+
+    pub fn direction(mut self, value: Direction) -> Self {
+        self.direction = Some(value);
+        self
+    }
+
+    pub fn protocol(mut self, value: Ipv4Protocol2) -> Self {
+        self.protocol = Some(value);
+        self
+    }
+
+    pub fn src(mut self, addr: Ipv4Addr, len: u8) -> Self {
+        self.src = Some(Prefix::new(addr, len));
+        self
+    }
+
+    pub fn dst(mut self, addr: Ipv4Addr, len: u8) -> Self {
+        self.dst = Some(Prefix::new(addr, len));
+        self
+    }
+
+    pub fn src_ports(mut self, first: u16, last: u16) -> Self {
+        assert!(first <= last);
+        self.src_ports = Some((first, last));
+        self
+    }
+
+    pub fn dst_ports(mut self, first: u16, last: u16) -> Self {
+        assert!(first <= last);
+        self.dst_ports = Some((first, last));
+        self
+    }
+
+    pub fn tcp_flags(mut self, mask: u8, value: u8) -> Self {
+        assert_eq!(value & !mask, 0);
+        self.protocol = Some(Ipv4Protocol2::Tcp);
+        self.tcp_flags = Some((mask, value));
+        self
+    }
+
+    fn matches(&self, direction: Direction, packet: &PacketSummary) -> bool {
+        fn in_range(range: Option<(u16, u16)>, port: Option<u16>) -> bool {
+            match (range, port) {
+                (None, _) => true,
+                (Some((first, last)), Some(p)) => first <= p && p <= last,
+                (Some(..), None) => false,
+            }
+        }
+        if self.direction.map(|d| d != direction).unwrap_or(false) {
+            return false;
+        }
+        if self.protocol.map(|p| p != packet.protocol).unwrap_or(false) {
+            return false;
+        }
+        if self
+            .src
+            .map(|p| !p.contains(packet.src_addr))
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        if self
+            .dst
+            .map(|p| !p.contains(packet.dst_addr))
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        if !in_range(self.src_ports, packet.src_port) || !in_range(self.dst_ports, packet.dst_port)
+        {
+            return false;
+        }
+        match (self.tcp_flags, packet.tcp_flags) {
+            (None, _) => true,
+            (Some((mask, value)), Some(flags)) => flags & mask == value,
+            (Some(..), None) => false,
+        }
+    }
+}
+
+/// The fields rules can match on, pulled out of a packet without fully parsing it.
+#[derive(Clone, Copy, Debug)]
+pub struct PacketSummary {
+    pub protocol: Ipv4Protocol2,
+    pub src_addr: Ipv4Addr,
+    pub dst_addr: Ipv4Addr,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub tcp_flags: Option<u8>,
+}
+
+impl PacketSummary {
+    /// Summarize a parsed IPv4 header and its (transport) payload.
+    pub fn new(header: &Ipv4Header, payload: &[u8]) -> Self {
+        let (src_port, dst_port) = match header.protocol {
+            Ipv4Protocol2::Tcp | Ipv4Protocol2::Udp if payload.len() >= 4 => (
+                Some(NetworkEndian::read_u16(&payload[0..2])),
+                Some(NetworkEndian::read_u16(&payload[2..4])),
+            ),
+            _ => (None, None),
+        };
+        let tcp_flags = match header.protocol {
+            Ipv4Protocol2::Tcp if payload.len() >= 14 => Some(payload[13]),
+            _ => None,
+        };
+        Self {
+            protocol: header.protocol,
+            src_addr: header.src_addr,
+            dst_addr: header.dst_addr,
+            src_port,
+            dst_port,
+            tcp_flags,
+        }
+    }
+
+    /// Summarize a serialized Ethernet frame, if it carries an IPv4 packet we understand.
+    pub fn from_frame(frame: &[u8]) -> Option<Self> {
+        if frame.len() < ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE {
+            return None;
+        }
+        if NetworkEndian::read_u16(&frame[12..14]) != 0x0800 {
+            return None;
+        }
+        let ip = &frame[ETHERNET2_HEADER_SIZE..];
+        let ihl = (ip[0] & 0xf) as usize * 4;
+        if ihl < IPV4_HEADER_SIZE || ip.len() < ihl {
+            return None;
+        }
+        let header = Ipv4Header::new(
+            Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]),
+            Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]),
+            Ipv4Protocol2::try_from(ip[9]).ok()?,
+        );
+        Some(Self::new(&header, &ip[ihl..]))
+    }
+}
+
+pub type RuleId = u64;
+
+struct Entry {
+    id: RuleId,
+    rule: Rule,
+    hits: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    rules: Vec<Entry>,
+    next_id: RuleId,
+    num_egress: usize,
+}
+
+impl Inner {
+    fn alloc(&mut self, rule: Rule) -> Entry {
+        let id = self.next_id;
+        self.next_id += 1;
+        if rule.direction != Some(Direction::Ingress) {
+            self.num_egress += 1;
+        }
+        Entry { id, rule, hits: 0 }
+    }
+}
+
+/// Handle to the engine's rule table. Clones share the same table.
+#[derive(Clone, Default)]
+pub struct Filter {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a rule, returning its id.
+    pub fn push(&self, rule: Rule) -> RuleId {
+        let mut inner = self.inner.borrow_mut();
+        let entry = inner.alloc(rule);
+        let id = entry.id;
+        inner.rules.push(entry);
+        id
+    }
+
+    /// Insert a rule at position `index`, ahead of the rules already there.
+    pub fn insert(&self, index: usize, rule: Rule) -> Result<RuleId, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        if index > inner.rules.len() {
+            return Err(Fail::OutOfRange {
+                details: "Rule index past the end of the table",
+            });
+        }
+        let entry = inner.alloc(rule);
+        let id = entry.id;
+        inner.rules.insert(index, entry);
+        Ok(id)
+    }
+
+    pub fn remove(&self, id: RuleId) -> Result<Rule, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let ix = inner
+            .rules
+            .iter()
+            .position(|e| e.id == id)
+            .ok_or(Fail::ResourceNotFound {
+                details: "No such filter rule",
+            })?;
+        let entry = inner.rules.remove(ix);
+        if entry.rule.direction != Some(Direction::Ingress) {
+            inner.num_egress -= 1;
+        }
+        Ok(entry.rule)
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.rules.clear();
+        inner.num_egress = 0;
+    }
+
+    /// Number of packets that have matched rule `id`.
+    pub fn hits(&self, id: RuleId) -> Option<u64> {
+        let inner = self.inner.borrow();
+        inner.rules.iter().find(|e| e.id == id).map(|e| e.hits)
+    }
+
+    pub fn reset_hits(&self) {
+        for entry in &mut self.inner.borrow_mut().rules {
+            entry.hits = 0;
+        }
+    }
+
+    /// The current table, in evaluation order, with each rule's hit counter.
+    pub fn rules(&self) -> Vec<(RuleId, Rule, u64)> {
+        let inner = self.inner.borrow();
+        inner
+            .rules
+            .iter()
+            .map(|e| (e.id, e.rule.clone(), e.hits))
+            .collect()
+    }
+
+    pub fn evaluate(&self, direction: Direction, packet: &PacketSummary) -> Action {
+        let mut inner = self.inner.borrow_mut();
+        for entry in &mut inner.rules {
+            if entry.rule.matches(direction, packet) {
+                entry.hits += 1;
+                return entry.rule.action;
+            }
+        }
+        Action::Accept
+    }
+
+    /// Whether an outgoing packet gets past the egress rules. Cheap when there aren't any.
+    pub fn permits_egress<T>(&self, pkt: &impl PacketBuf<T>) -> bool {
+        if self.inner.borrow().num_egress == 0 {
+            return true;
+        }
+        let mut header = vec![0u8; pkt.header_size()];
+        pkt.write_header(&mut header[..]);
+        let packet = match PacketSummary::from_frame(&header[..]) {
+            Some(p) => p,
+            None => return true,
+        };
+        match self.evaluate(Direction::Egress, &packet) {
+            Action::Accept => true,
+            action => {
+                debug!("Egress filter {:?} for {:?}", action, packet);
+                false
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Action,
+        Direction,
+        Filter,
+        PacketSummary,
+        Prefix,
+        Rule,
+        TCP_ACK,
+        TCP_SYN,
+    };
+    use crate::protocols::ipv4::datagram::Ipv4Protocol2;
+    use std::net::Ipv4Addr;
+
+    fn tcp(src: [u8; 4], dst_port: u16, flags: u8) -> PacketSummary {
+        PacketSummary {
+            protocol: Ipv4Protocol2::Tcp,
+            src_addr: Ipv4Addr::from(src),
+            dst_addr: Ipv4Addr::new(192, 168, 1, 2),
+            src_port: Some(40000),
+            dst_port: Some(dst_port),
+            tcp_flags: Some(flags),
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        let p = Prefix::new(Ipv4Addr::new(10, 1, 0, 0), 16);
+        assert!(p.contains(Ipv4Addr::new(10, 1, 200, 3)));
+        assert!(!p.contains(Ipv4Addr::new(10, 2, 0, 1)));
+        assert!(Prefix::new(Ipv4Addr::new(0, 0, 0, 0), 0).contains(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let filter = Filter::new();
+        let allow = filter.push(
+            Rule::new(Action::Accept)
+                .src(Ipv4Addr::new(10, 0, 0, 0), 8)
+                .dst_ports(80, 80),
+        );
+        // Only new connections: SYN without ACK.
+        let block = filter.push(
+            Rule::new(Action::Reject)
+                .direction(Direction::Ingress)
+                .tcp_flags(TCP_SYN | TCP_ACK, TCP_SYN),
+        );
+
+        let trusted_syn = tcp([10, 0, 0, 1], 80, TCP_SYN);
+        let other_syn = tcp([172, 16, 0, 1], 80, TCP_SYN);
+        let other_ack = tcp([172, 16, 0, 1], 80, TCP_ACK);
+        assert_eq!(
+            filter.evaluate(Direction::Ingress, &trusted_syn),
+            Action::Accept
+        );
+        assert_eq!(
+            filter.evaluate(Direction::Ingress, &other_syn),
+            Action::Reject
+        );
+        assert_eq!(
+            filter.evaluate(Direction::Ingress, &other_ack),
+            Action::Accept
+        );
+        assert_eq!(
+            filter.evaluate(Direction::Egress, &other_syn),
+            Action::Accept
+        );
+
+        assert_eq!(filter.hits(allow), Some(1));
+        assert_eq!(filter.hits(block), Some(1));
+
+        filter.insert(0, Rule::new(Action::Drop)).unwrap();
+        assert_eq!(
+            filter.evaluate(Direction::Ingress, &trusted_syn),
+            Action::Drop
+        );
+        filter.remove(block).unwrap();
+        assert_eq!(filter.rules().len(), 2);
+        assert!(filter.hits(block).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::datagram::{
+    Ipv4Header,
+    IPV4_FLAG_MORE_FRAGMENTS,
+    IPV4_HEADER_SIZE,
+};
+use crate::{
+    protocols::ethernet2::frame::Ethernet2Header,
+    runtime::{
+        PacketBuf,
+        RuntimeBuf,
+    },
+};
+
+/// One piece of a datagram too large for the link, as `fragment` cuts it.
+pub struct Ipv4Fragment<T> {
+    pub ethernet2_hdr: Ethernet2Header,
+    pub ipv4_hdr: Ipv4Header,
+    pub data: T,
+}
+
+impl<T: RuntimeBuf> PacketBuf<T> for Ipv4Fragment<T> {
+    fn header_size(&self) -> usize {
+        self.ethernet2_hdr.compute_size() + self.ipv4_hdr.compute_size()
+    }
+
+    fn body_size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write_header(&self, buf: &mut [u8]) {
+        let eth_hdr_size = self.ethernet2_hdr.compute_size();
+        self.ethernet2_hdr.serialize(&mut buf[..eth_hdr_size]);
+        self.ipv4_hdr.serialize(&mut buf[eth_hdr_size..], self.data.len());
+    }
+
+    fn take_body(self) -> Option<T> {
+        Some(self.data)
+    }
+}
+
+/// Cut `payload`, already serialized with its transport header and checksum, into fragments of
+/// at most `mtu` bytes. Every fragment carries a copy of `ipv4_hdr`, whose identification should
+/// tell this datagram's fragments apart from those of others still in flight (RFC 791).
+pub fn fragment<T: RuntimeBuf>(
+    ethernet2_hdr: Ethernet2Header,
+    ipv4_hdr: Ipv4Header,
+    payload: T,
+    mtu: usize,
+) -> Vec<Ipv4Fragment<T>> {
+    // Offsets are counted in units of 8 bytes, so all but the last fragment are a multiple of 8.
+    let max_len = (mtu - IPV4_HEADER_SIZE) & !7;
+    assert!(max_len > 0);
+    let mut fragments = Vec::with_capacity((payload.len() + max_len - 1) / max_len);
+    let mut offset = 0;
+    while offset < payload.len() {
+        let end = std::cmp::min(offset + max_len, payload.len());
+        let mut data = payload.clone();
+        data.adjust(offset);
+        data.trim(payload.len() - end);
+
+        let mut ipv4_hdr = ipv4_hdr.clone();
+        ipv4_hdr.fragment_offset = (offset / 8) as u16;
+        if end < payload.len() {
+            ipv4_hdr.flags |= IPV4_FLAG_MORE_FRAGMENTS;
+        }
+        fragments.push(Ipv4Fragment {
+            ethernet2_hdr: ethernet2_hdr.clone(),
+            ipv4_hdr,
+            data,
+        });
+        offset = end;
+    }
+    fragments
+}
@@ -1,11 +1,36 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-// mod checksum;
+mod checksum;
 pub mod datagram;
+mod egress;
 mod endpoint;
+pub mod filter;
+mod fragment;
+mod options;
 mod peer;
+pub mod reassembly;
+mod rewrite;
+mod route;
 
+pub use checksum::adjust as adjust_checksum;
 pub use endpoint::Ipv4Endpoint as Endpoint;
+pub use egress::{
+    Egress,
+    RateLimit,
+    Scheduling,
+    TrafficClass,
+};
+pub use filter::Filter;
+pub use fragment::{
+    fragment,
+    Ipv4Fragment as Fragment,
+};
+pub use options::Ipv4Options as Options;
 pub use peer::Ipv4Peer as Peer;
+pub use rewrite::Ipv4Rewrite as Rewrite;
+pub use route::{
+    Interface,
+    Route,
+};
 pub use datagram::{Ipv4Header, Ipv4Protocol2};
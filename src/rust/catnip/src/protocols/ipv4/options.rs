@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Ipv4Options {
+    /// Largest IPv4 packet sent without fragmenting it. The backends take it from `Options::mtu`.
+    pub mtu: usize,
+    /// How long the fragments of a datagram are kept waiting for the rest (RFC 791 suggests 15
+    /// seconds; Linux waits 30).
+    pub reassembly_timeout: Duration,
+    /// The most fragment payload, in bytes, kept for any one source. Fragments beyond it are
+    /// dropped, so a sender that never finishes its datagrams can't use up our memory.
+    pub reassembly_limit: usize,
+}
+
+impl Default for Ipv4Options {
+    fn default() -> Self {
+        Ipv4Options {
+            mtu: 1500,
+            reassembly_timeout: Duration::from_secs(30),
+            reassembly_limit: 256 * 1024,
+        }
+    }
+}
+
+impl Ipv4Options {
+    pub fn mtu(mut self, value: usize) -> Self {
+        assert!(value >= 68);
+        self.mtu = value;
+        self
+    }
+
+    pub fn reassembly_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.reassembly_timeout = value;
+        self
+    }
+
+    pub fn reassembly_limit(mut self, value: usize) -> Self {
+        self.reassembly_limit = value;
+        self
+    }
+}
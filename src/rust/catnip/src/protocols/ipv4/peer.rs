@@ -1,59 +1,288 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::datagram::{
-    Ipv4Header,
-    Ipv4Protocol2,
+use super::{
+    datagram::{
+        Ipv4Header,
+        Ipv4Protocol2,
+    },
+    egress::Egress,
+    filter::{
+        Action,
+        Direction,
+        Filter,
+        PacketSummary,
+    },
+    reassembly::Reassembler,
+    route::{
+        Interface,
+        Route,
+    },
 };
 #[cfg(test)]
 use crate::file_table::FileDescriptor;
 use crate::{
+    event::EventQueue,
     fail::Fail,
     file_table::FileTable,
     protocols::{
         arp,
+        ethernet2::MacAddress,
         icmpv4,
+        ip::PortTable,
         tcp,
         udp,
     },
     runtime::Runtime,
+    stats::ChecksumErrors,
 };
 use std::{
     future::Future,
     net::Ipv4Addr,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 pub struct Ipv4Peer<RT: Runtime> {
     rt: RT,
+    arp: arp::Peer<RT>,
     icmpv4: icmpv4::Peer<RT>,
+    icmpv4_errors: icmpv4::ErrorSender<RT>,
+    reassembler: Reassembler<RT>,
     pub tcp: tcp::Peer<RT>,
     pub udp: udp::Peer<RT>,
+    ports: PortTable,
+    egress: Egress<RT>,
+    num_disabled: u64,
+    checksum_errors: ChecksumErrors,
 }
 
 impl<RT: Runtime> Ipv4Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Ipv4Peer<RT> {
-        let udp = udp::Peer::new(rt.clone(), arp.clone(), file_table.clone());
-        let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone());
-        let tcp = tcp::Peer::new(rt.clone(), arp, file_table);
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        events: EventQueue,
+    ) -> Ipv4Peer<RT> {
+        let egress = Egress::new(rt.clone(), Filter::new());
+        let ports = PortTable::new(&rt);
+        let icmpv4_errors = icmpv4::ErrorSender::new(rt.clone(), arp.clone(), egress.clone());
+        let udp = udp::Peer::new(
+            rt.clone(),
+            arp.clone(),
+            file_table.clone(),
+            ports.clone(),
+            egress.clone(),
+            icmpv4_errors.clone(),
+        );
+        let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone(), egress.clone());
+        let reassembler = Reassembler::new(rt.clone(), icmpv4_errors.clone());
+        let tcp = tcp::Peer::new(
+            rt.clone(),
+            arp.clone(),
+            file_table,
+            ports.clone(),
+            egress.clone(),
+            events,
+        );
         Ipv4Peer {
             rt,
+            arp,
             udp,
             icmpv4,
+            icmpv4_errors,
+            reassembler,
             tcp,
+            ports,
+            egress,
+            num_disabled: 0,
+            checksum_errors: ChecksumErrors::default(),
+        }
+    }
+
+    pub fn filter(&self) -> &Filter {
+        self.egress.filter()
+    }
+
+    pub fn ports(&self) -> &PortTable {
+        &self.ports
+    }
+
+    pub fn icmpv4(&self) -> &icmpv4::Peer<RT> {
+        &self.icmpv4
+    }
+
+    pub fn egress(&self) -> &Egress<RT> {
+        &self.egress
+    }
+
+    pub fn icmpv4_errors(&self) -> &icmpv4::ErrorSender<RT> {
+        &self.icmpv4_errors
+    }
+
+    pub fn reassembler(&self) -> &Reassembler<RT> {
+        &self.reassembler
+    }
+
+    /// Find how packets for `dst` would be sent, querying ARP for the next hop's link address if
+    /// it isn't cached, so failures show up before anything is sent. A next hop that doesn't answer
+    /// fails with `Fail::Timeout`.
+    pub fn resolve(&self, dst: Ipv4Addr) -> impl Future<Output = Result<Route, Fail>> {
+        let r = if dst.is_unspecified() || dst == self.rt.local_ipv4_addr() {
+            Err(Fail::Invalid {
+                details: "No route to that address",
+            })
+        } else if dst.is_multicast() {
+            Err(Fail::Unsupported {
+                details: "Multicast destinations",
+            })
+        } else {
+            Ok(())
+        };
+        let interface = match self.egress.tunnel() {
+            Some(ref tunnel) if tunnel.options().remote == dst => Interface::Gre,
+            _ => Interface::Ethernet,
+        };
+        let arp = self.arp.clone();
+        async move {
+            r?;
+            let link_addr = if dst.is_broadcast() {
+                MacAddress::broadcast()
+            } else {
+                arp.query(dst).await?
+            };
+            Ok(Route {
+                interface,
+                next_hop: dst,
+                link_addr,
+            })
+        }
+    }
+
+    /// Packets dropped because their protocol is switched off in the options.
+    pub fn num_disabled(&self) -> u64 {
+        self.num_disabled
+    }
+
+    pub fn checksum_errors(&self) -> ChecksumErrors {
+        self.checksum_errors
+    }
+
+    fn count_checksum_error(&mut self, protocol: Ipv4Protocol2, details: &str) {
+        let counter = match protocol {
+            Ipv4Protocol2::Icmpv4 if details == icmpv4::CHECKSUM_MISMATCH => {
+                &mut self.checksum_errors.icmpv4
+            },
+            Ipv4Protocol2::Tcp if details == tcp::segment::CHECKSUM_MISMATCH => {
+                &mut self.checksum_errors.tcp
+            },
+            Ipv4Protocol2::Udp if details == udp::datagram::CHECKSUM_MISMATCH => {
+                &mut self.checksum_errors.udp
+            },
+            _ => return,
+        };
+        *counter += 1;
+    }
+
+    fn is_disabled(&self, header: &Ipv4Header, payload: &[u8]) -> bool {
+        match header.protocol {
+            Ipv4Protocol2::Icmpv4 => {
+                payload.get(0) == Some(&icmpv4::ECHO_REQUEST)
+                    && !self.rt.icmpv4_options().echo_reply
+            },
+            Ipv4Protocol2::Udp => !self.rt.udp_options().enabled,
+            _ => false,
         }
     }
 
-    pub fn receive(&mut self, buf: RT::Buf) -> Result<(), Fail> {
+    /// Handle a packet that arrived at `rx_time`.
+    pub fn receive(&mut self, buf: RT::Buf, rx_time: Instant) -> Result<(), Fail> {
+        self.receive_packet(buf, false, rx_time)
+    }
+
+    fn receive_packet(
+        &mut self,
+        buf: RT::Buf,
+        tunneled: bool,
+        rx_time: Instant,
+    ) -> Result<(), Fail> {
         let (header, payload) = Ipv4Header::parse(buf)?;
         debug!("Ipv4 received {:?}", header);
         if header.dst_addr != self.rt.local_ipv4_addr() && !header.dst_addr.is_broadcast() {
             return Err(Fail::Misdelivered {});
         }
-        match header.protocol {
-            Ipv4Protocol2::Icmpv4 => self.icmpv4.receive(&header, payload),
-            Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload),
-            Ipv4Protocol2::Udp => self.udp.receive(&header, payload),
+        // Filters and protocols only ever see whole datagrams.
+        let (header, payload) = if header.is_fragment() {
+            match self.reassembler.receive(header, payload)? {
+                Some(datagram) => datagram,
+                None => return Ok(()),
+            }
+        } else {
+            (header, payload)
+        };
+        let packet = PacketSummary::new(&header, &payload[..]);
+        match self.egress.filter().evaluate(Direction::Ingress, &packet) {
+            Action::Accept => (),
+            Action::Drop => {
+                return Err(Fail::Ignored {
+                    details: "Dropped by packet filter",
+                })
+            },
+            Action::Reject => {
+                self.reject(&header, payload);
+                return Err(Fail::Ignored {
+                    details: "Rejected by packet filter",
+                });
+            },
+        }
+        if self.is_disabled(&header, &payload[..]) {
+            self.num_disabled += 1;
+            return Err(Fail::Ignored {
+                details: "Protocol disabled",
+            });
+        }
+        let r = match header.protocol {
+            Ipv4Protocol2::Icmpv4 => match self.icmpv4.receive(&header, payload)? {
+                // UDP leaves fitting the path MTU to the application.
+                Some(error) if error.protocol == Ipv4Protocol2::Tcp as u8 => {
+                    self.tcp.receive_fragmentation_needed(&error);
+                    Ok(())
+                },
+                _ => Ok(()),
+            },
+            Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload, rx_time),
+            Ipv4Protocol2::Udp => self.udp.receive(&header, payload, rx_time),
+            Ipv4Protocol2::Gre if tunneled => Err(Fail::Unsupported {
+                details: "Nested GRE tunnels are unsupported",
+            }),
+            Ipv4Protocol2::Gre => {
+                let tunnel = self.egress.tunnel().ok_or(Fail::Unsupported {
+                    details: "No GRE tunnel open",
+                })?;
+                let inner = tunnel.decapsulate(&header, payload)?;
+                // The inner packet's checksum errors are counted by the nested call.
+                return self.receive_packet(inner, true, rx_time);
+            },
+        };
+        if let Err(Fail::Malformed { details }) = r {
+            self.count_checksum_error(header.protocol, details);
+        }
+        r
+    }
+
+    fn reject(&mut self, header: &Ipv4Header, payload: RT::Buf) {
+        if header.protocol != Ipv4Protocol2::Tcp {
+            self.icmpv4_errors.destination_unreachable(
+                icmpv4::CODE_ADMIN_PROHIBITED,
+                header,
+                &payload[..],
+            );
+            return;
+        }
+        if let Err(e) = self.tcp.reject(header, payload) {
+            warn!("Failed to reject packet from {}: {:?}", header.src_addr, e);
         }
     }
 
@@ -64,6 +293,25 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     ) -> impl Future<Output = Result<Duration, Fail>> {
         self.icmpv4.ping(dest_ipv4_addr, timeout)
     }
+
+    pub fn ping_with_payload(
+        &self,
+        dest_ipv4_addr: Ipv4Addr,
+        payload: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<Duration, Fail>> {
+        self.icmpv4
+            .ping_with_payload(dest_ipv4_addr, payload, timeout)
+    }
+
+    pub fn ping_many(
+        &self,
+        destinations: &[Ipv4Addr],
+        concurrency: usize,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Vec<(Ipv4Addr, Result<Duration, Fail>)>> {
+        self.icmpv4.ping_many(destinations, concurrency, timeout)
+    }
 }
 
 #[cfg(test)]
@@ -71,8 +319,4 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     pub fn tcp_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
         self.tcp.remote_mss(fd)
     }
-
-    pub fn tcp_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
-        self.tcp.current_rto(fd)
-    }
 }
@@ -0,0 +1,313 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Reassembly of fragmented IPv4 datagrams (RFC 791 3.2, RFC 815). Fragments are held by datagram
+//! until the last hole is filled or `Ipv4Options::reassembly_timeout` passes, when the sender is
+//! told with an ICMP time exceeded error if its first fragment arrived (RFC 792). Fragments that
+//! overlap ones already held end their datagram, since they're either an attack or a bug (the
+//! rule RFC 5722 makes for IPv6), and each source may only have `reassembly_limit` bytes waiting.
+
+use super::datagram::{
+    Ipv4Header,
+    Ipv4Protocol2,
+    IPV4_FLAG_MORE_FRAGMENTS,
+    IPV4_HEADER_SIZE,
+};
+use crate::{
+    collections::watched::WatchedValue,
+    fail::Fail,
+    protocols::icmpv4,
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use futures::{
+    future::{
+        self,
+        Either,
+    },
+    FutureExt,
+};
+use std::{
+    cell::RefCell,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    net::Ipv4Addr,
+    rc::Rc,
+    time::Instant,
+};
+
+// The largest payload an IPv4 datagram can have.
+const MAX_PAYLOAD_SIZE: usize = 0xffff - IPV4_HEADER_SIZE;
+
+// RFC 791: Fragments belong to the same datagram if they agree on all of these.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct DatagramId {
+    src_addr: Ipv4Addr,
+    dst_addr: Ipv4Addr,
+    protocol: Ipv4Protocol2,
+    identification: u16,
+}
+
+struct PartialDatagram<T> {
+    // The first fragment's header, once it's arrived.
+    header: Option<Ipv4Header>,
+    // Payloads by byte offset, none overlapping.
+    fragments: BTreeMap<usize, T>,
+    // Known once the last fragment arrives.
+    total_len: Option<usize>,
+    num_bytes: usize,
+    deadline: Instant,
+}
+
+impl<T: RuntimeBuf> PartialDatagram<T> {
+    fn is_complete(&self) -> bool {
+        let total_len = match self.total_len {
+            Some(n) => n,
+            None => return false,
+        };
+        let mut expected = 0;
+        for (&offset, data) in &self.fragments {
+            if offset != expected {
+                return false;
+            }
+            expected += data.len();
+        }
+        expected == total_len
+    }
+
+    fn overlaps(&self, offset: usize, end: usize) -> bool {
+        let before = self.fragments.range(..offset).next_back();
+        let after = self.fragments.range(offset..).next();
+        before.map_or(false, |(&o, d)| o + d.len() > offset)
+            || after.map_or(false, |(&o, _)| o < end)
+    }
+}
+
+struct Inner<T> {
+    datagrams: HashMap<DatagramId, PartialDatagram<T>>,
+    // Bytes held for each source, counted against the limit.
+    memory: HashMap<Ipv4Addr, usize>,
+    num_timeouts: u64,
+    num_dropped: u64,
+}
+
+impl<T: RuntimeBuf> Inner<T> {
+    fn remove(&mut self, id: &DatagramId) -> Option<PartialDatagram<T>> {
+        let datagram = self.datagrams.remove(id)?;
+        let held = self.memory.get_mut(&id.src_addr).unwrap();
+        *held -= datagram.num_bytes;
+        if *held == 0 {
+            self.memory.remove(&id.src_addr);
+        }
+        Some(datagram)
+    }
+
+    // Drop the datagrams whose time is up, returning the first fragment of each that has one,
+    // to be quoted in an error, and when the next one expires.
+    fn expire(&mut self, now: Instant) -> (Vec<(Ipv4Header, T)>, Option<Instant>) {
+        let expired = self
+            .datagrams
+            .iter()
+            .filter(|(_, d)| d.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let mut first_fragments = vec![];
+        for id in expired {
+            let mut datagram = self.remove(&id).unwrap();
+            self.num_timeouts += 1;
+            if let (Some(header), Some(data)) = (datagram.header, datagram.fragments.remove(&0)) {
+                first_fragments.push((header, data));
+            }
+        }
+        (first_fragments, self.next_deadline())
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.datagrams.values().map(|d| d.deadline).min()
+    }
+}
+
+/// Fragments awaiting reassembly, with a background coroutine that times them out.
+pub struct Reassembler<RT: Runtime> {
+    rt: RT,
+    inner: Rc<RefCell<Inner<RT::Buf>>>,
+    // When the background coroutine should next look for expired datagrams.
+    deadline: Rc<WatchedValue<Option<Instant>>>,
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> Reassembler<RT> {
+    pub fn new(rt: RT, icmpv4_errors: icmpv4::ErrorSender<RT>) -> Self {
+        let inner = Rc::new(RefCell::new(Inner {
+            datagrams: HashMap::new(),
+            memory: HashMap::new(),
+            num_timeouts: 0,
+            num_dropped: 0,
+        }));
+        let deadline = Rc::new(WatchedValue::new(None));
+        let future = Self::background(rt.clone(), inner.clone(), deadline.clone(), icmpv4_errors);
+        let handle = rt.spawn(future);
+        Self {
+            rt,
+            inner,
+            deadline,
+            handle,
+        }
+    }
+
+    async fn background(
+        rt: RT,
+        inner: Rc<RefCell<Inner<RT::Buf>>>,
+        deadline: Rc<WatchedValue<Option<Instant>>>,
+        icmpv4_errors: icmpv4::ErrorSender<RT>,
+    ) {
+        loop {
+            let (when, when_changed) = deadline.watch();
+            futures::pin_mut!(when_changed);
+
+            let wait_future = match when {
+                Some(t) => Either::Left(rt.wait_until(t).fuse()),
+                None => Either::Right(future::pending()),
+            };
+            futures::pin_mut!(wait_future);
+
+            futures::select_biased! {
+                _ = when_changed => continue,
+                _ = wait_future => {
+                    let (expired, next) = inner.borrow_mut().expire(rt.now());
+                    for (header, data) in expired {
+                        debug!(
+                            "Reassembly of datagram {} from {} timed out",
+                            header.identification, header.src_addr
+                        );
+                        icmpv4_errors.time_exceeded(
+                            icmpv4::CODE_REASSEMBLY_TIME_EXCEEDED,
+                            &header,
+                            &data[..],
+                        );
+                    }
+                    deadline.set(next);
+                },
+            }
+        }
+    }
+
+    /// Datagrams dropped because the rest of their fragments didn't arrive in time.
+    pub fn num_timeouts(&self) -> u64 {
+        self.inner.borrow().num_timeouts
+    }
+
+    /// Fragments dropped because their source already had too much waiting.
+    pub fn num_dropped(&self) -> u64 {
+        self.inner.borrow().num_dropped
+    }
+
+    /// Datagrams with fragments waiting.
+    pub fn num_pending(&self) -> usize {
+        self.inner.borrow().datagrams.len()
+    }
+
+    /// Take a fragment, returning the datagram it completes, with its first fragment's header
+    /// made whole again, if it does.
+    pub fn receive(
+        &self,
+        header: Ipv4Header,
+        payload: RT::Buf,
+    ) -> Result<Option<(Ipv4Header, RT::Buf)>, Fail> {
+        let offset = header.fragment_offset as usize * 8;
+        let end = offset + payload.len();
+        let more = header.flags & IPV4_FLAG_MORE_FRAGMENTS != 0;
+        if end > MAX_PAYLOAD_SIZE {
+            return Err(Fail::Malformed {
+                details: "IPv4 fragment ends past the largest datagram",
+            });
+        }
+        if more && (payload.is_empty() || payload.len() % 8 != 0) {
+            return Err(Fail::Malformed {
+                details: "IPv4 fragment isn't a multiple of 8 bytes",
+            });
+        }
+        let id = DatagramId {
+            src_addr: header.src_addr,
+            dst_addr: header.dst_addr,
+            protocol: header.protocol,
+            identification: header.identification,
+        };
+
+        let mut inner = self.inner.borrow_mut();
+        let limit = self.rt.ipv4_options().reassembly_limit;
+        let held = inner.memory.get(&id.src_addr).cloned().unwrap_or(0);
+        if held + payload.len() > limit {
+            inner.num_dropped += 1;
+            return Err(Fail::ResourceExhausted {
+                details: "Too many IPv4 fragments from this source",
+            });
+        }
+        let deadline = self.rt.now() + self.rt.ipv4_options().reassembly_timeout;
+        let is_new = !inner.datagrams.contains_key(&id);
+        let datagram = inner
+            .datagrams
+            .entry(id)
+            .or_insert_with(|| PartialDatagram {
+                header: None,
+                fragments: BTreeMap::new(),
+                total_len: None,
+                num_bytes: 0,
+                deadline,
+            });
+        if let Some(existing) = datagram.fragments.get(&offset) {
+            // A retransmitted duplicate of a fragment we have.
+            if existing.len() == payload.len() && (more || datagram.total_len == Some(end)) {
+                return Ok(None);
+            }
+        }
+        // Fragments can't go past the last one, which can't move once it's arrived.
+        let bad_end = match datagram.total_len {
+            Some(total_len) => end > total_len || (!more && end != total_len),
+            None => !more && datagram.overlaps(end, MAX_PAYLOAD_SIZE),
+        };
+        if bad_end || datagram.overlaps(offset, end) {
+            inner.remove(&id);
+            return Err(Fail::Malformed {
+                details: "Overlapping IPv4 fragments",
+            });
+        }
+
+        if !more {
+            datagram.total_len = Some(end);
+        }
+        if offset == 0 {
+            datagram.header = Some(header);
+        }
+        datagram.num_bytes += payload.len();
+        datagram.fragments.insert(offset, payload);
+        let complete = datagram.is_complete();
+        *inner.memory.entry(id.src_addr).or_insert(0) += end - offset;
+        if is_new {
+            let next = inner.next_deadline();
+            if self.deadline.get() != next {
+                self.deadline.set(next);
+            }
+        }
+        if !complete {
+            return Ok(None);
+        }
+
+        let datagram = inner.remove(&id).unwrap();
+        let mut header = datagram.header.unwrap();
+        header.flags &= !IPV4_FLAG_MORE_FRAGMENTS;
+        header.fragment_offset = 0;
+        let mut buf = Vec::with_capacity(datagram.num_bytes);
+        for data in datagram.fragments.values() {
+            buf.extend_from_slice(&data[..]);
+        }
+        debug!("Reassembled datagram {} from {}", header.identification, header.src_addr);
+        Ok(Some((header, RT::Buf::from_slice(&buf[..]))))
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    checksum::adjust as adjust_checksum,
+    datagram::{
+        Ipv4Protocol2,
+        IPV4_HEADER_SIZE,
+    },
+};
+use crate::{
+    fail::Fail,
+    protocols::ip,
+    runtime::RuntimeBuf,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    cmp,
+    convert::TryFrom,
+    net::Ipv4Addr,
+};
+
+// Offsets into the IPv4 header.
+const TTL_OFFSET: usize = 8;
+const CHECKSUM_OFFSET: usize = 10;
+const SRC_ADDR_OFFSET: usize = 12;
+const DST_ADDR_OFFSET: usize = 16;
+
+/// A TCP, UDP or ICMP packet kept serialized, so a proxy or NAT can change its addresses, ports,
+/// TTL or payload bytes in place and send it on. Each change fixes up the IPv4 and transport
+/// checksums incrementally (RFC 1624), instead of the packet being parsed into headers, rebuilt
+/// and checksummed again from scratch. Checksums that were wrong to begin with stay wrong.
+#[derive(Clone, Debug)]
+pub struct Ipv4Rewrite<T: RuntimeBuf> {
+    buf: T,
+    protocol: Ipv4Protocol2,
+    // Where the transport header and the payload after it start.
+    transport_offset: usize,
+    payload_offset: usize,
+}
+
+impl<T: RuntimeBuf> Ipv4Rewrite<T> {
+    /// Take `packet`, which starts with its IPv4 header, to change in place; any link-layer
+    /// padding after the total length is trimmed off. It's only copied if it's still shared with
+    /// another buffer when it's first written to. Fragments are refused, as the transport
+    /// checksum covers the whole datagram.
+    pub fn new(mut packet: T) -> Result<Self, Fail> {
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return Err(Fail::Malformed {
+                details: "Not an IPv4 packet",
+            });
+        }
+        let protocol = match packet[9] {
+            1 => Ipv4Protocol2::Icmpv4,
+            6 => Ipv4Protocol2::Tcp,
+            17 => Ipv4Protocol2::Udp,
+            _ => {
+                return Err(Fail::Unsupported {
+                    details: "Only TCP, UDP and ICMP packets can be rewritten",
+                })
+            },
+        };
+        if NetworkEndian::read_u16(&packet[6..8]) & 0x3fff != 0 {
+            return Err(Fail::Unsupported {
+                details: "Fragments can't be rewritten",
+            });
+        }
+        let ihl = (packet[0] & 0xf) as usize * 4;
+        let total_len = NetworkEndian::read_u16(&packet[2..4]) as usize;
+        let min_transport_len = match protocol {
+            Ipv4Protocol2::Tcp => 20,
+            _ => 8,
+        };
+        if ihl < IPV4_HEADER_SIZE || total_len < ihl + min_transport_len || total_len > packet.len()
+        {
+            return Err(Fail::Malformed {
+                details: "Packet too short to rewrite",
+            });
+        }
+        let transport_len = match protocol {
+            Ipv4Protocol2::Tcp => (packet[ihl + 12] >> 4) as usize * 4,
+            _ => 8,
+        };
+        if transport_len < min_transport_len || ihl + transport_len > total_len {
+            return Err(Fail::Malformed {
+                details: "Invalid TCP data offset",
+            });
+        }
+        let padding = packet.len() - total_len;
+        packet.trim(padding);
+        Ok(Self {
+            buf: packet,
+            protocol,
+            transport_offset: ihl,
+            payload_offset: ihl + transport_len,
+        })
+    }
+
+    pub fn protocol(&self) -> Ipv4Protocol2 {
+        self.protocol
+    }
+
+    pub fn src_addr(&self) -> Ipv4Addr {
+        addr(&self.buf[SRC_ADDR_OFFSET..])
+    }
+
+    pub fn dst_addr(&self) -> Ipv4Addr {
+        addr(&self.buf[DST_ADDR_OFFSET..])
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.buf[TTL_OFFSET]
+    }
+
+    /// The ports, for TCP and UDP; zero isn't a valid port, so it's `None` too.
+    pub fn src_port(&self) -> Option<ip::Port> {
+        self.port(0)
+    }
+
+    pub fn dst_port(&self) -> Option<ip::Port> {
+        self.port(2)
+    }
+
+    /// The TCP, UDP or ICMP header, options included.
+    pub fn transport_header(&self) -> &[u8] {
+        &self.buf[self.transport_offset..self.payload_offset]
+    }
+
+    /// What follows the transport header.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[self.payload_offset..]
+    }
+
+    /// The whole packet as it now stands.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    pub fn into_buf(self) -> T {
+        self.buf
+    }
+
+    pub fn set_src_addr(&mut self, addr: Ipv4Addr) {
+        self.patch(SRC_ADDR_OFFSET, &addr.octets(), self.has_pseudo_header());
+    }
+
+    pub fn set_dst_addr(&mut self, addr: Ipv4Addr) {
+        self.patch(DST_ADDR_OFFSET, &addr.octets(), self.has_pseudo_header());
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.patch(TTL_OFFSET, &[ttl], false);
+    }
+
+    pub fn set_src_port(&mut self, port: ip::Port) -> Result<(), Fail> {
+        self.set_port(0, port)
+    }
+
+    pub fn set_dst_port(&mut self, port: ip::Port) -> Result<(), Fail> {
+        self.set_port(2, port)
+    }
+
+    /// Overwrite payload bytes from `offset` on. The payload's length can't change.
+    pub fn write_payload(&mut self, offset: usize, data: &[u8]) -> Result<(), Fail> {
+        if offset + data.len() > self.payload().len() {
+            return Err(Fail::OutOfRange {
+                details: "Write past the end of the payload",
+            });
+        }
+        self.patch(self.payload_offset + offset, data, true);
+        Ok(())
+    }
+
+    fn has_pseudo_header(&self) -> bool {
+        self.protocol != Ipv4Protocol2::Icmpv4
+    }
+
+    fn port(&self, offset: usize) -> Option<ip::Port> {
+        if !self.has_pseudo_header() {
+            return None;
+        }
+        let offset = self.transport_offset + offset;
+        ip::Port::try_from(NetworkEndian::read_u16(&self.buf[offset..])).ok()
+    }
+
+    fn set_port(&mut self, offset: usize, port: ip::Port) -> Result<(), Fail> {
+        if !self.has_pseudo_header() {
+            return Err(Fail::Unsupported {
+                details: "ICMP has no ports",
+            });
+        }
+        let mut bytes = [0u8; 2];
+        NetworkEndian::write_u16(&mut bytes, port.into());
+        self.patch(self.transport_offset + offset, &bytes, true);
+        Ok(())
+    }
+
+    // Write `data` at `offset`, updating the IPv4 header's checksum if it's in the header and the
+    // transport checksum if `transport` says it covers it. Checksums sum 16-bit words, so the
+    // change is widened to whole words, with a missing last byte counting as zero; IPv4 headers
+    // are a multiple of 4 bytes, so words line up the same way in every checksum.
+    fn patch(&mut self, offset: usize, data: &[u8], transport: bool) {
+        let start = offset & !1;
+        let end = (offset + data.len() + 1) & !1;
+        let mut old = vec![0u8; end - start];
+        let available = cmp::min(end, self.buf.len()) - start;
+        old[..available].copy_from_slice(&self.buf[start..(start + available)]);
+        let mut new = old.clone();
+        new[(offset - start)..(offset - start + data.len())].copy_from_slice(data);
+
+        let protocol = self.protocol;
+        let header = start < self.transport_offset;
+        let checksum_offset = self.transport_offset
+            + match protocol {
+                Ipv4Protocol2::Tcp => 16,
+                Ipv4Protocol2::Udp => 6,
+                _ => 2,
+            };
+        let buf = self.bytes_mut();
+        if header {
+            let checksum = NetworkEndian::read_u16(&buf[CHECKSUM_OFFSET..]);
+            let checksum = adjust_checksum(checksum, &old, &new);
+            NetworkEndian::write_u16(&mut buf[CHECKSUM_OFFSET..], checksum);
+        }
+        if transport {
+            let checksum = NetworkEndian::read_u16(&buf[checksum_offset..]);
+            // A zero UDP checksum means the sender didn't compute one.
+            if protocol != Ipv4Protocol2::Udp || checksum != 0 {
+                let mut checksum = adjust_checksum(checksum, &old, &new);
+                if protocol == Ipv4Protocol2::Udp && checksum == 0 {
+                    checksum = 0xffff;
+                }
+                NetworkEndian::write_u16(&mut buf[checksum_offset..], checksum);
+            }
+        }
+        buf[offset..(offset + data.len())].copy_from_slice(data);
+    }
+
+    // The packet's bytes to write to, copying them first if another buffer shares them.
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        if self.buf.get_mut().is_none() {
+            self.buf = T::from_slice(&self.buf[..]);
+        }
+        self.buf.get_mut().unwrap()
+    }
+}
+
+fn addr(buf: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])
+}
@@ -0,0 +1,24 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ethernet2::MacAddress;
+use std::net::Ipv4Addr;
+
+/// Where packets leave the engine.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interface {
+    /// Straight onto the link.
+    Ethernet,
+    /// Wrapped by the open GRE tunnel, whose remote end they're addressed to.
+    Gre,
+}
+
+/// How packets for a destination are sent, found by `Peer::resolve`. There's no routing table:
+/// every destination is on the link, so the next hop is the destination itself.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Route {
+    pub interface: Interface,
+    pub next_hop: Ipv4Addr,
+    /// The next hop's link address, which frames are sent to.
+    pub link_addr: MacAddress,
+}
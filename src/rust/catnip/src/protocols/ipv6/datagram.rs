@@ -0,0 +1,174 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    runtime::RuntimeBuf,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    convert::TryInto,
+    net::Ipv6Addr,
+};
+
+pub const IPV6_HEADER_SIZE: usize = 40;
+pub const IPV6_VERSION: u8 = 6;
+
+/// RFC 4861 requires NDP messages to carry this, so that they can't have come from off-link.
+pub const NDP_HOP_LIMIT: u8 = 255;
+pub const DEFAULT_IPV6_HOP_LIMIT: u8 = 64;
+
+/// Next header values (the IANA protocol numbers IPv4 also uses, plus IPv6's extension headers).
+pub const NEXT_HEADER_HOP_BY_HOP: u8 = 0;
+pub const NEXT_HEADER_TCP: u8 = 6;
+pub const NEXT_HEADER_UDP: u8 = 17;
+pub const NEXT_HEADER_ROUTING: u8 = 43;
+pub const NEXT_HEADER_FRAGMENT: u8 = 44;
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+pub const NEXT_HEADER_NONE: u8 = 59;
+pub const NEXT_HEADER_DESTINATION_OPTIONS: u8 = 60;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ipv6Header {
+    pub traffic_class: u8,
+    // 20 bits.
+    pub flow_label: u32,
+    // Omit the payload length since it's generated on serialization.
+    // pub payload_length: u16,
+    /// After parsing, the upper-layer protocol that follows any extension headers, which are
+    /// skipped. We never send extension headers.
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src_addr: Ipv6Addr,
+    pub dst_addr: Ipv6Addr,
+}
+
+impl Ipv6Header {
+    pub fn new(src_addr: Ipv6Addr, dst_addr: Ipv6Addr, next_header: u8) -> Self {
+        Self {
+            traffic_class: 0,
+            flow_label: 0,
+            next_header,
+            hop_limit: DEFAULT_IPV6_HOP_LIMIT,
+            src_addr,
+            dst_addr,
+        }
+    }
+
+    pub fn compute_size(&self) -> usize {
+        IPV6_HEADER_SIZE
+    }
+
+    pub fn parse<T: RuntimeBuf>(mut buf: T) -> Result<(Self, T), Fail> {
+        if buf.len() < IPV6_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "Datagram too small",
+            });
+        }
+        let hdr_buf = &buf[..IPV6_HEADER_SIZE];
+        let version = hdr_buf[0] >> 4;
+        if version != IPV6_VERSION {
+            return Err(Fail::Unsupported {
+                details: "Unsupported IP version",
+            });
+        }
+        let first_word = NetworkEndian::read_u32(&hdr_buf[0..4]);
+        let traffic_class = (first_word >> 20) as u8;
+        let flow_label = first_word & 0xfffff;
+
+        let payload_length = NetworkEndian::read_u16(&hdr_buf[4..6]) as usize;
+        if IPV6_HEADER_SIZE + payload_length > buf.len() {
+            return Err(Fail::Malformed {
+                details: "IPv6 payload length greater than payload",
+            });
+        }
+        let mut next_header = hdr_buf[6];
+        let hop_limit = hdr_buf[7];
+        let src_addr = Ipv6Addr::from(read_addr(&hdr_buf[8..24]));
+        let dst_addr = Ipv6Addr::from(read_addr(&hdr_buf[24..40]));
+
+        // As with IPv4, Ethernet may have padded out a short packet.
+        let padding_bytes = buf.len() - IPV6_HEADER_SIZE - payload_length;
+        buf.adjust(IPV6_HEADER_SIZE);
+        buf.trim(padding_bytes);
+
+        // RFC 8200 4: Skip the extension headers we can, each of which starts with the next
+        // header's type and its own length in 8 byte units, not counting the first 8.
+        loop {
+            match next_header {
+                NEXT_HEADER_HOP_BY_HOP | NEXT_HEADER_ROUTING | NEXT_HEADER_DESTINATION_OPTIONS => {
+                    if buf.len() < 8 {
+                        return Err(Fail::Malformed {
+                            details: "IPv6 extension header too small",
+                        });
+                    }
+                    let len = (buf[1] as usize + 1) * 8;
+                    if buf.len() < len {
+                        return Err(Fail::Malformed {
+                            details: "IPv6 extension header overruns the payload",
+                        });
+                    }
+                    next_header = buf[0];
+                    buf.adjust(len);
+                },
+                NEXT_HEADER_FRAGMENT => {
+                    return Err(Fail::Unsupported {
+                        details: "IPv6 fragmentation is unsupported",
+                    })
+                },
+                NEXT_HEADER_NONE => {
+                    return Err(Fail::Ignored {
+                        details: "IPv6 packet without a payload",
+                    })
+                },
+                _ => break,
+            }
+        }
+
+        let header = Self {
+            traffic_class,
+            flow_label,
+            next_header,
+            hop_limit,
+            src_addr,
+            dst_addr,
+        };
+        Ok((header, buf))
+    }
+
+    pub fn serialize(&self, buf: &mut [u8], payload_len: usize) {
+        let buf: &mut [u8; IPV6_HEADER_SIZE] = buf.try_into().unwrap();
+        let first_word = (IPV6_VERSION as u32) << 28
+            | (self.traffic_class as u32) << 20
+            | self.flow_label & 0xfffff;
+        NetworkEndian::write_u32(&mut buf[0..4], first_word);
+        NetworkEndian::write_u16(&mut buf[4..6], payload_len as u16);
+        buf[6] = self.next_header;
+        buf[7] = self.hop_limit;
+        buf[8..24].copy_from_slice(&self.src_addr.octets());
+        buf[24..40].copy_from_slice(&self.dst_addr.octets());
+    }
+
+    /// The ones' complement sum of the pseudo-header upper-layer checksums cover (RFC 8200 8.1),
+    /// to be folded in with the upper-layer packet's own bytes.
+    pub fn pseudo_header_sum(&self, upper_layer_len: usize) -> u32 {
+        let mut state = 0u32;
+        for addr in &[self.src_addr, self.dst_addr] {
+            for segment in addr.segments().iter() {
+                state += *segment as u32;
+            }
+        }
+        state += (upper_layer_len >> 16) as u32 + (upper_layer_len & 0xffff) as u32;
+        state += self.next_header as u32;
+        state
+    }
+}
+
+fn read_addr(buf: &[u8]) -> [u8; 16] {
+    let mut addr = [0u8; 16];
+    addr.copy_from_slice(buf);
+    addr
+}
@@ -0,0 +1,56 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! IPv6 (RFC 8200) alongside IPv4. So far the engine only speaks NDP over it, resolving and
+//! answering for its link-local address; TCP and UDP remain IPv4-only.
+
+pub mod datagram;
+mod peer;
+
+#[cfg(test)]
+mod tests;
+
+pub use datagram::Ipv6Header;
+pub use peer::Ipv6Peer as Peer;
+
+use crate::protocols::ethernet2::MacAddress;
+use std::net::Ipv6Addr;
+
+/// Every node on the link (RFC 4291 2.7.1).
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// The link-local address formed from `link_addr` as a modified EUI-64 interface identifier
+/// (RFC 4291 appendix A), which is ours unless the runtime says otherwise.
+pub fn link_local_addr(link_addr: MacAddress) -> Ipv6Addr {
+    let mac = link_addr.octets();
+    let mut addr = [0u8; 16];
+    addr[0] = 0xfe;
+    addr[1] = 0x80;
+    addr[8] = mac[0] ^ 0x02;
+    addr[9] = mac[1];
+    addr[10] = mac[2];
+    addr[11] = 0xff;
+    addr[12] = 0xfe;
+    addr[13] = mac[3];
+    addr[14] = mac[4];
+    addr[15] = mac[5];
+    Ipv6Addr::from(addr)
+}
+
+/// The multicast group a node joins to hear neighbor solicitations for `addr` (RFC 4291 2.7.1).
+pub fn solicited_node_addr(addr: Ipv6Addr) -> Ipv6Addr {
+    let octets = addr.octets();
+    let mut group = [0u8; 16];
+    group[0] = 0xff;
+    group[1] = 0x02;
+    group[11] = 0x01;
+    group[12] = 0xff;
+    group[13..].copy_from_slice(&octets[13..]);
+    Ipv6Addr::from(group)
+}
+
+/// The Ethernet address IPv6 multicast to `group` is sent to (RFC 2464 7).
+pub fn multicast_link_addr(group: Ipv6Addr) -> MacAddress {
+    let octets = group.octets();
+    MacAddress::new([0x33, 0x33, octets[12], octets[13], octets[14], octets[15]])
+}
@@ -0,0 +1,68 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    datagram::{
+        Ipv6Header,
+        NEXT_HEADER_ICMPV6,
+    },
+    ALL_NODES,
+};
+use crate::{
+    fail::Fail,
+    protocols::{
+        ethernet2::MacAddress,
+        ipv6,
+        ndp,
+        ndp::pdu::{
+            ICMPV6_NEIGHBOR_ADVERTISEMENT,
+            ICMPV6_NEIGHBOR_SOLICITATION,
+        },
+    },
+    runtime::Runtime,
+};
+
+pub struct Ipv6Peer<RT: Runtime> {
+    rt: RT,
+    ndp: ndp::Peer<RT>,
+}
+
+impl<RT: Runtime> Ipv6Peer<RT> {
+    pub fn new(rt: RT) -> Self {
+        let ndp = ndp::Peer::new(rt.clone());
+        Self { rt, ndp }
+    }
+
+    pub fn ndp(&self) -> &ndp::Peer<RT> {
+        &self.ndp
+    }
+
+    /// Whether frames to `link_addr` are for us: besides our own address, there are the
+    /// multicast addresses of the all-nodes group and of our solicited-node group.
+    pub fn accepts(&self, link_addr: MacAddress) -> bool {
+        let solicited_node = ipv6::solicited_node_addr(self.rt.local_ipv6_addr());
+        link_addr == ipv6::multicast_link_addr(ALL_NODES)
+            || link_addr == ipv6::multicast_link_addr(solicited_node)
+    }
+
+    pub fn receive(&mut self, buf: RT::Buf) -> Result<(), Fail> {
+        let (header, payload) = Ipv6Header::parse(buf)?;
+        debug!("Ipv6 received {:?}", header);
+        let local_ipv6_addr = self.rt.local_ipv6_addr();
+        if header.dst_addr != local_ipv6_addr
+            && header.dst_addr != ALL_NODES
+            && header.dst_addr != ipv6::solicited_node_addr(local_ipv6_addr)
+        {
+            return Err(Fail::Misdelivered {});
+        }
+        match (header.next_header, payload.get(0)) {
+            (NEXT_HEADER_ICMPV6, Some(&ICMPV6_NEIGHBOR_SOLICITATION))
+            | (NEXT_HEADER_ICMPV6, Some(&ICMPV6_NEIGHBOR_ADVERTISEMENT)) => {
+                self.ndp.receive(&header, payload)
+            },
+            _ => Err(Fail::Unsupported {
+                details: "Only NDP is supported over IPv6",
+            }),
+        }
+    }
+}
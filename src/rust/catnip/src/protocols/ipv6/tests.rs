@@ -0,0 +1,91 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    datagram::{
+        Ipv6Header,
+        IPV6_HEADER_SIZE,
+        NEXT_HEADER_DESTINATION_OPTIONS,
+        NEXT_HEADER_FRAGMENT,
+        NEXT_HEADER_HOP_BY_HOP,
+        NEXT_HEADER_UDP,
+    },
+    link_local_addr,
+    multicast_link_addr,
+    solicited_node_addr,
+};
+use crate::{
+    fail::Fail,
+    protocols::ethernet2::MacAddress,
+    sync::Bytes,
+    test_helpers,
+};
+use must_let::must_let;
+use std::net::Ipv6Addr;
+
+fn datagram(hdr: &Ipv6Header, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; IPV6_HEADER_SIZE + payload.len()];
+    hdr.serialize(&mut buf[..IPV6_HEADER_SIZE], payload.len());
+    buf[IPV6_HEADER_SIZE..].copy_from_slice(payload);
+    buf
+}
+
+#[test]
+fn parse_round_trip() {
+    let mut hdr = Ipv6Header::new(
+        link_local_addr(test_helpers::ALICE_MAC),
+        link_local_addr(test_helpers::BOB_MAC),
+        NEXT_HEADER_UDP,
+    );
+    hdr.traffic_class = 0xb8;
+    hdr.flow_label = 0x12345;
+    let mut buf = datagram(&hdr, b"payload");
+    // Ethernet padding past the payload length is trimmed off.
+    buf.extend_from_slice(&[0u8; 5]);
+
+    let (parsed, payload) = Ipv6Header::parse(Bytes::from_slice(&buf)).unwrap();
+    assert_eq!(parsed, hdr);
+    assert_eq!(&payload[..], b"payload");
+}
+
+#[test]
+fn extension_headers_are_skipped() {
+    let hdr = Ipv6Header::new(
+        link_local_addr(test_helpers::ALICE_MAC),
+        link_local_addr(test_helpers::BOB_MAC),
+        NEXT_HEADER_HOP_BY_HOP,
+    );
+    let mut payload = vec![];
+    // An 8 byte hop-by-hop header, then a 16 byte destination options header.
+    payload.extend_from_slice(&[NEXT_HEADER_DESTINATION_OPTIONS, 0, 1, 4, 0, 0, 0, 0]);
+    payload.extend_from_slice(&[NEXT_HEADER_UDP, 1, 1, 12]);
+    payload.extend_from_slice(&[0u8; 12]);
+    payload.extend_from_slice(b"payload");
+
+    let (parsed, rest) = Ipv6Header::parse(Bytes::from_slice(&datagram(&hdr, &payload))).unwrap();
+    assert_eq!(parsed.next_header, NEXT_HEADER_UDP);
+    assert_eq!(&rest[..], b"payload");
+
+    // A header running past the end of the payload is malformed.
+    let truncated = datagram(&hdr, &payload[..20]);
+    must_let!(let Err(Fail::Malformed { .. }) = Ipv6Header::parse(Bytes::from_slice(&truncated)));
+
+    // Fragments aren't reassembled.
+    let mut fragment = payload.clone();
+    fragment[8] = NEXT_HEADER_FRAGMENT;
+    let fragment = datagram(&hdr, &fragment);
+    must_let!(let Err(Fail::Unsupported { .. }) = Ipv6Header::parse(Bytes::from_slice(&fragment)));
+}
+
+#[test]
+fn addresses() {
+    let mac = MacAddress::new([0x00, 0x15, 0x5d, 0x01, 0x02, 0x03]);
+    let addr = link_local_addr(mac);
+    assert_eq!(addr, "fe80::215:5dff:fe01:203".parse::<Ipv6Addr>().unwrap());
+    let group = solicited_node_addr(addr);
+    assert_eq!(group, "ff02::1:ff01:203".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(
+        multicast_link_addr(group),
+        MacAddress::new([0x33, 0x33, 0xff, 0x01, 0x02, 0x03])
+    );
+}
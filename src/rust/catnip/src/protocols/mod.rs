@@ -2,9 +2,18 @@
 // Licensed under the MIT license.
 
 pub mod arp;
+pub mod dhcp;
+pub mod dns;
 pub mod ethernet2;
+pub mod gre;
 pub mod icmpv4;
 pub mod ip;
 pub mod ipv4;
+pub mod ipv6;
+pub mod nat;
+pub mod ndp;
+pub mod packet;
+pub mod sntp;
 pub mod tcp;
 pub mod udp;
+pub mod vxlan;
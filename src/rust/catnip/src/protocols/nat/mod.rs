@@ -0,0 +1,34 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Port-translating NAT (NAPT) for using the engine as a middlebox in testbeds.
+//!
+//! Once `Engine::nat_start` is called, the engine forwards IPv4 traffic between hosts on an
+//! inside network, which use it as their gateway, and hosts outside it. TCP and UDP packets
+//! leaving the inside network get the engine's own address and a port allocated from the
+//! configured range as their source; replies to that port are translated back and forwarded to
+//! the inside host. Static mappings forward an external port to a fixed inside endpoint, like
+//! port forwarding on a home router.
+//!
+//! Dynamic mappings are endpoint-dependent: each (inside, remote) pair gets its own external
+//! port, and only the remote it was created for can use it. Connection tracking ages them out on
+//! the timeouts in `Options`, with TCP connections moving to the shorter transitory timeout once
+//! they've been reset or closed in both directions.
+//!
+//! Addresses and ports are rewritten in place, and the IPv4, TCP and UDP checksums updated
+//! incrementally (RFC 1624). Packets for the engine's address that don't match a mapping go to
+//! its own stack as usual. Fragments, ICMP and other protocols aren't translated.
+
+mod options;
+mod table;
+mod translator;
+
+#[cfg(test)]
+mod tests;
+
+pub use options::NatOptions as Options;
+pub use table::Mapping;
+pub use translator::{
+    Nat,
+    NatStats as Stats,
+};
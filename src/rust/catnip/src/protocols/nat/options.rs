@@ -0,0 +1,81 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ip;
+use std::{
+    convert::TryFrom,
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+#[derive(Clone, Debug)]
+pub struct NatOptions {
+    /// The network behind the NAT, as an address and prefix length.
+    pub inside_network: Ipv4Addr,
+    pub inside_prefix_len: u8,
+    /// External ports dynamic mappings are allocated from, inclusive. The range has to stay
+    /// clear of the ephemeral ports the engine's own connections use, and should stay clear of
+    /// any ports its sockets are bound to.
+    pub first_port: ip::Port,
+    pub last_port: ip::Port,
+    /// How long an idle TCP mapping lasts once both sides have been heard from (RFC 5382).
+    pub tcp_established_timeout: Duration,
+    /// How long an idle TCP mapping lasts before that, and after a reset or a close in both
+    /// directions.
+    pub tcp_transitory_timeout: Duration,
+    /// How long an idle UDP mapping lasts (RFC 4787).
+    pub udp_timeout: Duration,
+}
+
+impl Default for NatOptions {
+    fn default() -> Self {
+        NatOptions {
+            inside_network: Ipv4Addr::new(192, 168, 0, 0),
+            inside_prefix_len: 16,
+            first_port: ip::Port::try_from(32768).unwrap(),
+            last_port: ip::Port::try_from(49151).unwrap(),
+            tcp_established_timeout: Duration::from_secs(2 * 60 * 60 + 4 * 60),
+            tcp_transitory_timeout: Duration::from_secs(4 * 60),
+            udp_timeout: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+impl NatOptions {
+    pub fn inside_network(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32);
+        self.inside_network = addr;
+        self.inside_prefix_len = prefix_len;
+        self
+    }
+
+    pub fn port_range(mut self, first: ip::Port, last: ip::Port) -> Self {
+        assert!(first <= last);
+        self.first_port = first;
+        self.last_port = last;
+        self
+    }
+
+    pub fn tcp_established_timeout(mut self, value: Duration) -> Self {
+        self.tcp_established_timeout = value;
+        self
+    }
+
+    pub fn tcp_transitory_timeout(mut self, value: Duration) -> Self {
+        self.tcp_transitory_timeout = value;
+        self
+    }
+
+    pub fn udp_timeout(mut self, value: Duration) -> Self {
+        self.udp_timeout = value;
+        self
+    }
+
+    pub fn is_inside(&self, addr: Ipv4Addr) -> bool {
+        let mask = match self.inside_prefix_len {
+            0 => 0,
+            n => !0u32 << (32 - n as u32),
+        };
+        u32::from(addr) & mask == u32::from(self.inside_network) & mask
+    }
+}
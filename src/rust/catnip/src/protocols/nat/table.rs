@@ -0,0 +1,277 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::options::NatOptions;
+use crate::{
+    fail::Fail,
+    protocols::{
+        ip,
+        ipv4::{
+            self,
+            filter::{
+                TCP_FIN,
+                TCP_RST,
+            },
+            Ipv4Protocol2,
+        },
+    },
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// One external port's translation, as listed by `Engine::nat_mappings`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mapping {
+    pub protocol: Ipv4Protocol2,
+    pub external_port: ip::Port,
+    pub inside: ipv4::Endpoint,
+    /// The only remote that can use a dynamic mapping, or `None` for a static one.
+    pub remote: Option<ipv4::Endpoint>,
+    /// When a dynamic mapping expires unless it sees more traffic.
+    pub expires: Option<Instant>,
+}
+
+// A tracked connection through a dynamic mapping.
+struct Connection {
+    inside: ipv4::Endpoint,
+    remote: ipv4::Endpoint,
+    last_seen: Instant,
+    // Whether the remote has answered.
+    replied: bool,
+    fin_out: bool,
+    fin_in: bool,
+    reset: bool,
+}
+
+impl Connection {
+    fn timeout(&self, protocol: Ipv4Protocol2, options: &NatOptions) -> Duration {
+        match protocol {
+            Ipv4Protocol2::Tcp => {
+                if self.replied && !self.reset && !(self.fin_out && self.fin_in) {
+                    options.tcp_established_timeout
+                } else {
+                    options.tcp_transitory_timeout
+                }
+            },
+            _ => options.udp_timeout,
+        }
+    }
+
+    fn expires(&self, protocol: Ipv4Protocol2, options: &NatOptions) -> Instant {
+        self.last_seen + self.timeout(protocol, options)
+    }
+
+    fn update(&mut self, now: Instant, outbound: bool, tcp_flags: u8) {
+        self.last_seen = now;
+        if tcp_flags & TCP_RST != 0 {
+            self.reset = true;
+        }
+        if outbound {
+            self.fin_out |= tcp_flags & TCP_FIN != 0;
+        } else {
+            self.replied = true;
+            self.fin_in |= tcp_flags & TCP_FIN != 0;
+        }
+    }
+}
+
+type Flow = (Ipv4Protocol2, ipv4::Endpoint, ipv4::Endpoint);
+
+pub struct Table {
+    next_port: u16,
+    dynamic: HashMap<(Ipv4Protocol2, ip::Port), Connection>,
+    // (protocol, inside, remote) -> external port
+    by_flow: HashMap<Flow, ip::Port>,
+    statics: HashMap<(Ipv4Protocol2, ip::Port), ipv4::Endpoint>,
+    statics_by_inside: HashMap<(Ipv4Protocol2, ipv4::Endpoint), ip::Port>,
+}
+
+impl Table {
+    pub fn new(options: &NatOptions) -> Self {
+        Self {
+            next_port: options.first_port.into(),
+            dynamic: HashMap::new(),
+            by_flow: HashMap::new(),
+            statics: HashMap::new(),
+            statics_by_inside: HashMap::new(),
+        }
+    }
+
+    pub fn add_static(
+        &mut self,
+        protocol: Ipv4Protocol2,
+        external_port: ip::Port,
+        inside: ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        let key = (protocol, external_port);
+        if self.statics.contains_key(&key) || self.dynamic.contains_key(&key) {
+            return Err(Fail::ResourceBusy {
+                details: "External port already mapped",
+            });
+        }
+        if self.statics_by_inside.contains_key(&(protocol, inside)) {
+            return Err(Fail::ResourceBusy {
+                details: "Inside endpoint already statically mapped",
+            });
+        }
+        self.statics.insert(key, inside);
+        self.statics_by_inside
+            .insert((protocol, inside), external_port);
+        Ok(())
+    }
+
+    pub fn remove_static(&mut self, protocol: Ipv4Protocol2, external_port: ip::Port) -> bool {
+        match self.statics.remove(&(protocol, external_port)) {
+            Some(inside) => {
+                self.statics_by_inside.remove(&(protocol, inside));
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The external port for a packet from `inside` to `remote`, creating a mapping if there
+    /// isn't one.
+    pub fn outbound(
+        &mut self,
+        options: &NatOptions,
+        protocol: Ipv4Protocol2,
+        inside: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+        tcp_flags: u8,
+        now: Instant,
+    ) -> Result<ip::Port, Fail> {
+        if let Some(&port) = self.statics_by_inside.get(&(protocol, inside)) {
+            return Ok(port);
+        }
+        let flow = (protocol, inside, remote);
+        if let Some(&port) = self.by_flow.get(&flow) {
+            let connection = self.dynamic.get_mut(&(protocol, port)).unwrap();
+            if now < connection.expires(protocol, options) {
+                connection.update(now, true, tcp_flags);
+                return Ok(port);
+            }
+            self.remove(protocol, port);
+        }
+        let port = self.alloc(options, protocol)?;
+        let mut connection = Connection {
+            inside,
+            remote,
+            last_seen: now,
+            replied: false,
+            fin_out: false,
+            fin_in: false,
+            reset: false,
+        };
+        connection.update(now, true, tcp_flags);
+        self.dynamic.insert((protocol, port), connection);
+        self.by_flow.insert(flow, port);
+        Ok(port)
+    }
+
+    /// Where a packet from `remote` to `external_port` goes, if anywhere.
+    pub fn inbound(
+        &mut self,
+        options: &NatOptions,
+        protocol: Ipv4Protocol2,
+        external_port: ip::Port,
+        remote: ipv4::Endpoint,
+        tcp_flags: u8,
+        now: Instant,
+    ) -> Option<ipv4::Endpoint> {
+        let key = (protocol, external_port);
+        if let Some(connection) = self.dynamic.get_mut(&key) {
+            if connection.remote == remote && now < connection.expires(protocol, options) {
+                connection.update(now, false, tcp_flags);
+                return Some(connection.inside);
+            }
+            // Endpoint-dependent filtering: nobody else gets through.
+            return None;
+        }
+        self.statics.get(&key).cloned()
+    }
+
+    /// Drop the dynamic mappings that have expired by `now`, returning how many there were.
+    pub fn expire(&mut self, options: &NatOptions, now: Instant) -> usize {
+        let expired: Vec<_> = self
+            .dynamic
+            .iter()
+            .filter(|((protocol, _), c)| now >= c.expires(*protocol, options))
+            .map(|(key, _)| *key)
+            .collect();
+        for &(protocol, port) in &expired {
+            self.remove(protocol, port);
+        }
+        expired.len()
+    }
+
+    /// Live mappings, static ones first, each ordered by protocol and port.
+    pub fn mappings(&self, options: &NatOptions, now: Instant) -> Vec<Mapping> {
+        let mut statics: Vec<_> = self
+            .statics
+            .iter()
+            .map(|(&(protocol, external_port), &inside)| Mapping {
+                protocol,
+                external_port,
+                inside,
+                remote: None,
+                expires: None,
+            })
+            .collect();
+        let mut dynamic: Vec<_> = self
+            .dynamic
+            .iter()
+            .map(|(&(protocol, external_port), c)| Mapping {
+                protocol,
+                external_port,
+                inside: c.inside,
+                remote: Some(c.remote),
+                expires: Some(c.expires(protocol, options)),
+            })
+            .filter(|m| m.expires.unwrap() > now)
+            .collect();
+        statics.sort_by_key(|m| (m.protocol as u8, m.external_port));
+        dynamic.sort_by_key(|m| (m.protocol as u8, m.external_port));
+        statics.extend(dynamic);
+        statics
+    }
+
+    pub fn len(&self) -> usize {
+        self.statics.len() + self.dynamic.len()
+    }
+
+    fn remove(&mut self, protocol: Ipv4Protocol2, port: ip::Port) {
+        if let Some(c) = self.dynamic.remove(&(protocol, port)) {
+            self.by_flow.remove(&(protocol, c.inside, c.remote));
+        }
+    }
+
+    // The next free port in the range, round robin so recently freed ports aren't reused first.
+    fn alloc(&mut self, options: &NatOptions, protocol: Ipv4Protocol2) -> Result<ip::Port, Fail> {
+        let first: u16 = options.first_port.into();
+        let last: u16 = options.last_port.into();
+        let num_ports = (last - first) as usize + 1;
+        for _ in 0..num_ports {
+            let candidate = self.next_port;
+            self.next_port = if candidate >= last {
+                first
+            } else {
+                candidate + 1
+            };
+            let port = ip::Port::try_from(candidate).unwrap();
+            let key = (protocol, port);
+            if !self.dynamic.contains_key(&key) && !self.statics.contains_key(&key) {
+                return Ok(port);
+            }
+        }
+        Err(Fail::ResourceExhausted {
+            details: "No free NAT ports",
+        })
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::Options;
+use crate::{
+    engine::Protocol,
+    fail::Fail,
+    protocols::{
+        ethernet2::Ethernet2Header,
+        ip,
+        ipv4,
+        ipv4::{
+            adjust_checksum,
+            Ipv4Protocol2,
+        },
+    },
+    runtime::RuntimeBuf,
+    sync::Bytes,
+    test_helpers,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Alice is the only host inside, with Bob as her gateway. Carrie is outside.
+fn options() -> Options {
+    Options::default().inside_network(test_helpers::ALICE_IPV4, 32)
+}
+
+fn port(n: u16) -> ip::Port {
+    ip::Port::try_from(n).unwrap()
+}
+
+// Send a frame Alice addressed to Carrie to Bob's link address instead.
+fn via_gateway(frame: Bytes) -> Bytes {
+    let mut frame = frame[..].to_vec();
+    frame[0..6].copy_from_slice(&test_helpers::BOB_MAC.octets());
+    Bytes::from_slice(&frame[..])
+}
+
+fn checksum(buf: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for word in buf.chunks(2) {
+        sum += NetworkEndian::read_u16(word) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[test]
+fn incremental_checksum() {
+    let mut buf: Vec<u8> = (0..64u8).map(|i| i.wrapping_mul(37)).collect();
+    let before = checksum(&buf[..]);
+    let old = [buf[12], buf[13], buf[14], buf[15]];
+    let new = [0xff, 0x00, 0xab, 0xcd];
+    buf[12..16].copy_from_slice(&new);
+    assert_eq!(adjust_checksum(before, &old, &new), checksum(&buf[..]));
+    // Replacing something with itself is a no-op.
+    assert_eq!(adjust_checksum(before, &old, &old), before);
+}
+
+#[test]
+fn in_place_rewrite() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(5000));
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(54));
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    // Alice addresses port 53, and the packet is redirected to 54 with a byte of the payload
+    // changed at an odd offset.
+    alice
+        .udp_pushto(
+            alice_fd,
+            Bytes::from_slice(b"query"),
+            ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(53)),
+        )
+        .unwrap();
+    let frame = alice.rt().pop_frame();
+    let (eth_hdr, packet) = Ethernet2Header::parse(frame).unwrap();
+    let received = packet.as_ptr();
+    let mut packet = ipv4::Rewrite::new(packet).unwrap();
+    assert_eq!(packet.dst_port(), Some(port(53)));
+    packet.set_dst_port(port(54)).unwrap();
+    packet.write_payload(1, b"U").unwrap();
+    packet.set_ttl(packet.ttl() - 1);
+    must_let!(let Err(Fail::OutOfRange { .. }) = packet.write_payload(4, b"ab"));
+    assert_eq!(packet.payload(), b"qUery");
+    assert_eq!(checksum(&packet.as_bytes()[..20]), 0);
+    // Nothing else held the frame, so the changes went straight into it.
+    assert_eq!(packet.as_bytes().as_ptr(), received);
+
+    // Bob's stack checks both checksums on the way in.
+    let mut frame = vec![0u8; 14];
+    eth_hdr.serialize(&mut frame[..]);
+    frame.extend_from_slice(packet.as_bytes());
+    bob.receive(Bytes::from_slice(&frame[..])).unwrap();
+    let mut pop_future = bob.udp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok((Some(from), buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&buf[..], b"qUery");
+    assert_eq!(from, alice_addr);
+}
+
+#[test]
+fn udp_translation() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    bob.nat_start(options()).unwrap();
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(5000));
+    let carrie_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(53));
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+    let carrie_fd = carrie.socket(Protocol::Udp);
+    carrie.bind(carrie_fd, carrie_addr).unwrap();
+
+    // Carrie sees the query come from Bob, and her stack accepts its rewritten checksums.
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(b"query"), carrie_addr)
+        .unwrap();
+    bob.receive(via_gateway(alice.rt().pop_frame())).unwrap();
+    let frame = bob.rt().pop_frame();
+    let (eth_hdr, _) = Ethernet2Header::parse(frame.clone()).unwrap();
+    assert_eq!(eth_hdr.dst_addr, test_helpers::CARRIE_MAC);
+    carrie.receive(frame).unwrap();
+    let mut pop_future = carrie.udp_pop(carrie_fd);
+    must_let!(let Poll::Ready(Ok((Some(mapped), buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&buf[..], b"query");
+    assert_eq!(mapped.addr, test_helpers::BOB_IPV4);
+    must_let!(let [mapping] = &bob.nat_mappings()[..]);
+    assert_eq!(mapping.external_port, mapped.port);
+    assert_eq!(mapping.inside, alice_addr);
+    assert_eq!(mapping.remote, Some(carrie_addr));
+
+    // The answer finds its way back to Alice.
+    carrie
+        .udp_pushto(carrie_fd, Bytes::from_slice(b"answer"), mapped)
+        .unwrap();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let mut pop_future = alice.udp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok((Some(remote), buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&buf[..], b"answer");
+    assert_eq!(remote, carrie_addr);
+
+    // Nobody else can use the mapping, so Bob's own stack gets the datagram and rejects it.
+    let stranger = carrie.socket(Protocol::Udp);
+    carrie
+        .bind(
+            stranger,
+            ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(54)),
+        )
+        .unwrap();
+    carrie
+        .udp_pushto(stranger, Bytes::from_slice(b"hello"), mapped)
+        .unwrap();
+    assert!(bob.receive(carrie.rt().pop_frame()).is_err());
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    let stats = bob.nat_stats().unwrap();
+    assert_eq!((stats.outbound, stats.inbound, stats.mappings), (1, 1, 1));
+
+    // Idle mappings time out.
+    bob.rt()
+        .advance_clock(now + options().udp_timeout + Duration::from_secs(1));
+    assert!(bob.nat_mappings().is_empty());
+}
+
+#[test]
+fn tcp_static_mapping() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+
+    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(80));
+    let outside_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80));
+    let overlapping = options().port_range(port(40000), port(50000));
+    must_let!(let Err(Fail::Invalid { .. }) = bob.nat_start(overlapping));
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = bob.nat_add_static_mapping(Ipv4Protocol2::Tcp, port(8080), alice_addr));
+    bob.nat_start(options()).unwrap();
+    bob.nat_add_static_mapping(Ipv4Protocol2::Tcp, port(8080), alice_addr)
+        .unwrap();
+    must_let!(let Err(Fail::ResourceBusy { .. }) = bob.nat_add_static_mapping(Ipv4Protocol2::Tcp, port(8080), alice_addr));
+    must_let!(let Err(Fail::Invalid { .. }) = bob.nat_add_static_mapping(Ipv4Protocol2::Tcp, port(8081), outside_addr));
+
+    let listen_fd = alice.tcp_socket();
+    alice.tcp_bind(listen_fd, alice_addr).unwrap();
+    alice.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = alice.tcp_accept(listen_fd);
+
+    // Carrie connects to Bob's port, and both TCP stacks accept the translated segments.
+    let public_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(8080));
+    let carrie_fd = carrie.tcp_socket();
+    let mut connect_future = carrie.tcp_connect(carrie_fd, public_addr);
+    carrie.rt().poll_scheduler();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(via_gateway(alice.rt().pop_frame())).unwrap();
+    carrie.receive(bob.rt().pop_frame()).unwrap();
+    carrie.rt().poll_scheduler();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(alice_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    assert_eq!(
+        alice.tcp_endpoints(alice_fd).unwrap().1.addr,
+        test_helpers::CARRIE_IPV4
+    );
+    assert_eq!(carrie.tcp_endpoints(carrie_fd).unwrap().1, public_addr);
+
+    // ICMP from the inside isn't translated.
+    let mut ping = Box::pin(alice.ping(test_helpers::CARRIE_IPV4, None));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    must_let!(let Err(Fail::Unsupported { .. }) = bob.receive(via_gateway(alice.rt().pop_frame())));
+    assert_eq!(bob.nat_stats().unwrap().untranslatable, 1);
+
+    assert!(bob.nat_remove_static_mapping(Ipv4Protocol2::Tcp, port(8080)));
+    assert!(bob.nat_mappings().is_empty());
+}
@@ -0,0 +1,325 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    options::NatOptions,
+    table::{
+        Mapping,
+        Table,
+    },
+};
+use crate::{
+    fail::Fail,
+    protocols::{
+        arp,
+        ethernet2::{
+            EtherType2,
+            Ethernet2Header,
+            MacAddress,
+        },
+        ip,
+        ipv4::{
+            self,
+            datagram::IPV4_HEADER_SIZE,
+            Egress,
+            Ipv4Protocol2,
+        },
+    },
+    runtime::{
+        PacketBuf,
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use futures::{
+    channel::mpsc,
+    StreamExt,
+};
+use std::{
+    net::Ipv4Addr,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// How often expired mappings are swept out of the table. Lookups ignore them in the meantime.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Offsets into the IPv4 header.
+const SRC_ADDR_OFFSET: usize = 12;
+const DST_ADDR_OFFSET: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NatStats {
+    /// Packets translated on their way out of, and back into, the inside network.
+    pub outbound: u64,
+    pub inbound: u64,
+    /// Packets leaving the inside network that couldn't be translated: ICMP, fragments, other
+    /// protocols and malformed ones.
+    pub untranslatable: u64,
+    /// New outbound flows dropped for want of a free external port.
+    pub ports_exhausted: u64,
+    /// Dynamic mappings that timed out.
+    pub expired: u64,
+    /// Static and dynamic mappings currently in the table.
+    pub mappings: usize,
+}
+
+pub struct Nat<RT: Runtime> {
+    rt: RT,
+    arp: arp::Peer<RT>,
+    egress: Egress<RT>,
+    options: NatOptions,
+    table: Table,
+    stats: NatStats,
+    next_sweep: Instant,
+
+    // Translated packets waiting on ARP for their next hop.
+    unresolved: mpsc::UnboundedSender<(Ipv4Addr, RT::Buf)>,
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> Nat<RT> {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, egress: Egress<RT>, options: NatOptions) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let handle = rt.spawn(Self::background(
+            rt.clone(),
+            arp.clone(),
+            egress.clone(),
+            rx,
+        ));
+        Self {
+            next_sweep: rt.now() + SWEEP_INTERVAL,
+            rt,
+            arp,
+            egress,
+            table: Table::new(&options),
+            options,
+            stats: NatStats::default(),
+            unresolved: tx,
+            handle,
+        }
+    }
+
+    async fn background(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        egress: Egress<RT>,
+        mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, RT::Buf)>,
+    ) {
+        while let Some((next_hop, packet)) = rx.next().await {
+            match arp.query(next_hop).await {
+                Ok(link_addr) => egress.transmit(forwarded(&rt, link_addr, packet)),
+                Err(e) => warn!("Dropping NAT packet for {}: {:?}", next_hop, e),
+            }
+        }
+    }
+
+    pub fn options(&self) -> &NatOptions {
+        &self.options
+    }
+
+    pub fn add_static(
+        &mut self,
+        protocol: Ipv4Protocol2,
+        external_port: ip::Port,
+        inside: ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        match protocol {
+            Ipv4Protocol2::Tcp | Ipv4Protocol2::Udp => (),
+            _ => {
+                return Err(Fail::Unsupported {
+                    details: "NAT only maps TCP and UDP ports",
+                })
+            },
+        }
+        if !self.options.is_inside(inside.addr) {
+            return Err(Fail::Invalid {
+                details: "Static mapping target isn't on the inside network",
+            });
+        }
+        self.table.add_static(protocol, external_port, inside)
+    }
+
+    pub fn remove_static(&mut self, protocol: Ipv4Protocol2, external_port: ip::Port) -> bool {
+        self.table.remove_static(protocol, external_port)
+    }
+
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.table.mappings(&self.options, self.rt.now())
+    }
+
+    pub fn stats(&self) -> NatStats {
+        NatStats {
+            mappings: self.table.len(),
+            ..self.stats
+        }
+    }
+
+    /// Translate and forward `packet` (an IPv4 packet addressed to our link address) if it
+    /// belongs to the NAT. Anything else is handed back for the stack.
+    pub fn receive(&mut self, packet: RT::Buf) -> Result<Option<RT::Buf>, Fail> {
+        let now = self.rt.now();
+        if now >= self.next_sweep {
+            self.stats.expired += self.table.expire(&self.options, now) as u64;
+            self.next_sweep = now + SWEEP_INTERVAL;
+        }
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return Ok(Some(packet));
+        }
+        let src_addr = ip_addr(&packet[SRC_ADDR_OFFSET..]);
+        let dst_addr = ip_addr(&packet[DST_ADDR_OFFSET..]);
+        let outbound = if dst_addr == self.rt.local_ipv4_addr() {
+            false
+        } else if self.options.is_inside(src_addr)
+            && !self.options.is_inside(dst_addr)
+            && !dst_addr.is_broadcast()
+            && !dst_addr.is_multicast()
+        {
+            true
+        } else {
+            return Ok(Some(packet));
+        };
+        let r = self.translate(packet, outbound, now);
+        if let Err(ref e) = r {
+            if let Fail::Unsupported { .. } = e {
+                self.stats.untranslatable += 1;
+            }
+            debug!("Not translating packet from {}: {:?}", src_addr, e);
+        }
+        r
+    }
+
+    fn translate(
+        &mut self,
+        packet: RT::Buf,
+        outbound: bool,
+        now: Instant,
+    ) -> Result<Option<RT::Buf>, Fail> {
+        // Inbound packets we can't make sense of may still be for the stack.
+        let untranslatable = |packet: RT::Buf, details: &'static str| {
+            if outbound {
+                Err(Fail::Unsupported { details })
+            } else {
+                Ok(Some(packet))
+            }
+        };
+        // Later fragments don't carry ports, and reassembly isn't worth it here. The clone kept
+        // for the stack is dropped before the rewrite writes anything, so that's still in place.
+        let mut rewrite = match ipv4::Rewrite::new(packet.clone()) {
+            Ok(rewrite) => rewrite,
+            Err(Fail::Unsupported { details }) | Err(Fail::Malformed { details }) => {
+                return untranslatable(packet, details)
+            },
+            Err(e) => return Err(e),
+        };
+        drop(packet);
+        let protocol = match rewrite.protocol() {
+            Ipv4Protocol2::Tcp => Ipv4Protocol2::Tcp,
+            Ipv4Protocol2::Udp => Ipv4Protocol2::Udp,
+            _ => return untranslatable(rewrite.into_buf(), "NAT only translates TCP and UDP"),
+        };
+        let (src_port, dst_port) = match (rewrite.src_port(), rewrite.dst_port()) {
+            (Some(s), Some(d)) => (s, d),
+            _ => return untranslatable(rewrite.into_buf(), "Zero port"),
+        };
+        let tcp_flags = match protocol {
+            Ipv4Protocol2::Tcp => rewrite.transport_header()[13],
+            _ => 0,
+        };
+        let src = ipv4::Endpoint::new(rewrite.src_addr(), src_port);
+        let dst = ipv4::Endpoint::new(rewrite.dst_addr(), dst_port);
+
+        let (new, next_hop) = if outbound {
+            let port = self
+                .table
+                .outbound(&self.options, protocol, src, dst, tcp_flags, now)
+                .map_err(|e| {
+                    self.stats.ports_exhausted += 1;
+                    e
+                })?;
+            let new = ipv4::Endpoint::new(self.rt.local_ipv4_addr(), port);
+            (new, dst.addr)
+        } else {
+            match self
+                .table
+                .inbound(&self.options, protocol, dst_port, src, tcp_flags, now)
+            {
+                Some(inside) => (inside, inside.addr),
+                None => return Ok(Some(rewrite.into_buf())),
+            }
+        };
+        if rewrite.ttl() <= 1 {
+            return Err(Fail::Ignored {
+                details: "TTL expired in transit",
+            });
+        }
+
+        // The rewrite keeps the IPv4 and TCP or UDP checksums up to date as it goes.
+        rewrite.set_ttl(rewrite.ttl() - 1);
+        if outbound {
+            rewrite.set_src_addr(new.addr);
+            rewrite.set_src_port(new.port)?;
+            self.stats.outbound += 1;
+        } else {
+            rewrite.set_dst_addr(new.addr);
+            rewrite.set_dst_port(new.port)?;
+            self.stats.inbound += 1;
+        }
+        self.forward(next_hop, rewrite.into_buf());
+        Ok(None)
+    }
+
+    fn forward(&self, next_hop: Ipv4Addr, packet: RT::Buf) {
+        match self.arp.try_query(next_hop) {
+            Some(link_addr) => self.egress.transmit(forwarded(&self.rt, link_addr, packet)),
+            None => self.unresolved.unbounded_send((next_hop, packet)).unwrap(),
+        }
+    }
+}
+
+fn ip_addr(buf: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])
+}
+
+fn forwarded<RT: Runtime>(
+    rt: &RT,
+    link_addr: MacAddress,
+    packet: RT::Buf,
+) -> ForwardedFrame<RT::Buf> {
+    ForwardedFrame {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: link_addr,
+            src_addr: rt.local_link_addr(),
+            ether_type: EtherType2::Ipv4,
+        },
+        packet,
+    }
+}
+
+// A translated IPv4 packet with a fresh Ethernet header.
+struct ForwardedFrame<T> {
+    ethernet2_hdr: Ethernet2Header,
+    packet: T,
+}
+
+impl<T: RuntimeBuf> PacketBuf<T> for ForwardedFrame<T> {
+    fn header_size(&self) -> usize {
+        self.ethernet2_hdr.compute_size()
+    }
+
+    fn write_header(&self, buf: &mut [u8]) {
+        self.ethernet2_hdr.serialize(buf);
+    }
+
+    fn body_size(&self) -> usize {
+        self.packet.len()
+    }
+
+    fn take_body(self) -> Option<T> {
+        Some(self.packet)
+    }
+}
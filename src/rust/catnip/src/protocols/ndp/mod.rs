@@ -0,0 +1,12 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+mod options;
+pub(crate) mod pdu;
+mod peer;
+
+#[cfg(test)]
+mod tests;
+
+pub use options::NdpOptions as Options;
+pub use peer::NdpPeer as Peer;
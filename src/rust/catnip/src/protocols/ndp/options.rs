@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+
+/// The RFC 4861 10 protocol constants, where the RFC lets them be configured.
+#[derive(Clone, Debug)]
+pub struct NdpOptions {
+    /// How long a learned neighbor is used before it's solicited again.
+    pub reachable_time: Duration,
+    /// How long to wait for an advertisement before soliciting again.
+    pub retrans_timer: Duration,
+    /// Solicitations sent before a query gives up.
+    pub max_multicast_solicit: usize,
+}
+
+impl Default for NdpOptions {
+    fn default() -> Self {
+        NdpOptions {
+            reachable_time: Duration::from_secs(30),
+            retrans_timer: Duration::from_secs(1),
+            max_multicast_solicit: 3,
+        }
+    }
+}
+
+impl NdpOptions {
+    pub fn reachable_time(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.reachable_time = value;
+        self
+    }
+
+    pub fn retrans_timer(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.retrans_timer = value;
+        self
+    }
+
+    pub fn max_multicast_solicit(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.max_multicast_solicit = value;
+        self
+    }
+}
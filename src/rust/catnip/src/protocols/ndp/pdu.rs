@@ -0,0 +1,247 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    protocols::{
+        ethernet2::{
+            frame::Ethernet2Header,
+            MacAddress,
+        },
+        ipv6::datagram::{
+            Ipv6Header,
+            NDP_HOP_LIMIT,
+        },
+    },
+    runtime::PacketBuf,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::{
+    marker::PhantomData,
+    net::Ipv6Addr,
+};
+
+pub const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+pub const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+// Type, code, checksum, flags or reserved, and target address.
+const NDP_MESSAGE_SIZE: usize = 24;
+const LINK_ADDR_OPTION_SIZE: usize = 8;
+const OPTION_SOURCE_LINK_ADDR: u8 = 1;
+const OPTION_TARGET_LINK_ADDR: u8 = 2;
+
+const FLAG_ROUTER: u8 = 0x80;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NdpOperation {
+    Solicitation,
+    Advertisement {
+        router: bool,
+        solicited: bool,
+        override_: bool,
+    },
+}
+
+/// A neighbor solicitation or advertisement (RFC 4861 4.3, 4.4). Options other than the
+/// link-layer address are skipped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NdpPdu {
+    pub operation: NdpOperation,
+    pub target_addr: Ipv6Addr,
+    /// The sender's link address for a solicitation, or the target's for an advertisement.
+    pub link_addr: Option<MacAddress>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NdpMessage<T> {
+    pub ethernet2_hdr: Ethernet2Header,
+    pub ipv6_hdr: Ipv6Header,
+    pub ndp_pdu: NdpPdu,
+
+    pub _body_marker: PhantomData<T>,
+}
+
+impl<T> PacketBuf<T> for NdpMessage<T> {
+    fn header_size(&self) -> usize {
+        self.ethernet2_hdr.compute_size()
+            + self.ipv6_hdr.compute_size()
+            + self.ndp_pdu.compute_size()
+    }
+
+    fn body_size(&self) -> usize {
+        0
+    }
+
+    fn write_header(&self, buf: &mut [u8]) {
+        let eth_hdr_size = self.ethernet2_hdr.compute_size();
+        let ipv6_hdr_size = self.ipv6_hdr.compute_size();
+        let ndp_pdu_size = self.ndp_pdu.compute_size();
+        let mut cur_pos = 0;
+
+        self.ethernet2_hdr
+            .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
+        cur_pos += eth_hdr_size;
+
+        self.ipv6_hdr
+            .serialize(&mut buf[cur_pos..(cur_pos + ipv6_hdr_size)], ndp_pdu_size);
+        cur_pos += ipv6_hdr_size;
+
+        self.ndp_pdu.serialize(
+            &mut buf[cur_pos..(cur_pos + ndp_pdu_size)],
+            &self.ipv6_hdr,
+        );
+    }
+
+    fn take_body(self) -> Option<T> {
+        None
+    }
+}
+
+impl NdpPdu {
+    pub fn compute_size(&self) -> usize {
+        match self.link_addr {
+            Some(..) => NDP_MESSAGE_SIZE + LINK_ADDR_OPTION_SIZE,
+            None => NDP_MESSAGE_SIZE,
+        }
+    }
+
+    /// Parse and validate (RFC 4861 7.1) the ICMPv6 message that follows `ipv6_hdr`.
+    pub fn parse(ipv6_hdr: &Ipv6Header, buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < NDP_MESSAGE_SIZE {
+            return Err(Fail::Malformed {
+                details: "NDP message too short",
+            });
+        }
+        // Only a neighbor on the link can have sent it with the hop limit untouched.
+        if ipv6_hdr.hop_limit != NDP_HOP_LIMIT {
+            return Err(Fail::Malformed {
+                details: "NDP message with a hop limit other than 255",
+            });
+        }
+        if buf[1] != 0 {
+            return Err(Fail::Malformed {
+                details: "NDP message with a nonzero code",
+            });
+        }
+        if icmpv6_checksum(ipv6_hdr, buf) != NetworkEndian::read_u16(&buf[2..4]) {
+            return Err(Fail::Malformed {
+                details: "ICMPv6 checksum mismatch",
+            });
+        }
+        let (operation, option_type) = match buf[0] {
+            ICMPV6_NEIGHBOR_SOLICITATION => (NdpOperation::Solicitation, OPTION_SOURCE_LINK_ADDR),
+            ICMPV6_NEIGHBOR_ADVERTISEMENT => {
+                let flags = buf[4];
+                let operation = NdpOperation::Advertisement {
+                    router: flags & FLAG_ROUTER != 0,
+                    solicited: flags & FLAG_SOLICITED != 0,
+                    override_: flags & FLAG_OVERRIDE != 0,
+                };
+                (operation, OPTION_TARGET_LINK_ADDR)
+            },
+            _ => {
+                return Err(Fail::Unsupported {
+                    details: "Unsupported ICMPv6 type",
+                })
+            },
+        };
+        let mut target = [0u8; 16];
+        target.copy_from_slice(&buf[8..24]);
+        let target_addr = Ipv6Addr::from(target);
+        if target_addr.is_multicast() {
+            return Err(Fail::Malformed {
+                details: "NDP target is a multicast address",
+            });
+        }
+
+        let mut link_addr = None;
+        let mut options = &buf[NDP_MESSAGE_SIZE..];
+        while !options.is_empty() {
+            // Option lengths are in units of 8 bytes, and can't be zero.
+            let len = options.get(1).map_or(0, |&n| n as usize * 8);
+            if len == 0 || len > options.len() {
+                return Err(Fail::Malformed {
+                    details: "Bad NDP option length",
+                });
+            }
+            if options[0] == option_type && len == LINK_ADDR_OPTION_SIZE {
+                link_addr = Some(MacAddress::from_bytes(&options[2..8]));
+            }
+            options = &options[len..];
+        }
+        // A duplicate address probe comes from nowhere, so nothing can be answered there.
+        if ipv6_hdr.src_addr.is_unspecified() && link_addr.is_some() {
+            return Err(Fail::Malformed {
+                details: "NDP probe with a link address",
+            });
+        }
+        Ok(Self {
+            operation,
+            target_addr,
+            link_addr,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8], ipv6_hdr: &Ipv6Header) {
+        let buf = &mut buf[..self.compute_size()];
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        let option_type = match self.operation {
+            NdpOperation::Solicitation => {
+                buf[0] = ICMPV6_NEIGHBOR_SOLICITATION;
+                OPTION_SOURCE_LINK_ADDR
+            },
+            NdpOperation::Advertisement {
+                router,
+                solicited,
+                override_,
+            } => {
+                buf[0] = ICMPV6_NEIGHBOR_ADVERTISEMENT;
+                if router {
+                    buf[4] |= FLAG_ROUTER;
+                }
+                if solicited {
+                    buf[4] |= FLAG_SOLICITED;
+                }
+                if override_ {
+                    buf[4] |= FLAG_OVERRIDE;
+                }
+                OPTION_TARGET_LINK_ADDR
+            },
+        };
+        buf[8..24].copy_from_slice(&self.target_addr.octets());
+        if let Some(link_addr) = self.link_addr {
+            buf[24] = option_type;
+            buf[25] = (LINK_ADDR_OPTION_SIZE / 8) as u8;
+            buf[26..32].copy_from_slice(&link_addr.octets());
+        }
+        let checksum = icmpv6_checksum(ipv6_hdr, buf);
+        NetworkEndian::write_u16(&mut buf[2..4], checksum);
+    }
+}
+
+// The checksum over the pseudo-header and `buf`, skipping the checksum field itself.
+fn icmpv6_checksum(ipv6_hdr: &Ipv6Header, buf: &[u8]) -> u16 {
+    let mut state = ipv6_hdr.pseudo_header_sum(buf.len());
+    let mut chunks_iter = buf.chunks_exact(2);
+    let mut offset = 0;
+    while let Some(chunk) = chunks_iter.next() {
+        if offset != 2 {
+            state += NetworkEndian::read_u16(chunk) as u32;
+        }
+        offset += 2;
+    }
+    if let Some(&b) = chunks_iter.remainder().get(0) {
+        state += NetworkEndian::read_u16(&[b, 0]) as u32;
+    }
+    while state > 0xffff {
+        state = (state & 0xffff) + (state >> 16);
+    }
+    !state as u16
+}
@@ -0,0 +1,231 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::pdu::{
+    NdpMessage,
+    NdpOperation,
+    NdpPdu,
+};
+use crate::{
+    fail::Fail,
+    protocols::{
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+            },
+            MacAddress,
+        },
+        ipv6,
+        ipv6::datagram::{
+            Ipv6Header,
+            NDP_HOP_LIMIT,
+            NEXT_HEADER_ICMPV6,
+        },
+    },
+    runtime::Runtime,
+};
+use futures::{
+    channel::oneshot::{
+        channel,
+        Receiver,
+        Sender,
+    },
+    FutureExt,
+};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    net::Ipv6Addr,
+    rc::Rc,
+    time::Instant,
+};
+
+#[derive(Default)]
+struct NeighborCache {
+    // Link addresses and when they were last confirmed.
+    entries: HashMap<Ipv6Addr, (MacAddress, Instant)>,
+    waiters: HashMap<Ipv6Addr, Vec<Sender<MacAddress>>>,
+}
+
+impl NeighborCache {
+    fn insert(&mut self, ipv6_addr: Ipv6Addr, link_addr: MacAddress, now: Instant) {
+        self.entries.insert(ipv6_addr, (link_addr, now));
+        for waiter in self.waiters.remove(&ipv6_addr).unwrap_or_default() {
+            let _ = waiter.send(link_addr);
+        }
+    }
+
+    fn wait_link_addr(&mut self, ipv6_addr: Ipv6Addr) -> Receiver<MacAddress> {
+        let (tx, rx) = channel();
+        self.waiters.entry(ipv6_addr).or_default().push(tx);
+        rx
+    }
+}
+
+/// Neighbor discovery (RFC 4861), which resolves IPv6 addresses on the link to Ethernet
+/// addresses as ARP does for IPv4. Only address resolution is implemented: there's no router or
+/// prefix discovery, redirects or unreachability detection beyond entries aging out.
+#[derive(Clone)]
+pub struct NdpPeer<RT: Runtime> {
+    rt: RT,
+    cache: Rc<RefCell<NeighborCache>>,
+}
+
+impl<RT: Runtime> NdpPeer<RT> {
+    pub fn new(rt: RT) -> Self {
+        Self {
+            rt,
+            cache: Rc::new(RefCell::new(NeighborCache::default())),
+        }
+    }
+
+    pub fn receive(&self, ipv6_hdr: &Ipv6Header, buf: RT::Buf) -> Result<(), Fail> {
+        let pdu = NdpPdu::parse(ipv6_hdr, &buf[..])?;
+        debug!("Received {:?} from {}", pdu, ipv6_hdr.src_addr);
+        let local_ipv6_addr = self.rt.local_ipv6_addr();
+        let now = self.rt.now();
+        match pdu.operation {
+            NdpOperation::Solicitation => {
+                if pdu.target_addr != local_ipv6_addr {
+                    return Err(Fail::Ignored {
+                        details: "NDP solicitation for another address",
+                    });
+                }
+                // A duplicate address probe is answered to everyone, since its sender has no
+                // address yet (RFC 4861 7.2.4).
+                let (dst_addr, dst_link_addr, solicited) = if ipv6_hdr.src_addr.is_unspecified() {
+                    let group = ipv6::ALL_NODES;
+                    (group, ipv6::multicast_link_addr(group), false)
+                } else {
+                    let link_addr = match pdu.link_addr {
+                        Some(link_addr) => {
+                            let mut cache = self.cache.borrow_mut();
+                            cache.insert(ipv6_hdr.src_addr, link_addr, now);
+                            link_addr
+                        },
+                        None => self.try_query(ipv6_hdr.src_addr).ok_or(Fail::Ignored {
+                            details: "No link address to answer NDP solicitation",
+                        })?,
+                    };
+                    (ipv6_hdr.src_addr, link_addr, true)
+                };
+                let operation = NdpOperation::Advertisement {
+                    router: false,
+                    solicited,
+                    override_: true,
+                };
+                let reply = self.message(dst_link_addr, dst_addr, operation, local_ipv6_addr);
+                debug!("Responding {:?}", reply);
+                self.rt.transmit(reply);
+                Ok(())
+            },
+            NdpOperation::Advertisement { .. } => {
+                if pdu.target_addr == local_ipv6_addr {
+                    warn!("{:?} claims our address {}", pdu.link_addr, local_ipv6_addr);
+                    return Err(Fail::Ignored {
+                        details: "NDP advertisement for our address",
+                    });
+                }
+                let link_addr = pdu.link_addr.ok_or(Fail::Ignored {
+                    details: "NDP advertisement without a link address",
+                })?;
+                // Advertisements nobody asked for only update entries we have (RFC 4861 7.2.5).
+                let mut cache = self.cache.borrow_mut();
+                if !cache.entries.contains_key(&pdu.target_addr)
+                    && !cache.waiters.contains_key(&pdu.target_addr)
+                {
+                    return Err(Fail::Ignored {
+                        details: "Unsolicited NDP advertisement",
+                    });
+                }
+                cache.insert(pdu.target_addr, link_addr, now);
+                Ok(())
+            },
+        }
+    }
+
+    // A message to `dst_addr` from our link-local address.
+    fn message(
+        &self,
+        dst_link_addr: MacAddress,
+        dst_addr: Ipv6Addr,
+        operation: NdpOperation,
+        target_addr: Ipv6Addr,
+    ) -> NdpMessage<RT::Buf> {
+        let mut ipv6_hdr = Ipv6Header::new(self.rt.local_ipv6_addr(), dst_addr, NEXT_HEADER_ICMPV6);
+        ipv6_hdr.hop_limit = NDP_HOP_LIMIT;
+        NdpMessage {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: dst_link_addr,
+                src_addr: self.rt.local_link_addr(),
+                ether_type: EtherType2::Ipv6,
+            },
+            ipv6_hdr,
+            ndp_pdu: NdpPdu {
+                operation,
+                target_addr,
+                link_addr: Some(self.rt.local_link_addr()),
+            },
+            _body_marker: PhantomData,
+        }
+    }
+
+    /// The link address of `ipv6_addr`, if it was confirmed within the reachable time.
+    pub fn try_query(&self, ipv6_addr: Ipv6Addr) -> Option<MacAddress> {
+        let reachable_time = self.rt.ndp_options().reachable_time;
+        match self.cache.borrow().entries.get(&ipv6_addr) {
+            Some(&(link_addr, confirmed)) if confirmed + reachable_time > self.rt.now() => {
+                Some(link_addr)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn query(&self, ipv6_addr: Ipv6Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
+        let peer = self.clone();
+        async move {
+            if let Some(link_addr) = peer.try_query(ipv6_addr) {
+                return Ok(link_addr);
+            }
+            let group = ipv6::solicited_node_addr(ipv6_addr);
+            let msg = peer.message(
+                ipv6::multicast_link_addr(group),
+                group,
+                NdpOperation::Solicitation,
+                ipv6_addr,
+            );
+            let response = peer.cache.borrow_mut().wait_link_addr(ipv6_addr).fuse();
+            futures::pin_mut!(response);
+
+            let options = peer.rt.ndp_options();
+            for i in 0..options.max_multicast_solicit {
+                peer.rt.transmit(msg.clone());
+                futures::select! {
+                    link_addr = response => {
+                        if let Ok(link_addr) = link_addr {
+                            debug!("NDP result available ({})", link_addr);
+                            return Ok(link_addr);
+                        }
+                        break;
+                    },
+                    _ = peer.rt.wait(options.retrans_timer).fuse() => {
+                        warn!("NDP solicitation timeout; attempt {}.", i + 1);
+                    },
+                }
+            }
+            Err(Fail::Timeout {})
+        }
+    }
+
+    pub fn export_cache(&self) -> HashMap<Ipv6Addr, MacAddress> {
+        let cache = self.cache.borrow();
+        cache
+            .entries
+            .iter()
+            .map(|(&ipv6_addr, &(link_addr, _))| (ipv6_addr, link_addr))
+            .collect()
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::pdu::{
+    NdpMessage,
+    NdpOperation,
+    NdpPdu,
+};
+use crate::{
+    engine::Engine,
+    fail::Fail,
+    protocols::{
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+                ETHERNET2_HEADER_SIZE,
+            },
+            MacAddress,
+        },
+        ipv6,
+        ipv6::datagram::{
+            Ipv6Header,
+            NDP_HOP_LIMIT,
+            NEXT_HEADER_ICMPV6,
+        },
+    },
+    runtime::Runtime,
+    sync::Bytes,
+    test_helpers::{
+        self,
+        TestRuntime,
+    },
+};
+use futures::{
+    task::{
+        noop_waker_ref,
+        Context,
+    },
+    FutureExt,
+};
+use must_let::must_let;
+use std::{
+    future::Future,
+    marker::PhantomData,
+    net::Ipv6Addr,
+    task::Poll,
+    time::Instant,
+};
+
+// The NDP message in an Ethernet frame, along with its IPv6 header.
+fn parse_frame(frame: &Bytes) -> (Ethernet2Header, Ipv6Header, NdpPdu) {
+    let (eth_hdr, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+    let (ipv6_hdr, payload) = Ipv6Header::parse(payload).unwrap();
+    let pdu = NdpPdu::parse(&ipv6_hdr, &payload[..]).unwrap();
+    (eth_hdr, ipv6_hdr, pdu)
+}
+
+#[test]
+fn resolve_neighbor() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_addr = alice.rt().local_ipv6_addr();
+    let bob_addr = bob.rt().local_ipv6_addr();
+    assert_eq!(bob_addr, ipv6::link_local_addr(test_helpers::BOB_MAC));
+
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = alice.ndp_query(bob_addr).boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // Alice solicits bob's solicited-node group, saying where to answer.
+    let solicitation = alice.rt().pop_frame();
+    let (eth_hdr, ipv6_hdr, pdu) = parse_frame(&solicitation);
+    let group = ipv6::solicited_node_addr(bob_addr);
+    assert_eq!(eth_hdr.dst_addr, ipv6::multicast_link_addr(group));
+    assert_eq!(ipv6_hdr.dst_addr, group);
+    assert_eq!(pdu.operation, NdpOperation::Solicitation);
+    assert_eq!(pdu.target_addr, bob_addr);
+    assert_eq!(pdu.link_addr, Some(test_helpers::ALICE_MAC));
+
+    // Carrie's not in the group, so she drops it.
+    let mut carrie = test_helpers::new_carrie(now);
+    must_let!(let Err(Fail::Ignored { .. }) = carrie.receive(solicitation.clone()));
+
+    bob.receive(solicitation).unwrap();
+    assert_eq!(
+        bob.export_ndp_cache().get(&alice_addr),
+        Some(&test_helpers::ALICE_MAC)
+    );
+    let advertisement = bob.rt().pop_frame();
+    let (eth_hdr, ipv6_hdr, pdu) = parse_frame(&advertisement);
+    assert_eq!(eth_hdr.dst_addr, test_helpers::ALICE_MAC);
+    assert_eq!(ipv6_hdr.dst_addr, alice_addr);
+    must_let!(let NdpOperation::Advertisement { solicited: true, override_: true, .. } =
+        pdu.operation);
+    assert_eq!(pdu.link_addr, Some(test_helpers::BOB_MAC));
+
+    alice.receive(advertisement).unwrap();
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(link_addr, test_helpers::BOB_MAC);
+}
+
+// An advertisement from bob to alice, which bob transmits without anyone having asked.
+fn forged_advertisement(bob: &Engine<TestRuntime>, alice_addr: Ipv6Addr) -> Bytes {
+    let mut ipv6_hdr = Ipv6Header::new(bob.rt().local_ipv6_addr(), alice_addr, NEXT_HEADER_ICMPV6);
+    ipv6_hdr.hop_limit = NDP_HOP_LIMIT;
+    bob.rt().transmit(NdpMessage {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: test_helpers::ALICE_MAC,
+            src_addr: test_helpers::BOB_MAC,
+            ether_type: EtherType2::Ipv6,
+        },
+        ipv6_hdr,
+        ndp_pdu: NdpPdu {
+            operation: NdpOperation::Advertisement {
+                router: false,
+                solicited: false,
+                override_: true,
+            },
+            target_addr: bob.rt().local_ipv6_addr(),
+            link_addr: Some(test_helpers::BOB_MAC),
+        },
+        _body_marker: PhantomData,
+    });
+    bob.rt().pop_frame()
+}
+
+#[test]
+fn unsolicited_advertisement() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+    let alice_addr = alice.rt().local_ipv6_addr();
+
+    // Alice hasn't asked about bob, so she doesn't learn him from it.
+    let advertisement = forged_advertisement(&bob, alice_addr);
+    must_let!(let Err(Fail::Ignored { .. }) = alice.receive(advertisement.clone()));
+    assert!(alice.export_ndp_cache().is_empty());
+
+    // A hop limit other than 255 means it came through a router.
+    let mut frame = advertisement[..].to_vec();
+    frame[ETHERNET2_HEADER_SIZE + 7] = 64;
+    must_let!(let Err(Fail::Malformed { .. }) = alice.receive(Bytes::from_slice(&frame)));
+}
+
+#[test]
+fn query_timeout() {
+    let mut now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let options = alice.rt().ndp_options();
+    let unknown = ipv6::link_local_addr(MacAddress::new([0x02, 0, 0, 0, 0, 0x01]));
+
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = alice.ndp_query(unknown).boxed_local();
+    for _ in 0..options.max_multicast_solicit {
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        let (_, _, pdu) = parse_frame(&alice.rt().pop_frame());
+        assert_eq!(pdu.target_addr, unknown);
+        now += options.retrans_timer;
+        alice.rt().advance_clock(now);
+    }
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
+}
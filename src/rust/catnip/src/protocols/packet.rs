@@ -0,0 +1,137 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Outgoing frames put together from where they're going and what they carry, so transmit sites
+//! don't each fill in Ethernet and IPv4 headers by hand, where a source address is easy to get
+//! wrong.
+
+use crate::{
+    protocols::{
+        arp::pdu::{
+            ArpMessage,
+            ArpPdu,
+        },
+        ethernet2::{
+            frame::{
+                EtherType2,
+                Ethernet2Header,
+            },
+            MacAddress,
+        },
+        icmpv4::datagram::{
+            Icmpv4Header,
+            Icmpv4Message,
+        },
+        ipv4::datagram::{
+            Ipv4Header,
+            Ipv4Protocol2,
+        },
+        tcp::segment::{
+            TcpHeader,
+            TcpSegment,
+        },
+        udp::datagram::{
+            UdpDatagram,
+            UdpHeader,
+        },
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+};
+use std::{
+    marker::PhantomData,
+    net::Ipv4Addr,
+};
+
+/// The addresses of a packet's next hop: ours, taken from the runtime, and the destination's,
+/// with its link address as ARP resolved it. Each protocol's method returns a frame ready for
+/// `Runtime::transmit`, with lengths and checksums filled in as it's serialized.
+#[derive(Clone, Debug)]
+pub struct PacketBuilder {
+    src_link_addr: MacAddress,
+    dst_link_addr: MacAddress,
+    src_addr: Ipv4Addr,
+    dst_addr: Ipv4Addr,
+}
+
+impl PacketBuilder {
+    /// Packets from the runtime's addresses to `dst_addr`, reached through `dst_link_addr`.
+    pub fn new<RT: Runtime>(rt: &RT, dst_link_addr: MacAddress, dst_addr: Ipv4Addr) -> Self {
+        Self {
+            src_link_addr: rt.local_link_addr(),
+            dst_link_addr,
+            src_addr: rt.local_ipv4_addr(),
+            dst_addr,
+        }
+    }
+
+    /// Send from `src_addr` instead of the runtime's address, as a socket bound to another does.
+    pub fn src_addr(mut self, src_addr: Ipv4Addr) -> Self {
+        self.src_addr = src_addr;
+        self
+    }
+
+    pub fn ethernet2(&self, ether_type: EtherType2) -> Ethernet2Header {
+        Ethernet2Header {
+            dst_addr: self.dst_link_addr,
+            src_addr: self.src_link_addr,
+            ether_type,
+        }
+    }
+
+    /// An IPv4 header with the defaults of `Ipv4Header::new`, for the caller to adjust.
+    pub fn ipv4(&self, protocol: Ipv4Protocol2) -> Ipv4Header {
+        Ipv4Header::new(self.src_addr, self.dst_addr, protocol)
+    }
+
+    pub fn tcp<T: RuntimeBuf>(
+        &self,
+        tcp_hdr: TcpHeader,
+        data: T,
+        tx_checksum_offload: bool,
+    ) -> TcpSegment<T> {
+        TcpSegment {
+            ethernet2_hdr: self.ethernet2(EtherType2::Ipv4),
+            ipv4_hdr: self.ipv4(Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            data,
+            tx_checksum_offload,
+        }
+    }
+
+    pub fn udp<T: RuntimeBuf>(
+        &self,
+        udp_hdr: UdpHeader,
+        data: T,
+        tx_checksum_offload: bool,
+    ) -> UdpDatagram<T> {
+        UdpDatagram {
+            ethernet2_hdr: self.ethernet2(EtherType2::Ipv4),
+            ipv4_hdr: self.ipv4(Ipv4Protocol2::Udp),
+            udp_hdr,
+            data,
+            tx_checksum_offload,
+        }
+    }
+
+    pub fn icmpv4<T: RuntimeBuf>(&self, icmpv4_hdr: Icmpv4Header, data: T) -> Icmpv4Message<T> {
+        Icmpv4Message {
+            ethernet2_hdr: self.ethernet2(EtherType2::Ipv4),
+            ipv4_hdr: self.ipv4(Ipv4Protocol2::Icmpv4),
+            icmpv4_hdr,
+            data,
+        }
+    }
+
+    /// An ARP message. Only the link addresses are used: the PDU carries its own protocol
+    /// addresses.
+    pub fn arp<T>(&self, arp_pdu: ArpPdu) -> ArpMessage<T> {
+        ArpMessage {
+            ethernet2_hdr: self.ethernet2(EtherType2::Arp),
+            arp_pdu,
+            _body_marker: PhantomData,
+        }
+    }
+}
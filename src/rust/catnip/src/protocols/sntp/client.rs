@@ -0,0 +1,203 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    options::SntpOptions,
+    packet::NtpPacket,
+};
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ipv4,
+        udp,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use futures::FutureExt;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+/// A single successful exchange with the server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sample {
+    /// When the response arrived, on the runtime's clock.
+    pub reference: Instant,
+    /// The server's estimate of Unix time at `reference`, corrected for half the round trip.
+    pub unix_time: Duration,
+    /// Round trip delay, excluding the server's processing time.
+    pub round_trip: Duration,
+    pub stratum: u8,
+}
+
+impl Sample {
+    fn new(nonce: u64, sent: Instant, received: Instant, response: &NtpPacket) -> Result<Self, Fail> {
+        let (server_rx, server_tx) = response.validate_response(nonce)?;
+        let processing = server_tx.checked_sub(server_rx).unwrap_or_default();
+        let round_trip = received
+            .duration_since(sent)
+            .checked_sub(processing)
+            .unwrap_or_default();
+        Ok(Self {
+            reference: received,
+            unix_time: server_tx + round_trip / 2,
+            round_trip,
+            stratum: response.stratum,
+        })
+    }
+
+    /// Wall-clock time at `now` on the runtime's clock.
+    pub fn wall_clock(&self, now: Instant) -> SystemTime {
+        let unix_time = if now >= self.reference {
+            self.unix_time + (now - self.reference)
+        } else {
+            self.unix_time
+                .checked_sub(self.reference - now)
+                .unwrap_or_default()
+        };
+        UNIX_EPOCH + unix_time
+    }
+}
+
+#[derive(Default)]
+struct State {
+    sample: Option<Sample>,
+    num_requests: u64,
+    num_responses: u64,
+}
+
+pub struct SntpClient<RT: Runtime> {
+    rt: RT,
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+    state: Rc<RefCell<State>>,
+
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> SntpClient<RT> {
+    pub fn new(rt: RT, udp: udp::Peer<RT>, options: SntpOptions) -> Result<Self, Fail> {
+        let fd = udp.socket();
+        let local = ipv4::Endpoint::new(rt.local_ipv4_addr(), options.local_port);
+        if let Err(e) = udp.bind(fd, local) {
+            udp.close(fd)?;
+            return Err(e);
+        }
+        let state = Rc::new(RefCell::new(State::default()));
+        let future = Self::background(rt.clone(), udp.clone(), fd, options, state.clone());
+        let handle = rt.spawn(future);
+        Ok(Self {
+            rt,
+            udp,
+            fd,
+            state,
+            handle,
+        })
+    }
+
+    /// The most recent successful sample, if any.
+    pub fn sample(&self) -> Option<Sample> {
+        self.state.borrow().sample
+    }
+
+    /// Current wall-clock time, or `None` if we haven't heard from the server yet.
+    pub fn wall_clock(&self) -> Option<SystemTime> {
+        self.sample().map(|s| s.wall_clock(self.rt.now()))
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    pub fn num_requests(&self) -> u64 {
+        self.state.borrow().num_requests
+    }
+
+    pub fn num_responses(&self) -> u64 {
+        self.state.borrow().num_responses
+    }
+
+    async fn background(
+        rt: RT,
+        udp: udp::Peer<RT>,
+        fd: FileDescriptor,
+        options: SntpOptions,
+        state: Rc<RefCell<State>>,
+    ) {
+        loop {
+            let nonce: u64 = rt.rng_gen();
+            let request = NtpPacket::request(nonce).serialize();
+            let sent = rt.now();
+            if let Err(e) = udp.pushto(fd, RT::Buf::from_slice(&request[..]), options.server) {
+                warn!("Failed to send SNTP request: {:?}", e);
+            }
+            state.borrow_mut().num_requests += 1;
+
+            let deadline = sent + options.request_timeout;
+            let sample = loop {
+                let pop_future = udp.pop(fd).fuse();
+                let timeout = rt.wait_until(deadline).fuse();
+                futures::pin_mut!(pop_future);
+                futures::pin_mut!(timeout);
+                futures::select_biased! {
+                    r = pop_future => {
+                        let buf = match r {
+                            Ok((Some(remote), buf)) if remote == options.server => buf,
+                            Ok((remote, _)) => {
+                                debug!("Dropping SNTP datagram from {:?}", remote);
+                                continue;
+                            },
+                            Err(e) => {
+                                warn!("SNTP socket failed: {:?}", e);
+                                break None;
+                            },
+                        };
+                        let r = NtpPacket::parse(&buf[..])
+                            .and_then(|response| Sample::new(nonce, sent, rt.now(), &response));
+                        match r {
+                            Ok(sample) => break Some(sample),
+                            Err(e) => debug!("Ignoring SNTP response: {:?}", e),
+                        }
+                    },
+                    _ = timeout => break None,
+                }
+            };
+
+            match sample {
+                Some(sample) => {
+                    debug!("SNTP sample: {:?}", sample);
+                    let mut state = state.borrow_mut();
+                    state.sample = Some(sample);
+                    state.num_responses += 1;
+                },
+                None => {
+                    warn!("SNTP request to {:?} failed", options.server);
+                    rt.wait_until(deadline).await;
+                    continue;
+                },
+            }
+            rt.wait_until(sent + options.poll_interval).await;
+        }
+    }
+}
+
+impl<RT: Runtime> Drop for SntpClient<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close SNTP socket: {:?}", e);
+        }
+    }
+}
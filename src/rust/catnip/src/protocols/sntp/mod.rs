@@ -0,0 +1,22 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! A Simple Network Time Protocol (RFC 4330) client.
+//!
+//! Engines running on dedicated cores may not have any other source of wall-clock time, so the
+//! client periodically queries a single NTP server over UDP and keeps the most recent sample.
+//! Each sample pins a point on the runtime's monotonic clock to the server's estimate of Unix time
+//! at that instant, which is all we need to turn `Runtime::now` into wall-clock time.
+
+mod client;
+mod options;
+pub mod packet;
+
+#[cfg(test)]
+mod tests;
+
+pub use client::{
+    Sample,
+    SntpClient as Client,
+};
+pub use options::SntpOptions as Options;
@@ -0,0 +1,51 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::{
+    ip,
+    ipv4,
+};
+use std::{
+    convert::TryFrom,
+    time::Duration,
+};
+
+const NTP_PORT: u16 = 123;
+
+#[derive(Clone, Debug)]
+pub struct SntpOptions {
+    pub server: ipv4::Endpoint,
+    pub local_port: ip::Port,
+    /// Time between successful queries. RFC 4330 asks clients not to poll more than once a minute.
+    pub poll_interval: Duration,
+    /// How long to wait for a response before retrying.
+    pub request_timeout: Duration,
+}
+
+impl SntpOptions {
+    pub fn new(server: ipv4::Endpoint) -> Self {
+        SntpOptions {
+            server,
+            local_port: ip::Port::try_from(NTP_PORT).unwrap(),
+            poll_interval: Duration::from_secs(64),
+            request_timeout: Duration::from_secs(1),
+        }
+    }
+
+    pub fn local_port(mut self, value: ip::Port) -> Self {
+        self.local_port = value;
+        self
+    }
+
+    pub fn poll_interval(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.poll_interval = value;
+        self
+    }
+
+    pub fn request_timeout(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.request_timeout = value;
+        self
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::fail::Fail;
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::time::Duration;
+
+pub const NTP_PACKET_SIZE: usize = 48;
+
+pub const MODE_CLIENT: u8 = 3;
+pub const MODE_SERVER: u8 = 4;
+
+const NTP_VERSION: u8 = 4;
+// Leap indicator value for a server whose clock isn't synchronized.
+const LEAP_ALARM: u8 = 3;
+
+// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// The fixed 48-byte header of an NTP packet. We don't send or parse extension fields, and the
+/// root delay, dispersion, and reference fields are carried through without interpretation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NtpPacket {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: u32,
+    pub reference_timestamp: u64,
+    pub originate_timestamp: u64,
+    pub receive_timestamp: u64,
+    pub transmit_timestamp: u64,
+}
+
+impl NtpPacket {
+    /// A client request. RFC 4330 lets clients put any value in the transmit timestamp, since
+    /// the server only echoes it back, so we use a random nonce to match responses to requests
+    /// instead of our (nonexistent) wall-clock time.
+    pub fn request(nonce: u64) -> Self {
+        NtpPacket {
+            leap: 0,
+            version: NTP_VERSION,
+            mode: MODE_CLIENT,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: 0,
+            reference_timestamp: 0,
+            originate_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp: nonce,
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Fail> {
+        if buf.len() < NTP_PACKET_SIZE {
+            return Err(Fail::Malformed {
+                details: "NTP packet too small",
+            });
+        }
+        Ok(NtpPacket {
+            leap: buf[0] >> 6,
+            version: (buf[0] >> 3) & 0x7,
+            mode: buf[0] & 0x7,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: NetworkEndian::read_u32(&buf[4..8]),
+            root_dispersion: NetworkEndian::read_u32(&buf[8..12]),
+            reference_id: NetworkEndian::read_u32(&buf[12..16]),
+            reference_timestamp: NetworkEndian::read_u64(&buf[16..24]),
+            originate_timestamp: NetworkEndian::read_u64(&buf[24..32]),
+            receive_timestamp: NetworkEndian::read_u64(&buf[32..40]),
+            transmit_timestamp: NetworkEndian::read_u64(&buf[40..48]),
+        })
+    }
+
+    pub fn serialize(&self) -> [u8; NTP_PACKET_SIZE] {
+        let mut buf = [0u8; NTP_PACKET_SIZE];
+        buf[0] = (self.leap << 6) | ((self.version & 0x7) << 3) | (self.mode & 0x7);
+        buf[1] = self.stratum;
+        buf[2] = self.poll as u8;
+        buf[3] = self.precision as u8;
+        NetworkEndian::write_u32(&mut buf[4..8], self.root_delay);
+        NetworkEndian::write_u32(&mut buf[8..12], self.root_dispersion);
+        NetworkEndian::write_u32(&mut buf[12..16], self.reference_id);
+        NetworkEndian::write_u64(&mut buf[16..24], self.reference_timestamp);
+        NetworkEndian::write_u64(&mut buf[24..32], self.originate_timestamp);
+        NetworkEndian::write_u64(&mut buf[32..40], self.receive_timestamp);
+        NetworkEndian::write_u64(&mut buf[40..48], self.transmit_timestamp);
+        buf
+    }
+
+    /// Check that this is a usable server response to the request carrying `nonce`, returning its
+    /// receive and transmit timestamps as offsets from the Unix epoch.
+    pub fn validate_response(&self, nonce: u64) -> Result<(Duration, Duration), Fail> {
+        if self.mode != MODE_SERVER {
+            return Err(Fail::Ignored {
+                details: "NTP packet isn't a server response",
+            });
+        }
+        if self.originate_timestamp != nonce {
+            return Err(Fail::Ignored {
+                details: "NTP response doesn't match outstanding request",
+            });
+        }
+        // Stratum zero is a "kiss-o'-death" message telling us to back off.
+        if self.stratum == 0 || self.leap == LEAP_ALARM {
+            return Err(Fail::ResourceBusy {
+                details: "NTP server is unsynchronized or refused the request",
+            });
+        }
+        let receive = ntp_to_unix(self.receive_timestamp).ok_or(Fail::Malformed {
+            details: "Invalid NTP receive timestamp",
+        })?;
+        let transmit = ntp_to_unix(self.transmit_timestamp).ok_or(Fail::Malformed {
+            details: "Invalid NTP transmit timestamp",
+        })?;
+        Ok((receive, transmit))
+    }
+}
+
+/// Convert a 64-bit NTP timestamp to an offset from the Unix epoch. Following RFC 4330 section 3,
+/// timestamps with the high bit clear are taken to be in era 1 (after 2036).
+pub fn ntp_to_unix(timestamp: u64) -> Option<Duration> {
+    if timestamp == 0 {
+        return None;
+    }
+    let mut secs = timestamp >> 32;
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    let secs = secs.checked_sub(NTP_UNIX_OFFSET)?;
+    let nanos = ((timestamp & 0xffff_ffff) * 1_000_000_000) >> 32;
+    Some(Duration::new(secs, nanos as u32))
+}
+
+pub fn unix_to_ntp(time: Duration) -> u64 {
+    let secs = (time.as_secs() + NTP_UNIX_OFFSET) & 0xffff_ffff;
+    let frac = ((time.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
@@ -0,0 +1,129 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    packet::{
+        self,
+        NtpPacket,
+        MODE_SERVER,
+    },
+    Options,
+};
+use crate::{
+    engine::Protocol,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    runtime::RuntimeBuf,
+    sync::Bytes,
+    test_helpers,
+};
+use futures::task::{
+    noop_waker_ref,
+    Context,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::Poll,
+    time::{
+        Duration,
+        Instant,
+        UNIX_EPOCH,
+    },
+};
+
+#[test]
+fn timestamp_conversion() {
+    let t = Duration::new(1_600_000_000, 500_000_000);
+    let ntp = packet::unix_to_ntp(t);
+    assert_eq!(ntp >> 32, 1_600_000_000 + 2_208_988_800);
+    let back = packet::ntp_to_unix(ntp).unwrap();
+    assert!(t - back < Duration::from_nanos(2));
+
+    // Era 1 starts in 2036, when the 32-bit seconds field wraps.
+    let t = Duration::from_secs(2_100_000_000);
+    assert_eq!(packet::ntp_to_unix(packet::unix_to_ntp(t)).unwrap(), t);
+    assert_eq!(packet::ntp_to_unix(0), None);
+}
+
+#[test]
+fn sync_wall_clock() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    // Bob plays the NTP server.
+    let server = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(123).unwrap());
+    let server_fd = bob.socket(Protocol::Udp);
+    bob.bind(server_fd, server).unwrap();
+
+    let options = Options::new(server)
+        .local_port(ip::Port::try_from(50123).unwrap())
+        .poll_interval(Duration::from_secs(64));
+    alice.sntp_start(options).unwrap();
+    assert_eq!(alice.wall_clock(), None);
+    alice.rt().poll_scheduler();
+
+    now += Duration::from_millis(1);
+    bob.rt().advance_clock(now);
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut pop_future = bob.udp_pop(server_fd);
+    must_let!(let Poll::Ready(Ok((Some(client), request))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    let request = NtpPacket::parse(&request[..]).unwrap();
+
+    // The server's clock reads 1600000000s when the request arrives and takes 1ms to reply, and
+    // the reply takes 1ms to get back to Alice.
+    let server_rx = Duration::from_secs(1_600_000_000);
+    let mut reply = NtpPacket::request(0);
+    reply.mode = MODE_SERVER;
+    reply.stratum = 1;
+    reply.originate_timestamp = request.transmit_timestamp;
+    reply.receive_timestamp = packet::unix_to_ntp(server_rx);
+    reply.transmit_timestamp = packet::unix_to_ntp(server_rx + Duration::from_millis(1));
+    now += Duration::from_millis(1);
+    bob.rt().advance_clock(now);
+    let _push = bob.pushto(server_fd, Bytes::from_slice(&reply.serialize()[..]), client);
+
+    now += Duration::from_millis(1);
+    alice.rt().advance_clock(now);
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+
+    let sample = alice.sntp_sample().unwrap();
+    assert_eq!(sample.round_trip, Duration::from_millis(2));
+    assert_eq!(sample.stratum, 1);
+
+    // One hour later, the wall clock has advanced by an hour.
+    now += Duration::from_secs(3600);
+    alice.rt().advance_clock(now);
+    let expected = UNIX_EPOCH + server_rx + Duration::from_millis(2) + Duration::from_secs(3600);
+    let wall_clock = alice.wall_clock().unwrap();
+    let error = match wall_clock.duration_since(expected) {
+        Ok(d) => d,
+        Err(e) => e.duration(),
+    };
+    assert!(error < Duration::from_micros(1), "{:?}", error);
+}
+
+#[test]
+fn reject_mismatched_response() {
+    let mut response = NtpPacket::request(0);
+    response.mode = MODE_SERVER;
+    response.stratum = 2;
+    response.originate_timestamp = 1234;
+    response.receive_timestamp = packet::unix_to_ntp(Duration::from_secs(1_600_000_000));
+    response.transmit_timestamp = response.receive_timestamp;
+    let response = NtpPacket::parse(&response.serialize()[..]).unwrap();
+    assert!(response.validate_response(1234).is_ok());
+    assert!(response.validate_response(4321).is_err());
+
+    // Kiss-o'-death.
+    let mut kod = response.clone();
+    kod.stratum = 0;
+    assert!(kod.validate_response(1234).is_err());
+}
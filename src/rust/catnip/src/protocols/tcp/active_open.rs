@@ -1,8 +1,18 @@
 use super::{
-    constants::FALLBACK_MSS,
+    constants::{
+        FALLBACK_MSS,
+        MAX_WINDOW_SCALE,
+        MIN_MSS,
+    },
     established::state::{
+        connection_log,
         receiver::Receiver,
         sender::Sender,
+        timestamps::{
+            TimestampClock,
+            Timestamps,
+            TIMESTAMP_OPTION_SIZE,
+        },
         ControlBlock,
     },
 };
@@ -10,20 +20,14 @@ use crate::{
     fail::Fail,
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
-        },
         ipv4,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
-        },
+        ipv4::Egress,
+        packet::PacketBuilder,
         tcp::{
+            options::TcpMissingOptions,
             segment::{
                 TcpHeader,
                 TcpOptions2,
-                TcpSegment,
             },
             SeqNumber,
         },
@@ -31,10 +35,18 @@ use crate::{
     runtime::Runtime,
     runtime::RuntimeBuf,
     scheduler::SchedulerHandle,
+    stats::{
+        TcpLatencyRecorder,
+        TcpLatencyStats,
+        TcpThroughputRecorder,
+    },
 };
 use std::{
-    cell::RefCell,
-    convert::TryInto,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
     future::Future,
     num::Wrapping,
     rc::Rc,
@@ -59,6 +71,9 @@ pub struct ActiveOpenSocket<RT: Runtime> {
 
     rt: RT,
     arp: arp::Peer<RT>,
+    egress: Egress<RT>,
+    latency: Rc<RefCell<TcpLatencyStats>>,
+    ts_clock: TimestampClock,
 
     #[allow(unused)]
     handle: SchedulerHandle,
@@ -72,12 +87,15 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        egress: Egress<RT>,
+        latency: Rc<RefCell<TcpLatencyStats>>,
     ) -> Self {
         let result = ConnectResult {
             waker: None,
             result: None,
         };
         let result = Rc::new(RefCell::new(result));
+        let ts_clock = TimestampClock::new(rt.now(), rt.rng_gen());
 
         let future = Self::background(
             local_isn,
@@ -85,6 +103,8 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             remote.clone(),
             rt.clone(),
             arp.clone(),
+            egress.clone(),
+            ts_clock,
             result.clone(),
         );
         let handle = rt.spawn(future);
@@ -96,6 +116,9 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             remote,
             rt,
             arp,
+            egress,
+            latency,
+            ts_clock,
 
             handle,
             result,
@@ -132,66 +155,79 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         }
 
         // Acknowledge the SYN+ACK segment.
-        debug!("Received SYN+ACK: {:?}", header);
+        debug!("Received SYN+ACK: {}", header);
         let remote_link_addr = match self.arp.try_query(self.remote.address()) {
             Some(r) => r,
-            None => panic!("TODO: Clean up ARP query control flow"),
-        };
-        let remote_seq_num = header.seq_num + Wrapping(1);
-        let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
-        tcp_hdr.ack = true;
-        tcp_hdr.ack_num = remote_seq_num;
-        debug!("Sending ACK: {:?}", tcp_hdr);
-
-        let tcp_options = self.rt.tcp_options();
-        let segment = TcpSegment {
-            ethernet2_hdr: Ethernet2Header {
-                dst_addr: remote_link_addr,
-                src_addr: self.rt.local_link_addr(),
-                ether_type: EtherType2::Ipv4,
+            None => {
+                // The entry expired mid-handshake. Our SYN retransmits re-resolve it, and the
+                // remote will retransmit its SYN+ACK.
+                warn!("Dropping SYN+ACK from {:?}: not in ARP cache", self.remote);
+                return;
             },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
-            tcp_hdr,
-            data: RT::Buf::empty(),
-            tx_checksum_offload: tcp_options.tx_checksum_offload,
         };
-        self.rt.transmit(segment);
-
+        let tcp_options = self.rt.tcp_options();
         let mut remote_window_scale = None;
         let mut mss = FALLBACK_MSS;
+        let mut remote_mss = false;
+        let mut sack_permitted = false;
+        let mut remote_timestamp = None;
         for option in header.iter_options() {
             match option {
                 TcpOptions2::WindowScale(w) => {
                     info!("Received window scale: {}", w);
-                    remote_window_scale = Some(*w);
+                    remote_window_scale = Some(cmp::min(*w, MAX_WINDOW_SCALE));
                 },
                 TcpOptions2::MaximumSegmentSize(m) => {
                     info!("Received advertised MSS: {}", m);
-                    mss = *m as usize;
+                    remote_mss = true;
+                    // Clamped to what we advertise, which is sized for our own link.
+                    mss = cmp::max(cmp::min(*m as usize, tcp_options.advertised_mss), MIN_MSS);
+                },
+                // Only if we offered it, although a well-behaved remote wouldn't send it otherwise.
+                TcpOptions2::SelectiveAcknowlegementPermitted => sack_permitted = tcp_options.sack,
+                TcpOptions2::Timestamp {
+                    sender_timestamp,
+                    echo_timestamp,
+                } if tcp_options.timestamps => {
+                    remote_timestamp = Some((*sender_timestamp, *echo_timestamp));
                 },
                 _ => continue,
             }
         }
-
-        let (local_window_scale, remote_window_scale) = match remote_window_scale {
-            Some(w) => (tcp_options.window_scale as u32, w),
-            None => (0, 0),
+        let missing_options = TcpMissingOptions {
+            mss: !remote_mss,
+            window_scale: remote_window_scale.is_none(),
+            sack: tcp_options.sack && !sack_permitted,
+            timestamps: tcp_options.timestamps && remote_timestamp.is_none(),
         };
+        let now = self.rt.now();
+        let timestamps = remote_timestamp.map(|(tsval, _)| Timestamps::new(self.ts_clock, tsval, now));
 
-        // TODO(RFC1323): Clamp the scale to 14 instead of panicking.
-        assert!(local_window_scale <= 14 && remote_window_scale <= 14);
+        let remote_seq_num = header.seq_num + Wrapping(1);
+        let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
+        tcp_hdr.ack = true;
+        tcp_hdr.ack_num = remote_seq_num;
+        if let Some(ref timestamps) = timestamps {
+            tcp_hdr.push_option(timestamps.option(now));
+        }
+        debug!("Sending ACK: {}", tcp_hdr);
 
-        let rx_window_size: u32 = (tcp_options.receive_window_size as u32)
-            .checked_shl(local_window_scale as u32)
-            .expect("TODO: Window size overflow")
-            .try_into()
-            .expect("TODO: Window size overflow");
+        let segment = PacketBuilder::new(&self.rt, remote_link_addr, self.remote.addr)
+            .src_addr(self.local.addr)
+            .tcp(tcp_hdr, RT::Buf::empty(), tcp_options.tx_checksum_offload);
+        self.egress.transmit(segment);
 
-        let tx_window_size: u32 = (header.window_size as u32)
-            .checked_shl(remote_window_scale as u32)
-            .expect("TODO: Window size overflow")
-            .try_into()
-            .expect("TODO: Window size overflow");
+        // Without the remote's agreement, neither side scales and our window stops at 64KB.
+        let (local_window_scale, remote_window_scale, rx_window_size) = match remote_window_scale {
+            Some(w) => (
+                tcp_options.local_window_scale() as u32,
+                w,
+                tcp_options.receive_window_size,
+            ),
+            None => (0, 0, tcp_options.unscaled_receive_window() as u32),
+        };
+        // RFC 7323 2.2: The window in a SYN is never scaled.
+        let tx_window_size = header.window_size as u32;
 
         info!(
             "Window sizes: local {}, remote {}",
@@ -202,15 +238,48 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             local_window_scale, remote_window_scale
         );
 
-        let sender = Sender::new(expected_seq, tx_window_size, remote_window_scale, mss);
-        let receiver = Receiver::new(remote_seq_num, rx_window_size, local_window_scale);
+        // The MSS doesn't allow for options, so make room for the timestamp in every segment.
+        if timestamps.is_some() {
+            mss -= TIMESTAMP_OPTION_SIZE;
+        }
+        let sender = Sender::new(
+            expected_seq,
+            tx_window_size,
+            remote_window_scale,
+            mss,
+            sack_permitted,
+            self.rt.tcp_options().nagle,
+            self.rt.tcp_options().send_buffer_size,
+            self.rt.tcp_options().min_rto,
+            self.rt.tcp_options().max_rto,
+        );
+        // The SYN+ACK's echo of our SYN's timestamp gives a first RTT sample.
+        if let (Some(timestamps), Some((_, tsecr))) = (&timestamps, remote_timestamp) {
+            if let Some(rtt) = timestamps.rtt(tsecr, now) {
+                sender.rto.borrow_mut().add_sample(rtt);
+            }
+        }
+        let receiver = Receiver::new(
+            remote_seq_num,
+            rx_window_size,
+            local_window_scale,
+            self.rt.tcp_options().reassembly_limit,
+        );
         let cb = ControlBlock {
             local: self.local.clone(),
             remote: self.remote.clone(),
             rt: self.rt.clone(),
             arp: self.arp.clone(),
+            egress: self.egress.clone(),
             sender,
             receiver,
+            latency: TcpLatencyRecorder::new(self.latency.clone()),
+            throughput: TcpThroughputRecorder::new(self.rt.now()),
+            log: connection_log(&self.rt, self.local, self.remote),
+            ack_template: RefCell::new(None),
+            timestamps,
+            soft_errors: Cell::new(0),
+            missing_options,
         };
         self.set_result(Ok(cb));
     }
@@ -221,6 +290,8 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        egress: Egress<RT>,
+        ts_clock: TimestampClock,
         result: Rc<RefCell<ConnectResult<RT>>>,
     ) -> impl Future<Output = ()> {
         let tcp_options = rt.tcp_options();
@@ -240,28 +311,32 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                 let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
                 tcp_hdr.syn = true;
                 tcp_hdr.seq_num = local_isn;
-                tcp_hdr.window_size = tcp_options.receive_window_size;
+                tcp_hdr.window_size = tcp_options.unscaled_receive_window();
 
                 let mss = tcp_options.advertised_mss as u16;
                 tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
                 info!("Advertising MSS: {}", mss);
 
-                tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale));
-                info!("Advertising window scale: {}", tcp_options.window_scale);
+                let window_scale = tcp_options.local_window_scale();
+                tcp_hdr.push_option(TcpOptions2::WindowScale(window_scale));
+                info!("Advertising window scale: {}", window_scale);
 
-                debug!("Sending SYN {:?}", tcp_hdr);
-                let segment = TcpSegment {
-                    ethernet2_hdr: Ethernet2Header {
-                        dst_addr: remote_link_addr,
-                        src_addr: rt.local_link_addr(),
-                        ether_type: EtherType2::Ipv4,
-                    },
-                    ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
-                    tcp_hdr,
-                    data: RT::Buf::empty(),
-                    tx_checksum_offload: tcp_options.tx_checksum_offload,
-                };
-                rt.transmit(segment);
+                if tcp_options.sack {
+                    tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+                }
+
+                if tcp_options.timestamps {
+                    tcp_hdr.push_option(TcpOptions2::Timestamp {
+                        sender_timestamp: ts_clock.tsval(rt.now()),
+                        echo_timestamp: 0,
+                    });
+                }
+
+                debug!("Sending SYN: {}", tcp_hdr);
+                let segment = PacketBuilder::new(&rt, remote_link_addr, remote.addr)
+                    .src_addr(local.addr)
+                    .tcp(tcp_hdr, RT::Buf::empty(), tcp_options.tx_checksum_offload);
+                egress.transmit(segment);
                 rt.wait(handshake_timeout).await;
             }
             let mut r = result.borrow_mut();
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 // from [TCP/IP Illustrated](https://learning.oreilly.com/library/view/tcpip-illustrated-volume/9780132808200/ch13.html):
 // > if no MSS option is provided, a default value of 536 bytes is used.
 pub const FALLBACK_MSS: usize = 536;
@@ -5,5 +7,14 @@ pub const FALLBACK_MSS: usize = 536;
 pub const MIN_MSS: usize = 536;
 pub const MAX_MSS: usize = u16::max_value() as usize;
 
+// RFC 1323 2.3: Larger shifts from the remote are treated as 14.
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+// The largest window the scale can express.
+pub const MAX_WINDOW_SIZE: u32 = (u16::max_value() as u32) << MAX_WINDOW_SCALE;
+
 // TODO: does this need to be determined through MTU discovery?
 pub const DEFAULT_MSS: usize = 1450;
+
+// RFC 1122 4.2.3.2: An ACK must not be delayed longer than this.
+pub const MAX_DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(500);
@@ -8,10 +8,7 @@ use crate::{
     runtime::{Runtime, RuntimeBuf},
 };
 use futures::FutureExt;
-use std::{
-    num::Wrapping,
-    rc::Rc,
-};
+use std::rc::Rc;
 
 async fn rx_ack_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     loop {
@@ -21,33 +18,47 @@ async fn rx_ack_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail>
             continue;
         }
 
-        // Wait for all data to be acknowledged.
-        let (ack_seq, ack_seq_changed) = cb.receiver.ack_seq_no.watch();
-        let recv_seq = cb.receiver.recv_seq_no.get();
-
-        if ack_seq != recv_seq {
-            ack_seq_changed.await;
-            continue;
-        }
-
-        // Send ACK segment
+        // Acknowledge the FIN along with any data before it. This goes out even if we've already
+        // sent that ACK, since a retransmitted FIN means the remote never got it.
         cb.receiver.state.set(ReceiverState::AckdFin);
-        let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+        let remote_link_addr = cb.remote_link_addr().await?;
         let mut header = cb.tcp_header();
         header.ack = true;
-        header.ack_num = recv_seq + Wrapping(1);
+        header.ack_num = cb.receiver.ack_num();
         cb.emit(header, RT::Buf::empty(), remote_link_addr);
     }
 }
 
 async fn tx_fin_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    let mut fin_retries = 0;
     loop {
         let (sender_st, sender_st_changed) = cb.sender.state.watch();
         match sender_st {
-            SenderState::Open | SenderState::SentFin | SenderState::FinAckd => {
+            SenderState::Open | SenderState::FinAckd => {
                 sender_st_changed.await;
                 continue;
             },
+            SenderState::SentFin => {
+                // Resend the FIN each time the RTO passes without it being acknowledged.
+                let rto = cb.sender.rto.borrow().estimate();
+                futures::select_biased! {
+                    _ = sender_st_changed.fuse() => continue,
+                    _ = cb.rt.wait(rto).fuse() => (),
+                }
+                fin_retries += 1;
+                if fin_retries > cb.rt.tcp_options().retries {
+                    return Err(Fail::Timeout {});
+                }
+                cb.sender.rto.borrow_mut().record_failure();
+                let remote_link_addr = cb.remote_link_addr().await?;
+                let mut header = cb.tcp_header();
+                header.seq_num = cb.sender.sent_seq_no.get();
+                header.ack = true;
+                header.ack_num = cb.receiver.ack_num();
+                header.fin = true;
+                cb.emit(header, RT::Buf::empty(), remote_link_addr);
+                cb.throughput.record_retransmitted();
+            },
             SenderState::Closed => {
                 // Wait for `sent_seq_no` to catch up to `unsent_seq_no` and
                 // then send a FIN segment.
@@ -59,17 +70,20 @@ async fn tx_fin_sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail>
                     continue;
                 }
 
-                // TODO: When do we retransmit this?
-                let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+                // The FIN takes the sequence number after the last byte of data, and the ACK for
+                // it is the one after that.
+                let remote_link_addr = cb.remote_link_addr().await?;
                 let mut header = cb.tcp_header();
-                header.seq_num = sent_seq + Wrapping(1);
+                header.seq_num = sent_seq;
+                header.ack = true;
+                header.ack_num = cb.receiver.ack_num();
                 header.fin = true;
                 cb.emit(header, RT::Buf::empty(), remote_link_addr);
 
                 cb.sender.state.set(SenderState::SentFin);
             },
             SenderState::Reset => {
-                let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+                let remote_link_addr = cb.remote_link_addr().await?;
                 let mut header = cb.tcp_header();
                 header.rst = true;
                 cb.emit(header, RT::Buf::empty(), remote_link_addr);
@@ -93,8 +107,17 @@ async fn close_wait<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             continue;
         }
 
-        // TODO: Wait for 2*MSL if active close.
-        return Err(Fail::ConnectionAborted {});
+        if cb.sender.passive_close.get() {
+            return Err(Fail::ConnectionAborted {});
+        }
+
+        // We closed first, so linger in TIME_WAIT for 2*MSL. A retransmitted FIN means our last
+        // ACK was lost: it's acknowledged again and the wait starts over.
+        let time_wait = cb.rt.tcp_options().msl * 2;
+        futures::select_biased! {
+            _ = receiver_st_changed.fuse() => continue,
+            _ = cb.rt.wait(time_wait).fuse() => return Err(Fail::ConnectionAborted {}),
+        }
     }
 }
 
@@ -0,0 +1,70 @@
+use crate::{
+    collections::watched::WatchedValue,
+    runtime::Runtime,
+};
+use futures::{
+    future::Fuse,
+    FutureExt,
+};
+use std::{
+    pin::Pin,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// A connection timer on the runtime's shared timer heap that follows a deadline without
+/// rescheduling on every change. Deadlines are rounded up to the TCP timer granularity, counted
+/// from an engine-wide origin so every connection's buckets line up, and a deadline that moves
+/// later keeps the timer already armed: it fires early, finds the deadline hasn't passed, and
+/// only then re-arms. So pushing back the retransmit timer on each ACK costs nothing.
+pub struct DeadlineTimer<RT: Runtime> {
+    rt: RT,
+    origin: Instant,
+    armed: Option<Instant>,
+    wait: Pin<Box<Fuse<RT::WaitFuture>>>,
+}
+
+impl<RT: Runtime> DeadlineTimer<RT> {
+    pub fn new(rt: RT, origin: Instant) -> Self {
+        Self {
+            rt,
+            origin,
+            armed: None,
+            wait: Box::pin(Fuse::terminated()),
+        }
+    }
+
+    /// Returns once `deadline` is set and has passed.
+    pub async fn expired(&mut self, deadline: &WatchedValue<Option<Instant>>) {
+        loop {
+            let (current, changed) = deadline.watch();
+            futures::pin_mut!(changed);
+            if let Some(current) = current {
+                if current <= self.rt.now() {
+                    return;
+                }
+                let bucket = self.round_up(current);
+                if self.armed.map_or(true, |armed| bucket < armed) {
+                    self.wait.set(self.rt.wait_until(bucket).fuse());
+                    self.armed = Some(bucket);
+                }
+            }
+            futures::select_biased! {
+                _ = changed => (),
+                _ = self.wait.as_mut() => self.armed = None,
+            }
+        }
+    }
+
+    fn round_up(&self, deadline: Instant) -> Instant {
+        let granularity = self.rt.tcp_options().timer_granularity.as_nanos();
+        if granularity == 0 {
+            return deadline;
+        }
+        let since = deadline.saturating_duration_since(self.origin).as_nanos();
+        let buckets = (since + granularity - 1) / granularity;
+        self.origin + Duration::from_nanos((buckets * granularity) as u64)
+    }
+}
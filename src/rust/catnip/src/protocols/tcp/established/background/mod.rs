@@ -1,11 +1,13 @@
 mod acknowledger;
 mod closer;
+mod deadline;
 mod retransmitter;
 mod sender;
 
 use self::{
     acknowledger::acknowledger,
     closer::closer,
+    deadline::DeadlineTimer,
     retransmitter::retransmitter,
     sender::sender,
 };
@@ -19,6 +21,7 @@ use futures::FutureExt;
 use std::{
     future::Future,
     rc::Rc,
+    time::Instant,
 };
 
 // TODO: This type is quite large. We may have to switch back to manual combinators?
@@ -32,12 +35,15 @@ pub fn background<RT: Runtime>(
     cb: Rc<ControlBlock<RT>>,
     fd: FileDescriptor,
     dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
+    timer_origin: Instant,
 ) -> BackgroundFuture<RT> {
     async move {
-        let acknowledger = acknowledger(cb.clone()).fuse();
+        let ack_timer = DeadlineTimer::new(cb.rt.clone(), timer_origin);
+        let acknowledger = acknowledger(cb.clone(), ack_timer).fuse();
         futures::pin_mut!(acknowledger);
 
-        let retransmitter = retransmitter(cb.clone()).fuse();
+        let rtx_timer = DeadlineTimer::new(cb.rt.clone(), timer_origin);
+        let retransmitter = retransmitter(cb.clone(), rtx_timer).fuse();
         futures::pin_mut!(retransmitter);
 
         let sender = sender(cb.clone()).fuse();
@@ -1,59 +1,82 @@
-use super::super::state::ControlBlock;
+use super::{
+    super::state::ControlBlock,
+    deadline::DeadlineTimer,
+};
 use crate::{
     fail::Fail,
+    logging::RecordKind,
     runtime::Runtime,
+    sampled,
 };
-use futures::{
-    future::{
-        self,
-        Either,
-    },
-    FutureExt,
+use log::Level;
+use std::{
+    num::Wrapping,
+    rc::Rc,
 };
-use std::rc::Rc;
 
-pub async fn retransmitter<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+pub async fn retransmitter<RT: Runtime>(
+    cb: Rc<ControlBlock<RT>>,
+    mut timer: DeadlineTimer<RT>,
+) -> Result<!, Fail> {
     loop {
-        let (rtx_deadline, rtx_deadline_changed) = cb.sender.retransmit_deadline.watch();
-        futures::pin_mut!(rtx_deadline_changed);
+        timer.expired(&cb.sender.retransmit_deadline).await;
+        // Our retransmission timer fired, so we need to resend a packet.
+        let remote_link_addr = cb.remote_link_addr().await?;
+        // The remote may have dropped data it selectively acknowledged, so a timeout
+        // starts the scoreboard over.
+        cb.sender.clear_sacked();
 
-        let rtx_future = match rtx_deadline {
-            Some(t) => Either::Left(cb.rt.wait_until(t).fuse()),
-            None => Either::Right(future::pending()),
-        };
-        futures::pin_mut!(rtx_future);
-        futures::select_biased! {
-            _ = rtx_deadline_changed => continue,
-            _ = rtx_future => {
-                // Our retransmission timer fired, so we need to resend a packet.
-                let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
-
-                let mut unacked_queue = cb.sender.unacked_queue.borrow_mut();
-                let mut rto = cb.sender.rto.borrow_mut();
-
-                let seq_no = cb.sender.base_seq_no.get();
-                let segment = match unacked_queue.front_mut() {
-                    Some(s) => s,
-                    None => panic!("Retransmission timer set with empty acknowledge queue"),
-                };
-
-                // TODO: Repacketization
-                // TODO: Congestion control
-                rto.record_failure();
-
-                // Unset the initial timestamp so we don't use this for RTT estimation.
-                segment.initial_tx.take();
-
-                let mut header = cb.tcp_header();
-                header.seq_num = seq_no;
-                let rto_estimate = rto.estimate();
-                debug!("Retransmitting {} bytes, new_estimate {:?}: {:?}", segment.bytes.len(), rto_estimate, header);
-                cb.emit(header, segment.bytes.clone(), remote_link_addr);
-
-                // Set new retransmit deadline
-                let deadline = cb.rt.now() + rto_estimate; 
-                cb.sender.retransmit_deadline.set(Some(deadline));
-            },
+        // RFC 4821: Timing out again and again can mean that segments our size vanish on the
+        // path without anyone saying so, so try smaller ones.
+        let timeouts = cb.sender.consecutive_timeouts.get() + 1;
+        cb.sender.consecutive_timeouts.set(timeouts);
+        let blackhole_timeouts = cb.rt.tcp_options().blackhole_timeouts;
+        if blackhole_timeouts > 0 && timeouts >= blackhole_timeouts {
+            let mss = cb.sender.mss.get();
+            if cb.sender.reduce_mss(mss / 2) {
+                warn!(
+                    "{} retransmission timeouts in a row to {:?}: suspecting a PMTU black hole, \
+                     MSS now {}",
+                    timeouts,
+                    cb.remote,
+                    cb.sender.mss.get()
+                );
+            }
         }
+
+        let mut unacked_queue = cb.sender.unacked_queue.borrow_mut();
+        let mut rto = cb.sender.rto.borrow_mut();
+
+        let seq_no = cb.sender.base_seq_no.get();
+        let segment = match unacked_queue.front_mut() {
+            Some(s) => s,
+            None => {
+                warn!("Retransmission timer set with empty acknowledge queue");
+                cb.sender.retransmit_deadline.set(None);
+                continue;
+            },
+        };
+
+        rto.record_failure();
+        let sent_seq_no = cb.sender.sent_seq_no.get();
+        let Wrapping(flight_size) = sent_seq_no - seq_no;
+        let first = segment.initial_tx.is_some();
+        cb.sender.congestion.on_timeout(sent_seq_no, flight_size, first);
+
+        // Unset the initial timestamp so we don't use this for RTT estimation.
+        segment.initial_tx.take();
+
+        let mut header = cb.tcp_header();
+        header.seq_num = seq_no;
+        let rto_estimate = rto.estimate();
+        sampled!(cb.log, cb.rt.now(), Level::Debug, "Retransmitting {} bytes, new_estimate {:?}: {}", segment.bytes.len(), rto_estimate, header);
+        cb.log_record(RecordKind::Retransmit, &header, segment.bytes.len());
+        cb.emit(header, segment.bytes.clone(), remote_link_addr);
+        cb.throughput.record_retransmitted();
+        cb.check_invariants();
+
+        // Set new retransmit deadline
+        let deadline = cb.rt.now() + rto_estimate; 
+        cb.sender.retransmit_deadline.set(Some(deadline));
     }
 }
@@ -1,5 +1,8 @@
 use super::super::state::{
-    sender::UnackedSegment,
+    sender::{
+        TcpSendStatus,
+        UnackedSegment,
+    },
     ControlBlock,
 };
 use crate::{
@@ -14,6 +17,9 @@ use std::{
     time::Duration,
 };
 
+// Window probes back off until they're this far apart, the longest RTO.
+const MAX_PERSIST_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     'top: loop {
         // First, check to see if there's any unsent data.
@@ -39,16 +45,20 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         // If we don't have any window size at all, we need to transition to PERSIST state and
         // repeatedly send window probes until window opens up.
         if win_sz == 0 {
-            let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
-            let buf = cb
+            let remote_link_addr = cb.remote_link_addr().await?;
+            let segment = cb
                 .sender
                 .pop_one_unsent_byte()
                 .unwrap_or_else(|| panic!("No unsent data? {}, {}", sent_seq, unsent_seq));
+            cb.latency
+                .record_send_queue_time(cb.rt.now() - segment.enqueued);
+            let buf = segment.bytes;
 
             cb.sender.sent_seq_no.modify(|s| s + Wrapping(1));
             let unacked_segment = UnackedSegment {
                 bytes: buf.clone(),
                 initial_tx: Some(cb.rt.now()),
+                sacked: false,
             };
             cb.sender
                 .unacked_queue
@@ -58,52 +68,98 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             let mut header = cb.tcp_header();
             header.seq_num = sent_seq;
             cb.emit(header, buf.clone(), remote_link_addr);
+            cb.check_invariants();
 
-            // Note that we loop here *forever*, exponentially backing off.
-            // TODO: Use the correct PERSIST state timer here.
-            let mut timeout = Duration::from_secs(1);
+            // RFC 1122 4.2.2.17: Probe on the RTO, backing off exponentially, for as long as the
+            // window stays closed. Each probe gets an ACK back, so we hear when it opens.
+            let since = cb.rt.now();
+            let mut timeout = cb.sender.rto.borrow().estimate();
+            let mut next_probe = since + timeout;
+            let mut probes = 1;
             loop {
-                futures::select_biased! {
-                    _ = win_sz_changed => continue 'top,
-                    _ = cb.rt.wait(timeout).fuse() => {
-                        timeout *= 2;
+                let (win_sz, win_sz_changed) = cb.sender.window_size.watch();
+                if win_sz > 0 {
+                    cb.sender.status.set(TcpSendStatus::Open);
+                    // An unacknowledged probe is data like any other now.
+                    if cb.sender.retransmit_deadline.get().is_none()
+                        && !cb.sender.unacked_queue.borrow().is_empty()
+                    {
+                        let rto = cb.sender.rto.borrow().estimate();
+                        cb.sender.retransmit_deadline.set(Some(cb.rt.now() + rto));
                     }
+                    continue 'top;
+                }
+                cb.sender.status.set(TcpSendStatus::ZeroWindow {
+                    since,
+                    probes,
+                    next_probe,
+                });
+                futures::select_biased! {
+                    _ = win_sz_changed.fuse() => continue,
+                    _ = cb.rt.wait_until(next_probe).fuse() => (),
                 }
                 // Retransmit our window probe.
                 let mut header = cb.tcp_header();
                 header.seq_num = sent_seq;
                 cb.emit(header, buf.clone(), remote_link_addr);
+                probes += 1;
+                timeout = cmp::min(timeout * 2, MAX_PERSIST_TIMEOUT);
+                next_probe = cb.rt.now() + timeout;
             }
         }
 
-        // The remote window is nonzero, but there still may not be room.
+        // The remote window is nonzero, but there still may not be room, in it or in the
+        // congestion window.
         let (base_seq, base_seq_changed) = cb.sender.base_seq_no.watch();
         futures::pin_mut!(base_seq_changed);
+        let (cwnd, cwnd_changed) = cb.sender.congestion.cwnd.watch();
+        futures::pin_mut!(cwnd_changed);
 
+        let win_sz = cmp::min(win_sz, cwnd);
         let Wrapping(sent_data) = sent_seq - base_seq;
         if win_sz <= sent_data {
             futures::select_biased! {
                 _ = base_seq_changed => continue 'top,
                 _ = sent_seq_changed => continue 'top,
                 _ = win_sz_changed => continue 'top,
+                _ = cwnd_changed => continue 'top,
+            }
+        }
+
+        // Nagle's algorithm: with data in flight, wait for a full segment's worth or an ACK.
+        let (_, nagle_changed) = cb.sender.nagle.watch();
+        futures::pin_mut!(nagle_changed);
+        let Wrapping(unsent_data) = unsent_seq - sent_seq;
+        let sendable = cmp::min(unsent_data, win_sz - sent_data);
+        if cb.sender.nagle_holds(sent_data, sendable) {
+            futures::select_biased! {
+                _ = base_seq_changed => continue 'top,
+                _ = unsent_seq_changed => continue 'top,
+                _ = nagle_changed => continue 'top,
+                _ = win_sz_changed => continue 'top,
+                _ = cwnd_changed => continue 'top,
             }
         }
 
-        // TODO: Nagle's algorithm
         // TODO: Silly window syndrome
-        let remote_link_addr = cb.arp.query(cb.remote.address()).await?;
+        let remote_link_addr = cb.remote_link_addr().await?;
 
         // Form an outgoing packet.
-        let max_size = cmp::min((win_sz - sent_data) as usize, cb.sender.mss);
-        let segment_data = cb
+        let max_size = cmp::min((win_sz - sent_data) as usize, cb.sender.mss.get());
+        let segment = cb
             .sender
             .pop_unsent(max_size)
             .expect("No unsent data with sequence number gap?");
+        cb.latency
+            .record_send_queue_time(cb.rt.now() - segment.enqueued);
+        let segment_data = segment.bytes;
         let segment_data_len = segment_data.len();
         assert!(segment_data_len > 0);
 
         let mut header = cb.tcp_header();
         header.seq_num = sent_seq;
+        // Push once this empties the send queue, as BSD does.
+        header.psh = sent_seq + Wrapping(segment_data_len as u32) == cb.sender.unsent_seq_no.get();
         cb.emit(header, segment_data.clone(), remote_link_addr);
 
         cb.sender
@@ -112,6 +168,7 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         let unacked_segment = UnackedSegment {
             bytes: segment_data,
             initial_tx: Some(cb.rt.now()),
+            sacked: false,
         };
         cb.sender
             .unacked_queue
@@ -122,5 +179,6 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             let rto = cb.sender.rto.borrow().estimate();
             cb.sender.retransmit_deadline.set(Some(cb.rt.now() + rto));
         }
+        cb.check_invariants();
     }
 }
@@ -3,30 +3,68 @@ pub mod state;
 
 use self::{
     background::background,
-    state::ControlBlock,
+    state::{
+        congestion::TcpCongestion,
+        receiver::ReceiverState,
+        sender::{
+            SenderState,
+            TcpSendStatus,
+            TcpSendWindow,
+        },
+        ControlBlock,
+    },
 };
 use crate::{
     fail::Fail,
     file_table::FileDescriptor,
     protocols::{
         ipv4,
-        tcp::segment::TcpHeader,
+        tcp::{
+            framing::Framing,
+            options::TcpNegotiated,
+            peer::TcpState,
+            segment::TcpHeader,
+            transform::{
+                StreamFilter,
+                StreamTransform,
+            },
+        },
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
     },
-    runtime::Runtime,
     scheduler::SchedulerHandle,
+    snapshot::TcpConnectionSnapshot,
+    stats::{
+        TcpConnectionSummary,
+        TcpLatencyStats,
+        TcpThroughputStats,
+    },
 };
 use futures::channel::mpsc;
 use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
+    net::Shutdown,
     rc::Rc,
     task::{
         Context,
         Poll,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 pub struct EstablishedSocket<RT: Runtime> {
     pub cb: Rc<ControlBlock<RT>>,
+    filter: RefCell<Option<StreamFilter>>,
+    framing: Cell<Framing>,
     #[allow(unused)]
     background_work: SchedulerHandle,
 }
@@ -36,49 +74,472 @@ impl<RT: Runtime> EstablishedSocket<RT> {
         cb: ControlBlock<RT>,
         fd: FileDescriptor,
         dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
+        timer_origin: Instant,
     ) -> Self {
         let cb = Rc::new(cb);
-        let future = background(cb.clone(), fd, dead_socket_tx);
+        let future = background(cb.clone(), fd, dead_socket_tx, timer_origin);
         let handle = cb.rt.spawn(future);
         Self {
             cb: cb.clone(),
+            filter: RefCell::new(None),
+            framing: Cell::new(Framing::Stream),
             background_work: handle,
         }
     }
 
-    pub fn receive(&self, header: &TcpHeader, data: RT::Buf) {
-        self.cb.receive(header, data)
+    pub fn receive(&self, header: &TcpHeader, data: RT::Buf, rx_time: Instant) {
+        self.cb.receive(header, data, rx_time)
     }
 
     pub fn send(&self, buf: RT::Buf) -> Result<(), Fail> {
-        self.cb.sender.send(buf, &self.cb)
+        // Turned away before a transform sees it, since transforms keep state.
+        if !self.cb.sender.has_room(buf.len()) {
+            return Err(Fail::ResourceExhausted {
+                details: "Send buffer full",
+            });
+        }
+        let out = match *self.filter.borrow_mut() {
+            Some(ref mut filter) => filter.write(&buf)?,
+            None => return self.cb.sender.send(buf, &self.cb),
+        };
+        self.send_filtered(out)
+    }
+
+    /// Like `send`, for several buffers written back to back. They're queued as they are, not
+    /// joined into one, unless a transform has to see them.
+    pub fn send_vectored(&self, bufs: Vec<RT::Buf>) -> Result<(), Fail> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        if !self.cb.sender.has_room(len) {
+            return Err(Fail::ResourceExhausted {
+                details: "Send buffer full",
+            });
+        }
+        let out = match *self.filter.borrow_mut() {
+            Some(ref mut filter) => {
+                let mut out = vec![];
+                for buf in &bufs {
+                    out.extend(filter.write(buf)?);
+                }
+                out
+            },
+            None => return self.cb.sender.send_vectored(bufs, &self.cb),
+        };
+        self.send_filtered(out)
+    }
+
+    /// Install `transform` on the connection's byte streams, or remove the current one with
+    /// `None`. Bytes already read or queued to send aren't transformed again.
+    pub fn set_transform(&self, transform: Option<Box<dyn StreamTransform>>) -> Result<(), Fail> {
+        let mut filter = match transform {
+            Some(..) if self.framing.get() == Framing::Push => {
+                return Err(Fail::Unsupported {
+                    details: "Push framing can't see through a transform",
+                })
+            },
+            Some(transform) => StreamFilter::new(transform),
+            None => {
+                *self.filter.borrow_mut() = None;
+                return Ok(());
+            },
+        };
+        let out = filter.start()?;
+        *self.filter.borrow_mut() = Some(filter);
+        self.send_filtered(out)
+    }
+
+    pub fn set_framing(&self, framing: Framing) -> Result<(), Fail> {
+        match framing {
+            Framing::Push if self.filter.borrow().is_some() => {
+                return Err(Fail::Unsupported {
+                    details: "Push framing can't see through a transform",
+                })
+            },
+            Framing::LengthPrefixed { prefix_len, .. } if prefix_len < 1 || prefix_len > 4 => {
+                return Err(Fail::Invalid {
+                    details: "Length prefixes are 1 to 4 bytes",
+                })
+            },
+            _ => (),
+        }
+        self.framing.set(framing);
+        Ok(())
+    }
+
+    fn send_filtered(&self, bytes: Vec<u8>) -> Result<(), Fail> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.cb.sender.send(RT::Buf::from_slice(&bytes), &self.cb)
+    }
+
+    /// Run everything in the receive queue through the filter, returning whether there is one.
+    fn fill_filter(&self) -> Result<bool, Fail> {
+        let mut reply = vec![];
+        {
+            let mut filter = self.filter.borrow_mut();
+            let filter = match *filter {
+                Some(ref mut filter) => filter,
+                None => return Ok(false),
+            };
+            while let Ok(Some(buf)) = self.cb.receiver.recv() {
+                reply.extend(filter.read(&buf)?);
+            }
+        }
+        self.send_filtered(reply)?;
+        Ok(true)
+    }
+
+    fn check_open(&self) -> Result<(), Fail> {
+        if self.cb.receiver.state.get() != ReceiverState::Open {
+            return Err(Fail::ResourceNotFound {
+                details: "Receiver closed",
+            });
+        }
+        Ok(())
+    }
+
+    /// Take everything the filter has for the application, if anything.
+    fn take_filtered(&self) -> Option<RT::Buf> {
+        let mut filter = self.filter.borrow_mut();
+        let filter = filter.as_mut()?;
+        let len = filter.readable().len();
+        if len == 0 {
+            return None;
+        }
+        let buf = RT::Buf::from_slice(&filter.take(len));
+        self.cb.throughput.record_delivered(self.cb.rt.now(), len);
+        Some(buf)
     }
 
     pub fn peek(&self) -> Result<RT::Buf, Fail> {
-        self.cb.receiver.peek()
+        if !self.fill_filter()? {
+            return self.cb.receiver.peek();
+        }
+        let filter = self.filter.borrow();
+        let readable = filter.as_ref().unwrap().readable();
+        if readable.is_empty() {
+            self.check_open()?;
+            return Err(Fail::WouldBlock {});
+        }
+        Ok(RT::Buf::from_slice(readable))
+    }
+
+    pub fn peek_at(&self, offset: usize, len: usize) -> Result<RT::Buf, Fail> {
+        if !self.fill_filter()? {
+            return self.cb.receiver.peek_at(offset, len);
+        }
+        let filter = self.filter.borrow();
+        let readable = filter.as_ref().unwrap().readable();
+        if offset + len > readable.len() {
+            self.check_open()?;
+            return Err(Fail::WouldBlock {});
+        }
+        Ok(RT::Buf::from_slice(&readable[offset..(offset + len)]))
+    }
+
+    pub fn consume(&self, num_bytes: usize) -> Result<(), Fail> {
+        if self.fill_filter()? {
+            let mut filter = self.filter.borrow_mut();
+            let filter = filter.as_mut().unwrap();
+            if num_bytes > filter.readable().len() {
+                return Err(Fail::OutOfRange {
+                    details: "Consuming more than is unread",
+                });
+            }
+            filter.take(num_bytes);
+        } else {
+            self.cb.receiver.consume(num_bytes)?;
+        }
+        self.cb
+            .throughput
+            .record_delivered(self.cb.rt.now(), num_bytes);
+        Ok(())
+    }
+
+    /// The next whole record under the connection's framing, if it's all arrived.
+    fn recv_record(&self) -> Result<Option<RT::Buf>, Fail> {
+        let (prefix_len, len) = match self.framing.get() {
+            Framing::Stream => unreachable!(),
+            Framing::Push => {
+                let receiver = &self.cb.receiver;
+                let unread = receiver.unread();
+                match receiver.next_push_mark() {
+                    Some(len) => (0, len),
+                    // No boundary will come to free up a full window, nor after the remote closes.
+                    None if unread > 0
+                        && (unread >= receiver.max_window_size as usize || receiver.at_end()) =>
+                    {
+                        (0, unread)
+                    },
+                    None if receiver.at_end() => {
+                        return Err(Fail::ResourceNotFound {
+                            details: "Receiver closed",
+                        })
+                    },
+                    None => return Ok(None),
+                }
+            },
+            Framing::LengthPrefixed {
+                prefix_len,
+                max_len,
+            } => {
+                let prefix = match self.peek_at(0, prefix_len) {
+                    Ok(prefix) => prefix,
+                    Err(Fail::WouldBlock {}) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                let len = prefix.iter().fold(0, |len, &b| len << 8 | b as usize);
+                if len > max_len {
+                    return Err(Fail::Malformed {
+                        details: "Record too long",
+                    });
+                }
+                (prefix_len, len)
+            },
+        };
+        let record = match self.peek_at(prefix_len, len) {
+            Ok(record) => record,
+            Err(Fail::WouldBlock {}) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.consume(prefix_len + len)?;
+        Ok(Some(record))
+    }
+
+    /// Bytes the application could read now, once arrivals have been through any transform.
+    fn readable_len(&self) -> Result<usize, Fail> {
+        if !self.fill_filter()? {
+            return Ok(self.cb.receiver.unread());
+        }
+        Ok(self.filter.borrow().as_ref().unwrap().readable().len())
+    }
+
+    /// The next `len` bytes of the stream in one buffer, whatever segments they arrived in, once
+    /// they all have. Fails if the remote closes before then.
+    pub fn recv_exact(&self, len: usize) -> Result<Option<RT::Buf>, Fail> {
+        match self.peek_at(0, len) {
+            Ok(buf) => {
+                self.consume(len)?;
+                Ok(Some(buf))
+            },
+            Err(Fail::WouldBlock {}) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Everything that's arrived, up to `max` bytes, in one buffer.
+    pub fn recv_up_to(&self, max: usize) -> Result<Option<RT::Buf>, Fail> {
+        let len = cmp::min(self.readable_len()?, max);
+        if len == 0 && max > 0 {
+            if self.cb.receiver.at_end() {
+                return Err(Fail::ResourceNotFound {
+                    details: "Receiver closed",
+                });
+            }
+            return Ok(None);
+        }
+        self.recv_exact(len)
+    }
+
+    pub fn poll_recv_exact(&self, len: usize, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        self.poll_read(self.recv_exact(len), ctx)
+    }
+
+    pub fn poll_recv_up_to(&self, max: usize, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        self.poll_read(self.recv_up_to(max), ctx)
+    }
+
+    /// Ready once there's something to read, or the remote has closed and reads would fail.
+    pub fn poll_readable(&self, ctx: &mut Context) -> Poll<Result<(), Fail>> {
+        match self.readable_len() {
+            Ok(0) if !self.cb.receiver.at_end() => {
+                self.cb.receiver.register_waker(ctx);
+                Poll::Pending
+            },
+            Ok(..) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    // Wait for more to arrive if a read found too little.
+    fn poll_read(
+        &self,
+        r: Result<Option<RT::Buf>, Fail>,
+        ctx: &mut Context,
+    ) -> Poll<Result<RT::Buf, Fail>> {
+        match r {
+            Ok(Some(buf)) => Poll::Ready(Ok(buf)),
+            Ok(None) => {
+                self.cb.receiver.register_waker(ctx);
+                Poll::Pending
+            },
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
     pub fn recv(&self) -> Result<Option<RT::Buf>, Fail> {
-        self.cb.receiver.recv()
+        if self.framing.get() != Framing::Stream {
+            return self.recv_record();
+        }
+        if self.fill_filter()? {
+            if let Some(buf) = self.take_filtered() {
+                return Ok(Some(buf));
+            }
+            self.check_open()?;
+            return Ok(None);
+        }
+        let r = self.cb.receiver.recv();
+        if let Ok(Some(ref buf)) = r {
+            self.cb.throughput.record_delivered(self.cb.rt.now(), buf.len());
+        }
+        r
     }
 
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
-        self.cb.receiver.poll_recv(ctx)
+        if self.framing.get() != Framing::Stream {
+            return self.poll_read(self.recv_record(), ctx);
+        }
+        loop {
+            match self.fill_filter() {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if let Some(buf) = self.take_filtered() {
+                return Poll::Ready(Ok(buf));
+            }
+            // Nothing for the application yet, so wait for more to arrive for the filter.
+            match self.cb.receiver.poll_recv(ctx) {
+                Poll::Ready(Ok(buf)) => {
+                    let reply = match self.filter.borrow_mut().as_mut() {
+                        Some(filter) => filter.read(&buf),
+                        None => Ok(vec![]),
+                    };
+                    if let Err(e) = reply.and_then(|reply| self.send_filtered(reply)) {
+                        return Poll::Ready(Err(e));
+                    }
+                },
+                r => return r,
+            }
+        }
+        let r = self.cb.receiver.poll_recv(ctx);
+        if let Poll::Ready(Ok(ref buf)) = r {
+            self.cb.throughput.record_delivered(self.cb.rt.now(), buf.len());
+        }
+        r
     }
 
     pub fn close(&self) -> Result<(), Fail> {
         self.cb.close()
     }
 
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Fail> {
+        if how != Shutdown::Write {
+            self.cb.receiver.shutdown_read();
+        }
+        if how != Shutdown::Read {
+            self.cb.close()?;
+        }
+        Ok(())
+    }
+
+    pub fn abort(&self) {
+        self.cb.abort()
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.cb.state()
+    }
+
     pub fn remote_mss(&self) -> usize {
         self.cb.remote_mss()
     }
 
+    pub fn negotiated(&self) -> TcpNegotiated {
+        self.cb.negotiated()
+    }
+
+    pub fn congestion(&self) -> TcpCongestion {
+        self.cb.sender.congestion.stats()
+    }
+
+    pub fn send_status(&self) -> TcpSendStatus {
+        self.cb.sender.status.get()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.cb.sender.nagle.set(!nodelay);
+    }
+
+    pub fn send_window(&self) -> TcpSendWindow {
+        self.cb.sender.send_window()
+    }
+
+    /// Whether there's data queued to send or waiting to be acknowledged.
+    pub fn has_data_in_flight(&self) -> bool {
+        let sender = &self.cb.sender;
+        sender.unsent_seq_no.get() != sender.base_seq_no.get()
+    }
+
+    /// Whether a write would be taken rather than turned away for want of buffer space.
+    pub fn writable(&self) -> bool {
+        self.cb.sender.state.get() == SenderState::Open && self.cb.sender.has_room(1)
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) {
+        self.cb.sender.send_buffer_size.set(size);
+    }
+
     pub fn current_rto(&self) -> Duration {
         self.cb.current_rto()
     }
 
+    pub fn last_received(&self) -> Option<Instant> {
+        self.cb.receiver.last_received()
+    }
+
+    pub fn latency_stats(&self) -> TcpLatencyStats {
+        self.cb.latency_stats()
+    }
+
+    pub fn throughput_stats(&self) -> TcpThroughputStats {
+        self.cb.throughput_stats()
+    }
+
     pub fn endpoints(&self) -> (ipv4::Endpoint, ipv4::Endpoint) {
         (self.cb.local.clone(), self.cb.remote.clone())
     }
+
+    pub fn summary(&self, fd: FileDescriptor) -> TcpConnectionSummary {
+        let cb = &self.cb;
+        let unacked_queue = cb.sender.unacked_queue.borrow();
+        let unsent_queue = cb.sender.unsent_queue.borrow();
+        let recv_queue = cb.receiver.recv_queue.borrow();
+        TcpConnectionSummary {
+            fd,
+            local: cb.local,
+            remote: cb.remote,
+            throughput: cb.throughput_stats(),
+            srtt: cb.sender.rto.borrow().smoothed_rtt(),
+            rto: cb.current_rto(),
+            unacked_bytes: unacked_queue.iter().map(|s| s.bytes.len()).sum(),
+            unsent_bytes: unsent_queue.iter().map(|s| s.bytes.len()).sum(),
+            receive_queue_bytes: recv_queue.iter().map(|b| b.len()).sum(),
+        }
+    }
+
+    pub fn snapshot(&self, fd: FileDescriptor) -> TcpConnectionSnapshot {
+        TcpConnectionSnapshot {
+            fd,
+            local: self.cb.local.into(),
+            remote: self.cb.remote.into(),
+            sender: self.cb.sender.snapshot(),
+            receiver: self.cb.receiver.snapshot(),
+            timestamps: self
+                .cb
+                .timestamps
+                .as_ref()
+                .map(|t| t.snapshot(self.cb.rt.now())),
+        }
+    }
 }
@@ -0,0 +1,227 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! NewReno congestion control: RFC 5681's slow start, congestion avoidance and fast retransmit,
+//! with RFC 6582's fast recovery. This only keeps the books; the sender keeps what it has in
+//! flight within `cwnd` and does the retransmitting.
+
+use crate::{
+    collections::watched::WatchedValue,
+    protocols::tcp::SeqNumber,
+};
+use std::{
+    cell::Cell,
+    cmp,
+    num::Wrapping,
+};
+
+/// Where a connection's congestion control stands. See `Engine::tcp_congestion`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpCongestion {
+    /// How many bytes may be in flight, in addition to the remote's window.
+    pub cwnd: u32,
+    /// The slow start threshold, effectively unlimited until the first loss.
+    pub ssthresh: u32,
+    pub fast_recovery: bool,
+}
+
+// Duplicate ACKs in a row that are taken to mean a segment was lost.
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+// `a < b` in sequence space.
+fn seq_lt(a: SeqNumber, b: SeqNumber) -> bool {
+    let Wrapping(delta) = b - a;
+    delta != 0 && delta < (1 << 31)
+}
+
+pub struct NewReno {
+    mss: Cell<u32>,
+    pub cwnd: WatchedValue<u32>,
+    ssthresh: Cell<u32>,
+    dup_acks: Cell<u32>,
+    fast_recovery: Cell<bool>,
+    // `snd.nxt` when loss was last detected. Fast recovery lasts until it's acknowledged, and
+    // duplicate ACKs from before it don't start another round.
+    recover: Cell<SeqNumber>,
+    // During congestion avoidance, bytes acknowledged since `cwnd` last grew.
+    bytes_acked: Cell<u32>,
+}
+
+impl NewReno {
+    pub fn new(mss: usize, seq_no: SeqNumber) -> Self {
+        let mss = mss as u32;
+        // RFC 5681's initial window.
+        let initial_window = if mss > 2190 {
+            2 * mss
+        } else if mss > 1095 {
+            3 * mss
+        } else {
+            4 * mss
+        };
+        Self {
+            mss: Cell::new(mss),
+            cwnd: WatchedValue::new(initial_window),
+            ssthresh: Cell::new(u32::max_value()),
+            dup_acks: Cell::new(0),
+            fast_recovery: Cell::new(false),
+            recover: Cell::new(seq_no),
+            bytes_acked: Cell::new(0),
+        }
+    }
+
+    /// Segments got smaller, so growth is counted in the new size from now on.
+    pub fn set_mss(&self, mss: usize) {
+        self.mss.set(mss as u32);
+    }
+
+    pub fn cwnd(&self) -> u32 {
+        self.cwnd.get()
+    }
+
+    pub fn stats(&self) -> TcpCongestion {
+        TcpCongestion {
+            cwnd: self.cwnd.get(),
+            ssthresh: self.ssthresh.get(),
+            fast_recovery: self.fast_recovery.get(),
+        }
+    }
+
+    /// An ACK that took `bytes_acked` new bytes up to `ack_seq_no`, leaving `flight_size` bytes
+    /// outstanding. Returns whether the oldest unacknowledged segment should be retransmitted
+    /// right away, which is the case after a partial ACK during fast recovery.
+    pub fn on_ack(&self, ack_seq_no: SeqNumber, bytes_acked: u32, flight_size: u32) -> bool {
+        self.dup_acks.set(0);
+        let cwnd = self.cwnd.get();
+        if self.fast_recovery.get() {
+            if seq_lt(ack_seq_no, self.recover.get()) {
+                // Another segment from the same window was lost. Deflate the window by what was
+                // acknowledged, but add back a segment for the one that left the network.
+                let mut cwnd = cwnd.saturating_sub(bytes_acked);
+                if bytes_acked >= self.mss.get() {
+                    cwnd += self.mss.get();
+                }
+                self.cwnd.set(cmp::max(cwnd, self.mss.get()));
+                return true;
+            }
+            self.fast_recovery.set(false);
+            let cwnd = cmp::max(flight_size, self.mss.get()) + self.mss.get();
+            self.cwnd.set(cmp::min(self.ssthresh.get(), cwnd));
+            return false;
+        }
+        if cwnd < self.ssthresh.get() {
+            self.cwnd
+                .set(cwnd.saturating_add(cmp::min(bytes_acked, self.mss.get())));
+        } else {
+            // Grow by a segment per window's worth of data acknowledged.
+            let bytes_acked = self.bytes_acked.get() + bytes_acked;
+            if bytes_acked >= cwnd {
+                self.bytes_acked.set(bytes_acked - cwnd);
+                self.cwnd.set(cwnd.saturating_add(self.mss.get()));
+            } else {
+                self.bytes_acked.set(bytes_acked);
+            }
+        }
+        false
+    }
+
+    /// A duplicate ACK for `ack_seq_no` with `flight_size` bytes outstanding, `sent_seq_no` being
+    /// the next to send. Returns whether to fast retransmit the oldest unacknowledged segment.
+    pub fn on_duplicate_ack(
+        &self,
+        ack_seq_no: SeqNumber,
+        sent_seq_no: SeqNumber,
+        flight_size: u32,
+    ) -> bool {
+        if self.fast_recovery.get() {
+            // Each one means another segment has left the network.
+            self.cwnd.modify(|c| c.saturating_add(self.mss.get()));
+            return false;
+        }
+        let dup_acks = self.dup_acks.get() + 1;
+        self.dup_acks.set(dup_acks);
+        if dup_acks != DUP_ACK_THRESHOLD || seq_lt(ack_seq_no, self.recover.get()) {
+            return false;
+        }
+        let ssthresh = cmp::max(flight_size / 2, 2 * self.mss.get());
+        self.ssthresh.set(ssthresh);
+        self.cwnd.set(ssthresh + DUP_ACK_THRESHOLD * self.mss.get());
+        self.recover.set(sent_seq_no);
+        self.fast_recovery.set(true);
+        true
+    }
+
+    /// The retransmission timer fired with `flight_size` bytes outstanding. Only the first
+    /// timeout for a segment lowers `ssthresh`; later ones just keep `cwnd` at one segment.
+    pub fn on_timeout(&self, sent_seq_no: SeqNumber, flight_size: u32, first: bool) {
+        if first {
+            self.ssthresh.set(cmp::max(flight_size / 2, 2 * self.mss.get()));
+        }
+        self.cwnd.set(self.mss.get());
+        self.dup_acks.set(0);
+        self.fast_recovery.set(false);
+        self.recover.set(sent_seq_no);
+        self.bytes_acked.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NewReno;
+    use std::num::Wrapping;
+
+    #[test]
+    fn slow_start_and_avoidance() {
+        let c = NewReno::new(1000, Wrapping(0));
+        assert_eq!(c.cwnd(), 4000);
+
+        // Slow start grows by a segment per ACK.
+        assert!(!c.on_ack(Wrapping(1000), 1000, 3000));
+        assert_eq!(c.cwnd(), 5000);
+
+        // A timeout drops to one segment and halves the threshold.
+        c.on_timeout(Wrapping(5000), 4000, true);
+        assert_eq!(c.cwnd(), 1000);
+        assert_eq!(c.stats().ssthresh, 2000);
+        assert!(!c.on_ack(Wrapping(2000), 1000, 0));
+        assert_eq!(c.cwnd(), 2000);
+
+        // At the threshold, a whole window has to be acknowledged for another segment.
+        assert!(!c.on_ack(Wrapping(3000), 1000, 0));
+        assert_eq!(c.cwnd(), 2000);
+        assert!(!c.on_ack(Wrapping(4000), 1000, 0));
+        assert_eq!(c.cwnd(), 3000);
+    }
+
+    #[test]
+    fn fast_recovery() {
+        let c = NewReno::new(1000, Wrapping(0));
+
+        // Segments 0 and 2000 of four are lost.
+        assert!(!c.on_duplicate_ack(Wrapping(0), Wrapping(4000), 4000));
+        assert!(!c.on_duplicate_ack(Wrapping(0), Wrapping(4000), 4000));
+        assert!(c.on_duplicate_ack(Wrapping(0), Wrapping(4000), 4000));
+        let stats = c.stats();
+        assert!(stats.fast_recovery);
+        assert_eq!(stats.ssthresh, 2000);
+        assert_eq!(stats.cwnd, 5000);
+        assert!(!c.on_duplicate_ack(Wrapping(0), Wrapping(4000), 4000));
+        assert_eq!(c.cwnd(), 6000);
+
+        // The partial ACK asks for the next hole to be filled.
+        assert!(c.on_ack(Wrapping(2000), 2000, 2000));
+        assert_eq!(c.cwnd(), 5000);
+        assert!(c.stats().fast_recovery);
+
+        // Everything sent before the loss is acknowledged, so recovery is over.
+        assert!(!c.on_ack(Wrapping(4000), 2000, 0));
+        assert_eq!(c.stats().cwnd, 2000);
+        assert!(!c.stats().fast_recovery);
+
+        // After a timeout, duplicate ACKs for data sent before it don't start another round.
+        c.on_timeout(Wrapping(8000), 4000, true);
+        for _ in 0..3 {
+            assert!(!c.on_duplicate_ack(Wrapping(5000), Wrapping(8000), 3000));
+        }
+        assert_eq!(c.cwnd(), 1000);
+    }
+}
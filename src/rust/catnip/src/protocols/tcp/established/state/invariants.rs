@@ -0,0 +1,108 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Sequence-space invariants for an established connection, checked after every receive and
+//! transmit when the `invariants` feature is enabled. A violation panics with the full control
+//! block state, so state-machine bugs surface at the operation that caused them rather than as a
+//! stalled connection much later.
+
+use super::ControlBlock;
+use crate::{
+    protocols::tcp::SeqNumber,
+    runtime::Runtime,
+};
+use std::num::Wrapping;
+
+// `a <= b` in sequence space, assuming the two are within half the space of each other.
+fn seq_le(a: SeqNumber, b: SeqNumber) -> bool {
+    let Wrapping(delta) = b - a;
+    delta < (1 << 31)
+}
+
+pub fn check<RT: Runtime>(cb: &ControlBlock<RT>) {
+    if let Err(violation) = check_sender(cb).and_then(|()| check_receiver(cb)) {
+        panic!(
+            "TCP invariant violated: {}\nlocal: {:?}, remote: {:?}\nsender: {:?}\nreceiver: {:?}",
+            violation, cb.local, cb.remote, cb.sender, cb.receiver
+        );
+    }
+}
+
+fn check_sender<RT: Runtime>(cb: &ControlBlock<RT>) -> Result<(), &'static str> {
+    let sender = &cb.sender;
+    let base_seq = sender.base_seq_no.get();
+    let sent_seq = sender.sent_seq_no.get();
+    let unsent_seq = sender.unsent_seq_no.get();
+
+    if !seq_le(base_seq, sent_seq) {
+        return Err("snd.una > snd.nxt");
+    }
+    if !seq_le(sent_seq, unsent_seq) {
+        return Err("snd.nxt > end of send queue");
+    }
+
+    // Retransmit queue entries are contiguous, so they can only overlap (or leave gaps) if their
+    // lengths don't add up to the unacknowledged span.
+    let unacked_bytes = sender
+        .unacked_queue
+        .borrow()
+        .iter()
+        .map(|s| s.bytes.len())
+        .sum::<usize>();
+    if unacked_bytes != (sent_seq - base_seq).0 as usize {
+        return Err("retransmit queue doesn't match snd.nxt - snd.una");
+    }
+    if sender.unacked_queue.borrow().iter().any(|s| s.bytes.is_empty()) {
+        return Err("empty segment in retransmit queue");
+    }
+    let unsent_bytes = sender
+        .unsent_queue
+        .borrow()
+        .iter()
+        .map(|s| s.bytes.len())
+        .sum::<usize>();
+    if unsent_bytes != (unsent_seq - sent_seq).0 as usize {
+        return Err("send queue doesn't match its sequence span");
+    }
+
+    let max_window = (0xffff as u64) << sender.window_scale;
+    if sender.window_size.get() as u64 > max_window {
+        return Err("remote window larger than its scale allows");
+    }
+    if sender.unacked_queue.borrow().is_empty() != (base_seq == sent_seq) {
+        return Err("retransmit queue emptiness disagrees with sequence numbers");
+    }
+    Ok(())
+}
+
+fn check_receiver<RT: Runtime>(cb: &ControlBlock<RT>) -> Result<(), &'static str> {
+    let receiver = &cb.receiver;
+    let base_seq = receiver.base_seq_no.get();
+    let ack_seq = receiver.ack_seq_no.get();
+    let recv_seq = receiver.recv_seq_no.get();
+
+    if !seq_le(base_seq, recv_seq) {
+        return Err("rcv.nxt behind the application's read position");
+    }
+    // Once the FIN has arrived, the ACK covers one more sequence number than the data.
+    if !seq_le(ack_seq, receiver.ack_num()) {
+        return Err("ACK'd past rcv.nxt");
+    }
+
+    let queued_bytes = receiver
+        .recv_queue
+        .borrow()
+        .iter()
+        .map(|b| b.len())
+        .sum::<usize>();
+    if queued_bytes != (recv_seq - base_seq).0 as usize {
+        return Err("receive queue doesn't match rcv.nxt - read position");
+    }
+    if queued_bytes > receiver.max_window_size as usize {
+        return Err("receive queue exceeds the advertised window");
+    }
+    if receiver.out_of_order_keys().into_iter().any(|seq_no| seq_le(seq_no, recv_seq)) {
+        return Err("out-of-order segment at or before rcv.nxt");
+    }
+    Ok(())
+}
@@ -1,35 +1,87 @@
+pub mod congestion;
+#[cfg(feature = "invariants")]
+mod invariants;
 pub mod receiver;
 mod rto;
 pub mod sender;
+pub mod timestamps;
 
 use self::{
-    receiver::Receiver,
-    sender::Sender,
+    receiver::{
+        Receiver,
+        ReceiverState,
+    },
+    sender::{
+        Sender,
+        SenderState,
+    },
+    timestamps::{
+        Timestamps,
+        TIMESTAMP_OPTION_SIZE,
+    },
 };
 use crate::{
     fail::Fail,
+    logging::{
+        self,
+        LogRecord,
+        RecordKind,
+        Sampler,
+    },
     protocols::{
         arp,
-        ethernet2::{
-            frame::{
-                EtherType2,
-                Ethernet2Header,
-            },
-            MacAddress,
-        },
+        ethernet2::MacAddress,
+        icmpv4,
         ipv4,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
+        ipv4::{
+            datagram::IPV4_HEADER_SIZE,
+            Egress,
         },
-        tcp::segment::{
-            TcpHeader,
-            TcpSegment,
+        packet::PacketBuilder,
+        tcp::{
+            options::{
+                TcpMissingOptions,
+                TcpNegotiated,
+            },
+            peer::TcpState,
+            segment::{
+                AckTemplate,
+                SelectiveAcknowlegement,
+                TcpHeader,
+                TcpOptions2,
+                TcpSegment,
+                MIN_TCP_HEADER_SIZE,
+            },
+            SeqNumber,
         },
     },
-    runtime::Runtime,
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    sampled,
+    stats::{
+        TcpLatencyRecorder,
+        TcpLatencyStats,
+        TcpThroughputRecorder,
+        TcpThroughputStats,
+    },
+};
+use log::Level;
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    num::Wrapping,
+    time::{
+        Duration,
+        Instant,
+    },
 };
-use std::time::Duration;
+
+// RFC 1191 7.1's table of common MTUs, largest first.
+const MTU_PLATEAUS: [u16; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
 
 pub struct ControlBlock<RT: Runtime> {
     pub local: ipv4::Endpoint,
@@ -37,41 +89,276 @@ pub struct ControlBlock<RT: Runtime> {
 
     pub rt: RT,
     pub arp: arp::Peer<RT>,
+    pub egress: Egress<RT>,
 
     pub sender: Sender<RT>,
     pub receiver: Receiver<RT>,
+
+    pub latency: TcpLatencyRecorder,
+    pub throughput: TcpThroughputRecorder,
+
+    /// Sampler for this connection's per-segment logging.
+    pub log: Sampler,
+
+    /// The last pure ACK sent, patched up for the next one.
+    pub ack_template: RefCell<Option<AckTemplate>>,
+
+    /// Set if both sides agreed to timestamps during the handshake.
+    pub timestamps: Option<Timestamps>,
+
+    /// ARP failures since the remote's link address last resolved.
+    pub soft_errors: Cell<usize>,
+
+    /// Options the remote left out of its half of the handshake.
+    pub missing_options: TcpMissingOptions,
+}
+
+/// A log sampler for the connection between `local` and `remote`.
+pub fn connection_log<RT: Runtime>(
+    rt: &RT,
+    local: ipv4::Endpoint,
+    remote: ipv4::Endpoint,
+) -> Sampler {
+    let name = format!(
+        "TCP {}:{} -> {}:{}",
+        local.addr, local.port, remote.addr, remote.port
+    );
+    Sampler::new(name, rt.tcp_options().log_sampling)
 }
 
 impl<RT: Runtime> ControlBlock<RT> {
-    pub fn receive(&self, header: &TcpHeader, data: RT::Buf) {
-        debug!("Receiving {} bytes + {:?}", data.len(), header);
-        let now = self.rt.now();
+    /// Process a segment that arrived at `rx_time`, which RTT samples and ACK timing are
+    /// measured from.
+    pub fn receive(&self, header: &TcpHeader, data: RT::Buf, rx_time: Instant) {
+        sampled!(
+            self.log,
+            self.rt.now(),
+            Level::Debug,
+            "Receiving {} bytes + {}",
+            data.len(),
+            header
+        );
+        self.log_record(RecordKind::Receive, header, data.len());
+        let mut ts_rtt = None;
+        if let Some(ref timestamps) = self.timestamps {
+            // A segment without the option is processed as if timestamps were off.
+            if let Some((tsval, tsecr)) = header.timestamp() {
+                let now = self.rt.now();
+                if !header.rst && timestamps.is_stale(tsval, now) {
+                    // RFC 7323 5.3: Drop it, but acknowledge it in case that's what the remote's
+                    // waiting for.
+                    warn!("Dropping segment with an old timestamp (PAWS): {}", header);
+                    self.send_ack();
+                    return;
+                }
+                if (self.receiver.ack_seq_no.get() - header.seq_num).0 as i32 >= 0 {
+                    timestamps.update_recent(tsval, now);
+                }
+                if header.ack && tsecr != 0 {
+                    ts_rtt = timestamps.rtt(tsecr, rx_time);
+                }
+            }
+        }
         if header.syn {
             warn!("Ignoring duplicate SYN on established connection");
         }
         if header.rst {
             self.sender.receive_rst();
         }
-        if header.fin {
-            self.receiver.receive_fin();
-        }
         if header.ack {
-            if let Err(e) = self.sender.remote_ack(header.ack_num, now) {
-                warn!("Ignoring remote ack for {:?}: {:?}", header, e);
+            // RFC 5681's duplicate ACK carries nothing else and leaves the window alone.
+            let duplicate = data.is_empty()
+                && !header.syn
+                && !header.fin
+                && self.sender.window_unchanged(header.window_size);
+            let result =
+                self.sender
+                    .remote_ack(header.ack_num, duplicate, ts_rtt, rx_time, &self.latency);
+            if self.sender.sack_permitted {
+                self.sender.remote_sack(header.sack_blocks());
+            }
+            match result {
+                Ok(true) if self.sender.sack_permitted => self.retransmit_holes(),
+                Ok(true) => self.retransmit_oldest(),
+                Ok(false) => (),
+                Err(e @ Fail::Malformed { .. }) => {
+                    warn!("Dropping invalid remote ack for {}: {:?}", header, e);
+                    self.throughput.record_invalid();
+                },
+                Err(e) => warn!("Ignoring remote ack for {}: {:?}", header, e),
             }
         }
         if let Err(e) = self.sender.update_remote_window(header.window_size as u16) {
-            warn!("Invalid window size update for {:?}: {:?}", header, e);
+            warn!("Invalid window size update for {}: {:?}", header, e);
         }
-        if !data.is_empty() {
-            if let Err(e) = self.receiver.receive_data(header.seq_num, data, now) {
-                warn!("Ignoring remote data for {:?}: {:?}", header, e);
+        let data_len = data.len();
+        if data_len > 0 {
+            let ack_delay = self.rt.tcp_options().delayed_ack_timeout;
+            let in_order = header.seq_num == self.receiver.recv_seq_no.get();
+            if header.psh {
+                self.receiver.mark_push(header.seq_num + Wrapping(data.len() as u32));
+            }
+            if let Err(e) = self.receiver.receive_data(header.seq_num, data, rx_time, ack_delay) {
+                warn!("Ignoring remote data for {}: {:?}", header, e);
+                // RFC 5681 4.2: Acknowledge out-of-order and duplicate data right away. The
+                // duplicate ACKs drive the remote's fast retransmit, and with SACK (RFC 2018)
+                // they say what's arrived.
+                if !in_order {
+                    self.send_ack();
+                }
             }
         }
+        // After the data, which the receiver stops taking once it's seen a FIN. The FIN only
+        // counts once everything before it has arrived; until then the remote will resend it.
+        if header.fin {
+            if header.seq_num + Wrapping(data_len as u32) == self.receiver.recv_seq_no.get() {
+                self.receiver.receive_fin();
+            } else {
+                warn!("Dropping out of order FIN for {}", header);
+                if data_len == 0 {
+                    self.send_ack();
+                }
+            }
+        }
+        self.check_invariants();
+    }
+
+    /// Panics if the connection's sequence-space invariants don't hold. This is a no-op unless the
+    /// `invariants` feature is enabled.
+    #[inline]
+    pub fn check_invariants(&self) {
+        #[cfg(feature = "invariants")]
+        invariants::check(self);
     }
 
     pub fn close(&self) -> Result<(), Fail> {
-        self.sender.close()
+        self.sender
+            .close(self.receiver.state.get() != ReceiverState::Open)
+    }
+
+    /// Where the connection is in RFC 793's state machine, going by how far each direction has
+    /// got with closing. A reset connection is `Closed`.
+    pub fn state(&self) -> TcpState {
+        let fin_received = self.receiver.state.get() != ReceiverState::Open;
+        let passive = self.sender.passive_close.get();
+        match self.sender.state.get() {
+            SenderState::Open if fin_received => TcpState::CloseWait,
+            SenderState::Open => TcpState::Established,
+            SenderState::Closed | SenderState::SentFin if passive => TcpState::LastAck,
+            SenderState::Closed | SenderState::SentFin if fin_received => TcpState::Closing,
+            SenderState::Closed | SenderState::SentFin => TcpState::FinWait1,
+            SenderState::FinAckd if passive => TcpState::Closed,
+            SenderState::FinAckd if fin_received => TcpState::TimeWait,
+            SenderState::FinAckd => TcpState::FinWait2,
+            SenderState::Reset => TcpState::Closed,
+        }
+    }
+
+    /// Resend the oldest unacknowledged segment without waiting for the retransmission timer.
+    fn retransmit_oldest(&self) {
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let bytes = match self.sender.unacked_queue.borrow_mut().front_mut() {
+            Some(segment) => {
+                // Karn's algorithm: the ACK for this can't be used as an RTT sample.
+                segment.initial_tx.take();
+                segment.bytes.clone()
+            },
+            None => return,
+        };
+        let mut header = self.tcp_header();
+        header.seq_num = self.sender.base_seq_no.get();
+        sampled!(
+            self.log,
+            self.rt.now(),
+            Level::Debug,
+            "Fast retransmitting {} bytes: {}",
+            bytes.len(),
+            header
+        );
+        self.log_record(RecordKind::FastRetransmit, &header, bytes.len());
+        self.emit(header, bytes, remote_link_addr);
+        self.throughput.record_retransmitted();
+    }
+
+    /// Resend the oldest unacknowledged segment, and every other one below the highest the remote
+    /// has selectively acknowledged that it hasn't, as far as the congestion window allows.
+    /// Segments that have already been retransmitted are left to the retransmission timer.
+    fn retransmit_holes(&self) {
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let mut holes = vec![];
+        {
+            let mut unacked_queue = self.sender.unacked_queue.borrow_mut();
+            let mut seq_no = self.sender.base_seq_no.get();
+            let mut end_sacked = seq_no;
+            for segment in unacked_queue.iter() {
+                seq_no += Wrapping(segment.bytes.len() as u32);
+                if segment.sacked {
+                    end_sacked = seq_no;
+                }
+            }
+            let mut seq_no = self.sender.base_seq_no.get();
+            let mut budget = self.sender.congestion.cwnd() as usize;
+            for (i, segment) in unacked_queue.iter_mut().enumerate() {
+                let len = segment.bytes.len();
+                if i > 0 && (seq_no == end_sacked || budget < len) {
+                    break;
+                }
+                if i == 0 || !segment.sacked && segment.initial_tx.is_some() {
+                    // Karn's algorithm: the ACK for this can't be used as an RTT sample.
+                    segment.initial_tx.take();
+                    holes.push((seq_no, segment.bytes.clone()));
+                    budget = budget.saturating_sub(len);
+                }
+                seq_no += Wrapping(len as u32);
+            }
+        }
+        for (seq_no, bytes) in holes {
+            let mut header = self.tcp_header();
+            header.seq_num = seq_no;
+            sampled!(
+                self.log,
+                self.rt.now(),
+                Level::Debug,
+                "Retransmitting {} bytes missing from SACK: {}",
+                bytes.len(),
+                header
+            );
+            self.log_record(RecordKind::SackRetransmit, &header, bytes.len());
+            self.emit(header, bytes, remote_link_addr);
+            self.throughput.record_retransmitted();
+        }
+    }
+
+    /// Send a pure ACK, with any SACK blocks, without waiting for the acknowledger, which only
+    /// sends ACKs that acknowledge something new.
+    fn send_ack(&self) {
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let mut header = self.tcp_header();
+        header.ack = true;
+        header.ack_num = self.receiver.ack_num();
+        self.emit(header, RT::Buf::empty(), remote_link_addr);
+    }
+
+    /// Send a RST right away, for a connection that's about to be dropped without closing.
+    pub fn abort(&self) {
+        self.sender.receive_rst();
+        match self.arp.try_query(self.remote.addr) {
+            Some(remote_link_addr) => {
+                let mut header = self.tcp_header();
+                header.rst = true;
+                self.emit(header, RT::Buf::empty(), remote_link_addr);
+            },
+            None => warn!("Dropping RST for {:?}: not in ARP cache", self.remote),
+        }
     }
 
     pub fn tcp_header(&self) -> TcpHeader {
@@ -81,26 +368,162 @@ impl<RT: Runtime> ControlBlock<RT> {
             header.ack_num = ack_seq_no;
             header.ack = true;
         }
+        if let Some(ref timestamps) = self.timestamps {
+            header.push_option(timestamps.option(self.rt.now()));
+        }
+        if self.sender.sack_permitted {
+            let mut blocks = self.receiver.sack_blocks();
+            // RFC 2018: Only three blocks fit alongside a timestamp.
+            if self.timestamps.is_some() {
+                blocks.truncate(3);
+            }
+            if !blocks.is_empty() {
+                let mut sacks = [SelectiveAcknowlegement {
+                    begin: Wrapping(0),
+                    end: Wrapping(0),
+                }; 4];
+                sacks[..blocks.len()].copy_from_slice(&blocks[..]);
+                header.push_option(TcpOptions2::SelectiveAcknowlegement {
+                    num_sacks: blocks.len(),
+                    sacks,
+                });
+            }
+        }
         header
     }
 
+    /// The remote's link address. A failed ARP query is a soft error: it's counted and the query
+    /// retried, and only after `soft_error_limit` failures in a row does the connection give up.
+    pub async fn remote_link_addr(&self) -> Result<MacAddress, Fail> {
+        loop {
+            match self.arp.query(self.remote.address()).await {
+                Ok(link_addr) => {
+                    self.soft_errors.set(0);
+                    return Ok(link_addr);
+                },
+                Err(e) => {
+                    self.throughput.record_soft_error();
+                    let n = self.soft_errors.get() + 1;
+                    self.soft_errors.set(n);
+                    if n > self.rt.tcp_options().soft_error_limit {
+                        return Err(e);
+                    }
+                    warn!(
+                        "ARP query for {} failed ({} in a row): {:?}",
+                        self.remote.addr, n, e
+                    );
+                },
+            }
+        }
+    }
+
+    /// Report a segment of `len` bytes to the structured log, if a sink is installed.
+    pub fn log_record(&self, kind: RecordKind, header: &TcpHeader, len: usize) {
+        logging::emit_record(|| LogRecord {
+            timestamp: self.rt.now(),
+            engine: None,
+            kind,
+            local: self.local.into(),
+            remote: self.remote.into(),
+            seq: header.seq_num.0,
+            ack: if header.ack {
+                Some(header.ack_num.0)
+            } else {
+                None
+            },
+            window: header.window_size,
+            len,
+        });
+    }
+
     pub fn emit(&self, header: TcpHeader, data: RT::Buf, remote_link_addr: MacAddress) {
         if header.ack {
-            self.receiver.ack_sent(header.ack_num);
-        }
-        debug!("Sending {} bytes + {:?}", data.len(), header);
-        let segment = TcpSegment {
-            ethernet2_hdr: Ethernet2Header {
-                dst_addr: remote_link_addr,
-                src_addr: self.rt.local_link_addr(),
-                ether_type: EtherType2::Ipv4,
-            },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
-            tcp_hdr: header,
-            data,
-            tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload,
-        };
-        self.rt.transmit(segment);
+            if let Some(received) = self.receiver.ack_sent(header.ack_num) {
+                let now = self.rt.now();
+                self.latency
+                    .record_ack_latency(now.saturating_duration_since(received));
+            }
+        }
+        let now = self.rt.now();
+        sampled!(
+            self.log,
+            now,
+            Level::Debug,
+            "Sending {} bytes + {}",
+            data.len(),
+            header
+        );
+        self.log_record(RecordKind::Send, &header, data.len());
+        self.throughput.record_transmitted(now, data.len());
+        let tx_checksum_offload = self.rt.tcp_options().tx_checksum_offload;
+        if data.is_empty() && AckTemplate::fits(&header) {
+            let mut template = self.ack_template.borrow_mut();
+            match *template {
+                Some(ref mut t) if t.tx_checksum_offload() == tx_checksum_offload => {
+                    t.update(remote_link_addr, &header)
+                },
+                _ => {
+                    let segment = self.segment(header, data, remote_link_addr);
+                    *template = Some(AckTemplate::new(&segment));
+                },
+            }
+            let ack = template.as_ref().unwrap().clone();
+            drop(template);
+            self.egress.transmit(ack);
+            return;
+        }
+        self.egress
+            .transmit(self.segment(header, data, remote_link_addr));
+    }
+
+    fn segment(
+        &self,
+        header: TcpHeader,
+        data: RT::Buf,
+        remote_link_addr: MacAddress,
+    ) -> TcpSegment<RT::Buf> {
+        PacketBuilder::new(&self.rt, remote_link_addr, self.remote.addr)
+            .src_addr(self.local.addr)
+            .tcp(header, data, self.rt.tcp_options().tx_checksum_offload)
+    }
+
+    /// A router couldn't forward the segment starting at `seq_no` over a link with a smaller MTU
+    /// (RFC 1191), so send smaller ones. The error is only believed if that's data we've sent and
+    /// not had acknowledged (RFC 5927 5.1), since anyone can forge one.
+    pub fn receive_fragmentation_needed(
+        &self,
+        seq_no: SeqNumber,
+        error: &icmpv4::FragmentationNeeded,
+    ) {
+        let base_seq_no = self.sender.base_seq_no.get();
+        let Wrapping(offset) = seq_no - base_seq_no;
+        let Wrapping(in_flight) = self.sender.sent_seq_no.get() - base_seq_no;
+        if offset >= in_flight {
+            warn!("Ignoring fragmentation needed for {} outside the send window", seq_no);
+            return;
+        }
+        // Routers from before RFC 1191 leave out the MTU, so guess the next plateau down from the
+        // datagram's size (RFC 1191 7.1).
+        let mtu = match error.next_hop_mtu {
+            0 => MTU_PLATEAUS
+                .iter()
+                .find(|&&mtu| mtu < error.total_len)
+                .cloned()
+                .unwrap_or(68),
+            mtu => mtu,
+        } as usize;
+        let mut overhead = IPV4_HEADER_SIZE + MIN_TCP_HEADER_SIZE;
+        if self.timestamps.is_some() {
+            overhead += TIMESTAMP_OPTION_SIZE;
+        }
+        if self.sender.reduce_mss(mtu.saturating_sub(overhead)) {
+            info!(
+                "Path MTU to {:?} is {}, so the MSS is now {}",
+                self.remote,
+                mtu,
+                self.sender.mss.get()
+            );
+        }
     }
 
     pub fn remote_mss(&self) -> usize {
@@ -110,4 +533,31 @@ impl<RT: Runtime> ControlBlock<RT> {
     pub fn current_rto(&self) -> Duration {
         self.sender.current_rto()
     }
+
+    pub fn negotiated(&self) -> TcpNegotiated {
+        // The engine doesn't implement ECN, so it never agrees to it.
+        TcpNegotiated {
+            mss: self.sender.remote_mss(),
+            send_window_scale: self.sender.window_scale,
+            receive_window_scale: self.receiver.window_scale as u8,
+            sack_permitted: self.sender.sack_permitted,
+            timestamps: self.timestamps.is_some(),
+            ecn: false,
+            missing: self.missing_options,
+        }
+    }
+
+    pub fn latency_stats(&self) -> TcpLatencyStats {
+        self.latency.connection_stats()
+    }
+
+    pub fn throughput_stats(&self) -> TcpThroughputStats {
+        let mut stats = self.throughput.stats(self.rt.now());
+        stats.bytes_reassembled = self.receiver.bytes_reassembled.get();
+        stats.bytes_reassembly_discarded = self.receiver.bytes_reassembly_discarded.get();
+        stats.segments_duplicate = self.receiver.segments_duplicate.get();
+        stats.segments_overlapping = self.receiver.segments_overlapping.get();
+        stats.bytes_duplicate = self.receiver.bytes_duplicate.get();
+        stats
+    }
 }
@@ -1,16 +1,31 @@
 use crate::{
     collections::watched::WatchedValue,
     fail::Fail,
-    protocols::tcp::SeqNumber,
-    runtime::Runtime,
+    protocols::tcp::{
+        segment::SelectiveAcknowlegement,
+        SeqNumber,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    snapshot::ReceiverSnapshot,
+};
+use arrayvec::ArrayVec;
+use serde::{
+    Deserialize,
+    Serialize,
 };
 use std::{
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
     collections::{
         BTreeMap,
         VecDeque,
     },
-    convert::TryInto,
     num::Wrapping,
     task::{
         Context,
@@ -24,9 +39,10 @@ use std::{
 };
 
 const RECV_QUEUE_SZ: usize = 2048;
-const MAX_OUT_OF_ORDER: usize = 16;
+// As many SACK blocks as fit in a header's options.
+const MAX_SACK_BLOCKS: usize = 4;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ReceiverState {
     Open,
     ReceivedFin,
@@ -54,16 +70,41 @@ pub struct Receiver<RT: Runtime> {
     pub recv_seq_no: WatchedValue<SeqNumber>,
 
     pub ack_deadline: WatchedValue<Option<Instant>>,
+    // Arrival time of the oldest data not yet covered by an ACK we've sent.
+    unacked_since: Cell<Option<Instant>>,
+    // Arrival time of the latest in-order data.
+    last_received: Cell<Option<Instant>>,
 
     pub max_window_size: u32,
     pub window_scale: u32,
 
     waker: RefCell<Option<Waker>>,
+    // Segments that arrived ahead of a hole, held until it's filled, and their total size.
     out_of_order: RefCell<BTreeMap<SeqNumber, RT::Buf>>,
+    out_of_order_bytes: Cell<usize>,
+    reassembly_limit: usize,
+    // The most recently queued out-of-order segment, whose block goes first in a SACK option.
+    last_out_of_order: Cell<Option<SeqNumber>>,
+    // Out-of-order bytes delivered once the hole before them filled, and ones thrown away.
+    pub bytes_reassembled: Cell<u64>,
+    pub bytes_reassembly_discarded: Cell<u64>,
+    // Segments that repeated data we already had, entirely or in part, and the repeated bytes.
+    pub segments_duplicate: Cell<u64>,
+    pub segments_overlapping: Cell<u64>,
+    pub bytes_duplicate: Cell<u64>,
+    // The application shut down reading: data is still acknowledged, but then thrown away.
+    read_shutdown: Cell<bool>,
+    // Where segments the remote marked with PSH end, for `Framing::Push`.
+    push_marks: RefCell<Vec<SeqNumber>>,
 }
 
 impl<RT: Runtime> Receiver<RT> {
-    pub fn new(seq_no: SeqNumber, max_window_size: u32, window_scale: u32) -> Self {
+    pub fn new(
+        seq_no: SeqNumber,
+        max_window_size: u32,
+        window_scale: u32,
+        reassembly_limit: usize,
+    ) -> Self {
         Self {
             state: WatchedValue::new(ReceiverState::Open),
             base_seq_no: WatchedValue::new(seq_no),
@@ -71,20 +112,91 @@ impl<RT: Runtime> Receiver<RT> {
             ack_seq_no: WatchedValue::new(seq_no),
             recv_seq_no: WatchedValue::new(seq_no),
             ack_deadline: WatchedValue::new(None),
+            unacked_since: Cell::new(None),
+            last_received: Cell::new(None),
             max_window_size,
             window_scale,
             waker: RefCell::new(None),
             out_of_order: RefCell::new(BTreeMap::new()),
+            out_of_order_bytes: Cell::new(0),
+            reassembly_limit,
+            last_out_of_order: Cell::new(None),
+            bytes_reassembled: Cell::new(0),
+            bytes_reassembly_discarded: Cell::new(0),
+            segments_duplicate: Cell::new(0),
+            segments_overlapping: Cell::new(0),
+            bytes_duplicate: Cell::new(0),
+            read_shutdown: Cell::new(false),
+            push_marks: RefCell::new(vec![]),
         }
     }
 
+    /// Rebuild a receiver from a snapshot taken on another engine. Out-of-order segments aren't
+    /// carried over, so the remote will have to retransmit them.
+    pub fn restore(snapshot: &ReceiverSnapshot, now: Instant, reassembly_limit: usize) -> Self {
+        let mut recv_queue = VecDeque::with_capacity(RECV_QUEUE_SZ);
+        recv_queue.extend(
+            snapshot
+                .recv_queue
+                .iter()
+                .map(|bytes| RT::Buf::from_slice(&bytes[..])),
+        );
+        // Send any ACK the old engine still owed right away.
+        let ack_deadline = if snapshot.ack_seq_no != snapshot.recv_seq_no {
+            Some(now)
+        } else {
+            None
+        };
+        Self {
+            state: WatchedValue::new(snapshot.state),
+            base_seq_no: WatchedValue::new(Wrapping(snapshot.base_seq_no)),
+            recv_queue: RefCell::new(recv_queue),
+            ack_seq_no: WatchedValue::new(Wrapping(snapshot.ack_seq_no)),
+            recv_seq_no: WatchedValue::new(Wrapping(snapshot.recv_seq_no)),
+            ack_deadline: WatchedValue::new(ack_deadline),
+            unacked_since: Cell::new(None),
+            last_received: Cell::new(None),
+            max_window_size: snapshot.max_window_size,
+            window_scale: snapshot.window_scale,
+            waker: RefCell::new(None),
+            out_of_order: RefCell::new(BTreeMap::new()),
+            out_of_order_bytes: Cell::new(0),
+            reassembly_limit,
+            last_out_of_order: Cell::new(None),
+            bytes_reassembled: Cell::new(0),
+            bytes_reassembly_discarded: Cell::new(0),
+            segments_duplicate: Cell::new(0),
+            segments_overlapping: Cell::new(0),
+            bytes_duplicate: Cell::new(0),
+            read_shutdown: Cell::new(snapshot.read_shutdown),
+            push_marks: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn snapshot(&self) -> ReceiverSnapshot {
+        ReceiverSnapshot {
+            state: self.state.get(),
+            base_seq_no: self.base_seq_no.get().0,
+            ack_seq_no: self.ack_seq_no.get().0,
+            recv_seq_no: self.recv_seq_no.get().0,
+            recv_queue: self.recv_queue.borrow().iter().map(|b| b.to_vec()).collect(),
+            max_window_size: self.max_window_size,
+            window_scale: self.window_scale,
+            read_shutdown: self.read_shutdown.get(),
+        }
+    }
+
+    /// When the most recent in-order data arrived, if any has.
+    pub fn last_received(&self) -> Option<Instant> {
+        self.last_received.get()
+    }
+
     pub fn hdr_window_size(&self) -> u16 {
         let Wrapping(bytes_outstanding) = self.recv_seq_no.get() - self.base_seq_no.get();
-        let window_size = self.max_window_size - bytes_outstanding;
-        let hdr_window_size = (window_size >> self.window_scale)
-            .try_into()
-            .expect("Window size overflow");
-        debug!(
+        let window_size = self.max_window_size.saturating_sub(bytes_outstanding);
+        let hdr_window_size =
+            cmp::min(window_size >> self.window_scale, u16::max_value() as u32) as u16;
+        trace!(
             "Sending window size update -> {} (hdr {}, scale {})",
             (hdr_window_size as u32) << self.window_scale,
             hdr_window_size,
@@ -93,36 +205,49 @@ impl<RT: Runtime> Receiver<RT> {
         hdr_window_size
     }
 
-    pub fn current_ack(&self) -> Option<SeqNumber> {
-        let ack_seq_no = self.ack_seq_no.get();
+    /// The next ACK number: past all the data received and, once it's arrived, the FIN.
+    pub fn ack_num(&self) -> SeqNumber {
         let recv_seq_no = self.recv_seq_no.get();
-        if ack_seq_no != recv_seq_no {
-            Some(recv_seq_no)
+        if self.state.get() == ReceiverState::Open {
+            recv_seq_no
         } else {
-            None
+            recv_seq_no + Wrapping(1)
         }
     }
 
-    pub fn ack_sent(&self, seq_no: SeqNumber) {
-        if self.state.get() == ReceiverState::AckdFin {
-            assert_eq!(seq_no, self.recv_seq_no.get() + Wrapping(1));
+    pub fn current_ack(&self) -> Option<SeqNumber> {
+        let ack_num = self.ack_num();
+        if self.ack_seq_no.get() != ack_num {
+            Some(ack_num)
         } else {
-            assert_eq!(seq_no, self.recv_seq_no.get());
+            None
         }
-        self.ack_deadline.set(None);
+    }
+
+    #[cfg(feature = "invariants")]
+    pub fn out_of_order_keys(&self) -> Vec<SeqNumber> {
+        self.out_of_order.borrow().keys().cloned().collect()
+    }
+
+    /// Returns when the oldest data covered by this ACK arrived, if the ACK covers any new data.
+    pub fn ack_sent(&self, seq_no: SeqNumber) -> Option<Instant> {
         self.ack_seq_no.set(seq_no);
+        // An ACK made before more arrived leaves the rest still to acknowledge.
+        if seq_no != self.ack_num() {
+            return None;
+        }
+        self.ack_deadline.set(None);
+        self.unacked_since.take()
     }
 
     pub fn peek(&self) -> Result<RT::Buf, Fail> {
         if self.base_seq_no.get() == self.recv_seq_no.get() {
-            if self.state.get() != ReceiverState::Open {
+            if self.at_end() {
                 return Err(Fail::ResourceNotFound {
                     details: "Receiver closed",
                 });
             }
-            return Err(Fail::ResourceExhausted {
-                details: "No available data",
-            });
+            return Err(Fail::WouldBlock {});
         }
 
         let segment = self
@@ -135,9 +260,82 @@ impl<RT: Runtime> Receiver<RT> {
         Ok(segment)
     }
 
+    /// Unread bytes `offset..(offset + len)` of the stream, leaving them unread. A range within
+    /// one received segment is a view of it; only a range spanning segments is copied.
+    pub fn peek_at(&self, offset: usize, len: usize) -> Result<RT::Buf, Fail> {
+        let Wrapping(unread) = self.recv_seq_no.get() - self.base_seq_no.get();
+        if offset + len > unread as usize {
+            if self.at_end() {
+                return Err(Fail::ResourceNotFound {
+                    details: "Receiver closed",
+                });
+            }
+            return Err(Fail::WouldBlock {});
+        }
+        if len == 0 {
+            return Ok(RT::Buf::empty());
+        }
+
+        let recv_queue = self.recv_queue.borrow();
+        let mut segments = recv_queue.iter();
+        let mut skip = offset;
+        let first = loop {
+            let segment = segments
+                .next()
+                .expect("recv_seq > base_seq without data in queue?");
+            if skip < segment.len() {
+                break segment;
+            }
+            skip -= segment.len();
+        };
+        if skip + len <= first.len() {
+            let mut view = first.clone();
+            view.adjust(skip);
+            view.trim(first.len() - skip - len);
+            return Ok(view);
+        }
+
+        let mut bytes = Vec::with_capacity(len);
+        bytes.extend_from_slice(&first[skip..]);
+        for segment in segments {
+            let n = cmp::min(segment.len(), len - bytes.len());
+            bytes.extend_from_slice(&segment[..n]);
+            if bytes.len() == len {
+                break;
+            }
+        }
+        Ok(RT::Buf::from_slice(&bytes[..]))
+    }
+
+    /// Mark the first `num_bytes` unread bytes as read, whatever segments they arrived in.
+    pub fn consume(&self, num_bytes: usize) -> Result<(), Fail> {
+        let Wrapping(unread) = self.recv_seq_no.get() - self.base_seq_no.get();
+        if num_bytes > unread as usize {
+            return Err(Fail::OutOfRange {
+                details: "Consuming more than is unread",
+            });
+        }
+        let mut recv_queue = self.recv_queue.borrow_mut();
+        let mut remaining = num_bytes;
+        while remaining > 0 {
+            let segment = recv_queue
+                .front_mut()
+                .expect("recv_seq > base_seq without data in queue?");
+            if segment.len() > remaining {
+                segment.adjust(remaining);
+                break;
+            }
+            remaining -= segment.len();
+            recv_queue.pop_front();
+        }
+        self.base_seq_no
+            .modify(|b| b + Wrapping(num_bytes as u32));
+        Ok(())
+    }
+
     pub fn recv(&self) -> Result<Option<RT::Buf>, Fail> {
         if self.base_seq_no.get() == self.recv_seq_no.get() {
-            if self.state.get() != ReceiverState::Open {
+            if self.at_end() {
                 return Err(Fail::ResourceNotFound {
                     details: "Receiver closed",
                 });
@@ -158,7 +356,7 @@ impl<RT: Runtime> Receiver<RT> {
 
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
         if self.base_seq_no.get() == self.recv_seq_no.get() {
-            if self.state.get() != ReceiverState::Open {
+            if self.at_end() {
                 return Poll::Ready(Err(Fail::ResourceNotFound {
                     details: "Receiver closed",
                 }));
@@ -178,12 +376,89 @@ impl<RT: Runtime> Receiver<RT> {
         Poll::Ready(Ok(segment))
     }
 
+    /// The out-of-order data we're holding, as SACK blocks: runs of contiguous segments, the one
+    /// holding the most recently received segment first and the rest in sequence order.
+    pub fn sack_blocks(&self) -> ArrayVec<[SelectiveAcknowlegement; MAX_SACK_BLOCKS]> {
+        let out_of_order = self.out_of_order.borrow();
+        let mut runs: Vec<SelectiveAcknowlegement> = vec![];
+        for (&seq_no, buf) in out_of_order.iter() {
+            let end = seq_no + Wrapping(buf.len() as u32);
+            match runs.last_mut() {
+                Some(run) if run.end == seq_no => run.end = end,
+                _ => runs.push(SelectiveAcknowlegement { begin: seq_no, end }),
+            }
+        }
+        if let Some(last) = self.last_out_of_order.get() {
+            if let Some(i) = runs.iter().position(|r| last - r.begin < r.end - r.begin) {
+                let run = runs.remove(i);
+                runs.insert(0, run);
+            }
+        }
+        runs.into_iter().take(MAX_SACK_BLOCKS).collect()
+    }
+
+    /// Stop delivering data: what's unread is thrown away, as is anything that arrives from now
+    /// on, and reads fail as they do once the remote has closed.
+    pub fn shutdown_read(&self) {
+        self.read_shutdown.set(true);
+        self.recv_queue.borrow_mut().clear();
+        self.base_seq_no.set(self.recv_seq_no.get());
+        self.waker.borrow_mut().take().map(|w| w.wake());
+    }
+
+    /// Bytes received in order that the application hasn't read.
+    pub fn unread(&self) -> usize {
+        let Wrapping(unread) = self.recv_seq_no.get() - self.base_seq_no.get();
+        unread as usize
+    }
+
+    /// Wake the reader when more data arrives.
+    pub fn register_waker(&self, ctx: &mut Context) {
+        *self.waker.borrow_mut() = Some(ctx.waker().clone());
+    }
+
+    /// Note that a segment marked with PSH ends at `end`.
+    pub fn mark_push(&self, end: SeqNumber) {
+        let Wrapping(offset) = end - self.base_seq_no.get();
+        if offset > 0 && offset < (1 << 31) {
+            self.push_marks.borrow_mut().push(end);
+        }
+    }
+
+    /// How many unread bytes there are up to the first push boundary, if one has arrived. Marks
+    /// that reading has passed are dropped.
+    pub fn next_push_mark(&self) -> Option<usize> {
+        let base_seq_no = self.base_seq_no.get();
+        let mut push_marks = self.push_marks.borrow_mut();
+        push_marks.retain(|&end| {
+            let Wrapping(offset) = end - base_seq_no;
+            offset > 0 && offset < (1 << 31)
+        });
+        push_marks
+            .iter()
+            .map(|&end| (end - base_seq_no).0 as usize)
+            .filter(|&offset| offset <= self.unread())
+            .min()
+    }
+
+    /// Whether there's nothing more to read, once what's queued has been: the remote closed or
+    /// the application shut down reading.
+    pub fn at_end(&self) -> bool {
+        self.state.get() != ReceiverState::Open || self.read_shutdown.get()
+    }
+
     pub fn receive_fin(&self) {
         // Even if we've already ACKd the FIN, we need to resend the ACK if we receive another FIN.
         self.state.set(ReceiverState::ReceivedFin);
     }
 
-    pub fn receive_data(&self, seq_no: SeqNumber, buf: RT::Buf, now: Instant) -> Result<(), Fail> {
+    pub fn receive_data(
+        &self,
+        seq_no: SeqNumber,
+        mut buf: RT::Buf,
+        now: Instant,
+        ack_delay: Duration,
+    ) -> Result<(), Fail> {
         if self.state.get() != ReceiverState::Open {
             return Err(Fail::ResourceNotFound {
                 details: "Receiver closed",
@@ -192,60 +467,144 @@ impl<RT: Runtime> Receiver<RT> {
 
         let recv_seq_no = self.recv_seq_no.get();
         if seq_no > recv_seq_no {
-            let mut out_of_order = self.out_of_order.borrow_mut();
-            if !out_of_order.contains_key(&seq_no) {
-                while out_of_order.len() > MAX_OUT_OF_ORDER {
-                    let (&key, _) = out_of_order.iter().rev().next().unwrap();
-                    out_of_order.remove(&key);
-                }
-                out_of_order.insert(seq_no, buf);
-                return Err(Fail::Ignored {
-                    details: "Out of order segment (reordered)",
-                });
-            }
+            self.queue_out_of_order(seq_no, buf);
+            return Err(Fail::Ignored {
+                details: "Out of order segment (reordered)",
+            });
         }
-        if seq_no < recv_seq_no {
+        // A retransmission may overlap what we already have, in which case only the rest is new.
+        let Wrapping(overlap) = recv_seq_no - seq_no;
+        if overlap as usize >= buf.len() {
+            self.record_duplicate(&self.segments_duplicate, buf.len());
             return Err(Fail::Ignored {
                 details: "Out of order segment (duplicate)",
             });
         }
+        if overlap > 0 {
+            self.record_duplicate(&self.segments_overlapping, overlap as usize);
+            buf.adjust(overlap as usize);
+        }
 
-        let unread_bytes = self
-            .recv_queue
-            .borrow()
-            .iter()
-            .map(|b| b.len())
-            .sum::<usize>();
-        if unread_bytes + buf.len() > self.max_window_size as usize {
+        if buf.len() > self.window_remaining() {
             return Err(Fail::Ignored {
                 details: "Full receive window",
             });
         }
+        self.push_in_order(buf);
+        self.reassemble();
 
-        self.recv_seq_no.modify(|r| r + Wrapping(buf.len() as u32));
-        self.recv_queue.borrow_mut().push_back(buf);
         self.waker.borrow_mut().take().map(|w| w.wake());
+        self.last_received.set(Some(now));
+        if self.unacked_since.get().is_none() {
+            self.unacked_since.set(Some(now));
+        }
 
         // TODO: How do we handle when the other side is in PERSIST state here?
         if self.ack_deadline.get().is_none() {
-            // TODO: Configure this value (and also maybe just have an RT pointer here.)
-            self.ack_deadline
-                .set(Some(now + Duration::from_millis(500)));
+            self.ack_deadline.set(Some(now + ack_delay));
         }
 
-        let new_recv_seq_no = self.recv_seq_no.get();
-        let old_data = {
-            let mut out_of_order = self.out_of_order.borrow_mut();
-            out_of_order.remove(&new_recv_seq_no)
-        };
-        if let Some(old_data) = old_data {
-            info!("Recovering out-of-order packet at {}", new_recv_seq_no);
-            if let Err(e) = self.receive_data(new_recv_seq_no, old_data, now) {
-                info!("Failed to recover out-of-order packet: {:?}", e);
+        Ok(())
+    }
+
+    // How much more the receive queue has room for.
+    fn window_remaining(&self) -> usize {
+        let unread_bytes = self
+            .recv_queue
+            .borrow()
+            .iter()
+            .map(|b| b.len())
+            .sum::<usize>();
+        (self.max_window_size as usize).saturating_sub(unread_bytes)
+    }
+
+    fn push_in_order(&self, buf: RT::Buf) {
+        self.recv_seq_no.modify(|r| r + Wrapping(buf.len() as u32));
+        if self.read_shutdown.get() {
+            self.base_seq_no.set(self.recv_seq_no.get());
+            return;
+        }
+        self.recv_queue.borrow_mut().push_back(buf);
+    }
+
+    fn record_duplicate(&self, counter: &Cell<u64>, num_bytes: usize) {
+        counter.set(counter.get() + 1);
+        self.bytes_duplicate
+            .set(self.bytes_duplicate.get() + num_bytes as u64);
+    }
+
+    fn discard(&self, num_bytes: usize) {
+        self.bytes_reassembly_discarded
+            .set(self.bytes_reassembly_discarded.get() + num_bytes as u64);
+    }
+
+    // Hold on to a segment past a hole, within the window and the reassembly limit.
+    fn queue_out_of_order(&self, seq_no: SeqNumber, buf: RT::Buf) {
+        let Wrapping(offset) = seq_no - self.recv_seq_no.get();
+        if offset as usize + buf.len() > self.window_remaining() {
+            self.discard(buf.len());
+            return;
+        }
+        let mut out_of_order = self.out_of_order.borrow_mut();
+        // Of two segments starting at the same place, keep the longer.
+        if let Some(existing) = out_of_order.get(&seq_no) {
+            if existing.len() >= buf.len() {
+                self.record_duplicate(&self.segments_duplicate, buf.len());
+                self.discard(buf.len());
+                return;
             }
+            self.record_duplicate(&self.segments_overlapping, existing.len());
+            let existing = out_of_order.remove(&seq_no).unwrap();
+            self.out_of_order_bytes
+                .set(self.out_of_order_bytes.get() - existing.len());
+            self.discard(existing.len());
         }
+        self.out_of_order_bytes
+            .set(self.out_of_order_bytes.get() + buf.len());
+        out_of_order.insert(seq_no, buf);
 
-        Ok(())
+        // Over the limit, give up on what's furthest from the hole, which we'd need last.
+        while self.out_of_order_bytes.get() > self.reassembly_limit {
+            let (&key, _) = out_of_order.iter().next_back().unwrap();
+            let evicted = out_of_order.remove(&key).unwrap();
+            self.out_of_order_bytes
+                .set(self.out_of_order_bytes.get() - evicted.len());
+            self.discard(evicted.len());
+        }
+        if out_of_order.contains_key(&seq_no) {
+            self.last_out_of_order.set(Some(seq_no));
+        }
+    }
+
+    // Move whatever the latest in-order data has caught up with from the out-of-order queue to
+    // the receive queue, trimming what overlaps data we already have.
+    fn reassemble(&self) {
+        let mut out_of_order = self.out_of_order.borrow_mut();
+        loop {
+            let recv_seq_no = self.recv_seq_no.get();
+            let seq_no = match out_of_order.keys().next() {
+                Some(&seq_no) if seq_no <= recv_seq_no => seq_no,
+                _ => break,
+            };
+            let mut buf = out_of_order.remove(&seq_no).unwrap();
+            self.out_of_order_bytes
+                .set(self.out_of_order_bytes.get() - buf.len());
+            let Wrapping(overlap) = recv_seq_no - seq_no;
+            if overlap as usize >= buf.len() {
+                self.discard(buf.len());
+                continue;
+            }
+            self.discard(overlap as usize);
+            buf.adjust(overlap as usize);
+            if buf.len() > self.window_remaining() {
+                self.discard(buf.len());
+                continue;
+            }
+            info!("Recovering out-of-order data at {}", recv_seq_no);
+            self.bytes_reassembled
+                .set(self.bytes_reassembled.get() + buf.len() as u64);
+            self.push_in_order(buf);
+        }
     }
 }
 
@@ -254,22 +613,122 @@ mod tests {
     use super::Receiver;
     use crate::{
         fail::Fail,
-        sync::BytesMut,
+        sync::{
+            Bytes,
+            BytesMut,
+        },
     };
     use must_let::must_let;
     use std::{
         num::Wrapping,
-        time::Instant,
+        time::{
+            Duration,
+            Instant,
+        },
     };
     use crate::test_helpers::TestRuntime;
 
     #[test]
     fn test_out_of_order() {
         let now = Instant::now();
-        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0);
+        let ack_delay = Duration::from_millis(1);
+        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0, 65536);
         let buf = BytesMut::zeroed(16).freeze();
-        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(16), buf.clone(), now));
-        must_let!(let Ok(..) = receiver.receive_data(Wrapping(0), buf.clone(), now));
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(16), buf.clone(), now, ack_delay));
+        must_let!(let Ok(..) = receiver.receive_data(Wrapping(0), buf.clone(), now, ack_delay));
         assert_eq!(receiver.recv_seq_no.get(), Wrapping(32))
     }
+
+    #[test]
+    fn test_sack_blocks() {
+        let now = Instant::now();
+        let ack_delay = Duration::from_millis(1);
+        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0, 65536);
+        let buf = BytesMut::zeroed(16).freeze();
+        assert!(receiver.sack_blocks().is_empty());
+        for &seq_no in &[16, 64, 32, 80] {
+            must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(seq_no), buf.clone(), now, ack_delay));
+        }
+        // Contiguous segments are merged, and the latest arrival's block goes first.
+        let blocks = receiver
+            .sack_blocks()
+            .iter()
+            .map(|b| (b.begin.0, b.end.0))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![(64, 96), (16, 48)]);
+
+        // Filling the hole at the front leaves only the later block.
+        must_let!(let Ok(..) = receiver.receive_data(Wrapping(0), buf.clone(), now, ack_delay));
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(48));
+        assert_eq!(receiver.sack_blocks().len(), 1);
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let now = Instant::now();
+        let ack_delay = Duration::from_millis(1);
+        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0, 32);
+        let data: Vec<u8> = (0..64).collect();
+        let segment = |begin: usize, end: usize| Bytes::from_slice(&data[begin..end]);
+
+        // Only 32 bytes fit out of order, so the segment furthest ahead goes.
+        for &(begin, end) in &[(16, 32), (40, 56), (48, 64)] {
+            must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(begin as u32), segment(begin, end), now, ack_delay));
+        }
+        assert_eq!(receiver.bytes_reassembly_discarded.get(), 16);
+
+        // Each hole that fills brings in what's held behind it, less what overlaps.
+        receiver.receive_data(Wrapping(0), segment(0, 20), now, ack_delay).unwrap();
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(32));
+        receiver.receive_data(Wrapping(28), segment(28, 44), now, ack_delay).unwrap();
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(56));
+        assert_eq!(&receiver.peek_at(0, 56).unwrap()[..], &data[..56]);
+        assert_eq!(receiver.bytes_reassembled.get(), 24);
+        assert_eq!(receiver.bytes_reassembly_discarded.get(), 24);
+        assert!(receiver.sack_blocks().is_empty());
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let now = Instant::now();
+        let ack_delay = Duration::from_millis(1);
+        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0, 65536);
+        let data: Vec<u8> = (0..32).collect();
+        let segment = |begin: usize, end: usize| Bytes::from_slice(&data[begin..end]);
+
+        receiver.receive_data(Wrapping(0), segment(0, 16), now, ack_delay).unwrap();
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(0), segment(0, 16), now, ack_delay));
+        receiver.receive_data(Wrapping(8), segment(8, 24), now, ack_delay).unwrap();
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(28), segment(28, 32), now, ack_delay));
+        must_let!(let Err(Fail::Ignored { .. }) = receiver.receive_data(Wrapping(28), segment(28, 30), now, ack_delay));
+        assert_eq!(receiver.segments_duplicate.get(), 2);
+        assert_eq!(receiver.segments_overlapping.get(), 1);
+        assert_eq!(receiver.bytes_duplicate.get(), 16 + 8 + 2);
+
+        // Only the new part of the overlapping segment was delivered.
+        assert_eq!(receiver.recv_seq_no.get(), Wrapping(24));
+        assert_eq!(&receiver.peek_at(0, 24).unwrap()[..], &data[..24]);
+    }
+
+    #[test]
+    fn test_peek_at_and_consume() {
+        let now = Instant::now();
+        let ack_delay = Duration::from_millis(1);
+        let receiver = Receiver::<TestRuntime>::new(Wrapping(0), 65536, 0, 65536);
+        let data: Vec<u8> = (0..16).collect();
+        receiver.receive_data(Wrapping(0), Bytes::from_slice(&data[..8]), now, ack_delay).unwrap();
+        receiver.receive_data(Wrapping(8), Bytes::from_slice(&data[8..]), now, ack_delay).unwrap();
+
+        assert_eq!(&receiver.peek_at(4, 2).unwrap()[..], &data[4..6]);
+        assert_eq!(&receiver.peek_at(6, 4).unwrap()[..], &data[6..10]);
+        must_let!(let Err(Fail::WouldBlock {}) = receiver.peek_at(10, 10));
+
+        // Consuming splits the second segment, and nothing has to be popped whole.
+        receiver.consume(10).unwrap();
+        assert_eq!(receiver.base_seq_no.get(), Wrapping(10));
+        assert_eq!(&receiver.peek_at(0, 6).unwrap()[..], &data[10..]);
+        must_let!(let Err(Fail::OutOfRange { .. }) = receiver.consume(7));
+        must_let!(let Ok(Some(rest)) = receiver.recv());
+        assert_eq!(&rest[..], &data[10..]);
+    }
 }
@@ -1,34 +1,65 @@
+use crate::snapshot::RtoSnapshot;
 use float_duration::FloatDuration;
 use std::{
     cmp,
     time::Duration,
 };
 
-// RFC6298
+// Clock granularity, G in RFC 6298.
+const GRANULARITY: f64 = 0.001;
+
+/// Retransmission timeout estimation per RFC 6298. Callers follow Karn's algorithm by only
+/// sampling segments that weren't retransmitted.
 #[derive(Debug)]
 pub struct RtoCalculator {
     srtt: f64,
     rttvar: f64,
     rto: f64,
+    min_rto: f64,
+    max_rto: f64,
 
     received_sample: bool,
 }
 
 impl RtoCalculator {
-    pub fn new() -> Self {
-        Self {
+    /// Start at RFC 6298's initial RTO of one second, or the nearer bound if it's outside them.
+    pub fn new(min_rto: Duration, max_rto: Duration) -> Self {
+        let mut calculator = Self {
             srtt: 1.0,
             rttvar: 0.0,
             rto: 1.0,
+            min_rto: FloatDuration::from(min_rto).as_seconds(),
+            max_rto: FloatDuration::from(max_rto).as_seconds(),
 
             received_sample: false,
+        };
+        calculator.update_rto(1.0);
+        calculator
+    }
+
+    pub fn restore(snapshot: &RtoSnapshot, min_rto: Duration, max_rto: Duration) -> Self {
+        let mut calculator = Self::new(min_rto, max_rto);
+        calculator.rttvar = FloatDuration::from(snapshot.rttvar).as_seconds();
+        if let Some(srtt) = snapshot.srtt {
+            calculator.srtt = FloatDuration::from(srtt).as_seconds();
+            calculator.received_sample = true;
+        }
+        calculator.update_rto(FloatDuration::from(snapshot.rto).as_seconds());
+        calculator
+    }
+
+    pub fn snapshot(&self) -> RtoSnapshot {
+        RtoSnapshot {
+            srtt: self.smoothed_rtt(),
+            rttvar: self.rttvar(),
+            rto: self.estimate(),
         }
     }
 
     pub fn add_sample(&mut self, rtt: Duration) {
         const ALPHA: f64 = 0.125;
         const BETA: f64 = 0.25;
-        const GRANULARITY: f64 = 0.001f64;
+        const K: f64 = 4.0;
 
         let rtt = FloatDuration::from(rtt).as_seconds();
 
@@ -37,27 +68,26 @@ impl RtoCalculator {
             self.rttvar = rtt / 2.;
             self.received_sample = true;
         } else {
+            // RTTVAR uses the SRTT from before this sample, so it goes first.
             self.rttvar = (1.0 - BETA) * self.rttvar + BETA * (self.srtt - rtt).abs();
             self.srtt = (1.0 - ALPHA) * self.srtt + ALPHA * rtt;
         }
 
-        let rttvar_x4 = match (4.0 * self.rttvar).partial_cmp(&GRANULARITY) {
+        let variance = match (K * self.rttvar).partial_cmp(&GRANULARITY) {
             Some(cmp::Ordering::Less) => GRANULARITY,
             None => panic!("NaN rttvar: {:?}", self.rttvar),
-            _ => self.rttvar,
+            _ => K * self.rttvar,
         };
-        self.update_rto(self.srtt + rttvar_x4);
+        self.update_rto(self.srtt + variance);
     }
 
     fn update_rto(&mut self, new_rto: f64) {
-        const UBOUND_SEC: f64 = 60.0f64;
-        const LBOUND_SEC: f64 = 0.100f64;
         self.rto = match (
-            new_rto.partial_cmp(&LBOUND_SEC),
-            new_rto.partial_cmp(&UBOUND_SEC),
+            new_rto.partial_cmp(&self.min_rto),
+            new_rto.partial_cmp(&self.max_rto),
         ) {
-            (Some(cmp::Ordering::Less), _) => LBOUND_SEC,
-            (_, Some(cmp::Ordering::Greater)) => UBOUND_SEC,
+            (Some(cmp::Ordering::Less), _) => self.min_rto,
+            (_, Some(cmp::Ordering::Greater)) => self.max_rto,
             (None, _) | (_, None) => panic!("NaN RTO: {:?}", new_rto),
             _ => new_rto,
         };
@@ -67,6 +97,23 @@ impl RtoCalculator {
         self.update_rto(self.rto * 2.0);
     }
 
+    /// The smoothed RTT, once there's been a sample to smooth.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        if self.received_sample {
+            Some(self.srtt())
+        } else {
+            None
+        }
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        FloatDuration::seconds(self.rttvar).to_std().unwrap()
+    }
+
+    pub fn srtt(&self) -> Duration {
+        FloatDuration::seconds(self.srtt).to_std().unwrap()
+    }
+
     pub fn estimate(&self) -> Duration {
         FloatDuration::seconds(self.rto).to_std().unwrap()
     }
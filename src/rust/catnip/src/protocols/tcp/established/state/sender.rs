@@ -1,12 +1,29 @@
-use super::rto::RtoCalculator;
+use super::{
+    congestion::NewReno,
+    rto::RtoCalculator,
+};
 use crate::{
     collections::watched::WatchedValue,
     fail::Fail,
-    protocols::tcp::SeqNumber,
+    protocols::tcp::{
+        constants::MIN_MSS,
+        segment::SelectiveAcknowlegement,
+        SeqNumber,
+    },
     runtime::{Runtime, RuntimeBuf},
+    snapshot::SenderSnapshot,
+    stats::TcpLatencyRecorder,
+};
+use serde::{
+    Deserialize,
+    Serialize,
 };
 use std::{
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
     collections::VecDeque,
     convert::TryInto,
     fmt,
@@ -21,9 +38,17 @@ pub struct UnackedSegment<RT: Runtime> {
     pub bytes: RT::Buf,
     // Set to `None` on retransmission to implement Karn's algorithm.
     pub initial_tx: Option<Instant>,
+    // Whether the remote has selectively acknowledged this, so it needn't be resent on a loss.
+    pub sacked: bool,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnsentSegment<RT: Runtime> {
+    pub bytes: RT::Buf,
+    // When the data was pushed by the application, for tracking time spent in the send queue.
+    pub enqueued: Instant,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum SenderState {
     Open,
     Closed,
@@ -32,8 +57,38 @@ pub enum SenderState {
     Reset,
 }
 
+/// Whether a connection's sender is held up by the remote. See `Engine::tcp_send_status`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpSendStatus {
+    /// Sending whatever there is as fast as the windows allow.
+    Open,
+    /// The remote's window has been closed since `since` with data waiting to go. The persist
+    /// timer has sent `probes` window probes so far, and sends the next at `next_probe`.
+    ZeroWindow {
+        since: Instant,
+        probes: u32,
+        next_probe: Instant,
+    },
+}
+
+/// How much room a connection has to send, for choosing which connection to write to next. See
+/// `Engine::tcp_send_window`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpSendWindow {
+    /// Bytes sent but not yet acknowledged.
+    pub bytes_in_flight: u32,
+    /// How much more the remote's window has room for, after everything already written,
+    /// including what's queued behind the windows.
+    pub peer_window_remaining: u32,
+    /// Likewise for the congestion window.
+    pub cwnd_remaining: u32,
+}
+
 pub struct Sender<RT: Runtime> {
     pub state: WatchedValue<SenderState>,
+    // Whether the remote's FIN had already arrived when we closed, i.e. we're on the passive side
+    // of the close.
+    pub passive_close: Cell<bool>,
 
     // TODO: Just use Figure 5 from RFC 793 here.
     //
@@ -47,17 +102,30 @@ pub struct Sender<RT: Runtime> {
     pub base_seq_no: WatchedValue<SeqNumber>,
     pub unacked_queue: RefCell<VecDeque<UnackedSegment<RT>>>,
     pub sent_seq_no: WatchedValue<SeqNumber>,
-    pub unsent_queue: RefCell<VecDeque<RT::Buf>>,
+    pub unsent_queue: RefCell<VecDeque<UnsentSegment<RT>>>,
     pub unsent_seq_no: WatchedValue<SeqNumber>,
 
     pub window_size: WatchedValue<u32>,
+    // Set by the persist timer while the remote's window is closed.
+    pub status: Cell<TcpSendStatus>,
     // RFC 1323: Number of bits to shift advertised window, defaults to zero.
     pub window_scale: u8,
 
-    pub mss: usize,
+    // The largest segment we send: the remote's MSS, lowered if the path MTU turns out smaller.
+    pub mss: Cell<usize>,
+    // Retransmission timeouts since an ACK last took new data, for detecting PMTU black holes.
+    pub consecutive_timeouts: Cell<usize>,
+    // Whether both sides agreed to selective acknowledgments during the handshake.
+    pub sack_permitted: bool,
+    // Whether small segments wait for outstanding data to be acknowledged (Nagle's algorithm).
+    pub nagle: WatchedValue<bool>,
+    // Most bytes queued or unacknowledged before writes are turned away, or zero for no limit.
+    pub send_buffer_size: Cell<usize>,
 
     pub retransmit_deadline: WatchedValue<Option<Instant>>,
     pub rto: RefCell<RtoCalculator>,
+
+    pub congestion: NewReno,
 }
 
 impl<RT: Runtime> fmt::Debug for Sender<RT> {
@@ -68,17 +136,30 @@ impl<RT: Runtime> fmt::Debug for Sender<RT> {
             .field("unsent_seq_no", &self.unsent_seq_no)
             .field("window_size", &self.window_size)
             .field("window_scale", &self.window_scale)
-            .field("mss", &self.mss)
+            .field("mss", &self.mss.get())
+            .field("sack_permitted", &self.sack_permitted)
             .field("retransmit_deadline", &self.retransmit_deadline)
             .field("rto", &self.rto)
+            .field("congestion", &self.congestion.stats())
             .finish()
     }
 }
 
 impl<RT: Runtime> Sender<RT> {
-    pub fn new(seq_no: SeqNumber, window_size: u32, window_scale: u8, mss: usize) -> Self {
+    pub fn new(
+        seq_no: SeqNumber,
+        window_size: u32,
+        window_scale: u8,
+        mss: usize,
+        sack_permitted: bool,
+        nagle: bool,
+        send_buffer_size: usize,
+        min_rto: Duration,
+        max_rto: Duration,
+    ) -> Self {
         Self {
             state: WatchedValue::new(SenderState::Open),
+            passive_close: Cell::new(false),
 
             base_seq_no: WatchedValue::new(seq_no),
             unacked_queue: RefCell::new(VecDeque::new()),
@@ -87,11 +168,116 @@ impl<RT: Runtime> Sender<RT> {
             unsent_seq_no: WatchedValue::new(seq_no),
 
             window_size: WatchedValue::new(window_size),
+            status: Cell::new(TcpSendStatus::Open),
             window_scale,
-            mss,
+            mss: Cell::new(mss),
+            consecutive_timeouts: Cell::new(0),
+            sack_permitted,
+            nagle: WatchedValue::new(nagle),
+            send_buffer_size: Cell::new(send_buffer_size),
 
             retransmit_deadline: WatchedValue::new(None),
-            rto: RefCell::new(RtoCalculator::new()),
+            rto: RefCell::new(RtoCalculator::new(min_rto, max_rto)),
+
+            congestion: NewReno::new(mss, seq_no),
+        }
+    }
+
+    /// Rebuild a sender from a snapshot taken on another engine. Unacknowledged data is
+    /// retransmitted once the RTO expires, since we can't tell what the remote has received, and
+    /// the congestion window starts over.
+    pub fn restore(
+        snapshot: &SenderSnapshot,
+        now: Instant,
+        min_rto: Duration,
+        max_rto: Duration,
+    ) -> Self {
+        let unacked_queue = snapshot
+            .unacked
+            .iter()
+            .map(|bytes| UnackedSegment {
+                bytes: RT::Buf::from_slice(&bytes[..]),
+                initial_tx: None,
+                sacked: false,
+            })
+            .collect::<VecDeque<_>>();
+        let unsent_queue = snapshot
+            .unsent
+            .iter()
+            .map(|bytes| UnsentSegment {
+                bytes: RT::Buf::from_slice(&bytes[..]),
+                enqueued: now,
+            })
+            .collect();
+        let rto = RtoCalculator::restore(&snapshot.rto, min_rto, max_rto);
+        let retransmit_deadline = if unacked_queue.is_empty() {
+            None
+        } else {
+            Some(now + rto.estimate())
+        };
+        Self {
+            state: WatchedValue::new(snapshot.state),
+            passive_close: Cell::new(snapshot.passive_close),
+
+            base_seq_no: WatchedValue::new(Wrapping(snapshot.base_seq_no)),
+            unacked_queue: RefCell::new(unacked_queue),
+            sent_seq_no: WatchedValue::new(Wrapping(snapshot.sent_seq_no)),
+            unsent_queue: RefCell::new(unsent_queue),
+            unsent_seq_no: WatchedValue::new(Wrapping(snapshot.unsent_seq_no)),
+
+            window_size: WatchedValue::new(snapshot.window_size),
+            status: Cell::new(TcpSendStatus::Open),
+            window_scale: snapshot.window_scale,
+            mss: Cell::new(snapshot.mss),
+            consecutive_timeouts: Cell::new(0),
+            sack_permitted: snapshot.sack_permitted,
+            nagle: WatchedValue::new(snapshot.nagle),
+            send_buffer_size: Cell::new(snapshot.send_buffer_size),
+
+            retransmit_deadline: WatchedValue::new(retransmit_deadline),
+            rto: RefCell::new(rto),
+
+            congestion: NewReno::new(snapshot.mss, Wrapping(snapshot.base_seq_no)),
+        }
+    }
+
+    pub fn snapshot(&self) -> SenderSnapshot {
+        SenderSnapshot {
+            state: self.state.get(),
+            passive_close: self.passive_close.get(),
+            base_seq_no: self.base_seq_no.get().0,
+            sent_seq_no: self.sent_seq_no.get().0,
+            unsent_seq_no: self.unsent_seq_no.get().0,
+            unacked: self
+                .unacked_queue
+                .borrow()
+                .iter()
+                .map(|s| s.bytes.to_vec())
+                .collect(),
+            unsent: self
+                .unsent_queue
+                .borrow()
+                .iter()
+                .map(|s| s.bytes.to_vec())
+                .collect(),
+            window_size: self.window_size.get(),
+            window_scale: self.window_scale,
+            mss: self.mss.get(),
+            rto: self.rto.borrow().snapshot(),
+            sack_permitted: self.sack_permitted,
+            nagle: self.nagle.get(),
+            send_buffer_size: self.send_buffer_size.get(),
+        }
+    }
+
+    pub fn send_window(&self) -> TcpSendWindow {
+        let base_seq = self.base_seq_no.get();
+        let Wrapping(bytes_in_flight) = self.sent_seq_no.get() - base_seq;
+        let Wrapping(bytes_written) = self.unsent_seq_no.get() - base_seq;
+        TcpSendWindow {
+            bytes_in_flight,
+            peer_window_remaining: self.window_size.get().saturating_sub(bytes_written),
+            cwnd_remaining: self.congestion.cwnd().saturating_sub(bytes_written),
         }
     }
 
@@ -105,45 +291,115 @@ impl<RT: Runtime> Sender<RT> {
             details: "Buffer too large",
         })?;
 
-        let win_sz = self.window_size.get();
+        let win_sz = cmp::min(self.window_size.get(), self.congestion.cwnd());
         let base_seq = self.base_seq_no.get();
         let sent_seq = self.sent_seq_no.get();
         let Wrapping(sent_data) = sent_seq - base_seq;
 
-        // Fast path: Try to send the data immediately.
-        if win_sz > 0 && win_sz >= sent_data + buf_len {
+        // Fast path: Try to send the data immediately, unless it'd overtake queued data or Nagle's
+        // algorithm holds it back.
+        let queue_empty = self.unsent_seq_no.get() == sent_seq;
+        let nagle_holds = self.nagle_holds(sent_data, buf_len);
+        let fits = buf.len() <= self.mss.get();
+        if queue_empty && !nagle_holds && fits && win_sz > 0 && win_sz >= sent_data + buf_len {
             if let Some(remote_link_addr) = cb.arp.try_query(cb.remote.address()) {
                 let mut header = cb.tcp_header();
                 header.seq_num = sent_seq;
                 cb.emit(header, buf.clone(), remote_link_addr);
+                cb.latency.record_send_queue_time(Duration::from_secs(0));
 
                 self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
                 self.sent_seq_no.modify(|s| s + Wrapping(buf_len));
                 let unacked_segment = UnackedSegment {
                     bytes: buf,
                     initial_tx: Some(cb.rt.now()),
+                    sacked: false,
                 };
                 self.unacked_queue.borrow_mut().push_back(unacked_segment);
                 if self.retransmit_deadline.get().is_none() {
                     let rto = self.rto.borrow().estimate();
                     self.retransmit_deadline.set(Some(cb.rt.now() + rto));
                 }
+                cb.check_invariants();
                 return Ok(());
             }
         }
         // Slow path: Delegating sending the data to background processing.
-        self.unsent_queue.borrow_mut().push_back(buf);
+        let unsent_segment = UnsentSegment {
+            bytes: buf,
+            enqueued: cb.rt.now(),
+        };
+        self.unsent_queue.borrow_mut().push_back(unsent_segment);
         self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
+        cb.check_invariants();
+
+        Ok(())
+    }
+
+    /// Queue `bufs` to go out back to back, as if written one after the other, without joining
+    /// them first. Segments are cut from the queue as usual, so only a segment's worth of small
+    /// buffers at a time is copied together.
+    pub fn send_vectored(
+        &self,
+        bufs: Vec<RT::Buf>,
+        cb: &super::ControlBlock<RT>,
+    ) -> Result<(), Fail> {
+        if bufs.len() == 1 {
+            return self.send(bufs.into_iter().next().unwrap(), cb);
+        }
+        if self.state.get() != SenderState::Open {
+            return Err(Fail::Ignored {
+                details: "Sender closed",
+            });
+        }
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        let len: u32 = len.try_into().map_err(|_| Fail::Ignored {
+            details: "Buffer too large",
+        })?;
+
+        // Queued together, so a header isn't sent on its own ahead of the payload after it.
+        let now = cb.rt.now();
+        let mut unsent_queue = self.unsent_queue.borrow_mut();
+        for buf in bufs.into_iter().filter(|b| b.len() > 0) {
+            unsent_queue.push_back(UnsentSegment {
+                bytes: buf,
+                enqueued: now,
+            });
+        }
+        drop(unsent_queue);
+        self.unsent_seq_no.modify(|s| s + Wrapping(len));
+        cb.check_invariants();
 
         Ok(())
     }
 
-    pub fn close(&self) -> Result<(), Fail> {
+    /// Bytes written that the remote hasn't acknowledged yet, sent or not.
+    pub fn buffered(&self) -> usize {
+        let Wrapping(buffered) = self.unsent_seq_no.get() - self.base_seq_no.get();
+        buffered as usize
+    }
+
+    /// Whether a write of `len` bytes fits in the send buffer. One larger than the whole buffer
+    /// is let in once it's empty, or it could never be sent.
+    pub fn has_room(&self, len: usize) -> bool {
+        let limit = self.send_buffer_size.get();
+        let buffered = self.buffered();
+        limit == 0 || buffered == 0 || buffered + len <= limit
+    }
+
+    /// Nagle's algorithm (RFC 1122 4.2.3.4): with `in_flight` bytes unacknowledged, whether a
+    /// segment of `sendable` bytes, less than a full one, should wait for an ACK.
+    pub fn nagle_holds(&self, in_flight: u32, sendable: u32) -> bool {
+        self.nagle.get() && in_flight > 0 && (sendable as usize) < self.mss.get()
+    }
+
+    pub fn close(&self, passive: bool) -> Result<(), Fail> {
         if self.state.get() != SenderState::Open {
             return Err(Fail::Ignored {
                 details: "Sender closed",
             });
         }
+        self.passive_close.set(passive);
         self.state.set(SenderState::Closed);
         Ok(())
     }
@@ -152,18 +408,34 @@ impl<RT: Runtime> Sender<RT> {
         self.state.set(SenderState::Reset);
     }
 
-    pub fn remote_ack(&self, ack_seq_no: SeqNumber, now: Instant) -> Result<(), Fail> {
-        if self.state.get() == SenderState::SentFin
-            && ack_seq_no == self.base_seq_no.get() + Wrapping(1)
-        {
-            assert_eq!(self.base_seq_no.get(), self.sent_seq_no.get());
-            assert_eq!(self.sent_seq_no.get(), self.unsent_seq_no.get());
+    /// Process an ACK, which RFC 5681 would call a duplicate if `duplicate` is set and it doesn't
+    /// acknowledge anything new. `ts_rtt` is the RTT its timestamp echo measured, if it had one.
+    /// Returns whether the oldest unacknowledged segment should be retransmitted right away, for
+    /// fast retransmit or recovery.
+    pub fn remote_ack(
+        &self,
+        ack_seq_no: SeqNumber,
+        duplicate: bool,
+        ts_rtt: Option<Duration>,
+        now: Instant,
+        latency: &TcpLatencyRecorder,
+    ) -> Result<bool, Fail> {
+        let sent_seq_no = self.sent_seq_no.get();
+        if self.state.get() == SenderState::SentFin && ack_seq_no == sent_seq_no + Wrapping(1) {
+            if sent_seq_no != self.unsent_seq_no.get() {
+                return Err(Fail::Malformed {
+                    details: "ACK for FIN ahead of unsent data",
+                });
+            }
+            // The remote may acknowledge the last of the data and the FIN at once.
+            if self.base_seq_no.get() != sent_seq_no {
+                self.remote_ack(sent_seq_no, false, ts_rtt, now, latency)?;
+            }
             self.state.set(SenderState::FinAckd);
-            return Ok(());
+            return Ok(false);
         }
 
         let base_seq_no = self.base_seq_no.get();
-        let sent_seq_no = self.sent_seq_no.get();
 
         let bytes_outstanding = sent_seq_no - base_seq_no;
         let bytes_acknowledged = ack_seq_no - base_seq_no;
@@ -174,9 +446,31 @@ impl<RT: Runtime> Sender<RT> {
             });
         }
         if bytes_acknowledged == Wrapping(0) {
-            // TODO: Handle fast retransmit here.
-            return Ok(());
+            if duplicate && bytes_outstanding != Wrapping(0) {
+                return Ok(self.congestion.on_duplicate_ack(
+                    ack_seq_no,
+                    sent_seq_no,
+                    bytes_outstanding.0,
+                ));
+            }
+            return Ok(false);
         }
+        // TODO: Do acks need to be on segment boundaries? How does this interact with repacketization?
+        // Check before touching the queue or the retransmission timer, so a bad ACK leaves both as
+        // they were.
+        let mut boundary = 0;
+        for segment in self.unacked_queue.borrow().iter() {
+            if boundary >= bytes_acknowledged.0 as usize {
+                break;
+            }
+            boundary += segment.bytes.len();
+        }
+        if boundary != bytes_acknowledged.0 as usize {
+            return Err(Fail::Malformed {
+                details: "ACK isn't on segment boundary",
+            });
+        }
+        self.consecutive_timeouts.set(0);
 
         if ack_seq_no == sent_seq_no {
             // If we've acknowledged all sent data, turn off the retransmit timer.
@@ -187,62 +481,133 @@ impl<RT: Runtime> Sender<RT> {
             self.retransmit_deadline.set(Some(deadline));
         }
 
-        // TODO: Do acks need to be on segment boundaries? How does this interact with repacketization?
+        // RFC 7323 4.2: With timestamps, every ACK that acknowledges new data is a sample, even
+        // for a retransmission.
+        if let Some(rtt) = ts_rtt {
+            let mut rto = self.rto.borrow_mut();
+            rto.add_sample(rtt);
+            latency.record_srtt(rto.srtt());
+        }
+
         let mut bytes_remaining = bytes_acknowledged.0 as usize;
+        // Karn's algorithm: an ACK covering a retransmitted segment can't be timed, since it may
+        // answer either transmission. Otherwise the newest segment it covers gives one sample.
+        let mut retransmitted = false;
+        let mut newest_tx = None;
         while let Some(segment) = self.unacked_queue.borrow_mut().pop_front() {
-            if segment.bytes.len() > bytes_remaining {
-                // TODO: We need to close the connection in this case.
-                return Err(Fail::Ignored {
-                    details: "ACK isn't on segment boundary",
-                });
-            }
             bytes_remaining -= segment.bytes.len();
 
-            // Add sample for RTO if not a retransmission
-            // TODO: TCP timestamp support.
-            if let Some(initial_tx) = segment.initial_tx {
-                self.rto.borrow_mut().add_sample(now - initial_tx);
+            match segment.initial_tx {
+                Some(initial_tx) => newest_tx = Some(initial_tx),
+                None => retransmitted = true,
             }
             if bytes_remaining == 0 {
                 break;
             }
         }
+        if let (None, false, Some(initial_tx)) = (ts_rtt, retransmitted, newest_tx) {
+            let mut rto = self.rto.borrow_mut();
+            // `now` may be a device timestamp, so don't trust it to be after `initial_tx`.
+            rto.add_sample(now.saturating_duration_since(initial_tx));
+            latency.record_srtt(rto.srtt());
+        }
         self.base_seq_no.modify(|b| b + bytes_acknowledged);
 
-        Ok(())
+        let flight_size = sent_seq_no - ack_seq_no;
+        Ok(self
+            .congestion
+            .on_ack(ack_seq_no, bytes_acknowledged.0, flight_size.0))
+    }
+
+    /// Mark the unacknowledged segments that lie within `blocks` from a SACK option. Blocks that
+    /// only cover part of a segment don't count, since the whole segment would be resent anyway.
+    pub fn remote_sack(&self, blocks: &[SelectiveAcknowlegement]) {
+        if blocks.is_empty() {
+            return;
+        }
+        let mut seq_no = self.base_seq_no.get();
+        for segment in self.unacked_queue.borrow_mut().iter_mut() {
+            let len = Wrapping(segment.bytes.len() as u32);
+            if blocks
+                .iter()
+                .any(|b| seq_no - b.begin < b.end - b.begin && len <= b.end - seq_no)
+            {
+                segment.sacked = true;
+            }
+            seq_no += len;
+        }
+    }
+
+    /// Forget what the remote has selectively acknowledged, which RFC 2018 lets it renege on.
+    pub fn clear_sacked(&self) {
+        for segment in self.unacked_queue.borrow_mut().iter_mut() {
+            segment.sacked = false;
+        }
     }
 
-    pub fn pop_one_unsent_byte(&self) -> Option<RT::Buf> {
+    pub fn pop_one_unsent_byte(&self) -> Option<UnsentSegment<RT>> {
         let mut queue = self.unsent_queue.borrow_mut();
 
-        let buf = queue.front_mut()?;
-        let mut cloned_buf = buf.clone();
-        let buf_len = buf.len();
+        let segment = queue.front_mut()?;
+        let mut cloned_buf = segment.bytes.clone();
+        let buf_len = segment.bytes.len();
 
         // Pop one byte off the buf still in the queue and all but one of the bytes on our clone.
-        buf.adjust(1);
+        segment.bytes.adjust(1);
         cloned_buf.trim(buf_len - 1);
 
-        Some(cloned_buf)
+        Some(UnsentSegment {
+            bytes: cloned_buf,
+            enqueued: segment.enqueued,
+        })
     }
 
-    pub fn pop_unsent(&self, max_bytes: usize) -> Option<RT::Buf> {
-        // TODO: Use a scatter/gather array to coalesce multiple buffers into a single segment.
+    pub fn pop_unsent(&self, max_bytes: usize) -> Option<UnsentSegment<RT>> {
         let mut unsent_queue = self.unsent_queue.borrow_mut();
-        let mut buf = unsent_queue.pop_front()?;
-        let buf_len = buf.len();
+        let mut segment = unsent_queue.pop_front()?;
+        let buf_len = segment.bytes.len();
+
+        // Small writes that queued up, say while Nagle's algorithm held them, share a segment.
+        // TODO: Use a scatter/gather array rather than copying.
+        if buf_len < max_bytes && !unsent_queue.is_empty() {
+            let mut bytes = segment.bytes.to_vec();
+            while bytes.len() < max_bytes {
+                let next = match unsent_queue.front_mut() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let n = cmp::min(next.bytes.len(), max_bytes - bytes.len());
+                bytes.extend_from_slice(&next.bytes[..n]);
+                if n == next.bytes.len() {
+                    unsent_queue.pop_front();
+                } else {
+                    next.bytes.adjust(n);
+                }
+            }
+            segment.bytes = RT::Buf::from_slice(&bytes[..]);
+            return Some(segment);
+        }
 
         if buf_len > max_bytes {
-            let mut cloned_buf = buf.clone();
+            let mut cloned_buf = segment.bytes.clone();
 
-            buf.adjust(max_bytes);
+            segment.bytes.adjust(max_bytes);
             cloned_buf.trim(buf_len - max_bytes);
 
-            unsent_queue.push_front(buf);
-            buf = cloned_buf;
-
+            let enqueued = segment.enqueued;
+            unsent_queue.push_front(segment);
+            segment = UnsentSegment {
+                bytes: cloned_buf,
+                enqueued,
+            };
         }
-        Some(buf)
+        Some(segment)
+    }
+
+    /// Whether `window_size_hdr` advertises the window we already have.
+    pub fn window_unchanged(&self, window_size_hdr: u16) -> bool {
+        (window_size_hdr as u32).checked_shl(self.window_scale as u32)
+            == Some(self.window_size.get())
     }
 
     pub fn update_remote_window(&self, window_size_hdr: u16) -> Result<(), Fail> {
@@ -259,7 +624,7 @@ impl<RT: Runtime> Sender<RT> {
                 details: "Window size overflow",
             })?;
 
-        debug!(
+        trace!(
             "Updating window size -> {} (hdr {}, scale {})",
             window_size, window_size_hdr, self.window_scale
         );
@@ -269,7 +634,37 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn remote_mss(&self) -> usize {
-        self.mss
+        self.mss.get()
+    }
+
+    /// Lower the MSS to `mss`, though not below `MIN_MSS`, returning whether it went down.
+    /// Unacknowledged segments that no longer fit are split, so they're retransmitted at the new
+    /// size.
+    pub fn reduce_mss(&self, mss: usize) -> bool {
+        let mss = cmp::max(mss, MIN_MSS);
+        if mss >= self.mss.get() {
+            return false;
+        }
+        self.mss.set(mss);
+        self.congestion.set_mss(mss);
+        let mut unacked_queue = self.unacked_queue.borrow_mut();
+        let mut resegmented = VecDeque::with_capacity(unacked_queue.len());
+        for segment in unacked_queue.drain(..) {
+            let mut bytes = segment.bytes;
+            while bytes.len() > mss {
+                let mut head = bytes.clone();
+                head.trim(bytes.len() - mss);
+                bytes.adjust(mss);
+                resegmented.push_back(UnackedSegment {
+                    bytes: head,
+                    initial_tx: segment.initial_tx,
+                    sacked: segment.sacked,
+                });
+            }
+            resegmented.push_back(UnackedSegment { bytes, ..segment });
+        }
+        *unacked_queue = resegmented;
+        true
     }
 
     pub fn current_rto(&self) -> Duration {
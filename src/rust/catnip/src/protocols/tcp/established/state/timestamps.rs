@@ -0,0 +1,153 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! TCP timestamps (RFC 7323): every segment carries our clock and echoes the remote's, which gives
+//! an RTT sample from every ACK and lets old duplicates be told apart from new data once sequence
+//! numbers wrap (PAWS).
+
+use crate::{
+    protocols::tcp::segment::TcpOptions2,
+    snapshot::TimestampsSnapshot,
+};
+use std::{
+    cell::Cell,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Bytes the option takes from every segment, padding included.
+pub const TIMESTAMP_OPTION_SIZE: usize = 12;
+
+// RFC 7323 5.5: TS.Recent is forgotten after the connection's been idle this long, since the
+// remote's clock may have wrapped its sign bit since.
+const PAWS_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// Our timestamp clock, which ticks in milliseconds from a random start.
+#[derive(Clone, Copy, Debug)]
+pub struct TimestampClock {
+    base: Instant,
+    offset: u32,
+}
+
+impl TimestampClock {
+    pub fn new(now: Instant, offset: u32) -> Self {
+        Self { base: now, offset }
+    }
+
+    pub fn tsval(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.base).as_millis() as u32;
+        self.offset.wrapping_add(elapsed)
+    }
+
+    /// How long ago our clock read `tsecr`, unless that's in the future.
+    pub fn rtt(&self, tsecr: u32, now: Instant) -> Option<Duration> {
+        let elapsed = self.tsval(now).wrapping_sub(tsecr);
+        if (elapsed as i32) < 0 {
+            return None;
+        }
+        Some(Duration::from_millis(elapsed as u64))
+    }
+}
+
+/// A connection's timestamp state, once both sides have agreed to the option.
+#[derive(Debug)]
+pub struct Timestamps {
+    clock: TimestampClock,
+    // TS.Recent, the remote timestamp we echo, and when it was last updated.
+    recent: Cell<(u32, Instant)>,
+}
+
+impl Timestamps {
+    pub fn new(clock: TimestampClock, recent: u32, now: Instant) -> Self {
+        Self {
+            clock,
+            recent: Cell::new((recent, now)),
+        }
+    }
+
+    /// Our clock carries on from the snapshot's reading, so the remote never sees it go back.
+    pub fn restore(snapshot: &TimestampsSnapshot, now: Instant) -> Self {
+        Self::new(TimestampClock::new(now, snapshot.tsval), snapshot.recent, now)
+    }
+
+    pub fn snapshot(&self, now: Instant) -> TimestampsSnapshot {
+        TimestampsSnapshot {
+            tsval: self.clock.tsval(now),
+            recent: self.recent.get().0,
+        }
+    }
+
+    /// The option for a segment sent at `now`.
+    pub fn option(&self, now: Instant) -> TcpOptions2 {
+        TcpOptions2::Timestamp {
+            sender_timestamp: self.clock.tsval(now),
+            echo_timestamp: self.recent.get().0,
+        }
+    }
+
+    /// PAWS: whether a segment stamped `tsval` was sent before the last one we took TS.Recent
+    /// from, and so is an old duplicate.
+    pub fn is_stale(&self, tsval: u32, now: Instant) -> bool {
+        let (recent, updated) = self.recent.get();
+        if now.saturating_duration_since(updated) > PAWS_IDLE_TIMEOUT {
+            return false;
+        }
+        (tsval.wrapping_sub(recent) as i32) < 0
+    }
+
+    /// Take `tsval` as TS.Recent. The caller checks the segment covers the last ACK we sent, so
+    /// that with delayed ACKs we echo the oldest segment they acknowledge.
+    pub fn update_recent(&self, tsval: u32, now: Instant) {
+        let (recent, _) = self.recent.get();
+        if (tsval.wrapping_sub(recent) as i32) >= 0 {
+            self.recent.set((tsval, now));
+        }
+    }
+
+    pub fn rtt(&self, tsecr: u32, now: Instant) -> Option<Duration> {
+        self.clock.rtt(tsecr, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        TimestampClock,
+        Timestamps,
+    };
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    #[test]
+    fn test_paws() {
+        let now = Instant::now();
+        let ts = Timestamps::new(TimestampClock::new(now, 0), u32::max_value() - 1, now);
+        // Comparisons wrap with the remote's clock.
+        assert!(!ts.is_stale(3, now));
+        ts.update_recent(3, now);
+        assert!(ts.is_stale(u32::max_value(), now));
+        assert!(!ts.is_stale(3, now));
+
+        // An older timestamp doesn't move TS.Recent back.
+        ts.update_recent(2, now);
+        assert!(ts.is_stale(2, now));
+
+        // After a long enough idle, anything goes.
+        let later = now + Duration::from_secs(25 * 24 * 60 * 60);
+        assert!(!ts.is_stale(2, later));
+    }
+
+    #[test]
+    fn test_rtt() {
+        let now = Instant::now();
+        let clock = TimestampClock::new(now, u32::max_value() - 5);
+        let tsecr = clock.tsval(now);
+        let later = now + Duration::from_millis(20);
+        assert_eq!(clock.rtt(tsecr, later), Some(Duration::from_millis(20)));
+        assert_eq!(clock.rtt(clock.tsval(later) + 1, later), None);
+    }
+}
@@ -0,0 +1,26 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+/// The units a connection's arriving data is handed to the application in, set with
+/// `Peer::set_framing`. Outside of `Stream`, each `pop` returns exactly one record, so simple
+/// request/response protocols don't need to re-frame what they read. `peek_at` and `consume`
+/// still see the raw byte stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Framing {
+    /// Whatever has arrived, in the segments it arrived in.
+    Stream,
+    /// Up to the end of each segment the sender marked with PSH, which this engine's TCP sets
+    /// on the last segment of what was queued. When no boundary has arrived but the receive window
+    /// is full, or the remote has closed, what's there is delivered as it is. Can't be combined
+    /// with a stream transform, which hides where segments ended.
+    Push,
+    /// Messages each preceded by their length, a big-endian integer of `prefix_len` bytes (1 to
+    /// 4), which is stripped. A message longer than `max_len` fails the read.
+    LengthPrefixed { prefix_len: usize, max_len: usize },
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::Stream
+    }
+}
@@ -0,0 +1,42 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Callbacks run as TCP connections are established and torn down, so layers built on the peer
+//! can set up and release per-connection state, such as stats, a TLS context or an application
+//! session, without polling for new descriptors.
+//!
+//! Hooks registered with `Peer::add_connection_hooks` see every connection from then on. They run
+//! while the peer is in the middle of handling the connection, so they mustn't call back into it;
+//! anything that needs the peer should be queued for later.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::{
+        ipv4,
+        tcp::peer::TcpState,
+    },
+};
+
+/// The connection a hook is called for. `id` numbers connections in the order the peer
+/// established them and, unlike the descriptor, is never reused.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    pub fd: FileDescriptor,
+    pub id: u64,
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+}
+
+pub trait ConnectionHooks {
+    /// Called once the connection is established, whether it was accepted, connected or restored
+    /// from a snapshot, before the application gets its descriptor.
+    fn on_open(&self, _conn: &ConnectionInfo) {}
+
+    /// Called once as the connection is torn down, shutdown included, with the state it was in,
+    /// as in `Event::TcpClosed`.
+    fn on_close(&self, _conn: &ConnectionInfo, _state: TcpState) {}
+}
+
+/// Registered hooks, for `Peer::remove_connection_hooks`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct HooksId(pub(super) u64);
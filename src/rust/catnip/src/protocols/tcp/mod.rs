@@ -1,12 +1,18 @@
 mod active_open;
 pub mod constants;
 mod established;
+mod framing;
+pub mod hooks;
 mod isn_generator;
 pub mod operations;
 mod options;
 mod passive_open;
 pub mod peer;
 pub mod segment;
+mod shard;
+mod syn_cookies;
+mod traffic;
+mod transform;
 
 #[cfg(test)]
 mod tests;
@@ -16,6 +22,40 @@ use std::num::Wrapping;
 pub type SeqNumber = Wrapping<u32>;
 
 pub use self::{
-    options::TcpOptions as Options,
-    peer::Peer,
+    established::state::{
+        congestion::TcpCongestion as Congestion,
+        receiver::ReceiverState,
+        sender::{
+            SenderState,
+            TcpSendStatus as SendStatus,
+            TcpSendWindow as SendWindow,
+        },
+    },
+    framing::Framing,
+    options::{
+        RstPolicy,
+        SynCookiePolicy,
+        TcpMissingOptions as MissingOptions,
+        TcpNegotiated as Negotiated,
+        TcpOptions as Options,
+    },
+    peer::{
+        GroupId,
+        Peer,
+        TcpState as State,
+    },
+    shard::{
+        shard_of,
+        steer,
+        Shard,
+    },
+    traffic::{
+        TcpTraffic as Traffic,
+        TrafficPattern,
+        TrafficStats,
+    },
+    transform::{
+        StreamTransform,
+        TransformFactory,
+    },
 };
@@ -1,23 +1,151 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
-use crate::protocols::tcp::constants::{
-    DEFAULT_MSS,
-    MAX_MSS,
-    MIN_MSS,
+use crate::{
+    logging::LogSampling,
+    protocols::{
+        ip::port::FIRST_PRIVATE_PORT,
+        tcp::{
+            constants::{
+                DEFAULT_MSS,
+                MAX_DELAYED_ACK_TIMEOUT,
+                MAX_MSS,
+                MAX_WINDOW_SCALE,
+                MAX_WINDOW_SIZE,
+                MIN_MSS,
+            },
+            shard::Shard,
+        },
+    },
+};
+use std::{
+    cmp,
+    ops::RangeInclusive,
+    time::Duration,
 };
-use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct TcpOptions {
     pub advertised_mss: usize,
     pub handshake_retries: usize,
     pub handshake_timeout: Duration,
-    pub receive_window_size: u16,
+    /// How much data we'll accept ahead of the application, in bytes. More than 64KB can only be
+    /// advertised if the remote agrees to window scaling (RFC 7323); otherwise the window stops
+    /// there.
+    pub receive_window_size: u32,
+    /// Most out-of-order data held per connection, in bytes, waiting for the hole before it to be
+    /// filled. Past this, what's furthest ahead is dropped for the remote to resend.
+    pub reassembly_limit: usize,
     pub retries: usize,
+    /// Bounds on the retransmission timeout (RFC 6298). RFC 6298 suggests at least a second for
+    /// the lower one, which is far above datacenter RTTs.
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Failed ARP queries in a row an established connection rides out, waiting for the remote to
+    /// become reachable again, before it's aborted. Zero aborts on the first.
+    pub soft_error_limit: usize,
+    /// Retransmission timeouts in a row after which a connection suspects a PMTU black hole, a
+    /// path that drops segments its size without a fragmentation needed error, and halves its
+    /// MSS, again on each further timeout, down to `MIN_MSS`. Zero never does.
+    pub blackhole_timeouts: usize,
+    /// Maximum segment lifetime. Connections we close first linger in TIME_WAIT for twice this
+    /// before their port is reused.
+    pub msl: Duration,
     pub trailing_ack_delay: Duration,
+    /// How long received data waits for an outgoing segment to carry its ACK before one is sent
+    /// on its own. RFC 1122 4.2.3.2 caps this at 500ms.
+    pub delayed_ack_timeout: Duration,
+    /// Delayed ACK and retransmit deadlines are rounded up to a multiple of this, so connections'
+    /// timers expire together and rescheduling within a bucket is free. Zero keeps exact deadlines.
+    pub timer_granularity: Duration,
+    /// The window scale we offer, which is raised if `receive_window_size` needs more.
     pub window_scale: u8,
+    /// Offer and accept selective acknowledgments (RFC 2018), so a loss costs only the missing
+    /// segments rather than everything sent after them.
+    pub sack: bool,
+    /// Offer and accept timestamps (RFC 7323), which give an RTT sample from every ACK and
+    /// protect long-lived connections against old segments once sequence numbers wrap (PAWS).
+    pub timestamps: bool,
+    /// Hold back small segments while there's unacknowledged data, so small writes are coalesced
+    /// (Nagle's algorithm, RFC 896). Connections can opt out with `Peer::set_nodelay`.
+    pub nagle: bool,
+    /// Most data a connection holds for sending, queued or unacknowledged, in bytes, before
+    /// writes push back. Zero means no limit.
+    pub send_buffer_size: usize,
     pub rx_checksum_offload: bool,
     pub tx_checksum_offload: bool,
+    /// Local ports handed out to active opens.
+    pub ephemeral_ports: RangeInclusive<u16>,
+    /// Thinning applied to per-segment debug logging, per connection.
+    pub log_sampling: LogSampling,
+    /// Only accept connections: `connect` fails, and segments that match no listener or
+    /// connection are dropped instead of answered with a RST.
+    pub listen_only: bool,
+    /// Answer SYNs that find a listener's backlog full with a RST, so the remote's connect fails
+    /// right away, instead of dropping them for the remote to retry.
+    pub reset_on_backlog_overflow: bool,
+    /// Whether segments for ports nothing is listening on get a RST back.
+    pub closed_port_rst: RstPolicy,
+    /// When listeners answer SYNs with SYN cookies, keeping no state until the handshake
+    /// completes, so a SYN flood can't fill the backlog.
+    pub syn_cookies: SynCookiePolicy,
+    /// This engine's share of connections when several engines serve the same address. Segments
+    /// of connections owned by other shards are ignored.
+    pub shard: Option<Shard>,
+    /// How long `connect_any` waits on one attempt before starting the next (RFC 8305's
+    /// Connection Attempt Delay).
+    pub connect_attempt_delay: Duration,
+    /// Pass over options we don't know or can't parse instead of dropping the segment, counting
+    /// each one by kind in the engine's stats.
+    pub strict_option_parsing: bool,
+}
+
+/// How TCP answers segments for ports nothing is listening on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RstPolicy {
+    /// With a RST each time, as RFC 793 says.
+    Always,
+    /// With at most this many RSTs a second, dropping the rest, so a port scan can't make the
+    /// engine send as much as it receives.
+    RateLimited { per_second: u32 },
+    /// Never: they're dropped, so closed ports look filtered.
+    Never,
+}
+
+/// When a listener answers a SYN with a SYN cookie (RFC 4987) instead of starting a handshake.
+/// Connections opened from a cookie go without window scaling, SACK and timestamps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SynCookiePolicy {
+    Never,
+    /// Only once the backlog is full, which would otherwise drop or refuse the SYN.
+    OnOverflow,
+    Always,
+}
+
+/// What a connection's handshake actually settled on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpNegotiated {
+    /// The largest segment we send, from the remote's MSS option (or the fallback without one),
+    /// lowered if the path turns out to carry less.
+    pub mss: usize,
+    /// Shift applied to windows the remote advertises. Zero unless both sides sent the option.
+    pub send_window_scale: u8,
+    /// Shift applied to windows we advertise.
+    pub receive_window_scale: u8,
+    pub sack_permitted: bool,
+    pub timestamps: bool,
+    pub ecn: bool,
+    /// Options we asked for that the remote's SYN or SYN+ACK didn't carry.
+    pub missing: TcpMissingOptions,
+}
+
+/// Which of the options we expected in the remote's half of the handshake were left out. SACK
+/// and timestamps only count if we offered them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TcpMissingOptions {
+    pub mss: bool,
+    pub window_scale: bool,
+    pub sack: bool,
+    pub timestamps: bool,
 }
 
 impl Default for TcpOptions {
@@ -27,11 +155,32 @@ impl Default for TcpOptions {
             handshake_retries: 5,
             handshake_timeout: Duration::from_secs(3),
             receive_window_size: 0xffff,
+            reassembly_limit: 0xffff,
             retries: 5,
+            min_rto: Duration::from_millis(100),
+            max_rto: Duration::from_secs(60),
+            soft_error_limit: 3,
+            blackhole_timeouts: 0,
+            msl: Duration::from_secs(30),
             trailing_ack_delay: Duration::from_micros(1),
+            delayed_ack_timeout: MAX_DELAYED_ACK_TIMEOUT,
+            timer_granularity: Duration::from_secs(0),
             window_scale: 0,
+            sack: false,
+            timestamps: false,
+            nagle: false,
+            send_buffer_size: 0,
             rx_checksum_offload: false,
             tx_checksum_offload: false,
+            ephemeral_ports: FIRST_PRIVATE_PORT..=65535,
+            log_sampling: LogSampling::default(),
+            listen_only: false,
+            reset_on_backlog_overflow: false,
+            closed_port_rst: RstPolicy::Always,
+            syn_cookies: SynCookiePolicy::Never,
+            shard: None,
+            connect_attempt_delay: Duration::from_millis(250),
+            strict_option_parsing: false,
         }
     }
 }
@@ -56,20 +205,156 @@ impl TcpOptions {
         self
     }
 
-    pub fn receive_window_size(mut self, value: u16) -> Self {
-        assert!(value > 0);
+    pub fn receive_window_size(mut self, value: u32) -> Self {
+        assert!(value > 0 && value <= MAX_WINDOW_SIZE);
         self.receive_window_size = value;
         self
     }
 
+    pub fn reassembly_limit(mut self, value: usize) -> Self {
+        self.reassembly_limit = value;
+        self
+    }
+
     pub fn retries(mut self, value: usize) -> Self {
         assert!(value > 0);
         self.retries = value;
         self
     }
 
+    pub fn min_rto(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        assert!(value <= self.max_rto);
+        self.min_rto = value;
+        self
+    }
+
+    pub fn max_rto(mut self, value: Duration) -> Self {
+        assert!(value >= self.min_rto);
+        self.max_rto = value;
+        self
+    }
+
+    pub fn soft_error_limit(mut self, value: usize) -> Self {
+        self.soft_error_limit = value;
+        self
+    }
+
+    pub fn blackhole_timeouts(mut self, value: usize) -> Self {
+        self.blackhole_timeouts = value;
+        self
+    }
+
+    pub fn msl(mut self, value: Duration) -> Self {
+        self.msl = value;
+        self
+    }
+
     pub fn trailing_ack_delay(mut self, value: Duration) -> Self {
         self.trailing_ack_delay = value;
         self
     }
+
+    pub fn delayed_ack_timeout(mut self, value: Duration) -> Self {
+        assert!(value <= MAX_DELAYED_ACK_TIMEOUT);
+        self.delayed_ack_timeout = value;
+        self
+    }
+
+    pub fn timer_granularity(mut self, value: Duration) -> Self {
+        self.timer_granularity = value;
+        self
+    }
+
+    pub fn window_scale(mut self, value: u8) -> Self {
+        assert!(value <= 14);
+        self.window_scale = value;
+        self
+    }
+
+    pub fn sack(mut self, value: bool) -> Self {
+        self.sack = value;
+        self
+    }
+
+    pub fn timestamps(mut self, value: bool) -> Self {
+        self.timestamps = value;
+        self
+    }
+
+    pub fn nagle(mut self, value: bool) -> Self {
+        self.nagle = value;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, value: usize) -> Self {
+        self.send_buffer_size = value;
+        self
+    }
+
+    pub fn ephemeral_ports(mut self, value: RangeInclusive<u16>) -> Self {
+        assert!(*value.start() >= FIRST_PRIVATE_PORT);
+        assert!(value.start() <= value.end());
+        self.ephemeral_ports = value;
+        self
+    }
+
+    pub fn log_sampling(mut self, value: LogSampling) -> Self {
+        assert!(value.every > 0);
+        self.log_sampling = value;
+        self
+    }
+
+    pub fn listen_only(mut self, value: bool) -> Self {
+        self.listen_only = value;
+        self
+    }
+
+    pub fn reset_on_backlog_overflow(mut self, value: bool) -> Self {
+        self.reset_on_backlog_overflow = value;
+        self
+    }
+
+    pub fn closed_port_rst(mut self, value: RstPolicy) -> Self {
+        if let RstPolicy::RateLimited { per_second } = value {
+            assert!(per_second > 0);
+        }
+        self.closed_port_rst = value;
+        self
+    }
+
+    pub fn syn_cookies(mut self, value: SynCookiePolicy) -> Self {
+        self.syn_cookies = value;
+        self
+    }
+
+    pub fn shard(mut self, value: Shard) -> Self {
+        self.shard = Some(value);
+        self
+    }
+
+    pub fn connect_attempt_delay(mut self, value: Duration) -> Self {
+        self.connect_attempt_delay = value;
+        self
+    }
+
+    pub fn strict_option_parsing(mut self, value: bool) -> Self {
+        self.strict_option_parsing = value;
+        self
+    }
+
+    /// The window scale to offer: `window_scale`, or the smallest that can advertise the whole
+    /// receive window if that's larger.
+    pub fn local_window_scale(&self) -> u8 {
+        let mut scale = self.window_scale;
+        while scale < MAX_WINDOW_SCALE && self.receive_window_size >> scale > 0xffff {
+            scale += 1;
+        }
+        scale
+    }
+
+    /// The receive window if the remote doesn't agree to scaling, which is also what SYNs carry.
+    pub fn unscaled_receive_window(&self) -> u16 {
+        cmp::min(self.receive_window_size, u16::max_value() as u32) as u16
+    }
 }
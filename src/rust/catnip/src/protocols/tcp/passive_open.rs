@@ -1,46 +1,67 @@
 use super::{
-    constants::FALLBACK_MSS,
+    constants::{
+        FALLBACK_MSS,
+        MAX_WINDOW_SCALE,
+        MIN_MSS,
+    },
     established::state::{
+        connection_log,
         receiver::Receiver,
         sender::Sender,
+        timestamps::{
+            TimestampClock,
+            Timestamps,
+            TIMESTAMP_OPTION_SIZE,
+        },
         ControlBlock,
     },
     isn_generator::IsnGenerator,
+    syn_cookies::SynCookieGenerator,
 };
 use crate::{
     fail::Fail,
     runtime::RuntimeBuf,
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
-        },
         ipv4,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
+        ipv4::{
+            datagram::Ipv4Header,
+            Egress,
         },
+        packet::PacketBuilder,
         tcp::{
+            options::{
+                SynCookiePolicy,
+                TcpMissingOptions,
+                TcpOptions,
+            },
             segment::{
                 TcpHeader,
                 TcpOptions2,
-                TcpSegment,
             },
             SeqNumber,
         },
     },
     runtime::Runtime,
     scheduler::SchedulerHandle,
+    stats::{
+        TcpLatencyRecorder,
+        TcpLatencyStats,
+        TcpListenerStats,
+        TcpThroughputRecorder,
+    },
 };
 use std::collections::{
     HashMap,
     HashSet,
 };
 use std::{
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    cmp,
     collections::VecDeque,
-    convert::TryInto,
     future::Future,
     num::Wrapping,
     rc::Rc,
@@ -55,9 +76,12 @@ use std::{
 struct InflightAccept {
     local_isn: SeqNumber,
     remote_isn: SeqNumber,
-    header_window_size: u16,
     remote_window_scale: Option<u8>,
     mss: usize,
+    sack_permitted: bool,
+    // Our timestamp clock and the SYN's timestamp, if both sides want them.
+    timestamps: Option<(TimestampClock, u32)>,
+    missing_options: TcpMissingOptions,
 
     #[allow(unused)]
     handle: SchedulerHandle,
@@ -105,15 +129,28 @@ pub struct PassiveSocket<RT: Runtime> {
     ready: Rc<RefCell<ReadySockets<RT>>>,
 
     max_backlog: usize,
+    // The options in force when `listen` was called, which its handshakes go by.
+    options: TcpOptions,
     isn_generator: IsnGenerator,
+    syn_cookies: SynCookieGenerator,
 
     local: ipv4::Endpoint,
     rt: RT,
     arp: arp::Peer<RT>,
+    egress: Egress<RT>,
+    latency: Rc<RefCell<TcpLatencyStats>>,
+    stats: Rc<RefCell<TcpListenerStats>>,
 }
 
 impl<RT: Runtime> PassiveSocket<RT> {
-    pub fn new(local: ipv4::Endpoint, max_backlog: usize, rt: RT, arp: arp::Peer<RT>) -> Self {
+    pub fn new(
+        local: ipv4::Endpoint,
+        max_backlog: usize,
+        rt: RT,
+        arp: arp::Peer<RT>,
+        egress: Egress<RT>,
+        latency: Rc<RefCell<TcpLatencyStats>>,
+    ) -> Self {
         let ready = ReadySockets {
             ready: VecDeque::new(),
             endpoints: HashSet::new(),
@@ -125,18 +162,71 @@ impl<RT: Runtime> PassiveSocket<RT> {
             inflight: HashMap::new(),
             ready,
             max_backlog,
+            options: rt.tcp_options(),
             isn_generator: IsnGenerator::new(nonce),
+            syn_cookies: SynCookieGenerator::new(rt.rng_gen(), rt.now()),
             local,
             rt,
             arp,
+            egress,
+            latency,
+            stats: Rc::new(RefCell::new(TcpListenerStats::new(local))),
         }
     }
 
+    pub fn backlog(&self) -> usize {
+        self.max_backlog
+    }
+
+    /// Reset every connection the listener hasn't handed to the application: handshakes still
+    /// in progress, whose background work is cancelled with them, and those waiting to be
+    /// accepted.
+    pub fn close(&mut self) {
+        let inflight: Vec<_> = self.inflight.drain().collect();
+        for (remote, accept) in inflight {
+            let seq_num = accept.local_isn + Wrapping(1);
+            let ack_num = accept.remote_isn + Wrapping(1);
+            if let Err(e) = self.send_rst(remote, seq_num, ack_num) {
+                warn!("Dropping RST for {:?}: {:?}", remote, e);
+            }
+        }
+        let mut ready = self.ready.borrow_mut();
+        for cb in ready.ready.drain(..).filter_map(Result::ok) {
+            cb.abort();
+        }
+        ready.endpoints.clear();
+        ready.waker.take().map(|w| w.wake());
+    }
+
+    pub fn stats(&self) -> TcpListenerStats {
+        let mut stats = *self.stats.borrow();
+        stats.inflight = self.inflight.len();
+        // Failed handshakes queue up for `accept` too, but only to report their error.
+        stats.ready = self.ready.borrow().endpoints.len();
+        stats
+    }
+
     pub fn poll_accept(&mut self, ctx: &mut Context) -> Poll<Result<ControlBlock<RT>, Fail>> {
-        self.ready.borrow_mut().poll(ctx)
+        let r = self.ready.borrow_mut().poll(ctx);
+        if let Poll::Ready(Ok(..)) = r {
+            self.stats.borrow_mut().accepted += 1;
+        }
+        r
     }
 
     pub fn receive(&mut self, ip_header: &Ipv4Header, header: &TcpHeader) -> Result<(), Fail> {
+        let r = self.receive_segment(ip_header, header);
+        if let Err(ref e) = r {
+            let mut stats = self.stats.borrow_mut();
+            match e {
+                Fail::ConnectionRefused {} => stats.backlog_overflows += 1,
+                _ => stats.refused += 1,
+            }
+        }
+        r
+    }
+
+    fn receive_segment(&mut self, ip_header: &Ipv4Header, header: &TcpHeader) -> Result<(), Fail> {
         let remote = ipv4::Endpoint::new(ip_header.src_addr, header.src_port);
         if self.ready.borrow().endpoints.contains(&remote) {
             // TODO: What should we do if a packet shows up for a connection that hasn't been
@@ -152,14 +242,16 @@ impl<RT: Runtime> PassiveSocket<RT> {
                     details: "Expected ACK",
                 });
             }
-            debug!("Received ACK: {:?}", header);
+            debug!("Received ACK: {}", header);
             // TODO: Add entry API.
             let &InflightAccept {
                 local_isn,
                 remote_isn,
-                header_window_size,
                 remote_window_scale,
                 mss,
+                sack_permitted,
+                timestamps,
+                missing_options,
                 ..
             } = self.inflight.get(&remote).unwrap();
             if header.ack_num != local_isn + Wrapping(1) {
@@ -167,50 +259,56 @@ impl<RT: Runtime> PassiveSocket<RT> {
                     details: "Invalid SYN+ACK seq num",
                 });
             }
-
-            let tcp_options = self.rt.tcp_options();
-            let (local_window_scale, remote_window_scale) = match remote_window_scale {
-                Some(w) => (tcp_options.window_scale as u32, w),
-                None => (0, 0),
-            };
-            let remote_window_size = (header_window_size as u32)
-                .checked_shl(remote_window_scale as u32)
-                .expect("TODO: Window size overflow")
-                .try_into()
-                .expect("TODO: Window size overflow");
-            let local_window_size = (tcp_options.receive_window_size as u32)
-                .checked_shl(local_window_scale as u32)
-                .expect("TODO: Window size overflow");
-            info!(
-                "Window sizes: local {}, remote {}",
-                local_window_size, remote_window_size
-            );
-            info!(
-                "Window scale: local {}, remote {}",
-                local_window_scale, remote_window_scale
-            );
-
-            let sender = Sender::new(
-                local_isn + Wrapping(1),
-                remote_window_size,
+            self.inflight.remove(&remote);
+            self.establish(
+                remote,
+                header,
+                local_isn,
+                remote_isn,
                 remote_window_scale,
                 mss,
+                sack_permitted,
+                timestamps,
+                missing_options,
             );
-            let receiver = Receiver::new(
-                remote_isn + Wrapping(1),
-                local_window_size,
-                local_window_scale,
-            );
-            self.inflight.remove(&remote);
-            let cb = ControlBlock {
-                local: self.local.clone(),
-                remote: remote.clone(),
-                rt: self.rt.clone(),
-                arp: self.arp.clone(),
-                sender,
-                receiver,
+            return Ok(());
+        }
+
+        let syn_cookies = self.options.syn_cookies;
+        // An ACK that isn't for a handshake in progress may complete one we answered with a cookie.
+        if syn_cookies != SynCookiePolicy::Never && header.ack && !header.syn && !header.rst {
+            let local_isn = header.ack_num - Wrapping(1);
+            let remote_isn = header.seq_num - Wrapping(1);
+            let now = self.rt.now();
+            let mss = match self
+                .syn_cookies
+                .validate(&self.local, &remote, remote_isn, local_isn, now)
+            {
+                Some(mss) => mss,
+                None => {
+                    return Err(Fail::Malformed {
+                        details: "Invalid SYN cookie",
+                    })
+                },
             };
-            self.ready.borrow_mut().push_ok(cb);
+            if self.ready.borrow().len() >= self.max_backlog {
+                return Err(Fail::ConnectionRefused {});
+            }
+            debug!("Received ACK for SYN cookie: {}", header);
+            self.stats.borrow_mut().cookies_validated += 1;
+            // The cookie doesn't say which options the SYN carried.
+            let missing_options = TcpMissingOptions::default();
+            self.establish(
+                remote,
+                header,
+                local_isn,
+                remote_isn,
+                None,
+                mss,
+                false,
+                None,
+                missing_options,
+            );
             return Ok(());
         }
 
@@ -220,61 +318,263 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 details: "Invalid flags",
             });
         }
-        debug!("Received SYN: {:?}", header);
-        if inflight_len + self.ready.borrow().len() >= self.max_backlog {
-            // TODO: Should we send a RST here?
+        debug!("Received SYN: {}", header);
+        self.stats.borrow_mut().syns_received += 1;
+        let ready_len = self.ready.borrow().len();
+        let backlog_full = inflight_len + ready_len >= self.max_backlog;
+        // Cookies make room for handshakes, but not for connections waiting to be accepted.
+        let use_cookie = ready_len < self.max_backlog
+            && match syn_cookies {
+                SynCookiePolicy::Never => false,
+                SynCookiePolicy::OnOverflow => backlog_full,
+                SynCookiePolicy::Always => true,
+            };
+        if backlog_full && !use_cookie {
+            // The caller answers with a RST if `reset_on_backlog_overflow` is set.
             return Err(Fail::ConnectionRefused {});
         }
-        let local_isn = self.isn_generator.generate(&self.local, &remote);
         let remote_isn = header.seq_num;
-        let future = Self::background(
-            local_isn,
-            remote_isn,
-            self.local,
-            remote.clone(),
-            self.rt.clone(),
-            self.arp.clone(),
-            self.ready.clone(),
-        );
-        let handle = self.rt.spawn(future);
 
         let mut remote_window_scale = None;
         let mut mss = FALLBACK_MSS;
+        let mut remote_mss = false;
+        let mut sack_permitted = false;
+        let mut remote_tsval = None;
         for option in header.iter_options() {
             match option {
                 TcpOptions2::WindowScale(w) => {
                     info!("Received window scale: {:?}", w);
-                    remote_window_scale = Some(*w);
+                    remote_window_scale = Some(cmp::min(*w, MAX_WINDOW_SCALE));
                 },
                 TcpOptions2::MaximumSegmentSize(m) => {
                     info!("Received advertised MSS: {}", m);
-                    mss = *m as usize;
+                    remote_mss = true;
+                    // Clamped to what we advertise, which is sized for our own link.
+                    let advertised_mss = self.options.advertised_mss;
+                    mss = cmp::max(cmp::min(*m as usize, advertised_mss), MIN_MSS);
+                },
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    sack_permitted = self.options.sack;
+                },
+                TcpOptions2::Timestamp {
+                    sender_timestamp, ..
+                } if self.options.timestamps => {
+                    remote_tsval = Some(*sender_timestamp);
                 },
                 _ => continue,
             }
         }
+        let missing_options = TcpMissingOptions {
+            mss: !remote_mss,
+            window_scale: remote_window_scale.is_none(),
+            sack: self.options.sack && !sack_permitted,
+            timestamps: self.options.timestamps && remote_tsval.is_none(),
+        };
+        if use_cookie {
+            let cookie = self
+                .syn_cookies
+                .generate(&self.local, &remote, remote_isn, mss, self.rt.now());
+            return self.send_syn_cookie(remote, cookie, remote_isn);
+        }
+        let local_isn = self.isn_generator.generate(&self.local, &remote);
+        let timestamps = remote_tsval.map(|tsval| {
+            let clock = TimestampClock::new(self.rt.now(), self.rt.rng_gen());
+            (clock, tsval)
+        });
+        let future = Self::background(
+            local_isn,
+            remote_isn,
+            remote_window_scale.is_some(),
+            sack_permitted,
+            timestamps,
+            self.local,
+            remote.clone(),
+            self.options.clone(),
+            self.rt.clone(),
+            self.arp.clone(),
+            self.egress.clone(),
+            self.ready.clone(),
+            self.stats.clone(),
+        );
+        let handle = self.rt.spawn(future);
         let accept = InflightAccept {
             local_isn,
             remote_isn,
-            header_window_size: header.window_size,
             remote_window_scale,
             mss,
+            sack_permitted,
+            timestamps,
+            missing_options,
             handle,
         };
         self.inflight.insert(remote, accept);
         Ok(())
     }
 
+    // Queue the connection a handshake completed for `accept`.
+    fn establish(
+        &mut self,
+        remote: ipv4::Endpoint,
+        header: &TcpHeader,
+        local_isn: SeqNumber,
+        remote_isn: SeqNumber,
+        remote_window_scale: Option<u8>,
+        mss: usize,
+        sack_permitted: bool,
+        timestamps: Option<(TimestampClock, u32)>,
+        missing_options: TcpMissingOptions,
+    ) {
+        let tcp_options = &self.options;
+        // Without the remote's agreement, neither side scales and our window stops at 64KB.
+        let (local_window_scale, remote_window_scale, local_window_size) =
+            match remote_window_scale {
+                Some(w) => (
+                    tcp_options.local_window_scale() as u32,
+                    w,
+                    tcp_options.receive_window_size,
+                ),
+                None => (0, 0, tcp_options.unscaled_receive_window() as u32),
+            };
+        // The SYN's window wasn't scaled, but this ACK's is. With the scale at most 14, the
+        // shift can't overflow.
+        let remote_window_size = (header.window_size as u32) << remote_window_scale;
+        info!(
+            "Window sizes: local {}, remote {}",
+            local_window_size, remote_window_size
+        );
+        info!(
+            "Window scale: local {}, remote {}",
+            local_window_scale, remote_window_scale
+        );
+
+        let now = self.rt.now();
+        let remote_timestamp = header.timestamp();
+        let timestamps = timestamps.map(|(clock, syn_tsval)| {
+            let recent = remote_timestamp.map(|(tsval, _)| tsval).unwrap_or(syn_tsval);
+            Timestamps::new(clock, recent, now)
+        });
+        // The MSS doesn't allow for options, so make room for the timestamp in every segment.
+        let mss = match timestamps {
+            Some(..) => mss - TIMESTAMP_OPTION_SIZE,
+            None => mss,
+        };
+        let sender = Sender::new(
+            local_isn + Wrapping(1),
+            remote_window_size,
+            remote_window_scale,
+            mss,
+            sack_permitted,
+            self.options.nagle,
+            self.options.send_buffer_size,
+            self.options.min_rto,
+            self.options.max_rto,
+        );
+        // The ACK's echo of our SYN+ACK's timestamp gives a first RTT sample.
+        if let (Some(timestamps), Some((_, tsecr))) = (&timestamps, remote_timestamp) {
+            if let Some(rtt) = timestamps.rtt(tsecr, now) {
+                sender.rto.borrow_mut().add_sample(rtt);
+            }
+        }
+        let receiver = Receiver::new(
+            remote_isn + Wrapping(1),
+            local_window_size,
+            local_window_scale,
+            self.options.reassembly_limit,
+        );
+        let cb = ControlBlock {
+            local: self.local.clone(),
+            remote: remote.clone(),
+            rt: self.rt.clone(),
+            arp: self.arp.clone(),
+            egress: self.egress.clone(),
+            sender,
+            receiver,
+            latency: TcpLatencyRecorder::new(self.latency.clone()),
+            throughput: TcpThroughputRecorder::new(self.rt.now()),
+            log: connection_log(&self.rt, self.local, remote),
+            ack_template: RefCell::new(None),
+            timestamps,
+            soft_errors: Cell::new(0),
+            missing_options,
+        };
+        self.ready.borrow_mut().push_ok(cb);
+        self.stats.borrow_mut().handshakes_completed += 1;
+    }
+
+    // Answer a SYN with a SYN+ACK carrying `cookie`. It's sent once, leaving retransmission to
+    // the remote's SYN, and only offers an MSS since the cookie can't hold other options.
+    fn send_syn_cookie(
+        &self,
+        remote: ipv4::Endpoint,
+        cookie: SeqNumber,
+        remote_isn: SeqNumber,
+    ) -> Result<(), Fail> {
+        // Like RSTs, these don't wait on ARP, so they don't tie up state either.
+        let remote_link_addr =
+            self.arp
+                .try_query(remote.addr)
+                .ok_or_else(|| Fail::ResourceNotFound {
+                    details: "SYN cookie destination not in ARP cache",
+                })?;
+        let tcp_options = &self.options;
+        let mut tcp_hdr = TcpHeader::new(self.local.port, remote.port);
+        tcp_hdr.syn = true;
+        tcp_hdr.seq_num = cookie;
+        tcp_hdr.ack = true;
+        tcp_hdr.ack_num = remote_isn + Wrapping(1);
+        tcp_hdr.window_size = tcp_options.unscaled_receive_window();
+        tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(
+            tcp_options.advertised_mss as u16,
+        ));
+        debug!("Sending SYN+ACK with cookie: {}", tcp_hdr);
+        let segment = PacketBuilder::new(&self.rt, remote_link_addr, remote.addr)
+            .src_addr(self.local.addr)
+            .tcp(tcp_hdr, RT::Buf::empty(), tcp_options.tx_checksum_offload);
+        self.egress.transmit(segment);
+        Ok(())
+    }
+
+    // Reset a handshake we answered, in the window of the SYN+ACK the remote may have had.
+    fn send_rst(
+        &self,
+        remote: ipv4::Endpoint,
+        seq_num: SeqNumber,
+        ack_num: SeqNumber,
+    ) -> Result<(), Fail> {
+        let remote_link_addr =
+            self.arp
+                .try_query(remote.addr)
+                .ok_or_else(|| Fail::ResourceNotFound {
+                    details: "RST destination not in ARP cache",
+                })?;
+        let mut tcp_hdr = TcpHeader::new(self.local.port, remote.port);
+        tcp_hdr.rst = true;
+        tcp_hdr.seq_num = seq_num;
+        tcp_hdr.ack = true;
+        tcp_hdr.ack_num = ack_num;
+        debug!("Sending RST: {}", tcp_hdr);
+        let segment = PacketBuilder::new(&self.rt, remote_link_addr, remote.addr)
+            .src_addr(self.local.addr)
+            .tcp(tcp_hdr, RT::Buf::empty(), self.options.tx_checksum_offload);
+        self.egress.transmit(segment);
+        Ok(())
+    }
+
     fn background(
         local_isn: SeqNumber,
         remote_isn: SeqNumber,
+        window_scale_permitted: bool,
+        sack_permitted: bool,
+        timestamps: Option<(TimestampClock, u32)>,
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
+        tcp_options: TcpOptions,
         rt: RT,
         arp: arp::Peer<RT>,
+        egress: Egress<RT>,
         ready: Rc<RefCell<ReadySockets<RT>>>,
+        stats: Rc<RefCell<TcpListenerStats>>,
     ) -> impl Future<Output = ()> {
-        let tcp_options = rt.tcp_options();
         let handshake_retries = 3usize;
         let handshake_timeout = Duration::from_secs(5);
 
@@ -292,30 +592,38 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 tcp_hdr.seq_num = local_isn;
                 tcp_hdr.ack = true;
                 tcp_hdr.ack_num = remote_isn + Wrapping(1);
-                tcp_hdr.window_size = tcp_options.receive_window_size;
+                tcp_hdr.window_size = tcp_options.unscaled_receive_window();
 
                 let mss = tcp_options.advertised_mss as u16;
                 tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
                 info!("Advertising MSS: {}", mss);
 
-                tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale));
-                info!("Advertising window scale: {}", tcp_options.window_scale);
+                // RFC 7323 1.3: Only in reply to a SYN that offered it.
+                if window_scale_permitted {
+                    let window_scale = tcp_options.local_window_scale();
+                    tcp_hdr.push_option(TcpOptions2::WindowScale(window_scale));
+                    info!("Advertising window scale: {}", window_scale);
+                }
 
-                debug!("Sending SYN+ACK: {:?}", tcp_hdr);
-                let segment = TcpSegment {
-                    ethernet2_hdr: Ethernet2Header {
-                        dst_addr: remote_link_addr,
-                        src_addr: rt.local_link_addr(),
-                        ether_type: EtherType2::Ipv4,
-                    },
-                    ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
-                    tcp_hdr,
-                    data: RT::Buf::empty(),
-                    tx_checksum_offload: tcp_options.tx_checksum_offload,
-                };
-                rt.transmit(segment);
+                if sack_permitted {
+                    tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+                }
+
+                if let Some((clock, syn_tsval)) = timestamps {
+                    tcp_hdr.push_option(TcpOptions2::Timestamp {
+                        sender_timestamp: clock.tsval(rt.now()),
+                        echo_timestamp: syn_tsval,
+                    });
+                }
+
+                debug!("Sending SYN+ACK: {}", tcp_hdr);
+                let segment = PacketBuilder::new(&rt, remote_link_addr, remote.addr)
+                    .src_addr(local.addr)
+                    .tcp(tcp_hdr, RT::Buf::empty(), tcp_options.tx_checksum_offload);
+                egress.transmit(segment);
                 rt.wait(handshake_timeout).await;
             }
+            stats.borrow_mut().handshake_timeouts += 1;
             ready.borrow_mut().push_err(Fail::Timeout {});
         }
     }
@@ -1,30 +1,57 @@
 use super::{
     active_open::ActiveOpenSocket,
-    established::EstablishedSocket,
+    established::{
+        state::{
+            congestion::TcpCongestion,
+            connection_log,
+            receiver::Receiver,
+            sender::{
+                Sender,
+                SenderState,
+                TcpSendStatus,
+                TcpSendWindow,
+            },
+            timestamps::Timestamps,
+            ControlBlock,
+        },
+        EstablishedSocket,
+    },
     isn_generator::IsnGenerator,
     passive_open::PassiveSocket,
+    traffic::{
+        TcpTraffic,
+        TrafficGenerator,
+        TrafficPattern,
+    },
 };
 use crate::{
     runtime::RuntimeBuf,
+    collections::watched::WatchedValue,
+    event::{
+        Event,
+        EventQueue,
+    },
     fail::Fail,
     file_table::{
         File,
         FileDescriptor,
         FileTable,
     },
+    logging::Sampler,
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
-        },
+        icmpv4,
         ip,
-        ip::port::EphemeralPorts,
+        ip::{
+            PortProtocol,
+            PortTable,
+        },
         ipv4,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
+        ipv4::{
+            datagram::Ipv4Header,
+            Egress,
         },
+        packet::PacketBuilder,
         tcp::{
             operations::{
                 AcceptFuture,
@@ -33,36 +60,128 @@ use crate::{
                 PopFuture,
                 PushFuture,
             },
+            options::{
+                RstPolicy,
+                TcpMissingOptions,
+                TcpNegotiated,
+            },
+            framing::Framing,
+            hooks::{
+                ConnectionHooks,
+                ConnectionInfo,
+                HooksId,
+            },
             segment::{
                 TcpHeader,
-                TcpSegment,
+                TcpOptionProblem,
+            },
+            transform::{
+                StreamTransform,
+                TransformFactory,
             },
         },
     },
     runtime::Runtime,
+    sampled,
     scheduler::SchedulerHandle,
+    snapshot::{
+        TcpConnectionSnapshot,
+        TcpListenerSnapshot,
+    },
+    stats::{
+        TcpConnectionSummary,
+        TcpGroupStats,
+        TcpLatencyRecorder,
+        TcpLatencyStats,
+        TcpListenerStats,
+        TcpOptionStats,
+        TcpThroughputRecorder,
+        TcpThroughputStats,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
 };
 use futures::channel::mpsc;
-use futures::stream::StreamExt;
-use std::collections::HashMap;
+use futures::future::Fuse;
+use futures::stream::{
+    FuturesUnordered,
+    StreamExt,
+};
+use futures::task::noop_waker_ref;
+use futures::FutureExt;
+use log::Level;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
 use std::{
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
+    convert::TryFrom,
+    future::Future,
+    net::Shutdown,
+    num::Wrapping,
     rc::Rc,
     task::{
         Context,
         Poll,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 pub struct Peer<RT: Runtime> {
     pub(super) inner: Rc<RefCell<Inner<RT>>>,
 }
 
+/// An application-chosen tag for a set of connections (all of one tenant's, say) that can then
+/// be closed, aborted, reconfigured or measured together. Connections accepted from a listener
+/// in a group join it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct GroupId(pub u64);
+
+/// Where a connection is in the TCP state machine (RFC 793, section 3.2). There's no
+/// SYN-RECEIVED: passive opens in progress belong to their listener and have no descriptor yet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl<RT: Runtime> Clone for Peer<RT> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
 impl<RT: Runtime> Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        ports: PortTable,
+        egress: Egress<RT>,
+        events: EventQueue,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded();
-        let inner = Rc::new(RefCell::new(Inner::new(rt.clone(), arp, file_table, tx)));
+        let inner = Inner::new(rt.clone(), arp, file_table, ports, egress, events, tx);
+        let inner = Rc::new(RefCell::new(inner));
         let bg_handle = rt.spawn(Self::background(rx, inner.clone()));
         inner.borrow_mut().dead_socket_handle = Some(bg_handle);
         Self { inner }
@@ -74,6 +193,7 @@ impl<RT: Runtime> Peer<RT> {
     ) {
         while let Some(fd) = dead_socket_rx.next().await {
             let mut inner = inner.borrow_mut();
+            inner.groups.remove(&fd);
 
             let (local, remote) = match inner.sockets.remove(&fd) {
                 None => continue,
@@ -93,10 +213,21 @@ impl<RT: Runtime> Peer<RT> {
                     )
                 });
 
-            // TODO: Assert we've been properly closed here.
-            // TODO: Recycle this FD.
             info!("Cleaning up dead socket for FD {}", fd);
+            inner.report_closed(fd, &socket);
+            // Active opens hold their local port, and it's free once the connection is gone. The
+            // FD is recycled too if the application closed the connection, which it will have if
+            // both FINs were acknowledged, unless it only shut down writing. After a reset it
+            // keeps the FD until it notices.
+            inner.ports.release(PortProtocol::Tcp, local.port, fd);
+            let closed = socket.cb.sender.state.get() == SenderState::FinAckd;
+            if closed && !inner.shut_down.contains(&fd) {
+                inner.file_table.free(fd);
+                inner.sources.remove(&fd);
+                inner.sinks.remove(&fd);
+            }
             drop(socket);
+            inner.num_closed.modify(|n| n + 1);
         }
     }
 
@@ -117,19 +248,60 @@ impl<RT: Runtime> Peer<RT> {
                 details: "Port number in private port range",
             });
         }
-        match inner.sockets.get_mut(&fd) {
-            Some(Socket::Inactive { ref mut local }) => {
-                *local = Some(addr);
-                Ok(())
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { local: None }) => (),
+            _ => {
+                return Err(Fail::Malformed {
+                    details: "Invalid file descriptor",
+                })
             },
-            _ => Err(Fail::Malformed {
-                details: "Invalid file descriptor",
-            }),
         }
+        inner.ports.claim(PortProtocol::Tcp, addr.port(), fd)?;
+        inner
+            .sockets
+            .insert(fd, Socket::Inactive { local: Some(addr) });
+        Ok(())
+    }
+
+    /// Shrink the MSS of the connection a fragmentation needed error is about. See
+    /// `ControlBlock::receive_fragmentation_needed`.
+    pub fn receive_fragmentation_needed(&self, error: &icmpv4::FragmentationNeeded) {
+        let port =
+            |offset: usize| ip::Port::try_from(NetworkEndian::read_u16(&error.payload[offset..]));
+        let (src_port, dst_port) = match (port(0), port(2)) {
+            (Ok(src_port), Ok(dst_port)) => (src_port, dst_port),
+            _ => return,
+        };
+        let local = ipv4::Endpoint::new(error.src_addr, src_port);
+        let remote = ipv4::Endpoint::new(error.dst_addr, dst_port);
+        let seq_no = Wrapping(NetworkEndian::read_u32(&error.payload[4..8]));
+        let inner = self.inner.borrow();
+        match inner.established.get(&(local, remote)) {
+            Some(s) => s.cb.receive_fragmentation_needed(seq_no, error),
+            None => debug!("Fragmentation needed for unknown connection {:?}", (local, remote)),
+        }
+    }
+
+    pub fn receive(
+        &self,
+        ip_header: &Ipv4Header,
+        buf: RT::Buf,
+        rx_time: Instant,
+    ) -> Result<(), Fail> {
+        self.inner.borrow_mut().receive(ip_header, buf, rx_time)
     }
 
-    pub fn receive(&self, ip_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
-        self.inner.borrow_mut().receive(ip_header, buf)
+    /// Answer a segment the packet filter rejected with a RST.
+    pub fn reject(&self, ip_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let tcp_options = inner.rt.tcp_options();
+        let (tcp_hdr, _) = TcpHeader::parse(ip_header, buf, tcp_options.rx_checksum_offload)?;
+        if tcp_hdr.rst {
+            return Ok(());
+        }
+        let local = ipv4::Endpoint::new(ip_header.dst_addr, tcp_hdr.dst_port);
+        let remote = ipv4::Endpoint::new(ip_header.src_addr, tcp_hdr.src_port);
+        inner.send_rst(&local, &remote)
     }
 
     pub fn listen(&self, fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
@@ -142,14 +314,23 @@ impl<RT: Runtime> Peer<RT> {
                 })
             },
         };
-        // TODO: Should this move to bind?
+        if inner.shutting_down {
+            return Err(SHUTTING_DOWN);
+        }
         if inner.passive.contains_key(&local) {
             return Err(Fail::ResourceBusy {
                 details: "Port already in use",
             });
         }
 
-        let socket = PassiveSocket::new(local, backlog, inner.rt.clone(), inner.arp.clone());
+        let socket = PassiveSocket::new(
+            local,
+            backlog,
+            inner.rt.clone(),
+            inner.arp.clone(),
+            inner.egress.clone(),
+            inner.latency.clone(),
+        );
         assert!(inner.passive.insert(local.clone(), socket).is_none());
         inner.sockets.insert(fd, Socket::Listening { local });
         Ok(())
@@ -170,6 +351,7 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Socket not listening",
                 }))
             },
+            None if inner.shutting_down => return Poll::Ready(Err(SHUTTING_DOWN)),
             None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
         };
         let passive = inner
@@ -179,11 +361,30 @@ impl<RT: Runtime> Peer<RT> {
         let cb = match passive.poll_accept(ctx) {
             Poll::Pending => return Poll::Pending,
             Poll::Ready(Ok(e)) => e,
-            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Ready(Err(e)) => {
+                // That may have been the last connection a draining listener was waiting on.
+                let local = *local;
+                inner.check_drained(local);
+                return Poll::Ready(Err(e));
+            },
         };
+        let listener_group = inner.groups.get(&fd).cloned();
+        let transform = inner.transforms.get(&fd).map(|factory| factory(cb.remote));
         let fd = inner.file_table.alloc(File::TcpSocket);
-        let established = EstablishedSocket::new(cb, fd, inner.dead_socket_tx.clone());
+        let established = EstablishedSocket::new(
+            cb,
+            fd,
+            inner.dead_socket_tx.clone(),
+            inner.timer_origin,
+        );
         let key = (established.cb.local.clone(), established.cb.remote.clone());
+        let missing = established.cb.negotiated().missing;
+        if let Some(group) = listener_group {
+            inner.groups.insert(fd, group);
+        }
+        if let Err(e) = established.set_transform(transform) {
+            warn!("Failed to start transform for {:?}: {:?}", key.1, e);
+        }
 
         let socket = Socket::Established {
             local: established.cb.local.clone(),
@@ -191,6 +392,8 @@ impl<RT: Runtime> Peer<RT> {
         };
         assert!(inner.sockets.insert(fd, socket).is_none());
         assert!(inner.established.insert(key, established).is_none());
+        inner.count_missing_options(missing);
+        inner.report_opened(fd, key.0, key.1);
 
         Poll::Ready(Ok(fd))
     }
@@ -203,6 +406,28 @@ impl<RT: Runtime> Peer<RT> {
     }
 
     pub fn connect(&self, fd: FileDescriptor, remote: ipv4::Endpoint) -> ConnectFuture<RT> {
+        self.open(fd, None, remote)
+    }
+
+    /// Like `connect`, but from `local` instead of an ephemeral port, for protocols that expect a
+    /// particular source port (e.g. FTP's active mode) and for reproducible tests. `local` has to
+    /// be our address, its port can't be held by another socket, and there can't already be a
+    /// connection between the two endpoints.
+    pub fn connect_from(
+        &self,
+        fd: FileDescriptor,
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+    ) -> ConnectFuture<RT> {
+        self.open(fd, Some(local), remote)
+    }
+
+    fn open(
+        &self,
+        fd: FileDescriptor,
+        local: Option<ipv4::Endpoint>,
+        remote: ipv4::Endpoint,
+    ) -> ConnectFuture<RT> {
         let mut inner = self.inner.borrow_mut();
 
         let r = try {
@@ -212,10 +437,52 @@ impl<RT: Runtime> Peer<RT> {
                     details: "Invalid file descriptor",
                 })?,
             }
+            if inner.rt.tcp_options().listen_only {
+                Err(Fail::Unsupported {
+                    details: "TCP is in listen-only mode",
+                })?;
+            }
+            if inner.shutting_down {
+                Err(SHUTTING_DOWN)?;
+            }
 
-            // TODO: We need to free these!
-            let local_port = inner.ephemeral_ports.alloc()?;
-            let local = ipv4::Endpoint::new(inner.rt.local_ipv4_addr(), local_port);
+            let local_addr = inner.rt.local_ipv4_addr();
+            let shard = inner.rt.tcp_options().shard;
+            let local = match local {
+                Some(local) => {
+                    if local.addr != local_addr {
+                        Err(Fail::Malformed {
+                            details: "Local address isn't ours",
+                        })?;
+                    }
+                    // Replies have to be steered back to this engine.
+                    if shard.map_or(false, |shard| !shard.owns(&local, &remote)) {
+                        Err(Fail::Invalid {
+                            details: "Local endpoint belongs to another shard",
+                        })?;
+                    }
+                    let key = (local, remote);
+                    if inner.connecting.contains_key(&key) || inner.established.contains_key(&key)
+                    {
+                        Err(Fail::ResourceBusy {
+                            details: "Connection already exists",
+                        })?;
+                    }
+                    if local.port.is_private() {
+                        inner.ports.reserve_ephemeral(local.port, fd)?;
+                    } else {
+                        inner.ports.claim(PortProtocol::Tcp, local.port, fd)?;
+                    }
+                    local
+                },
+                None => {
+                    let local_port = inner.ports.alloc_ephemeral(fd, |port| match shard {
+                        Some(shard) => shard.owns(&ipv4::Endpoint::new(local_addr, port), &remote),
+                        None => true,
+                    })?;
+                    ipv4::Endpoint::new(local_addr, local_port)
+                },
+            };
 
             let socket = Socket::Connecting {
                 local: local.clone(),
@@ -231,6 +498,8 @@ impl<RT: Runtime> Peer<RT> {
                 remote,
                 inner.rt.clone(),
                 inner.arp.clone(),
+                inner.egress.clone(),
+                inner.latency.clone(),
             );
             assert!(inner.connecting.insert(key, socket).is_none());
             fd
@@ -246,6 +515,70 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
+    /// Connect to whichever of `candidates` (e.g. every address a name resolved to) answers
+    /// first, trying them in order. Each attempt gets the `connect_attempt_delay` option to finish
+    /// before the next one starts alongside it, and a failed attempt starts the next right away,
+    /// as in RFC 8305. Resolves to the first connection established and the candidate it's to.
+    /// The other attempts are abandoned, resetting any that completed too; dropping the future
+    /// abandons all of them. Fails with the last error if every attempt does.
+    pub fn connect_any(
+        &self,
+        candidates: &[ipv4::Endpoint],
+    ) -> impl Future<Output = Result<(FileDescriptor, ipv4::Endpoint), Fail>> {
+        let candidates = candidates.to_vec();
+        let peer = Peer {
+            inner: self.inner.clone(),
+        };
+        async move {
+            if candidates.is_empty() {
+                return Err(Fail::Invalid {
+                    details: "No addresses to connect to",
+                });
+            }
+            let rt = peer.inner.borrow().rt.clone();
+            let delay = rt.tcp_options().connect_attempt_delay;
+            let mut attempts = ConnectAttempts {
+                inner: peer.inner.clone(),
+                fds: vec![],
+            };
+            let mut in_progress = FuturesUnordered::new();
+            let mut next = 0;
+            let mut last_error = None;
+            loop {
+                if let Some(&remote) = candidates.get(next) {
+                    next += 1;
+                    let fd = peer.socket();
+                    attempts.fds.push(fd);
+                    in_progress.push(peer.connect(fd, remote).map(move |r| (fd, remote, r)));
+                }
+                if in_progress.is_empty() {
+                    return Err(last_error.unwrap());
+                }
+                let stagger = if next < candidates.len() {
+                    rt.wait(delay).fuse()
+                } else {
+                    Fuse::terminated()
+                };
+                futures::pin_mut!(stagger);
+                futures::select_biased! {
+                    (fd, remote, result) = in_progress.select_next_some() => match result {
+                        Ok(()) => {
+                            attempts.fds.retain(|&f| f != fd);
+                            return Ok((fd, remote));
+                        },
+                        Err(e) => {
+                            debug!("connect_any: attempt to {:?} failed: {:?}", remote, e);
+                            attempts.fds.retain(|&f| f != fd);
+                            peer.inner.borrow_mut().abandon_connect(fd);
+                            last_error = Some(e);
+                        },
+                    },
+                    _ = stagger => (),
+                }
+            }
+        }
+    }
+
     pub fn peek(&self, fd: FileDescriptor) -> Result<RT::Buf, Fail> {
         let inner = self.inner.borrow_mut();
         let key = match inner.sockets.get(&fd) {
@@ -265,6 +598,48 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
+    /// Unread bytes `offset..(offset + len)` of the receive stream, without consuming them. Fails
+    /// with `WouldBlock` until that much has arrived.
+    pub fn peek_at(&self, fd: FileDescriptor, offset: usize, len: usize) -> Result<RT::Buf, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => s.peek_at(offset, len),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// Discard the first `num_bytes` unread bytes of the receive stream, which counts as
+    /// delivering them. Pair with `peek_at` to parse without double handling.
+    pub fn consume(&self, fd: FileDescriptor, num_bytes: usize) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => s.consume(num_bytes),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
     pub fn recv(&self, fd: FileDescriptor) -> Result<Option<RT::Buf>, Fail> {
         let inner = self.inner.borrow_mut();
         let key = match inner.sockets.get(&fd) {
@@ -303,6 +678,71 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
+    /// Read exactly `len` bytes, in one buffer whatever segments they arrived in, waiting until
+    /// they all have. Fails if the remote closes first, leaving what did arrive unread.
+    pub fn read_exact(
+        &self,
+        fd: FileDescriptor,
+        len: usize,
+    ) -> impl Future<Output = Result<RT::Buf, Fail>> {
+        let peer = Self {
+            inner: self.inner.clone(),
+        };
+        futures::future::poll_fn(move |ctx| {
+            peer.poll_established(fd, ctx, |s, ctx| s.poll_recv_exact(len, ctx))
+        })
+    }
+
+    /// Read whatever has arrived, up to `max` bytes, in one buffer, waiting for at least a byte.
+    pub fn read_up_to(
+        &self,
+        fd: FileDescriptor,
+        max: usize,
+    ) -> impl Future<Output = Result<RT::Buf, Fail>> {
+        let peer = Self {
+            inner: self.inner.clone(),
+        };
+        futures::future::poll_fn(move |ctx| {
+            peer.poll_established(fd, ctx, |s, ctx| s.poll_recv_up_to(max, ctx))
+        })
+    }
+
+    /// Resolves once `fd` has data to read, or once the remote has closed so reads would fail,
+    /// without reading anything.
+    pub fn readable(&self, fd: FileDescriptor) -> impl Future<Output = Result<(), Fail>> {
+        let peer = Self {
+            inner: self.inner.clone(),
+        };
+        futures::future::poll_fn(move |ctx| {
+            peer.poll_established(fd, ctx, |s, ctx| s.poll_readable(ctx))
+        })
+    }
+
+    // Poll the established connection `fd` refers to with `f`.
+    fn poll_established<T>(
+        &self,
+        fd: FileDescriptor,
+        ctx: &mut Context,
+        f: impl FnOnce(&EstablishedSocket<RT>, &mut Context) -> Poll<Result<T, Fail>>,
+    ) -> Poll<Result<T, Fail>> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Poll::Ready(Err(Fail::Malformed {
+                    details: "Socket not established",
+                }))
+            },
+            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+        };
+        match inner.established.get(&key) {
+            Some(s) => f(s, ctx),
+            None => Poll::Ready(Err(Fail::Malformed {
+                details: "Socket not established",
+            })),
+        }
+    }
+
     pub fn push(&self, fd: FileDescriptor, buf: RT::Buf) -> PushFuture<RT> {
         let err = match self.send(fd, buf) {
             Ok(()) => None,
@@ -315,15 +755,107 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    pub fn pop(&self, fd: FileDescriptor) -> PopFuture<RT> {
-        PopFuture {
+    /// Push several buffers, such as a header and the payload after it, as one write: they're
+    /// sent back to back without first being copied into one buffer. Like `push`, the write fails
+    /// as a whole if the send buffer hasn't room for all of it.
+    pub fn writev(&self, fd: FileDescriptor, bufs: Vec<RT::Buf>) -> PushFuture<RT> {
+        let err = match self.send_vectored(fd, bufs) {
+            Ok(()) => None,
+            Err(e) => Some(e),
+        };
+        PushFuture {
             fd,
+            err,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Like `push`, but when the send buffer is full, wait for the remote to acknowledge enough
+    /// to make room instead of failing with `Fail::ResourceExhausted`.
+    pub fn write(
+        &self,
+        fd: FileDescriptor,
+        buf: RT::Buf,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        let cb = self.control_block(fd);
+        let peer = Self {
             inner: self.inner.clone(),
+        };
+        async move {
+            let cb = cb?;
+            loop {
+                let (_, acked) = cb.sender.base_seq_no.watch();
+                let (_, state_changed) = cb.sender.state.watch();
+                match peer.send(fd, buf.clone()) {
+                    Err(Fail::ResourceExhausted { .. }) => (),
+                    r => return r,
+                }
+                if cb.sender.state.get() == SenderState::Reset {
+                    return Err(Fail::ConnectionAborted {});
+                }
+                futures::select_biased! {
+                    _ = acked.fuse() => (),
+                    _ = state_changed.fuse() => (),
+                }
+            }
         }
     }
 
-    fn send(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
-        let inner = self.inner.borrow_mut();
+    /// Like `push`, but the future resolves only once the peer has acknowledged all of `buf`, and
+    /// fails with `Fail::Timeout` if that hasn't happened by `deadline`. With `abort_on_miss`, a
+    /// missed deadline also resets the connection rather than let the rest of `buf` arrive late.
+    pub fn write_with_deadline(
+        &self,
+        fd: FileDescriptor,
+        buf: RT::Buf,
+        deadline: Instant,
+        abort_on_miss: bool,
+    ) -> impl Future<Output = Result<(), Fail>> {
+        // `buf` is all acknowledged once the send window's base reaches the end of it.
+        let r = self
+            .send(fd, buf)
+            .and_then(|()| self.control_block(fd))
+            .map(|cb| {
+                let end = cb.sender.unsent_seq_no.get();
+                (cb, end)
+            });
+        let rt = self.inner.borrow().rt.clone();
+        let peer = self.inner.clone();
+        async move {
+            let (cb, end) = r?;
+            loop {
+                if cb.sender.state.get() == SenderState::Reset {
+                    return Err(Fail::ConnectionAborted {});
+                }
+                let (base_seq_no, acked) = cb.sender.base_seq_no.watch();
+                // `base_seq_no` only moves up to `end`, so this is the same as `end <= base_seq_no`.
+                let Wrapping(remaining) = end - base_seq_no;
+                if remaining == 0 || remaining >= (1 << 31) {
+                    return Ok(());
+                }
+                let (_, reset) = cb.sender.state.watch();
+                futures::select_biased! {
+                    _ = acked.fuse() => (),
+                    _ = reset.fuse() => (),
+                    _ = rt.wait_until(deadline).fuse() => {
+                        if abort_on_miss {
+                            peer.borrow_mut().abort_established(fd, (cb.local, cb.remote));
+                        }
+                        return Err(Fail::Timeout {});
+                    },
+                }
+            }
+        }
+    }
+
+    /// Install `transform` between an established connection and the application, replacing any
+    /// already there, or remove it with `None`.
+    pub fn set_transform(
+        &self,
+        fd: FileDescriptor,
+        transform: Option<Box<dyn StreamTransform>>,
+    ) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
         let key = match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => (*local, *remote),
             Some(..) => {
@@ -334,40 +866,74 @@ impl<RT: Runtime> Peer<RT> {
             None => return Err(Fail::Malformed { details: "Bad FD" }),
         };
         match inner.established.get(&key) {
-            Some(ref s) => s.send(buf),
-            None => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
+            Some(ref s) => s.set_transform(transform),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
         }
     }
 
-    pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
-        let inner = self.inner.borrow_mut();
+    /// Run `hooks` as connections are established and torn down from now on; see `tcp::hooks`.
+    /// They're only told of the teardown of connections they saw established.
+    pub fn add_connection_hooks(&self, hooks: Rc<dyn ConnectionHooks>) -> HooksId {
+        let mut inner = self.inner.borrow_mut();
+        let id = HooksId(inner.next_hooks_id);
+        inner.next_hooks_id += 1;
+        let first_connection = inner.next_connection_id;
+        inner.hooks.push(RegisteredHooks {
+            id,
+            first_connection,
+            hooks,
+        });
+        id
+    }
+
+    pub fn remove_connection_hooks(&self, id: HooksId) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let i = inner
+            .hooks
+            .iter()
+            .position(|h| h.id == id)
+            .ok_or(Fail::ResourceNotFound {
+                details: "No such connection hooks",
+            })?;
+        inner.hooks.remove(i);
+        Ok(())
+    }
+
+    /// Have a listener install a transform from `factory` on every connection it accepts from now
+    /// on, or stop with `None`.
+    pub fn set_listener_transform(
+        &self,
+        fd: FileDescriptor,
+        factory: Option<TransformFactory>,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
         match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => {
-                let key = (local.clone(), remote.clone());
-                match inner.established.get(&key) {
-                    Some(ref s) => s.close()?,
-                    None => {
-                        return Err(Fail::Malformed {
-                            details: "Socket not established",
-                        })
-                    },
-                }
-            },
+            Some(Socket::Listening { .. }) => (),
             Some(..) => {
-                // TODO: Implement close for listening sockets.
-                // unimplemented!();
+                return Err(Fail::Malformed {
+                    details: "Socket not listening",
+                })
             },
             None => return Err(Fail::Malformed { details: "Bad FD" }),
         }
+        match factory {
+            Some(factory) => inner.transforms.insert(fd, factory),
+            None => inner.transforms.remove(&fd),
+        };
         Ok(())
     }
 
-    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
-        let inner = self.inner.borrow();
+    pub fn pop(&self, fd: FileDescriptor) -> PopFuture<RT> {
+        PopFuture {
+            fd,
+            inner: self.inner.clone(),
+        }
+    }
+
+    fn send(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
+        let inner = self.inner.borrow_mut();
         let key = match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => (*local, *remote),
             Some(..) => {
@@ -378,7 +944,7 @@ impl<RT: Runtime> Peer<RT> {
             None => return Err(Fail::Malformed { details: "Bad FD" }),
         };
         match inner.established.get(&key) {
-            Some(ref s) => Ok(s.remote_mss()),
+            Some(ref s) => s.send(buf),
             None => {
                 return Err(Fail::Malformed {
                     details: "Socket not established",
@@ -387,8 +953,8 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
-        let inner = self.inner.borrow();
+    fn send_vectored(&self, fd: FileDescriptor, bufs: Vec<RT::Buf>) -> Result<(), Fail> {
+        let inner = self.inner.borrow_mut();
         let key = match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => (*local, *remote),
             Some(..) => {
@@ -399,7 +965,7 @@ impl<RT: Runtime> Peer<RT> {
             None => return Err(Fail::Malformed { details: "Bad FD" }),
         };
         match inner.established.get(&key) {
-            Some(ref s) => Ok(s.current_rto()),
+            Some(ref s) => s.send_vectored(bufs),
             None => {
                 return Err(Fail::Malformed {
                     details: "Socket not established",
@@ -408,7 +974,7 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    pub fn endpoints(&self, fd: FileDescriptor) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
+    fn control_block(&self, fd: FileDescriptor) -> Result<Rc<ControlBlock<RT>>, Fail> {
         let inner = self.inner.borrow();
         let key = match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => (*local, *remote),
@@ -420,14 +986,779 @@ impl<RT: Runtime> Peer<RT> {
             None => return Err(Fail::Malformed { details: "Bad FD" }),
         };
         match inner.established.get(&key) {
-            Some(ref s) => Ok(s.endpoints()),
-            None => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            },
+            Some(s) => Ok(s.cb.clone()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
         }
     }
+
+    pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let shut_down = inner.shut_down.remove(&fd);
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => {
+                let key = (local.clone(), remote.clone());
+                match inner.established.get(&key) {
+                    // Closing after shutting down writes just hands the descriptor back.
+                    Some(ref s) => match s.close() {
+                        Err(Fail::Ignored { .. }) if shut_down => (),
+                        r => r?,
+                    },
+                    None => {
+                        return Err(Fail::Malformed {
+                            details: "Socket not established",
+                        })
+                    },
+                }
+            },
+            Some(Socket::Listening { local }) => {
+                let local = *local;
+                inner.close_listener(fd, local);
+            },
+            Some(..) => {
+                // TODO: Implement close for unconnected sockets.
+                // unimplemented!();
+            },
+            // The connection finished closing before the application closed its descriptor.
+            None if shut_down => {
+                inner.file_table.free(fd);
+                inner.sources.remove(&fd);
+                inner.sinks.remove(&fd);
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        }
+        Ok(())
+    }
+
+    /// Shut down one or both directions of `fd`'s connection, like POSIX `shutdown`. Shutting
+    /// down writing sends a FIN once what's queued has gone out, while the remote's data can
+    /// still be read until it closes its side too, as when a client signals the end of its
+    /// request. Shutting down reading throws away unread data and anything that arrives later.
+    /// Unlike after `close`, the descriptor stays valid until the application closes it.
+    pub fn shutdown_socket(&self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(s) => s.shutdown(how)?,
+            None => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+        }
+        inner.shut_down.insert(fd);
+        Ok(())
+    }
+
+    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.remote_mss()),
+            None => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+        }
+    }
+
+    /// Where `fd` is in the TCP state machine. Unbound and bound sockets are `Closed`, and a
+    /// connection stays `SynSent` until the application sees its connect complete.
+    pub fn get_state(&self, fd: FileDescriptor) -> Result<TcpState, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { .. }) => return Ok(TcpState::Closed),
+            Some(Socket::Listening { .. }) => return Ok(TcpState::Listen),
+            Some(Socket::Connecting { .. }) => return Ok(TcpState::SynSent),
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.state()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// The parameters `fd`'s handshake agreed on.
+    pub fn get_negotiated(&self, fd: FileDescriptor) -> Result<TcpNegotiated, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.negotiated()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    pub fn get_congestion(&self, fd: FileDescriptor) -> Result<TcpCongestion, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.congestion()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    pub fn get_send_status(&self, fd: FileDescriptor) -> Result<TcpSendStatus, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.send_status()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// Send small segments on `fd` right away, even with data unacknowledged, or go back to
+    /// holding them for Nagle's algorithm.
+    pub fn set_nodelay(&self, fd: FileDescriptor, nodelay: bool) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => {
+                s.set_nodelay(nodelay);
+                Ok(())
+            },
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// Whether a `push` to `fd` would be taken now, rather than fail with
+    /// `Fail::ResourceExhausted` because the send buffer is full or fail because the connection
+    /// is closing.
+    pub fn writable(&self, fd: FileDescriptor) -> Result<bool, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.writable()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// Deliver what arrives on an established connection as records rather than as a stream. See
+    /// `Framing`.
+    pub fn set_framing(&self, fd: FileDescriptor, framing: Framing) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => s.set_framing(framing),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// Limit how much `fd` holds for sending, overriding the `send_buffer_size` option. Zero
+    /// means no limit.
+    pub fn set_send_buffer_size(&self, fd: FileDescriptor, size: usize) -> Result<(), Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => {
+                s.set_send_buffer_size(size);
+                Ok(())
+            },
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    pub fn get_send_window(&self, fd: FileDescriptor) -> Result<TcpSendWindow, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.send_window()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// When data last arrived on `fd`, by the device's timestamp if it gave one. `None` until
+    /// any has.
+    pub fn last_received(&self, fd: FileDescriptor) -> Result<Option<Instant>, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.last_received()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.current_rto()),
+            None => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+        }
+    }
+
+    /// The smoothed RTT of `fd`'s connection, or `None` before it has taken a sample.
+    pub fn get_srtt(&self, fd: FileDescriptor) -> Result<Option<Duration>, Fail> {
+        let cb = self.control_block(fd)?;
+        let srtt = cb.sender.rto.borrow().smoothed_rtt();
+        Ok(srtt)
+    }
+
+    pub fn latency_stats(&self, fd: FileDescriptor) -> Result<TcpLatencyStats, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.latency_stats()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    pub fn throughput_stats(&self, fd: FileDescriptor) -> Result<TcpThroughputStats, Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.throughput_stats()),
+            None => Err(Fail::Malformed {
+                details: "Socket not established",
+            }),
+        }
+    }
+
+    /// Send `pattern` on `fd` from inside the engine, as fast as the connection allows or at
+    /// `rate` bytes per second, until the connection closes or `stop_traffic`. For measuring the
+    /// stack without an application in the loop; anything the application pushes meanwhile is
+    /// interleaved with the pattern.
+    pub fn start_source(
+        &self,
+        fd: FileDescriptor,
+        rate: Option<u64>,
+        pattern: TrafficPattern,
+    ) -> Result<(), Fail> {
+        if rate == Some(0) {
+            return Err(Fail::Invalid {
+                details: "Traffic source rate must be positive",
+            });
+        }
+        let cb = self.control_block(fd)?;
+        let mut inner = self.inner.borrow_mut();
+        if inner.sources.contains_key(&fd) {
+            return Err(Fail::ResourceBusy {
+                details: "Traffic source already running",
+            });
+        }
+        inner
+            .sources
+            .insert(fd, TrafficGenerator::source(cb, rate, pattern));
+        Ok(())
+    }
+
+    /// Receive and discard everything that arrives on `fd` from inside the engine, until the
+    /// remote closes or `stop_traffic`. The application shouldn't pop from `fd` meanwhile.
+    pub fn start_sink(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        let cb = self.control_block(fd)?;
+        let mut inner = self.inner.borrow_mut();
+        if inner.sinks.contains_key(&fd) {
+            return Err(Fail::ResourceBusy {
+                details: "Traffic sink already running",
+            });
+        }
+        inner.sinks.insert(fd, TrafficGenerator::sink(cb));
+        Ok(())
+    }
+
+    /// How much the traffic generators on `fd` have moved, and how fast.
+    pub fn traffic_stats(&self, fd: FileDescriptor) -> TcpTraffic {
+        let inner = self.inner.borrow();
+        let now = inner.rt.now();
+        TcpTraffic {
+            source: inner.sources.get(&fd).map(|g| g.stats(now)),
+            sink: inner.sinks.get(&fd).map(|g| g.stats(now)),
+        }
+    }
+
+    /// Stop the traffic generators on `fd`, returning their final stats.
+    pub fn stop_traffic(&self, fd: FileDescriptor) -> TcpTraffic {
+        let mut inner = self.inner.borrow_mut();
+        let now = inner.rt.now();
+        TcpTraffic {
+            source: inner.sources.remove(&fd).map(|g| g.stop(now)),
+            sink: inner.sinks.remove(&fd).map(|g| g.stop(now)),
+        }
+    }
+
+    /// Stop the listener on `port` taking new connections, for a graceful restart: SYNs are
+    /// answered as if the port were closed, while handshakes already under way finish and can
+    /// still be accepted, and established connections carry on. Once the last of them is gone, an
+    /// `Event::TcpDrained` is raised.
+    pub fn drain(&self, port: ip::Port) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let (fd, local) = inner
+            .sockets
+            .iter()
+            .find_map(|(fd, s)| match s {
+                Socket::Listening { local } if local.port == port => Some((*fd, *local)),
+                _ => None,
+            })
+            .ok_or(Fail::ResourceNotFound {
+                details: "No listener on port",
+            })?;
+        if inner.draining.contains_key(&local) {
+            return Ok(());
+        }
+        inner.draining.insert(local, Drain { fd, done: false });
+        inner.check_drained(local);
+        Ok(())
+    }
+
+    /// Close the listener on `port`, as closing its descriptor would: handshakes in progress and
+    /// connections waiting to be accepted are reset, pending accepts fail, and the port is free to
+    /// bind again. Connections already accepted carry on.
+    pub fn close_listener(&self, port: ip::Port) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let (fd, local) = inner
+            .sockets
+            .iter()
+            .find_map(|(fd, s)| match s {
+                Socket::Listening { local } if local.port == port => Some((*fd, *local)),
+                _ => None,
+            })
+            .ok_or(Fail::ResourceNotFound {
+                details: "No listener on port",
+            })?;
+        inner.close_listener(fd, local);
+        Ok(())
+    }
+
+    /// Stop accepting connections and close every established one, resetting any that haven't
+    /// finished closing after `timeout`. Listeners and handshakes in progress are dropped, so
+    /// their futures fail. The returned future resolves once any connections left are in
+    /// TIME_WAIT.
+    pub fn shutdown(&self, timeout: Duration) -> impl Future<Output = ()> {
+        let mut inner = self.inner.borrow_mut();
+        inner.shutting_down = true;
+        inner.passive.clear();
+        inner.draining.clear();
+        inner.connecting.clear();
+        inner.sockets.retain(|_, s| match s {
+            Socket::Listening { .. } | Socket::Connecting { .. } => false,
+            _ => true,
+        });
+        inner.forget_stale_groups();
+        for socket in inner.established.values() {
+            // Connections the application already closed just carry on.
+            let _ = socket.close();
+        }
+        let deadline = inner.rt.now() + timeout;
+        let rt = inner.rt.clone();
+        let num_closed = inner.num_closed.clone();
+        let peer = self.inner.clone();
+        async move {
+            let mut deadline = Some(deadline);
+            loop {
+                // Connections in TIME_WAIT have nothing left to send.
+                let done = peer
+                    .borrow()
+                    .established
+                    .values()
+                    .all(|s| s.state() == TcpState::TimeWait);
+                if done {
+                    return;
+                }
+                let (_, closed) = num_closed.watch();
+                match deadline {
+                    Some(when) => futures::select_biased! {
+                        _ = closed.fuse() => (),
+                        _ = rt.wait_until(when).fuse() => {
+                            peer.borrow_mut().abort_all();
+                            deadline = None;
+                        },
+                    },
+                    None => closed.await,
+                }
+            }
+        }
+    }
+
+    /// Put a socket of any kind in `group`, moving it out of any other, or take it out with
+    /// `None`.
+    pub fn set_group(&self, fd: FileDescriptor, group: Option<GroupId>) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.sockets.contains_key(&fd) {
+            return Err(Fail::Malformed { details: "Bad FD" });
+        }
+        match group {
+            Some(group) => inner.groups.insert(fd, group),
+            None => inner.groups.remove(&fd),
+        };
+        Ok(())
+    }
+
+    pub fn group(&self, fd: FileDescriptor) -> Option<GroupId> {
+        self.inner.borrow().groups.get(&fd).cloned()
+    }
+
+    /// Every socket in `group`, ordered by file descriptor.
+    pub fn group_members(&self, group: GroupId) -> Vec<FileDescriptor> {
+        let inner = self.inner.borrow();
+        let mut fds: Vec<_> = inner
+            .groups
+            .iter()
+            .filter(|(_, g)| **g == group)
+            .map(|(fd, _)| *fd)
+            .collect();
+        fds.sort();
+        fds
+    }
+
+    /// Start closing every established connection in `group`, returning how many weren't already
+    /// closing. Like `close`, this leaves listeners and handshakes in progress alone.
+    pub fn close_group(&self, group: GroupId) -> usize {
+        let inner = self.inner.borrow();
+        inner
+            .group_connections(group)
+            .filter(|(_, key)| inner.established[key].close().is_ok())
+            .count()
+    }
+
+    /// Reset every established connection in `group` right away, returning how many there were.
+    /// Their file descriptors become invalid.
+    pub fn abort_group(&self, group: GroupId) -> usize {
+        let mut inner = self.inner.borrow_mut();
+        let members: Vec<_> = inner.group_connections(group).collect();
+        for (fd, key) in &members {
+            inner.sockets.remove(fd);
+            inner.groups.remove(fd);
+            if let Some(socket) = inner.established.remove(key) {
+                inner.report_closed(*fd, &socket);
+                socket.abort();
+            }
+            inner.release_aborted(*fd, key.0);
+        }
+        if !members.is_empty() {
+            inner.num_closed.modify(|n| n + 1);
+        }
+        members.len()
+    }
+
+    /// Totals over the established connections currently in `group`.
+    pub fn group_stats(&self, group: GroupId) -> TcpGroupStats {
+        let inner = self.inner.borrow();
+        let mut stats = TcpGroupStats::default();
+        for (fd, key) in inner.group_connections(group) {
+            stats.add(&inner.established[&key].summary(fd));
+        }
+        stats
+    }
+
+    pub fn engine_latency_stats(&self) -> TcpLatencyStats {
+        self.inner.borrow().latency.borrow().clone()
+    }
+
+    pub fn num_established(&self) -> usize {
+        self.inner.borrow().established.len()
+    }
+
+    /// Segments for closed ports that didn't get a RST because of `Options::closed_port_rst`.
+    pub fn num_rsts_suppressed(&self) -> u64 {
+        self.inner.borrow().num_rsts_suppressed
+    }
+
+    pub fn option_stats(&self) -> TcpOptionStats {
+        self.inner.borrow().option_stats.clone()
+    }
+
+    pub fn endpoints(&self, fd: FileDescriptor) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
+        let inner = self.inner.borrow();
+        let key = match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(..) => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+            None => return Err(Fail::Malformed { details: "Bad FD" }),
+        };
+        match inner.established.get(&key) {
+            Some(ref s) => Ok(s.endpoints()),
+            None => {
+                return Err(Fail::Malformed {
+                    details: "Socket not established",
+                })
+            },
+        }
+    }
+
+    /// Established connections with data queued to send or waiting to be acknowledged.
+    pub fn num_active(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner
+            .established
+            .values()
+            .filter(|s| s.has_data_in_flight())
+            .count()
+    }
+
+    /// The health of every established connection, ordered by file descriptor.
+    pub fn connection_summaries(&self) -> Vec<TcpConnectionSummary> {
+        let inner = self.inner.borrow();
+        let mut summaries: Vec<_> = inner
+            .sockets
+            .iter()
+            .filter_map(|(&fd, socket)| match socket {
+                Socket::Established { local, remote } => {
+                    Some(inner.established[&(*local, *remote)].summary(fd))
+                },
+                _ => None,
+            })
+            .collect();
+        summaries.sort_by_key(|s| s.fd);
+        summaries
+    }
+
+    pub fn listener_stats(&self, fd: FileDescriptor) -> Result<TcpListenerStats, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Listening { local }) => Ok(inner.passive[local].stats()),
+            Some(..) => Err(Fail::Malformed {
+                details: "Socket not listening",
+            }),
+            None => Err(Fail::Malformed { details: "Bad FD" }),
+        }
+    }
+
+    /// Every listening socket's statistics, ordered by file descriptor.
+    pub fn all_listener_stats(&self) -> Vec<TcpListenerStats> {
+        let inner = self.inner.borrow();
+        let mut listeners: Vec<_> = inner
+            .sockets
+            .iter()
+            .filter_map(|(&fd, socket)| match socket {
+                Socket::Listening { local } => Some((fd, inner.passive[local].stats())),
+                _ => None,
+            })
+            .collect();
+        listeners.sort_by_key(|&(fd, _)| fd);
+        listeners.into_iter().map(|(_, s)| s).collect()
+    }
+
+    /// Listening sockets and established connections, ordered by file descriptor.
+    pub fn snapshot(&self) -> (Vec<TcpListenerSnapshot>, Vec<TcpConnectionSnapshot>) {
+        let inner = self.inner.borrow();
+        let mut listeners = vec![];
+        let mut connections = vec![];
+        for (&fd, socket) in &inner.sockets {
+            match socket {
+                Socket::Listening { local } => listeners.push(TcpListenerSnapshot {
+                    fd,
+                    local: (*local).into(),
+                    backlog: inner.passive[local].backlog(),
+                }),
+                Socket::Established { local, remote } => {
+                    let socket = &inner.established[&(*local, *remote)];
+                    connections.push(socket.snapshot(fd));
+                },
+                Socket::Inactive { .. } | Socket::Connecting { .. } => (),
+            }
+        }
+        listeners.sort_by_key(|l| l.fd);
+        connections.sort_by_key(|c| c.fd);
+        (listeners, connections)
+    }
+
+    /// Adopt an established connection from another engine's snapshot, returning its new file
+    /// descriptor.
+    pub fn restore(&self, snapshot: &TcpConnectionSnapshot) -> Result<FileDescriptor, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let local = ipv4::Endpoint::try_from(snapshot.local)?;
+        let remote = ipv4::Endpoint::try_from(snapshot.remote)?;
+        let key = (local, remote);
+        if inner.established.contains_key(&key) || inner.connecting.contains_key(&key) {
+            return Err(Fail::ResourceBusy {
+                details: "Connection already exists",
+            });
+        }
+        let fd = inner.file_table.alloc(File::TcpSocket);
+        if local.port.is_private() {
+            if let Err(e) = inner.ports.reserve_ephemeral(local.port, fd) {
+                inner.file_table.free(fd);
+                return Err(e);
+            }
+        }
+
+        let now = inner.rt.now();
+        let cb = ControlBlock {
+            local,
+            remote,
+            rt: inner.rt.clone(),
+            arp: inner.arp.clone(),
+            egress: inner.egress.clone(),
+            sender: Sender::restore(
+                &snapshot.sender,
+                now,
+                inner.rt.tcp_options().min_rto,
+                inner.rt.tcp_options().max_rto,
+            ),
+            receiver: Receiver::restore(
+                &snapshot.receiver,
+                now,
+                inner.rt.tcp_options().reassembly_limit,
+            ),
+            latency: TcpLatencyRecorder::new(inner.latency.clone()),
+            throughput: TcpThroughputRecorder::new(now),
+            log: connection_log(&inner.rt, local, remote),
+            ack_template: RefCell::new(None),
+            timestamps: snapshot
+                .timestamps
+                .as_ref()
+                .map(|t| Timestamps::restore(t, now)),
+            soft_errors: Cell::new(0),
+            // Snapshots don't carry the handshake's options.
+            missing_options: TcpMissingOptions::default(),
+        };
+        let socket = EstablishedSocket::new(
+            cb,
+            fd,
+            inner.dead_socket_tx.clone(),
+            inner.timer_origin,
+        );
+        assert!(inner.established.insert(key, socket).is_none());
+        assert!(inner
+            .sockets
+            .insert(fd, Socket::Established { local, remote })
+            .is_none());
+        inner.report_opened(fd, local, remote);
+        Ok(fd)
+    }
 }
 
 enum Socket {
@@ -451,7 +1782,7 @@ pub struct Inner<RT: Runtime> {
     isn_generator: IsnGenerator,
 
     file_table: FileTable,
-    ephemeral_ports: EphemeralPorts,
+    ports: PortTable,
 
     // FD -> local port
     sockets: HashMap<FileDescriptor, Socket>,
@@ -462,37 +1793,345 @@ pub struct Inner<RT: Runtime> {
 
     rt: RT,
     arp: arp::Peer<RT>,
+    egress: Egress<RT>,
+    events: EventQueue,
+
+    // Engine-wide latency histograms, shared with every connection's control block.
+    latency: Rc<RefCell<TcpLatencyStats>>,
 
     dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
     dead_socket_handle: Option<SchedulerHandle>,
+
+    // Demultiplexing sees every segment, so its logging is sampled too.
+    log: Sampler,
+
+    groups: HashMap<FileDescriptor, GroupId>,
+    // Listener FD -> transform for the connections it accepts.
+    transforms: HashMap<FileDescriptor, TransformFactory>,
+    // Traffic generators, kept after their connection closes until the FD is recycled.
+    sources: HashMap<FileDescriptor, TrafficGenerator>,
+    sinks: HashMap<FileDescriptor, TrafficGenerator>,
+
+    shutting_down: bool,
+    // Connections the application shut down but hasn't closed, whose descriptors outlive them
+    // until it does.
+    shut_down: HashSet<FileDescriptor>,
+    // Listeners that stopped taking new connections, by local endpoint.
+    draining: HashMap<ipv4::Endpoint, Drain>,
+    // Connection hooks in the order they were added, and the established connections they know
+    // of, by FD.
+    hooks: Vec<RegisteredHooks>,
+    next_hooks_id: u64,
+    connections: HashMap<FileDescriptor, ConnectionInfo>,
+    next_connection_id: u64,
+    // When the current second of closed-port RSTs started, and how many have gone out in it.
+    rst_window: (Instant, u32),
+    num_rsts_suppressed: u64,
+    option_stats: TcpOptionStats,
+    // Connection timers round their deadlines up to buckets counted from here.
+    timer_origin: Instant,
+    // Bumped whenever an established connection is torn down.
+    num_closed: Rc<WatchedValue<u64>>,
+}
+
+struct RegisteredHooks {
+    id: HooksId,
+    // The first connection established after they were added; they don't hear about earlier ones.
+    first_connection: u64,
+    hooks: Rc<dyn ConnectionHooks>,
+}
+
+struct Drain {
+    // The listener's descriptor, for the event.
+    fd: FileDescriptor,
+    // Whether its connections are all gone and the event raised.
+    done: bool,
 }
 
+const SHUTTING_DOWN: Fail = Fail::Invalid {
+    details: "Engine is shutting down",
+};
+
 impl<RT: Runtime> Inner<RT> {
     fn new(
         rt: RT,
         arp: arp::Peer<RT>,
         file_table: FileTable,
+        ports: PortTable,
+        egress: Egress<RT>,
+        events: EventQueue,
         dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
     ) -> Self {
+        let log = Sampler::new("TCP demux".to_string(), rt.tcp_options().log_sampling);
+        let now = rt.now();
         Self {
             isn_generator: IsnGenerator::new(rt.rng_gen()),
             file_table,
-            ephemeral_ports: EphemeralPorts::new(&rt),
+            ports,
             sockets: HashMap::new(),
             passive: HashMap::new(),
             connecting: HashMap::new(),
             established: HashMap::new(),
             rt,
             arp,
+            egress,
+            events,
+            latency: Rc::new(RefCell::new(TcpLatencyStats::default())),
             dead_socket_tx,
             dead_socket_handle: None,
+            log,
+            groups: HashMap::new(),
+            transforms: HashMap::new(),
+            sources: HashMap::new(),
+            sinks: HashMap::new(),
+            shutting_down: false,
+            shut_down: HashSet::new(),
+            draining: HashMap::new(),
+            hooks: Vec::new(),
+            next_hooks_id: 0,
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            rst_window: (now, 0),
+            num_rsts_suppressed: 0,
+            option_stats: TcpOptionStats::default(),
+            timer_origin: now,
+            num_closed: Rc::new(WatchedValue::new(0)),
+        }
+    }
+
+    /// Give up on an active open the application will never see: a handshake in progress (or
+    /// failed) is dropped, and a connection it already established is reset.
+    fn abandon_connect(&mut self, fd: FileDescriptor) {
+        let local = match self.sockets.remove(&fd) {
+            Some(Socket::Connecting { local, remote }) => {
+                if let Some(mut socket) = self.connecting.remove(&(local, remote)) {
+                    // The handshake may have finished without anyone polling for it.
+                    let mut ctx = Context::from_waker(noop_waker_ref());
+                    if let Poll::Ready(Ok(cb)) = socket.poll_result(&mut ctx) {
+                        cb.abort();
+                    }
+                }
+                local
+            },
+            Some(Socket::Established { local, remote }) => {
+                if let Some(socket) = self.established.remove(&(local, remote)) {
+                    self.report_closed(fd, &socket);
+                    socket.abort();
+                }
+                local
+            },
+            // The attempt failed before it got going.
+            Some(Socket::Inactive { .. }) => {
+                self.groups.remove(&fd);
+                self.file_table.free(fd);
+                return;
+            },
+            Some(socket) => {
+                self.sockets.insert(fd, socket);
+                return;
+            },
+            None => return,
+        };
+        self.groups.remove(&fd);
+        self.ports.release(PortProtocol::Tcp, local.port, fd);
+        self.file_table.free(fd);
+    }
+
+    // Hand back the descriptor and local port of a connection that was reset from under the
+    // application, which has no socket left to close.
+    fn release_aborted(&mut self, fd: FileDescriptor, local: ipv4::Endpoint) {
+        self.ports.release(PortProtocol::Tcp, local.port, fd);
+        self.file_table.free(fd);
+        self.shut_down.remove(&fd);
+        self.sources.remove(&fd);
+        self.sinks.remove(&fd);
+    }
+
+    // Tear down the listener `fd` on `local`, resetting whatever it hasn't handed out yet.
+    fn close_listener(&mut self, fd: FileDescriptor, local: ipv4::Endpoint) {
+        if let Some(mut passive) = self.passive.remove(&local) {
+            passive.close();
         }
+        self.sockets.remove(&fd);
+        self.draining.remove(&local);
+        self.transforms.remove(&fd);
+        self.groups.remove(&fd);
+        self.ports.release(PortProtocol::Tcp, local.port, fd);
+        self.file_table.free(fd);
     }
 
-    fn receive(&mut self, ip_hdr: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
+    /// Reset the connection `fd` refers to, as long as it's still the one between `key`'s
+    /// endpoints: the application may have closed it and reused the descriptor since.
+    fn abort_established(&mut self, fd: FileDescriptor, key: (ipv4::Endpoint, ipv4::Endpoint)) {
+        match self.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) if (*local, *remote) == key => (),
+            _ => return,
+        }
+        self.sockets.remove(&fd);
+        self.groups.remove(&fd);
+        if let Some(socket) = self.established.remove(&key) {
+            self.report_closed(fd, &socket);
+            socket.abort();
+        }
+        self.release_aborted(fd, key.0);
+        self.num_closed.modify(|n| n + 1);
+    }
+
+    // Number a newly established connection and run the open hooks for it.
+    fn report_opened(&mut self, fd: FileDescriptor, local: ipv4::Endpoint, remote: ipv4::Endpoint) {
+        let conn = ConnectionInfo {
+            fd,
+            id: self.next_connection_id,
+            local,
+            remote,
+        };
+        self.next_connection_id += 1;
+        for h in &self.hooks {
+            h.hooks.on_open(&conn);
+        }
+        self.connections.insert(fd, conn);
+    }
+
+    // Count the options a newly established connection's peer left out of its handshake.
+    fn count_missing_options(&mut self, missing: TcpMissingOptions) {
+        let stats = &mut self.option_stats;
+        stats.missing_mss += missing.mss as u64;
+        stats.missing_window_scale += missing.window_scale as u64;
+        stats.missing_sack += missing.sack as u64;
+        stats.missing_timestamps += missing.timestamps as u64;
+    }
+
+    // Run the close hooks for the connection on `fd`, in the state it's being dropped in.
+    fn run_close_hooks(&mut self, fd: FileDescriptor, state: TcpState) {
+        let conn = match self.connections.remove(&fd) {
+            Some(conn) => conn,
+            None => return,
+        };
+        for h in self.hooks.iter().filter(|h| conn.id >= h.first_connection) {
+            h.hooks.on_close(&conn, state);
+        }
+    }
+
+    // Raise the event for an established connection that's being dropped, before an abort
+    // resets its state. Shutdown's `abort_all` doesn't bother, though it runs the close hooks.
+    fn report_closed(&mut self, fd: FileDescriptor, socket: &EstablishedSocket<RT>) {
+        let state = socket.state();
+        self.events.push(Event::TcpClosed {
+            fd,
+            local: socket.cb.local,
+            remote: socket.cb.remote,
+            state,
+        });
+        self.run_close_hooks(fd, state);
+        self.check_drained(socket.cb.local);
+    }
+
+    // Raise `Event::TcpDrained` if the listener on `local` is draining and has nothing left:
+    // no established connections from it, and no handshakes in progress or waiting for accept.
+    fn check_drained(&mut self, local: ipv4::Endpoint) {
+        let fd = match self.draining.get(&local) {
+            Some(drain) if !drain.done => drain.fd,
+            _ => return,
+        };
+        if let Some(passive) = self.passive.get(&local) {
+            let stats = passive.stats();
+            if stats.inflight + stats.ready > 0 {
+                return;
+            }
+        }
+        if self.established.keys().any(|(l, _)| *l == local) {
+            return;
+        }
+        self.draining.get_mut(&local).unwrap().done = true;
+        self.events.push(Event::TcpDrained { fd, local });
+    }
+
+    /// Reset and drop every established connection, cancelling their background work.
+    fn abort_all(&mut self) {
+        let fds: Vec<_> = self.connections.keys().cloned().collect();
+        for fd in fds {
+            let key = (self.connections[&fd].local, self.connections[&fd].remote);
+            if let Some(state) = self.established.get(&key).map(|s| s.state()) {
+                self.run_close_hooks(fd, state);
+            }
+        }
+        for (_, socket) in self.established.drain() {
+            socket.abort();
+        }
+        let aborted: Vec<_> = self
+            .sockets
+            .iter()
+            .filter_map(|(fd, s)| match s {
+                Socket::Established { local, .. } => Some((*fd, *local)),
+                _ => None,
+            })
+            .collect();
+        for (fd, local) in aborted {
+            self.sockets.remove(&fd);
+            self.release_aborted(fd, local);
+        }
+        self.forget_stale_groups();
+    }
+
+    // Drop the group memberships of sockets that are gone.
+    fn forget_stale_groups(&mut self) {
+        let sockets = &self.sockets;
+        self.groups.retain(|fd, _| sockets.contains_key(fd));
+    }
+
+    // The file descriptors and keys of `group`'s established connections.
+    fn group_connections<'a>(
+        &'a self,
+        group: GroupId,
+    ) -> impl Iterator<Item = (FileDescriptor, (ipv4::Endpoint, ipv4::Endpoint))> + 'a {
+        self.groups
+            .iter()
+            .filter(move |(_, g)| **g == group)
+            .filter_map(move |(fd, _)| match self.sockets.get(fd) {
+                Some(Socket::Established { local, remote }) => Some((*fd, (*local, *remote))),
+                _ => None,
+            })
+    }
+
+    fn receive(
+        &mut self,
+        ip_hdr: &Ipv4Header,
+        buf: RT::Buf,
+        rx_time: Instant,
+    ) -> Result<(), Fail> {
         let tcp_options = self.rt.tcp_options();
-        let (tcp_hdr, data) = TcpHeader::parse(ip_hdr, buf, tcp_options.rx_checksum_offload)?;
-        debug!("TCP received {:?}", tcp_hdr);
+        let checksum_offload = tcp_options.rx_checksum_offload;
+        let (tcp_hdr, data) = if tcp_options.strict_option_parsing {
+            let mut skipped = vec![];
+            let r = TcpHeader::parse_strict(ip_hdr, buf, checksum_offload, &mut skipped);
+            for option in skipped {
+                sampled!(
+                    self.log,
+                    self.rt.now(),
+                    Level::Warn,
+                    "Skipping {:?} TCP option of kind {} with length {} from {}",
+                    option.problem,
+                    option.kind,
+                    option.length,
+                    ip_hdr.src_addr
+                );
+                let counts = match option.problem {
+                    TcpOptionProblem::Unknown => &mut self.option_stats.unknown,
+                    TcpOptionProblem::Malformed => &mut self.option_stats.malformed,
+                };
+                *counts.entry(option.kind).or_insert(0) += 1;
+            }
+            r?
+        } else {
+            TcpHeader::parse(ip_hdr, buf, checksum_offload)?
+        };
+        sampled!(
+            self.log,
+            self.rt.now(),
+            Level::Debug,
+            "TCP received {}",
+            tcp_hdr
+        );
         let local = ipv4::Endpoint::new(ip_hdr.dst_addr, tcp_hdr.dst_port);
         let remote = ipv4::Endpoint::new(ip_hdr.src_addr, tcp_hdr.src_port);
 
@@ -505,27 +2144,84 @@ impl<RT: Runtime> Inner<RT> {
         let key = (local, remote);
 
         if let Some(s) = self.established.get(&key) {
-            debug!("Routing to established connection: {:?}", key);
-            s.receive(&tcp_hdr, data);
+            trace!("Routing to established connection: {:?}", key);
+            s.receive(&tcp_hdr, data, rx_time);
             return Ok(());
         }
         if let Some(s) = self.connecting.get_mut(&key) {
-            debug!("Routing to connecting connection: {:?}", key);
+            trace!("Routing to connecting connection: {:?}", key);
             s.receive(&tcp_hdr);
             return Ok(());
         }
+        if let Some(shard) = tcp_options.shard {
+            if !shard.owns(&key.0, &key.1) {
+                return Err(Fail::Ignored {
+                    details: "Connection belongs to another shard",
+                });
+            }
+        }
         let (local, _) = key;
-        if let Some(s) = self.passive.get_mut(&local) {
-            debug!("Routing to passive connection: {:?}", local);
-            return s.receive(ip_hdr, &tcp_hdr);
+        let draining = self.draining.contains_key(&local);
+        match self.passive.get_mut(&local) {
+            // A draining listener finishes the handshakes it started but takes no new SYNs.
+            Some(_) if draining && tcp_hdr.syn && !tcp_hdr.ack => (),
+            Some(s) => {
+                trace!("Routing to passive connection: {:?}", local);
+                let r = s.receive(ip_hdr, &tcp_hdr);
+                if let Err(Fail::ConnectionRefused {}) = r {
+                    // The backlog is full.
+                    if tcp_options.reset_on_backlog_overflow {
+                        self.send_rst(&local, &remote)?;
+                    }
+                }
+                return r;
+            },
+            None => (),
         }
 
-        // The packet isn't for an open port; send a RST segment.
-        debug!("Sending RST for {:?}, {:?}", local, remote);
+        if tcp_options.listen_only {
+            return Err(Fail::Ignored {
+                details: "TCP is in listen-only mode",
+            });
+        }
+
+        // The packet isn't for an open port; send a RST segment, if the policy allows.
+        if !self.closed_port_rst_permitted(tcp_options.closed_port_rst) {
+            self.num_rsts_suppressed += 1;
+            return Err(Fail::Ignored {
+                details: "RST suppressed",
+            });
+        }
+        sampled!(
+            self.log,
+            self.rt.now(),
+            Level::Debug,
+            "Sending RST for {:?}, {:?}",
+            local,
+            remote
+        );
         self.send_rst(&local, &remote)?;
         Ok(())
     }
 
+    // Whether a RST for a closed port may go out now, counting it against the rate limit if so.
+    fn closed_port_rst_permitted(&mut self, policy: RstPolicy) -> bool {
+        let per_second = match policy {
+            RstPolicy::Always => return true,
+            RstPolicy::Never => return false,
+            RstPolicy::RateLimited { per_second } => per_second,
+        };
+        let now = self.rt.now();
+        if now >= self.rst_window.0 + Duration::from_secs(1) {
+            self.rst_window = (now, 0);
+        }
+        if self.rst_window.1 >= per_second {
+            return false;
+        }
+        self.rst_window.1 += 1;
+        true
+    }
+
     fn send_rst(&mut self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> Result<(), Fail> {
         // TODO: Make this work pending on ARP resolution if needed.
         let remote_link_addr =
@@ -538,18 +2234,10 @@ impl<RT: Runtime> Inner<RT> {
         let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
         tcp_hdr.rst = true;
 
-        let segment = TcpSegment {
-            ethernet2_hdr: Ethernet2Header {
-                dst_addr: remote_link_addr,
-                src_addr: self.rt.local_link_addr(),
-                ether_type: EtherType2::Ipv4,
-            },
-            ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
-            tcp_hdr,
-            data: RT::Buf::empty(),
-            tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload,
-        };
-        self.rt.transmit(segment);
+        let segment = PacketBuilder::new(&self.rt, remote_link_addr, remote.addr)
+            .src_addr(local.addr)
+            .tcp(tcp_hdr, RT::Buf::empty(), self.rt.tcp_options().tx_checksum_offload);
+        self.egress.transmit(segment);
 
         Ok(())
     }
@@ -566,6 +2254,7 @@ impl<RT: Runtime> Inner<RT> {
                     details: "Socket not connecting",
                 }))
             },
+            None if self.shutting_down => return Poll::Ready(Err(SHUTTING_DOWN)),
             None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
         };
 
@@ -586,12 +2275,35 @@ impl<RT: Runtime> Inner<RT> {
         self.connecting.remove(&key);
 
         let cb = result?;
-        let socket = EstablishedSocket::new(cb, fd, self.dead_socket_tx.clone());
+        self.count_missing_options(cb.negotiated().missing);
+        let socket = EstablishedSocket::new(
+            cb,
+            fd,
+            self.dead_socket_tx.clone(),
+            self.timer_origin,
+        );
         assert!(self.established.insert(key, socket).is_none());
         let (local, remote) = key;
         self.sockets
             .insert(fd, Socket::Established { local, remote });
+        self.report_opened(fd, local, remote);
 
         Poll::Ready(Ok(()))
     }
 }
+
+// Abandons `connect_any`'s attempts however it ends, including by being dropped. The winner is
+// taken out of `fds` first.
+struct ConnectAttempts<RT: Runtime> {
+    inner: Rc<RefCell<Inner<RT>>>,
+    fds: Vec<FileDescriptor>,
+}
+
+impl<RT: Runtime> Drop for ConnectAttempts<RT> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        for &fd in &self.fds {
+            inner.abandon_connect(fd);
+        }
+    }
+}
@@ -4,13 +4,21 @@ use crate::{
     fail::Fail,
     runtime::RuntimeBuf,
     protocols::{
-        ethernet2::frame::{
-            Ethernet2Header,
+        ethernet2::{
+            frame::{
+                Ethernet2Header,
+                ETHERNET2_HEADER_SIZE,
+            },
+            MacAddress,
         },
         ip,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
+        ipv4::{
+            adjust_checksum,
+            datagram::{
+                Ipv4Header,
+                Ipv4Protocol2,
+                IPV4_HEADER_SIZE,
+            },
         },
         tcp::SeqNumber,
     },
@@ -34,6 +42,9 @@ pub const MIN_TCP_HEADER_SIZE: usize = 20;
 pub const MAX_TCP_HEADER_SIZE: usize = 60;
 pub const MAX_TCP_OPTIONS: usize = 5;
 
+/// `Fail::Malformed` details for a segment whose checksum is wrong.
+pub const CHECKSUM_MISMATCH: &str = "TCP checksum mismatch";
+
 pub struct TcpSegment<T: RuntimeBuf> {
     pub ethernet2_hdr: Ethernet2Header,
     pub ipv4_hdr: Ipv4Header,
@@ -84,6 +95,86 @@ impl<T: RuntimeBuf> PacketBuf<T> for TcpSegment<T> {
     }
 }
 
+const ACK_TCP_OFFSET: usize = ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE;
+const ACK_FRAME_SIZE: usize = ACK_TCP_OFFSET + MIN_TCP_HEADER_SIZE;
+
+/// A pure ACK kept serialized, so a connection sending a stream of them patches the sequence
+/// numbers and window into the last one (fixing up the checksum incrementally, RFC 1624) rather
+/// than rebuilding and re-checksumming its headers each time.
+#[derive(Clone)]
+pub struct AckTemplate {
+    frame: [u8; ACK_FRAME_SIZE],
+    tx_checksum_offload: bool,
+}
+
+impl AckTemplate {
+    /// Whether `header` can be sent from a template: an ACK with no other flags and no options.
+    pub fn fits(header: &TcpHeader) -> bool {
+        header.ack
+            && !(header.ns
+                || header.cwr
+                || header.ece
+                || header.urg
+                || header.psh
+                || header.rst
+                || header.syn
+                || header.fin)
+            && header.urgent_pointer == 0
+            && header.num_options == 0
+    }
+
+    /// Serialize `segment`, which must be empty and have a header that `fits`.
+    pub fn new<T: RuntimeBuf>(segment: &TcpSegment<T>) -> Self {
+        assert!(Self::fits(&segment.tcp_hdr) && segment.data.is_empty());
+        let mut frame = [0u8; ACK_FRAME_SIZE];
+        segment.write_header(&mut frame[..]);
+        Self {
+            frame,
+            tx_checksum_offload: segment.tx_checksum_offload,
+        }
+    }
+
+    pub fn tx_checksum_offload(&self) -> bool {
+        self.tx_checksum_offload
+    }
+
+    /// Turn the template into `header` sent to `dst_addr`. Only the link address, sequence
+    /// numbers and window can differ from what the template was made from.
+    pub fn update(&mut self, dst_addr: MacAddress, header: &TcpHeader) {
+        assert!(Self::fits(header));
+        self.frame[0..6].copy_from_slice(&dst_addr.octets());
+        let tcp = &mut self.frame[ACK_TCP_OFFSET..];
+        let mut old = [0u8; 12];
+        old.copy_from_slice(&tcp[4..16]);
+        NetworkEndian::write_u32(&mut tcp[4..8], header.seq_num.0);
+        NetworkEndian::write_u32(&mut tcp[8..12], header.ack_num.0);
+        NetworkEndian::write_u16(&mut tcp[14..16], header.window_size);
+        if !self.tx_checksum_offload {
+            let checksum = NetworkEndian::read_u16(&tcp[16..18]);
+            let checksum = adjust_checksum(checksum, &old[..], &tcp[4..16]);
+            NetworkEndian::write_u16(&mut tcp[16..18], checksum);
+        }
+    }
+}
+
+impl<T: RuntimeBuf> PacketBuf<T> for AckTemplate {
+    fn header_size(&self) -> usize {
+        ACK_FRAME_SIZE
+    }
+
+    fn write_header(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.frame[..]);
+    }
+
+    fn body_size(&self) -> usize {
+        0
+    }
+
+    fn take_body(self) -> Option<T> {
+        None
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SelectiveAcknowlegement {
     pub begin: SeqNumber,
@@ -106,6 +197,36 @@ pub enum TcpOptions2 {
     },
 }
 
+/// Why `TcpHeader::parse_strict` passed over an option.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpOptionProblem {
+    /// A kind we don't implement.
+    Unknown,
+    /// A kind we implement with the wrong length, or any option that runs past the header.
+    Malformed,
+}
+
+/// An option `TcpHeader::parse_strict` passed over.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SkippedTcpOption {
+    pub kind: u8,
+    /// The option's length byte, or zero if the header ended before it.
+    pub length: u8,
+    pub problem: TcpOptionProblem,
+}
+
+// Whether `length` is right for an option of `kind`, or `None` if we don't know the kind.
+fn option_length_valid(kind: u8, length: u8) -> Option<bool> {
+    match kind {
+        2 => Some(length == 4),
+        3 => Some(length == 3),
+        4 => Some(length == 2),
+        5 => Some(matches!(length, 10 | 18 | 26 | 34)),
+        8 => Some(length == 10),
+        _ => None,
+    }
+}
+
 impl TcpOptions2 {
     fn compute_size(&self) -> usize {
         use TcpOptions2::*;
@@ -229,10 +350,32 @@ impl TcpHeader {
         }
     }
 
+    /// Parse the header at the start of `buf`, returning it and the data after it. An unknown
+    /// or malformed option fails the parse.
     pub fn parse<T: RuntimeBuf>(
+        ipv4_header: &Ipv4Header,
+        buf: T,
+        rx_checksum_offload: bool,
+    ) -> Result<(Self, T), Fail> {
+        Self::parse_options(ipv4_header, buf, rx_checksum_offload, None)
+    }
+
+    /// Like `parse`, but options we don't know or whose length is wrong are passed over and
+    /// added to `skipped`. An option that runs past the header ends the options there.
+    pub fn parse_strict<T: RuntimeBuf>(
+        ipv4_header: &Ipv4Header,
+        buf: T,
+        rx_checksum_offload: bool,
+        skipped: &mut Vec<SkippedTcpOption>,
+    ) -> Result<(Self, T), Fail> {
+        Self::parse_options(ipv4_header, buf, rx_checksum_offload, Some(skipped))
+    }
+
+    fn parse_options<T: RuntimeBuf>(
         ipv4_header: &Ipv4Header,
         mut buf: T,
         rx_checksum_offload: bool,
+        mut skipped: Option<&mut Vec<SkippedTcpOption>>,
     ) -> Result<(Self, T), Fail> {
         if buf.len() < MIN_TCP_HEADER_SIZE {
             return Err(Fail::Malformed {
@@ -280,7 +423,7 @@ impl TcpHeader {
             let checksum = NetworkEndian::read_u16(&hdr_buf[16..18]);
             if checksum != tcp_checksum(ipv4_header, &hdr_buf[..], &data_buf[..]) {
                 return Err(Fail::Malformed {
-                    details: "TCP checksum mismatch",
+                    details: CHECKSUM_MISMATCH,
                 });
             }
         }
@@ -291,9 +434,35 @@ impl TcpHeader {
         let mut option_list = [TcpOptions2::NoOperation; MAX_TCP_OPTIONS];
 
         if data_offset > MIN_TCP_HEADER_SIZE {
-            let mut option_rdr = Cursor::new(&hdr_buf[MIN_TCP_HEADER_SIZE..data_offset]);
-            while (option_rdr.position() as usize) < data_offset - MIN_TCP_HEADER_SIZE {
+            let options_buf = &hdr_buf[MIN_TCP_HEADER_SIZE..data_offset];
+            let mut option_rdr = Cursor::new(options_buf);
+            while (option_rdr.position() as usize) < options_buf.len() {
                 let option_kind = option_rdr.read_u8()?;
+                if let (Some(skipped), true) = (skipped.as_mut(), option_kind > 1) {
+                    // Check the option's length up front, so that what we can't use is passed
+                    // over rather than failing the parse.
+                    let start = option_rdr.position() as usize - 1;
+                    let length = options_buf.get(start + 1).copied().unwrap_or(0);
+                    let fits = length >= 2 && start + length as usize <= options_buf.len();
+                    let problem = match option_length_valid(option_kind, length) {
+                        Some(true) if fits => None,
+                        None if fits => Some(TcpOptionProblem::Unknown),
+                        _ => Some(TcpOptionProblem::Malformed),
+                    };
+                    if let Some(problem) = problem {
+                        skipped.push(SkippedTcpOption {
+                            kind: option_kind,
+                            length,
+                            problem,
+                        });
+                        // Without a length, there's no telling where the next option starts.
+                        if !fits {
+                            break;
+                        }
+                        option_rdr.set_position((start + length as usize) as u64);
+                        continue;
+                    }
+                }
                 let option = match option_kind {
                     0 => break,
                     1 => continue,
@@ -496,6 +665,27 @@ impl TcpHeader {
         self.option_list[self.num_options] = option;
         self.num_options += 1;
     }
+
+    /// The blocks of the first SACK option, if there is one.
+    pub fn sack_blocks(&self) -> &[SelectiveAcknowlegement] {
+        for option in self.iter_options() {
+            if let TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } = option {
+                return &sacks[..*num_sacks];
+            }
+        }
+        &[]
+    }
+
+    /// The first timestamp option's TSval and TSecr, if there is one.
+    pub fn timestamp(&self) -> Option<(u32, u32)> {
+        self.iter_options().find_map(|option| match *option {
+            TcpOptions2::Timestamp {
+                sender_timestamp,
+                echo_timestamp,
+            } => Some((sender_timestamp, echo_timestamp)),
+            _ => None,
+        })
+    }
 }
 
 fn tcp_checksum(ipv4_header: &Ipv4Header, header: &[u8], data: &[u8]) -> u16 {
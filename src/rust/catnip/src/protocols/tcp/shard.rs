@@ -0,0 +1,109 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Spreading one address's TCP connections over several engines, like `SO_REUSEPORT`.
+//!
+//! With one engine per core, every engine binds and listens on the same ports, and each is
+//! configured with its own `Shard`. A hash of a connection's 4-tuple decides which engine owns
+//! it: the others ignore its segments instead of accepting or resetting them, and active opens
+//! pick a local port that hashes back to the engine making them. Whatever receives frames from
+//! the device calls `steer` to find the engine each TCP frame belongs to.
+
+use crate::protocols::{
+    ethernet2::frame::{
+        EtherType2,
+        ETHERNET2_HEADER_SIZE,
+    },
+    ipv4,
+    ipv4::datagram::{
+        Ipv4Protocol2,
+        IPV4_HEADER_SIZE,
+    },
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use std::net::Ipv4Addr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    pub fn new(index: usize, count: usize) -> Self {
+        assert!(index < count);
+        Self { index, count }
+    }
+
+    pub fn owns(&self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> bool {
+        shard_of(local, remote, self.count) == self.index
+    }
+}
+
+/// The shard owning the connection between `local` and `remote`, out of `count`. This only
+/// depends on its arguments, so every engine and the dispatcher agree on it.
+pub fn shard_of(local: &ipv4::Endpoint, remote: &ipv4::Endpoint, count: usize) -> usize {
+    hash_4tuple(
+        local.addr,
+        local.port.into(),
+        remote.addr,
+        remote.port.into(),
+    ) as usize
+        % count
+}
+
+/// The shard an inbound frame belongs to, out of `count`, or `None` if it isn't an unfragmented
+/// TCP segment. Those frames (ARP, ICMP, UDP, ...) are up to the dispatcher; ARP in particular is
+/// usually handed to every engine. This only looks at the fields it needs, so it doesn't validate
+/// the frame: the engine still does that.
+pub fn steer(frame: &[u8], count: usize) -> Option<usize> {
+    if frame.len() < ETHERNET2_HEADER_SIZE {
+        return None;
+    }
+    let ether_type = NetworkEndian::read_u16(&frame[12..14]);
+    if ether_type != EtherType2::Ipv4 as u16 {
+        return None;
+    }
+    let ip = &frame[ETHERNET2_HEADER_SIZE..];
+    if ip.len() < IPV4_HEADER_SIZE || ip[9] != Ipv4Protocol2::Tcp as u8 {
+        return None;
+    }
+    // Later fragments don't carry the TCP header.
+    if NetworkEndian::read_u16(&ip[6..8]) & 0x1fff != 0 {
+        return None;
+    }
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    if ihl < IPV4_HEADER_SIZE || ip.len() < ihl + 4 {
+        return None;
+    }
+    let src_addr = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_addr = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+    let src_port = NetworkEndian::read_u16(&ip[ihl..(ihl + 2)]);
+    let dst_port = NetworkEndian::read_u16(&ip[(ihl + 2)..(ihl + 4)]);
+    // The frame is inbound, so its destination is the local end.
+    Some(hash_4tuple(dst_addr, dst_port, src_addr, src_port) as usize % count)
+}
+
+// FNV-1a, which is cheap and the same in every process.
+fn hash_4tuple(
+    local_addr: Ipv4Addr,
+    local_port: u16,
+    remote_addr: Ipv4Addr,
+    remote_port: u16,
+) -> u32 {
+    let mut bytes = [0u8; 12];
+    bytes[0..4].copy_from_slice(&local_addr.octets());
+    NetworkEndian::write_u16(&mut bytes[4..6], local_port);
+    bytes[6..10].copy_from_slice(&remote_addr.octets());
+    NetworkEndian::write_u16(&mut bytes[10..12], remote_port);
+
+    let mut hash = 0x811c_9dc5u32;
+    for &b in &bytes {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
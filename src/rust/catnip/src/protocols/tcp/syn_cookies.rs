@@ -0,0 +1,97 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! SYN cookies (RFC 4987 3.6): the SYN+ACK's sequence number encodes what the handshake needs,
+//! so a listener can answer a SYN without keeping any state and rebuild it from the ACK that
+//! completes the handshake.
+//!
+//! The top 5 bits of a cookie count 64 second periods, the next 3 pick an MSS from a table, and
+//! the low 24 are a hash of the endpoints, the remote's ISN, the other bits and a secret. A cookie
+//! is good for the period it was made in and the next. Window scaling, SACK and timestamps don't
+//! fit, so connections opened from a cookie go without them.
+
+use crate::protocols::{
+    ipv4,
+    tcp::SeqNumber,
+};
+use crc::{
+    crc32,
+    Hasher32,
+};
+use std::{
+    hash::Hasher,
+    num::Wrapping,
+    time::Instant,
+};
+
+const PERIOD_SECS: u64 = 64;
+const MSS_TABLE: [usize; 8] = [536, 1200, 1300, 1400, 1440, 1460, 4312, 8960];
+
+pub struct SynCookieGenerator {
+    secret: u32,
+    epoch: Instant,
+}
+
+impl SynCookieGenerator {
+    pub fn new(secret: u32, epoch: Instant) -> Self {
+        Self { secret, epoch }
+    }
+
+    /// The ISN to answer a SYN with, rounding `mss` down to one the cookie can hold.
+    pub fn generate(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        remote_isn: SeqNumber,
+        mss: usize,
+        now: Instant,
+    ) -> SeqNumber {
+        let index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        let bits = (self.period(now) << 3) | index;
+        Wrapping((bits << 24) | self.hash(local, remote, remote_isn, bits))
+    }
+
+    /// Check the cookie an ACK returns, `ack_num - 1`, returning the MSS it holds if it's one we
+    /// made recently for this remote and its ISN, `seq_num - 1`.
+    pub fn validate(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        remote_isn: SeqNumber,
+        cookie: SeqNumber,
+        now: Instant,
+    ) -> Option<usize> {
+        let Wrapping(cookie) = cookie;
+        let bits = cookie >> 24;
+        if self.period(now).wrapping_sub(bits >> 3) & 0x1f > 1 {
+            return None;
+        }
+        if cookie & 0xff_ffff != self.hash(local, remote, remote_isn, bits) {
+            return None;
+        }
+        Some(MSS_TABLE[(bits & 0x7) as usize])
+    }
+
+    fn period(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.epoch).as_secs();
+        ((elapsed / PERIOD_SECS) & 0x1f) as u32
+    }
+
+    fn hash(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        remote_isn: SeqNumber,
+        bits: u32,
+    ) -> u32 {
+        let mut hash = crc32::Digest::new(crc32::IEEE);
+        hash.write_u32(remote.address().into());
+        hash.write_u16(remote.port().into());
+        hash.write_u32(local.address().into());
+        hash.write_u16(local.port().into());
+        hash.write_u32(remote_isn.0);
+        hash.write_u32(bits);
+        hash.write_u32(self.secret);
+        hash.sum32() & 0xff_ffff
+    }
+}
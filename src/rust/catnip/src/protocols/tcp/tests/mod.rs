@@ -1,18 +1,84 @@
 use crate::{
+    engine::Engine,
+    event::{
+        Event,
+        LabeledEvent,
+    },
+    fail::Fail,
+    file_table::FileDescriptor,
     protocols::{
+        ethernet2::{
+            frame::MIN_FRAME_SIZE,
+            EtherType2,
+            Ethernet2Header,
+        },
         ip,
         ipv4,
+        ipv4::{
+            filter::{
+                Action,
+                Direction,
+                Rule,
+                TCP_ACK,
+                TCP_SYN,
+            },
+            Ipv4Header,
+            Ipv4Protocol2,
+        },
+        tcp::{
+            constants::{
+                FALLBACK_MSS,
+                MAX_DELAYED_ACK_TIMEOUT,
+                MAX_WINDOW_SCALE,
+                MIN_MSS,
+            },
+            segment::{
+                AckTemplate,
+                TcpHeader,
+                TcpOptions2,
+                TcpSegment,
+            },
+            hooks::{
+                ConnectionHooks,
+                ConnectionInfo,
+            },
+            steer,
+            Framing,
+            GroupId,
+            Options,
+            RstPolicy,
+            SendStatus,
+            SendWindow,
+            Shard,
+            State,
+            StreamTransform,
+            SynCookiePolicy,
+            TrafficPattern,
+        },
+    },
+    runtime::{
+        PacketBuf,
+        Runtime,
+        RuntimeBuf,
+    },
+    sync::{
+        Bytes,
+        BytesMut,
     },
-    runtime::Runtime,
-    sync::BytesMut,
     test_helpers,
+    test_helpers::TestRuntime,
 };
 use futures::task::noop_waker_ref;
 use must_let::must_let;
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     convert::TryFrom,
     future::Future,
+    net::Shutdown,
+    num::Wrapping,
     pin::Pin,
+    rc::Rc,
     task::{
         Context,
         Poll,
@@ -31,32 +97,7 @@ fn test_connect() {
     let mut alice = test_helpers::new_alice(now);
     let mut bob = test_helpers::new_bob(now);
 
-    // Establish the connection between the two peers.
-    let listen_port = ip::Port::try_from(80).unwrap();
-    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
-
-    let listen_fd = bob.tcp_socket();
-    bob.tcp_bind(listen_fd, listen_addr).unwrap();
-    bob.tcp_listen(listen_fd, 1).unwrap();
-    let mut accept_future = bob.tcp_accept(listen_fd);
-
-    let alice_fd = alice.tcp_socket();
-    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
-
-    // Send the SYN from Alice to Bob
-    alice.rt().poll_scheduler();
-    bob.receive(alice.rt().pop_frame()).unwrap();
-
-    // Send the SYN+ACK from Bob to Alice
-    bob.rt().poll_scheduler();
-    alice.receive(bob.rt().pop_frame()).unwrap();
-
-    // Send the ACK from Alice to Bob
-    alice.rt().poll_scheduler();
-    bob.receive(alice.rt().pop_frame()).unwrap();
-
-    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
-    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
 
     // Send data from Alice to Bob
     let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
@@ -64,12 +105,22 @@ fn test_connect() {
     must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut write_future), &mut ctx));
     alice.rt().poll_scheduler();
 
+    // The window was open, so the push skipped the send queue entirely.
+    let send_queue_time = alice.tcp_latency_stats(alice_fd).unwrap().send_queue_time;
+    assert_eq!(send_queue_time.len(), 1);
+    assert_eq!(send_queue_time.max(), Some(Duration::from_secs(0)));
+
     // Receive it on Bob's side.
     bob.receive(alice.rt().pop_frame()).unwrap();
     let mut pop_future = bob.tcp_pop(bob_fd);
     must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
     assert_eq!(received_buf, buf);
 
+    let throughput = bob.tcp_throughput_stats(bob_fd).unwrap();
+    assert_eq!(throughput.bytes_delivered, 32);
+    assert_eq!(throughput.segments_delivered, 1);
+    assert!(alice.tcp_throughput_stats(alice_fd).unwrap().bytes_transmitted >= 32);
+
     // Test closing the socket from the client
     alice.close(alice_fd).unwrap();
 
@@ -93,4 +144,2488 @@ fn test_connect() {
 
     bob.receive(alice.rt().pop_frame()).unwrap();
     bob.rt().poll_scheduler();
+
+    // Bob's delayed ACK for Alice's data is reflected in the engine-wide stats.
+    assert!(!bob.stats().tcp_latency.ack_latency.is_empty());
+}
+
+#[test]
+fn test_filter_reject() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    // Bob refuses new connections from Alice even though he's listening.
+    let rule = bob.filter().push(
+        Rule::new(Action::Reject)
+            .direction(Direction::Ingress)
+            .src(test_helpers::ALICE_IPV4, 32)
+            .tcp_flags(TCP_SYN | TCP_ACK, TCP_SYN),
+    );
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(alice.rt().pop_frame()));
+    assert_eq!(bob.filter().hits(rule), Some(1));
+
+    // The RST goes out immediately and fails the connect.
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+#[test]
+fn test_migrate() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_fd = listen(&mut bob);
+    let (alice_fd, bob_fd) = connect(&mut alice, &mut bob, listen_fd);
+
+    // Bob receives some data but doesn't read it before he's snapshotted.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    let snapshot = bob.snapshot();
+    assert_eq!(snapshot.tcp_listeners.len(), 1);
+    assert_eq!(snapshot.tcp_connections.len(), 1);
+    assert_eq!(snapshot.tcp_connections[0].receiver.recv_queue, vec![vec![0x5a; 32]]);
+    drop(bob);
+
+    // A new engine with Bob's addresses picks the connection up.
+    let mut bob = test_helpers::new_bob(now);
+    let fds = bob.restore(&snapshot).unwrap();
+    let bob_fd = fds[&bob_fd];
+    assert!(fds.contains_key(&listen_fd));
+
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received_buf, buf);
+
+    let reply = BytesMut::from(&vec![0xa5; 16][..]).freeze();
+    let mut push_future = bob.tcp_push(bob_fd, reply.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(received_buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received_buf, reply);
+
+    // The restored listening socket still accepts.
+    let mut accept_future = bob.tcp_accept(fds[&listen_fd]);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+}
+
+/// The address Bob listens on in `listen`.
+fn bob_addr() -> ipv4::Endpoint {
+    ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap())
+}
+
+/// Bind a listening socket on Bob with a backlog of one.
+fn listen(bob: &mut Engine<TestRuntime>) -> FileDescriptor {
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, bob_addr()).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    listen_fd
+}
+
+/// Connect Alice to Bob's `listen_fd` and run the three-way handshake, returning Alice's and
+/// Bob's ends of the connection.
+fn connect(
+    alice: &mut Engine<TestRuntime>,
+    bob: &mut Engine<TestRuntime>,
+    listen_fd: FileDescriptor,
+) -> (FileDescriptor, FileDescriptor) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, bob_addr());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    (alice_fd, bob_fd)
+}
+
+/// `listen` followed by `connect`.
+fn establish(
+    alice: &mut Engine<TestRuntime>,
+    bob: &mut Engine<TestRuntime>,
+) -> (FileDescriptor, FileDescriptor) {
+    let listen_fd = listen(bob);
+    connect(alice, bob, listen_fd)
+}
+
+/// A segment from Alice to Bob, or from Bob to Alice if `to_alice` is set.
+fn forged_segment(tcp_hdr: TcpHeader, to_alice: bool) -> TcpSegment<Bytes> {
+    let (src, dst) = if to_alice {
+        (
+            (test_helpers::BOB_MAC, test_helpers::BOB_IPV4),
+            (test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4),
+        )
+    } else {
+        (
+            (test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4),
+            (test_helpers::BOB_MAC, test_helpers::BOB_IPV4),
+        )
+    };
+    TcpSegment {
+        ethernet2_hdr: Ethernet2Header {
+            dst_addr: dst.0,
+            src_addr: src.0,
+            ether_type: EtherType2::Ipv4,
+        },
+        ipv4_hdr: Ipv4Header::new(src.1, dst.1, Ipv4Protocol2::Tcp),
+        tcp_hdr,
+        data: Bytes::empty(),
+        tx_checksum_offload: false,
+    }
+}
+
+fn parse_segment(frame: Bytes) -> TcpHeader {
+    let (_, payload) = Ethernet2Header::parse(frame).unwrap();
+    let (ipv4_hdr, payload) = Ipv4Header::parse(payload).unwrap();
+    TcpHeader::parse(&ipv4_hdr, payload, false).unwrap().0
+}
+
+#[test]
+fn test_hostile_syn_options() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    // A SYN asking for a window scale past RFC 1323's limit and an MSS of zero.
+    let alice_port = ip::Port::try_from(12345).unwrap();
+    let mut syn = TcpHeader::new(alice_port, listen_port);
+    syn.syn = true;
+    syn.seq_num = Wrapping(1000);
+    syn.window_size = 0xffff;
+    syn.push_option(TcpOptions2::WindowScale(200));
+    syn.push_option(TcpOptions2::MaximumSegmentSize(0));
+    alice.rt().transmit(forged_segment(syn, false));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    bob.rt().poll_scheduler();
+    let syn_ack = parse_segment(bob.rt().pop_frame());
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    let mut ack = TcpHeader::new(alice_port, listen_port);
+    ack.ack = true;
+    ack.seq_num = Wrapping(1001);
+    ack.ack_num = syn_ack.seq_num + Wrapping(1);
+    ack.window_size = 0xffff;
+    alice.rt().transmit(forged_segment(ack, false));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    let snapshot = bob.snapshot();
+    let sender = &snapshot.tcp_connections[0].sender;
+    assert_eq!(sender.window_scale, MAX_WINDOW_SCALE);
+    assert_eq!(sender.mss, MIN_MSS);
+    assert_eq!(sender.window_size, 0xffff << MAX_WINDOW_SCALE);
+}
+
+#[test]
+fn test_strict_option_parsing() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    // The SYN gets patched below, which breaks its checksum.
+    bob.rt().set_tcp_options(|o| o.rx_checksum_offload = true);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    // The options start after the Ethernet, IPv4 and TCP headers, at byte 54. The MSS becomes an
+    // unknown kind, and the window scale's length runs past the header.
+    let alice_port = ip::Port::try_from(12345).unwrap();
+    let mut syn = TcpHeader::new(alice_port, listen_port);
+    syn.syn = true;
+    syn.seq_num = Wrapping(1000);
+    syn.window_size = 0xffff;
+    syn.push_option(TcpOptions2::MaximumSegmentSize(1460));
+    syn.push_option(TcpOptions2::WindowScale(2));
+    alice.rt().transmit(forged_segment(syn, false));
+    let mut frame = alice.rt().pop_frame()[..].to_vec();
+    assert_eq!((frame[54], frame[58], frame[59]), (2, 3, 3));
+    frame[54] = 254;
+    frame[59] = 9;
+
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(Bytes::from_slice(&frame[..])));
+    assert_eq!(bob.stats().tcp_options, Default::default());
+
+    bob.rt().set_tcp_options(|o| o.strict_option_parsing = true);
+    bob.receive(Bytes::from_slice(&frame[..])).unwrap();
+    bob.rt().poll_scheduler();
+    let syn_ack = parse_segment(bob.rt().pop_frame());
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    let mut ack = TcpHeader::new(alice_port, listen_port);
+    ack.ack = true;
+    ack.seq_num = Wrapping(1001);
+    ack.ack_num = syn_ack.seq_num + Wrapping(1);
+    ack.window_size = 0xffff;
+    alice.rt().transmit(forged_segment(ack, false));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    let stats = bob.stats().tcp_options;
+    assert_eq!(stats.unknown.into_iter().collect::<Vec<_>>(), vec![(254, 1)]);
+    assert_eq!(stats.malformed.into_iter().collect::<Vec<_>>(), vec![(3, 1)]);
+    assert_eq!((stats.missing_mss, stats.missing_window_scale), (1, 1));
+    // SACK and timestamps weren't offered, so they aren't missed.
+    assert_eq!((stats.missing_sack, stats.missing_timestamps), (0, 0));
+    let missing = bob.tcp_negotiated(fd).unwrap().missing;
+    assert!(missing.mss && missing.window_scale);
+    assert_eq!(bob.tcp_negotiated(fd).unwrap().mss, FALLBACK_MSS);
+}
+
+#[test]
+fn test_hostile_syn_ack_options() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    let mut alice = test_helpers::new_alice(now);
+    let bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    let syn = parse_segment(alice.rt().pop_frame());
+
+    let mut syn_ack = TcpHeader::new(listen_port, syn.src_port);
+    syn_ack.syn = true;
+    syn_ack.ack = true;
+    syn_ack.seq_num = Wrapping(5000);
+    syn_ack.ack_num = syn.seq_num + Wrapping(1);
+    syn_ack.window_size = 0xffff;
+    syn_ack.push_option(TcpOptions2::WindowScale(255));
+    syn_ack.push_option(TcpOptions2::MaximumSegmentSize(1));
+    bob.rt().transmit(forged_segment(syn_ack, true));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    let snapshot = alice.snapshot();
+    let sender = &snapshot.tcp_connections[0].sender;
+    assert_eq!(sender.window_scale, MAX_WINDOW_SCALE);
+    assert_eq!(sender.mss, MIN_MSS);
+}
+
+#[test]
+fn test_garbage_frames() {
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let mut syn = TcpHeader::new(ip::Port::try_from(12345).unwrap(), listen_port);
+    syn.syn = true;
+    syn.push_option(TcpOptions2::MaximumSegmentSize(1460));
+    alice.rt().transmit(forged_segment(syn, false));
+    let frame = alice.rt().pop_frame();
+
+    // Every truncation of a valid frame, then the frame with each byte flipped in turn. None of
+    // these may panic, whatever they return.
+    for len in 0..frame.len() {
+        let _ = bob.receive(Bytes::from_slice(&frame[..len]));
+    }
+    for i in 0..frame.len() {
+        let mut corrupt = frame[..].to_vec();
+        corrupt[i] ^= 0xff;
+        let _ = bob.receive(Bytes::from_slice(&corrupt[..]));
+        bob.rt().poll_scheduler();
+    }
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(Bytes::from_slice(&frame[..10])));
+    assert!(bob.stats().rx_dropped > frame.len() as u64);
+}
+
+#[test]
+fn test_listen_only() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt().set_tcp_options(|o| o.listen_only = true);
+
+    // Segments for ports nobody listens on are dropped without a RST.
+    let mut syn = TcpHeader::new(
+        ip::Port::try_from(12345).unwrap(),
+        ip::Port::try_from(81).unwrap(),
+    );
+    syn.syn = true;
+    alice.rt().transmit(forged_segment(syn, false));
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(alice.rt().pop_frame()));
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(80).unwrap());
+    let fd = bob.tcp_socket();
+    let mut connect_future = bob.tcp_connect(fd, remote);
+    must_let!(let Poll::Ready(Err(Fail::Unsupported { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+#[test]
+fn test_sharding() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bobs = vec![test_helpers::new_bob(now), test_helpers::new_bob(now)];
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    for (i, bob) in bobs.iter_mut().enumerate() {
+        bob.rt()
+            .set_tcp_options(|o| o.shard = Some(Shard::new(i, 2)));
+        let fd = bob.tcp_socket();
+        bob.tcp_bind(fd, listen_addr).unwrap();
+        bob.tcp_listen(fd, 8).unwrap();
+    }
+
+    // Both engines listen on the port, but only the one a SYN is steered to answers it.
+    let mut owners = vec![];
+    for port in 50000..50008 {
+        let mut syn = TcpHeader::new(
+            ip::Port::try_from(port).unwrap(),
+            ip::Port::try_from(80).unwrap(),
+        );
+        syn.syn = true;
+        alice.rt().transmit(forged_segment(syn, false));
+        let frame = alice.rt().pop_frame();
+        let owner = steer(&frame[..], 2).unwrap();
+        must_let!(let Err(Fail::Ignored { .. }) = bobs[1 - owner].receive(frame.clone()));
+        bobs[owner].receive(frame).unwrap();
+        bobs[owner].rt().poll_scheduler();
+        assert_eq!(bobs[owner].rt().num_outgoing(), 1);
+        assert_eq!(bobs[1 - owner].rt().num_outgoing(), 0);
+        bobs[owner].rt().pop_frame();
+        owners.push(owner);
+    }
+    assert!(owners.contains(&0) && owners.contains(&1));
+
+    // Active opens choose a local port whose replies are steered back to the same engine.
+    let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(80).unwrap());
+    for (i, bob) in bobs.iter_mut().enumerate() {
+        let fd = bob.tcp_socket();
+        let mut connect_future = bob.tcp_connect(fd, remote);
+        assert!(Future::poll(Pin::new(&mut connect_future), &mut ctx).is_pending());
+        bob.rt().poll_scheduler();
+        let syn = parse_segment(bob.rt().pop_frame());
+        assert!(syn.syn);
+
+        let mut syn_ack = TcpHeader::new(syn.dst_port, syn.src_port);
+        syn_ack.syn = true;
+        syn_ack.ack = true;
+        alice.rt().transmit(forged_segment(syn_ack, false));
+        assert_eq!(steer(&alice.rt().pop_frame()[..], 2), Some(i));
+    }
+}
+
+#[test]
+fn test_window_scaling() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt().set_tcp_options(|o| {
+        o.receive_window_size = 1 << 20;
+        o.window_scale = 0;
+    });
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+
+    // Bob offers the smallest scale that covers his window, but the SYN+ACK's own window can't
+    // be scaled.
+    let syn_ack_frame = bob.rt().pop_frame();
+    let syn_ack = parse_segment(syn_ack_frame.clone());
+    assert_eq!(syn_ack.window_size, 0xffff);
+    assert!(syn_ack
+        .iter_options()
+        .any(|o| matches!(o, TcpOptions2::WindowScale(5))));
+    alice.receive(syn_ack_frame).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert_eq!(alice.tcp_negotiated(alice_fd).unwrap().send_window_scale, 5);
+    assert_eq!(
+        alice.snapshot().tcp_connections[0].sender.window_size,
+        0xffff
+    );
+
+    // Bob's first ACK shows Alice the rest of his window.
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    now += Duration::from_secs(1);
+    bob.rt().advance_clock(now);
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let window_size = alice.snapshot().tcp_connections[0].sender.window_size;
+    assert_eq!(window_size, ((1 << 20) - 100) >> 5 << 5);
+}
+
+#[test]
+fn test_window_scaling_refused() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt()
+        .set_tcp_options(|o| o.receive_window_size = 1 << 20);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    // A SYN without a window scale option gets a SYN+ACK without one.
+    let alice_port = ip::Port::try_from(12345).unwrap();
+    let mut syn = TcpHeader::new(alice_port, listen_port);
+    syn.syn = true;
+    syn.seq_num = Wrapping(1000);
+    syn.window_size = 0xffff;
+    alice.rt().transmit(forged_segment(syn, false));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let syn_ack = parse_segment(bob.rt().pop_frame());
+    assert!(!syn_ack
+        .iter_options()
+        .any(|o| matches!(o, TcpOptions2::WindowScale(..))));
+
+    let mut ack = TcpHeader::new(alice_port, listen_port);
+    ack.ack = true;
+    ack.seq_num = Wrapping(1001);
+    ack.ack_num = syn_ack.seq_num + Wrapping(1);
+    ack.window_size = 0xffff;
+    alice.rt().transmit(forged_segment(ack, false));
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    // Neither side scales, so Bob's window stops at 64KB.
+    let snapshot = bob.snapshot();
+    let connection = &snapshot.tcp_connections[0];
+    assert_eq!(connection.sender.window_scale, 0);
+    assert_eq!(connection.receiver.window_scale, 0);
+    assert_eq!(connection.receiver.max_window_size, 0xffff);
+}
+
+#[test]
+fn test_negotiated() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_tcp_options(|o| o.window_scale = 7);
+    bob.rt().set_tcp_options(|o| {
+        o.window_scale = 3;
+        o.advertised_mss = 1000;
+    });
+
+    let listen_fd = listen(&mut bob);
+    let (alice_fd, bob_fd) = connect(&mut alice, &mut bob, listen_fd);
+
+    let negotiated = alice.tcp_negotiated(alice_fd).unwrap();
+    assert_eq!(negotiated.mss, 1000);
+    assert_eq!(negotiated.send_window_scale, 3);
+    assert_eq!(negotiated.receive_window_scale, 7);
+    assert!(!negotiated.sack_permitted);
+    assert!(!negotiated.timestamps);
+    assert!(!negotiated.ecn);
+
+    // Alice offers more than Bob's own link takes, so he clamps it to what he advertised.
+    let negotiated = bob.tcp_negotiated(bob_fd).unwrap();
+    assert_eq!(negotiated.mss, 1000);
+    assert_eq!(negotiated.send_window_scale, 7);
+    assert_eq!(negotiated.receive_window_scale, 3);
+
+    must_let!(let Err(Fail::Malformed { .. }) = bob.tcp_negotiated(listen_fd));
+}
+
+#[test]
+fn test_checksum_validation() {
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let mut syn = TcpHeader::new(
+        ip::Port::try_from(12345).unwrap(),
+        ip::Port::try_from(80).unwrap(),
+    );
+    syn.syn = true;
+    alice.rt().transmit(forged_segment(syn, false));
+    let mut corrupted = alice.rt().pop_frame()[..].to_vec();
+    // Flip the urgent pointer, which nothing else looks at.
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    let corrupted = Bytes::from_slice(&corrupted[..]);
+
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(corrupted.clone()));
+    let errors = bob.stats().rx_checksum_errors;
+    assert_eq!((errors.tcp, errors.udp, errors.icmpv4), (1, 0, 0));
+
+    // Trusting the device's validation, Bob answers the closed port with a RST.
+    bob.rt().set_tcp_options(|o| o.rx_checksum_offload = true);
+    bob.receive(corrupted).unwrap();
+    assert!(parse_segment(bob.rt().pop_frame()).rst);
+    assert_eq!(bob.stats().rx_checksum_errors.tcp, 1);
+}
+
+#[test]
+fn test_shutdown() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    // Shutting down starts a graceful close.
+    let mut shutdown = Box::pin(alice.shutdown(Duration::from_secs(1)));
+    assert!(Future::poll(shutdown.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    assert!(parse_segment(alice.rt().pop_frame()).fin);
+
+    // No new connections are started in the meantime.
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(fd, bob_addr());
+    must_let!(let Poll::Ready(Err(Fail::Invalid { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Bob never closes his end, so Alice resets the connection at the deadline.
+    alice.rt().advance_clock(now + Duration::from_secs(2));
+    alice.rt().poll_scheduler();
+    must_let!(let Poll::Ready(()) = Future::poll(shutdown.as_mut(), &mut ctx));
+    let mut frames = vec![];
+    while alice.rt().num_outgoing() > 0 {
+        frames.push(parse_segment(alice.rt().pop_frame()));
+    }
+    assert!(frames.last().unwrap().rst);
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_negotiated(alice_fd));
+    assert!(!alice.is_qd_valid(alice_fd));
+}
+
+#[test]
+fn test_connect_any() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    let port = |n| ip::Port::try_from(n).unwrap();
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    let mut no_candidates = Box::pin(alice.tcp_connect_any(&[]));
+    must_let!(let Poll::Ready(Err(Fail::Invalid { .. })) = Future::poll(no_candidates.as_mut(), &mut ctx));
+
+    // The first candidate never answers, the second refuses, and the third accepts.
+    let candidates = [
+        ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(80)),
+        ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, port(81)),
+        listen_addr,
+    ];
+    let mut connect_future = Box::pin(alice.tcp_connect_any(&candidates));
+    assert!(Future::poll(connect_future.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    assert_eq!(parse_segment(alice.rt().pop_frame()).dst_port, port(80));
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    // The next attempt starts once the first has had its head start.
+    let delay = alice.rt().tcp_options().connect_attempt_delay;
+    now += delay;
+    alice.rt().advance_clock(now);
+    assert!(Future::poll(connect_future.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    assert_eq!(parse_segment(syn.clone()).dst_port, port(81));
+    carrie.receive(syn).unwrap();
+    carrie.rt().poll_scheduler();
+    alice.receive(carrie.rt().pop_frame()).unwrap();
+
+    // A refusal starts the next attempt without waiting.
+    assert!(Future::poll(connect_future.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok((alice_fd, remote))) = Future::poll(connect_future.as_mut(), &mut ctx));
+    assert_eq!(remote, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    assert_eq!(alice.tcp_endpoints(alice_fd).unwrap().1, listen_addr);
+
+    // The abandoned first attempt stays quiet past its retransmission timeout.
+    now += alice.rt().tcp_options().handshake_timeout * 2;
+    alice.rt().advance_clock(now);
+    alice.rt().poll_scheduler();
+    while alice.rt().num_outgoing() > 0 {
+        let (eth_hdr, _) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+        assert_ne!(eth_hdr.dst_addr, test_helpers::CARRIE_MAC);
+    }
+}
+
+#[test]
+fn test_rx_timestamps() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+    assert_eq!(bob.tcp_last_received(bob_fd).unwrap(), None);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+
+    // Bob only gets around to the segment well after the device stamped it.
+    let rx_time = now + Duration::from_millis(5);
+    bob.rt().advance_clock(now + Duration::from_millis(50));
+    bob.receive_with_timestamp(alice.rt().pop_frame(), rx_time)
+        .unwrap();
+    assert_eq!(bob.tcp_last_received(bob_fd).unwrap(), Some(rx_time));
+    bob.rt().poll_scheduler();
+    let ack = bob.rt().pop_frame();
+    assert!(parse_segment(ack.clone()).ack);
+
+    // Alice's RTT sample comes from the ACK's timestamp, not from when she processed it.
+    alice.rt().advance_clock(now + Duration::from_millis(200));
+    alice
+        .receive_with_timestamp(ack, now + Duration::from_millis(10))
+        .unwrap();
+    let srtt = alice
+        .tcp_latency_stats(alice_fd)
+        .unwrap()
+        .srtt
+        .max()
+        .unwrap();
+    assert!(srtt >= Duration::from_millis(9), "{:?}", srtt);
+    assert!(srtt <= Duration::from_millis(11), "{:?}", srtt);
+}
+
+#[test]
+fn test_rto() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice
+        .rt()
+        .set_tcp_options(|o| o.min_rto = Duration::from_millis(200));
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    // Without timestamps the handshake isn't timed, so the RTO starts at a second.
+    assert_eq!(alice.tcp_srtt(alice_fd).unwrap(), None);
+    assert_eq!(alice.tcp_rto(alice_fd).unwrap(), Duration::from_secs(1));
+
+    // A 10ms round trip would give a 30ms RTO, which is raised to the minimum.
+    let buf = Bytes::from_slice(&[0x5a; 100]);
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut t = now + Duration::from_millis(10);
+    bob.rt().advance_clock(t);
+    bob.rt().poll_scheduler();
+    alice.rt().advance_clock(t);
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let srtt = alice.tcp_srtt(alice_fd).unwrap().unwrap();
+    assert!(srtt >= Duration::from_millis(9), "{:?}", srtt);
+    assert!(srtt <= Duration::from_millis(11), "{:?}", srtt);
+    assert_eq!(alice.tcp_rto(alice_fd).unwrap(), Duration::from_millis(200));
+
+    // The next segment is lost. The timeout doubles the RTO, and the ACK for the retransmission
+    // isn't sampled (Karn's algorithm), so it stays doubled.
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    alice.rt().pop_frame();
+    t += Duration::from_millis(200);
+    alice.rt().advance_clock(t);
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_rto(alice_fd).unwrap(), Duration::from_millis(400));
+    bob.rt().advance_clock(t);
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    t += Duration::from_millis(1);
+    bob.rt().advance_clock(t);
+    bob.rt().poll_scheduler();
+    alice.rt().advance_clock(t);
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_srtt(alice_fd).unwrap(), Some(srtt));
+    assert_eq!(alice.tcp_rto(alice_fd).unwrap(), Duration::from_millis(400));
+}
+
+#[test]
+fn test_groups() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let tenant = GroupId(1);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, bob_addr()).unwrap();
+    bob.tcp_listen(listen_fd, 2).unwrap();
+    bob.tcp_set_group(listen_fd, Some(tenant)).unwrap();
+
+    // Both accepted connections join the listener's group.
+    let mut bob_fds = vec![];
+    for _ in 0..2 {
+        let (_, bob_fd) = connect(&mut alice, &mut bob, listen_fd);
+        assert_eq!(bob.tcp_group(bob_fd), Some(tenant));
+        bob_fds.push(bob_fd);
+    }
+    assert_eq!(bob.tcp_group_stats(tenant).connections, 2);
+
+    let mut push_future = bob.tcp_push(bob_fds[0], Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        bob.rt().pop_frame();
+    }
+    let stats = bob.tcp_group_stats(tenant);
+    assert_eq!(stats.unacked_bytes, 100);
+    assert!(stats.throughput.bytes_transmitted >= 100);
+
+    // Moving a connection out of the group shields it from group operations.
+    bob.tcp_set_group(bob_fds[1], None).unwrap();
+    assert_eq!(bob.tcp_group_stats(tenant).connections, 1);
+    assert_eq!(bob.tcp_group_abort(tenant), 1);
+    assert!(parse_segment(bob.rt().pop_frame()).rst);
+    must_let!(let Err(Fail::Malformed { .. }) = bob.tcp_endpoints(bob_fds[0]));
+    assert!(!bob.is_qd_valid(bob_fds[0]));
+    assert_eq!(bob.tcp_group_stats(tenant).connections, 0);
+
+    let other = GroupId(2);
+    bob.tcp_set_group(bob_fds[1], Some(other)).unwrap();
+    assert_eq!(bob.tcp_group_close(other), 1);
+    bob.rt().poll_scheduler();
+    assert!(parse_segment(bob.rt().pop_frame()).fin);
+    // It's already closing, so a second close does nothing.
+    assert_eq!(bob.tcp_group_close(other), 0);
+    must_let!(let Err(Fail::Malformed { .. }) = bob.tcp_set_group(1000, Some(other)));
+}
+
+#[test]
+fn test_listener_stats() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // With the application not accepting, the connection fills the backlog and Carrie's SYN
+    // overflows it.
+    let carrie_fd = carrie.tcp_socket();
+    let _connect_future = carrie.tcp_connect(carrie_fd, listen_addr);
+    carrie.rt().poll_scheduler();
+    let syn = carrie.rt().pop_frame();
+    must_let!(let Err(Fail::ConnectionRefused {}) = bob.receive(syn.clone()));
+    let stats = bob.tcp_listener_stats(listen_fd).unwrap();
+    assert_eq!(stats.local, listen_addr);
+    assert_eq!(stats.syns_received, 2);
+    assert_eq!(stats.backlog_overflows, 1);
+    assert_eq!(stats.handshakes_completed, 1);
+    assert_eq!(stats.ready, 1);
+    assert_eq!(stats.accepted, 0);
+
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    // Now there's room, but Carrie never answers the SYN+ACK.
+    bob.receive(syn).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.tcp_listener_stats(listen_fd).unwrap().inflight, 1);
+    for i in 1..=3 {
+        bob.rt().advance_clock(now + Duration::from_secs(5 * i));
+        bob.rt().poll_scheduler();
+    }
+    let stats = bob.tcp_listener_stats(listen_fd).unwrap();
+    assert_eq!(stats.handshake_timeouts, 1);
+    assert_eq!(stats.accepted, 1);
+    assert_eq!(stats.ready, 0);
+    assert_eq!(bob.stats().tcp_listeners, vec![stats]);
+    must_let!(let Err(Fail::Malformed { .. }) = bob.tcp_listener_stats(1000));
+}
+
+#[test]
+fn test_write_with_deadline() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    // The write only completes once Bob acknowledges it.
+    let buf = Bytes::from_slice(&[0x5a; 100]);
+    let deadline = now + Duration::from_secs(1);
+    let mut write = Box::pin(alice.tcp_write_with_deadline(alice_fd, buf.clone(), deadline, true));
+    assert!(Future::poll(write.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert!(Future::poll(write.as_mut(), &mut ctx).is_pending());
+    bob.rt().advance_clock(now + Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(write.as_mut(), &mut ctx));
+
+    // This time the ACK is lost, so the deadline passes and the connection is reset.
+    let deadline = now + Duration::from_secs(2);
+    let mut write = Box::pin(alice.tcp_write_with_deadline(alice_fd, buf, deadline, true));
+    assert!(Future::poll(write.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    alice.rt().pop_frame();
+    alice.rt().advance_clock(deadline);
+    alice.rt().poll_scheduler();
+    must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(write.as_mut(), &mut ctx));
+    let mut frames = vec![];
+    while alice.rt().num_outgoing() > 0 {
+        frames.push(parse_segment(alice.rt().pop_frame()));
+    }
+    assert!(frames.last().unwrap().rst);
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_endpoints(alice_fd));
+    assert!(!alice.is_qd_valid(alice_fd));
+}
+
+#[test]
+fn test_send_buffer() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    // Room for two 100 byte writes; the third is turned away.
+    alice.tcp_set_send_buffer_size(alice_fd, 200).unwrap();
+    let buf = Bytes::from_slice(&[0x5a; 100]);
+    assert!(alice.tcp_writable(alice_fd).unwrap());
+    alice.tcp_push(alice_fd, buf.clone());
+    alice.tcp_push(alice_fd, buf.clone());
+    assert!(!alice.tcp_writable(alice_fd).unwrap());
+    let mut push = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Err(Fail::ResourceExhausted { .. })) = Future::poll(Pin::new(&mut push), &mut ctx));
+
+    // A write waits instead, until Bob's ACK frees the space.
+    let mut write = Box::pin(alice.tcp_write(alice_fd, buf));
+    assert!(Future::poll(write.as_mut(), &mut ctx).is_pending());
+    alice.rt().poll_scheduler();
+    while alice.rt().num_outgoing() > 0 {
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    assert!(Future::poll(write.as_mut(), &mut ctx).is_pending());
+    bob.rt().advance_clock(now + Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert!(alice.tcp_writable(alice_fd).unwrap());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(write.as_mut(), &mut ctx));
+    assert!(alice.tcp_writable(alice_fd).unwrap());
+}
+
+#[test]
+fn test_framing() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    // Each write ends in a segment marked with PSH, and Bob reads them back one at a time.
+    bob.tcp_set_framing(bob_fd, Framing::Push).unwrap();
+    for data in &[&b"abc"[..], &b"defg"[..]] {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(data));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        let frame = alice.rt().pop_frame();
+        assert!(parse_segment(frame.clone()).psh);
+        bob.receive(frame).unwrap();
+    }
+    for &expected in &[&b"abc"[..], &b"defg"[..]] {
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        assert_eq!(&buf[..], expected);
+    }
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+    drop(pop_future);
+
+    // Length-prefixed records are delivered whole, however the writes split and join them.
+    bob.tcp_set_framing(bob_fd, Framing::LengthPrefixed { prefix_len: 2, max_len: 100 })
+        .unwrap();
+    for data in &[&b"\x00\x03x"[..], &b"yz\x00\x01"[..], &b"!"[..]] {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(data));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    for &expected in &[&b"xyz"[..], &b"!"[..]] {
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        assert_eq!(&buf[..], expected);
+    }
+
+    // A record over the limit fails the read.
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x00, 0xc8]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Err(Fail::Malformed { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    // Prefixes are one to four bytes.
+    must_let!(let Err(Fail::Invalid { .. }) = bob.tcp_set_framing(bob_fd, Framing::LengthPrefixed { prefix_len: 5, max_len: 100 }));
+}
+
+#[test]
+fn test_idle_hint() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    // Data in flight keeps Alice busy.
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 10]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let hint = alice.idle_hint();
+    assert_eq!(hint.active_connections, 1);
+    assert!(!hint.can_sleep(Duration::from_secs(0)));
+
+    // Once it's acknowledged she can rest, but only after being idle for long enough.
+    bob.advance_clock(now + Duration::from_millis(1));
+    bob.poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.poll_scheduler();
+    let hint = alice.idle_hint();
+    assert_eq!(hint.active_connections, 0);
+    assert!(!hint.runnable);
+    assert!(!hint.can_sleep(Duration::from_millis(1)));
+    alice.advance_clock(now + Duration::from_millis(5));
+    let hint = alice.idle_hint();
+    assert_eq!(hint.idle_for, Duration::from_millis(5));
+    assert!(hint.can_sleep(Duration::from_millis(1)));
+}
+
+#[test]
+fn test_soft_errors() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    // Bob stops answering ARP for a whole query, which the connection rides out.
+    alice.import_arp_cache(HashMap::new());
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 10]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let options = alice.rt().arp_options();
+    for _ in 0..options.retry_count + 1 {
+        alice.rt().poll_scheduler();
+        alice.rt().pop_frame();
+        now += options.request_timeout;
+        alice.rt().advance_clock(now);
+    }
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_throughput_stats(alice_fd).unwrap().soft_errors, 1);
+
+    // He answers the retry, and the data goes out.
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    let segment = parse_segment(alice.rt().pop_frame());
+    assert_eq!(segment.dst_port, bob_addr().port);
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::Established);
+}
+
+#[test]
+fn test_traffic_generators() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+    let mss = alice.tcp_negotiated(alice_fd).unwrap().mss;
+
+    // Ten segments a second: the first goes out right away, and the next a tenth of a second
+    // later.
+    let rate = Some(10 * mss as u64);
+    alice
+        .tcp_start_source(alice_fd, rate, TrafficPattern::Counting)
+        .unwrap();
+    must_let!(let Err(Fail::ResourceBusy { .. }) = alice.tcp_start_source(alice_fd, None, TrafficPattern::Zeros));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.rt().num_outgoing(), 0);
+    now += Duration::from_millis(100);
+    alice.advance_clock(now);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // The pattern carries on across segments.
+    let mut received = vec![];
+    for _ in 0..2 {
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        received.extend_from_slice(&buf[..]);
+    }
+    assert_eq!(received.len(), 2 * mss);
+    assert!(received.iter().enumerate().all(|(i, &b)| b == i as u8));
+    let source = alice.tcp_traffic_stats(alice_fd).source.unwrap();
+    assert_eq!(source.bytes, 2 * mss as u64);
+    assert_eq!(source.elapsed, Duration::from_millis(100));
+    assert!(!source.finished);
+
+    // Bob's sink takes the next segment without the application.
+    bob.tcp_start_sink(bob_fd).unwrap();
+    now += Duration::from_millis(100);
+    alice.advance_clock(now);
+    bob.advance_clock(now);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let sink = bob.tcp_traffic_stats(bob_fd).sink.unwrap();
+    assert_eq!(sink.bytes, mss as u64);
+    assert!(alice.tcp_traffic_stats(alice_fd).sink.is_none());
+
+    let source = alice.tcp_stop_traffic(alice_fd).source.unwrap();
+    assert_eq!(source.bytes, 3 * mss as u64);
+    assert!(source.finished);
+    assert!((source.bytes_per_second() - 15.0 * mss as f64).abs() < 1e-6);
+    assert!(alice.tcp_traffic_stats(alice_fd).source.is_none());
+}
+
+#[test]
+fn test_short_frames() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+
+    // The handshake's final ACK is a bare 54 bytes, so it goes out padded with zeros. The
+    // padding on the way in is garbage, which mustn't turn into stream data.
+    let ack = alice.rt().pop_frame();
+    assert_eq!(ack.len(), MIN_FRAME_SIZE);
+    assert!(ack[54..].iter().all(|&b| b == 0));
+    let mut garbled = ack[..].to_vec();
+    for b in &mut garbled[54..] {
+        *b = 0xff;
+    }
+    bob.receive(Bytes::from_slice(&garbled[..])).unwrap();
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    must_let!(let Err(Fail::WouldBlock {}) = bob.tcp_peek_at(bob_fd, 0, 1));
+}
+
+#[test]
+fn test_fast_retransmit() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let syn_ack_frame = bob.rt().pop_frame();
+    let syn_ack = parse_segment(syn_ack_frame.clone());
+    alice.receive(syn_ack_frame).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let mss = alice.tcp_negotiated(alice_fd).unwrap().mss as u32;
+
+    // Four segments go out, and the first is lost.
+    for _ in 0..4 {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    alice.rt().poll_scheduler();
+    let data = (0..4)
+        .map(|_| parse_segment(alice.rt().pop_frame()))
+        .collect::<Vec<_>>();
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    // Bob acknowledges up to the hole for each one that arrives.
+    let ack = |ack_num| {
+        let mut tcp_hdr = TcpHeader::new(listen_port, data[0].src_port);
+        tcp_hdr.ack = true;
+        tcp_hdr.seq_num = syn_ack.seq_num + Wrapping(1);
+        tcp_hdr.ack_num = ack_num;
+        tcp_hdr.window_size = syn_ack.window_size;
+        forged_segment(tcp_hdr, true)
+    };
+    // The SYN+ACK's window wasn't scaled, so the first ACK changes it and isn't a duplicate.
+    bob.rt().transmit(ack(data[0].seq_num));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    for _ in 0..2 {
+        bob.rt().transmit(ack(data[0].seq_num));
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    // The third duplicate ACK gets the lost segment resent right away.
+    bob.rt().transmit(ack(data[0].seq_num));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(parse_segment(alice.rt().pop_frame()).seq_num, data[0].seq_num);
+    let congestion = alice.tcp_congestion(alice_fd).unwrap();
+    assert!(congestion.fast_recovery);
+    assert_eq!(congestion.ssthresh, 2 * mss);
+    assert_eq!(congestion.cwnd, 5 * mss);
+    assert_eq!(
+        alice
+            .tcp_throughput_stats(alice_fd)
+            .unwrap()
+            .segments_retransmitted,
+        1
+    );
+
+    // Once everything is acknowledged, recovery is over.
+    bob.rt().transmit(ack(data[3].seq_num + Wrapping(100)));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let congestion = alice.tcp_congestion(alice_fd).unwrap();
+    assert!(!congestion.fast_recovery);
+    assert_eq!(congestion.cwnd, 2 * mss);
+}
+
+#[test]
+fn test_duplicate_acks() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    // Five segments go out, and the first is lost.
+    for i in 0..5 {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[i; 100]));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    alice.rt().poll_scheduler();
+    let frames = (0..5).map(|_| alice.rt().pop_frame()).collect::<Vec<_>>();
+    let lost = parse_segment(frames[0].clone()).seq_num;
+
+    // Bob acknowledges each of the others right away, without SACK, and that's enough for Alice
+    // to resend the lost one without waiting for the RTO.
+    for frame in &frames[1..] {
+        bob.receive(frame.clone()).unwrap();
+        let ack = bob.rt().pop_frame();
+        assert_eq!(parse_segment(ack.clone()).ack_num, lost);
+        alice.receive(ack).unwrap();
+    }
+    assert_eq!(alice.rt().num_outgoing(), 1);
+    let resent = alice.rt().pop_frame();
+    assert_eq!(parse_segment(resent.clone()).seq_num, lost);
+
+    bob.receive(resent).unwrap();
+    let expected = (0..5).flat_map(|i| vec![i; 100]).collect::<Vec<u8>>();
+    assert_eq!(&bob.tcp_peek_at(bob_fd, 0, 500).unwrap()[..], &expected[..]);
+}
+
+#[test]
+fn test_sack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_tcp_options(|o| o.sack = true);
+    bob.rt().set_tcp_options(|o| o.sack = true);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+    assert!(alice.tcp_negotiated(alice_fd).unwrap().sack_permitted);
+    assert!(bob.tcp_negotiated(bob_fd).unwrap().sack_permitted);
+
+    // Six segments go out, and the first and third are lost.
+    for i in 0..6 {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[i; 100]));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    alice.rt().poll_scheduler();
+    let frames = (0..6).map(|_| alice.rt().pop_frame()).collect::<Vec<_>>();
+    let seq_nums = frames
+        .iter()
+        .map(|f| parse_segment(f.clone()).seq_num)
+        .collect::<Vec<_>>();
+
+    // Bob acknowledges each of the others as it arrives, listing what he holds in SACK blocks.
+    let mut acks = vec![];
+    for &i in &[1, 3, 4, 5] {
+        bob.receive(frames[i].clone()).unwrap();
+        acks.push(bob.rt().pop_frame());
+    }
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    let ack = parse_segment(acks[3].clone());
+    assert_eq!(ack.ack_num, seq_nums[0]);
+    must_let!(let [first, second] = ack.sack_blocks());
+    assert_eq!(
+        (first.begin, first.end),
+        (seq_nums[3], seq_nums[5] + Wrapping(100))
+    );
+    assert_eq!((second.begin, second.end), (seq_nums[1], seq_nums[2]));
+
+    // The first ACK scales the window the SYN+ACK left unscaled, so it's not a duplicate. The
+    // third duplicate gets both holes filled, and nothing Bob already has.
+    for ack in acks {
+        alice.receive(ack).unwrap();
+    }
+    let resent = (0..alice.rt().num_outgoing())
+        .map(|_| alice.rt().pop_frame())
+        .collect::<Vec<_>>();
+    let resent_seq_nums = resent
+        .iter()
+        .map(|f| parse_segment(f.clone()).seq_num)
+        .collect::<Vec<_>>();
+    assert_eq!(resent_seq_nums, vec![seq_nums[0], seq_nums[2]]);
+    assert_eq!(
+        alice
+            .tcp_throughput_stats(alice_fd)
+            .unwrap()
+            .segments_retransmitted,
+        2
+    );
+
+    for frame in resent {
+        bob.receive(frame).unwrap();
+    }
+    let expected = (0..6).flat_map(|i| vec![i; 100]).collect::<Vec<u8>>();
+    assert_eq!(&bob.tcp_peek_at(bob_fd, 0, 600).unwrap()[..], &expected[..]);
+}
+
+#[test]
+fn test_timestamps() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_tcp_options(|o| o.timestamps = true);
+    bob.rt().set_tcp_options(|o| o.timestamps = true);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    // The SYN+ACK's echo of the SYN's timestamp gives Alice her first RTT sample.
+    alice.rt().advance_clock(now + Duration::from_millis(20));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert!(alice.tcp_negotiated(alice_fd).unwrap().timestamps);
+    assert!(bob.tcp_negotiated(bob_fd).unwrap().timestamps);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[1; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let first = alice.rt().pop_frame();
+    let (first_tsval, _) = parse_segment(first.clone()).timestamp().unwrap();
+
+    // Bob's ACK echoes the segment's timestamp, which gives Alice another.
+    bob.receive(first.clone()).unwrap();
+    bob.rt().advance_clock(now + Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    let ack = bob.rt().pop_frame();
+    let (_, tsecr) = parse_segment(ack.clone()).timestamp().unwrap();
+    assert_eq!(tsecr, first_tsval);
+    alice.rt().advance_clock(now + Duration::from_millis(40));
+    alice.receive(ack).unwrap();
+    let srtt = alice
+        .tcp_latency_stats(alice_fd)
+        .unwrap()
+        .srtt
+        .max()
+        .unwrap();
+    assert!(srtt >= Duration::from_millis(19), "{:?}", srtt);
+    assert!(srtt <= Duration::from_millis(21), "{:?}", srtt);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[2; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let second = alice.rt().pop_frame();
+    let (second_tsval, _) = parse_segment(second.clone()).timestamp().unwrap();
+    bob.receive(second).unwrap();
+
+    // A stray copy of the first segment fails PAWS and is dropped, but still acknowledged.
+    bob.receive(first).unwrap();
+    assert_eq!(bob.rt().num_outgoing(), 1);
+    let ack = parse_segment(bob.rt().pop_frame());
+    assert_eq!(ack.timestamp().unwrap().1, second_tsval);
+    let expected = [vec![1; 100], vec![2; 100]].concat();
+    assert_eq!(&bob.tcp_peek_at(bob_fd, 0, 200).unwrap()[..], &expected[..]);
+}
+
+#[test]
+fn test_timer_granularity() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt()
+        .set_tcp_options(|o| o.timer_granularity = Duration::from_millis(10));
+
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // The ACK is due after the trailing delay, but waits for the end of its 10ms bucket.
+    bob.rt().advance_clock(now + Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    bob.rt().advance_clock(now + Duration::from_millis(10));
+    bob.rt().poll_scheduler();
+    assert!(parse_segment(bob.rt().pop_frame()).ack);
+}
+
+#[test]
+fn test_zero_window_probe() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt().set_tcp_options(|o| o.receive_window_size = 100);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    // Alice fills Bob's window, and has more waiting behind it.
+    for len in &[100, 10] {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&vec![0x5a; *len]));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(alice.rt().num_outgoing(), 0);
+    assert_eq!(alice.tcp_send_status(alice_fd).unwrap(), SendStatus::Open);
+
+    // Bob's ACK closes the window, so Alice starts probing it.
+    bob.rt().advance_clock(now + Duration::from_millis(500));
+    bob.rt().poll_scheduler();
+    let ack = bob.rt().pop_frame();
+    assert_eq!(parse_segment(ack.clone()).window_size, 0);
+    alice.receive(ack).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let SendStatus::ZeroWindow { probes: 1, next_probe, .. } = alice.tcp_send_status(alice_fd).unwrap());
+
+    // Bob has no room for the probe until he reads, after which the next one gets in.
+    alice.rt().advance_clock(next_probe);
+    alice.rt().poll_scheduler();
+    let probe = alice.rt().pop_frame();
+    must_let!(let SendStatus::ZeroWindow { probes: 2, next_probe: later, .. } = alice.tcp_send_status(alice_fd).unwrap());
+    assert!(later - next_probe > next_probe - now);
+    bob.tcp_consume(bob_fd, 100).unwrap();
+    bob.receive(probe).unwrap();
+    bob.rt().advance_clock(now + Duration::from_millis(1000));
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_send_status(alice_fd).unwrap(), SendStatus::Open);
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, payload) = Ipv4Header::parse(payload).unwrap();
+    let (_, data) = TcpHeader::parse(&ipv4_hdr, payload, false).unwrap();
+    assert_eq!(data.len(), 9);
+}
+
+struct XorTransform {
+    greeting: &'static [u8],
+}
+
+impl StreamTransform for XorTransform {
+    fn start(&mut self, out: &mut Vec<u8>) -> Result<(), Fail> {
+        self.on_write(self.greeting, out)
+    }
+
+    fn on_write(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<(), Fail> {
+        out.extend(data.iter().map(|b| b ^ 0x5a));
+        Ok(())
+    }
+
+    fn on_read(&mut self, data: &[u8], out: &mut Vec<u8>, _reply: &mut Vec<u8>) -> Result<(), Fail> {
+        self.on_write(data, out)
+    }
+}
+
+#[test]
+fn test_nagle() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_tcp_options(|o| o.nagle = true);
+
+    let listen_fd = listen(&mut bob);
+    let (alice_fd, _) = connect(&mut alice, &mut bob, listen_fd);
+
+    // The first small write goes out, and the next two wait for its ACK.
+    for i in 0..3 {
+        let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[i; 10]));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    // Then they share a segment.
+    bob.rt().advance_clock(now + Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    let (_, payload) = Ethernet2Header::parse(alice.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, payload) = Ipv4Header::parse(payload).unwrap();
+    let (_, data) = TcpHeader::parse(&ipv4_hdr, payload, false).unwrap();
+    assert_eq!(&data[..], &[[1; 10], [2; 10]].concat()[..]);
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    // With Nagle's algorithm off, small writes go straight out despite what's in flight.
+    alice.tcp_set_nodelay(alice_fd, true).unwrap();
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[3; 10]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    assert_eq!(alice.rt().num_outgoing(), 1);
+    must_let!(let Err(Fail::Malformed { .. }) = bob.tcp_set_nodelay(listen_fd, true));
+}
+
+#[test]
+fn test_send_window() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt().set_tcp_options(|o| o.receive_window_size = 100);
+
+    let listen_fd = listen(&mut bob);
+    let (alice_fd, _) = connect(&mut alice, &mut bob, listen_fd);
+    let cwnd = alice.tcp_congestion(alice_fd).unwrap().cwnd;
+    let window = alice.tcp_send_window(alice_fd).unwrap();
+    assert_eq!(window, SendWindow { bytes_in_flight: 0, peer_window_remaining: 100, cwnd_remaining: cwnd });
+
+    // The first write goes straight out, and the second waits behind Bob's window.
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 60]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    assert_eq!(alice.rt().num_outgoing(), 1);
+    let window = alice.tcp_send_window(alice_fd).unwrap();
+    assert_eq!(window, SendWindow { bytes_in_flight: 60, peer_window_remaining: 40, cwnd_remaining: cwnd - 60 });
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 60]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    assert_eq!(alice.rt().num_outgoing(), 1);
+    let window = alice.tcp_send_window(alice_fd).unwrap();
+    assert_eq!(window, SendWindow { bytes_in_flight: 60, peer_window_remaining: 0, cwnd_remaining: cwnd - 120 });
+
+    must_let!(let Err(Fail::Malformed { .. }) = bob.tcp_send_window(listen_fd));
+}
+
+#[test]
+fn test_stream_transform() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_fd = listen(&mut bob);
+    bob.tcp_set_listener_transform(
+        listen_fd,
+        Some(Rc::new(|_: ipv4::Endpoint| -> Box<dyn StreamTransform> {
+            Box::new(XorTransform { greeting: b"" })
+        })),
+    )
+    .unwrap();
+    let (alice_fd, bob_fd) = connect(&mut alice, &mut bob, listen_fd);
+
+    // Alice's transform sends its greeting as soon as it's installed.
+    alice
+        .tcp_set_transform(alice_fd, Some(Box::new(XorTransform { greeting: b"hi" })))
+        .unwrap();
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(b"hello"));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+
+    // Only the transformed bytes go on the wire.
+    let mut wire = vec![];
+    for _ in 0..alice.rt().num_outgoing() {
+        let frame = alice.rt().pop_frame();
+        let (_, payload) = Ethernet2Header::parse(frame.clone()).unwrap();
+        let (ipv4_hdr, payload) = Ipv4Header::parse(payload).unwrap();
+        let (_, data) = TcpHeader::parse(&ipv4_hdr, payload, false).unwrap();
+        wire.extend_from_slice(&data[..]);
+        bob.receive(frame).unwrap();
+    }
+    let expected = b"hihello".iter().map(|b| b ^ 0x5a).collect::<Vec<u8>>();
+    assert_eq!(wire, expected);
+
+    // Bob's, from his listener, undoes them before he reads.
+    assert_eq!(&bob.tcp_peek_at(bob_fd, 2, 5).unwrap()[..], b"hello");
+    bob.tcp_consume(bob_fd, 2).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&buf[..], b"hello");
+    assert_eq!(bob.tcp_throughput_stats(bob_fd).unwrap().bytes_delivered, 7);
+}
+
+#[test]
+fn test_state() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    assert_eq!(bob.tcp_state(listen_fd).unwrap(), State::Listen);
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    let alice_fd = alice.tcp_socket();
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::Closed);
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::SynSent);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::Established);
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::Established);
+
+    // Alice closes first.
+    alice.set_label("alice");
+    alice.tcp_close(alice_fd).unwrap();
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::FinWait1);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::CloseWait);
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::FinWait2);
+
+    bob.tcp_close(bob_fd).unwrap();
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::LastAck);
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::TimeWait);
+
+    // Once its FIN is acknowledged, Bob's end goes away, and its close event says how it ended.
+    // Alice's lingers in TIME_WAIT for 2*MSL first.
+    alice.rt().poll_scheduler();
+    alice.rt().poll_scheduler();
+    while alice.rt().num_outgoing() > 0 {
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    bob.rt().poll_scheduler();
+    bob.rt().poll_scheduler();
+    let events = bob.take_events();
+    must_let!(let [Event::TcpClosed { fd, state, .. }] = &events[..]);
+    assert_eq!(*fd, bob_fd);
+    assert_eq!(*state, State::Closed);
+    assert!(alice.take_events().is_empty());
+
+    alice.advance_clock(now + alice.rt().tcp_options().msl * 2);
+    alice.rt().poll_scheduler();
+    alice.rt().poll_scheduler();
+    // Alice's is labelled with her name.
+    let events = alice.take_labeled_events();
+    must_let!(let [LabeledEvent { engine: Some(engine), event: Event::TcpClosed { fd, remote, state, .. } }] = &events[..]);
+    assert_eq!(&**engine, "alice");
+    assert_eq!(*fd, alice_fd);
+    assert_eq!(*remote, listen_addr);
+    assert_eq!(*state, State::TimeWait);
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_state(alice_fd));
+}
+
+#[test]
+fn test_time_wait() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    // A single ephemeral port, so it's plain when Alice can use it again.
+    let rt = test_helpers::TestRuntime::new(
+        "alice",
+        now,
+        test_helpers::ALICE_MAC,
+        test_helpers::ALICE_IPV4,
+    );
+    rt.set_tcp_options(|o| o.ephemeral_ports = 65535..=65535);
+    let mut alice = Engine::new(rt).unwrap();
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+    let fin_seq_num = Wrapping(alice.snapshot().tcp_connections[0].sender.sent_seq_no);
+
+    // Alice's first FIN is lost, so it goes out again once the RTO passes. It takes the sequence
+    // number after her last byte, and Bob's ACK for it the one after that.
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = parse_segment(alice.rt().pop_frame());
+    assert!(fin.fin);
+    assert_eq!(fin.seq_num, fin_seq_num);
+    now += Duration::from_secs(5);
+    alice.advance_clock(now);
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    assert!(parse_segment(fin.clone()).fin);
+    assert_eq!(parse_segment(fin.clone()).seq_num, fin_seq_num);
+    bob.receive(fin).unwrap();
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        let ack = bob.rt().pop_frame();
+        assert_eq!(parse_segment(ack.clone()).ack_num, fin_seq_num + Wrapping(1));
+        alice.receive(ack).unwrap();
+    }
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::FinWait2);
+
+    // Bob's FIN arrives, but Alice's ACK for it is lost.
+    bob.tcp_close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    assert!(parse_segment(alice.rt().pop_frame()).ack);
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::TimeWait);
+
+    // Bob sends the FIN again, and Alice acknowledges it again, starting TIME_WAIT over.
+    now += Duration::from_secs(5);
+    bob.advance_clock(now);
+    bob.rt().poll_scheduler();
+    let fin = bob.rt().pop_frame();
+    assert!(parse_segment(fin.clone()).fin);
+    alice.advance_clock(now);
+    alice.receive(fin).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    bob.rt().poll_scheduler();
+    let events = bob.take_events();
+    must_let!(let [Event::TcpClosed { state: State::Closed, .. }] = &events[..]);
+
+    // Until 2*MSL after that, the connection holds on to Alice's only port.
+    let time_wait = alice.rt().tcp_options().msl * 2;
+    alice.advance_clock(now + time_wait - Duration::from_secs(1));
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::TimeWait);
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(fd, bob_addr());
+    must_let!(let Poll::Ready(Err(Fail::ResourceExhausted { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Then the connection goes away, and the port can be used again.
+    alice.advance_clock(now + time_wait);
+    alice.rt().poll_scheduler();
+    alice.rt().poll_scheduler();
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_state(alice_fd));
+    let fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(fd, bob_addr());
+    alice.rt().poll_scheduler();
+    let syn = parse_segment(alice.rt().pop_frame());
+    assert!(syn.syn);
+    assert_eq!(syn.src_port, ip::Port::try_from(65535).unwrap());
+}
+
+#[test]
+fn test_out_of_order_fin() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let data = alice.rt().pop_frame();
+    let fin = alice.rt().pop_frame();
+    let data_seq_num = parse_segment(data.clone()).seq_num;
+    assert!(parse_segment(fin.clone()).fin);
+
+    // The FIN overtakes the data, so Bob drops it and asks for the data again.
+    bob.receive(fin.clone()).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::Established);
+    assert_eq!(parse_segment(bob.rt().pop_frame()).ack_num, data_seq_num);
+
+    // Once the data's in, the resent FIN is taken.
+    bob.receive(data).unwrap();
+    bob.receive(fin).unwrap();
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::CloseWait);
+}
+
+#[test]
+fn test_delayed_ack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let delay = Options::default().delayed_ack_timeout;
+    assert_eq!(delay, MAX_DELAYED_ACK_TIMEOUT);
+    bob.rt().set_tcp_options(|o| o.delayed_ack_timeout = delay);
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let data = alice.rt().pop_frame();
+    let seq_num = parse_segment(data.clone()).seq_num;
+    bob.receive(data).unwrap();
+
+    // Bob holds the ACK back in case a reply can carry it, but no longer than the default.
+    bob.advance_clock(now + delay - Duration::from_millis(1));
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    bob.advance_clock(now + delay);
+    bob.rt().poll_scheduler();
+    assert_eq!(parse_segment(bob.rt().pop_frame()).ack_num, seq_num + Wrapping(100));
+}
+
+#[test]
+fn test_hostile_partial_ack() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let data = parse_segment(alice.rt().pop_frame());
+
+    // Bob acknowledges half the segment, which Alice drops without touching what's unacknowledged.
+    let mut tcp_hdr = TcpHeader::new(data.dst_port, data.src_port);
+    tcp_hdr.ack = true;
+    tcp_hdr.seq_num = data.ack_num;
+    tcp_hdr.ack_num = data.seq_num + Wrapping(50);
+    tcp_hdr.window_size = 0xffff;
+    bob.rt().transmit(forged_segment(tcp_hdr, true));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let stats = alice.tcp_throughput_stats(alice_fd).unwrap();
+    assert_eq!(stats.segments_invalid, 1);
+
+    // So the whole segment goes out again once the RTO passes.
+    now += Duration::from_secs(5);
+    alice.advance_clock(now);
+    alice.rt().poll_scheduler();
+    assert_eq!(parse_segment(alice.rt().pop_frame()).seq_num, data.seq_num);
+}
+
+#[test]
+fn test_fin_acked_with_data() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, _) = establish(&mut alice, &mut bob);
+
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let data = parse_segment(alice.rt().pop_frame());
+    let fin = parse_segment(alice.rt().pop_frame());
+    assert!(fin.fin);
+
+    // One ACK covers both the data and the FIN.
+    let mut tcp_hdr = TcpHeader::new(data.dst_port, data.src_port);
+    tcp_hdr.ack = true;
+    tcp_hdr.seq_num = data.ack_num;
+    tcp_hdr.ack_num = fin.seq_num + Wrapping(1);
+    tcp_hdr.window_size = 0xffff;
+    bob.rt().transmit(forged_segment(tcp_hdr, true));
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::FinWait2);
+    assert_eq!(alice.tcp_throughput_stats(alice_fd).unwrap().segments_invalid, 0);
+}
+
+#[test]
+fn test_ack_during_arp_query() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    // Bob's delayed ACK has to wait on ARP for Alice.
+    bob.import_arp_cache(HashMap::new());
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    now += Duration::from_millis(1);
+    bob.advance_clock(now);
+    bob.rt().poll_scheduler();
+    let arp_request = bob.rt().pop_frame();
+
+    // More data and then the FIN arrive in the meantime, which the ACK has to cover.
+    let mut push_future = alice.tcp_push(alice_fd, Bytes::from_slice(&[0x5a; 100]));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    let data = alice.rt().pop_frame();
+    let fin = alice.rt().pop_frame();
+    let fin_seq_num = parse_segment(fin.clone()).seq_num;
+    assert_eq!(fin_seq_num, parse_segment(data.clone()).seq_num + Wrapping(100));
+    bob.receive(data).unwrap();
+    bob.receive(fin).unwrap();
+
+    alice.receive(arp_request).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let mut ack_num = None;
+    while bob.rt().num_outgoing() > 0 {
+        ack_num = Some(parse_segment(bob.rt().pop_frame()).ack_num);
+    }
+    assert_eq!(ack_num, Some(fin_seq_num + Wrapping(1)));
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::CloseWait);
+}
+
+#[test]
+fn test_connect_from() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    // Alice can't connect from someone else's address or from a port she's listening on.
+    let alice_port =
+        |port: u16| ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(port).unwrap());
+    let fd = alice.tcp_socket();
+    let bob_local = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(20).unwrap());
+    let mut connect_future = alice.tcp_connect_from(fd, bob_local, listen_addr);
+    must_let!(let Poll::Ready(Err(Fail::Malformed { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let alice_listen_fd = alice.tcp_socket();
+    alice.tcp_bind(alice_listen_fd, alice_port(21)).unwrap();
+    alice.tcp_listen(alice_listen_fd, 1).unwrap();
+    let mut connect_future = alice.tcp_connect_from(fd, alice_port(21), listen_addr);
+    must_let!(let Poll::Ready(Err(Fail::ResourceBusy { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // From a free port, the SYN goes out from it and the connection is set up as usual.
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect_from(alice_fd, alice_port(20), listen_addr);
+    alice.rt().poll_scheduler();
+    let syn = alice.rt().pop_frame();
+    assert_eq!(parse_segment(syn.clone()).src_port, ip::Port::try_from(20).unwrap());
+    bob.receive(syn).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let claim = alice
+        .ports()
+        .into_iter()
+        .find(|c| c.port == ip::Port::try_from(20).unwrap())
+        .unwrap();
+    assert_eq!(claim.fd, alice_fd);
+    assert_eq!(claim.usage, ip::PortUse::Bound);
+
+    // Another connection from the same port is turned away, even to a different remote.
+    let other_remote = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(81).unwrap());
+    let fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect_from(fd, alice_port(20), other_remote);
+    must_let!(let Poll::Ready(Err(Fail::ResourceBusy { .. })) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // A port from the private range is taken out of the ephemeral pool.
+    let fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect_from(fd, alice_port(50000), listen_addr);
+    let claim = alice
+        .ports()
+        .into_iter()
+        .find(|c| c.port == ip::Port::try_from(50000).unwrap())
+        .unwrap();
+    assert_eq!(claim.fd, fd);
+    assert_eq!(claim.usage, ip::PortUse::Ephemeral);
+}
+
+#[test]
+fn test_drain() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_fd = listen(&mut bob);
+    let (alice_fd, bob_fd) = connect(&mut alice, &mut bob, listen_fd);
+
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = bob.tcp_drain(ip::Port::try_from(81).unwrap()));
+    bob.tcp_drain(bob_addr().port).unwrap();
+    assert!(bob.take_events().is_empty());
+
+    // New connections are refused as if nothing were listening.
+    let fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(fd, bob_addr());
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert!(parse_segment(bob.rt().pop_frame()).rst);
+    assert_eq!(bob.tcp_state(listen_fd).unwrap(), State::Listen);
+
+    // The existing connection still works, and the listener is drained once it's closed.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.tcp_close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    alice.rt().poll_scheduler();
+    while alice.rt().num_outgoing() > 0 {
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    bob.rt().poll_scheduler();
+    bob.rt().poll_scheduler();
+    let events = bob.take_events();
+    must_let!(let [Event::TcpClosed { .. }, Event::TcpDrained { fd, local }] = &events[..]);
+    assert_eq!(*fd, listen_fd);
+    assert_eq!(*local, bob_addr());
+}
+
+#[test]
+fn test_close_listener() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = bob.tcp_accept(listen_fd);
+
+    // Leave a handshake half-open: bob's SYN+ACK is lost.
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    let syn_ack = parse_segment(bob.rt().pop_frame());
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = bob.tcp_close_listener(ip::Port::try_from(81).unwrap()));
+    bob.tcp_close_listener(listen_port).unwrap();
+
+    // The handshake is reset, in the window of the SYN+ACK, and the accept fails.
+    let rst_frame = bob.rt().pop_frame();
+    let rst = parse_segment(rst_frame.clone());
+    assert!(rst.rst);
+    assert_eq!(rst.seq_num, syn_ack.seq_num + Wrapping(1));
+    assert_eq!(rst.ack_num, syn_ack.ack_num);
+    alice.receive(rst_frame).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    must_let!(let Poll::Ready(Err(..)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    // SYNs now find the port closed, and it can be listened on again.
+    let fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    assert!(parse_segment(bob.rt().pop_frame()).rst);
+
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+}
+
+#[derive(Default)]
+struct RecordingHooks {
+    opened: RefCell<Vec<ConnectionInfo>>,
+    closed: RefCell<Vec<(ConnectionInfo, State)>>,
+}
+
+impl ConnectionHooks for RecordingHooks {
+    fn on_open(&self, conn: &ConnectionInfo) {
+        self.opened.borrow_mut().push(*conn);
+    }
+
+    fn on_close(&self, conn: &ConnectionInfo, state: State) {
+        self.closed.borrow_mut().push((*conn, state));
+    }
+}
+
+#[test]
+fn test_connection_hooks() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let alice_hooks = Rc::new(RecordingHooks::default());
+    let bob_hooks = Rc::new(RecordingHooks::default());
+    alice.tcp_add_connection_hooks(alice_hooks.clone());
+    let bob_id = bob.tcp_add_connection_hooks(bob_hooks.clone());
+
+    let listen_fd = listen(&mut bob);
+    let group = GroupId(1);
+    bob.tcp_set_group(listen_fd, Some(group)).unwrap();
+    let (alice_fd, bob_fd) = connect(&mut alice, &mut bob, listen_fd);
+
+    // Both ends are told of their connection as it's established.
+    assert_eq!(alice_hooks.opened.borrow().len(), 1);
+    let alice_conn = alice_hooks.opened.borrow()[0];
+    assert_eq!(alice_conn.fd, alice_fd);
+    assert_eq!(alice_conn.id, 0);
+    assert_eq!(alice_conn.remote, bob_addr());
+    assert_eq!(bob_hooks.opened.borrow().len(), 1);
+    let bob_conn = bob_hooks.opened.borrow()[0];
+    assert_eq!(bob_conn.fd, bob_fd);
+    assert_eq!(bob_conn.id, 0);
+    assert_eq!(bob_conn.local, bob_addr());
+    assert_eq!(bob_conn.remote, alice_conn.local);
+
+    // Hooks added later don't hear about the connection going away.
+    let late_hooks = Rc::new(RecordingHooks::default());
+    bob.tcp_add_connection_hooks(late_hooks.clone());
+    assert_eq!(bob.tcp_group_abort(group), 1);
+    assert_eq!(bob_hooks.closed.borrow()[..], [(bob_conn, State::Established)]);
+    assert!(late_hooks.closed.borrow().is_empty());
+
+    // Alice's end goes away with the RST.
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    alice.rt().poll_scheduler();
+    assert_eq!(alice_hooks.closed.borrow()[..], [(alice_conn, State::Closed)]);
+
+    bob.tcp_remove_connection_hooks(bob_id).unwrap();
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = bob.tcp_remove_connection_hooks(bob_id));
+}
+
+#[test]
+fn test_writev() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    // A header and its payload go out in one segment.
+    let header = BytesMut::from(&[0x01; 8][..]).freeze();
+    let payload = BytesMut::from(&vec![0x5a; 100][..]).freeze();
+    let empty = BytesMut::from(&[][..]).freeze();
+    let bufs = vec![header.clone(), empty, payload.clone()];
+    let mut push_future = alice.tcp_writev(alice_fd, bufs);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.rt().num_outgoing(), 1);
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut pop_future = bob.tcp_pop(bob_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..8], &header[..]);
+    assert_eq!(&received[8..], &payload[..]);
+
+    // More than a segment's worth is cut into full segments.
+    let mss = alice.tcp_negotiated(alice_fd).unwrap().mss;
+    let payload = BytesMut::from(&vec![0x6b; mss * 2][..]).freeze();
+    let mut push_future = alice.tcp_writev(alice_fd, vec![header.clone(), payload.clone()]);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    let mut received = vec![];
+    while alice.rt().num_outgoing() > 0 {
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        let mut pop_future = bob.tcp_pop(bob_fd);
+        must_let!(let Poll::Ready(Ok(segment)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        assert!(segment.len() <= mss);
+        received.extend_from_slice(&segment[..]);
+    }
+    assert_eq!(received.len(), 8 + mss * 2);
+    assert_eq!(&received[8..], &payload[..]);
+}
+
+#[test]
+fn test_read_exact() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    let mut readable_future = Box::pin(bob.tcp_readable(bob_fd));
+    assert!(Future::poll(readable_future.as_mut(), &mut ctx).is_pending());
+    let mut exact_future = Box::pin(bob.tcp_read_exact(bob_fd, 12));
+    assert!(Future::poll(exact_future.as_mut(), &mut ctx).is_pending());
+
+    // Two segments of 8 bytes: the read takes all of the first and half the second.
+    for i in 0..2u8 {
+        let buf = BytesMut::from(&[i; 8][..]).freeze();
+        let mut push_future = alice.tcp_push(alice_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(readable_future.as_mut(), &mut ctx));
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(exact_future.as_mut(), &mut ctx));
+    assert_eq!(&buf[..], &[0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1]);
+
+    // What's left comes back from a larger read, and a smaller one leaves the rest.
+    let mut up_to_future = Box::pin(bob.tcp_read_up_to(bob_fd, 3));
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(up_to_future.as_mut(), &mut ctx));
+    assert_eq!(&buf[..], &[1, 1, 1]);
+    let mut up_to_future = Box::pin(bob.tcp_read_up_to(bob_fd, 100));
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(up_to_future.as_mut(), &mut ctx));
+    assert_eq!(&buf[..], &[1]);
+
+    // Once Alice closes, a read that wants more than arrived fails, leaving it unread.
+    let buf = BytesMut::from(&[2; 4][..]).freeze();
+    let mut push_future = alice.tcp_push(alice_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    alice.tcp_close(alice_fd).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    let mut exact_future = Box::pin(bob.tcp_read_exact(bob_fd, 8));
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(exact_future.as_mut(), &mut ctx));
+    let mut up_to_future = Box::pin(bob.tcp_read_up_to(bob_fd, 8));
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(up_to_future.as_mut(), &mut ctx));
+    assert_eq!(&buf[..], &[2; 4]);
+    let mut readable_future = Box::pin(bob.tcp_readable(bob_fd));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(readable_future.as_mut(), &mut ctx));
+    let mut up_to_future = Box::pin(bob.tcp_read_up_to(bob_fd, 8));
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(up_to_future.as_mut(), &mut ctx));
+}
+
+#[test]
+fn test_half_close() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let (alice_fd, bob_fd) = establish(&mut alice, &mut bob);
+
+    // Alice finishes her request by shutting down writing.
+    alice.tcp_shutdown(alice_fd, Shutdown::Write).unwrap();
+    alice.rt().poll_scheduler();
+    let fin = alice.rt().pop_frame();
+    assert!(parse_segment(fin.clone()).fin);
+    bob.receive(fin).unwrap();
+    assert_eq!(bob.tcp_state(bob_fd).unwrap(), State::CloseWait);
+
+    // Bob's reply still reaches her.
+    let reply = BytesMut::from(&b"reply"[..]).freeze();
+    let mut push_future = bob.tcp_push(bob_fd, reply.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, reply);
+
+    // Once she shuts down reading too, what else Bob sends is acknowledged but thrown away.
+    alice.tcp_shutdown(alice_fd, Shutdown::Read).unwrap();
+    let mut push_future = bob.tcp_push(bob_fd, reply.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    let mut pop_future = alice.tcp_pop(alice_fd);
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    bob.tcp_close(bob_fd).unwrap();
+    bob.rt().poll_scheduler();
+    while bob.rt().num_outgoing() > 0 {
+        alice.receive(bob.rt().pop_frame()).unwrap();
+    }
+    alice.rt().poll_scheduler();
+    while alice.rt().num_outgoing() > 0 {
+        bob.receive(alice.rt().pop_frame()).unwrap();
+    }
+    assert_eq!(alice.tcp_state(alice_fd).unwrap(), State::TimeWait);
+
+    // The connection goes away after TIME_WAIT, but the descriptor is Alice's until she closes it.
+    now += alice.rt().tcp_options().msl * 2;
+    alice.advance_clock(now);
+    alice.rt().poll_scheduler();
+    alice.rt().poll_scheduler();
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_state(alice_fd));
+    alice.tcp_close(alice_fd).unwrap();
+    must_let!(let Err(Fail::Malformed { .. }) = alice.tcp_close(alice_fd));
+}
+
+#[test]
+fn test_backlog_overflow_reset() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+    let alice_fd = alice.tcp_socket();
+    let mut connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Alice's unaccepted connection fills the backlog, so by default Carrie's SYN is dropped.
+    let carrie_fd = carrie.tcp_socket();
+    let mut connect_future = carrie.tcp_connect(carrie_fd, listen_addr);
+    carrie.rt().poll_scheduler();
+    let syn = carrie.rt().pop_frame();
+    must_let!(let Err(Fail::ConnectionRefused {}) = bob.receive(syn.clone()));
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    // Asked to, Bob refuses it with a RST instead.
+    bob.rt()
+        .set_tcp_options(|o| o.reset_on_backlog_overflow = true);
+    must_let!(let Err(Fail::ConnectionRefused {}) = bob.receive(syn));
+    let rst = bob.rt().pop_frame();
+    assert!(parse_segment(rst.clone()).rst);
+    carrie.receive(rst).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let stats = bob.tcp_listener_stats(listen_fd).unwrap();
+    assert_eq!(stats.backlog_overflows, 2);
+}
+
+#[test]
+fn test_syn_cookies() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    let mut carrie = test_helpers::new_carrie(now);
+    bob.rt()
+        .set_tcp_options(|o| o.syn_cookies = SynCookiePolicy::OnOverflow);
+
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+    let listen_fd = bob.tcp_socket();
+    bob.tcp_bind(listen_fd, listen_addr).unwrap();
+    bob.tcp_listen(listen_fd, 1).unwrap();
+
+    // Alice's handshake fills the backlog.
+    let alice_fd = alice.tcp_socket();
+    let _connect_future = alice.tcp_connect(alice_fd, listen_addr);
+    alice.rt().poll_scheduler();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    bob.rt().pop_frame();
+
+    // Carrie's SYN is answered with a cookie right away, with nothing kept for it.
+    let carrie_fd = carrie.tcp_socket();
+    let mut connect_future = carrie.tcp_connect(carrie_fd, listen_addr);
+    carrie.rt().poll_scheduler();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    assert_eq!(bob.tcp_listener_stats(listen_fd).unwrap().inflight, 1);
+    let syn_ack = bob.rt().pop_frame();
+    assert_eq!(parse_segment(syn_ack.clone()).iter_options().count(), 1);
+    carrie.receive(syn_ack).unwrap();
+    carrie.rt().poll_scheduler();
+    bob.receive(carrie.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    let stats = bob.tcp_listener_stats(listen_fd).unwrap();
+    assert_eq!(stats.cookies_validated, 1);
+    assert_eq!(stats.handshakes_completed, 1);
+    assert_eq!(stats.backlog_overflows, 0);
+    let mut accept_future = bob.tcp_accept(listen_fd);
+    must_let!(let Poll::Ready(Ok(bob_fd)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    let negotiated = bob.tcp_negotiated(bob_fd).unwrap();
+    assert_eq!(negotiated.send_window_scale, 0);
+    assert!(!negotiated.sack_permitted);
+    assert!(!negotiated.timestamps);
+}
+
+#[test]
+fn test_closed_port_rst() {
+    let now = Instant::now();
+    let alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt()
+        .set_tcp_options(|o| o.closed_port_rst = RstPolicy::RateLimited { per_second: 2 });
+
+    let send_syn = |bob: &mut test_helpers::TestEngine| {
+        let mut syn = TcpHeader::new(
+            ip::Port::try_from(12345).unwrap(),
+            ip::Port::try_from(81).unwrap(),
+        );
+        syn.syn = true;
+        alice.rt().transmit(forged_segment(syn, false));
+        bob.receive(alice.rt().pop_frame())
+    };
+
+    // Nothing listens on port 81, so the first two SYNs are refused and the third is ignored.
+    for _ in 0..2 {
+        send_syn(&mut bob).unwrap();
+        assert!(parse_segment(bob.rt().pop_frame()).rst);
+    }
+    must_let!(let Err(Fail::Ignored { .. }) = send_syn(&mut bob));
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    assert_eq!(bob.stats().tcp_rsts_suppressed, 1);
+
+    // The limit starts over a second later.
+    bob.advance_clock(now + Duration::from_secs(1));
+    send_syn(&mut bob).unwrap();
+    assert!(parse_segment(bob.rt().pop_frame()).rst);
+
+    // In stealth mode nothing is ever sent.
+    bob.rt()
+        .set_tcp_options(|o| o.closed_port_rst = RstPolicy::Never);
+    must_let!(let Err(Fail::Ignored { .. }) = send_syn(&mut bob));
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    assert_eq!(bob.stats().tcp_rsts_suppressed, 2);
+}
+
+#[test]
+fn test_ack_template() {
+    fn serialize(pkt: impl PacketBuf<Bytes>) -> Vec<u8> {
+        let mut buf = vec![0u8; pkt.header_size()];
+        pkt.write_header(&mut buf[..]);
+        buf
+    }
+    fn ack(seq_num: u32, ack_num: u32, window_size: u16) -> TcpHeader {
+        let mut tcp_hdr = TcpHeader::new(
+            ip::Port::try_from(80).unwrap(),
+            ip::Port::try_from(12345).unwrap(),
+        );
+        tcp_hdr.ack = true;
+        tcp_hdr.seq_num = Wrapping(seq_num);
+        tcp_hdr.ack_num = Wrapping(ack_num);
+        tcp_hdr.window_size = window_size;
+        tcp_hdr
+    }
+
+    let mut template = AckTemplate::new(&forged_segment(ack(1, 2, 1024), true));
+    assert!(!AckTemplate::fits(&TcpHeader::new(
+        ip::Port::try_from(80).unwrap(),
+        ip::Port::try_from(12345).unwrap(),
+    )));
+
+    // Patching the template gives the same bytes, checksum included, as building it afresh.
+    for &(seq_num, ack_num, window_size) in &[(0x1234_5678, 0xffff_fff0, 0xffff), (7, 0, 0)] {
+        template.update(test_helpers::ALICE_MAC, &ack(seq_num, ack_num, window_size));
+        let expected = serialize(forged_segment(ack(seq_num, ack_num, window_size), true));
+        let frame = serialize(template.clone());
+        assert_eq!(frame, expected);
+        let tcp_hdr = parse_segment(Bytes::from_slice(&frame[..]));
+        assert_eq!(tcp_hdr.ack_num, Wrapping(ack_num));
+    }
 }
@@ -0,0 +1,197 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Traffic sources and sinks that run inside the engine, for measuring the stack itself without
+//! an application copying data in and out. See `Peer::start_source` and `Peer::start_sink`.
+
+use super::established::state::{
+    sender::SenderState,
+    ControlBlock,
+};
+use crate::{
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    scheduler::SchedulerHandle,
+};
+use futures::FutureExt;
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// What a traffic source fills the stream with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrafficPattern {
+    Zeros,
+    /// Each byte is its offset in the stream, modulo 256, so the far end can check what arrives.
+    Counting,
+}
+
+/// How much a traffic source has sent, or a sink has received.
+#[derive(Clone, Copy, Debug)]
+pub struct TrafficStats {
+    pub bytes: u64,
+    pub started: Instant,
+    /// Up to now, or to when the generator stopped: when it was stopped, or the connection
+    /// closed under it.
+    pub elapsed: Duration,
+    pub finished: bool,
+}
+
+impl TrafficStats {
+    /// The rate achieved over `elapsed`.
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 / secs
+    }
+}
+
+/// Both directions' generators on one connection, either of which may not be running.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpTraffic {
+    pub source: Option<TrafficStats>,
+    pub sink: Option<TrafficStats>,
+}
+
+struct Progress {
+    started: Instant,
+    bytes: Cell<u64>,
+    finished: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    fn finish(&self, now: Instant) {
+        if self.finished.get().is_none() {
+            self.finished.set(Some(now));
+        }
+    }
+}
+
+/// A running source or sink. Dropping it stops it.
+pub struct TrafficGenerator {
+    progress: Rc<Progress>,
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl TrafficGenerator {
+    /// Send `pattern` on `cb` as fast as the send window allows, or at `rate` bytes per second.
+    pub fn source<RT: Runtime>(
+        cb: Rc<ControlBlock<RT>>,
+        rate: Option<u64>,
+        pattern: TrafficPattern,
+    ) -> Self {
+        let progress = Rc::new(Progress {
+            started: cb.rt.now(),
+            bytes: Cell::new(0),
+            finished: Cell::new(None),
+        });
+        let rt = cb.rt.clone();
+        let handle = rt.spawn(source(cb, rate, pattern, progress.clone()));
+        Self { progress, handle }
+    }
+
+    /// Receive and discard everything that arrives on `cb`.
+    pub fn sink<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Self {
+        let progress = Rc::new(Progress {
+            started: cb.rt.now(),
+            bytes: Cell::new(0),
+            finished: Cell::new(None),
+        });
+        let rt = cb.rt.clone();
+        let handle = rt.spawn(sink(cb, progress.clone()));
+        Self { progress, handle }
+    }
+
+    pub fn stats(&self, now: Instant) -> TrafficStats {
+        let p = &self.progress;
+        let end = p.finished.get().unwrap_or(now);
+        TrafficStats {
+            bytes: p.bytes.get(),
+            started: p.started,
+            elapsed: end - p.started,
+            finished: p.finished.get().is_some(),
+        }
+    }
+
+    /// Stop generating and return the final stats.
+    pub fn stop(self, now: Instant) -> TrafficStats {
+        self.progress.finish(now);
+        self.stats(now)
+    }
+}
+
+async fn source<RT: Runtime>(
+    cb: Rc<ControlBlock<RT>>,
+    rate: Option<u64>,
+    pattern: TrafficPattern,
+    progress: Rc<Progress>,
+) {
+    // One segment at a time. A counting pattern's segments are cut from a template with 256
+    // bytes to spare, at the stream offset's position in the cycle.
+    let segment_size = cb.remote_mss();
+    let template: Vec<u8> = match pattern {
+        TrafficPattern::Zeros => vec![0; segment_size],
+        TrafficPattern::Counting => (0..segment_size + 256).map(|i| i as u8).collect(),
+    };
+    let template = RT::Buf::from_slice(&template[..]);
+    loop {
+        if cb.sender.state.get() != SenderState::Open {
+            break;
+        }
+        // Hand the sender more only once it's put everything it has on the wire, so the source
+        // never runs further ahead of the connection than a segment.
+        let sent_seq_no = cb.sender.sent_seq_no.get();
+        if cb.sender.unsent_seq_no.get() != sent_seq_no {
+            let (_, sent) = cb.sender.sent_seq_no.watch();
+            let (_, state) = cb.sender.state.watch();
+            futures::select_biased! {
+                _ = sent.fuse() => (),
+                _ = state.fuse() => (),
+            }
+            continue;
+        }
+        if let Some(rate) = rate {
+            let due = progress.bytes.get() as f64 / rate as f64;
+            cb.rt
+                .wait_until(progress.started + Duration::from_secs_f64(due))
+                .await;
+        }
+        let mut buf = template.clone();
+        if pattern == TrafficPattern::Counting {
+            let offset = (progress.bytes.get() % 256) as usize;
+            buf.adjust(offset);
+            buf.trim(256 - offset);
+        }
+        if cb.sender.send(buf, &cb).is_err() {
+            break;
+        }
+        progress
+            .bytes
+            .set(progress.bytes.get() + segment_size as u64);
+    }
+    progress.finish(cb.rt.now());
+}
+
+async fn sink<RT: Runtime>(cb: Rc<ControlBlock<RT>>, progress: Rc<Progress>) {
+    loop {
+        match futures::future::poll_fn(|ctx| cb.receiver.poll_recv(ctx)).await {
+            Ok(buf) => {
+                cb.throughput.record_delivered(cb.rt.now(), buf.len());
+                progress.bytes.set(progress.bytes.get() + buf.len() as u64);
+            },
+            // The remote closed its side, or the connection's gone.
+            Err(_) => break,
+        }
+    }
+    progress.finish(cb.rt.now());
+}
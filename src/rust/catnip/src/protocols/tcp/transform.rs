@@ -0,0 +1,84 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Byte-stream filters between a TCP connection and the application, so TLS or compression can be
+//! layered on without the TCP module knowing about it.
+//!
+//! A `StreamTransform` installed with `Peer::set_transform` sees everything the application writes
+//! before it reaches the send queue, and everything that arrives before the application can read
+//! it. A listener's `TransformFactory`, set with `Peer::set_listener_transform`, installs one on
+//! every connection it accepts, which is where a TLS server would terminate.
+//!
+//! Arriving bytes are taken off the receive queue as soon as the application tries to read, so
+//! they count as read for flow control even if the transform holds on to them, as it might a
+//! partial TLS record. Traffic sources and sinks bypass the transform.
+
+use crate::{
+    fail::Fail,
+    protocols::ipv4,
+};
+use std::rc::Rc;
+
+/// A filter on one connection's byte streams. Each call appends its output to `out`; output can
+/// be empty while the transform waits for more input.
+pub trait StreamTransform {
+    /// Called once when the transform is installed, for anything it sends first, such as a TLS
+    /// client's hello.
+    fn start(&mut self, _out: &mut Vec<u8>) -> Result<(), Fail> {
+        Ok(())
+    }
+
+    /// Turn bytes the application wrote into bytes to send.
+    fn on_write(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<(), Fail>;
+
+    /// Turn bytes that arrived into bytes for the application. Anything to send in reply, such as
+    /// handshake messages, goes in `reply`.
+    fn on_read(&mut self, data: &[u8], out: &mut Vec<u8>, reply: &mut Vec<u8>) -> Result<(), Fail>;
+}
+
+/// Makes a listener's transform for each connection it accepts, given the remote endpoint.
+pub type TransformFactory = Rc<dyn Fn(ipv4::Endpoint) -> Box<dyn StreamTransform>>;
+
+/// An installed transform and the output it's produced that the application hasn't read yet.
+pub struct StreamFilter {
+    transform: Box<dyn StreamTransform>,
+    readable: Vec<u8>,
+}
+
+impl StreamFilter {
+    pub fn new(transform: Box<dyn StreamTransform>) -> Self {
+        Self {
+            transform,
+            readable: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self) -> Result<Vec<u8>, Fail> {
+        let mut out = vec![];
+        self.transform.start(&mut out)?;
+        Ok(out)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, Fail> {
+        let mut out = vec![];
+        self.transform.on_write(data, &mut out)?;
+        Ok(out)
+    }
+
+    /// Feed in bytes that arrived, returning any reply to send.
+    pub fn read(&mut self, data: &[u8]) -> Result<Vec<u8>, Fail> {
+        let mut reply = vec![];
+        self.transform
+            .on_read(data, &mut self.readable, &mut reply)?;
+        Ok(reply)
+    }
+
+    pub fn readable(&self) -> &[u8] {
+        &self.readable[..]
+    }
+
+    /// Take the first `num_bytes` readable bytes, which the caller has checked are there.
+    pub fn take(&mut self, num_bytes: usize) -> Vec<u8> {
+        self.readable.drain(..num_bytes).collect()
+    }
+}
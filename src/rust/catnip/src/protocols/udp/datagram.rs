@@ -26,6 +26,9 @@ use std::{
 
 pub const UDP_HEADER_SIZE: usize = 8;
 
+/// `Fail::Malformed` details for a datagram whose checksum is wrong.
+pub const CHECKSUM_MISMATCH: &str = "UDP checksum mismatch";
+
 #[derive(Debug)]
 pub struct UdpHeader {
     pub src_port: Option<ip::Port>,
@@ -112,7 +115,7 @@ impl UdpHeader {
             let checksum = NetworkEndian::read_u16(&hdr_buf[6..8]);
             if checksum != 0 && checksum != udp_checksum(&ipv4_header, hdr_buf, &buf[UDP_HEADER_SIZE..]) {
                 return Err(Fail::Malformed {
-                    details: "UDP checksum mismatch",
+                    details: CHECKSUM_MISMATCH,
                 });
             }
         }
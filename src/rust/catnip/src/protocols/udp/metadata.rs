@@ -0,0 +1,65 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ipv4;
+use std::time::Instant;
+
+/// Linux's limit on segments per GSO send or GRO receive, which QUIC stacks already size their
+/// batches for.
+pub const MAX_SEGMENTS: usize = 64;
+
+/// The two ECN bits of the IPv4 header (RFC 3168).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Ecn {
+    NotEct = 0,
+    Ect1 = 1,
+    Ect0 = 2,
+    Ce = 3,
+}
+
+impl Default for Ecn {
+    fn default() -> Self {
+        Ecn::NotEct
+    }
+}
+
+impl From<u8> for Ecn {
+    fn from(bits: u8) -> Self {
+        match bits & 3 {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+}
+
+/// How `sendmsg` should send a buffer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TxMeta {
+    pub ecn: Ecn,
+    /// Split the buffer into datagrams of this many bytes (the last may be shorter). Overrides the
+    /// socket's GSO setting.
+    pub segment_size: Option<usize>,
+    /// Hold the datagrams until this time, for paced senders. Times that already passed send
+    /// immediately.
+    pub send_at: Option<Instant>,
+}
+
+/// What `recvmsg` knows about a received buffer, like the ancillary data `IP_RECVTOS`,
+/// `IP_RECVTTL` and `SO_TIMESTAMPNS` ask Linux for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RxMeta {
+    pub remote: Option<ipv4::Endpoint>,
+    pub ecn: Ecn,
+    /// The differentiated services code point of the IPv4 header (RFC 2474).
+    pub dscp: u8,
+    /// The IPv4 header's TTL on arrival, from which senders' hop counts can be guessed.
+    pub ttl: u8,
+    /// When the (first) datagram arrived: the device's timestamp if it gave one to
+    /// `Engine::receive_with_timestamp`, otherwise the runtime's clock at processing time.
+    pub timestamp: Instant,
+    /// Set when GRO coalesced several datagrams into this buffer: every one is this long except
+    /// possibly the last.
+    pub segment_size: Option<usize>,
+}
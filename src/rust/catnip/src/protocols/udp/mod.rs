@@ -2,8 +2,10 @@
 // Licensed under the MIT license.
 
 pub mod datagram;
+mod metadata;
 pub mod peer;
 mod options;
+mod service;
 
 #[cfg(test)]
 mod tests;
@@ -11,3 +13,16 @@ mod tests;
 pub use peer::UdpPeer as Peer;
 pub use options::UdpOptions as Options;
 pub use datagram::UdpHeader;
+pub use service::{
+    UdpService as Service,
+    UdpServiceKind as ServiceKind,
+    UdpServiceStats as ServiceStats,
+    DISCARD_PORT,
+    ECHO_PORT,
+};
+pub use metadata::{
+    Ecn,
+    RxMeta,
+    TxMeta,
+    MAX_SEGMENTS,
+};
@@ -1,9 +1,17 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
+use crate::protocols::ip;
+
 #[derive(Clone, Debug)]
 pub struct UdpOptions {
     pub rx_checksum_offload: bool,
     pub tx_checksum_offload: bool,
+    /// When off, every received UDP datagram is dropped at the IPv4 demux.
+    pub enabled: bool,
+    /// Run an echo service (RFC 862) on this port, usually `ECHO_PORT`, from engine startup.
+    pub echo_port: Option<ip::Port>,
+    /// Likewise for a discard service (RFC 863), usually on `DISCARD_PORT`.
+    pub discard_port: Option<ip::Port>,
 }
 
 impl Default for UdpOptions {
@@ -11,6 +19,9 @@ impl Default for UdpOptions {
         UdpOptions {
             rx_checksum_offload: false,
             tx_checksum_offload: false,
+            enabled: true,
+            echo_port: None,
+            discard_port: None,
         }
     }
 }
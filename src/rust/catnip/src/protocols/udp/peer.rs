@@ -1,9 +1,18 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::datagram::{
-    UdpDatagram,
-    UdpHeader,
+use super::{
+    datagram::{
+        UdpDatagram,
+        UdpHeader,
+        UDP_HEADER_SIZE,
+    },
+    metadata::{
+        Ecn,
+        RxMeta,
+        TxMeta,
+        MAX_SEGMENTS,
+    },
 };
 use crate::{
     fail::Fail,
@@ -18,26 +27,42 @@ use crate::{
     },
     protocols::{
         arp,
-        ethernet2::frame::{
-            EtherType2,
-            Ethernet2Header,
+        ethernet2::MacAddress,
+        icmpv4,
+        ip::{
+            PortProtocol,
+            PortTable,
         },
         ipv4,
-        ipv4::datagram::{
-            Ipv4Header,
-            Ipv4Protocol2,
+        ipv4::{
+            datagram::{
+                Ipv4Header,
+                IPV4_HEADER_SIZE,
+            },
+            Egress,
         },
+        packet::PacketBuilder,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
     },
-    runtime::Runtime,
     scheduler::SchedulerHandle,
+    snapshot::UdpSocketSnapshot,
 };
 use futures::channel::mpsc;
 use futures::stream::StreamExt;
+use futures::FutureExt;
 use std::collections::HashMap;
 use std::{
     cell::RefCell,
-    collections::VecDeque,
+    cmp::Ordering,
+    collections::{
+        BinaryHeap,
+        VecDeque,
+    },
     future::Future,
+    net::Ipv4Addr,
     pin::Pin,
     rc::Rc,
     task::{
@@ -45,14 +70,24 @@ use std::{
         Poll,
         Waker,
     },
+    time::Instant,
 };
 
 pub struct UdpPeer<RT: Runtime> {
     inner: Rc<RefCell<Inner<RT>>>,
 }
 
+struct Received<T> {
+    remote: Option<ipv4::Endpoint>,
+    ecn: Ecn,
+    dscp: u8,
+    ttl: u8,
+    timestamp: Instant,
+    data: T,
+}
+
 struct Listener<T> {
-    buf: VecDeque<(Option<ipv4::Endpoint>, T)>,
+    buf: VecDeque<Received<T>>,
     waker: Option<Waker>,
 }
 
@@ -62,69 +97,130 @@ struct Socket {
     local: Option<ipv4::Endpoint>,
     // `connect(2)` fixes a remote address
     remote: Option<ipv4::Endpoint>,
+    // Segment size for splitting outgoing buffers, like `UDP_SEGMENT`.
+    gso: Option<usize>,
+    // Whether `recvmsg` coalesces datagrams, like `UDP_GRO`.
+    gro: bool,
 }
 
-type OutgoingReq<T> = (Option<ipv4::Endpoint>, ipv4::Endpoint, T);
+// The most data a datagram can carry in an IPv4 packet of the largest size, fragmented or not.
+const MAX_PAYLOAD_SIZE: usize = 0xffff - IPV4_HEADER_SIZE - UDP_HEADER_SIZE;
+
+struct OutgoingReq<T> {
+    local: Option<ipv4::Endpoint>,
+    remote: ipv4::Endpoint,
+    ecn: Ecn,
+    buf: T,
+}
 type OutgoingSender<T> = mpsc::UnboundedSender<OutgoingReq<T>>;
 type OutgoingReceiver<T> = mpsc::UnboundedReceiver<OutgoingReq<T>>;
 
+type PacedSender<T> = mpsc::UnboundedSender<(Instant, OutgoingReq<T>)>;
+type PacedReceiver<T> = mpsc::UnboundedReceiver<(Instant, OutgoingReq<T>)>;
+
+// A datagram waiting for its pacing deadline. Ordered so the `BinaryHeap` pops the earliest
+// deadline first, and datagrams with the same deadline in the order they were sent.
+struct Paced<T> {
+    when: Instant,
+    seq_no: u64,
+    req: OutgoingReq<T>,
+}
+
+impl<T> PartialEq for Paced<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.when, self.seq_no) == (other.when, other.seq_no)
+    }
+}
+
+impl<T> Eq for Paced<T> {}
+
+impl<T> PartialOrd for Paced<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Paced<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.when, other.seq_no).cmp(&(self.when, self.seq_no))
+    }
+}
+
 struct Inner<RT: Runtime> {
     rt: RT,
     #[allow(unused)]
     arp: arp::Peer<RT>,
     file_table: FileTable,
+    ports: PortTable,
+    egress: Egress<RT>,
+    icmpv4_errors: icmpv4::ErrorSender<RT>,
 
     sockets: HashMap<FileDescriptor, Socket>,
     bound: HashMap<ipv4::Endpoint, Rc<RefCell<Listener<RT::Buf>>>>,
 
     outgoing: OutgoingSender<RT::Buf>,
+    paced: PacedSender<RT::Buf>,
     #[allow(unused)]
     handle: SchedulerHandle,
+    #[allow(unused)]
+    pacer_handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> Clone for UdpPeer<RT> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
 }
 
 impl<RT: Runtime> UdpPeer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        ports: PortTable,
+        egress: Egress<RT>,
+        icmpv4_errors: icmpv4::ErrorSender<RT>,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded();
-        let future = Self::background(rt.clone(), arp.clone(), rx);
+        let (paced_tx, paced_rx) = mpsc::unbounded();
+        let future = Self::background(rt.clone(), arp.clone(), egress.clone(), rx);
         let handle = rt.spawn(future);
+        let pacer_handle = rt.spawn(Self::pacer(rt.clone(), paced_rx, tx.clone()));
         let inner = Inner {
             rt,
             arp,
             file_table,
+            ports,
+            egress,
+            icmpv4_errors,
             sockets: HashMap::new(),
             bound: HashMap::new(),
             outgoing: tx,
+            paced: paced_tx,
             handle,
+            pacer_handle,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
         }
     }
 
-    async fn background(rt: RT, arp: arp::Peer<RT>, mut rx: OutgoingReceiver<RT::Buf>) {
-        while let Some((local, remote, buf)) = rx.next().await {
+    async fn background(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        egress: Egress<RT>,
+        mut rx: OutgoingReceiver<RT::Buf>,
+    ) {
+        while let Some(req) = rx.next().await {
             let r: Result<_, Fail> = try {
-                let link_addr = arp.query(remote.addr).await?;
-                let datagram = UdpDatagram {
-                    ethernet2_hdr: Ethernet2Header {
-                        dst_addr: link_addr,
-                        src_addr: rt.local_link_addr(),
-                        ether_type: EtherType2::Ipv4,
-                    },
-                    ipv4_hdr: Ipv4Header::new(
-                        rt.local_ipv4_addr(),
-                        remote.addr,
-                        Ipv4Protocol2::Udp,
-                    ),
-                    udp_hdr: UdpHeader {
-                        src_port: local.map(|l| l.port),
-                        dst_port: remote.port,
-                    },
-                    data: buf,
-
-                    tx_checksum_offload: rt.udp_options().tx_checksum_offload,
+                let link_addr = if req.remote.addr.is_broadcast() {
+                    MacAddress::broadcast()
+                } else {
+                    arp.query(req.remote.addr).await?
                 };
-                rt.transmit(datagram);
+                transmit(&rt, &egress, link_addr, req);
             };
             if let Err(e) = r {
                 warn!("Failed to send UDP message: {:?}", e);
@@ -132,6 +228,36 @@ impl<RT: Runtime> UdpPeer<RT> {
         }
     }
 
+    async fn pacer(rt: RT, mut rx: PacedReceiver<RT::Buf>, outgoing: OutgoingSender<RT::Buf>) {
+        let mut queue = BinaryHeap::new();
+        let mut seq_no = 0;
+        loop {
+            let deadline = match queue.peek() {
+                Some(&Paced { when, .. }) => rt.wait_until(when).left_future(),
+                None => futures::future::pending().right_future(),
+            };
+            futures::pin_mut!(deadline);
+            futures::select_biased! {
+                paced = rx.next() => match paced {
+                    Some((when, req)) => {
+                        queue.push(Paced { when, seq_no, req });
+                        seq_no += 1;
+                    },
+                    None => return,
+                },
+                _ = deadline.fuse() => {
+                    let now = rt.now();
+                    while queue.peek().map(|p| p.when <= now).unwrap_or(false) {
+                        let paced = queue.pop().unwrap();
+                        // Sends always go through the async path so they stay ordered behind
+                        // anything still waiting on ARP.
+                        outgoing.unbounded_send(paced.req).unwrap();
+                    }
+                },
+            }
+        }
+    }
+
     pub fn accept(&self) -> Fail {
         Fail::Malformed {
             details: "Operation not supported",
@@ -144,6 +270,8 @@ impl<RT: Runtime> UdpPeer<RT> {
         let socket = Socket {
             local: None,
             remote: None,
+            gso: None,
+            gro: false,
         };
         assert!(inner.sockets.insert(fd, socket).is_none());
         fd
@@ -151,21 +279,16 @@ impl<RT: Runtime> UdpPeer<RT> {
 
     pub fn bind(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
-        if inner.bound.contains_key(&addr) {
-            return Err(Fail::Malformed {
-                details: "Port already listening",
-            });
-        }
-        match inner.sockets.get_mut(&fd) {
-            Some(Socket { ref mut local, .. }) if local.is_none() => {
-                *local = Some(addr);
-            },
+        match inner.sockets.get(&fd) {
+            Some(Socket { local: None, .. }) => (),
             _ => {
                 return Err(Fail::Malformed {
                     details: "Invalid file descriptor on bind",
                 })
             },
         }
+        inner.ports.claim(PortProtocol::Udp, addr.port(), fd)?;
+        inner.sockets.get_mut(&fd).unwrap().local = Some(addr);
         let listener = Listener {
             buf: VecDeque::new(),
             waker: None,
@@ -190,51 +313,115 @@ impl<RT: Runtime> UdpPeer<RT> {
         }
     }
 
-    pub fn receive(&self, ipv4_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
+    pub fn receive(
+        &self,
+        ipv4_header: &Ipv4Header,
+        buf: RT::Buf,
+        timestamp: Instant,
+    ) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
+        // Kept to be quoted in an ICMP error if nobody's listening.
+        let datagram = buf.clone();
         let (hdr, data) = UdpHeader::parse(ipv4_header, buf, inner.rt.udp_options().rx_checksum_offload)?;
-        let local = ipv4::Endpoint::new(ipv4_header.dst_addr, hdr.dst_port);
+        let mut local = ipv4::Endpoint::new(ipv4_header.dst_addr, hdr.dst_port);
         let remote = hdr
             .src_port
             .map(|p| ipv4::Endpoint::new(ipv4_header.src_addr, p));
+        // Sockets bound to the unspecified address get whatever no other socket on the port does.
+        if !inner.bound.contains_key(&local) {
+            local = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, hdr.dst_port);
+        }
 
-        // TODO: Send ICMPv4 error in this condition.
-        let listener = inner.bound.get_mut(&local).ok_or_else(|| Fail::Malformed {
-            details: "Port not bound",
-        })?;
+        let listener = match inner.bound.get_mut(&local) {
+            Some(listener) => listener,
+            None => {
+                inner.icmpv4_errors.destination_unreachable(
+                    icmpv4::CODE_PORT_UNREACHABLE,
+                    ipv4_header,
+                    &datagram[..],
+                );
+                return Err(Fail::Malformed {
+                    details: "Port not bound",
+                });
+            },
+        };
         let mut l = listener.borrow_mut();
-        l.buf.push_back((remote, data));
+        l.buf.push_back(Received {
+            remote,
+            ecn: Ecn::from(ipv4_header.ecn),
+            dscp: ipv4_header.dscp,
+            ttl: ipv4_header.time_to_live,
+            timestamp,
+            data,
+        });
         l.waker.take().map(|w| w.wake());
         Ok(())
     }
 
     pub fn push(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
         let inner = self.inner.borrow();
-        let (local, remote) = match inner.sockets.get(&fd) {
+        let remote = match inner.sockets.get(&fd) {
             Some(Socket {
-                local,
                 remote: Some(remote),
-            }) => (*local, *remote),
+                ..
+            }) => *remote,
             _ => {
                 return Err(Fail::Malformed {
                     details: "Invalid file descriptor on push",
                 })
             },
         };
-        inner.send_datagram(buf, local, remote)
+        inner.sendmsg(fd, buf, remote, TxMeta::default())
     }
 
     pub fn pushto(&self, fd: FileDescriptor, buf: RT::Buf, to: ipv4::Endpoint) -> Result<(), Fail> {
-        let inner = self.inner.borrow();
-        let local = match inner.sockets.get(&fd) {
-            Some(Socket { local, .. }) => *local,
-            _ => {
-                return Err(Fail::Malformed {
-                    details: "Invalid file descriptor on pushto",
-                })
+        self.inner.borrow().sendmsg(fd, buf, to, TxMeta::default())
+    }
+
+    /// Send `buf` to `to`, split into several datagrams, marked, or paced as `meta` asks.
+    pub fn sendmsg(
+        &self,
+        fd: FileDescriptor,
+        buf: RT::Buf,
+        to: ipv4::Endpoint,
+        meta: TxMeta,
+    ) -> Result<(), Fail> {
+        self.inner.borrow().sendmsg(fd, buf, to, meta)
+    }
+
+    /// Split every buffer sent on `fd` into datagrams of `segment_size` bytes, or stop splitting
+    /// them.
+    pub fn set_gso(&self, fd: FileDescriptor, segment_size: Option<usize>) -> Result<(), Fail> {
+        if segment_size == Some(0) {
+            return Err(Fail::Invalid {
+                details: "GSO segment size must be positive",
+            });
+        }
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get_mut(&fd) {
+            Some(socket) => {
+                socket.gso = segment_size;
+                Ok(())
             },
-        };
-        inner.send_datagram(buf, local, to)
+            None => Err(Fail::Malformed {
+                details: "Invalid file descriptor",
+            }),
+        }
+    }
+
+    /// Let `recvmsg` on `fd` hand back runs of same-sized datagrams from one sender as a single
+    /// buffer.
+    pub fn set_gro(&self, fd: FileDescriptor, enabled: bool) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get_mut(&fd) {
+            Some(socket) => {
+                socket.gro = enabled;
+                Ok(())
+            },
+            None => Err(Fail::Malformed {
+                details: "Invalid file descriptor",
+            }),
+        }
     }
 
     pub fn pop(&self, fd: FileDescriptor) -> PopFuture<RT> {
@@ -250,6 +437,25 @@ impl<RT: Runtime> UdpPeer<RT> {
         PopFuture { listener, fd }
     }
 
+    /// Like `pop`, but also returns the datagram's metadata.
+    pub fn recvmsg(&self, fd: FileDescriptor) -> RecvMsgFuture<RT> {
+        let inner = self.inner.borrow();
+        let (listener, gro) = match inner.sockets.get(&fd) {
+            Some(Socket {
+                local: Some(local),
+                gro,
+                ..
+            }) => (Ok(inner.bound.get(&local).unwrap().clone()), *gro),
+            _ => (
+                Err(Fail::Malformed {
+                    details: "Invalid file descriptor",
+                }),
+                false,
+            ),
+        };
+        RecvMsgFuture { listener, fd, gro }
+    }
+
     pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
         let socket = match inner.sockets.remove(&fd) {
@@ -262,47 +468,157 @@ impl<RT: Runtime> UdpPeer<RT> {
         };
         if let Some(local) = socket.local {
             assert!(inner.bound.remove(&local).is_some());
+            inner.ports.release(PortProtocol::Udp, local.port(), fd);
         }
         inner.file_table.free(fd);
         Ok(())
     }
+
+    /// Bound sockets, ordered by file descriptor. Queued datagrams aren't included.
+    pub fn snapshot(&self) -> Vec<UdpSocketSnapshot> {
+        let inner = self.inner.borrow();
+        let mut sockets = inner
+            .sockets
+            .iter()
+            .filter_map(|(&fd, socket)| {
+                Some(UdpSocketSnapshot {
+                    fd,
+                    local: socket.local?.into(),
+                    remote: socket.remote.map(|r| r.into()),
+                })
+            })
+            .collect::<Vec<_>>();
+        sockets.sort_by_key(|s| s.fd);
+        sockets
+    }
+}
+
+fn datagram<RT: Runtime>(
+    rt: &RT,
+    link_addr: MacAddress,
+    req: OutgoingReq<RT::Buf>,
+) -> UdpDatagram<RT::Buf> {
+    let udp_hdr = UdpHeader {
+        src_port: req.local.map(|l| l.port),
+        dst_port: req.remote.port,
+    };
+    let mut datagram = PacketBuilder::new(rt, link_addr, req.remote.addr).udp(
+        udp_hdr,
+        req.buf,
+        rt.udp_options().tx_checksum_offload,
+    );
+    datagram.ipv4_hdr.ecn = req.ecn as u8;
+    datagram
+}
+
+// Send a datagram, in fragments if it's too large for the link.
+fn transmit<RT: Runtime>(
+    rt: &RT,
+    egress: &Egress<RT>,
+    link_addr: MacAddress,
+    req: OutgoingReq<RT::Buf>,
+) {
+    let mtu = rt.ipv4_options().mtu;
+    if IPV4_HEADER_SIZE + UDP_HEADER_SIZE + req.buf.len() <= mtu {
+        egress.transmit(datagram(rt, link_addr, req));
+        return;
+    }
+    let UdpDatagram {
+        ethernet2_hdr,
+        mut ipv4_hdr,
+        udp_hdr,
+        data,
+        ..
+    } = datagram(rt, link_addr, req);
+    // The checksum covers the whole datagram, so it can't be left to a device that only ever
+    // sees a fragment.
+    let mut payload = vec![0u8; UDP_HEADER_SIZE + data.len()];
+    udp_hdr.serialize(&mut payload[..UDP_HEADER_SIZE], &ipv4_hdr, &data[..], false);
+    payload[UDP_HEADER_SIZE..].copy_from_slice(&data[..]);
+    ipv4_hdr.identification = rt.rng_gen();
+    let payload = RT::Buf::from_slice(&payload[..]);
+    for fragment in ipv4::fragment(ethernet2_hdr, ipv4_hdr, payload, mtu) {
+        egress.transmit(fragment);
+    }
 }
 
 impl<RT: Runtime> Inner<RT> {
-    fn send_datagram(
+    fn sendmsg(
         &self,
+        fd: FileDescriptor,
         buf: RT::Buf,
-        local: Option<ipv4::Endpoint>,
         remote: ipv4::Endpoint,
+        meta: TxMeta,
     ) -> Result<(), Fail> {
-        // First, try to send the packet immediately.
-        if let Some(link_addr) = self.arp.try_query(remote.addr) {
-            let datagram = UdpDatagram {
-                ethernet2_hdr: Ethernet2Header {
-                    dst_addr: link_addr,
-                    src_addr: self.rt.local_link_addr(),
-                    ether_type: EtherType2::Ipv4,
-                },
-                ipv4_hdr: Ipv4Header::new(
-                    self.rt.local_ipv4_addr(),
-                    remote.addr,
-                    Ipv4Protocol2::Udp,
-                ),
-                udp_hdr: UdpHeader {
-                    src_port: local.map(|l| l.port),
-                    dst_port: remote.port,
-                },
-                data: buf,
-
-                tx_checksum_offload: self.rt.udp_options().tx_checksum_offload,
+        let (local, gso) = match self.sockets.get(&fd) {
+            Some(socket) => (socket.local, socket.gso),
+            None => {
+                return Err(Fail::Malformed {
+                    details: "Invalid file descriptor on pushto",
+                })
+            },
+        };
+        let segments = match meta.segment_size.or(gso) {
+            Some(0) => {
+                return Err(Fail::Invalid {
+                    details: "GSO segment size must be positive",
+                })
+            },
+            Some(size) if buf.len() > size => {
+                let num_segments = (buf.len() + size - 1) / size;
+                if num_segments > MAX_SEGMENTS {
+                    return Err(Fail::OutOfRange {
+                        details: "Too many GSO segments",
+                    });
+                }
+                let mut segments = Vec::with_capacity(num_segments);
+                let mut offset = 0;
+                while offset < buf.len() {
+                    let end = std::cmp::min(offset + size, buf.len());
+                    let mut segment = buf.clone();
+                    segment.adjust(offset);
+                    segment.trim(buf.len() - end);
+                    segments.push(segment);
+                    offset = end;
+                }
+                segments
+            },
+            _ => vec![buf],
+        };
+        if segments.iter().any(|s| s.len() > MAX_PAYLOAD_SIZE) {
+            return Err(Fail::OutOfRange {
+                details: "Datagram too large",
+            });
+        }
+        for buf in segments {
+            let req = OutgoingReq {
+                local,
+                remote,
+                ecn: meta.ecn,
+                buf,
             };
-            self.rt.transmit(datagram);
+            self.send_datagram(req, meta.send_at);
+        }
+        Ok(())
+    }
+
+    fn send_datagram(&self, req: OutgoingReq<RT::Buf>, send_at: Option<Instant>) {
+        if let Some(when) = send_at {
+            if when > self.rt.now() {
+                self.paced.unbounded_send((when, req)).unwrap();
+                return;
+            }
+        }
+        // First, try to send the packet immediately. Broadcasts need no ARP.
+        if req.remote.addr.is_broadcast() {
+            transmit(&self.rt, &self.egress, MacAddress::broadcast(), req);
+        } else if let Some(link_addr) = self.arp.try_query(req.remote.addr) {
+            transmit(&self.rt, &self.egress, link_addr, req);
         }
         // Otherwise defer to the async path.
         else {
-            self.outgoing.unbounded_send((local, remote, buf)).unwrap();
+            self.outgoing.unbounded_send(req).unwrap();
         }
-        Ok(())
     }
 }
 
@@ -321,7 +637,7 @@ impl<RT: Runtime> Future for PopFuture<RT> {
             Ok(ref l) => {
                 let mut listener = l.borrow_mut();
                 match listener.buf.pop_front() {
-                    Some(r) => return Poll::Ready(Ok(r)),
+                    Some(r) => return Poll::Ready(Ok((r.remote, r.data))),
                     None => (),
                 }
                 let waker = ctx.waker();
@@ -332,6 +648,74 @@ impl<RT: Runtime> Future for PopFuture<RT> {
     }
 }
 
+pub struct RecvMsgFuture<RT: Runtime> {
+    pub fd: FileDescriptor,
+    listener: Result<Rc<RefCell<Listener<RT::Buf>>>, Fail>,
+    gro: bool,
+}
+
+impl<RT: Runtime> Future for RecvMsgFuture<RT> {
+    type Output = Result<(RxMeta, RT::Buf), Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let mut listener = match self_.listener {
+            Err(ref e) => return Poll::Ready(Err(e.clone())),
+            Ok(ref l) => l.borrow_mut(),
+        };
+        let first = match listener.buf.pop_front() {
+            Some(r) => r,
+            None => {
+                listener.waker = Some(ctx.waker().clone());
+                return Poll::Pending;
+            },
+        };
+        let mut meta = RxMeta {
+            remote: first.remote,
+            ecn: first.ecn,
+            dscp: first.dscp,
+            ttl: first.ttl,
+            timestamp: first.timestamp,
+            segment_size: None,
+        };
+        if !self_.gro {
+            return Poll::Ready(Ok((meta, first.data)));
+        }
+
+        // Coalesce the datagrams a GSO send on the other end would have produced: same sender,
+        // marking and TTL, all the same size except for a shorter last one.
+        let size = first.data.len();
+        let mut segments = vec![first.data];
+        let mut last_short = false;
+        while segments.len() < MAX_SEGMENTS && !last_short {
+            let next = match listener.buf.front() {
+                Some(next) => next,
+                None => break,
+            };
+            if next.remote != meta.remote
+                || next.ecn != meta.ecn
+                || next.dscp != meta.dscp
+                || next.ttl != meta.ttl
+                || next.data.len() > size
+                || next.data.is_empty()
+            {
+                break;
+            }
+            last_short = next.data.len() < size;
+            segments.push(listener.buf.pop_front().unwrap().data);
+        }
+        if segments.len() == 1 {
+            return Poll::Ready(Ok((meta, segments.pop().unwrap())));
+        }
+        meta.segment_size = Some(size);
+        let mut buf = Vec::with_capacity(segments.iter().map(|s| s.len()).sum());
+        for segment in &segments {
+            buf.extend_from_slice(&segment[..]);
+        }
+        Poll::Ready(Ok((meta, RT::Buf::from_slice(&buf[..]))))
+    }
+}
+
 pub enum UdpOperation<RT: Runtime> {
     Accept(FileDescriptor, Fail),
     Connect(FileDescriptor, Result<(), Fail>),
@@ -0,0 +1,136 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Built-in echo (RFC 862) and discard (RFC 863) responders, so there's always something on the
+//! engine to test against. They run over ordinary UDP sockets, which the engine owns.
+
+use super::peer::UdpPeer;
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    runtime::Runtime,
+    scheduler::SchedulerHandle,
+};
+use std::{
+    cell::Cell,
+    rc::Rc,
+};
+
+pub const ECHO_PORT: u16 = 7;
+pub const DISCARD_PORT: u16 = 9;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UdpServiceKind {
+    /// Sends every datagram back where it came from.
+    Echo,
+    /// Drops every datagram.
+    Discard,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UdpServiceStats {
+    pub kind: UdpServiceKind,
+    pub local: ipv4::Endpoint,
+    /// Datagrams that have arrived, whether or not they were answered.
+    pub num_received: u64,
+}
+
+pub struct UdpService<RT: Runtime> {
+    udp: UdpPeer<RT>,
+    fd: FileDescriptor,
+    kind: UdpServiceKind,
+    local: ipv4::Endpoint,
+    num_received: Rc<Cell<u64>>,
+
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> UdpService<RT> {
+    /// Start `kind` on `port` of the local address.
+    pub fn new(
+        rt: RT,
+        udp: UdpPeer<RT>,
+        kind: UdpServiceKind,
+        port: ip::Port,
+    ) -> Result<Self, Fail> {
+        let fd = udp.socket();
+        let local = ipv4::Endpoint::new(rt.local_ipv4_addr(), port);
+        if let Err(e) = udp.bind(fd, local) {
+            udp.close(fd)?;
+            return Err(e);
+        }
+        let num_received = Rc::new(Cell::new(0));
+        let future = Self::background(udp.clone(), fd, kind, local, num_received.clone());
+        let handle = rt.spawn(future);
+        Ok(Self {
+            udp,
+            fd,
+            kind,
+            local,
+            num_received,
+            handle,
+        })
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    pub fn stats(&self) -> UdpServiceStats {
+        UdpServiceStats {
+            kind: self.kind,
+            local: self.local,
+            num_received: self.num_received.get(),
+        }
+    }
+
+    async fn background(
+        udp: UdpPeer<RT>,
+        fd: FileDescriptor,
+        kind: UdpServiceKind,
+        local: ipv4::Endpoint,
+        num_received: Rc<Cell<u64>>,
+    ) {
+        loop {
+            let (remote, buf) = match udp.pop(fd).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("UDP {:?} service socket failed: {:?}", kind, e);
+                    return;
+                },
+            };
+            num_received.set(num_received.get() + 1);
+            if kind != UdpServiceKind::Echo {
+                continue;
+            }
+            match remote {
+                // Two echo services pointed at each other would bounce a datagram forever.
+                Some(remote) if remote.port == local.port => {
+                    debug!("Not echoing to {:?}, another echo service", remote);
+                },
+                Some(remote) => {
+                    if let Err(e) = udp.pushto(fd, buf, remote) {
+                        warn!("Failed to echo UDP datagram to {:?}: {:?}", remote, e);
+                    }
+                },
+                None => debug!("Not echoing a datagram without a source port"),
+            }
+        }
+    }
+}
+
+impl<RT: Runtime> Drop for UdpService<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!(
+                "Failed to close UDP {:?} service socket: {:?}",
+                self.kind, e
+            );
+        }
+    }
+}
@@ -123,3 +123,490 @@
 //     // assert_eq!(next_hop_mtu, &0u16);
 //     // todo: validate `context`
 // }
+
+use super::{
+    Ecn,
+    ServiceKind,
+    TxMeta,
+    DISCARD_PORT,
+    ECHO_PORT,
+};
+use crate::{
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::Fail,
+    protocols::{
+        ethernet2::frame::{
+            ETHERNET2_HEADER_SIZE,
+            MIN_FRAME_SIZE,
+        },
+        icmpv4,
+        ip,
+        ip::{
+            PortProtocol,
+            PortUse,
+        },
+        ipv4,
+        ipv4::datagram::{
+            Ipv4Header,
+            DEFAULT_IPV4_TTL,
+            IPV4_HEADER_SIZE,
+        },
+    },
+    runtime::RuntimeBuf,
+    sync::Bytes,
+    test_helpers,
+};
+use futures::task::noop_waker_ref;
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[test]
+fn gso_gro_and_ecn() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let alice_addr =
+        ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(4433).unwrap());
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+    alice.udp_set_gso(alice_fd, Some(4)).unwrap();
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+    bob.udp_set_gro(bob_fd, true).unwrap();
+
+    let meta = TxMeta {
+        ecn: Ecn::Ect0,
+        ..TxMeta::default()
+    };
+    let data = Bytes::from_slice(b"0123456789");
+    alice.udp_sendmsg(alice_fd, data, bob_addr, meta).unwrap();
+
+    // The socket's segment size splits the buffer into three datagrams, all marked ECT(0).
+    assert_eq!(alice.rt().num_outgoing(), 3);
+    for _ in 0..3 {
+        let frame = alice.rt().pop_frame();
+        assert_eq!(frame[ETHERNET2_HEADER_SIZE + 1] & 3, Ecn::Ect0 as u8);
+        bob.receive(frame).unwrap();
+    }
+
+    let mut recv_future = bob.udp_recvmsg(bob_fd);
+    must_let!(let Poll::Ready(Ok((meta, buf))) = Future::poll(Pin::new(&mut recv_future), &mut ctx));
+    assert_eq!(&buf[..], b"0123456789");
+    assert_eq!(meta.remote, Some(alice_addr));
+    assert_eq!(meta.ecn, Ecn::Ect0);
+    assert_eq!(meta.segment_size, Some(4));
+    assert_eq!(meta.timestamp, now);
+
+    // Too many segments for one send.
+    let data = Bytes::from_slice(&[0u8; 300][..]);
+    assert!(alice
+        .udp_sendmsg(alice_fd, data, bob_addr, TxMeta::default())
+        .is_err());
+}
+
+#[test]
+fn paced_send() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let meta = TxMeta {
+        send_at: Some(now + Duration::from_millis(10)),
+        ..TxMeta::default()
+    };
+    alice
+        .udp_sendmsg(alice_fd, Bytes::from_slice(b"later"), bob_addr, meta)
+        .unwrap();
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.rt().num_outgoing(), 0);
+
+    now += Duration::from_millis(10);
+    alice.advance_clock(now);
+    alice.rt().poll_scheduler();
+    alice.rt().poll_scheduler();
+    assert_eq!(alice.rt().num_outgoing(), 1);
+}
+
+#[test]
+fn disabled_protocols() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt().set_udp_options(|o| o.enabled = false);
+    bob.rt().set_icmpv4_options(|o| o.echo_reply = false);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(53).unwrap());
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice
+        .udp_sendmsg(
+            alice_fd,
+            Bytes::from_slice(b"query"),
+            bob_addr,
+            TxMeta::default(),
+        )
+        .unwrap();
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(alice.rt().pop_frame()));
+
+    // Bob doesn't answer pings, but can still send them.
+    let mut ping = Box::pin(alice.ping(test_helpers::BOB_IPV4, Some(Duration::from_secs(1))));
+    assert!(Future::poll(ping.as_mut(), &mut ctx).is_pending());
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(alice.rt().pop_frame()));
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    let stats = bob.stats();
+    assert_eq!(stats.rx_protocol_disabled, 2);
+    assert_eq!(stats.rx_dropped, 2);
+}
+
+#[test]
+fn device_timestamps() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(b"stamped"), bob_addr)
+        .unwrap();
+    let stamped = now + Duration::from_millis(3);
+    bob.rt().advance_clock(now + Duration::from_millis(10));
+    bob.receive_with_timestamp(alice.rt().pop_frame(), stamped)
+        .unwrap();
+
+    let mut recv_future = bob.udp_recvmsg(bob_fd);
+    must_let!(let Poll::Ready(Ok((meta, _))) = Future::poll(Pin::new(&mut recv_future), &mut ctx));
+    assert_eq!(meta.timestamp, stamped);
+}
+
+#[test]
+fn ttl_and_dscp() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+    bob.udp_set_gro(bob_fd, true).unwrap();
+
+    for _ in 0..2 {
+        alice
+            .udp_pushto(alice_fd, Bytes::from_slice(b"hops"), bob_addr)
+            .unwrap();
+    }
+    bob.receive(alice.rt().pop_frame()).unwrap();
+
+    // The second datagram comes the long way round, marked expedited forwarding.
+    let frame = alice.rt().pop_frame();
+    let mut packet = frame.clone();
+    packet.adjust(ETHERNET2_HEADER_SIZE);
+    let (mut header, payload) = Ipv4Header::parse(packet).unwrap();
+    header.time_to_live = 50;
+    header.dscp = 46;
+    let mut rerouted = frame[..].to_vec();
+    header.serialize(
+        &mut rerouted[ETHERNET2_HEADER_SIZE..][..IPV4_HEADER_SIZE],
+        payload.len(),
+    );
+    bob.receive(Bytes::from_slice(&rerouted[..])).unwrap();
+
+    // So GRO doesn't coalesce it with the first.
+    let mut recv_future = bob.udp_recvmsg(bob_fd);
+    must_let!(let Poll::Ready(Ok((meta, buf))) = Future::poll(Pin::new(&mut recv_future), &mut ctx));
+    assert_eq!(&buf[..], b"hops");
+    assert_eq!((meta.ttl, meta.dscp), (DEFAULT_IPV4_TTL, 0));
+    let mut recv_future = bob.udp_recvmsg(bob_fd);
+    must_let!(let Poll::Ready(Ok((meta, buf))) = Future::poll(Pin::new(&mut recv_future), &mut ctx));
+    assert_eq!(&buf[..], b"hops");
+    assert_eq!((meta.ttl, meta.dscp), (50, 46));
+    assert_eq!(meta.timestamp, now);
+}
+
+#[test]
+fn short_frames() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    // A one-byte datagram is padded out to a minimum-size frame with zeros.
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(b"x"), bob_addr)
+        .unwrap();
+    let frame = alice.rt().pop_frame();
+    assert_eq!(frame.len(), MIN_FRAME_SIZE);
+    let unpadded = ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE + 8 + 1;
+    assert!(frame[unpadded..].iter().all(|&b| b == 0));
+
+    // Whatever the padding contains, it isn't taken for payload.
+    let mut garbled = frame[..].to_vec();
+    for b in &mut garbled[unpadded..] {
+        *b = 0xff;
+    }
+    bob.receive(Bytes::from_slice(&garbled[..])).unwrap();
+    let mut recv_future = bob.udp_recvmsg(bob_fd);
+    must_let!(let Poll::Ready(Ok((_, buf))) = Future::poll(Pin::new(&mut recv_future), &mut ctx));
+    assert_eq!(&buf[..], b"x");
+}
+
+#[test]
+fn port_unreachable() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    bob.rt()
+        .set_icmpv4_options(|o| o.error_rate_limit = Some(1));
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(53).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let query = Bytes::from_slice(b"a query longer than eight bytes");
+    alice.udp_pushto(alice_fd, query.clone(), bob_addr).unwrap();
+    let datagram = alice.rt().pop_frame();
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(datagram.clone()));
+
+    // Bob says so, quoting the datagram's IPv4 header and just its UDP header.
+    let error = bob.rt().pop_frame();
+    let icmpv4 = &error[ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE..];
+    assert_eq!(&icmpv4[..2], &[3, icmpv4::CODE_PORT_UNREACHABLE]);
+    let context = &icmpv4[8..];
+    assert_eq!(context.len(), 28);
+    assert_eq!(&context[..], &datagram[ETHERNET2_HEADER_SIZE..][..28]);
+
+    // A second one within the second is dropped silently.
+    alice.udp_pushto(alice_fd, query.clone(), bob_addr).unwrap();
+    assert!(bob.receive(alice.rt().pop_frame()).is_err());
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    assert_eq!(bob.stats().icmpv4_errors_suppressed, 1);
+
+    now += Duration::from_secs(1);
+    bob.advance_clock(now);
+    alice.udp_pushto(alice_fd, query, bob_addr).unwrap();
+    assert!(bob.receive(alice.rt().pop_frame()).is_err());
+    assert_eq!(bob.rt().num_outgoing(), 1);
+}
+
+#[test]
+fn echo_and_discard_services() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let rt =
+        test_helpers::TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+    rt.set_udp_options(|o| {
+        o.echo_port = Some(ip::Port::try_from(ECHO_PORT).unwrap());
+        o.discard_port = Some(ip::Port::try_from(DISCARD_PORT).unwrap());
+    });
+    let mut bob = Engine::new(rt).unwrap();
+
+    let alice_addr =
+        ipv4::Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(5000).unwrap());
+    let echo_addr = ipv4::Endpoint::new(
+        test_helpers::BOB_IPV4,
+        ip::Port::try_from(ECHO_PORT).unwrap(),
+    );
+    let discard_addr = ipv4::Endpoint::new(
+        test_helpers::BOB_IPV4,
+        ip::Port::try_from(DISCARD_PORT).unwrap(),
+    );
+    let alice_fd = alice.socket(Protocol::Udp);
+    alice.bind(alice_fd, alice_addr).unwrap();
+
+    // The echo service sends the datagram straight back.
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(b"hello"), echo_addr)
+        .unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    let mut pop_future = alice.udp_pop(alice_fd);
+    must_let!(let Poll::Ready(Ok((Some(remote), buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(remote, echo_addr);
+    assert_eq!(&buf[..], b"hello");
+
+    // The discard service doesn't answer.
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(b"hello"), discard_addr)
+        .unwrap();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    let services = bob.udp_services();
+    assert_eq!(services.len(), 2);
+    assert_eq!(services[0].kind, ServiceKind::Echo);
+    assert_eq!(services[0].local, echo_addr);
+    assert_eq!(services[0].num_received, 1);
+    assert_eq!(services[1].kind, ServiceKind::Discard);
+    assert_eq!(services[1].num_received, 1);
+
+    // Their sockets are the engine's, so they aren't migrated with the application's.
+    assert!(bob.snapshot().udp_sockets.is_empty());
+}
+
+#[test]
+fn port_table() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let port = ip::Port::try_from(80).unwrap();
+    let addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port);
+
+    // TCP and UDP each have their own port 80, and a second claim on either is turned away.
+    let tcp_fd = alice.socket(Protocol::Tcp);
+    alice.bind(tcp_fd, addr).unwrap();
+    let other_tcp_fd = alice.socket(Protocol::Tcp);
+    must_let!(let Err(Fail::ResourceBusy { .. }) = alice.bind(other_tcp_fd, addr));
+    let udp_fd = alice.socket(Protocol::Udp);
+    alice.bind(udp_fd, addr).unwrap();
+    let other_udp_fd = alice.socket(Protocol::Udp);
+    must_let!(let Err(Fail::ResourceBusy { .. }) = alice.bind(other_udp_fd, addr));
+
+    // An active open's port shows up alongside them.
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+    let _connect_future = alice.tcp_connect(other_tcp_fd, bob_addr);
+    let claims = alice.ports();
+    must_let!(let [bound, ephemeral, udp] = &claims[..]);
+    assert_eq!(
+        (bound.protocol, bound.port, bound.fd, bound.usage),
+        (PortProtocol::Tcp, port, tcp_fd, PortUse::Bound)
+    );
+    assert_eq!(
+        (ephemeral.protocol, ephemeral.fd, ephemeral.usage),
+        (PortProtocol::Tcp, other_tcp_fd, PortUse::Ephemeral)
+    );
+    assert!(ephemeral.port.is_private());
+    assert_eq!(
+        (udp.protocol, udp.port, udp.fd, udp.usage),
+        (PortProtocol::Udp, port, udp_fd, PortUse::Bound)
+    );
+
+    // Closing the UDP socket frees its port.
+    alice.close(udp_fd).unwrap();
+    alice.bind(other_udp_fd, addr).unwrap();
+}
+
+#[test]
+fn fragmentation_and_reassembly() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_ipv4_options(|o| o.mtu = 576);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    // The UDP header and 1400 bytes take two full fragments of 552 bytes and a shorter last one.
+    let data = (0..1400).map(|i| i as u8).collect::<Vec<_>>();
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(&data[..]), bob_addr)
+        .unwrap();
+    assert_eq!(alice.rt().num_outgoing(), 3);
+    let fragments = (0..3).map(|_| alice.rt().pop_frame()).collect::<Vec<_>>();
+    for (i, fragment) in fragments.iter().enumerate() {
+        let flags = fragment[ETHERNET2_HEADER_SIZE + 6];
+        assert_eq!(flags & 0x20 != 0, i < 2);
+    }
+    assert_eq!(fragments[0].len(), ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE + 552);
+
+    // They can arrive in any order.
+    for fragment in fragments.iter().rev() {
+        bob.receive(fragment.clone()).unwrap();
+    }
+    let mut recv_future = bob.udp_recvmsg(bob_fd);
+    must_let!(let Poll::Ready(Ok((_, buf))) = Future::poll(Pin::new(&mut recv_future), &mut ctx));
+    assert_eq!(&buf[..], &data[..]);
+
+    // Fragments that overlap one already received end their datagram.
+    alice
+        .udp_pushto(alice_fd, Bytes::from_slice(&data[..]), bob_addr)
+        .unwrap();
+    let first = alice.rt().pop_frame();
+    bob.receive(first.clone()).unwrap();
+    let mut packet = first.clone();
+    packet.adjust(ETHERNET2_HEADER_SIZE);
+    let (mut header, payload) = Ipv4Header::parse(packet).unwrap();
+    header.fragment_offset = 1;
+    let mut overlapping = first[..].to_vec();
+    header.serialize(
+        &mut overlapping[ETHERNET2_HEADER_SIZE..][..IPV4_HEADER_SIZE],
+        payload.len(),
+    );
+    must_let!(let Err(Fail::Malformed { .. }) = bob.receive(Bytes::from_slice(&overlapping[..])));
+}
+
+#[test]
+fn reassembly_timeout() {
+    let mut now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.rt().set_ipv4_options(|o| o.mtu = 576);
+
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(443).unwrap());
+    let alice_fd = alice.socket(Protocol::Udp);
+    let bob_fd = bob.socket(Protocol::Udp);
+    bob.bind(bob_fd, bob_addr).unwrap();
+
+    let data = Bytes::from_slice(&[0u8; 1400][..]);
+    alice.udp_pushto(alice_fd, data.clone(), bob_addr).unwrap();
+    let first = alice.rt().pop_frame();
+    bob.receive(first.clone()).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 0);
+
+    // The rest never arrive, so bob gives up and says so, quoting the first fragment.
+    now += Duration::from_secs(30);
+    bob.advance_clock(now);
+    bob.rt().poll_scheduler();
+    let error = bob.rt().pop_frame();
+    let icmpv4 = &error[ETHERNET2_HEADER_SIZE + IPV4_HEADER_SIZE..];
+    assert_eq!(&icmpv4[..2], &[11, icmpv4::CODE_REASSEMBLY_TIME_EXCEEDED]);
+    assert_eq!(&icmpv4[8..36], &first[ETHERNET2_HEADER_SIZE..][..28]);
+    assert_eq!(bob.stats().ipv4_reassembly_timeouts, 1);
+
+    // Without the first fragment there's nothing to quote, so the datagram is dropped quietly.
+    alice.udp_pushto(alice_fd, data, bob_addr).unwrap();
+    alice.rt().pop_frame();
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    now += Duration::from_secs(30);
+    bob.advance_clock(now);
+    bob.rt().poll_scheduler();
+    assert_eq!(bob.rt().num_outgoing(), 0);
+    assert_eq!(bob.stats().ipv4_reassembly_timeouts, 2);
+}
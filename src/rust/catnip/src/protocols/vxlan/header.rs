@@ -0,0 +1,53 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    fail::Fail,
+    runtime::RuntimeBuf,
+};
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+
+pub const VXLAN_HEADER_SIZE: usize = 8;
+pub const MAX_VNI: u32 = (1 << 24) - 1;
+
+// Set when the VNI is valid, which it must be.
+const FLAG_VNI: u8 = 0x08;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VxlanHeader {
+    pub vni: u32,
+}
+
+impl VxlanHeader {
+    pub fn new(vni: u32) -> Self {
+        assert!(vni <= MAX_VNI);
+        Self { vni }
+    }
+
+    pub fn parse<T: RuntimeBuf>(mut buf: T) -> Result<(Self, T), Fail> {
+        if buf.len() < VXLAN_HEADER_SIZE {
+            return Err(Fail::Malformed {
+                details: "VXLAN header too small",
+            });
+        }
+        // Receivers must ignore the reserved bits.
+        if buf[0] & FLAG_VNI == 0 {
+            return Err(Fail::Malformed {
+                details: "VXLAN VNI flag not set",
+            });
+        }
+        let vni = NetworkEndian::read_u32(&buf[4..8]) >> 8;
+        buf.adjust(VXLAN_HEADER_SIZE);
+        Ok((Self { vni }, buf))
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) {
+        assert_eq!(buf.len(), VXLAN_HEADER_SIZE);
+        buf[0] = FLAG_VNI;
+        buf[1..4].copy_from_slice(&[0, 0, 0]);
+        NetworkEndian::write_u32(&mut buf[4..8], self.vni << 8);
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! VXLAN (RFC 7348) tunnel endpoint.
+//!
+//! A `Vtep` listens on a UDP port of the underlay engine and carries Ethernet frames for any
+//! number of virtual networks, each identified by its 24-bit VNI. Attaching a VNI returns a
+//! [`VxlanDevice`], which is an ordinary
+//! [`backends::NetworkDevice`](crate::backends::NetworkDevice), so a second engine on a
+//! `DeviceRuntime` can sit on the overlay with its own link and IPv4 addresses.
+//!
+//! Forwarding is flood-and-learn: broadcast, multicast and unknown unicast frames go to every
+//! remote VTEP configured for the network, and the inner source MAC of each frame we receive is
+//! learned against the VTEP it came from.
+
+mod header;
+mod options;
+mod vtep;
+
+#[cfg(test)]
+mod tests;
+
+pub use header::{
+    VxlanHeader,
+    MAX_VNI,
+    VXLAN_HEADER_SIZE,
+};
+pub use options::VxlanOptions as Options;
+pub use vtep::{
+    Vtep,
+    VxlanDevice,
+};
@@ -0,0 +1,44 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ip;
+use std::convert::TryFrom;
+
+const VXLAN_PORT: u16 = 4789;
+
+#[derive(Clone, Debug)]
+pub struct VxlanOptions {
+    pub local_port: ip::Port,
+    /// Port remote VTEPs listen on.
+    pub remote_port: ip::Port,
+    /// Received frames each virtual network buffers for its device before dropping new ones.
+    pub rx_queue_size: usize,
+}
+
+impl Default for VxlanOptions {
+    fn default() -> Self {
+        VxlanOptions {
+            local_port: ip::Port::try_from(VXLAN_PORT).unwrap(),
+            remote_port: ip::Port::try_from(VXLAN_PORT).unwrap(),
+            rx_queue_size: 1024,
+        }
+    }
+}
+
+impl VxlanOptions {
+    pub fn local_port(mut self, value: ip::Port) -> Self {
+        self.local_port = value;
+        self
+    }
+
+    pub fn remote_port(mut self, value: ip::Port) -> Self {
+        self.remote_port = value;
+        self
+    }
+
+    pub fn rx_queue_size(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.rx_queue_size = value;
+        self
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    Options,
+    VxlanHeader,
+};
+use crate::{
+    backends::NetworkDevice,
+    fail::Fail,
+    protocols::ethernet2::{
+        frame::ETHERNET2_HEADER_SIZE,
+        MacAddress,
+    },
+    runtime::{
+        RuntimeBuf,
+        RECEIVE_BATCH_SIZE,
+    },
+    sync::Bytes,
+    test_helpers,
+};
+use arrayvec::ArrayVec;
+use must_let::must_let;
+use std::time::Instant;
+
+const INNER_ALICE_MAC: MacAddress = MacAddress::new([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+const INNER_BOB_MAC: MacAddress = MacAddress::new([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]);
+
+// Where the outer IPv4 destination sits in an encapsulated frame.
+const DST_ADDR_OFFSET: usize = ETHERNET2_HEADER_SIZE + 16;
+
+fn inner_frame(dst: MacAddress, src: MacAddress, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET2_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&dst.octets());
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&[0x88, 0xb5]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn receive_all<D: NetworkDevice>(device: &mut D) -> Vec<Bytes> {
+    let mut batch = ArrayVec::<[Bytes; RECEIVE_BATCH_SIZE]>::new();
+    device.poll_receive(&mut batch);
+    batch.into_iter().collect()
+}
+
+#[test]
+fn header() {
+    let mut buf = vec![0u8; 8];
+    VxlanHeader::new(0xabcdef).serialize(&mut buf[..]);
+    assert_eq!(&buf[..], &[0x08, 0, 0, 0, 0xab, 0xcd, 0xef, 0]);
+    buf.push(0xff);
+    let (header, payload) = VxlanHeader::parse(Bytes::from_slice(&buf[..])).unwrap();
+    assert_eq!(header.vni, 0xabcdef);
+    assert_eq!(&payload[..], &[0xff]);
+
+    buf[0] = 0;
+    must_let!(let Err(Fail::Malformed { .. }) = VxlanHeader::parse(Bytes::from_slice(&buf[..])));
+}
+
+#[test]
+fn flood_and_learn() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.vxlan_start(Options::default()).unwrap();
+    bob.vxlan_start(Options::default()).unwrap();
+
+    let remotes = vec![test_helpers::CARRIE_IPV4, test_helpers::BOB_IPV4];
+    let mut alice_dev = alice.vxlan_attach(100, remotes).unwrap();
+    let mut bob_dev = bob
+        .vxlan_attach(100, vec![test_helpers::ALICE_IPV4])
+        .unwrap();
+    must_let!(let Err(Fail::ResourceBusy { .. }) = bob.vxlan_attach(100, vec![]));
+
+    // Broadcasts are flooded to every remote VTEP.
+    let frame = inner_frame(MacAddress::broadcast(), INNER_ALICE_MAC, b"hello");
+    alice_dev.transmit(&frame[..]);
+    let to_carrie = alice.rt().pop_frame();
+    assert_eq!(
+        to_carrie[DST_ADDR_OFFSET..][..4],
+        test_helpers::CARRIE_IPV4.octets()
+    );
+    let to_bob = alice.rt().pop_frame();
+    assert_eq!(
+        to_bob[DST_ADDR_OFFSET..][..4],
+        test_helpers::BOB_IPV4.octets()
+    );
+
+    bob.receive(to_bob).unwrap();
+    bob.rt().poll_scheduler();
+    let received = receive_all(&mut bob_dev);
+    assert_eq!(received.len(), 1);
+    assert_eq!(&received[0][..], &frame[..]);
+
+    // Once Alice has heard from Bob's inner address, frames to it only go to Bob's VTEP.
+    let reply = inner_frame(INNER_ALICE_MAC, INNER_BOB_MAC, b"world");
+    bob_dev.transmit(&reply[..]);
+    alice.receive(bob.rt().pop_frame()).unwrap();
+    alice.rt().poll_scheduler();
+    let received = receive_all(&mut alice_dev);
+    assert_eq!(received.len(), 1);
+    assert_eq!(&received[0][..], &reply[..]);
+
+    let frame = inner_frame(INNER_BOB_MAC, INNER_ALICE_MAC, b"again");
+    alice_dev.transmit(&frame[..]);
+    let to_bob = alice.rt().pop_frame();
+    assert_eq!(
+        to_bob[DST_ADDR_OFFSET..][..4],
+        test_helpers::BOB_IPV4.octets()
+    );
+    bob.receive(to_bob).unwrap();
+    bob.rt().poll_scheduler();
+    assert_eq!(receive_all(&mut bob_dev).len(), 1);
+}
+
+#[test]
+fn unknown_vni() {
+    let now = Instant::now();
+    let mut alice = test_helpers::new_alice(now);
+    let mut bob = test_helpers::new_bob(now);
+    alice.vxlan_start(Options::default()).unwrap();
+    bob.vxlan_start(Options::default()).unwrap();
+
+    let mut alice_dev = alice
+        .vxlan_attach(200, vec![test_helpers::BOB_IPV4])
+        .unwrap();
+    let mut bob_dev = bob
+        .vxlan_attach(100, vec![test_helpers::ALICE_IPV4])
+        .unwrap();
+
+    let frame = inner_frame(MacAddress::broadcast(), INNER_ALICE_MAC, b"hello");
+    alice_dev.transmit(&frame[..]);
+    bob.receive(alice.rt().pop_frame()).unwrap();
+    bob.rt().poll_scheduler();
+    assert!(receive_all(&mut bob_dev).is_empty());
+
+    // Dropping the device detaches the network.
+    drop(bob_dev);
+    bob.vxlan_attach(100, vec![]).unwrap();
+}
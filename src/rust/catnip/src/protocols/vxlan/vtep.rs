@@ -0,0 +1,259 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::{
+    header::{
+        VxlanHeader,
+        MAX_VNI,
+        VXLAN_HEADER_SIZE,
+    },
+    options::VxlanOptions,
+};
+use crate::{
+    backends::NetworkDevice,
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{
+        ethernet2::{
+            frame::ETHERNET2_HEADER_SIZE,
+            MacAddress,
+        },
+        ip,
+        ipv4,
+        udp,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+        RECEIVE_BATCH_SIZE,
+    },
+    scheduler::SchedulerHandle,
+    sync::Bytes,
+};
+use arrayvec::ArrayVec;
+use std::{
+    cell::RefCell,
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    net::Ipv4Addr,
+    rc::Rc,
+};
+
+struct Network {
+    remotes: Vec<Ipv4Addr>,
+    // Inner MAC addresses and the VTEP we last saw each of them behind.
+    learned: HashMap<MacAddress, Ipv4Addr>,
+    rx: VecDeque<Bytes>,
+    num_dropped: u64,
+}
+
+impl Network {
+    fn receive(&mut self, vtep: Ipv4Addr, frame: &[u8], rx_queue_size: usize) {
+        let src_addr = MacAddress::from_bytes(&frame[6..12]);
+        if src_addr.is_unicast() {
+            self.learned.insert(src_addr, vtep);
+        }
+        if self.rx.len() >= rx_queue_size {
+            self.num_dropped += 1;
+            return;
+        }
+        self.rx.push_back(Bytes::from_slice(frame));
+    }
+}
+
+#[derive(Default)]
+struct State {
+    networks: HashMap<u32, Network>,
+    num_malformed: u64,
+    num_unknown: u64,
+}
+
+pub struct Vtep<RT: Runtime> {
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+    options: VxlanOptions,
+    state: Rc<RefCell<State>>,
+
+    #[allow(unused)]
+    handle: SchedulerHandle,
+}
+
+impl<RT: Runtime> Vtep<RT> {
+    pub fn new(rt: RT, udp: udp::Peer<RT>, options: VxlanOptions) -> Result<Self, Fail> {
+        let fd = udp.socket();
+        let local = ipv4::Endpoint::new(rt.local_ipv4_addr(), options.local_port);
+        if let Err(e) = udp.bind(fd, local) {
+            udp.close(fd)?;
+            return Err(e);
+        }
+        let state = Rc::new(RefCell::new(State::default()));
+        let future = Self::background(udp.clone(), fd, options.rx_queue_size, state.clone());
+        let handle = rt.spawn(future);
+        Ok(Self {
+            udp,
+            fd,
+            options,
+            state,
+            handle,
+        })
+    }
+
+    /// Join virtual network `vni`, flooding to the VTEPs at `remotes`. The network lives until the
+    /// returned device is dropped.
+    pub fn attach(&self, vni: u32, remotes: Vec<Ipv4Addr>) -> Result<VxlanDevice<RT>, Fail> {
+        if vni > MAX_VNI {
+            return Err(Fail::OutOfRange {
+                details: "VNI must fit in 24 bits",
+            });
+        }
+        let mut state = self.state.borrow_mut();
+        if state.networks.contains_key(&vni) {
+            return Err(Fail::ResourceBusy {
+                details: "VNI already attached",
+            });
+        }
+        let network = Network {
+            remotes,
+            learned: HashMap::new(),
+            rx: VecDeque::new(),
+            num_dropped: 0,
+        };
+        state.networks.insert(vni, network);
+        Ok(VxlanDevice {
+            vni,
+            udp: self.udp.clone(),
+            fd: self.fd,
+            remote_port: self.options.remote_port,
+            state: self.state.clone(),
+        })
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    /// Datagrams that weren't valid VXLAN or came from a VTEP the network doesn't list.
+    pub fn num_malformed(&self) -> u64 {
+        self.state.borrow().num_malformed
+    }
+
+    /// Frames for VNIs nothing is attached to.
+    pub fn num_unknown(&self) -> u64 {
+        self.state.borrow().num_unknown
+    }
+
+    async fn background(
+        udp: udp::Peer<RT>,
+        fd: FileDescriptor,
+        rx_queue_size: usize,
+        state: Rc<RefCell<State>>,
+    ) {
+        loop {
+            let (remote, buf) = match udp.pop(fd).await {
+                Ok((Some(remote), buf)) => (remote, buf),
+                Ok((None, _)) => continue,
+                Err(e) => {
+                    warn!("VXLAN socket failed: {:?}", e);
+                    return;
+                },
+            };
+            let mut state = state.borrow_mut();
+            let state = &mut *state;
+            let (header, frame) = match VxlanHeader::parse(buf) {
+                Ok((header, frame)) if frame.len() >= ETHERNET2_HEADER_SIZE => (header, frame),
+                _ => {
+                    state.num_malformed += 1;
+                    continue;
+                },
+            };
+            let network = match state.networks.get_mut(&header.vni) {
+                Some(network) => network,
+                None => {
+                    debug!("Dropping VXLAN frame for VNI {}", header.vni);
+                    state.num_unknown += 1;
+                    continue;
+                },
+            };
+            if !network.remotes.contains(&remote.addr) {
+                state.num_malformed += 1;
+                continue;
+            }
+            network.receive(remote.addr, &frame[..], rx_queue_size);
+        }
+    }
+}
+
+impl<RT: Runtime> Drop for Vtep<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.udp.close(self.fd) {
+            warn!("Failed to close VXLAN socket: {:?}", e);
+        }
+    }
+}
+
+/// One virtual network's port on a `Vtep`.
+pub struct VxlanDevice<RT: Runtime> {
+    vni: u32,
+    udp: udp::Peer<RT>,
+    fd: FileDescriptor,
+    remote_port: ip::Port,
+    state: Rc<RefCell<State>>,
+}
+
+impl<RT: Runtime> VxlanDevice<RT> {
+    pub fn vni(&self) -> u32 {
+        self.vni
+    }
+
+    /// Received frames dropped because the device wasn't polled fast enough.
+    pub fn num_dropped(&self) -> u64 {
+        self.state.borrow().networks[&self.vni].num_dropped
+    }
+}
+
+impl<RT: Runtime> NetworkDevice for VxlanDevice<RT> {
+    fn transmit(&mut self, frame: &[u8]) {
+        if frame.len() < ETHERNET2_HEADER_SIZE {
+            warn!("Dropping runt VXLAN frame");
+            return;
+        }
+        let dst_addr = MacAddress::from_bytes(&frame[0..6]);
+        let remotes = {
+            let state = self.state.borrow();
+            let network = &state.networks[&self.vni];
+            match network.learned.get(&dst_addr) {
+                Some(&vtep) if dst_addr.is_unicast() => vec![vtep],
+                _ => network.remotes.clone(),
+            }
+        };
+        let mut buf = vec![0u8; VXLAN_HEADER_SIZE + frame.len()];
+        VxlanHeader::new(self.vni).serialize(&mut buf[..VXLAN_HEADER_SIZE]);
+        buf[VXLAN_HEADER_SIZE..].copy_from_slice(frame);
+        let buf = RT::Buf::from_slice(&buf[..]);
+        for vtep in remotes {
+            let remote = ipv4::Endpoint::new(vtep, self.remote_port);
+            if let Err(e) = self.udp.pushto(self.fd, buf.clone(), remote) {
+                warn!("Failed to send VXLAN frame to {}: {:?}", vtep, e);
+            }
+        }
+    }
+
+    fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+        let mut state = self.state.borrow_mut();
+        let network = state.networks.get_mut(&self.vni).unwrap();
+        while !batch.is_full() {
+            match network.rx.pop_front() {
+                Some(frame) => batch.push(frame),
+                None => break,
+            }
+        }
+    }
+}
+
+impl<RT: Runtime> Drop for VxlanDevice<RT> {
+    fn drop(&mut self) {
+        self.state.borrow_mut().networks.remove(&self.vni);
+    }
+}
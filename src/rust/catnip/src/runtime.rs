@@ -3,7 +3,14 @@
 use crate::{
     protocols::{
         arp,
-        ethernet2::MacAddress,
+        ethernet2::{
+            frame::MIN_FRAME_SIZE,
+            MacAddress,
+        },
+        icmpv4,
+        ipv4,
+        ipv6,
+        ndp,
         tcp,
         udp,
     },
@@ -20,9 +27,13 @@ use rand::distributions::{
     Standard,
 };
 use std::{
+    cmp,
     fmt::Debug,
     future::Future,
-    net::Ipv4Addr,
+    net::{
+        Ipv4Addr,
+        Ipv6Addr,
+    },
     time::{
         Duration,
         Instant,
@@ -35,10 +46,16 @@ pub const RECEIVE_BATCH_SIZE: usize = 4;
 pub trait RuntimeBuf: Clone + Debug + Deref<Target=[u8]> + Sized + Unpin {
     fn empty() -> Self;
 
+    /// Copy `bytes` into a freshly allocated buffer.
+    fn from_slice(bytes: &[u8]) -> Self;
+
     /// Remove `num_bytes` from the beginning of the buffer.
     fn adjust(&mut self, num_bytes: usize);
     /// Remove `num_bytes` from the end of the buffer;
     fn trim(&mut self, num_bytes: usize);
+
+    /// The bytes, to write in place, unless another buffer shares them.
+    fn get_mut(&mut self) -> Option<&mut [u8]>;
 }
 
 pub trait PacketBuf<T>: Sized {
@@ -48,6 +65,25 @@ pub trait PacketBuf<T>: Sized {
     fn take_body(self) -> Option<T>;
 }
 
+/// How long `pkt` is on the wire: packets shorter than the minimum Ethernet frame get padded.
+pub fn frame_size<T>(pkt: &impl PacketBuf<T>) -> usize {
+    cmp::max(pkt.header_size() + pkt.body_size(), MIN_FRAME_SIZE)
+}
+
+/// Write `pkt` into `buf`, which must be `frame_size(&pkt)` long. The padding is zeroed
+/// explicitly, since `buf` may be reused; receivers trim it off using the IP total length.
+pub fn serialize_frame<T: RuntimeBuf>(pkt: impl PacketBuf<T>, buf: &mut [u8]) {
+    let header_size = pkt.header_size();
+    let body_size = pkt.body_size();
+    pkt.write_header(&mut buf[..header_size]);
+    if let Some(body) = pkt.take_body() {
+        buf[header_size..(header_size + body_size)].copy_from_slice(&body[..]);
+    }
+    for b in &mut buf[(header_size + body_size)..] {
+        *b = 0;
+    }
+}
+
 pub trait Runtime: Clone + Unpin + 'static {
     type Buf: RuntimeBuf;
     fn into_sgarray(&self, buf: Self::Buf) -> dmtr_sgarray_t;
@@ -57,18 +93,41 @@ pub trait Runtime: Clone + Unpin + 'static {
 
     fn advance_clock(&self, now: Instant);
     fn transmit(&self, pkt: impl PacketBuf<Self::Buf>);
+    /// Whether the device has room for another frame. While it doesn't, IPv4 frames are held
+    /// back in the egress queues and retried shortly, rather than transmitted to be dropped.
+    fn tx_ready(&self) -> bool {
+        true
+    }
     fn receive(&self) -> ArrayVec<[Self::Buf; RECEIVE_BATCH_SIZE]>;
 
     fn local_link_addr(&self) -> MacAddress;
     fn local_ipv4_addr(&self) -> Ipv4Addr;
+    /// Change the address `local_ipv4_addr` returns, as the DHCP client does. Sockets bound to
+    /// the old address aren't moved.
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr);
+    /// Our only IPv6 address, which NDP resolves and answers for.
+    fn local_ipv6_addr(&self) -> Ipv6Addr {
+        ipv6::link_local_addr(self.local_link_addr())
+    }
     fn arp_options(&self) -> arp::Options;
     fn tcp_options(&self) -> tcp::Options;
     fn udp_options(&self) -> udp::Options;
+    fn icmpv4_options(&self) -> icmpv4::Options {
+        icmpv4::Options::default()
+    }
+    fn ipv4_options(&self) -> ipv4::Options {
+        ipv4::Options::default()
+    }
+    fn ndp_options(&self) -> ndp::Options {
+        ndp::Options::default()
+    }
 
     type WaitFuture: Future<Output = ()>;
     fn wait(&self, duration: Duration) -> Self::WaitFuture;
     fn wait_until(&self, when: Instant) -> Self::WaitFuture;
     fn now(&self) -> Instant;
+    /// When the earliest pending `wait` or `wait_until` expires, if anything is waiting.
+    fn next_expiry(&self) -> Option<Instant>;
 
     fn rng_gen<T>(&self) -> T
     where
@@ -151,8 +151,15 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
         }
     }
 
-    pub fn poll(&self) {
+    /// Whether any future has been woken since it was last polled.
+    pub fn has_runnable(&self) -> bool {
+        self.inner.borrow().pages.iter().any(|page| page.has_notified())
+    }
+
+    /// Poll every future that's been woken, returning how many there were.
+    pub fn poll(&self) -> usize {
         let _s = static_span!();
+        let mut num_polled = 0;
         let mut inner = self.inner.borrow_mut();
         // inner.root_waker.register(ctx.waker());
         for page_ix in 0..inner.pages.len() {
@@ -174,6 +181,7 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
                     let pinned_ref = unsafe { Pin::new_unchecked(&mut *pinned_ptr) };
                     let poll_result = { Future::poll(pinned_ref, &mut sub_ctx) };
                     inner = self.inner.borrow_mut();
+                    num_polled += 1;
 
                     match poll_result {
                         Poll::Ready(()) => inner.pages[page_ix].mark_completed(subpage_ix),
@@ -189,6 +197,7 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
                 }
             }
         }
+        num_polled
     }
 }
 
@@ -0,0 +1,124 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Engine state snapshots, for handing connections off to another engine.
+//!
+//! [`Engine::snapshot`](crate::engine::Engine::snapshot) captures the ARP cache, bound UDP
+//! sockets, TCP listeners, and established TCP connections (sequence numbers, windows, RTO state,
+//! and all buffered data) into plain data that serializes with any serde format.
+//! [`Engine::restore`](crate::engine::Engine::restore) recreates those sockets on another engine,
+//! which picks each connection up where the first one left off. The remote end doesn't notice,
+//! provided the new engine takes over the old one's addresses and the old engine is dropped
+//! without closing anything.
+//!
+//! Some state isn't carried over: queued UDP datagrams, connections still in their handshake,
+//! out-of-order TCP segments (the remote retransmits them), statistics, and the engine's own
+//! services such as SNTP and VXLAN.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::tcp::{
+        ReceiverState,
+        SenderState,
+    },
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    net::{
+        Ipv4Addr,
+        SocketAddrV4,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub arp_cache: Vec<(Ipv4Addr, [u8; 6])>,
+    pub udp_sockets: Vec<UdpSocketSnapshot>,
+    pub tcp_listeners: Vec<TcpListenerSnapshot>,
+    pub tcp_connections: Vec<TcpConnectionSnapshot>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UdpSocketSnapshot {
+    pub fd: FileDescriptor,
+    pub local: SocketAddrV4,
+    pub remote: Option<SocketAddrV4>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TcpListenerSnapshot {
+    pub fd: FileDescriptor,
+    pub local: SocketAddrV4,
+    pub backlog: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TcpConnectionSnapshot {
+    pub fd: FileDescriptor,
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub sender: SenderSnapshot,
+    pub receiver: ReceiverSnapshot,
+    /// `None` unless both sides agreed to TCP timestamps.
+    #[serde(default)]
+    pub timestamps: Option<TimestampsSnapshot>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SenderSnapshot {
+    pub state: SenderState,
+    #[serde(default)]
+    pub passive_close: bool,
+    pub base_seq_no: u32,
+    pub sent_seq_no: u32,
+    pub unsent_seq_no: u32,
+    /// Sent but unacknowledged data, one entry per segment since ACKs must land on boundaries.
+    pub unacked: Vec<Vec<u8>>,
+    pub unsent: Vec<Vec<u8>>,
+    pub window_size: u32,
+    pub window_scale: u8,
+    pub mss: usize,
+    pub rto: RtoSnapshot,
+    #[serde(default)]
+    pub sack_permitted: bool,
+    #[serde(default)]
+    pub nagle: bool,
+    /// Zero, for no limit, in snapshots from before there was one.
+    #[serde(default)]
+    pub send_buffer_size: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RtoSnapshot {
+    /// `None` until the connection has taken an RTT sample.
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    pub rto: Duration,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReceiverSnapshot {
+    pub state: ReceiverState,
+    pub base_seq_no: u32,
+    pub ack_seq_no: u32,
+    pub recv_seq_no: u32,
+    /// Received data the application hasn't read yet.
+    pub recv_queue: Vec<Vec<u8>>,
+    pub max_window_size: u32,
+    pub window_scale: u32,
+    /// The application shut down reading, so new data is thrown away.
+    #[serde(default)]
+    pub read_shutdown: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TimestampsSnapshot {
+    /// Our timestamp clock's reading when the snapshot was taken.
+    pub tsval: u32,
+    /// The remote timestamp we echo.
+    pub recent: u32,
+}
@@ -0,0 +1,340 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! A POSIX-style socket facade over [`LibOS`].
+//!
+//! The calls here mirror their BSD socket counterparts closely enough that socket-based
+//! application code can be ported by swapping the calls out: every operation runs to completion
+//! before returning (so `accept`, `connect`, and `recv` block while driving the engine), addresses
+//! are `SocketAddrV4`s, byte counts are `usize`s, and failures are reported as an [`Errno`].
+//!
+//! Like their POSIX counterparts, `recv` on a TCP socket may return fewer bytes than were
+//! received, holding on to the remainder for the next call, while `recv` on a UDP socket discards
+//! whatever part of the datagram doesn't fit in the caller's buffer.
+
+use crate::{
+    engine::Protocol,
+    fail::Fail,
+    file_table::FileDescriptor,
+    libos::LibOS,
+    operations::OperationResult,
+    protocols::{
+        ip,
+        ipv4,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+};
+use libc::c_int;
+use std::{
+    cmp,
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    net::SocketAddrV4,
+};
+
+/// An `errno` value describing why a socket call failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Errno(pub c_int);
+
+impl From<Fail> for Errno {
+    fn from(fail: Fail) -> Self {
+        match fail.errno() {
+            // `Fail::Ignored` maps to zero, which isn't a valid error.
+            0 => Errno(libc::EINVAL),
+            errno => Errno(errno),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "errno {}", self.0)
+    }
+}
+
+struct Socket<RT: Runtime> {
+    protocol: Protocol,
+    connected: bool,
+    // Bytes from a TCP pop that didn't fit in the caller's buffer.
+    unread: Option<RT::Buf>,
+}
+
+pub struct Sockets<RT: Runtime> {
+    libos: LibOS<RT>,
+    sockets: HashMap<FileDescriptor, Socket<RT>>,
+}
+
+impl<RT: Runtime> Sockets<RT> {
+    pub fn new(libos: LibOS<RT>) -> Self {
+        Self {
+            libos,
+            sockets: HashMap::new(),
+        }
+    }
+
+    pub fn libos(&mut self) -> &mut LibOS<RT> {
+        &mut self.libos
+    }
+
+    pub fn socket(
+        &mut self,
+        domain: c_int,
+        socket_type: c_int,
+        protocol: c_int,
+    ) -> Result<c_int, Errno> {
+        if domain != libc::AF_INET {
+            return Err(Errno(libc::EAFNOSUPPORT));
+        }
+        let protocol = match (socket_type, protocol) {
+            (libc::SOCK_STREAM, 0) | (libc::SOCK_STREAM, libc::IPPROTO_TCP) => Protocol::Tcp,
+            (libc::SOCK_DGRAM, 0) | (libc::SOCK_DGRAM, libc::IPPROTO_UDP) => Protocol::Udp,
+            (libc::SOCK_STREAM, _) | (libc::SOCK_DGRAM, _) => {
+                return Err(Errno(libc::EPROTONOSUPPORT))
+            },
+            _ => return Err(Errno(libc::ESOCKTNOSUPPORT)),
+        };
+        let fd = self.libos.socket(domain, socket_type, 0)?;
+        self.insert(fd, protocol);
+        Ok(fd as c_int)
+    }
+
+    pub fn bind(&mut self, fd: c_int, addr: SocketAddrV4) -> Result<(), Errno> {
+        let fd = self.lookup(fd)?.0;
+        self.libos.bind(fd, endpoint(addr)?)?;
+        Ok(())
+    }
+
+    pub fn listen(&mut self, fd: c_int, backlog: c_int) -> Result<(), Errno> {
+        let (fd, protocol) = self.lookup(fd)?;
+        if protocol != Protocol::Tcp {
+            return Err(Errno(libc::EOPNOTSUPP));
+        }
+        // POSIX silently bumps nonsensical backlogs up to something usable.
+        self.libos.listen(fd, cmp::max(backlog, 1) as usize)?;
+        Ok(())
+    }
+
+    /// Block until a connection arrives on the listening socket `fd`, returning the new socket.
+    pub fn accept(&mut self, fd: c_int) -> Result<c_int, Errno> {
+        let (fd, protocol) = self.lookup(fd)?;
+        if protocol != Protocol::Tcp {
+            return Err(Errno(libc::EOPNOTSUPP));
+        }
+        let qt = self.libos.accept(fd);
+        match self.libos.wait2(qt).1 {
+            OperationResult::Accept(new_fd) => {
+                self.insert(new_fd, Protocol::Tcp);
+                self.sockets.get_mut(&new_fd).unwrap().connected = true;
+                Ok(new_fd as c_int)
+            },
+            OperationResult::Failed(e) => Err(e.into()),
+            r => panic!("Unexpected accept result: {:?}", r),
+        }
+    }
+
+    /// Connect a TCP socket, blocking until the handshake completes, or fix the default
+    /// destination of a UDP socket.
+    pub fn connect(&mut self, fd: c_int, addr: SocketAddrV4) -> Result<(), Errno> {
+        let fd = self.lookup(fd)?.0;
+        if self.sockets[&fd].connected {
+            return Err(Errno(libc::EISCONN));
+        }
+        let qt = self.libos.connect(fd, endpoint(addr)?);
+        match self.libos.wait2(qt).1 {
+            OperationResult::Connect => {
+                self.sockets.get_mut(&fd).unwrap().connected = true;
+                Ok(())
+            },
+            OperationResult::Failed(e) => Err(e.into()),
+            r => panic!("Unexpected connect result: {:?}", r),
+        }
+    }
+
+    pub fn send(&mut self, fd: c_int, buf: &[u8]) -> Result<usize, Errno> {
+        let fd = self.lookup(fd)?.0;
+        if !self.sockets[&fd].connected {
+            return Err(Errno(libc::ENOTCONN));
+        }
+        let qt = self.libos.push2(fd, RT::Buf::from_slice(buf));
+        self.wait_push(qt, buf.len())
+    }
+
+    pub fn sendto(&mut self, fd: c_int, buf: &[u8], addr: SocketAddrV4) -> Result<usize, Errno> {
+        let (fd, protocol) = self.lookup(fd)?;
+        if protocol != Protocol::Udp {
+            return Err(Errno(libc::EISCONN));
+        }
+        let qt = self
+            .libos
+            .pushto2(fd, RT::Buf::from_slice(buf), endpoint(addr)?);
+        self.wait_push(qt, buf.len())
+    }
+
+    /// Block until data arrives, copying as much as fits into `buf`. Returns zero once the remote
+    /// side of a TCP connection has closed.
+    pub fn recv(&mut self, fd: c_int, buf: &mut [u8]) -> Result<usize, Errno> {
+        self.recvfrom(fd, buf).map(|(n, _)| n)
+    }
+
+    /// Like `recv`, also returning the sender's address for UDP sockets.
+    pub fn recvfrom(
+        &mut self,
+        fd: c_int,
+        buf: &mut [u8],
+    ) -> Result<(usize, Option<SocketAddrV4>), Errno> {
+        let (fd, protocol) = self.lookup(fd)?;
+        if let Some(unread) = self.sockets.get_mut(&fd).unwrap().unread.take() {
+            return Ok((self.copy_out(fd, unread, buf), None));
+        }
+        let qt = self.libos.pop(fd);
+        let (remote, data) = match self.libos.wait2(qt).1 {
+            OperationResult::Pop(remote, data) => (remote, data),
+            OperationResult::Failed(e) => return Err(e.into()),
+            r => panic!("Unexpected pop result: {:?}", r),
+        };
+        let remote = remote.map(|e| SocketAddrV4::new(e.addr, e.port.into()));
+        match protocol {
+            Protocol::Tcp => Ok((self.copy_out(fd, data, buf), remote)),
+            Protocol::Udp => {
+                let n = cmp::min(data.len(), buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok((n, remote))
+            },
+        }
+    }
+
+    pub fn close(&mut self, fd: c_int) -> Result<(), Errno> {
+        let fd = self.lookup(fd)?.0;
+        self.sockets.remove(&fd);
+        self.libos.close(fd)?;
+        Ok(())
+    }
+
+    fn insert(&mut self, fd: FileDescriptor, protocol: Protocol) {
+        let socket = Socket {
+            protocol,
+            connected: false,
+            unread: None,
+        };
+        assert!(self.sockets.insert(fd, socket).is_none());
+    }
+
+    fn lookup(&self, fd: c_int) -> Result<(FileDescriptor, Protocol), Errno> {
+        let fd = FileDescriptor::try_from(fd).map_err(|_| Errno(libc::EBADF))?;
+        match self.sockets.get(&fd) {
+            Some(s) => Ok((fd, s.protocol)),
+            None => Err(Errno(libc::EBADF)),
+        }
+    }
+
+    fn wait_push(&mut self, qt: u64, len: usize) -> Result<usize, Errno> {
+        match self.libos.wait2(qt).1 {
+            OperationResult::Push => Ok(len),
+            OperationResult::Failed(e) => Err(e.into()),
+            r => panic!("Unexpected push result: {:?}", r),
+        }
+    }
+
+    fn copy_out(&mut self, fd: FileDescriptor, mut data: RT::Buf, buf: &mut [u8]) -> usize {
+        let n = cmp::min(data.len(), buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        if n < data.len() {
+            data.adjust(n);
+            self.sockets.get_mut(&fd).unwrap().unread = Some(data);
+        }
+        n
+    }
+}
+
+fn endpoint(addr: SocketAddrV4) -> Result<ipv4::Endpoint, Errno> {
+    // We don't allocate ephemeral ports on `bind`, so port zero is invalid.
+    let port = ip::Port::try_from(addr.port()).map_err(|_| Errno(libc::EINVAL))?;
+    Ok(ipv4::Endpoint::new(*addr.ip(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Errno,
+        Sockets,
+    };
+    use crate::{
+        libos::LibOS,
+        test_helpers::{
+            self,
+            TestRuntime,
+        },
+    };
+    use std::{
+        net::SocketAddrV4,
+        time::Instant,
+    };
+
+    #[test]
+    fn udp_sendto_recvfrom() {
+        let now = Instant::now();
+        let alice_rt = TestRuntime::new(
+            "alice",
+            now,
+            test_helpers::ALICE_MAC,
+            test_helpers::ALICE_IPV4,
+        );
+        let bob_rt = TestRuntime::new("bob", now, test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+        let mut alice = Sockets::new(LibOS::new(alice_rt.clone()).unwrap());
+        let mut bob = Sockets::new(LibOS::new(bob_rt.clone()).unwrap());
+
+        let alice_addr = SocketAddrV4::new(test_helpers::ALICE_IPV4, 5000);
+        let bob_addr = SocketAddrV4::new(test_helpers::BOB_IPV4, 6000);
+        let alice_fd = alice.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+        alice.bind(alice_fd, alice_addr).unwrap();
+        let bob_fd = bob.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+        bob.bind(bob_fd, bob_addr).unwrap();
+
+        assert_eq!(alice.sendto(alice_fd, b"hello, bob", bob_addr), Ok(10));
+        bob_rt.push_frame(alice_rt.pop_frame());
+
+        // The part of the datagram that doesn't fit is discarded.
+        let mut buf = [0u8; 5];
+        assert_eq!(
+            bob.recvfrom(bob_fd, &mut buf[..]),
+            Ok((5, Some(alice_addr)))
+        );
+        assert_eq!(&buf[..], b"hello");
+
+        assert_eq!(alice.send(alice_fd, b"hi"), Err(Errno(libc::ENOTCONN)));
+        alice.close(alice_fd).unwrap();
+        assert_eq!(alice.close(alice_fd), Err(Errno(libc::EBADF)));
+    }
+
+    #[test]
+    fn socket_errors() {
+        let now = Instant::now();
+        let rt = TestRuntime::new(
+            "alice",
+            now,
+            test_helpers::ALICE_MAC,
+            test_helpers::ALICE_IPV4,
+        );
+        let mut alice = Sockets::new(LibOS::new(rt).unwrap());
+
+        assert_eq!(
+            alice.socket(libc::AF_INET6, libc::SOCK_STREAM, 0),
+            Err(Errno(libc::EAFNOSUPPORT))
+        );
+        assert_eq!(
+            alice.socket(libc::AF_INET, libc::SOCK_STREAM, libc::IPPROTO_UDP),
+            Err(Errno(libc::EPROTONOSUPPORT))
+        );
+        let udp_fd = alice.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+        assert_eq!(alice.listen(udp_fd, 16), Err(Errno(libc::EOPNOTSUPP)));
+        assert_eq!(alice.listen(1234, 16), Err(Errno(libc::EBADF)));
+
+        let tcp_fd = alice.socket(libc::AF_INET, libc::SOCK_STREAM, 0).unwrap();
+        let addr = SocketAddrV4::new(test_helpers::ALICE_IPV4, 0);
+        assert_eq!(alice.bind(tcp_fd, addr), Err(Errno(libc::EINVAL)));
+    }
+}
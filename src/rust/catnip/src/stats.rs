@@ -0,0 +1,537 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Engine statistics.
+//!
+//! `Engine::stats` returns a snapshot of the engine-wide counters and histograms, which keep
+//! accumulating for the lifetime of the engine (including samples from connections that have since
+//! been closed). Per-connection views are available through the engine's `tcp_*_stats` accessors,
+//! or pushed periodically as `TcpConnectionSummary`s, and `Engine::tcp_group_stats` totals them
+//! over a connection group.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::{
+        icmpv4::PathStatus,
+        ipv4,
+    },
+};
+use histogram::Histogram;
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::BTreeMap,
+    fmt,
+    rc::Rc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// Samples are recorded in nanoseconds; anything slower than a minute is clamped.
+const MAX_LATENCY_NS: u64 = 60_000_000_000;
+
+// Engine-wide histograms get three significant figures. Per-connection ones get two, since they
+// are multiplied by the number of connections.
+const ENGINE_PRECISION: u32 = 3;
+const CONNECTION_PRECISION: u32 = 2;
+
+// Throughput is measured over fixed windows, and the per-window rates are smoothed with an EWMA.
+const THROUGHPUT_WINDOW: Duration = Duration::from_millis(100);
+const THROUGHPUT_EWMA_ALPHA: f64 = 0.25;
+// After this many idle windows the EWMA has decayed to (nearly) zero, so stop iterating.
+const THROUGHPUT_MAX_IDLE_WINDOWS: u32 = 64;
+
+/// An HDR-style histogram of latency samples.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    histogram: Histogram,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::with_precision(ENGINE_PRECISION)
+    }
+
+    fn with_precision(precision: u32) -> Self {
+        let histogram = Histogram::configure()
+            .max_value(MAX_LATENCY_NS)
+            .precision(precision)
+            .build()
+            .expect("Invalid histogram configuration");
+        Self { histogram }
+    }
+
+    pub fn record(&mut self, sample: Duration) {
+        let ns = cmp::min(sample.as_nanos(), MAX_LATENCY_NS as u128) as u64;
+        // The only failure mode is a value above `max_value`, which we've already clamped.
+        let _ = self.histogram.increment(ns);
+    }
+
+    /// Number of recorded samples.
+    pub fn len(&self) -> u64 {
+        self.histogram.entries()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Latency at the given percentile, in the range `0.0..=100.0`.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        self.histogram
+            .percentile(percentile)
+            .ok()
+            .map(Duration::from_nanos)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        self.histogram.minimum().ok().map(Duration::from_nanos)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        self.histogram.maximum().ok().map(Duration::from_nanos)
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        self.histogram.mean().ok().map(Duration::from_nanos)
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("len", &self.len())
+            .field("p50", &self.percentile(50.0))
+            .field("p99", &self.percentile(99.0))
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+/// Latency distributions for TCP connections.
+#[derive(Clone, Debug, Default)]
+pub struct TcpLatencyStats {
+    /// Smoothed RTT estimate after each accepted RTT sample.
+    pub srtt: LatencyHistogram,
+    /// Time between receiving data and sending the ACK that covers it.
+    pub ack_latency: LatencyHistogram,
+    /// Time data waits in the send queue before the segment carrying it is first transmitted,
+    /// sampled once per segment. Pushes that go out immediately record zero.
+    pub send_queue_time: LatencyHistogram,
+}
+
+impl TcpLatencyStats {
+    fn per_connection() -> Self {
+        Self {
+            srtt: LatencyHistogram::with_precision(CONNECTION_PRECISION),
+            ack_latency: LatencyHistogram::with_precision(CONNECTION_PRECISION),
+            send_queue_time: LatencyHistogram::with_precision(CONNECTION_PRECISION),
+        }
+    }
+}
+
+/// Snapshot of engine-wide statistics.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub tcp_latency: TcpLatencyStats,
+    /// Frames `Engine::receive` rejected, whether malformed, unsupported or not for us.
+    pub rx_dropped: u64,
+    /// Of those, packets for a protocol the options switch off.
+    pub rx_protocol_disabled: u64,
+    /// ARP cache lookups that found an entry, and ones that didn't.
+    pub arp_hits: u64,
+    pub arp_misses: u64,
+    /// Received ARP packets in a format other than Ethernet/IPv4, which are ignored.
+    pub arp_unsupported: u64,
+    /// TCP connections currently established.
+    pub tcp_established: usize,
+    /// Of the dropped frames, TCP segments for closed ports that the RST policy left unanswered.
+    pub tcp_rsts_suppressed: u64,
+    /// ICMPv4 errors about undeliverable datagrams that the rate limit held back.
+    pub icmpv4_errors_suppressed: u64,
+    /// Fragmented IPv4 datagrams dropped because the rest of their fragments didn't arrive in
+    /// time.
+    pub ipv4_reassembly_timeouts: u64,
+    /// Of the dropped frames, IPv4 fragments from a source that had too much awaiting
+    /// reassembly already.
+    pub ipv4_fragments_dropped: u64,
+    /// Of the dropped frames, ones whose checksum failed validation.
+    pub rx_checksum_errors: ChecksumErrors,
+    /// Times outgoing frames had to wait because the device had no room for them.
+    pub tx_busy: u64,
+    /// Destinations watched by the path monitor, if it's running.
+    pub paths: Vec<PathStatus>,
+    /// Every listening TCP socket, ordered by file descriptor.
+    pub tcp_listeners: Vec<TcpListenerStats>,
+    pub tcp_options: TcpOptionStats,
+}
+
+/// Problems with the options remotes send. Only `strict_option_parsing` counts unknown and
+/// malformed options, since otherwise they drop the segment along with the rest of it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TcpOptionStats {
+    /// Options passed over because we don't know their kind, by kind.
+    pub unknown: BTreeMap<u8, u64>,
+    /// Options passed over because their length was wrong or ran past the header, by kind.
+    pub malformed: BTreeMap<u8, u64>,
+    /// Connections established without the remote sending each option we expected.
+    pub missing_mss: u64,
+    pub missing_window_scale: u64,
+    pub missing_sack: u64,
+    pub missing_timestamps: u64,
+}
+
+/// Checksum validation failures on receive, by protocol. Protocols whose receive checksums are
+/// offloaded in the options aren't validated, so they never count here.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChecksumErrors {
+    pub icmpv4: u64,
+    pub tcp: u64,
+    pub udp: u64,
+}
+
+/// Byte and segment counters for one TCP connection, along with smoothed rates in bytes per
+/// second. "Delivered" counts data handed to the application (goodput); "transmitted" counts every
+/// segment put on the wire, including retransmissions and pure ACKs.
+#[derive(Clone, Debug, Default)]
+pub struct TcpThroughputStats {
+    pub bytes_delivered: u64,
+    pub segments_delivered: u64,
+    pub bytes_transmitted: u64,
+    pub segments_transmitted: u64,
+    /// Of the transmitted segments, ones resent after the retransmission timer fired or by fast
+    /// retransmit.
+    pub segments_retransmitted: u64,
+    /// Bytes that arrived out of order and were delivered once the hole before them filled.
+    pub bytes_reassembled: u64,
+    /// Bytes that arrived out of order and were thrown away instead: past the window or the
+    /// reassembly limit, or repeating data already held.
+    pub bytes_reassembly_discarded: u64,
+    /// Segments that arrived carrying only data we already had, usually the remote
+    /// retransmitting before our ACK reached it.
+    pub segments_duplicate: u64,
+    /// Segments that repeated some data we already had, which was trimmed off before delivery.
+    pub segments_overlapping: u64,
+    /// The repeated bytes in both.
+    pub bytes_duplicate: u64,
+    /// Transient failures to send, like the remote's link address not resolving, counting ones
+    /// the connection rode out. See `tcp::Options::soft_error_limit`.
+    pub soft_errors: u64,
+    /// ACKs dropped for breaking the protocol, like acknowledging part of a segment.
+    pub segments_invalid: u64,
+
+    pub goodput: f64,
+    pub transmit_rate: f64,
+}
+
+struct RateEstimator {
+    window_start: Instant,
+    window_bytes: u64,
+    rate: f64,
+}
+
+impl RateEstimator {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            window_bytes: 0,
+            rate: 0.0,
+        }
+    }
+
+    // Fold every window that has completed by `now` into the EWMA.
+    fn advance(&mut self, now: Instant) {
+        let mut num_windows = 0;
+        while now >= self.window_start + THROUGHPUT_WINDOW {
+            if num_windows >= THROUGHPUT_MAX_IDLE_WINDOWS {
+                self.rate = 0.0;
+                self.window_start = now;
+                break;
+            }
+            let window_rate = self.window_bytes as f64 / THROUGHPUT_WINDOW.as_secs_f64();
+            self.rate =
+                THROUGHPUT_EWMA_ALPHA * window_rate + (1.0 - THROUGHPUT_EWMA_ALPHA) * self.rate;
+            self.window_bytes = 0;
+            self.window_start += THROUGHPUT_WINDOW;
+            num_windows += 1;
+        }
+    }
+
+    fn record(&mut self, now: Instant, num_bytes: usize) {
+        self.advance(now);
+        self.window_bytes += num_bytes as u64;
+    }
+
+    fn rate(&mut self, now: Instant) -> f64 {
+        self.advance(now);
+        self.rate
+    }
+}
+
+/// Tracks delivered and transmitted data for one connection.
+pub struct TcpThroughputRecorder {
+    inner: RefCell<ThroughputInner>,
+}
+
+struct ThroughputInner {
+    stats: TcpThroughputStats,
+    delivered: RateEstimator,
+    transmitted: RateEstimator,
+}
+
+impl TcpThroughputRecorder {
+    pub fn new(now: Instant) -> Self {
+        let inner = ThroughputInner {
+            stats: TcpThroughputStats::default(),
+            delivered: RateEstimator::new(now),
+            transmitted: RateEstimator::new(now),
+        };
+        Self {
+            inner: RefCell::new(inner),
+        }
+    }
+
+    pub fn record_delivered(&self, now: Instant, num_bytes: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.stats.bytes_delivered += num_bytes as u64;
+        inner.stats.segments_delivered += 1;
+        inner.delivered.record(now, num_bytes);
+    }
+
+    pub fn record_transmitted(&self, now: Instant, num_bytes: usize) {
+        let mut inner = self.inner.borrow_mut();
+        inner.stats.bytes_transmitted += num_bytes as u64;
+        inner.stats.segments_transmitted += 1;
+        inner.transmitted.record(now, num_bytes);
+    }
+
+    pub fn record_retransmitted(&self) {
+        self.inner.borrow_mut().stats.segments_retransmitted += 1;
+    }
+
+    pub fn record_soft_error(&self) {
+        self.inner.borrow_mut().stats.soft_errors += 1;
+    }
+
+    pub fn record_invalid(&self) {
+        self.inner.borrow_mut().stats.segments_invalid += 1;
+    }
+
+    pub fn stats(&self, now: Instant) -> TcpThroughputStats {
+        let mut inner = self.inner.borrow_mut();
+        let mut stats = inner.stats.clone();
+        stats.goodput = inner.delivered.rate(now);
+        stats.transmit_rate = inner.transmitted.rate(now);
+        stats
+    }
+}
+
+/// One established connection's health at a point in time, for monitoring that only watches what
+/// the engine pushes out. See `Engine::set_connection_summary_interval`.
+#[derive(Clone, Debug)]
+pub struct TcpConnectionSummary {
+    pub fd: FileDescriptor,
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+    pub throughput: TcpThroughputStats,
+    /// The current smoothed RTT, or `None` before the first sample.
+    pub srtt: Option<Duration>,
+    pub rto: Duration,
+    /// Bytes sent but not yet acknowledged.
+    pub unacked_bytes: usize,
+    /// Bytes the application pushed that haven't been sent yet.
+    pub unsent_bytes: usize,
+    /// Bytes received that the application hasn't read yet.
+    pub receive_queue_bytes: usize,
+}
+
+/// Totals over the established connections in one TCP connection group. See
+/// `Engine::tcp_group_stats`.
+#[derive(Clone, Debug, Default)]
+pub struct TcpGroupStats {
+    pub connections: usize,
+    /// The members' counters and rates, summed.
+    pub throughput: TcpThroughputStats,
+    pub unacked_bytes: usize,
+    pub unsent_bytes: usize,
+    pub receive_queue_bytes: usize,
+    /// The slowest member's smoothed RTT, or `None` if none of them has a sample yet.
+    pub max_srtt: Option<Duration>,
+}
+
+impl TcpGroupStats {
+    pub fn add(&mut self, summary: &TcpConnectionSummary) {
+        let t = &summary.throughput;
+        self.connections += 1;
+        self.throughput.bytes_delivered += t.bytes_delivered;
+        self.throughput.segments_delivered += t.segments_delivered;
+        self.throughput.bytes_transmitted += t.bytes_transmitted;
+        self.throughput.segments_transmitted += t.segments_transmitted;
+        self.throughput.segments_retransmitted += t.segments_retransmitted;
+        self.throughput.bytes_reassembled += t.bytes_reassembled;
+        self.throughput.bytes_reassembly_discarded += t.bytes_reassembly_discarded;
+        self.throughput.segments_duplicate += t.segments_duplicate;
+        self.throughput.segments_overlapping += t.segments_overlapping;
+        self.throughput.bytes_duplicate += t.bytes_duplicate;
+        self.throughput.soft_errors += t.soft_errors;
+        self.throughput.segments_invalid += t.segments_invalid;
+        self.throughput.goodput += t.goodput;
+        self.throughput.transmit_rate += t.transmit_rate;
+        self.unacked_bytes += summary.unacked_bytes;
+        self.unsent_bytes += summary.unsent_bytes;
+        self.receive_queue_bytes += summary.receive_queue_bytes;
+        self.max_srtt = cmp::max(self.max_srtt, summary.srtt);
+    }
+}
+
+/// Handshake-layer counters for one listening socket, since it started listening. Together with
+/// `ready` they tell a listener whose application isn't accepting fast enough (`ready` stays high)
+/// from one dropping handshakes (`backlog_overflows`, `handshake_timeouts`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpListenerStats {
+    pub local: ipv4::Endpoint,
+    /// SYNs that didn't belong to a handshake already in progress.
+    pub syns_received: u64,
+    /// Of those, ones dropped because the backlog was full.
+    pub backlog_overflows: u64,
+    /// Other segments for the listener that were rejected, e.g. ones with bad flags or a bad ACK.
+    pub refused: u64,
+    /// Handshakes completed and queued for `accept`.
+    pub handshakes_completed: u64,
+    /// Of those, ones completed from a SYN cookie rather than from backlog state.
+    pub cookies_validated: u64,
+    /// Handshakes abandoned after the SYN+ACK was retransmitted without an answer.
+    pub handshake_timeouts: u64,
+    /// Connections taken by the application.
+    pub accepted: u64,
+    /// Handshakes currently in progress.
+    pub inflight: usize,
+    /// Completed handshakes currently waiting for `accept`.
+    pub ready: usize,
+}
+
+impl TcpListenerStats {
+    pub fn new(local: ipv4::Endpoint) -> Self {
+        Self {
+            local,
+            syns_received: 0,
+            backlog_overflows: 0,
+            refused: 0,
+            handshakes_completed: 0,
+            cookies_validated: 0,
+            handshake_timeouts: 0,
+            accepted: 0,
+            inflight: 0,
+            ready: 0,
+        }
+    }
+}
+
+/// Records TCP latency samples for one connection into both its own histograms and the
+/// engine-wide ones.
+pub struct TcpLatencyRecorder {
+    connection: RefCell<TcpLatencyStats>,
+    engine: Rc<RefCell<TcpLatencyStats>>,
+}
+
+impl TcpLatencyRecorder {
+    pub fn new(engine: Rc<RefCell<TcpLatencyStats>>) -> Self {
+        Self {
+            connection: RefCell::new(TcpLatencyStats::per_connection()),
+            engine,
+        }
+    }
+
+    pub fn record_srtt(&self, srtt: Duration) {
+        self.connection.borrow_mut().srtt.record(srtt);
+        self.engine.borrow_mut().srtt.record(srtt);
+    }
+
+    pub fn record_ack_latency(&self, latency: Duration) {
+        self.connection.borrow_mut().ack_latency.record(latency);
+        self.engine.borrow_mut().ack_latency.record(latency);
+    }
+
+    pub fn record_send_queue_time(&self, latency: Duration) {
+        self.connection.borrow_mut().send_queue_time.record(latency);
+        self.engine.borrow_mut().send_queue_time.record(latency);
+    }
+
+    pub fn connection_stats(&self) -> TcpLatencyStats {
+        self.connection.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        LatencyHistogram,
+        TcpThroughputRecorder,
+    };
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    #[test]
+    fn throughput_ewma() {
+        let mut now = Instant::now();
+        let recorder = TcpThroughputRecorder::new(now);
+
+        // Deliver 1KB every 10ms (100KB/s) for a second.
+        for _ in 0..100 {
+            recorder.record_delivered(now, 1024);
+            now += Duration::from_millis(10);
+        }
+        let stats = recorder.stats(now);
+        assert_eq!(stats.bytes_delivered, 102400);
+        assert_eq!(stats.segments_delivered, 100);
+        assert!(stats.goodput > 90000.0 && stats.goodput < 110000.0, "{}", stats.goodput);
+        assert_eq!(stats.transmit_rate, 0.0);
+
+        // The rate decays once the connection goes idle.
+        now += Duration::from_secs(60);
+        assert_eq!(recorder.stats(now).goodput, 0.0);
+        assert_eq!(recorder.stats(now).bytes_delivered, 102400);
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let mut h = LatencyHistogram::new();
+        assert_eq!(h.percentile(50.0), None);
+        for i in 1..=100 {
+            h.record(Duration::from_micros(i));
+        }
+        h.record(Duration::from_secs(3600));
+        assert_eq!(h.len(), 101);
+
+        // Buckets are only accurate to three significant figures.
+        let p50 = h.percentile(50.0).unwrap();
+        assert!(p50 >= Duration::from_micros(50) && p50 <= Duration::from_micros(52));
+        assert!(h.min().unwrap() <= Duration::from_micros(2));
+
+        // Samples past the maximum are clamped rather than dropped.
+        let max = h.max().unwrap();
+        assert!(max >= Duration::from_secs(59) && max <= Duration::from_secs(60));
+    }
+}
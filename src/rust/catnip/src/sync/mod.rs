@@ -43,6 +43,13 @@ impl RuntimeBuf for Bytes {
         }
     }
 
+    fn from_slice(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            return Self::empty();
+        }
+        BytesMut::from(bytes).freeze()
+    }
+
     fn adjust(&mut self, num_bytes: usize) {
         if num_bytes > self.len {
             panic!("Adjusting past end of buffer: {} vs. {}", num_bytes, self.len);
@@ -57,6 +64,14 @@ impl RuntimeBuf for Bytes {
         }
         self.len -= num_bytes;
     }
+
+    fn get_mut(&mut self) -> Option<&mut [u8]> {
+        let (offset, len) = (self.offset, self.len);
+        match self.buf {
+            None => Some(&mut []),
+            Some(ref mut buf) => Arc::get_mut(buf).map(|b| &mut b[offset..(offset + len)]),
+        }
+    }
 }
 
 impl Deref for Bytes {
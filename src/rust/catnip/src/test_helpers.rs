@@ -11,10 +11,13 @@ use crate::{
     protocols::{
         arp,
         ethernet2::MacAddress,
+        icmpv4,
+        ipv4,
         tcp,
         udp,
     },
     runtime::{
+        self,
         PacketBuf,
         Runtime,
         RECEIVE_BATCH_SIZE,
@@ -89,7 +92,10 @@ impl TestRuntime {
 
         let mut tcp_options = tcp::Options::default();
         tcp_options.advertised_mss = 2048;
+        tcp_options.receive_window_size = 0xffff << 2;
         tcp_options.window_scale = 2;
+        // Tests advance the clock a millisecond to see delayed ACKs.
+        tcp_options.delayed_ack_timeout = Duration::from_millis(1);
 
         let inner = Inner {
             name,
@@ -100,7 +106,11 @@ impl TestRuntime {
             link_addr,
             ipv4_addr,
             tcp_options,
+            udp_options: udp::Options::default(),
+            icmpv4_options: icmpv4::Options::default(),
+            ipv4_options: ipv4::Options::default(),
             arp_options,
+            tx_ready: true,
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -112,10 +122,36 @@ impl TestRuntime {
         self.inner.borrow_mut().outgoing.pop_front().unwrap()
     }
 
+    pub fn num_outgoing(&self) -> usize {
+        self.inner.borrow().outgoing.len()
+    }
+
     pub fn push_frame(&self, buf: Bytes) {
         self.inner.borrow_mut().incoming.push_back(buf);
     }
 
+    /// Change the options the engine sees from now on.
+    pub fn set_tcp_options(&self, f: impl FnOnce(&mut tcp::Options)) {
+        f(&mut self.inner.borrow_mut().tcp_options);
+    }
+
+    pub fn set_udp_options(&self, f: impl FnOnce(&mut udp::Options)) {
+        f(&mut self.inner.borrow_mut().udp_options);
+    }
+
+    pub fn set_icmpv4_options(&self, f: impl FnOnce(&mut icmpv4::Options)) {
+        f(&mut self.inner.borrow_mut().icmpv4_options);
+    }
+
+    pub fn set_ipv4_options(&self, f: impl FnOnce(&mut ipv4::Options)) {
+        f(&mut self.inner.borrow_mut().ipv4_options);
+    }
+
+    /// Make the device report its TX ring full, or not.
+    pub fn set_tx_ready(&self, ready: bool) {
+        self.inner.borrow_mut().tx_ready = ready;
+    }
+
     pub fn poll_scheduler(&self) {
         // let mut ctx = Context::from_waker(noop_waker_ref());
         self.scheduler.poll();
@@ -133,7 +169,11 @@ struct Inner {
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
     tcp_options: tcp::Options,
+    udp_options: udp::Options,
+    icmpv4_options: icmpv4::Options,
+    ipv4_options: ipv4::Options,
     arp_options: arp::Options,
+    tx_ready: bool,
 }
 
 impl Runtime for TestRuntime {
@@ -201,17 +241,15 @@ impl Runtime for TestRuntime {
     }
 
     fn transmit(&self, pkt: impl PacketBuf<Bytes>) {
-        let header_size = pkt.header_size();
-        let body_size = pkt.body_size();
-
-        let mut buf = BytesMut::zeroed(header_size + body_size);
-        pkt.write_header(&mut buf[..header_size]);
-        if let Some(body) = pkt.take_body() {
-            buf[header_size..].copy_from_slice(&body[..]);
-        }
+        let mut buf = BytesMut::zeroed(runtime::frame_size(&pkt));
+        runtime::serialize_frame(pkt, &mut buf[..]);
         self.inner.borrow_mut().outgoing.push_back(buf.freeze());
     }
 
+    fn tx_ready(&self) -> bool {
+        self.inner.borrow().tx_ready
+    }
+
     fn receive(&self) -> ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]> {
         let mut out = ArrayVec::new();
         if let Some(buf) = self.inner.borrow_mut().incoming.pop_front() {
@@ -232,12 +270,24 @@ impl Runtime for TestRuntime {
         self.inner.borrow().ipv4_addr.clone()
     }
 
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
     fn tcp_options(&self) -> tcp::Options {
         self.inner.borrow().tcp_options.clone()
     }
 
     fn udp_options(&self) -> udp::Options {
-        udp::Options::default()
+        self.inner.borrow().udp_options.clone()
+    }
+
+    fn icmpv4_options(&self) -> icmpv4::Options {
+        self.inner.borrow().icmpv4_options.clone()
+    }
+
+    fn ipv4_options(&self) -> ipv4::Options {
+        self.inner.borrow().ipv4_options.clone()
     }
 
     fn arp_options(&self) -> arp::Options {
@@ -266,6 +316,10 @@ impl Runtime for TestRuntime {
         self.inner.borrow().timer.0.now()
     }
 
+    fn next_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
     fn rng_gen<T>(&self) -> T
     where
         Standard: Distribution<T>,
@@ -124,6 +124,15 @@ impl<P: TimerPtr> Timer<P> {
         self.inner.borrow().now
     }
 
+    /// When the earliest pending wait expires, if anything is waiting.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let mut inner = self.inner.borrow_mut();
+        inner
+            .heap
+            .peek_min()
+            .map(|first| unsafe { first.as_ref().expiry })
+    }
+
     pub fn wait(&self, ptr: P, timeout: Duration) -> WaitFuture<P> {
         self.wait_until(ptr, self.now() + timeout)
     }
@@ -0,0 +1,612 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Embedding the engine in a `tokio` application.
+//!
+//! A [`Stack`] owns an engine and the [`DeviceRuntime`] it runs on. [`Stack::run`] is its driver:
+//! spawn it on a `tokio::task::LocalSet` (the engine isn't `Send`) and it feeds received frames
+//! and the clock to the engine whenever the device's file descriptor turns readable, a timer tick
+//! passes, or an application task queues work. Sockets opened through the stack are ordinary
+//! async types: [`TcpStream`] implements `AsyncRead` and `AsyncWrite`, while [`TcpListener`] and
+//! [`UdpSocket`] are `Stream`s of accepted connections and received datagrams.
+
+use crate::{
+    backends::{
+        DeviceRuntime,
+        NetworkDevice,
+    },
+    engine::{
+        Engine,
+        Protocol,
+    },
+    fail::{
+        Error,
+        Fail,
+        Protocol as ErrorProtocol,
+    },
+    file_table::FileDescriptor,
+    options::Options,
+    protocols::{
+        ipv4,
+        tcp::operations::{
+            AcceptFuture,
+            PopFuture,
+        },
+        udp::peer::PopFuture as UdpPopFuture,
+    },
+    runtime::{
+        Runtime,
+        RuntimeBuf,
+    },
+    sync::Bytes,
+};
+use futures::{
+    FutureExt,
+    Stream,
+};
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+        RefMut,
+    },
+    convert::TryFrom,
+    future::Future,
+    io,
+    net::SocketAddrV4,
+    os::unix::io::{
+        AsRawFd,
+        RawFd,
+    },
+    pin::Pin,
+    rc::Rc,
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::io::{
+    unix::AsyncFd,
+    AsyncRead,
+    AsyncWrite,
+    ReadBuf,
+};
+
+/// How often the driver advances the engine's clock when nothing else wakes it.
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(1);
+
+// Batches to take from the device per wakeup before yielding to other tasks.
+const MAX_RECV_ITERS: usize = 16;
+
+struct Shared<D: NetworkDevice> {
+    engine: RefCell<Engine<DeviceRuntime<D>>>,
+    rt: DeviceRuntime<D>,
+    tick_interval: Duration,
+
+    // Set when an application task queued work the engine's background tasks need to act on.
+    kicked: Cell<bool>,
+    driver: RefCell<Option<Waker>>,
+}
+
+impl<D: NetworkDevice> Shared<D> {
+    fn kick(&self) {
+        self.kicked.set(true);
+        if let Some(waker) = self.driver.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+
+    /// Pump received frames and timers through the engine. Returns whether the device may have
+    /// more frames waiting.
+    fn poll_engine(&self) -> bool {
+        let mut engine = self.engine.borrow_mut();
+        engine.advance_clock(Instant::now());
+        let mut more = true;
+        for _ in 0..MAX_RECV_ITERS {
+            let batch = self.rt.receive();
+            if batch.is_empty() {
+                more = false;
+                break;
+            }
+            for frame in batch {
+                if let Err(e) = engine.receive(frame) {
+                    debug!("Dropped packet: {:?}", e);
+                }
+            }
+        }
+        engine.poll_scheduler();
+        more
+    }
+}
+
+/// An engine running on a userspace device, driven by `tokio`.
+pub struct Stack<D: NetworkDevice + AsRawFd> {
+    shared: Rc<Shared<D>>,
+}
+
+// `#[derive(Clone)]` would needlessly require `D: Clone`.
+impl<D: NetworkDevice + AsRawFd> Clone for Stack<D> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> Stack<D> {
+    pub fn new(device: D, options: Options) -> Result<Self, Fail> {
+        Self::with_tick_interval(device, options, DEFAULT_TICK_INTERVAL)
+    }
+
+    /// Like `new`, but with a custom timer tick. Shorter ticks make retransmit and delayed-ACK
+    /// timers fire closer to their deadlines at the cost of more wakeups.
+    pub fn with_tick_interval(
+        device: D,
+        options: Options,
+        tick_interval: Duration,
+    ) -> Result<Self, Fail> {
+        let rt = DeviceRuntime::new(device, options);
+        let engine = Engine::new(rt.clone())?;
+        let shared = Shared {
+            engine: RefCell::new(engine),
+            rt,
+            tick_interval,
+            kicked: Cell::new(false),
+            driver: RefCell::new(None),
+        };
+        Ok(Self {
+            shared: Rc::new(shared),
+        })
+    }
+
+    /// Direct access to the engine, for everything the async wrappers don't cover. Don't hold
+    /// on to the borrow across an `.await`.
+    pub fn engine(&self) -> RefMut<Engine<DeviceRuntime<D>>> {
+        self.shared.engine.borrow_mut()
+    }
+
+    /// Drive the engine until the device fails. Only returns on error.
+    pub async fn run(&self) -> Result<(), Fail> {
+        let fd = self.shared.rt.with_device(|d| d.as_raw_fd());
+        let device = AsyncFd::new(DeviceFd(fd))?;
+        loop {
+            if self.shared.poll_engine() {
+                tokio::task::yield_now().await;
+                continue;
+            }
+            let readable = device.readable().fuse();
+            let tick = tokio::time::sleep(self.shared.tick_interval).fuse();
+            let kicked = Kicked {
+                shared: &self.shared,
+            }
+            .fuse();
+            futures::pin_mut!(readable, tick, kicked);
+            futures::select_biased! {
+                guard = readable => {
+                    // Every frame gets drained on the next iteration, so it's safe to wait for
+                    // the next edge.
+                    guard?.clear_ready();
+                },
+                _ = kicked => (),
+                _ = tick => (),
+            }
+        }
+    }
+
+    pub fn tcp_listen(&self, local: SocketAddrV4, backlog: usize) -> Result<TcpListener<D>, Fail> {
+        let local = ipv4::Endpoint::try_from(local)?;
+        let mut engine = self.engine();
+        let fd = engine.tcp_socket();
+        let r = engine
+            .tcp_bind(fd, local)
+            .and_then(|_| engine.tcp_listen(fd, backlog));
+        if let Err(e) = r {
+            engine.tcp_close(fd)?;
+            return Err(e);
+        }
+        Ok(TcpListener {
+            stack: self.clone(),
+            fd,
+            accept: None,
+        })
+    }
+
+    pub async fn tcp_connect(&self, remote: SocketAddrV4) -> Result<TcpStream<D>, Fail> {
+        let remote = ipv4::Endpoint::try_from(remote)?;
+        let (fd, future) = {
+            let mut engine = self.engine();
+            let fd = engine.tcp_socket();
+            (fd, engine.tcp_connect(fd, remote))
+        };
+        self.shared.kick();
+        if let Err(e) = future.await {
+            self.engine().tcp_close(fd)?;
+            return Err(e);
+        }
+        Ok(TcpStream::new(self.clone(), fd))
+    }
+
+    pub fn udp_bind(&self, local: SocketAddrV4) -> Result<UdpSocket<D>, Fail> {
+        let local = ipv4::Endpoint::try_from(local)?;
+        let mut engine = self.engine();
+        let fd = engine.socket(Protocol::Udp);
+        if let Err(e) = engine.bind(fd, local) {
+            engine.close(fd)?;
+            return Err(e);
+        }
+        Ok(UdpSocket {
+            stack: self.clone(),
+            fd,
+            pop: None,
+        })
+    }
+}
+
+struct DeviceFd(RawFd);
+
+impl AsRawFd for DeviceFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+struct Kicked<'a, D: NetworkDevice> {
+    shared: &'a Shared<D>,
+}
+
+impl<'a, D: NetworkDevice> Future for Kicked<'a, D> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        if self.shared.kicked.replace(false) {
+            return Poll::Ready(());
+        }
+        *self.shared.driver.borrow_mut() = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+}
+
+fn io_error(e: Error) -> io::Error {
+    let kind = match e.kind() {
+        Fail::ConnectionAborted {} => io::ErrorKind::ConnectionAborted,
+        Fail::ConnectionRefused {} => io::ErrorKind::ConnectionRefused,
+        Fail::Timeout {} => io::ErrorKind::TimedOut,
+        Fail::ResourceBusy { .. } => io::ErrorKind::AddrInUse,
+        Fail::WouldBlock {} => io::ErrorKind::WouldBlock,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e)
+}
+
+/// Accepted connections, in the order their handshakes finished.
+pub struct TcpListener<D: NetworkDevice + AsRawFd> {
+    stack: Stack<D>,
+    fd: FileDescriptor,
+    accept: Option<AcceptFuture<DeviceRuntime<D>>>,
+}
+
+impl<D: NetworkDevice + AsRawFd> TcpListener<D> {
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> Stream for TcpListener<D> {
+    type Item = Result<TcpStream<D>, Fail>;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let self_ = self.get_mut();
+        let (stack, fd) = (&self_.stack, self_.fd);
+        let accept = self_
+            .accept
+            .get_or_insert_with(|| stack.engine().tcp_accept(fd));
+        let r = futures::ready!(Future::poll(Pin::new(accept), ctx));
+        self_.accept = None;
+        Poll::Ready(Some(r.map(|fd| TcpStream::new(self_.stack.clone(), fd))))
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> Drop for TcpListener<D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stack.engine().tcp_close(self.fd) {
+            warn!("Failed to close TCP listener: {:?}", e);
+        }
+    }
+}
+
+/// An established TCP connection.
+///
+/// Writes never block: the engine buffers everything it hasn't sent yet. Shutting down the write
+/// half closes the whole connection, since the engine doesn't support half-close.
+pub struct TcpStream<D: NetworkDevice + AsRawFd> {
+    stack: Stack<D>,
+    fd: FileDescriptor,
+    pop: Option<PopFuture<DeviceRuntime<D>>>,
+    // The rest of a segment that didn't fit in the caller's buffer.
+    pending: Option<Bytes>,
+    endpoints: Option<(ipv4::Endpoint, ipv4::Endpoint)>,
+    closed: bool,
+}
+
+impl<D: NetworkDevice + AsRawFd> TcpStream<D> {
+    fn new(stack: Stack<D>, fd: FileDescriptor) -> Self {
+        let endpoints = stack.engine().tcp_endpoints(fd).ok();
+        Self {
+            stack,
+            fd,
+            pop: None,
+            pending: None,
+            endpoints,
+            closed: false,
+        }
+    }
+
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddrV4> {
+        self.endpoints.map(|(local, _)| local.into())
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddrV4> {
+        self.endpoints.map(|(_, remote)| remote.into())
+    }
+
+    fn io_error(&self, e: Fail) -> io::Error {
+        let mut e = Error::new(e).with_protocol(ErrorProtocol::Tcp);
+        if let Some((local, remote)) = self.endpoints {
+            e = e.with_local(local).with_remote(remote);
+        }
+        io_error(e)
+    }
+
+    fn close(&mut self) -> Result<(), Fail> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        let r = self.stack.engine().tcp_close(self.fd);
+        self.stack.shared.kick();
+        r
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> AsyncRead for TcpStream<D> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let self_ = self.get_mut();
+        let mut segment = match self_.pending.take() {
+            Some(segment) => segment,
+            None => {
+                let (stack, fd) = (&self_.stack, self_.fd);
+                let pop = self_.pop.get_or_insert_with(|| stack.engine().tcp_pop(fd));
+                let r = futures::ready!(Future::poll(Pin::new(pop), ctx));
+                self_.pop = None;
+                // Taking data off the receive queue opens the window, which may need announcing.
+                self_.stack.shared.kick();
+                match r {
+                    Ok(segment) => segment,
+                    // The remote sent a FIN and we've read everything before it.
+                    Err(Fail::ResourceNotFound { .. }) => return Poll::Ready(Ok(())),
+                    Err(e) => return Poll::Ready(Err(self_.io_error(e))),
+                }
+            },
+        };
+        let n = std::cmp::min(buf.remaining(), segment.len());
+        buf.put_slice(&segment[..n]);
+        segment.adjust(n);
+        if segment.len() > 0 {
+            self_.pending = Some(segment);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> AsyncWrite for TcpStream<D> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let self_ = self.get_mut();
+        let mut push = self_
+            .stack
+            .engine()
+            .tcp_push(self_.fd, Bytes::from_slice(buf));
+        let r = futures::ready!(Future::poll(Pin::new(&mut push), ctx));
+        self_.stack.shared.kick();
+        Poll::Ready(r.map(|_| buf.len()).map_err(|e| self_.io_error(e)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<io::Result<()>> {
+        let self_ = self.get_mut();
+        Poll::Ready(self_.close().map_err(|e| self_.io_error(e)))
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> Drop for TcpStream<D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            warn!("Failed to close TCP connection: {:?}", e);
+        }
+    }
+}
+
+/// A bound UDP socket. Received datagrams come out of the `Stream` along with their source, when
+/// known.
+pub struct UdpSocket<D: NetworkDevice + AsRawFd> {
+    stack: Stack<D>,
+    fd: FileDescriptor,
+    pop: Option<UdpPopFuture<DeviceRuntime<D>>>,
+}
+
+impl<D: NetworkDevice + AsRawFd> UdpSocket<D> {
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+
+    pub fn send_to(&self, buf: &[u8], to: SocketAddrV4) -> Result<(), Fail> {
+        let to = ipv4::Endpoint::try_from(to)?;
+        let r = self
+            .stack
+            .engine()
+            .udp_pushto(self.fd, Bytes::from_slice(buf), to);
+        self.stack.shared.kick();
+        r
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> Stream for UdpSocket<D> {
+    type Item = Result<(Option<SocketAddrV4>, Bytes), Fail>;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let self_ = self.get_mut();
+        let (stack, fd) = (&self_.stack, self_.fd);
+        let pop = self_.pop.get_or_insert_with(|| stack.engine().udp_pop(fd));
+        let r = futures::ready!(Future::poll(Pin::new(pop), ctx));
+        self_.pop = None;
+        Poll::Ready(Some(
+            r.map(|(from, buf)| (from.map(SocketAddrV4::from), buf)),
+        ))
+    }
+}
+
+impl<D: NetworkDevice + AsRawFd> Drop for UdpSocket<D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stack.engine().close(self.fd) {
+            warn!("Failed to close UDP socket: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stack;
+    use crate::{
+        backends::NetworkDevice,
+        options::Options,
+        protocols::arp,
+        runtime::{
+            RuntimeBuf,
+            RECEIVE_BATCH_SIZE,
+        },
+        sync::Bytes,
+        test_helpers,
+    };
+    use arrayvec::ArrayVec;
+    use futures::StreamExt;
+    use std::{
+        net::SocketAddrV4,
+        os::unix::{
+            io::{
+                AsRawFd,
+                RawFd,
+            },
+            net::UnixDatagram,
+        },
+    };
+    use tokio::io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    };
+
+    // One end of a datagram socket pair, standing in for a wire between two stacks.
+    struct PairDevice(UnixDatagram);
+
+    impl NetworkDevice for PairDevice {
+        fn transmit(&mut self, frame: &[u8]) {
+            let _ = self.0.send(frame);
+        }
+
+        fn poll_receive(&mut self, batch: &mut ArrayVec<[Bytes; RECEIVE_BATCH_SIZE]>) {
+            let mut buf = [0u8; 2048];
+            while !batch.is_full() {
+                match self.0.recv(&mut buf) {
+                    Ok(n) => batch.push(Bytes::from_slice(&buf[..n])),
+                    Err(..) => break,
+                }
+            }
+        }
+    }
+
+    impl AsRawFd for PairDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    fn stack(device: PairDevice, name: &str) -> Stack<PairDevice> {
+        let (link_addr, ipv4_addr) = match name {
+            "alice" => (test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4),
+            _ => (test_helpers::BOB_MAC, test_helpers::BOB_IPV4),
+        };
+        let mut arp = arp::Options::default();
+        arp.initial_values
+            .insert(test_helpers::ALICE_MAC, test_helpers::ALICE_IPV4);
+        arp.initial_values
+            .insert(test_helpers::BOB_MAC, test_helpers::BOB_IPV4);
+        let options = Options::default()
+            .my_link_addr(link_addr)
+            .my_ipv4_addr(ipv4_addr)
+            .arp(arp);
+        Stack::new(device, options).unwrap()
+    }
+
+    #[test]
+    fn echo() {
+        let (a, b) = UnixDatagram::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&runtime, async {
+            let alice = stack(PairDevice(a), "alice");
+            let bob = stack(PairDevice(b), "bob");
+            for s in &[alice.clone(), bob.clone()] {
+                let s = s.clone();
+                tokio::task::spawn_local(async move { s.run().await });
+            }
+
+            let addr = SocketAddrV4::new(test_helpers::BOB_IPV4, 80);
+            let mut listener = bob.tcp_listen(addr, 1).unwrap();
+            tokio::task::spawn_local(async move {
+                let mut stream = listener.next().await.unwrap().unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            });
+
+            let mut stream = alice.tcp_connect(addr).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let addr = SocketAddrV4::new(test_helpers::BOB_IPV4, 53);
+            let mut server = bob.udp_bind(addr).unwrap();
+            let client = alice
+                .udp_bind(SocketAddrV4::new(test_helpers::ALICE_IPV4, 5353))
+                .unwrap();
+            client.send_to(b"ping", addr).unwrap();
+            let (from, buf) = server.next().await.unwrap().unwrap();
+            assert_eq!(
+                from,
+                Some(SocketAddrV4::new(test_helpers::ALICE_IPV4, 5353))
+            );
+            assert_eq!(&buf[..], b"ping");
+        });
+    }
+}
@@ -228,6 +228,10 @@ impl Runtime for TestRuntime {
         self.inner.borrow().ipv4_addr.clone()
     }
 
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
     fn tcp_options(&self) -> tcp::Options {
         self.inner.borrow().tcp_options.clone()
     }
@@ -262,6 +266,10 @@ impl Runtime for TestRuntime {
         self.inner.borrow().timer.0.now()
     }
 
+    fn next_expiry(&self) -> Option<Instant> {
+        self.inner.borrow().timer.0.next_expiry()
+    }
+
     fn rng_gen<T>(&self) -> T
     where
         Standard: Distribution<T>,
@@ -0,0 +1,27 @@
+#!/bin/sh
+# Copyright (c) Microsoft Corporation.
+# Licensed under the MIT license.
+
+# Build the engine for the browser: `catnip_module.js` and `catnip_module.wasm` next to this
+# script, loaded by `catnip.js`. Needs the `wasm32-unknown-emscripten` Rust target and `emcc` on
+# the path.
+
+set -e
+cd "$(dirname "$0")/.."
+
+cargo build --release --lib --features wasm --target wasm32-unknown-emscripten
+target_dir="${CARGO_TARGET_DIR:-../target}"
+
+exports="_malloc,_free,_catnip_strerror"
+for f in $(grep -o 'fn catnip_sim_[a-z_]*' src/ffi/wasm.rs | cut -d' ' -f2); do
+    exports="$exports,_$f"
+done
+
+emcc "$target_dir/wasm32-unknown-emscripten/release/libcatnip.a" \
+    -O2 \
+    -s MODULARIZE=1 \
+    -s EXPORT_NAME=createCatnipModule \
+    -s ALLOW_MEMORY_GROWTH=1 \
+    -s "EXPORTED_FUNCTIONS=[$(echo "$exports" | sed "s/[^,]*/'&'/g")]" \
+    -s "EXTRA_EXPORTED_RUNTIME_METHODS=['UTF8ToString']" \
+    -o wasm/catnip_module.js
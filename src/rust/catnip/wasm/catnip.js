@@ -0,0 +1,176 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+// A small wrapper over the `catnip_sim_*` exports (see `src/ffi/wasm.rs`) for driving engines
+// from JavaScript. Build the module with `build.sh`, then:
+//
+//     const Module = await createCatnipModule();
+//     const alice = new Host(Module, "10.0.0.1", "02:00:00:00:00:01", seed);
+//     alice.onEvent = (event) => { ... };
+//
+// The caller plays the network: every `"frame"` event is a frame the host transmitted, which can
+// be handed to another host's `receive` (or dropped, delayed, or reordered). Completed
+// operations arrive as events too, carrying the token returned when they were started.
+
+const EAGAIN = 11; // Emscripten's errno values follow Linux.
+const EVENT_SIZE = 40;
+const EVENT_KINDS = ["frame", "failed", "connect", "accept", "push", "pop"];
+
+function parseIpv4(addr) {
+    const octets = addr.split(".").map(Number);
+    return ((octets[0] << 24) | (octets[1] << 16) | (octets[2] << 8) | octets[3]) >>> 0;
+}
+
+function formatIpv4(addr) {
+    return [addr >>> 24, (addr >>> 16) & 0xff, (addr >>> 8) & 0xff, addr & 0xff].join(".");
+}
+
+export class Host {
+    constructor(Module, ipv4Addr, linkAddr, seed) {
+        this.Module = Module;
+        this.nextToken = 1;
+        this.onEvent = () => {};
+
+        const mac = linkAddr.split(":").map((b) => parseInt(b, 16));
+        const args = Module._malloc(6 + 16);
+        Module.HEAPU8.set(mac, args);
+        Module.HEAPU8.set(seed.subarray(0, 16), args + 6);
+        this.sim = Module._catnip_sim_create(parseIpv4(ipv4Addr), args, args + 6);
+        Module._free(args);
+        if (this.sim === 0) {
+            throw new Error("Invalid host addresses");
+        }
+        // Room for an event, which is bigger than any other out-parameter.
+        this.scratch = Module._malloc(EVENT_SIZE);
+    }
+
+    destroy() {
+        this.Module._catnip_sim_destroy(this.sim);
+        this.Module._free(this.scratch);
+        this.sim = 0;
+    }
+
+    // Throw on failure, then hand any events the call produced to `onEvent`.
+    check(status) {
+        this.drain();
+        if (status !== 0 && status !== EAGAIN) {
+            throw new Error(`catnip call failed with errno ${status}`);
+        }
+    }
+
+    drain() {
+        const M = this.Module;
+        while (M._catnip_sim_next_event(this.sim, this.scratch) === 0) {
+            const fields = new Uint32Array(M.HEAPU8.buffer, this.scratch, EVENT_SIZE / 4);
+            const [kind, token, qd, newQd, code, remoteAddr, remotePort, data, dataLen, summary] =
+                fields;
+            const event = {
+                kind: EVENT_KINDS[kind],
+                token,
+                qd,
+                data: data ? M.HEAPU8.slice(data, data + dataLen) : null,
+            };
+            if (event.kind === "frame") {
+                event.summary = M.UTF8ToString(summary);
+            } else if (event.kind === "accept") {
+                event.newQd = newQd;
+            } else if (event.kind === "failed") {
+                event.error = M.UTF8ToString(M._catnip_strerror(code));
+            } else if (event.kind === "pop" && remoteAddr !== 0) {
+                event.remote = { addr: formatIpv4(remoteAddr), port: remotePort };
+            }
+            M._catnip_sim_event_free(this.scratch);
+            this.onEvent(event);
+        }
+    }
+
+    // Deliver a frame. Returns false if the engine dropped it.
+    receive(frame) {
+        const M = this.Module;
+        const buf = M._malloc(frame.length);
+        M.HEAPU8.set(frame, buf);
+        const status = M._catnip_sim_receive(this.sim, buf, frame.length);
+        M._free(buf);
+        this.drain();
+        return status === 0;
+    }
+
+    get now() {
+        return this.Module._catnip_sim_now(this.sim);
+    }
+
+    // Milliseconds at which the next timer fires, or null if none is pending.
+    get nextTimer() {
+        const t = this.Module._catnip_sim_next_timer(this.sim);
+        return t < 0 ? null : t;
+    }
+
+    advanceClock(nowMs) {
+        this.check(this.Module._catnip_sim_advance_clock(this.sim, nowMs));
+    }
+
+    withQd(call) {
+        this.check(call(this.scratch));
+        return this.Module.HEAPU32[this.scratch >> 2];
+    }
+
+    tcpSocket() {
+        return this.withQd((out) => this.Module._catnip_sim_tcp_socket(this.sim, out));
+    }
+
+    udpSocket() {
+        return this.withQd((out) => this.Module._catnip_sim_udp_socket(this.sim, out));
+    }
+
+    bind(qd, addr, port) {
+        this.check(this.Module._catnip_sim_bind(this.sim, qd, parseIpv4(addr), port));
+    }
+
+    listen(qd, backlog) {
+        this.check(this.Module._catnip_sim_tcp_listen(this.sim, qd, backlog));
+    }
+
+    // The operations below return the token their completion event will carry.
+
+    accept(qd) {
+        const token = this.nextToken++;
+        this.check(this.Module._catnip_sim_tcp_accept(this.sim, qd, token));
+        return token;
+    }
+
+    connect(qd, addr, port) {
+        const token = this.nextToken++;
+        this.check(this.Module._catnip_sim_tcp_connect(this.sim, qd, parseIpv4(addr), port, token));
+        return token;
+    }
+
+    write(qd, data) {
+        const M = this.Module;
+        const token = this.nextToken++;
+        const buf = M._malloc(data.length);
+        M.HEAPU8.set(data, buf);
+        const status = M._catnip_sim_tcp_write(this.sim, qd, buf, data.length, token);
+        M._free(buf);
+        this.check(status);
+        return token;
+    }
+
+    read(qd) {
+        const token = this.nextToken++;
+        this.check(this.Module._catnip_sim_read(this.sim, qd, token));
+        return token;
+    }
+
+    sendTo(qd, data, addr, port) {
+        const M = this.Module;
+        const buf = M._malloc(data.length);
+        M.HEAPU8.set(data, buf);
+        const status = M._catnip_sim_udp_sendto(this.sim, qd, buf, data.length, parseIpv4(addr), port);
+        M._free(buf);
+        this.check(status);
+    }
+
+    close(qd) {
+        this.check(this.Module._catnip_sim_close(this.sim, qd));
+    }
+}
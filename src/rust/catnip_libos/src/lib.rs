@@ -363,11 +363,12 @@ pub extern "C" fn dmtr_pop(qtok_out: *mut dmtr_qtoken_t, qd: c_int) -> c_int {
 
 #[no_mangle]
 pub extern "C" fn dmtr_poll(qr_out: *mut dmtr_qresult_t, qt: dmtr_qtoken_t) -> c_int {
-    with_libos(|libos| match libos.poll(qt) {
+    with_libos(|libos| match libos.poll_any2(&[qt]) {
         None => libc::EAGAIN,
-        Some(r) => {
-            unsafe { *qr_out = r };
-            0
+        Some((_, qd, r)) => {
+            let ret = dmtr_qresult_t::errno(&r);
+            unsafe { *qr_out = dmtr_qresult_t::pack(libos.rt(), r, qd, qt) };
+            ret
         },
     })
 }
@@ -384,11 +385,12 @@ pub extern "C" fn dmtr_drop(qt: dmtr_qtoken_t) -> c_int {
 pub extern "C" fn dmtr_wait(qr_out: *mut dmtr_qresult_t, qt: dmtr_qtoken_t) -> c_int {
     with_libos(|libos| {
         let (qd, r) = libos.wait2(qt);
+        let ret = dmtr_qresult_t::errno(&r);
         if !qr_out.is_null() {
             let packed = dmtr_qresult_t::pack(libos.rt(), r, qd, qt);
             unsafe { *qr_out = packed };
         }
-        0
+        ret
     })
 }
 
@@ -399,14 +401,45 @@ pub extern "C" fn dmtr_wait_any(
     qts: *mut dmtr_qtoken_t,
     num_qts: c_int,
 ) -> c_int {
+    if qts.is_null() || num_qts <= 0 {
+        return libc::EINVAL;
+    }
     let qts = unsafe { slice::from_raw_parts(qts, num_qts as usize) };
     with_libos(|libos| {
-        let (ix, qr) = libos.wait_any(qts);
+        let (ix, qd, r) = libos.wait_any2(qts);
+        let ret = dmtr_qresult_t::errno(&r);
         unsafe {
-            *qr_out = qr;
+            *qr_out = dmtr_qresult_t::pack(libos.rt(), r, qd, qts[ix]);
             *ready_offset = ix as c_int;
         }
-        0
+        ret
+    })
+}
+
+/// Wait for all of `qtoks` to complete, filling in one result per token. Returns the first
+/// failure's `errno`, if any.
+#[no_mangle]
+pub extern "C" fn dmtr_wait_all(
+    qr_out: *mut dmtr_qresult_t,
+    qts: *mut dmtr_qtoken_t,
+    num_qts: c_int,
+) -> c_int {
+    if qts.is_null() || num_qts < 0 {
+        return libc::EINVAL;
+    }
+    let qts = unsafe { slice::from_raw_parts(qts, num_qts as usize) };
+    with_libos(|libos| {
+        let mut ret = 0;
+        for (i, (qd, r)) in libos.wait_all2(qts).into_iter().enumerate() {
+            if ret == 0 {
+                ret = dmtr_qresult_t::errno(&r);
+            }
+            let packed = dmtr_qresult_t::pack(libos.rt(), r, qd, qts[i]);
+            if !qr_out.is_null() {
+                unsafe { *qr_out.add(i) = packed };
+            }
+        }
+        ret
     })
 }
 
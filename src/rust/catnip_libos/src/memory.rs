@@ -534,6 +534,10 @@ impl RuntimeBuf for DPDKBuf {
         DPDKBuf::External(Bytes::empty())
     }
 
+    fn from_slice(bytes: &[u8]) -> Self {
+        DPDKBuf::External(Bytes::from_slice(bytes))
+    }
+
     fn adjust(&mut self, num_bytes: usize) {
         match self {
             DPDKBuf::External(ref mut buf) => buf.adjust(num_bytes),
@@ -547,6 +551,15 @@ impl RuntimeBuf for DPDKBuf {
             DPDKBuf::Managed(ref mut mbuf) => mbuf.trim(num_bytes),
         }
     }
+
+    fn get_mut(&mut self) -> Option<&mut [u8]> {
+        match self {
+            DPDKBuf::External(ref mut buf) => buf.get_mut(),
+            // Indirect clones of an mbuf share its data without it knowing, so it's never
+            // written in place.
+            DPDKBuf::Managed(..) => None,
+        }
+    }
 }
 
 #[cfg(test)]
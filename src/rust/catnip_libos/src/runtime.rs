@@ -106,7 +106,7 @@ impl DPDKRuntime {
         let mut tcp_options = tcp::Options::default();
         tcp_options.advertised_mss = mss;
         tcp_options.window_scale = 5;
-        tcp_options.receive_window_size = 0xffff;
+        tcp_options.receive_window_size = 0xffff << 5;
         tcp_options.tx_checksum_offload = tcp_checksum_offload;
         tcp_options.rx_checksum_offload = tcp_checksum_offload;
 
@@ -306,6 +306,10 @@ impl Runtime for DPDKRuntime {
         self.inner.borrow().ipv4_addr.clone()
     }
 
+    fn set_local_ipv4_addr(&self, addr: Ipv4Addr) {
+        self.inner.borrow_mut().ipv4_addr = addr;
+    }
+
     fn tcp_options(&self) -> tcp::Options {
         self.inner.borrow().tcp_options.clone()
     }